@@ -0,0 +1,93 @@
+use crate::{FileLoader, MemoryMap, ReadSeek};
+use std::io::SeekFrom;
+
+/// The size of a boot sector, whose last two bytes are the boot signature.
+const SECTOR_SIZE: usize = 512;
+const SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// Where the BIOS loads the boot sector and jumps to it.
+const LOAD_ADDRESS: u64 = 0x7C00;
+/// Everything real mode can address: the first 1 MiB, and the high memory area just past
+/// it that's reachable with the A20 gate open.
+const MEMORY_SIZE: usize = 0x10_FFF0;
+
+/// The memory a boot sector starts in: the sector at 0x7C00 and the rest zeroed.
+pub struct BootMemoryMap {
+    memory: Vec<u8>,
+}
+
+impl MemoryMap for BootMemoryMap {
+    fn bits(&self) -> u8 {
+        16
+    }
+
+    // Nothing answers past the end of memory, so reads float high and writes are lost
+    fn read_u8(&self, addr: u64) -> u8 {
+        *self.memory.get(addr as usize).unwrap_or(&0xFF)
+    }
+
+    fn write_u8(&mut self, addr: u64, data: u8) {
+        if let Some(byte) = self.memory.get_mut(addr as usize) {
+            *byte = data;
+        }
+    }
+
+    fn read_bytes(&self, addr: u64, buf: &mut [u8]) {
+        match self.memory.get(addr as usize..addr as usize + buf.len()) {
+            Some(bytes) => buf.copy_from_slice(bytes),
+            None => {
+                for (i, byte) in buf.iter_mut().enumerate() {
+                    *byte = self.read_u8(addr + i as u64);
+                }
+            }
+        }
+    }
+
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) {
+        match self
+            .memory
+            .get_mut(addr as usize..addr as usize + data.len())
+        {
+            Some(bytes) => bytes.copy_from_slice(data),
+            None => {
+                for (i, &byte) in data.iter().enumerate() {
+                    self.write_u8(addr + i as u64, byte);
+                }
+            }
+        }
+    }
+
+    fn entry_point(&self) -> u64 {
+        LOAD_ADDRESS
+    }
+
+    /// The stack grows down from just below the boot sector, where most BIOSes leave it.
+    fn starting_stack(&self) -> u64 {
+        LOAD_ADDRESS
+    }
+}
+
+/// Loads a 512-byte boot sector image ending in the 0x55 0xAA signature, to be run in real
+/// mode as a BIOS would.
+pub struct BootSectorLoader {}
+
+impl FileLoader for BootSectorLoader {
+    fn can_load(&self, file: &mut dyn ReadSeek) -> bool {
+        let Ok(len) = file.seek(SeekFrom::End(0)) else {
+            return false;
+        };
+        let mut signature = [0u8; 2];
+        let readable = len == SECTOR_SIZE as u64
+            && file.seek(SeekFrom::Start(SECTOR_SIZE as u64 - 2)).is_ok()
+            && file.read_exact(&mut signature).is_ok();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        readable && signature == SIGNATURE
+    }
+
+    fn load(&self, file: &mut dyn ReadSeek) -> Box<dyn MemoryMap> {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let start = LOAD_ADDRESS as usize;
+        file.read_exact(&mut memory[start..start + SECTOR_SIZE])
+            .unwrap();
+        Box::new(BootMemoryMap { memory })
+    }
+}
@@ -4,6 +4,7 @@ use byteorder::{LittleEndian, ReadBytesExt};
 use std::io::SeekFrom;
 use std::vec::Vec;
 
+#[allow(dead_code)] // Not every header field is consumed yet
 struct ElfPrgHead {
     pub ph_type: u32,
     pub flags: u32,
@@ -16,6 +17,7 @@ struct ElfPrgHead {
 
 struct ElfSectHead {}
 
+#[allow(dead_code)]
 struct ElfFile {
     pub bits: u8,
     pub abi: u8,
@@ -85,7 +87,7 @@ impl ElfFile {
             }
             let align = read_u32_u64(file);
 
-            next_off = file.seek(SeekFrom::Current(0)).unwrap();
+            next_off = file.stream_position().unwrap();
             file.seek(SeekFrom::Start(offset)).unwrap();
             let mut data = vec![0; filesz as usize];
             file.read_exact(&mut data).unwrap();
@@ -126,7 +128,7 @@ impl ElfFile {
 
 use std::borrow::Cow;
 
-pub fn prefix_zeroed<const N: usize>(slice: &[u8]) -> Cow<[u8; N]> {
+pub fn prefix_zeroed<const N: usize>(slice: &[u8]) -> Cow<'_, [u8; N]> {
     slice
         .get(..N)
         .map(|s| &bytemuck::cast_slice(s)[0])
@@ -214,12 +216,50 @@ impl MemoryMap for ElfMemoryMap {
     }
 
     fn write_u8(&mut self, addr: u64, data: u8) {
+        self.write_bytes(addr, &[data])
+    }
+
+    fn read_bytes(&self, addr: u64, buf: &mut [u8]) {
+        let end = addr + buf.len() as u64;
+        for ph in &self.prghead {
+            if ph.vaddr <= addr && (ph.vaddr + ph.memsz) >= end {
+                let start = (addr - ph.vaddr) as usize;
+                // Anything past the file-backed part of the segment reads as zero
+                let backed = ph.data.get(start..).unwrap_or(&[]);
+                let backed = &backed[..backed.len().min(buf.len())];
+                buf[..backed.len()].copy_from_slice(backed);
+                buf[backed.len()..].fill(0);
+                return;
+            }
+        }
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.read_u8(addr + i as u64);
+        }
+    }
+
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = addr + data.len() as u64;
         for ph in &mut self.prghead {
-            if ph.vaddr <= addr && (ph.vaddr + ph.memsz) > addr {
-                ph.data[(addr - ph.vaddr) as usize] = data;
+            if ph.vaddr <= addr && (ph.vaddr + ph.memsz) >= end {
+                let start = (addr - ph.vaddr) as usize;
+                // The zero-filled tail of a segment (.bss) isn't in `data` until it's written
+                if ph.data.len() < start + data.len() {
+                    ph.data.resize(start + data.len(), 0);
+                }
+                ph.data[start..start + data.len()].copy_from_slice(data);
                 return;
             }
         }
+        if data.len() > 1 {
+            // The range straddles segments, so place each byte separately
+            for (i, &byte) in data.iter().enumerate() {
+                self.write_bytes(addr + i as u64, &[byte]);
+            }
+            return;
+        }
         panic!("Segmentation fault writing to address {:#018X}", addr)
     }
 
@@ -253,3 +293,37 @@ impl FileLoader for ElfFileLoader {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bss_map() -> ElfMemoryMap {
+        ElfMemoryMap {
+            prghead: vec![ElfPrgHead {
+                ph_type: 1,
+                flags: 6,
+                vaddr: 0x1000,
+                paddr: 0x1000,
+                memsz: 0x100,
+                align: 0x1000,
+                data: vec![0xAA; 0x10],
+            }],
+            bits: 64,
+            e_entry: 0x1000,
+        }
+    }
+
+    #[test]
+    fn bulk_reads_from_bss() {
+        let map = bss_map();
+        // Entirely past the file-backed bytes
+        let mut buf = [0xFFu8; 0x20];
+        map.read_bytes(0x1040, &mut buf);
+        assert_eq!(buf, [0; 0x20]);
+        // Straddling the end of the file-backed bytes
+        let mut buf = [0xFFu8; 8];
+        map.read_bytes(0x100C, &mut buf);
+        assert_eq!(buf, [0xAA, 0xAA, 0xAA, 0xAA, 0, 0, 0, 0]);
+    }
+}
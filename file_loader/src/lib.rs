@@ -1,7 +1,8 @@
-#![feature(iter_advance_by)]
 use std::io::{Read, Seek};
 
+pub mod boot;
 pub mod elf;
+pub mod shared;
 
 use bytemuck::{Pod, Zeroable};
 
@@ -34,15 +35,72 @@ pub union YMMWord {
 unsafe impl Zeroable for YMMWord {}
 unsafe impl Pod for YMMWord {}
 
+#[repr(C, align(64))]
+#[derive(Copy, Clone)]
+pub union ZMMWord {
+    pub ymm: [YMMWord; 2],
+    pub single: [f32; 16],
+    pub double: [f64; 8],
+    pub i8: [u8; 64],
+    pub i16: [u16; 32],
+    pub i32: [u32; 16],
+    pub i64: [u64; 8],
+}
+
+unsafe impl Zeroable for ZMMWord {}
+unsafe impl Pod for ZMMWord {}
+
+/// An x87 data register: an 80-bit extended-precision value, padded to 16 bytes as in the
+/// FXSAVE image.
+#[repr(C)]
+#[derive(Copy, Clone, Zeroable, Pod, Debug, PartialEq, Eq)]
+pub struct X87Register {
+    pub significand: u64,
+    pub sign_exponent: u16,
+    pub reserved: [u16; 3],
+}
+
+/// The x87 unit's control, status and data registers, and MXCSR.
+#[repr(C)]
+#[derive(Copy, Clone, Zeroable, Pod)]
+pub struct FpuRegisters {
+    /// Control word
+    pub fcw: u16,
+    /// Status word
+    pub fsw: u16,
+    /// Tag word, two bits per physical register: valid, zero, special or empty
+    pub ftw: u16,
+    /// Last non-control instruction's opcode: the low 3 bits of its first opcode byte and
+    /// its ModR/M byte
+    pub fop: u16,
+    /// Last non-control instruction's address
+    pub fip: u64,
+    /// Last non-control instruction's memory operand address
+    pub fdp: u64,
+    pub mxcsr: u32,
+    /// Code and data segment selectors of `fip` and `fdp`
+    pub fcs: u16,
+    pub fds: u16,
+    /// Data registers R0-R7 in physical order: ST(i) is R((TOP + i) mod 8)
+    pub st: [X87Register; 8],
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Zeroable, Pod)]
 pub struct Registers {
+    /// ZMM0-ZMM31, whose low 256 and 128 bits are the YMM and XMM registers
+    pub zmm: [ZMMWord; 32],
     pub gprs: [u64; 16],
     pub cr: [u64; 16],
     pub sr: [u16; 8],
+    /// The base address of each segment register; in 64-bit mode only FS's and GS's are
+    /// used
+    pub sr_base: [u64; 8],
     pub rip: u64,
     pub rflags: u64,
-    pub ymm: [YMMWord; 16],
+    /// The AVX-512 opmask registers k0-k7
+    pub k: [u64; 8],
+    pub fpu: FpuRegisters,
 }
 
 pub trait ReadSeek: Read + Seek {}
@@ -58,14 +116,14 @@ pub trait MemoryMap {
         (self.read_u16(addr) as u32) | (self.read_u16(addr + 2) as u32) << 16
     }
     fn read_u64(&self, addr: u64) -> u64 {
-        (self.read_u32(addr) as u64) | (self.read_u32(addr + 1) as u64) << 16
+        (self.read_u32(addr) as u64) | (self.read_u32(addr + 4) as u64) << 32
     }
     fn read_xmmword(&self, addr: u64) -> XMMWord {
         let x = [self.read_u64(addr), self.read_u64(addr + 8)];
         bytemuck::cast(x)
     }
     fn read_ymmword(&self, addr: u64) -> YMMWord {
-        let x = [self.read_xmmword(addr), self.read_xmmword(addr + 8)];
+        let x = [self.read_xmmword(addr), self.read_xmmword(addr + 16)];
         bytemuck::cast(x)
     }
     fn write_u8(&mut self, addr: u64, data: u8);
@@ -81,6 +139,24 @@ pub trait MemoryMap {
         self.write_u32(addr, (data & 0xFFFFFFFF) as u32);
         self.write_u32(addr + 4, ((data >> 32) & 0xFFFFFFFF) as u32);
     }
+    /// Fills `buf` with the bytes starting at `addr`. Maps backed by contiguous storage
+    /// should override this so bulk copies don't go through `read_u8` one byte at a time.
+    fn read_bytes(&self, addr: u64, buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.read_u8(addr + i as u64);
+        }
+    }
+    /// Writes `data` starting at `addr`. See [`MemoryMap::read_bytes`].
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            self.write_u8(addr + i as u64, byte);
+        }
+    }
+    /// Starts a locked read-modify-write. Until the matching [`MemoryMap::unlock_bus`], no
+    /// other processor sharing this memory may access it. A map only one processor can reach
+    /// is trivially atomic, hence the no-op default.
+    fn lock_bus(&mut self) {}
+    fn unlock_bus(&mut self) {}
     fn entry_point(&self) -> u64;
     fn starting_stack(&self) -> u64;
 }
@@ -90,7 +166,7 @@ pub trait FileLoader {
     fn load(&self, file: &mut dyn ReadSeek) -> Box<dyn MemoryMap>;
 }
 
-const LOADERS: [&dyn FileLoader; 1] = [&elf::ElfFileLoader {}];
+const LOADERS: [&dyn FileLoader; 2] = [&elf::ElfFileLoader {}, &boot::BootSectorLoader {}];
 
 pub trait LoadableFile: ReadSeek {
     fn loader(&mut self) -> Option<&'static dyn FileLoader>;
@@ -98,11 +174,6 @@ pub trait LoadableFile: ReadSeek {
 
 impl<F: ReadSeek> LoadableFile for F {
     fn loader(&mut self) -> Option<&'static dyn FileLoader> {
-        for loader in LOADERS {
-            if loader.can_load(self) {
-                return Some(loader);
-            }
-        }
-        None
+        LOADERS.into_iter().find(|loader| loader.can_load(self))
     }
 }
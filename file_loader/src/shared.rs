@@ -0,0 +1,131 @@
+use crate::MemoryMap;
+use crate::{XMMWord, YMMWord};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+struct Bus {
+    map: Box<dyn MemoryMap + Send>,
+    /// The handle holding the bus lock, if any
+    owner: Option<u64>,
+}
+
+struct Shared {
+    bus: Mutex<Bus>,
+    released: Condvar,
+    next_id: AtomicU64,
+}
+
+/// A [`MemoryMap`] that several processors, each on its own thread, can use at once.
+///
+/// Every clone is a handle onto the same memory. Single accesses are atomic, and a handle
+/// that takes the bus lock with [`MemoryMap::lock_bus`] has the memory to itself until it
+/// calls [`MemoryMap::unlock_bus`], so locked read-modify-write instructions are atomic with
+/// respect to every other handle.
+pub struct SharedMemoryMap {
+    shared: Arc<Shared>,
+    id: u64,
+}
+
+impl SharedMemoryMap {
+    pub fn new(map: Box<dyn MemoryMap + Send>) -> SharedMemoryMap {
+        SharedMemoryMap {
+            shared: Arc::new(Shared {
+                bus: Mutex::new(Bus { map, owner: None }),
+                released: Condvar::new(),
+                next_id: AtomicU64::new(1),
+            }),
+            id: 0,
+        }
+    }
+
+    /// Waits until no other handle holds the bus lock, then locks the memory.
+    fn bus(&self) -> MutexGuard<'_, Bus> {
+        let bus = self.shared.bus.lock().unwrap();
+        self.shared
+            .released
+            .wait_while(bus, |bus| bus.owner.is_some_and(|owner| owner != self.id))
+            .unwrap()
+    }
+}
+
+impl Clone for SharedMemoryMap {
+    fn clone(&self) -> SharedMemoryMap {
+        SharedMemoryMap {
+            shared: self.shared.clone(),
+            id: self.shared.next_id.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl MemoryMap for SharedMemoryMap {
+    fn bits(&self) -> u8 {
+        self.bus().map.bits()
+    }
+
+    fn read_u8(&self, addr: u64) -> u8 {
+        self.bus().map.read_u8(addr)
+    }
+
+    fn read_u16(&self, addr: u64) -> u16 {
+        self.bus().map.read_u16(addr)
+    }
+
+    fn read_u32(&self, addr: u64) -> u32 {
+        self.bus().map.read_u32(addr)
+    }
+
+    fn read_u64(&self, addr: u64) -> u64 {
+        self.bus().map.read_u64(addr)
+    }
+
+    fn read_xmmword(&self, addr: u64) -> XMMWord {
+        self.bus().map.read_xmmword(addr)
+    }
+
+    fn read_ymmword(&self, addr: u64) -> YMMWord {
+        self.bus().map.read_ymmword(addr)
+    }
+
+    fn read_bytes(&self, addr: u64, buf: &mut [u8]) {
+        self.bus().map.read_bytes(addr, buf)
+    }
+
+    fn write_u8(&mut self, addr: u64, data: u8) {
+        self.bus().map.write_u8(addr, data)
+    }
+
+    fn write_u16(&mut self, addr: u64, data: u16) {
+        self.bus().map.write_u16(addr, data)
+    }
+
+    fn write_u32(&mut self, addr: u64, data: u32) {
+        self.bus().map.write_u32(addr, data)
+    }
+
+    fn write_u64(&mut self, addr: u64, data: u64) {
+        self.bus().map.write_u64(addr, data)
+    }
+
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) {
+        self.bus().map.write_bytes(addr, data)
+    }
+
+    fn lock_bus(&mut self) {
+        let mut bus = self.bus();
+        bus.owner = Some(self.id);
+    }
+
+    fn unlock_bus(&mut self) {
+        let mut bus = self.bus();
+        bus.owner = None;
+        self.shared.released.notify_all();
+    }
+
+    fn entry_point(&self) -> u64 {
+        self.bus().map.entry_point()
+    }
+
+    fn starting_stack(&self) -> u64 {
+        self.bus().map.starting_stack()
+    }
+}
@@ -1 +1,5 @@
+pub mod cpu_model;
+pub mod decode;
+pub mod exception;
 pub mod interp;
+pub mod softfloat;
@@ -0,0 +1,799 @@
+//! Descriptions of the processor being emulated: what CPUID reports about it, and which
+//! instruction-set extensions it has.
+//!
+//! Instructions from an extension the model doesn't have raise #UD, so one binary can be
+//! run against several models to exercise each of its dispatch paths.
+
+use super::decode::{Instruction, Mnemonic, Operand, OperandSize, Prefixes};
+use std::collections::BTreeMap;
+
+const EAX: usize = 0;
+const EBX: usize = 1;
+const ECX: usize = 2;
+const EDX: usize = 3;
+
+macro_rules! features {
+    ($($name:ident = ($leaf:expr, $subleaf:expr, $reg:ident, $bit:expr)),* $(,)?) => {
+        /// An instruction-set extension or capability reported by a CPUID feature bit.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum Feature {
+            $($name),*
+        }
+
+        impl Feature {
+            pub const ALL: &'static [Feature] = &[$(Feature::$name),*];
+
+            /// Where CPUID reports the feature: leaf, subleaf, output register (0-3 for
+            /// EAX-EDX) and bit.
+            pub fn location(self) -> (u32, u32, usize, u32) {
+                match self {
+                    $(Feature::$name => ($leaf, $subleaf, $reg, $bit)),*
+                }
+            }
+        }
+    };
+}
+
+features! {
+    Fpu = (1, 0, EDX, 0),
+    Pse = (1, 0, EDX, 3),
+    Pae = (1, 0, EDX, 6),
+    Pge = (1, 0, EDX, 13),
+    Tsc = (1, 0, EDX, 4),
+    Cx8 = (1, 0, EDX, 8),
+    Cmov = (1, 0, EDX, 15),
+    Clflush = (1, 0, EDX, 19),
+    Mmx = (1, 0, EDX, 23),
+    Fxsr = (1, 0, EDX, 24),
+    Sse = (1, 0, EDX, 25),
+    Sse2 = (1, 0, EDX, 26),
+    Sse3 = (1, 0, ECX, 0),
+    Pclmulqdq = (1, 0, ECX, 1),
+    Ssse3 = (1, 0, ECX, 9),
+    Fma = (1, 0, ECX, 12),
+    Cx16 = (1, 0, ECX, 13),
+    Pcid = (1, 0, ECX, 17),
+    Sse41 = (1, 0, ECX, 19),
+    Sse42 = (1, 0, ECX, 20),
+    Movbe = (1, 0, ECX, 22),
+    Popcnt = (1, 0, ECX, 23),
+    Aes = (1, 0, ECX, 25),
+    Xsave = (1, 0, ECX, 26),
+    Osxsave = (1, 0, ECX, 27),
+    Avx = (1, 0, ECX, 28),
+    F16c = (1, 0, ECX, 29),
+    Rdrand = (1, 0, ECX, 30),
+    Fsgsbase = (7, 0, EBX, 0),
+    Bmi1 = (7, 0, EBX, 3),
+    Avx2 = (7, 0, EBX, 5),
+    Bmi2 = (7, 0, EBX, 8),
+    Erms = (7, 0, EBX, 9),
+    Invpcid = (7, 0, EBX, 10),
+    Avx512f = (7, 0, EBX, 16),
+    Avx512dq = (7, 0, EBX, 17),
+    Rdseed = (7, 0, EBX, 18),
+    Adx = (7, 0, EBX, 19),
+    Clflushopt = (7, 0, EBX, 23),
+    Avx512cd = (7, 0, EBX, 28),
+    Sha = (7, 0, EBX, 29),
+    Avx512bw = (7, 0, EBX, 30),
+    Avx512vl = (7, 0, EBX, 31),
+    // 5-level paging; the model's linear address width should be 57 bits to go with it
+    La57 = (7, 0, ECX, 16),
+    Xsaveopt = (0xD, 1, EAX, 0),
+    Xsavec = (0xD, 1, EAX, 1),
+    LahfLm = (0x8000_0001, 0, ECX, 0),
+    // LZCNT, under AMD's "advanced bit manipulation" name
+    Abm = (0x8000_0001, 0, ECX, 5),
+    Prefetchw = (0x8000_0001, 0, ECX, 8),
+    Syscall = (0x8000_0001, 0, EDX, 11),
+    Nx = (0x8000_0001, 0, EDX, 20),
+    Pdpe1gb = (0x8000_0001, 0, EDX, 26),
+    Rdtscp = (0x8000_0001, 0, EDX, 27),
+    Lm = (0x8000_0001, 0, EDX, 29),
+}
+
+impl Feature {
+    /// The extension an instruction belongs to, if it isn't part of the base architecture.
+    pub fn required_by(insn: &Instruction) -> Option<Feature> {
+        use Mnemonic::*;
+        Some(match insn.mnemonic {
+            Vzeroupper | Vzeroall => Feature::Avx,
+            _ if insn.prefixes.contains(Prefixes::EVEX) || insn.is_opmask() => avx512_subset(insn),
+            _ if insn.prefixes.contains(Prefixes::VEX) && insn.is_sse() => avx_generation(insn),
+            Rdtsc => Feature::Tsc,
+            Cmpxchg8b => Feature::Cx8,
+            Cmovcc => Feature::Cmov,
+            Cmpxchg16b => Feature::Cx16,
+            Movbe => Feature::Movbe,
+            Popcnt => Feature::Popcnt,
+            Xgetbv | Xsetbv => Feature::Xsave,
+            Lzcnt => Feature::Abm,
+            Tzcnt | Andn | Bextr | Blsi | Blsmsk | Blsr => Feature::Bmi1,
+            Bzhi | Pdep | Pext | Sarx | Shlx | Shrx | Rorx | Mulx => Feature::Bmi2,
+            // Only in 64-bit mode; they're part of the base architecture everywhere else
+            Lahf | Sahf => Feature::LahfLm,
+            Syscall | Sysret => Feature::Syscall,
+            Rdtscp => Feature::Rdtscp,
+            Invpcid => Feature::Invpcid,
+            Rdfsbase | Rdgsbase | Wrfsbase | Wrgsbase => Feature::Fsgsbase,
+            Rdrand => Feature::Rdrand,
+            Rdseed => Feature::Rdseed,
+            Aesenc | Aesenclast | Aesdec | Aesdeclast | Aesimc | Aeskeygenassist => Feature::Aes,
+            Pclmulqdq => Feature::Pclmulqdq,
+            Sha1rnds4 | Sha1nexte | Sha1msg1 | Sha1msg2 | Sha256rnds2 | Sha256msg1 | Sha256msg2 => {
+                Feature::Sha
+            }
+            // FCMOVcc and FCOMI arrived with CMOV in the P6
+            Fcmovcc | Fcomi | Fcomip | Fucomi | Fucomip => Feature::Cmov,
+            Fisttp => Feature::Sse3,
+            Fwait => return None,
+            Fxsave | Fxrstor => Feature::Fxsr,
+            Xsave | Xrstor => Feature::Xsave,
+            Xsaveopt => Feature::Xsaveopt,
+            Xsavec => Feature::Xsavec,
+            Clflush => Feature::Clflush,
+            Sfence | Ldmxcsr | Stmxcsr => Feature::Sse,
+            Lfence | Mfence | Movnti => Feature::Sse2,
+            Addsubps | Addsubpd | Haddps | Haddpd | Hsubps | Hsubpd | Movsldup | Movshdup
+            | Movddup | Lddqu => Feature::Sse3,
+            // On MMX registers as well as XMM ones
+            Pshufb | Phaddw | Phaddd | Phaddsw | Phsubw | Phsubd | Phsubsw | Pmaddubsw | Psignb
+            | Psignw | Psignd | Pmulhrsw | Pabsb | Pabsw | Pabsd | Palignr => Feature::Ssse3,
+            Pcmpestri | Pcmpestrm | Pcmpistri | Pcmpistrm | Pcmpgtq | Crc32 => Feature::Sse42,
+            mnemonic if is_sse41(mnemonic) => Feature::Sse41,
+            mnemonic if insn.is_sse() && is_sse1(mnemonic) => Feature::Sse,
+            _ if insn.is_sse() => Feature::Sse2,
+            // SSE2 also extended the quadword arithmetic to MMX registers
+            Paddq | Psubq | Pmuludq => Feature::Sse2,
+            mnemonic if insn.is_mmx() && is_sse1(mnemonic) => Feature::Sse,
+            _ if insn.is_mmx() => Feature::Mmx,
+            mnemonic if mnemonic.is_x87() => Feature::Fpu,
+            _ => return None,
+        })
+    }
+
+    /// The extension an instruction needs besides the one it belongs to: AVX512VL for the
+    /// EVEX-encoded forms on 128- and 256-bit vectors, and AVX for the VEX-encoded AES and
+    /// PCLMULQDQ.
+    pub fn also_required_by(insn: &Instruction) -> Option<Feature> {
+        use Mnemonic::*;
+        if insn.prefixes.contains(Prefixes::VEX) {
+            return matches!(
+                insn.mnemonic,
+                Aesenc | Aesenclast | Aesdec | Aesdeclast | Aesimc | Aeskeygenassist | Pclmulqdq
+            )
+            .then_some(Feature::Avx);
+        }
+        let evex = insn.evex?;
+        evex.vector_length
+            .filter(|&size| size != OperandSize::R512)
+            .map(|_| Feature::Avx512vl)
+    }
+}
+
+/// Which of AVX512F, AVX512BW, AVX512DQ and AVX512CD an EVEX-encoded or opmask instruction
+/// came with: BW added the byte and word elements and the wider opmasks, DQ the byte
+/// opmasks and the quadword multiply, CD the conflict detection, and F everything else.
+fn avx512_subset(insn: &Instruction) -> Feature {
+    use Mnemonic::*;
+    match insn.mnemonic {
+        Kmovb | Kandb | Kandnb | Korb | Kxnorb | Kxorb | Knotb | Kortestb | Ktestb | Kaddb
+        | Kshiftlb | Kshiftrb | Vpmullq => Feature::Avx512dq,
+        // KADD and KTEST didn't come with F even on words
+        Kaddw | Ktestw => Feature::Avx512dq,
+        // As are the floating-point logic instructions, the inserts and extracts of
+        // doublewords and quadwords, the moves between opmasks and vectors of them, and the
+        // broadcasts, inserts and extracts of pairs of quadwords and octets of doublewords
+        Andps | Andpd | Andnps | Andnpd | Orps | Orpd | Xorps | Xorpd | Pinsrd | Pinsrq
+        | Pextrd | Pextrq | Vpmovm2d | Vpmovm2q | Vpmovd2m | Vpmovq2m | Vbroadcastf32x2
+        | Vbroadcasti32x2 | Vbroadcastf64x2 | Vbroadcasti64x2 | Vbroadcastf32x8
+        | Vbroadcasti32x8 | Vinsertf64x2 | Vinserti64x2 | Vinsertf32x8 | Vinserti32x8
+        | Vextractf64x2 | Vextracti64x2 | Vextractf32x8 | Vextracti32x8 => Feature::Avx512dq,
+        // And the conversions to and from quadwords, VREDUCE, VRANGE and VFPCLASS
+        Vcvtps2qq | Vcvtpd2qq | Vcvttps2qq | Vcvttpd2qq | Vcvtps2uqq | Vcvtpd2uqq | Vcvttps2uqq
+        | Vcvttpd2uqq | Vcvtqq2ps | Vcvtqq2pd | Vcvtuqq2ps | Vcvtuqq2pd | Vreduceps | Vreducepd
+        | Vreducess | Vreducesd | Vrangeps | Vrangepd | Vrangess | Vrangesd | Vfpclassps
+        | Vfpclasspd | Vfpclassss | Vfpclasssd => Feature::Avx512dq,
+        Kmovd | Kmovq | Kandd | Kandq | Kandnd | Kandnq | Kord | Korq | Kxnord | Kxnorq | Kxord
+        | Kxorq | Knotd | Knotq | Kortestd | Kortestq | Ktestd | Ktestq | Kaddd | Kaddq
+        | Kshiftld | Kshiftlq | Kshiftrd | Kshiftrq | Kunpckwd | Kunpckdq | Vmovdqu8
+        | Vmovdqu16 => Feature::Avx512bw,
+        _ if insn.is_opmask() => Feature::Avx512f,
+        Vpbroadcastmb2q | Vpbroadcastmw2d | Vpconflictd | Vpconflictq | Vplzcntd | Vplzcntq => {
+            Feature::Avx512cd
+        }
+        // Bytes and words: the integer instructions on those elements, and the packs,
+        // unpacks and shuffles to or from them
+        Packssdw | Packusdw | Punpcklwd | Punpckhwd | Palignr | Pmaddwd | Pslldq | Psrldq
+        | Vpmovb2m | Vpmovw2m | Vdbpsadbw => Feature::Avx512bw,
+        // But narrowing doublewords and quadwords came with F
+        Vpmovdb | Vpmovqb | Vpmovdw | Vpmovqw | Vpmovsdb | Vpmovsqb | Vpmovsdw | Vpmovsqw
+        | Vpmovusdb | Vpmovusqb | Vpmovusdw | Vpmovusqw => Feature::Avx512f,
+        mnemonic => {
+            let name = mnemonic.name();
+            let bytes_or_words = name.starts_with('P') || name.starts_with("Vp");
+            if bytes_or_words && (name.ends_with('b') || name.ends_with('w')) {
+                Feature::Avx512bw
+            } else {
+                Feature::Avx512f
+            }
+        }
+    }
+}
+
+/// Which of AVX and AVX2 a VEX-encoded vector instruction came with: AVX2 added the
+/// 256-bit forms of the integer instructions along with its own. FMA3, F16C, AES and
+/// PCLMULQDQ have feature bits of their own.
+fn avx_generation(insn: &Instruction) -> Feature {
+    use Mnemonic::*;
+    let name = insn.mnemonic.name();
+    if name.starts_with("Vfm") || name.starts_with("Vfnm") {
+        return Feature::Fma;
+    }
+    let avx2 = match insn.mnemonic {
+        Vcvtph2ps | Vcvtps2ph => return Feature::F16c,
+        Aesenc | Aesenclast | Aesdec | Aesdeclast | Aesimc | Aeskeygenassist => {
+            return Feature::Aes
+        }
+        Pclmulqdq => return Feature::Pclmulqdq,
+        Vpbroadcastb | Vpbroadcastw | Vpbroadcastd | Vpbroadcastq | Vbroadcasti128
+        | Vinserti128 | Vextracti128 | Vperm2i128 | Vpermd | Vpermps | Vpermq | Vpermpd
+        | Vpblendd | Vpmaskmovd | Vpmaskmovq | Vpsllvd | Vpsllvq | Vpsrlvd | Vpsrlvq | Vpsravd
+        | Vpgatherdd | Vpgatherdq | Vpgatherqd | Vpgatherqq | Vgatherdps | Vgatherdpd
+        | Vgatherqps | Vgatherqpd => true,
+        // AVX could only broadcast from memory
+        Vbroadcastss | Vbroadcastsd => matches!(insn.operand(1), Operand::Register(..)),
+        Ptest => false,
+        Movntdqa | Mpsadbw => insn.prefixes.contains(Prefixes::VEX_L),
+        mnemonic => insn.prefixes.contains(Prefixes::VEX_L) && mnemonic.name().starts_with('P'),
+    };
+    if avx2 {
+        Feature::Avx2
+    } else {
+        Feature::Avx
+    }
+}
+
+/// Whether an instruction came with SSE4.1.
+fn is_sse41(mnemonic: Mnemonic) -> bool {
+    use Mnemonic::*;
+    matches!(
+        mnemonic,
+        Blendps
+            | Blendpd
+            | Blendvps
+            | Blendvpd
+            | Pblendvb
+            | Pblendw
+            | Dpps
+            | Dppd
+            | Extractps
+            | Insertps
+            | Movntdqa
+            | Mpsadbw
+            | Packusdw
+            | Pcmpeqq
+            | Pextrb
+            | Pextrd
+            | Pextrq
+            | Pinsrb
+            | Pinsrd
+            | Pinsrq
+            | Phminposuw
+            | Pmaxsb
+            | Pmaxsd
+            | Pmaxud
+            | Pmaxuw
+            | Pminsb
+            | Pminsd
+            | Pminud
+            | Pminuw
+            | Pmovsxbw
+            | Pmovsxbd
+            | Pmovsxbq
+            | Pmovsxwd
+            | Pmovsxwq
+            | Pmovsxdq
+            | Pmovzxbw
+            | Pmovzxbd
+            | Pmovzxbq
+            | Pmovzxwd
+            | Pmovzxwq
+            | Pmovzxdq
+            | Pmuldq
+            | Pmulld
+            | Ptest
+            | Roundps
+            | Roundpd
+            | Roundss
+            | Roundsd
+    )
+}
+
+/// Whether an instruction on XMM or MMX registers came with SSE rather than SSE2 or MMX:
+/// the single-precision instructions, and the integer ones SSE added to MMX.
+fn is_sse1(mnemonic: Mnemonic) -> bool {
+    use Mnemonic::*;
+    matches!(
+        mnemonic,
+        Movups
+            | Movaps
+            | Movss
+            | Movlps
+            | Movhps
+            | Movhlps
+            | Movlhps
+            | Movntps
+            | Movmskps
+            | Unpcklps
+            | Unpckhps
+            | Shufps
+            | Andps
+            | Andnps
+            | Orps
+            | Xorps
+            | Addps
+            | Addss
+            | Subps
+            | Subss
+            | Mulps
+            | Mulss
+            | Divps
+            | Divss
+            | Minps
+            | Minss
+            | Maxps
+            | Maxss
+            | Sqrtps
+            | Sqrtss
+            | Rcpps
+            | Rcpss
+            | Rsqrtps
+            | Rsqrtss
+            | Cmpps
+            | Cmpss
+            | Comiss
+            | Ucomiss
+            | Cvtpi2ps
+            | Cvtps2pi
+            | Cvttps2pi
+            | Cvtsi2ss
+            | Cvtss2si
+            | Cvttss2si
+            | Pshufw
+            | Pinsrw
+            | Pextrw
+            | Pmovmskb
+            | Pminub
+            | Pmaxub
+            | Pminsw
+            | Pmaxsw
+            | Pavgb
+            | Pavgw
+            | Pmulhuw
+            | Psadbw
+            | Movntq
+            | Maskmovq
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+/// One level of the cache hierarchy, as described by CPUID leaf 4.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cache {
+    pub level: u8,
+    pub kind: CacheKind,
+    /// Total size in bytes
+    pub size: u32,
+    pub ways: u32,
+    pub line_size: u32,
+    /// Number of logical processors sharing the cache
+    pub shared_by: u32,
+}
+
+impl Cache {
+    const fn new(level: u8, kind: CacheKind, size: u32, ways: u32, shared_by: u32) -> Cache {
+        Cache {
+            level,
+            kind,
+            size,
+            ways,
+            line_size: 64,
+            shared_by,
+        }
+    }
+}
+
+/// Everything CPUID reports about the emulated processor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuModel {
+    /// Vendor identification string, such as `GenuineIntel`
+    pub vendor: [u8; 12],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    /// Processor brand string, up to 47 bytes
+    pub brand: String,
+    pub caches: Vec<Cache>,
+    pub linear_address_bits: u8,
+    pub physical_address_bits: u8,
+    /// The feature words of each (leaf, subleaf), indexed EAX, EBX, ECX, EDX. Only the bits
+    /// named by [`Feature`] are consulted.
+    features: BTreeMap<(u32, u32), [u32; 4]>,
+}
+
+const SKYLAKE_CACHES: [Cache; 4] = [
+    Cache::new(1, CacheKind::Data, 32 << 10, 8, 2),
+    Cache::new(1, CacheKind::Instruction, 32 << 10, 8, 2),
+    Cache::new(2, CacheKind::Unified, 256 << 10, 4, 2),
+    Cache::new(3, CacheKind::Unified, 8 << 20, 16, 8),
+];
+
+impl CpuModel {
+    fn generic(brand: &str, features: &[Feature]) -> CpuModel {
+        let model = CpuModel {
+            vendor: *b"GenuineIntel",
+            family: 6,
+            model: 0,
+            stepping: 0,
+            brand: brand.to_string(),
+            caches: SKYLAKE_CACHES.to_vec(),
+            linear_address_bits: 48,
+            physical_address_bits: 46,
+            features: BTreeMap::new(),
+        };
+        features
+            .iter()
+            .fold(model, |model, &feature| model.with(feature))
+    }
+
+    /// The x86-64 baseline: SSE2 and nothing later.
+    pub fn x86_64_v1() -> CpuModel {
+        CpuModel::generic("x86-64-v1 processor", &V1)
+    }
+
+    /// x86-64-v2: adds SSE3 through SSE4.2, POPCNT, CMPXCHG16B and LAHF/SAHF.
+    pub fn x86_64_v2() -> CpuModel {
+        CpuModel::generic("x86-64-v2 processor", &[&V1[..], &V2].concat())
+    }
+
+    /// x86-64-v3: adds AVX, AVX2, BMI1/BMI2, FMA, F16C, LZCNT and MOVBE.
+    pub fn x86_64_v3() -> CpuModel {
+        CpuModel::generic("x86-64-v3 processor", &[&V1[..], &V2, &V3].concat())
+    }
+
+    /// x86-64-v4: adds AVX512F, AVX512BW, AVX512CD, AVX512DQ and AVX512VL.
+    pub fn x86_64_v4() -> CpuModel {
+        CpuModel::generic("x86-64-v4 processor", &[&V1[..], &V2, &V3, &V4].concat())
+    }
+
+    /// A Skylake desktop part (Core i7-6700K).
+    pub fn skylake() -> CpuModel {
+        CpuModel {
+            model: 0x5E,
+            stepping: 3,
+            ..CpuModel::generic(
+                "Intel(R) Core(TM) i7-6700K CPU @ 4.00GHz",
+                &[&V1[..], &V2, &V3, &SKYLAKE].concat(),
+            )
+        }
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        let (leaf, subleaf, reg, bit) = feature.location();
+        self.features
+            .get(&(leaf, subleaf))
+            .is_some_and(|words| words[reg] & (1 << bit) != 0)
+    }
+
+    pub fn enable(&mut self, feature: Feature) {
+        let (leaf, subleaf, reg, bit) = feature.location();
+        self.features.entry((leaf, subleaf)).or_default()[reg] |= 1 << bit;
+    }
+
+    pub fn disable(&mut self, feature: Feature) {
+        let (leaf, subleaf, reg, bit) = feature.location();
+        if let Some(words) = self.features.get_mut(&(leaf, subleaf)) {
+            words[reg] &= !(1 << bit);
+        }
+    }
+
+    /// This model with `feature` added.
+    pub fn with(mut self, feature: Feature) -> CpuModel {
+        self.enable(feature);
+        self
+    }
+
+    /// This model with `feature` removed.
+    pub fn without(mut self, feature: Feature) -> CpuModel {
+        self.disable(feature);
+        self
+    }
+
+    /// The state components XCR0 can enable: x87 and SSE always, then AVX and the AVX-512
+    /// opmask and ZMM state if the model has them.
+    pub fn xcr0(&self) -> u64 {
+        let mut xcr0 = 0b11;
+        if self.has(Feature::Avx) {
+            xcr0 |= 0b100;
+        }
+        if self.has(Feature::Avx512f) {
+            xcr0 |= 0b1110_0000;
+        }
+        xcr0
+    }
+
+    /// The highest basic leaf this model reports.
+    fn max_leaf(&self) -> u32 {
+        0xD
+    }
+
+    /// The EAX, EBX, ECX and EDX that CPUID returns for `leaf` and `subleaf` once the
+    /// operating system has enabled XSAVE and every state component. The interpreter reports
+    /// OSXSAVE and the size of the XSAVE area from its own CR4 and XCR0 instead.
+    pub fn cpuid(&self, leaf: u32, subleaf: u32) -> [u32; 4] {
+        const MAX_EXTENDED: u32 = 0x8000_0008;
+        let features = |leaf, subleaf| {
+            self.features
+                .get(&(leaf, subleaf))
+                .copied()
+                .unwrap_or_default()
+        };
+        let vendor =
+            |i: usize| u32::from_le_bytes(self.vendor[i * 4..i * 4 + 4].try_into().unwrap());
+        match leaf {
+            0 => [self.max_leaf(), vendor(0), vendor(2), vendor(1)],
+            1 => {
+                let [_, _, ecx, edx] = features(1, 0);
+                // Families above 15 and models above 15 spill into the extended fields
+                let family = self.family.min(15) << 8 | self.family.saturating_sub(15) << 20;
+                let model = (self.model & 0xF) << 4 | (self.model >> 4) << 16;
+                let signature = family | model | (self.stepping & 0xF);
+                // CLFLUSH line size in qwords, and one logical processor
+                let ebx = 8 << 8 | 1 << 16;
+                [signature, ebx, ecx, edx]
+            }
+            4 => match self.caches.get(subleaf as usize) {
+                Some(cache) => {
+                    let kind = match cache.kind {
+                        CacheKind::Data => 1,
+                        CacheKind::Instruction => 2,
+                        CacheKind::Unified => 3,
+                    };
+                    let sets = cache.size / (cache.ways * cache.line_size);
+                    let eax =
+                        kind | (cache.level as u32) << 5 | 1 << 8 | (cache.shared_by - 1) << 14;
+                    let ebx = (cache.ways - 1) << 22 | (cache.line_size - 1);
+                    [eax, ebx, sets - 1, 0]
+                }
+                None => [0; 4],
+            },
+            7 => match subleaf {
+                0 => {
+                    let [_, ebx, ecx, edx] = features(7, 0);
+                    [0, ebx, ecx, edx]
+                }
+                _ => [0; 4],
+            },
+            0xD if self.has(Feature::Xsave) => match subleaf {
+                0 => {
+                    let size = xsave_size(self.xcr0());
+                    [self.xcr0() as u32, size, size, 0]
+                }
+                1 => [features(0xD, 1)[EAX], 0, 0, 0],
+                _ => XSAVE_COMPONENTS
+                    .iter()
+                    .find(|&&(component, ..)| {
+                        component == subleaf && self.xcr0() & 1 << component != 0
+                    })
+                    .map_or([0; 4], |&(_, size, offset)| [size, offset, 0, 0]),
+            },
+            0x8000_0000 => [MAX_EXTENDED, 0, 0, 0],
+            0x8000_0001 => {
+                let [_, _, ecx, edx] = features(0x8000_0001, 0);
+                [0, 0, ecx, edx]
+            }
+            0x8000_0002..=0x8000_0004 => {
+                let mut brand = [0u8; 48];
+                let len = self.brand.len().min(47);
+                brand[..len].copy_from_slice(&self.brand.as_bytes()[..len]);
+                let chunk = &brand[(leaf - 0x8000_0002) as usize * 16..][..16];
+                let word =
+                    |i: usize| u32::from_le_bytes(chunk[i * 4..i * 4 + 4].try_into().unwrap());
+                [word(0), word(1), word(2), word(3)]
+            }
+            0x8000_0006 => match self.caches.iter().find(|cache| cache.level == 2) {
+                Some(l2) => {
+                    // Intel only reports the size, line size and an associativity code here
+                    let ways = match l2.ways {
+                        16.. => 8,
+                        8.. => 6,
+                        4.. => 4,
+                        ways => ways,
+                    };
+                    [0, 0, (l2.size >> 10) << 16 | ways << 12 | l2.line_size, 0]
+                }
+                None => [0; 4],
+            },
+            0x8000_0008 => {
+                let widths =
+                    (self.linear_address_bits as u32) << 8 | self.physical_address_bits as u32;
+                [widths, 0, 0, 0]
+            }
+            // Out-of-range leaves return the highest basic leaf, as Intel processors do
+            0x8000_0000.. if leaf > MAX_EXTENDED => self.cpuid(self.max_leaf(), subleaf),
+            _ if leaf > self.max_leaf() && leaf < 0x8000_0000 => {
+                self.cpuid(self.max_leaf(), subleaf)
+            }
+            _ => [0; 4],
+        }
+    }
+}
+
+impl Default for CpuModel {
+    fn default() -> Self {
+        CpuModel::x86_64_v3()
+    }
+}
+
+/// The XSAVE state components past x87 and SSE, as their number, size and offset in the
+/// standard format: the upper halves of YMM0-YMM15, the opmask registers, the upper halves
+/// of ZMM0-ZMM15, and ZMM16-ZMM31.
+pub const XSAVE_COMPONENTS: [(u32, u32, u32); 4] = [
+    (2, 256, 576),
+    (5, 64, 1088),
+    (6, 512, 1152),
+    (7, 1024, 1664),
+];
+
+/// Size of the standard-format XSAVE area for the state components in `xcr0`: up to the end
+/// of the last of them.
+pub fn xsave_size(xcr0: u64) -> u32 {
+    XSAVE_COMPONENTS
+        .iter()
+        .filter(|&&(component, ..)| xcr0 & 1 << component != 0)
+        .map(|&(_, size, offset)| offset + size)
+        .fold(512 + 64, u32::max)
+}
+
+const V1: [Feature; 16] = [
+    Feature::Fpu,
+    Feature::Pse,
+    Feature::Pae,
+    Feature::Pge,
+    Feature::Tsc,
+    Feature::Cx8,
+    Feature::Cmov,
+    Feature::Clflush,
+    Feature::Mmx,
+    Feature::Fxsr,
+    Feature::Sse,
+    Feature::Sse2,
+    Feature::Syscall,
+    Feature::Nx,
+    Feature::Lm,
+    Feature::Rdtscp,
+];
+
+const V2: [Feature; 7] = [
+    Feature::Sse3,
+    Feature::Ssse3,
+    Feature::Sse41,
+    Feature::Sse42,
+    Feature::Cx16,
+    Feature::Popcnt,
+    Feature::LahfLm,
+];
+
+const V3: [Feature; 11] = [
+    Feature::Avx,
+    Feature::Avx2,
+    Feature::Bmi1,
+    Feature::Bmi2,
+    Feature::Fma,
+    Feature::F16c,
+    Feature::Abm,
+    Feature::Movbe,
+    Feature::Xsave,
+    Feature::Osxsave,
+    Feature::Xsaveopt,
+];
+
+const V4: [Feature; 5] = [
+    Feature::Avx512f,
+    Feature::Avx512bw,
+    Feature::Avx512cd,
+    Feature::Avx512dq,
+    Feature::Avx512vl,
+];
+
+const SKYLAKE: [Feature; 12] = [
+    Feature::Aes,
+    Feature::Pclmulqdq,
+    Feature::Rdrand,
+    Feature::Rdseed,
+    Feature::Fsgsbase,
+    Feature::Erms,
+    Feature::Clflushopt,
+    Feature::Prefetchw,
+    Feature::Pdpe1gb,
+    Feature::Xsavec,
+    Feature::Pcid,
+    Feature::Invpcid,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(words: &[u32]) -> String {
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        String::from_utf8(bytes)
+            .unwrap()
+            .trim_end_matches('\0')
+            .to_string()
+    }
+
+    #[test]
+    fn vendor_and_brand_strings() {
+        let model = CpuModel::skylake();
+        let [max, ebx, ecx, edx] = model.cpuid(0, 0);
+        assert_eq!(max, 0xD);
+        assert_eq!(string(&[ebx, edx, ecx]), "GenuineIntel");
+        let brand: Vec<u32> = (0x8000_0002..=0x8000_0004)
+            .flat_map(|leaf| model.cpuid(leaf, 0))
+            .collect();
+        assert_eq!(string(&brand), "Intel(R) Core(TM) i7-6700K CPU @ 4.00GHz");
+    }
+
+    #[test]
+    fn signature_and_feature_bits() {
+        let model = CpuModel::skylake();
+        assert_eq!(model.cpuid(1, 0)[EAX], 0x506E3);
+        assert_ne!(model.cpuid(7, 0)[EBX] & 1 << 5, 0);
+        let v1 = CpuModel::x86_64_v1();
+        assert_eq!(v1.cpuid(7, 0)[EBX], 0);
+        assert_ne!(v1.cpuid(1, 0)[EDX] & 1 << 26, 0);
+        assert!(!v1.has(Feature::Popcnt));
+        // Like AES, carry-less multiplication is in no x86-64 level
+        assert!(!CpuModel::x86_64_v4().has(Feature::Pclmulqdq));
+        assert!(model.has(Feature::Pclmulqdq));
+        // ADCX and ADOX aren't executed, so no preset advertises them
+        assert!(!model.has(Feature::Adx));
+        assert!(!CpuModel::x86_64_v3()
+            .without(Feature::Bmi2)
+            .has(Feature::Bmi2));
+    }
+
+    #[test]
+    fn cache_leaves() {
+        let model = CpuModel::skylake();
+        // L2: unified, level 2, 4 ways of 1024 sets of 64-byte lines
+        let [eax, ebx, ecx, _] = model.cpuid(4, 2);
+        assert_eq!(eax & 0xFF, 3 | 2 << 5);
+        assert_eq!(
+            ((ebx >> 22) + 1) * ((ebx & 0xFFF) + 1) * (ecx + 1),
+            256 << 10
+        );
+        assert_eq!(model.cpuid(4, 4), [0; 4]);
+    }
+
+    #[test]
+    fn xsave_leaf() {
+        // The reported size covers the last component, ZMM16-ZMM31 at 1664
+        let v4 = CpuModel::x86_64_v4();
+        assert_eq!(v4.cpuid(0xD, 0), [0xE7, 2688, 2688, 0]);
+        assert_eq!(v4.cpuid(0xD, 7), [1024, 1664, 0, 0]);
+        let v3 = CpuModel::x86_64_v3();
+        assert_eq!(v3.cpuid(0xD, 0), [0x7, 832, 832, 0]);
+        assert_eq!(v3.cpuid(0xD, 2), [256, 576, 0, 0]);
+        assert_eq!(v3.cpuid(0xD, 5), [0; 4]);
+    }
+}
@@ -0,0 +1,3371 @@
+//! A side-effect-free decoder for x86-64 machine code.
+//!
+//! [`decode`] turns the bytes at an address into an [`Instruction`] describing its prefixes,
+//! opcode, operands and length without touching any architectural state, so execution,
+//! tracing and disassembly all share one description of the encoding. Besides 64-bit code it
+//! decodes the 32- and 16-bit code of the other modes, which the [`Mode`] selects.
+
+use super::exception::Exception;
+use bitflags::bitflags;
+use std::fmt;
+
+mod simd;
+
+/// The longest encoding the processor accepts; anything longer raises #GP(0).
+pub const MAX_INSTRUCTION_LENGTH: u8 = 15;
+
+/// The kind of code being decoded, which decides the default operand and address sizes and
+/// which encodings exist: 64-bit mode, or a 32- or 16-bit code segment of compatibility,
+/// protected or real mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    Bits16,
+    Bits32,
+    #[default]
+    Bits64,
+}
+
+impl Mode {
+    /// The default address size, which is also the width of the instruction pointer.
+    pub fn address_size(self) -> OperandSize {
+        match self {
+            Mode::Bits16 => OperandSize::R16,
+            Mode::Bits32 => OperandSize::R32,
+            Mode::Bits64 => OperandSize::R64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperandSize {
+    R8,
+    R16,
+    R32,
+    R64,
+    /// An x87 register or extended-precision memory operand
+    R80,
+    /// An XMM register, or CMPXCHG16B's memory operand
+    R128,
+    /// A YMM register
+    R256,
+    /// A ZMM register
+    R512,
+}
+
+impl OperandSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            OperandSize::R8 => 1,
+            OperandSize::R16 => 2,
+            OperandSize::R32 => 4,
+            OperandSize::R64 => 8,
+            OperandSize::R80 => 10,
+            OperandSize::R128 => 16,
+            OperandSize::R256 => 32,
+            OperandSize::R512 => 64,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        self.bytes() as u32 * 8
+    }
+
+    /// A mask covering every bit of a value of this size (all 64 for wider sizes).
+    pub fn mask(self) -> u64 {
+        u64::MAX >> (64 - self.bits().min(64))
+    }
+}
+
+bitflags! {
+    pub struct Prefixes: u64 {
+        const NONE = 0b00000000;
+        const REP = 0b00000001;
+        const OPSIZE = 0b00000010;
+        const REX = 0b00000100;
+        const REX_B = 0b00001000;
+        const REX_X = 0b00010000;
+        const REX_R = 0b00100000;
+        const REX_W = 0b01000000;
+        const ADDRSIZE = 0b10000000;
+        const REPNE = 0b100000000;
+        const LOCK = 0b1000000000;
+        /// The instruction was encoded with a two- or three-byte VEX prefix, whose R/X/B/W
+        /// bits are reported through the REX flags
+        const VEX = 0b10000000000;
+        /// VEX.L: the instruction operates on 256-bit vectors
+        const VEX_L = 0b100000000000;
+        /// The instruction was encoded with an EVEX prefix, whose R/X/B/W bits are reported
+        /// through the REX flags, and EVEX.L'L through `VEX_L` and `EVEX_L2`
+        const EVEX = 0b1000000000000;
+        /// EVEX.L'L = 2: the instruction operates on 512-bit vectors
+        const EVEX_L2 = 0b10000000000000;
+    }
+}
+
+/// The opcode table an instruction's opcode byte is looked up in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpcodeMap {
+    Primary,
+    Map0F,
+    Map0F38,
+    Map0F3A,
+}
+
+/// An architectural register named by an operand. General-purpose registers are numbered
+/// 0-15 in encoding order and sized by the operand that names them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    Gpr(u8),
+    /// AH, CH, DH or BH: byte registers 4-7 when no REX prefix is present
+    HighByte(u8),
+    Segment(u8),
+    Control(u8),
+    Debug(u8),
+    /// x87 stack register ST(i), relative to the top of the stack
+    St(u8),
+    /// MMX register, an alias of the significand of x87 register R(i)
+    Mm(u8),
+    /// SSE, AVX or AVX-512 register XMM0-XMM31/YMM0-YMM31/ZMM0-ZMM31, sized by the operand
+    /// that names it: scalar operands only cover its low element
+    Vector(u8),
+    /// AVX-512 opmask register k0-k7
+    Opmask(u8),
+}
+
+/// A memory reference encoded by a ModR/M byte and optional SIB byte, or implied by the
+/// opcode (string instructions, `moffs`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryOperand {
+    pub base: Option<u8>,
+    pub index: Option<u8>,
+    pub scale: u8,
+    pub displacement: i64,
+    pub rip_relative: bool,
+    pub address_size: OperandSize,
+    /// VSIB addressing (the AVX2 gathers): `index` names a vector register of this size,
+    /// each of whose elements indexes memory separately
+    pub vector_index: Option<OperandSize>,
+    /// Segment register named by a segment-override prefix (or ES for a string
+    /// destination outside 64-bit mode), whose base is added to the address. `None` means
+    /// the default segment: SS for addresses based on RSP or RBP, DS otherwise.
+    pub segment: Option<u8>,
+}
+
+/// The `r/m` half of a ModR/M byte.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModRM {
+    Register(u8),
+    Memory(MemoryOperand),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    None,
+    Register(Register, OperandSize),
+    Memory(MemoryOperand, OperandSize),
+    /// An immediate, already sign- or zero-extended to 64 bits as the encoding dictates
+    Immediate(u64, OperandSize),
+    /// A branch displacement relative to the end of the instruction
+    Relative(i64),
+    /// A far pointer: a selector and an offset into its segment
+    Far(u16, u64),
+    /// An EVEX memory operand of one element, of the first size, repeated across a vector
+    /// of the second
+    Broadcast(MemoryOperand, OperandSize, OperandSize),
+}
+
+impl Operand {
+    pub fn size(&self) -> Option<OperandSize> {
+        match *self {
+            Operand::Register(_, size)
+            | Operand::Memory(_, size)
+            | Operand::Immediate(_, size)
+            | Operand::Broadcast(_, _, size) => Some(size),
+            Operand::None | Operand::Relative(_) | Operand::Far(..) => None,
+        }
+    }
+}
+
+macro_rules! mnemonics {
+    ($($name:ident),* $(,)?) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum Mnemonic {
+            $($name),*
+        }
+
+        impl Mnemonic {
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Mnemonic::$name => stringify!($name)),*
+                }
+            }
+        }
+    };
+}
+
+mnemonics! {
+    Aaa, Aad, Aam, Aas, Adc, Add, Addpd, Addps, Addsd, Addss, Addsubpd, Addsubps, Aesdec,
+    Aesdeclast, Aesenc, Aesenclast, Aesimc, Aeskeygenassist, And, Andn, Andnpd, Andnps, Andpd,
+    Andps, Arpl, Bextr, Blendpd, Blendps, Blendvpd, Blendvps, Blsi, Blsmsk, Blsr, Bound, Bsf, Bsr,
+    Bswap, Bt, Btc, Btr, Bts, Bzhi, Call, Callf, Cbw, Cdq, Cdqe, Clc, Cld, Clflush, Cli, Clts, Cmc,
+    Cmovcc, Cmp, Cmppd, Cmpps, Cmps, Cmpsd, Cmpss, Cmpxchg, Cmpxchg8b, Cmpxchg16b, Comisd, Comiss,
+    Cpuid, Cqo, Crc32, Cvtdq2pd, Cvtdq2ps, Cvtpd2dq, Cvtpd2pi, Cvtpd2ps, Cvtpi2pd, Cvtpi2ps,
+    Cvtps2dq, Cvtps2pd, Cvtps2pi, Cvtsd2si, Cvtsd2ss, Cvtsi2sd, Cvtsi2ss, Cvtss2sd, Cvtss2si,
+    Cvttpd2dq, Cvttpd2pi, Cvttps2dq, Cvttps2pi, Cvttsd2si, Cvttss2si, Cwd, Cwde, Daa, Das, Dec, Div,
+    Divpd, Divps, Divsd, Divss, Dppd, Dpps, Emms, Endbr32, Endbr64, Enter, Extractps, F2xm1, Fabs,
+    Fadd, Faddp, Fbld, Fbstp, Fchs, Fcmovcc, Fcom, Fcomi, Fcomip, Fcomp, Fcompp, Fcos, Fdecstp,
+    Fdiv, Fdivp, Fdivr, Fdivrp, Ffree, Ffreep, Fiadd, Ficom, Ficomp, Fidiv, Fidivr, Fild, Fimul,
+    Fincstp, Fist, Fistp, Fisttp, Fisub, Fisubr, Fld, Fld1, Fldcw, Fldenv, Fldl2e, Fldl2t, Fldlg2,
+    Fldln2, Fldpi, Fldz, Fmul, Fmulp, Fnclex, Fninit, Fnop, Fnsave, Fnstcw, Fnstenv, Fnstsw, Fpatan,
+    Fprem, Fprem1, Fptan, Frndint, Frstor, Fscale, Fsin, Fsincos, Fsqrt, Fst, Fstp, Fsub, Fsubp,
+    Fsubr, Fsubrp, Ftst, Fucom, Fucomi, Fucomip, Fucomp, Fucompp, Fwait, Fxam, Fxch, Fxrstor,
+    Fxsave, Fxtract, Fyl2x, Fyl2xp1, Haddpd, Haddps, Hlt, Hsubpd, Hsubps, Idiv, Imul, In, Inc, Ins,
+    Insertps, Int, Int1, Int3, Into, Invd, Invlpg, Invpcid, Iret, Iretd, Iretq, Jcc, Jmp, Jmpf,
+    Jrcxz, Kaddb, Kaddd, Kaddq, Kaddw, Kandb, Kandd, Kandnb, Kandnd, Kandnq, Kandnw, Kandq, Kandw,
+    Kmovb, Kmovd, Kmovq, Kmovw, Knotb, Knotd, Knotq, Knotw, Korb, Kord, Korq, Kortestb, Kortestd,
+    Kortestq, Kortestw, Korw, Kshiftlb, Kshiftld, Kshiftlq, Kshiftlw, Kshiftrb, Kshiftrd, Kshiftrq,
+    Kshiftrw, Ktestb, Ktestd, Ktestq, Ktestw, Kunpckbw, Kunpckdq, Kunpckwd, Kxnorb, Kxnord, Kxnorq,
+    Kxnorw, Kxorb, Kxord, Kxorq, Kxorw, Lahf, Lar, Lddqu, Ldmxcsr, Lds, Lea, Leave, Les, Lfence,
+    Lfs, Lgdt, Lgs, Lidt, Lldt, Lmsw, Lods, Loop, Loope, Loopne, Lsl, Lss, Ltr, Lzcnt, Maskmovdqu,
+    Maskmovq, Maxpd, Maxps, Maxsd, Maxss, Mfence, Minpd, Minps, Minsd, Minss, Mov, Movapd, Movaps,
+    Movd, Movddup, Movdq2q, Movdqa, Movdqu, Movhlps, Movhpd, Movhps, Movlhps, Movlpd, Movlps,
+    Movmskpd, Movmskps, Movntdq, Movntdqa, Movnti, Movntpd, Movntps, Movntq, Movq, Movq2dq, Movs,
+    Movsd, Movshdup, Movsldup, Movss, Movsx, Movsxd, Movbe, Movupd, Movups, Movzx, Mpsadbw, Mul,
+    Mulpd, Mulps, Mulsd, Mulss, Mulx, Neg, Nop, Not, Or, Orpd, Orps, Out, Outs, Pabsb, Pabsd, Pabsw,
+    Packssdw, Packsswb, Packusdw, Packuswb, Paddb, Paddd, Paddq, Paddsb, Paddsw, Paddusb, Paddusw,
+    Paddw, Palignr, Pand, Pandn, Pause, Pavgb, Pavgw, Pblendvb, Pblendw, Pclmulqdq, Pcmpeqb,
+    Pcmpeqd, Pcmpeqq, Pcmpeqw, Pcmpestri, Pcmpestrm, Pcmpgtb, Pcmpgtd, Pcmpgtq, Pcmpgtw, Pcmpistri,
+    Pcmpistrm, Pdep, Pext, Pextrb, Pextrd, Pextrq, Pextrw, Phaddd, Phaddsw, Phaddw, Phminposuw,
+    Phsubd, Phsubsw, Phsubw, Pinsrb, Pinsrd, Pinsrq, Pinsrw, Pmaddubsw, Pmaddwd, Pmaxsb, Pmaxsd,
+    Pmaxsw, Pmaxub, Pmaxud, Pmaxuw, Pminsb, Pminsd, Pminsw, Pminub, Pminud, Pminuw, Pmovmskb,
+    Pmovsxbd, Pmovsxbq, Pmovsxbw, Pmovsxdq, Pmovsxwd, Pmovsxwq, Pmovzxbd, Pmovzxbq, Pmovzxbw,
+    Pmovzxdq, Pmovzxwd, Pmovzxwq, Pmuldq, Pmulhrsw, Pmulhuw, Pmulhw, Pmulld, Pmullw, Pmuludq, Pop,
+    Popa, Popcnt, Popf, Por, Psadbw, Pshufb, Pshufd, Pshufhw, Pshuflw, Pshufw, Psignb, Psignd,
+    Psignw, Pslld, Pslldq, Psllq, Psllw, Psrad, Psraw, Psrld, Psrldq, Psrlq, Psrlw, Psubb, Psubd,
+    Psubq, Psubsb, Psubsw, Psubusb, Psubusw, Psubw, Ptest, Punpckhbw, Punpckhdq, Punpckhqdq,
+    Punpckhwd, Punpcklbw, Punpckldq, Punpcklqdq, Punpcklwd, Push, Pusha, Pushf, Pxor, Rcl, Rcpps,
+    Rcpss, Rcr, Rdfsbase, Rdgsbase, Rdmsr, Rdpmc, Rdrand, Rdseed, Rdtsc, Rdtscp, Ret, Retf, Rol,
+    Ror, Rorx, Roundpd, Roundps, Roundsd, Roundss, Rsm, Rsqrtps, Rsqrtss, Sahf, Salc, Sar, Sarx,
+    Sbb, Scas, Setcc, Sfence, Sgdt, Sha1msg1, Sha1msg2, Sha1nexte, Sha1rnds4, Sha256msg1,
+    Sha256msg2, Sha256rnds2, Shl, Shld, Shlx, Shr, Shrd, Shrx, Shufpd, Shufps, Sidt, Sldt, Smsw,
+    Sqrtpd, Sqrtps, Sqrtsd, Sqrtss, Stc, Std, Sti, Stmxcsr, Stos, Str, Sub, Subpd, Subps, Subsd,
+    Subss, Swapgs, Syscall, Sysenter, Sysexit, Sysret, Test, Tzcnt, Ucomisd, Ucomiss, Ud0, Ud1, Ud2,
+    Unpckhpd, Unpckhps, Unpcklpd, Unpcklps, Valignd, Valignq, Vblendmpd, Vblendmps, Vbroadcastf128,
+    Vbroadcastf32x2, Vbroadcastf32x4, Vbroadcastf32x8, Vbroadcastf64x2, Vbroadcastf64x4,
+    Vbroadcasti128, Vbroadcasti32x2, Vbroadcasti32x4, Vbroadcasti32x8, Vbroadcasti64x2,
+    Vbroadcasti64x4, Vbroadcastsd, Vbroadcastss, Vcompresspd, Vcompressps, Vcvtpd2qq, Vcvtpd2udq,
+    Vcvtpd2uqq, Vcvtph2ps, Vcvtps2ph, Vcvtps2qq, Vcvtps2udq, Vcvtps2uqq, Vcvtqq2pd, Vcvtqq2ps,
+    Vcvtsd2usi, Vcvtss2usi, Vcvttpd2qq, Vcvttpd2udq, Vcvttpd2uqq, Vcvttps2qq, Vcvttps2udq,
+    Vcvttps2uqq, Vcvttsd2usi, Vcvttss2usi, Vcvtudq2pd, Vcvtudq2ps, Vcvtuqq2pd, Vcvtuqq2ps,
+    Vcvtusi2sd, Vcvtusi2ss, Vdbpsadbw, Verr, Verw, Vexpandpd, Vexpandps, Vextractf128,
+    Vextractf32x4, Vextractf32x8, Vextractf64x2, Vextractf64x4, Vextracti128, Vextracti32x4,
+    Vextracti32x8, Vextracti64x2, Vextracti64x4, Vfixupimmpd, Vfixupimmps, Vfixupimmsd, Vfixupimmss,
+    Vfmadd132pd, Vfmadd132ps, Vfmadd132sd, Vfmadd132ss, Vfmadd213pd, Vfmadd213ps, Vfmadd213sd,
+    Vfmadd213ss, Vfmadd231pd, Vfmadd231ps, Vfmadd231sd, Vfmadd231ss, Vfmaddsub132pd, Vfmaddsub132ps,
+    Vfmaddsub213pd, Vfmaddsub213ps, Vfmaddsub231pd, Vfmaddsub231ps, Vfmsub132pd, Vfmsub132ps,
+    Vfmsub132sd, Vfmsub132ss, Vfmsub213pd, Vfmsub213ps, Vfmsub213sd, Vfmsub213ss, Vfmsub231pd,
+    Vfmsub231ps, Vfmsub231sd, Vfmsub231ss, Vfmsubadd132pd, Vfmsubadd132ps, Vfmsubadd213pd,
+    Vfmsubadd213ps, Vfmsubadd231pd, Vfmsubadd231ps, Vfnmadd132pd, Vfnmadd132ps, Vfnmadd132sd,
+    Vfnmadd132ss, Vfnmadd213pd, Vfnmadd213ps, Vfnmadd213sd, Vfnmadd213ss, Vfnmadd231pd,
+    Vfnmadd231ps, Vfnmadd231sd, Vfnmadd231ss, Vfnmsub132pd, Vfnmsub132ps, Vfnmsub132sd,
+    Vfnmsub132ss, Vfnmsub213pd, Vfnmsub213ps, Vfnmsub213sd, Vfnmsub213ss, Vfnmsub231pd,
+    Vfnmsub231ps, Vfnmsub231sd, Vfnmsub231ss, Vfpclasspd, Vfpclassps, Vfpclasssd, Vfpclassss,
+    Vgatherdpd, Vgatherdps, Vgatherqpd, Vgatherqps, Vgetexppd, Vgetexpps, Vgetexpsd, Vgetexpss,
+    Vgetmantpd, Vgetmantps, Vgetmantsd, Vgetmantss, Vinsertf128, Vinsertf32x4, Vinsertf32x8,
+    Vinsertf64x2, Vinsertf64x4, Vinserti128, Vinserti32x4, Vinserti32x8, Vinserti64x2, Vinserti64x4,
+    Vmaskmovpd, Vmaskmovps, Vmovdqa32, Vmovdqa64, Vmovdqu16, Vmovdqu32, Vmovdqu64, Vmovdqu8, Vpabsq,
+    Vpandd, Vpandnd, Vpandnq, Vpandq, Vpblendd, Vpblendmb, Vpblendmd, Vpblendmq, Vpblendmw,
+    Vpbroadcastb, Vpbroadcastd, Vpbroadcastmb2q, Vpbroadcastmw2d, Vpbroadcastq, Vpbroadcastw,
+    Vpcmpb, Vpcmpd, Vpcmpq, Vpcmpub, Vpcmpud, Vpcmpuq, Vpcmpuw, Vpcmpw, Vpcompressd, Vpcompressq,
+    Vpconflictd, Vpconflictq, Vperm2f128, Vperm2i128, Vpermd, Vpermi2d, Vpermi2pd, Vpermi2ps,
+    Vpermi2q, Vpermi2w, Vpermilpd, Vpermilps, Vpermpd, Vpermps, Vpermq, Vpermt2d, Vpermt2pd,
+    Vpermt2ps, Vpermt2q, Vpermt2w, Vpermw, Vpexpandd, Vpexpandq, Vpgatherdd, Vpgatherdq, Vpgatherqd,
+    Vpgatherqq, Vplzcntd, Vplzcntq, Vpmaskmovd, Vpmaskmovq, Vpmaxsq, Vpmaxuq, Vpminsq, Vpminuq,
+    Vpmovb2m, Vpmovd2m, Vpmovdb, Vpmovdw, Vpmovm2b, Vpmovm2d, Vpmovm2q, Vpmovm2w, Vpmovq2m, Vpmovqb,
+    Vpmovqd, Vpmovqw, Vpmovsdb, Vpmovsdw, Vpmovsqb, Vpmovsqd, Vpmovsqw, Vpmovswb, Vpmovusdb,
+    Vpmovusdw, Vpmovusqb, Vpmovusqd, Vpmovusqw, Vpmovuswb, Vpmovw2m, Vpmovwb, Vpmullq, Vpord, Vporq,
+    Vprold, Vprolq, Vprolvd, Vprolvq, Vprord, Vprorq, Vprorvd, Vprorvq, Vpscatterdd, Vpscatterdq,
+    Vpscatterqd, Vpscatterqq, Vpsllvd, Vpsllvq, Vpsllvw, Vpsraq, Vpsravd, Vpsravq, Vpsravw, Vpsrlvd,
+    Vpsrlvq, Vpsrlvw, Vpternlogd, Vpternlogq, Vptestmb, Vptestmd, Vptestmq, Vptestmw, Vptestnmb,
+    Vptestnmd, Vptestnmq, Vptestnmw, Vpxord, Vpxorq, Vrangepd, Vrangeps, Vrangesd, Vrangess,
+    Vrcp14pd, Vrcp14ps, Vrcp14sd, Vrcp14ss, Vreducepd, Vreduceps, Vreducesd, Vreducess, Vrndscalepd,
+    Vrndscaleps, Vrndscalesd, Vrndscaless, Vrsqrt14pd, Vrsqrt14ps, Vrsqrt14sd, Vrsqrt14ss,
+    Vscalefpd, Vscalefps, Vscalefsd, Vscalefss, Vscatterdpd, Vscatterdps, Vscatterqpd, Vscatterqps,
+    Vshuff32x4, Vshuff64x2, Vshufi32x4, Vshufi64x2, Vtestpd, Vtestps, Vzeroall, Vzeroupper, Wbinvd,
+    Wrfsbase, Wrgsbase, Wrmsr, Xadd, Xchg, Xgetbv, Xlat, Xor, Xorpd, Xorps, Xrstor, Xsave, Xsavec,
+    Xsaveopt, Xsetbv,
+}
+
+impl Mnemonic {
+    /// Whether this is an x87 instruction, including FWAIT.
+    pub fn is_x87(self) -> bool {
+        matches!(
+            self,
+            F2xm1
+                | Fabs
+                | Fadd
+                | Faddp
+                | Fbld
+                | Fbstp
+                | Fchs
+                | Fcmovcc
+                | Fcom
+                | Fcomi
+                | Fcomip
+                | Fcomp
+                | Fcompp
+                | Fcos
+                | Fdecstp
+                | Fdiv
+                | Fdivp
+                | Fdivr
+                | Fdivrp
+                | Ffree
+                | Ffreep
+                | Fiadd
+                | Ficom
+                | Ficomp
+                | Fidiv
+                | Fidivr
+                | Fild
+                | Fimul
+                | Fincstp
+                | Fist
+                | Fistp
+                | Fisttp
+                | Fisub
+                | Fisubr
+                | Fld
+                | Fld1
+                | Fldcw
+                | Fldenv
+                | Fldl2e
+                | Fldl2t
+                | Fldlg2
+                | Fldln2
+                | Fldpi
+                | Fldz
+                | Fmul
+                | Fmulp
+                | Fnclex
+                | Fninit
+                | Fnop
+                | Fnsave
+                | Fnstcw
+                | Fnstenv
+                | Fnstsw
+                | Fpatan
+                | Fprem
+                | Fprem1
+                | Fptan
+                | Frndint
+                | Frstor
+                | Fscale
+                | Fsin
+                | Fsincos
+                | Fsqrt
+                | Fst
+                | Fstp
+                | Fsub
+                | Fsubp
+                | Fsubr
+                | Fsubrp
+                | Ftst
+                | Fucom
+                | Fucomi
+                | Fucomip
+                | Fucomp
+                | Fucompp
+                | Fwait
+                | Fxam
+                | Fxch
+                | Fxtract
+                | Fyl2x
+                | Fyl2xp1
+        )
+    }
+}
+
+/// What an EVEX prefix adds to an AVX-512 instruction besides its operands.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Evex {
+    /// The opmask register (EVEX.aaa) selecting the elements written, or 0 for all of them
+    pub mask: u8,
+    /// Masked-off elements are zeroed (EVEX.z) rather than left alone
+    pub zeroing: bool,
+    /// A static rounding mode (EVEX.RC, encoded as MXCSR.RC) replacing MXCSR's for this
+    /// instruction, which also suppresses its floating-point exceptions
+    pub rounding: Option<u8>,
+    /// Floating-point exceptions are suppressed (SAE)
+    pub sae: bool,
+    /// The vector length, for instructions that have a choice of one
+    pub vector_length: Option<OperandSize>,
+}
+
+/// A fully decoded instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Instruction {
+    /// Address of the first byte of the instruction
+    pub address: u64,
+    pub length: u8,
+    pub prefixes: Prefixes,
+    /// Segment register named by a segment-override prefix, if any
+    pub segment: Option<u8>,
+    pub map: OpcodeMap,
+    pub opcode: u8,
+    pub mnemonic: Mnemonic,
+    /// Condition code (low nibble of the opcode) for Jcc, SETcc and CMOVcc, and the
+    /// equivalent code for FCMOVcc
+    pub condition: u8,
+    /// Effective operand size of the instruction
+    pub operand_size: OperandSize,
+    /// Effective address size of the instruction
+    pub address_size: OperandSize,
+    /// The ModR/M byte, if the encoding has one
+    pub modrm: Option<u8>,
+    /// The AVX-512 controls of an EVEX-encoded instruction
+    pub evex: Option<Evex>,
+    operands: [Operand; 4],
+    operand_count: u8,
+    nds: bool,
+}
+
+impl Instruction {
+    pub fn operands(&self) -> &[Operand] {
+        &self.operands[..self.operand_count as usize]
+    }
+
+    /// Returns operand `n`, or [`Operand::None`] if the instruction has fewer operands.
+    pub fn operand(&self, n: usize) -> Operand {
+        self.operands().get(n).copied().unwrap_or(Operand::None)
+    }
+
+    /// The first immediate operand, if any.
+    pub fn immediate(&self) -> Option<u64> {
+        self.operands().iter().find_map(|op| match op {
+            Operand::Immediate(value, _) => Some(*value),
+            _ => None,
+        })
+    }
+
+    /// Address of the instruction following this one.
+    pub fn next_address(&self) -> u64 {
+        self.address.wrapping_add(self.length as u64)
+    }
+
+    /// Whether the instruction has an SSE register operand.
+    pub fn is_sse(&self) -> bool {
+        self.operands()
+            .iter()
+            .any(|operand| matches!(operand, Operand::Register(Register::Vector(_), _)))
+    }
+
+    /// Whether this is an AVX-512 instruction on opmask registers alone.
+    pub fn is_opmask(&self) -> bool {
+        !self.is_sse()
+            && self
+                .operands()
+                .iter()
+                .any(|operand| matches!(operand, Operand::Register(Register::Opmask(_), _)))
+    }
+
+    /// Whether the second operand of a VEX- or EVEX-encoded vector instruction is its first
+    /// source, rather than the destination also being one.
+    pub fn is_nds(&self) -> bool {
+        self.nds
+    }
+
+    /// Whether this is an MMX instruction: EMMS, or one with an MMX register operand.
+    pub fn is_mmx(&self) -> bool {
+        self.mnemonic == Mnemonic::Emms
+            || self
+                .operands()
+                .iter()
+                .any(|operand| matches!(operand, Operand::Register(Register::Mm(_), _)))
+    }
+}
+
+// Operand specifications, named after the addressing-method/operand-type codes of the
+// opcode tables in the Intel SDM, volume 2, appendix A.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Spec {
+    Eb,
+    Ew,
+    Ed,
+    Ev,
+    Gb,
+    /// Word register in ModR/M `reg` (ARPL)
+    Gw,
+    Gv,
+    /// Doubleword or quadword ModR/M operand, by REX.W/VEX.W alone
+    Ey,
+    /// Doubleword or quadword register in ModR/M `reg`, by REX.W/VEX.W alone
+    Gy,
+    /// Register in ModR/M `reg`, 64 bits wide in 64-bit mode and 32 bits otherwise
+    /// (INVPCID)
+    Gq,
+    /// Doubleword or quadword register selected by VEX.vvvv
+    By,
+    /// Memory-only ModR/M operand of the effective operand size
+    M,
+    /// Memory-only ModR/M operand whose size isn't meaningful
+    Mb,
+    /// Word, doubleword and extended-precision (80-bit) memory operands of x87 instructions
+    Mw,
+    Md,
+    Mt,
+    /// Far pointer in memory
+    Mp,
+    /// Far pointer encoded in the instruction: an offset of the operand size, then a
+    /// selector
+    Ap,
+    /// Pseudo-descriptor in memory
+    Ms,
+    /// Quadword in memory
+    Mq,
+    /// Double quadword in memory
+    Mdq,
+    /// Register of the effective operand size or a 16-bit memory operand (MOV from a segment
+    /// register)
+    RvMw,
+    /// Register-only ModR/M operand, 64 bits wide (MOV to/from control registers)
+    Rq,
+    Sw,
+    Cq,
+    Dq,
+    Ib,
+    /// Imm8 sign-extended to the effective operand size
+    Ibs,
+    Iw,
+    /// Imm16 or imm32, sign-extended to the effective operand size
+    Iz,
+    /// Immediate as wide as the effective operand size (MOV r64, imm64)
+    Iv,
+    Jb,
+    Jz,
+    Al,
+    /// rAX at the effective operand size
+    Ax,
+    /// AX regardless of the operand size (FNSTSW)
+    Aw,
+    Cl,
+    Dx,
+    /// Byte register encoded in the low bits of the opcode
+    Zb,
+    /// Register of the effective operand size encoded in the low bits of the opcode
+    Zv,
+    /// Byte at an absolute address encoded in the instruction
+    Ob,
+    Ov,
+    /// String source at rSI
+    Xb,
+    Xv,
+    /// String destination at rDI
+    Yb,
+    Yv,
+    One,
+    /// A segment register implied by the opcode
+    Es,
+    Cs,
+    Ss,
+    Ds,
+    Fs,
+    Gs,
+    /// The top of the x87 stack
+    St0,
+    /// x87 stack register selected by ModR/M `r/m`
+    StI,
+    /// MMX register in ModR/M `reg`
+    Pq,
+    /// MMX register or memory operand, 64 or 32 bits wide
+    Qq,
+    Qd,
+    /// MMX register in ModR/M `r/m`
+    Nq,
+    /// XMM or YMM register in ModR/M `reg`: the whole vector (256 bits with VEX.L), or its
+    /// low quadword or doubleword
+    Vx,
+    Vq,
+    Vd,
+    /// The low 128 bits of the register in ModR/M `reg`, whatever the vector length
+    Vdq,
+    /// The register in ModR/M `reg`, half as wide as the vector: the destination of a
+    /// narrowing conversion
+    Vh,
+    /// XMM or YMM register or memory operand: the whole vector, or a 64-, 32-, 16- or 8-bit
+    /// scalar
+    Wx,
+    Wq,
+    Wd,
+    Ww,
+    Wb,
+    /// XMM register or 128-bit memory operand, whatever the vector length
+    Wdq,
+    /// XMM register or memory operand half, a quarter or an eighth as wide as the vector:
+    /// the source of a widening conversion
+    Wh,
+    Wf,
+    We,
+    /// XMM or YMM register in ModR/M `r/m`
+    Ux,
+    /// Memory operand as wide as the vector, or half as wide
+    Mx,
+    Mh,
+    /// XMM or YMM register selected by VEX.vvvv, as wide as the vector
+    Hx,
+    /// The XMM register selected by VEX.vvvv, whatever the vector length
+    Hdq,
+    /// XMM or YMM register selected by bits 7:4 of an immediate byte (the VEX blends)
+    Lx,
+    /// VSIB memory operand of doubleword or quadword elements, by VEX.W, indexed by a
+    /// vector as wide as the vector length or by an XMM register (a YMM register for a
+    /// 512-bit vector)
+    VSx,
+    VSdq,
+    /// Doubleword or quadword memory operand, by REX.W alone
+    My,
+    /// Doubleword register or word memory operand (PINSRW)
+    RdMw,
+    /// Doubleword register or byte memory operand (PINSRB)
+    RdMb,
+    /// The whole of an XMM register in ModR/M `r/m`, or a doubleword memory operand
+    /// (INSERTPS)
+    UxMd,
+    /// XMM0, implied by the opcode (the variable blends)
+    Xmm0,
+    /// Opmask register in ModR/M `reg`, in ModR/M `r/m`, or selected by VEX.vvvv
+    Kg,
+    Kr,
+    Kh,
+    /// Opmask register or memory operand, as wide as the mask instruction's suffix
+    Ke,
+    /// Doubleword or quadword register-only ModR/M operand, by REX.W/VEX.W alone
+    Ry,
+}
+
+bitflags! {
+    struct Attr: u16 {
+        const NONE = 0;
+        /// Operand size defaults to 64 bits in 64-bit mode
+        const D64 = 0b0001;
+        /// Operand size is always 64 bits in 64-bit mode
+        const F64 = 0b0010;
+        /// Invalid in 64-bit mode
+        const I64 = 0b0100;
+        /// VEX-encoded with VEX.L (or EVEX.L'L) required to be 0
+        const L0 = 0b1000;
+        /// VEX-encoded with VEX.L (or EVEX.L'L) required to be nonzero
+        const L1 = 0b1_0000;
+        /// VEX.L and EVEX.L'L are ignored: a scalar instruction, whose operands don't
+        /// depend on them
+        const LIG = 0b10_0000;
+        /// EVEX-encoded with 512-bit vectors required
+        const L2 = 0b100_0000;
+        /// EVEX.b on a memory operand broadcasts one element, as wide as EVEX.W selects
+        const BCST = 0b1000_0000;
+        /// EVEX.b between registers selects a static rounding mode (EVEX.RC)
+        const ER = 0b1_0000_0000;
+        /// EVEX.b between registers suppresses floating-point exceptions
+        const SAE = 0b10_0000_0000;
+        /// Only valid in 64-bit mode
+        const O64 = 0b100_0000_0000;
+    }
+}
+
+type Def = (Mnemonic, &'static [Spec], Attr);
+
+#[derive(Clone, Copy)]
+enum Entry {
+    Invalid,
+    Op(Def),
+    /// Selected by the `reg` field of the ModR/M byte
+    Group(&'static [Option<Def>; 8]),
+    /// Selected by the mandatory prefix (none, 66, F3 or F2), either a legacy prefix or
+    /// VEX.pp
+    Mandatory(&'static [Entry; 4]),
+    /// One of the x87 escape opcodes D8-DF, selected by the whole ModR/M byte
+    Escape(u8),
+    /// Looked up in the SIMD tables by the opcode and mandatory prefix
+    Simd,
+}
+
+use Mnemonic::*;
+use Spec::*;
+
+const ALU: [Mnemonic; 8] = [Add, Or, Adc, Sbb, And, Sub, Xor, Cmp];
+
+const fn op(mnemonic: Mnemonic, specs: &'static [Spec]) -> Entry {
+    Entry::Op((mnemonic, specs, Attr::NONE))
+}
+
+const fn op_attr(mnemonic: Mnemonic, specs: &'static [Spec], attr: Attr) -> Entry {
+    Entry::Op((mnemonic, specs, attr))
+}
+
+const fn def(mnemonic: Mnemonic, specs: &'static [Spec]) -> Option<Def> {
+    Some((mnemonic, specs, Attr::NONE))
+}
+
+const fn def_attr(mnemonic: Mnemonic, specs: &'static [Spec], attr: Attr) -> Option<Def> {
+    Some((mnemonic, specs, attr))
+}
+
+static GROUP1_EB_IB: [Option<Def>; 8] = [
+    def(Add, &[Eb, Ib]),
+    def(Or, &[Eb, Ib]),
+    def(Adc, &[Eb, Ib]),
+    def(Sbb, &[Eb, Ib]),
+    def(And, &[Eb, Ib]),
+    def(Sub, &[Eb, Ib]),
+    def(Xor, &[Eb, Ib]),
+    def(Cmp, &[Eb, Ib]),
+];
+
+static GROUP1_EV_IZ: [Option<Def>; 8] = [
+    def(Add, &[Ev, Iz]),
+    def(Or, &[Ev, Iz]),
+    def(Adc, &[Ev, Iz]),
+    def(Sbb, &[Ev, Iz]),
+    def(And, &[Ev, Iz]),
+    def(Sub, &[Ev, Iz]),
+    def(Xor, &[Ev, Iz]),
+    def(Cmp, &[Ev, Iz]),
+];
+
+static GROUP1_EV_IBS: [Option<Def>; 8] = [
+    def(Add, &[Ev, Ibs]),
+    def(Or, &[Ev, Ibs]),
+    def(Adc, &[Ev, Ibs]),
+    def(Sbb, &[Ev, Ibs]),
+    def(And, &[Ev, Ibs]),
+    def(Sub, &[Ev, Ibs]),
+    def(Xor, &[Ev, Ibs]),
+    def(Cmp, &[Ev, Ibs]),
+];
+
+static GROUP1A: [Option<Def>; 8] = [
+    def_attr(Pop, &[Ev], Attr::D64),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+];
+
+macro_rules! group2 {
+    ($name:ident, $dst:ident, $count:ident) => {
+        static $name: [Option<Def>; 8] = [
+            def(Rol, &[$dst, $count]),
+            def(Ror, &[$dst, $count]),
+            def(Rcl, &[$dst, $count]),
+            def(Rcr, &[$dst, $count]),
+            def(Shl, &[$dst, $count]),
+            def(Shr, &[$dst, $count]),
+            // SAL is an alias of SHL
+            def(Shl, &[$dst, $count]),
+            def(Sar, &[$dst, $count]),
+        ];
+    };
+}
+
+group2!(GROUP2_EB_IB, Eb, Ib);
+group2!(GROUP2_EV_IB, Ev, Ib);
+group2!(GROUP2_EB_1, Eb, One);
+group2!(GROUP2_EV_1, Ev, One);
+group2!(GROUP2_EB_CL, Eb, Cl);
+group2!(GROUP2_EV_CL, Ev, Cl);
+
+static GROUP3_EB: [Option<Def>; 8] = [
+    def(Test, &[Eb, Ib]),
+    def(Test, &[Eb, Ib]),
+    def(Not, &[Eb]),
+    def(Neg, &[Eb]),
+    def(Mul, &[Eb]),
+    def(Imul, &[Eb]),
+    def(Div, &[Eb]),
+    def(Idiv, &[Eb]),
+];
+
+static GROUP3_EV: [Option<Def>; 8] = [
+    def(Test, &[Ev, Iz]),
+    def(Test, &[Ev, Iz]),
+    def(Not, &[Ev]),
+    def(Neg, &[Ev]),
+    def(Mul, &[Ev]),
+    def(Imul, &[Ev]),
+    def(Div, &[Ev]),
+    def(Idiv, &[Ev]),
+];
+
+static GROUP4: [Option<Def>; 8] = [
+    def(Inc, &[Eb]),
+    def(Dec, &[Eb]),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+];
+
+static GROUP5: [Option<Def>; 8] = [
+    def(Inc, &[Ev]),
+    def(Dec, &[Ev]),
+    def_attr(Call, &[Ev], Attr::F64),
+    def(Callf, &[Mp]),
+    def_attr(Jmp, &[Ev], Attr::F64),
+    def(Jmpf, &[Mp]),
+    def_attr(Push, &[Ev], Attr::D64),
+    None,
+];
+
+static GROUP6: [Option<Def>; 8] = [
+    def(Sldt, &[Ew]),
+    def(Str, &[Ew]),
+    def(Lldt, &[Ew]),
+    def(Ltr, &[Ew]),
+    def(Verr, &[Ew]),
+    def(Verw, &[Ew]),
+    None,
+    None,
+];
+
+static GROUP7: [Option<Def>; 8] = [
+    def(Sgdt, &[Ms]),
+    def(Sidt, &[Ms]),
+    def(Lgdt, &[Ms]),
+    def(Lidt, &[Ms]),
+    def(Smsw, &[Ew]),
+    None,
+    def(Lmsw, &[Ew]),
+    def(Invlpg, &[Mb]),
+];
+
+static GROUP8: [Option<Def>; 8] = [
+    None,
+    None,
+    None,
+    None,
+    def(Bt, &[Ev, Ib]),
+    def(Bts, &[Ev, Ib]),
+    def(Btr, &[Ev, Ib]),
+    def(Btc, &[Ev, Ib]),
+];
+
+static GROUP9: [Option<Def>; 8] = [
+    None,
+    def(Cmpxchg8b, &[Mq]),
+    None,
+    None,
+    def(Xsavec, &[Mb]),
+    None,
+    def(Rdrand, &[Ev]),
+    def(Rdseed, &[Ev]),
+];
+
+static GROUP15_NONE: [Option<Def>; 8] = [
+    def(Fxsave, &[Mb]),
+    def(Fxrstor, &[Mb]),
+    def(Ldmxcsr, &[Md]),
+    def(Stmxcsr, &[Md]),
+    def(Xsave, &[Mb]),
+    // The fences are the register forms of /5-/7: XRSTOR's becomes LFENCE, XSAVEOPT's MFENCE
+    // and CLFLUSH's SFENCE
+    def(Xrstor, &[Mb]),
+    def(Xsaveopt, &[Mb]),
+    def(Clflush, &[Mb]),
+];
+
+static GROUP15_F3: [Option<Def>; 8] = [
+    def_attr(Rdfsbase, &[Ry], Attr::O64),
+    def_attr(Rdgsbase, &[Ry], Attr::O64),
+    def_attr(Wrfsbase, &[Ry], Attr::O64),
+    def_attr(Wrgsbase, &[Ry], Attr::O64),
+    None,
+    None,
+    None,
+    None,
+];
+
+static GROUP15: [Entry; 4] = [
+    Entry::Group(&GROUP15_NONE),
+    Entry::Invalid,
+    Entry::Group(&GROUP15_F3),
+    Entry::Invalid,
+];
+
+static GROUP11_EB: [Option<Def>; 8] = [
+    def(Mov, &[Eb, Ib]),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+];
+
+static GROUP11_EV: [Option<Def>; 8] = [
+    def(Mov, &[Ev, Iz]),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+];
+
+static HINT_NOP: [Option<Def>; 8] = [
+    def(Nop, &[Ev]),
+    def(Nop, &[Ev]),
+    def(Nop, &[Ev]),
+    def(Nop, &[Ev]),
+    def(Nop, &[Ev]),
+    def(Nop, &[Ev]),
+    def(Nop, &[Ev]),
+    def(Nop, &[Ev]),
+];
+
+static BSF_TZCNT: [Entry; 4] = [
+    op(Bsf, &[Gv, Ev]),
+    op(Bsf, &[Gv, Ev]),
+    op(Tzcnt, &[Gv, Ev]),
+    op(Bsf, &[Gv, Ev]),
+];
+
+static BSR_LZCNT: [Entry; 4] = [
+    op(Bsr, &[Gv, Ev]),
+    op(Bsr, &[Gv, Ev]),
+    op(Lzcnt, &[Gv, Ev]),
+    op(Bsr, &[Gv, Ev]),
+];
+
+static POPCNT: [Entry; 4] = [
+    Entry::Invalid,
+    Entry::Invalid,
+    op(Popcnt, &[Gv, Ev]),
+    Entry::Invalid,
+];
+
+static GROUP17: [Option<Def>; 8] = [
+    None,
+    def_attr(Blsr, &[By, Ey], Attr::L0),
+    def_attr(Blsmsk, &[By, Ey], Attr::L0),
+    def_attr(Blsi, &[By, Ey], Attr::L0),
+    None,
+    None,
+    None,
+    None,
+];
+
+static MAP_0F38_82: [Entry; 4] = [
+    Entry::Invalid,
+    op(Invpcid, &[Gq, Mdq]),
+    Entry::Invalid,
+    Entry::Invalid,
+];
+
+static MAP_0F38_F0: [Entry; 4] = [
+    op(Movbe, &[Gv, M]),
+    op(Movbe, &[Gv, M]),
+    Entry::Invalid,
+    op(Crc32, &[Gy, Eb]),
+];
+
+static MAP_0F38_F1: [Entry; 4] = [
+    op(Movbe, &[M, Gv]),
+    op(Movbe, &[M, Gv]),
+    Entry::Invalid,
+    op(Crc32, &[Gy, Ev]),
+];
+
+static VEX_0F38_F2: [Entry; 4] = [
+    op_attr(Andn, &[Gy, By, Ey], Attr::L0),
+    Entry::Invalid,
+    Entry::Invalid,
+    Entry::Invalid,
+];
+
+static VEX_0F38_F3: [Entry; 4] = [
+    Entry::Group(&GROUP17),
+    Entry::Invalid,
+    Entry::Invalid,
+    Entry::Invalid,
+];
+
+static VEX_0F38_F5: [Entry; 4] = [
+    op_attr(Bzhi, &[Gy, Ey, By], Attr::L0),
+    Entry::Invalid,
+    op_attr(Pext, &[Gy, By, Ey], Attr::L0),
+    op_attr(Pdep, &[Gy, By, Ey], Attr::L0),
+];
+
+static VEX_0F38_F6: [Entry; 4] = [
+    Entry::Invalid,
+    Entry::Invalid,
+    Entry::Invalid,
+    op_attr(Mulx, &[Gy, By, Ey], Attr::L0),
+];
+
+static VEX_0F38_F7: [Entry; 4] = [
+    op_attr(Bextr, &[Gy, Ey, By], Attr::L0),
+    op_attr(Shlx, &[Gy, Ey, By], Attr::L0),
+    op_attr(Sarx, &[Gy, Ey, By], Attr::L0),
+    op_attr(Shrx, &[Gy, Ey, By], Attr::L0),
+];
+
+static VEX_0F3A_F0: [Entry; 4] = [
+    Entry::Invalid,
+    Entry::Invalid,
+    Entry::Invalid,
+    op_attr(Rorx, &[Gy, Ey, Ib], Attr::L0),
+];
+
+fn primary(opcode: u8) -> Entry {
+    match opcode {
+        0x00..=0x3F if opcode & 0x07 < 6 => {
+            let mnemonic = ALU[(opcode >> 3) as usize];
+            let specs: &'static [Spec] = match opcode & 0x07 {
+                0 => &[Eb, Gb],
+                1 => &[Ev, Gv],
+                2 => &[Gb, Eb],
+                3 => &[Gv, Ev],
+                4 => &[Al, Ib],
+                _ => &[Ax, Iz],
+            };
+            op(mnemonic, specs)
+        }
+        0x06 => op_attr(Push, &[Es], Attr::I64),
+        0x07 => op_attr(Pop, &[Es], Attr::I64),
+        0x0E => op_attr(Push, &[Cs], Attr::I64),
+        0x16 => op_attr(Push, &[Ss], Attr::I64),
+        0x17 => op_attr(Pop, &[Ss], Attr::I64),
+        0x1E => op_attr(Push, &[Ds], Attr::I64),
+        0x1F => op_attr(Pop, &[Ds], Attr::I64),
+        0x27 => op_attr(Daa, &[], Attr::I64),
+        0x2F => op_attr(Das, &[], Attr::I64),
+        0x37 => op_attr(Aaa, &[], Attr::I64),
+        0x3F => op_attr(Aas, &[], Attr::I64),
+        // REX prefixes in 64-bit mode
+        0x40..=0x47 => op_attr(Inc, &[Zv], Attr::I64),
+        0x48..=0x4F => op_attr(Dec, &[Zv], Attr::I64),
+        0x50..=0x57 => op_attr(Push, &[Zv], Attr::D64),
+        0x58..=0x5F => op_attr(Pop, &[Zv], Attr::D64),
+        0x60 => op_attr(Pusha, &[], Attr::I64),
+        0x61 => op_attr(Popa, &[], Attr::I64),
+        0x62 => op_attr(Bound, &[Gv, M], Attr::I64),
+        0x63 => op(Movsxd, &[Gv, Ed]),
+        0x68 => op_attr(Push, &[Iz], Attr::D64),
+        0x69 => op(Imul, &[Gv, Ev, Iz]),
+        0x6A => op_attr(Push, &[Ibs], Attr::D64),
+        0x6B => op(Imul, &[Gv, Ev, Ibs]),
+        0x6C => op(Ins, &[Yb, Dx]),
+        0x6D => op(Ins, &[Yv, Dx]),
+        0x6E => op(Outs, &[Dx, Xb]),
+        0x6F => op(Outs, &[Dx, Xv]),
+        0x70..=0x7F => op_attr(Jcc, &[Jb], Attr::F64),
+        0x80 => Entry::Group(&GROUP1_EB_IB),
+        0x81 => Entry::Group(&GROUP1_EV_IZ),
+        0x83 => Entry::Group(&GROUP1_EV_IBS),
+        0x84 => op(Test, &[Eb, Gb]),
+        0x85 => op(Test, &[Ev, Gv]),
+        0x86 => op(Xchg, &[Eb, Gb]),
+        0x87 => op(Xchg, &[Ev, Gv]),
+        0x88 => op(Mov, &[Eb, Gb]),
+        0x89 => op(Mov, &[Ev, Gv]),
+        0x8A => op(Mov, &[Gb, Eb]),
+        0x8B => op(Mov, &[Gv, Ev]),
+        0x8C => op(Mov, &[RvMw, Sw]),
+        0x8D => op(Lea, &[Gv, M]),
+        0x8E => op(Mov, &[Sw, Ew]),
+        0x8F => Entry::Group(&GROUP1A),
+        0x90 => op(Nop, &[]),
+        0x91..=0x97 => op(Xchg, &[Zv, Ax]),
+        0x98 => op(Cbw, &[]),
+        0x99 => op(Cwd, &[]),
+        0x9A => op_attr(Callf, &[Ap], Attr::I64),
+        0x9B => op(Fwait, &[]),
+        0x9C => op_attr(Pushf, &[], Attr::D64),
+        0x9D => op_attr(Popf, &[], Attr::D64),
+        0x9E => op(Sahf, &[]),
+        0x9F => op(Lahf, &[]),
+        0xA0 => op(Mov, &[Al, Ob]),
+        0xA1 => op(Mov, &[Ax, Ov]),
+        0xA2 => op(Mov, &[Ob, Al]),
+        0xA3 => op(Mov, &[Ov, Ax]),
+        0xA4 => op(Movs, &[Yb, Xb]),
+        0xA5 => op(Movs, &[Yv, Xv]),
+        0xA6 => op(Cmps, &[Xb, Yb]),
+        0xA7 => op(Cmps, &[Xv, Yv]),
+        0xA8 => op(Test, &[Al, Ib]),
+        0xA9 => op(Test, &[Ax, Iz]),
+        0xAA => op(Stos, &[Yb, Al]),
+        0xAB => op(Stos, &[Yv, Ax]),
+        0xAC => op(Lods, &[Al, Xb]),
+        0xAD => op(Lods, &[Ax, Xv]),
+        0xAE => op(Scas, &[Al, Yb]),
+        0xAF => op(Scas, &[Ax, Yv]),
+        0xB0..=0xB7 => op(Mov, &[Zb, Ib]),
+        0xB8..=0xBF => op(Mov, &[Zv, Iv]),
+        0xC0 => Entry::Group(&GROUP2_EB_IB),
+        0xC1 => Entry::Group(&GROUP2_EV_IB),
+        0xC2 => op_attr(Ret, &[Iw], Attr::F64),
+        0xC3 => op_attr(Ret, &[], Attr::F64),
+        0xC4 => op_attr(Les, &[Gv, Mp], Attr::I64),
+        0xC5 => op_attr(Lds, &[Gv, Mp], Attr::I64),
+        0xC6 => Entry::Group(&GROUP11_EB),
+        0xC7 => Entry::Group(&GROUP11_EV),
+        0xC8 => op_attr(Enter, &[Iw, Ib], Attr::D64),
+        0xC9 => op_attr(Leave, &[], Attr::D64),
+        0xCA => op(Retf, &[Iw]),
+        0xCB => op(Retf, &[]),
+        0xCC => op(Int3, &[]),
+        0xCD => op(Int, &[Ib]),
+        0xCE => op_attr(Into, &[], Attr::I64),
+        0xCF => op(Iret, &[]),
+        0xD0 => Entry::Group(&GROUP2_EB_1),
+        0xD1 => Entry::Group(&GROUP2_EV_1),
+        0xD2 => Entry::Group(&GROUP2_EB_CL),
+        0xD3 => Entry::Group(&GROUP2_EV_CL),
+        0xD4 => op_attr(Aam, &[Ib], Attr::I64),
+        0xD5 => op_attr(Aad, &[Ib], Attr::I64),
+        0xD6 => op_attr(Salc, &[], Attr::I64),
+        0xD7 => op(Xlat, &[]),
+        0xD8..=0xDF => Entry::Escape(opcode),
+        0xE0 => op_attr(Loopne, &[Jb], Attr::F64),
+        0xE1 => op_attr(Loope, &[Jb], Attr::F64),
+        0xE2 => op_attr(Loop, &[Jb], Attr::F64),
+        0xE3 => op_attr(Jrcxz, &[Jb], Attr::F64),
+        0xE4 => op(In, &[Al, Ib]),
+        0xE5 => op(In, &[Ax, Ib]),
+        0xE6 => op(Out, &[Ib, Al]),
+        0xE7 => op(Out, &[Ib, Ax]),
+        0xE8 => op_attr(Call, &[Jz], Attr::F64),
+        0xE9 => op_attr(Jmp, &[Jz], Attr::F64),
+        0xEA => op_attr(Jmpf, &[Ap], Attr::I64),
+        0xEB => op_attr(Jmp, &[Jb], Attr::F64),
+        0xEC => op(In, &[Al, Dx]),
+        0xED => op(In, &[Ax, Dx]),
+        0xEE => op(Out, &[Dx, Al]),
+        0xEF => op(Out, &[Dx, Ax]),
+        0xF1 => op(Int1, &[]),
+        0xF4 => op(Hlt, &[]),
+        0xF5 => op(Cmc, &[]),
+        0xF6 => Entry::Group(&GROUP3_EB),
+        0xF7 => Entry::Group(&GROUP3_EV),
+        0xF8 => op(Clc, &[]),
+        0xF9 => op(Stc, &[]),
+        0xFA => op(Cli, &[]),
+        0xFB => op(Sti, &[]),
+        0xFC => op(Cld, &[]),
+        0xFD => op(Std, &[]),
+        0xFE => Entry::Group(&GROUP4),
+        0xFF => Entry::Group(&GROUP5),
+        _ => Entry::Invalid,
+    }
+}
+
+const X87_ARITHMETIC: [Mnemonic; 8] = [Fadd, Fmul, Fcom, Fcomp, Fsub, Fsubr, Fdiv, Fdivr];
+const X87_INTEGER_ARITHMETIC: [Mnemonic; 8] =
+    [Fiadd, Fimul, Ficom, Ficomp, Fisub, Fisubr, Fidiv, Fidivr];
+
+/// Looks up an x87 instruction by its escape opcode and ModR/M byte. Memory forms are
+/// selected by `reg` like a group; register forms name a stack register in `r/m`, or use the
+/// whole byte as an opcode extension.
+fn x87(escape: u8, modrm: u8) -> Option<Def> {
+    let reg = ((modrm >> 3) & 0x07) as usize;
+    if modrm < 0xC0 {
+        return match (escape, reg) {
+            (0xD8, _) => def(X87_ARITHMETIC[reg], &[Md]),
+            (0xD9, 0) => def(Fld, &[Md]),
+            (0xD9, 2) => def(Fst, &[Md]),
+            (0xD9, 3) => def(Fstp, &[Md]),
+            (0xD9, 4) => def(Fldenv, &[Mb]),
+            (0xD9, 5) => def(Fldcw, &[Mw]),
+            (0xD9, 6) => def(Fnstenv, &[Mb]),
+            (0xD9, 7) => def(Fnstcw, &[Mw]),
+            (0xDA, _) => def(X87_INTEGER_ARITHMETIC[reg], &[Md]),
+            (0xDB, 0) => def(Fild, &[Md]),
+            (0xDB, 1) => def(Fisttp, &[Md]),
+            (0xDB, 2) => def(Fist, &[Md]),
+            (0xDB, 3) => def(Fistp, &[Md]),
+            (0xDB, 5) => def(Fld, &[Mt]),
+            (0xDB, 7) => def(Fstp, &[Mt]),
+            (0xDC, _) => def(X87_ARITHMETIC[reg], &[Mq]),
+            (0xDD, 0) => def(Fld, &[Mq]),
+            (0xDD, 1) => def(Fisttp, &[Mq]),
+            (0xDD, 2) => def(Fst, &[Mq]),
+            (0xDD, 3) => def(Fstp, &[Mq]),
+            (0xDD, 4) => def(Frstor, &[Mb]),
+            (0xDD, 6) => def(Fnsave, &[Mb]),
+            (0xDD, 7) => def(Fnstsw, &[Mw]),
+            (0xDE, _) => def(X87_INTEGER_ARITHMETIC[reg], &[Mw]),
+            (0xDF, 0) => def(Fild, &[Mw]),
+            (0xDF, 1) => def(Fisttp, &[Mw]),
+            (0xDF, 2) => def(Fist, &[Mw]),
+            (0xDF, 3) => def(Fistp, &[Mw]),
+            (0xDF, 4) => def(Fbld, &[Mt]),
+            (0xDF, 5) => def(Fild, &[Mq]),
+            (0xDF, 6) => def(Fbstp, &[Mt]),
+            (0xDF, 7) => def(Fistp, &[Mq]),
+            _ => None,
+        };
+    }
+    match (escape, modrm) {
+        (0xD9, 0xD0) => def(Fnop, &[]),
+        (0xD9, 0xE0) => def(Fchs, &[]),
+        (0xD9, 0xE1) => def(Fabs, &[]),
+        (0xD9, 0xE4) => def(Ftst, &[]),
+        (0xD9, 0xE5) => def(Fxam, &[]),
+        (0xD9, 0xE8) => def(Fld1, &[]),
+        (0xD9, 0xE9) => def(Fldl2t, &[]),
+        (0xD9, 0xEA) => def(Fldl2e, &[]),
+        (0xD9, 0xEB) => def(Fldpi, &[]),
+        (0xD9, 0xEC) => def(Fldlg2, &[]),
+        (0xD9, 0xED) => def(Fldln2, &[]),
+        (0xD9, 0xEE) => def(Fldz, &[]),
+        (0xD9, 0xF0) => def(F2xm1, &[]),
+        (0xD9, 0xF1) => def(Fyl2x, &[]),
+        (0xD9, 0xF2) => def(Fptan, &[]),
+        (0xD9, 0xF3) => def(Fpatan, &[]),
+        (0xD9, 0xF4) => def(Fxtract, &[]),
+        (0xD9, 0xF5) => def(Fprem1, &[]),
+        (0xD9, 0xF6) => def(Fdecstp, &[]),
+        (0xD9, 0xF7) => def(Fincstp, &[]),
+        (0xD9, 0xF8) => def(Fprem, &[]),
+        (0xD9, 0xF9) => def(Fyl2xp1, &[]),
+        (0xD9, 0xFA) => def(Fsqrt, &[]),
+        (0xD9, 0xFB) => def(Fsincos, &[]),
+        (0xD9, 0xFC) => def(Frndint, &[]),
+        (0xD9, 0xFD) => def(Fscale, &[]),
+        (0xD9, 0xFE) => def(Fsin, &[]),
+        (0xD9, 0xFF) => def(Fcos, &[]),
+        (0xDA, 0xE9) => def(Fucompp, &[]),
+        // FENI, FDISI and FSETPM only did something on the 8087 and 80287
+        (0xDB, 0xE0 | 0xE1 | 0xE4) => def(Fnop, &[]),
+        (0xDB, 0xE2) => def(Fnclex, &[]),
+        (0xDB, 0xE3) => def(Fninit, &[]),
+        (0xDE, 0xD9) => def(Fcompp, &[]),
+        (0xDF, 0xE0) => def(Fnstsw, &[Aw]),
+        _ => match (escape, reg) {
+            (0xD8, 2 | 3) => def(X87_ARITHMETIC[reg], &[StI]),
+            (0xD8, _) => def(X87_ARITHMETIC[reg], &[St0, StI]),
+            (0xD9, 0) => def(Fld, &[StI]),
+            (0xD9, 1) => def(Fxch, &[StI]),
+            (0xDA | 0xDB, 0..=3) => def(Fcmovcc, &[St0, StI]),
+            (0xDB, 5) => def(Fucomi, &[St0, StI]),
+            (0xDB, 6) => def(Fcomi, &[St0, StI]),
+            // The reversed forms swap SUB/SUBR and DIV/DIVR relative to D8
+            (0xDC, 0) => def(Fadd, &[StI, St0]),
+            (0xDC, 1) => def(Fmul, &[StI, St0]),
+            (0xDC, 4) => def(Fsubr, &[StI, St0]),
+            (0xDC, 5) => def(Fsub, &[StI, St0]),
+            (0xDC, 6) => def(Fdivr, &[StI, St0]),
+            (0xDC, 7) => def(Fdiv, &[StI, St0]),
+            (0xDD, 0) => def(Ffree, &[StI]),
+            (0xDD, 2) => def(Fst, &[StI]),
+            (0xDD, 3) => def(Fstp, &[StI]),
+            (0xDD, 4) => def(Fucom, &[StI]),
+            (0xDD, 5) => def(Fucomp, &[StI]),
+            (0xDE, 0) => def(Faddp, &[StI, St0]),
+            (0xDE, 1) => def(Fmulp, &[StI, St0]),
+            (0xDE, 4) => def(Fsubrp, &[StI, St0]),
+            (0xDE, 5) => def(Fsubp, &[StI, St0]),
+            (0xDE, 6) => def(Fdivrp, &[StI, St0]),
+            (0xDE, 7) => def(Fdivp, &[StI, St0]),
+            (0xDF, 0) => def(Ffreep, &[StI]),
+            (0xDF, 5) => def(Fucomip, &[St0, StI]),
+            (0xDF, 6) => def(Fcomip, &[St0, StI]),
+            _ => None,
+        },
+    }
+}
+
+fn secondary(opcode: u8) -> Entry {
+    match opcode {
+        0x00 => Entry::Group(&GROUP6),
+        0x01 => Entry::Group(&GROUP7),
+        0x02 => op(Lar, &[Gv, Ew]),
+        0x03 => op(Lsl, &[Gv, Ew]),
+        0x05 => op(Syscall, &[]),
+        0x06 => op(Clts, &[]),
+        0x07 => op(Sysret, &[]),
+        0x08 => op(Invd, &[]),
+        0x09 => op(Wbinvd, &[]),
+        0x0B => op(Ud2, &[]),
+        // Prefetches and reserved-NOP hints: architecturally no-ops
+        0x0D | 0x18..=0x1F => Entry::Group(&HINT_NOP),
+        0x20 => op_attr(Mov, &[Rq, Cq], Attr::F64),
+        0x21 => op_attr(Mov, &[Rq, Dq], Attr::F64),
+        0x22 => op_attr(Mov, &[Cq, Rq], Attr::F64),
+        0x23 => op_attr(Mov, &[Dq, Rq], Attr::F64),
+        0x30 => op(Wrmsr, &[]),
+        0x31 => op(Rdtsc, &[]),
+        0x32 => op(Rdmsr, &[]),
+        0x33 => op(Rdpmc, &[]),
+        0x34 => op(Sysenter, &[]),
+        0x35 => op(Sysexit, &[]),
+        0x40..=0x4F => op(Cmovcc, &[Gv, Ev]),
+        0x80..=0x8F => op_attr(Jcc, &[Jz], Attr::F64),
+        0x90..=0x9F => op(Setcc, &[Eb]),
+        0xA0 => op_attr(Push, &[Fs], Attr::D64),
+        0xA1 => op_attr(Pop, &[Fs], Attr::D64),
+        0xA2 => op(Cpuid, &[]),
+        0xA3 => op(Bt, &[Ev, Gv]),
+        0xA4 => op(Shld, &[Ev, Gv, Ib]),
+        0xA5 => op(Shld, &[Ev, Gv, Cl]),
+        0xA8 => op_attr(Push, &[Gs], Attr::D64),
+        0xA9 => op_attr(Pop, &[Gs], Attr::D64),
+        0xAA => op(Rsm, &[]),
+        0xAB => op(Bts, &[Ev, Gv]),
+        0xAC => op(Shrd, &[Ev, Gv, Ib]),
+        0xAD => op(Shrd, &[Ev, Gv, Cl]),
+        0xAE => Entry::Mandatory(&GROUP15),
+        0xAF => op(Imul, &[Gv, Ev]),
+        0xB0 => op(Cmpxchg, &[Eb, Gb]),
+        0xB1 => op(Cmpxchg, &[Ev, Gv]),
+        0xB2 => op(Lss, &[Gv, Mp]),
+        0xB3 => op(Btr, &[Ev, Gv]),
+        0xB4 => op(Lfs, &[Gv, Mp]),
+        0xB5 => op(Lgs, &[Gv, Mp]),
+        0xB6 => op(Movzx, &[Gv, Eb]),
+        0xB7 => op(Movzx, &[Gv, Ew]),
+        0xB8 => Entry::Mandatory(&POPCNT),
+        0xB9 => op(Ud1, &[Gv, Ev]),
+        0xBA => Entry::Group(&GROUP8),
+        0xBB => op(Btc, &[Ev, Gv]),
+        0xBC => Entry::Mandatory(&BSF_TZCNT),
+        0xBD => Entry::Mandatory(&BSR_LZCNT),
+        0xBE => op(Movsx, &[Gv, Eb]),
+        0xBF => op(Movsx, &[Gv, Ew]),
+        0xC0 => op(Xadd, &[Eb, Gb]),
+        0xC1 => op(Xadd, &[Ev, Gv]),
+        0xC3 => op(Movnti, &[My, Gy]),
+        0xC7 => Entry::Group(&GROUP9),
+        0xC8..=0xCF => op(Bswap, &[Zv]),
+        0x10..=0x17 | 0x28..=0x2F | 0x50..=0x7F | 0xC2 | 0xC4..=0xC6 | 0xD0..=0xFE => Entry::Simd,
+        0xFF => op(Ud0, &[Gv, Ev]),
+        _ => Entry::Invalid,
+    }
+}
+
+/// The 0F38 map of legacy-encoded instructions.
+fn map_0f38(opcode: u8) -> Entry {
+    match opcode {
+        0x00..=0x0B
+        | 0x10
+        | 0x14
+        | 0x15
+        | 0x17
+        | 0x1C..=0x1E
+        | 0x20..=0x25
+        | 0x28..=0x2B
+        | 0x30..=0x35
+        | 0x37..=0x41
+        | 0xC8..=0xCD
+        | 0xDB..=0xDF => Entry::Simd,
+        0x82 => Entry::Mandatory(&MAP_0F38_82),
+        0xF0 => Entry::Mandatory(&MAP_0F38_F0),
+        0xF1 => Entry::Mandatory(&MAP_0F38_F1),
+        _ => Entry::Invalid,
+    }
+}
+
+/// The 0F3A map of legacy-encoded instructions.
+fn map_0f3a(opcode: u8) -> Entry {
+    match opcode {
+        0x08..=0x0F
+        | 0x14..=0x17
+        | 0x20..=0x22
+        | 0x40..=0x42
+        | 0x44
+        | 0x60..=0x63
+        | 0xCC
+        | 0xDF => Entry::Simd,
+        _ => Entry::Invalid,
+    }
+}
+
+/// The 0F38 map of VEX-encoded instructions: BMI on general-purpose registers, and AVX
+/// elsewhere.
+fn vex_0f38(opcode: u8) -> Entry {
+    match opcode {
+        0xF2 => Entry::Mandatory(&VEX_0F38_F2),
+        0xF3 => Entry::Mandatory(&VEX_0F38_F3),
+        0xF5 => Entry::Mandatory(&VEX_0F38_F5),
+        0xF6 => Entry::Mandatory(&VEX_0F38_F6),
+        0xF7 => Entry::Mandatory(&VEX_0F38_F7),
+        _ => Entry::Simd,
+    }
+}
+
+/// The 0F3A map of VEX-encoded instructions: RORX, and AVX elsewhere.
+fn vex_0f3a(opcode: u8) -> Entry {
+    match opcode {
+        0xF0 => Entry::Mandatory(&VEX_0F3A_F0),
+        _ => Entry::Simd,
+    }
+}
+
+/// Reads instruction bytes on behalf of the decoder, enforcing the 15-byte limit.
+struct Cursor<'a> {
+    address: u64,
+    length: u8,
+    fetch: &'a mut dyn FnMut(u64) -> Result<u8, Exception>,
+}
+
+impl Cursor<'_> {
+    /// The next byte, without consuming it.
+    fn peek(&mut self) -> Result<u8, Exception> {
+        (self.fetch)(self.address.wrapping_add(self.length as u64))
+    }
+
+    fn u8(&mut self) -> Result<u8, Exception> {
+        if self.length >= MAX_INSTRUCTION_LENGTH {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let byte = (self.fetch)(self.address.wrapping_add(self.length as u64))?;
+        self.length += 1;
+        Ok(byte)
+    }
+
+    fn u16(&mut self) -> Result<u16, Exception> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Exception> {
+        Ok(u32::from_le_bytes([
+            self.u8()?,
+            self.u8()?,
+            self.u8()?,
+            self.u8()?,
+        ]))
+    }
+
+    fn u64(&mut self) -> Result<u64, Exception> {
+        Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+    }
+}
+
+/// Reads the SIB byte and displacement (if any) following a ModR/M byte. With
+/// `vector_index` the operand must be a VSIB memory reference, whose SIB index names a
+/// vector register of that size.
+fn decode_modrm(
+    cursor: &mut Cursor,
+    modrm_byte: u8,
+    prefixes: Prefixes,
+    decode_mode: Mode,
+    address_size: OperandSize,
+    vector_index: Option<OperandSize>,
+) -> Result<ModRM, Exception> {
+    let mode = modrm_byte >> 6;
+    let rm = modrm_byte & 0x07;
+    let rex_b = if prefixes.contains(Prefixes::REX_B) {
+        8
+    } else {
+        0
+    };
+
+    if vector_index.is_some() && (mode == 3 || rm != 4) {
+        return Err(Exception::InvalidOpcode);
+    }
+    if mode == 3 {
+        return Ok(ModRM::Register(rm | rex_b));
+    }
+    if address_size == OperandSize::R16 {
+        // There's no SIB byte to name a vector index with
+        if vector_index.is_some() {
+            return Err(Exception::InvalidOpcode);
+        }
+        return decode_modrm_16(cursor, mode, rm);
+    }
+
+    let mut mem = MemoryOperand {
+        base: None,
+        index: None,
+        scale: 1,
+        displacement: 0,
+        rip_relative: false,
+        address_size,
+        vector_index,
+        segment: None,
+    };
+
+    match rm {
+        4 => {
+            let sib = cursor.u8()?;
+            let index = ((sib >> 3) & 0x07)
+                | if prefixes.contains(Prefixes::REX_X) {
+                    8
+                } else {
+                    0
+                };
+            let base = sib & 0x07;
+            if index != 4 || vector_index.is_some() {
+                // RSP can't be an index, but R12 and any vector register can
+                mem.index = Some(index);
+                mem.scale = 1 << (sib >> 6);
+            }
+            if base == 5 && mode == 0 {
+                // No base, disp32 follows (regardless of REX.B)
+                mem.displacement = cursor.u32()? as i32 as i64;
+            } else {
+                mem.base = Some(base | rex_b);
+            }
+        }
+        // Only 64-bit mode has RIP-relative addressing; the other modes take the
+        // displacement as an absolute address
+        5 if mode == 0 => {
+            mem.rip_relative = decode_mode == Mode::Bits64;
+            mem.displacement = cursor.u32()? as i32 as i64;
+        }
+        _ => mem.base = Some(rm | rex_b),
+    }
+
+    match mode {
+        1 => mem.displacement = cursor.u8()? as i8 as i64,
+        2 => mem.displacement = cursor.u32()? as i32 as i64,
+        _ => {}
+    }
+
+    Ok(ModRM::Memory(mem))
+}
+
+/// The base and index registers of the 16-bit ModR/M forms, by `r/m`.
+const MODRM_16: [(u8, Option<u8>); 8] = [
+    (3, Some(6)), // [bx+si]
+    (3, Some(7)), // [bx+di]
+    (5, Some(6)), // [bp+si]
+    (5, Some(7)), // [bp+di]
+    (6, None),    // [si]
+    (7, None),    // [di]
+    (5, None),    // [bp], or disp16 alone with mod 00
+    (3, None),    // [bx]
+];
+
+/// Reads the displacement of a memory operand with 16-bit addressing, which has no SIB
+/// byte or REX extensions.
+fn decode_modrm_16(cursor: &mut Cursor, mode: u8, rm: u8) -> Result<ModRM, Exception> {
+    let mut mem = MemoryOperand {
+        base: None,
+        index: None,
+        scale: 1,
+        displacement: 0,
+        rip_relative: false,
+        address_size: OperandSize::R16,
+        vector_index: None,
+        segment: None,
+    };
+    if rm == 6 && mode == 0 {
+        mem.displacement = cursor.u16()? as i64;
+    } else {
+        let (base, index) = MODRM_16[rm as usize];
+        mem.base = Some(base);
+        mem.index = index;
+    }
+    match mode {
+        1 => mem.displacement = cursor.u8()? as i8 as i64,
+        2 => mem.displacement = cursor.u16()? as i16 as i64,
+        _ => {}
+    }
+    Ok(ModRM::Memory(mem))
+}
+
+fn needs_modrm(specs: &[Spec]) -> bool {
+    specs.iter().any(|spec| {
+        matches!(
+            spec,
+            Eb | Ew
+                | Ed
+                | Ev
+                | Gb
+                | Gw
+                | Gv
+                | Ey
+                | Gy
+                | M
+                | Mb
+                | Mw
+                | Md
+                | Mt
+                | Mp
+                | Ms
+                | Mq
+                | Mdq
+                | RvMw
+                | Rq
+                | Sw
+                | Cq
+                | Dq
+                | StI
+                | Pq
+                | Qq
+                | Qd
+                | Nq
+                | Vx
+                | Vq
+                | Vd
+                | Vdq
+                | Wx
+                | Wq
+                | Wd
+                | Ww
+                | Wb
+                | Wdq
+                | Wh
+                | Wf
+                | We
+                | Ux
+                | Mx
+                | Mh
+                | VSx
+                | VSdq
+                | My
+                | RdMw
+                | RdMb
+                | UxMd
+                | Vh
+                | Kg
+                | Kr
+                | Ke
+                | Ry
+        )
+    })
+}
+
+/// A half, quarter or eighth of a vector: the narrow side of a conversion.
+fn vector_fraction(size: OperandSize, divisor: u64) -> OperandSize {
+    match size.bytes() / divisor {
+        2 => OperandSize::R16,
+        4 => OperandSize::R32,
+        8 => OperandSize::R64,
+        16 => OperandSize::R128,
+        _ => OperandSize::R256,
+    }
+}
+
+/// Decodes the instruction at `address` as code of the given mode, reading its bytes
+/// through `fetch`.
+///
+/// Undefined encodings raise #UD and encodings longer than 15 bytes raise #GP(0); faults
+/// returned by `fetch` are passed through unchanged.
+pub fn decode(
+    address: u64,
+    mode: Mode,
+    fetch: &mut dyn FnMut(u64) -> Result<u8, Exception>,
+) -> Result<Instruction, Exception> {
+    let mut cursor = Cursor {
+        address,
+        length: 0,
+        fetch,
+    };
+    let mut prefixes = Prefixes::NONE;
+    let mut segment = None;
+
+    let mut byte = cursor.u8()?;
+    loop {
+        match byte {
+            0xF0 => prefixes |= Prefixes::LOCK,
+            0xF2 => prefixes = (prefixes - Prefixes::REP) | Prefixes::REPNE,
+            0xF3 => prefixes = (prefixes - Prefixes::REPNE) | Prefixes::REP,
+            0x66 => prefixes |= Prefixes::OPSIZE,
+            0x67 => prefixes |= Prefixes::ADDRSIZE,
+            0x26 => segment = Some(0),
+            0x2E => segment = Some(1),
+            0x36 => segment = Some(2),
+            0x3E => segment = Some(3),
+            0x64 => segment = Some(4),
+            0x65 => segment = Some(5),
+            // Outside 64-bit mode these are the one-byte INC and DEC
+            0x40..=0x4F if mode == Mode::Bits64 => {
+                let next = cursor.u8()?;
+                if matches!(next, 0x26 | 0x2E | 0x36 | 0x3E | 0x40..=0x4F | 0x64..=0x67 | 0xF0 | 0xF2 | 0xF3)
+                {
+                    // A REX prefix only counts if it immediately precedes the opcode
+                    byte = next;
+                    continue;
+                }
+                prefixes |= Prefixes::REX;
+                prefixes.set(Prefixes::REX_W, byte & 0x08 != 0);
+                prefixes.set(Prefixes::REX_R, byte & 0x04 != 0);
+                prefixes.set(Prefixes::REX_X, byte & 0x02 != 0);
+                prefixes.set(Prefixes::REX_B, byte & 0x01 != 0);
+                byte = next;
+                break;
+            }
+            _ => break,
+        }
+        byte = cursor.u8()?;
+    }
+
+    if mode == Mode::Bits64 && segment.is_some_and(|sreg| sreg < 4) {
+        // ES, CS, SS and DS overrides are ignored in 64-bit mode
+        segment = None;
+    }
+
+    // Index into an `Entry::Mandatory` table: none, 66, F3 or F2, with the last two winning
+    let mut mandatory = if prefixes.contains(Prefixes::REPNE) {
+        3
+    } else if prefixes.contains(Prefixes::REP) {
+        2
+    } else if prefixes.contains(Prefixes::OPSIZE) {
+        1
+    } else {
+        0
+    };
+    let mut vvvv = 0;
+    // The last EVEX payload byte, and the fifth bits of vector register numbers it and the
+    // first payload byte extend ModR/M `reg` and a register `r/m` with
+    let mut evex_payload = None;
+    let (mut high_reg, mut high_rm) = (0, 0);
+    // Outside 64-bit mode, C4, C5 and 62 are only VEX and EVEX when the next byte couldn't
+    // be the ModR/M byte of a memory operand of LES, LDS or BOUND. The bits that tests are
+    // inverted register-number extensions, which end up clear as they have to with only
+    // eight registers.
+    let avx_prefix =
+        matches!(byte, 0xC4 | 0xC5 | 0x62) && (mode == Mode::Bits64 || cursor.peek()? >> 6 == 3);
+    let (map, opcode) = match byte {
+        0xC4 | 0xC5 if avx_prefix => {
+            // VEX replaces the REX and SIMD prefixes, so it can't be combined with them
+            if prefixes.intersects(
+                Prefixes::OPSIZE | Prefixes::REP | Prefixes::REPNE | Prefixes::LOCK | Prefixes::REX,
+            ) {
+                return Err(Exception::InvalidOpcode);
+            }
+            let first = cursor.u8()?;
+            let (map_select, last) = if byte == 0xC5 {
+                (1, first)
+            } else {
+                let second = cursor.u8()?;
+                prefixes.set(Prefixes::REX_X, first & 0x40 == 0);
+                prefixes.set(Prefixes::REX_B, first & 0x20 == 0);
+                prefixes.set(Prefixes::REX_W, second & 0x80 != 0);
+                (first & 0x1F, second)
+            };
+            prefixes |= Prefixes::VEX;
+            prefixes.set(Prefixes::REX_R, first & 0x80 == 0);
+            prefixes.set(Prefixes::VEX_L, last & 0x04 != 0);
+            vvvv = (!last >> 3) & 0x0F;
+            mandatory = last & 0x03;
+            let map = match map_select {
+                1 => OpcodeMap::Map0F,
+                2 => OpcodeMap::Map0F38,
+                3 => OpcodeMap::Map0F3A,
+                _ => return Err(Exception::InvalidOpcode),
+            };
+            (map, cursor.u8()?)
+        }
+        0x62 if avx_prefix => {
+            // So does EVEX
+            if prefixes.intersects(
+                Prefixes::OPSIZE | Prefixes::REP | Prefixes::REPNE | Prefixes::LOCK | Prefixes::REX,
+            ) {
+                return Err(Exception::InvalidOpcode);
+            }
+            let (first, second, last) = (cursor.u8()?, cursor.u8()?, cursor.u8()?);
+            // Bits 3:2 of the first payload byte are 0 and bit 2 of the second is 1
+            if first & 0x0C != 0 || second & 0x04 == 0 {
+                return Err(Exception::InvalidOpcode);
+            }
+            prefixes |= Prefixes::EVEX;
+            prefixes.set(Prefixes::REX_R, first & 0x80 == 0);
+            prefixes.set(Prefixes::REX_X, first & 0x40 == 0);
+            prefixes.set(Prefixes::REX_B, first & 0x20 == 0);
+            prefixes.set(Prefixes::REX_W, second & 0x80 != 0);
+            // EVEX.R', EVEX.X and EVEX.V'
+            high_reg = if first & 0x10 == 0 { 16 } else { 0 };
+            high_rm = if first & 0x40 == 0 { 16 } else { 0 };
+            vvvv = (!second >> 3) & 0x0F | if last & 0x08 == 0 { 16 } else { 0 };
+            mandatory = second & 0x03;
+            evex_payload = Some(last);
+            let map = match first & 0x03 {
+                1 => OpcodeMap::Map0F,
+                2 => OpcodeMap::Map0F38,
+                3 => OpcodeMap::Map0F3A,
+                _ => return Err(Exception::InvalidOpcode),
+            };
+            (map, cursor.u8()?)
+        }
+        0x0F => match cursor.u8()? {
+            0x38 => (OpcodeMap::Map0F38, cursor.u8()?),
+            0x3A => (OpcodeMap::Map0F3A, cursor.u8()?),
+            opcode => (OpcodeMap::Map0F, opcode),
+        },
+        opcode => (OpcodeMap::Primary, opcode),
+    };
+    let vex = prefixes.contains(Prefixes::VEX);
+    let evex = prefixes.contains(Prefixes::EVEX);
+    let avx = vex || evex;
+    if avx && mode != Mode::Bits64 {
+        // The other extensions are ignored
+        prefixes.remove(Prefixes::REX_B);
+        high_reg = 0;
+        vvvv &= 0x07;
+    }
+
+    let entry = match (map, vex) {
+        _ if evex => Entry::Simd,
+        (OpcodeMap::Primary, false) => primary(opcode),
+        (OpcodeMap::Map0F, false) => secondary(opcode),
+        (OpcodeMap::Map0F38, false) => map_0f38(opcode),
+        (OpcodeMap::Map0F38, true) => vex_0f38(opcode),
+        (OpcodeMap::Map0F3A, false) => map_0f3a(opcode),
+        (OpcodeMap::Map0F3A, true) => vex_0f3a(opcode),
+        (OpcodeMap::Map0F, true) => Entry::Simd,
+        (OpcodeMap::Primary, true) => Entry::Invalid,
+    };
+    let entry = match entry {
+        Entry::Mandatory(entries) => entries[mandatory as usize],
+        Entry::Simd if vex => simd::vex(map, opcode, mandatory, prefixes.contains(Prefixes::REX_W)),
+        Entry::Simd if evex => {
+            simd::evex(map, opcode, mandatory, prefixes.contains(Prefixes::REX_W))
+        }
+        Entry::Simd => match map {
+            OpcodeMap::Map0F => simd::map_0f(opcode, mandatory),
+            OpcodeMap::Map0F38 => simd::map_0f38(opcode, mandatory),
+            OpcodeMap::Map0F3A => simd::map_0f3a(opcode, mandatory),
+            OpcodeMap::Primary => Entry::Invalid,
+        },
+        entry => entry,
+    };
+    // VEX.W and EVEX.W choose between instructions and element sizes in any mode, but only
+    // widen general-purpose operands in 64-bit mode
+    let element_size = if prefixes.contains(Prefixes::REX_W) {
+        OperandSize::R64
+    } else {
+        OperandSize::R32
+    };
+    if mode != Mode::Bits64 {
+        prefixes.remove(Prefixes::REX_W);
+    }
+
+    let mut modrm_byte = None;
+    let (mut mnemonic, specs, attr) = match entry {
+        Entry::Invalid | Entry::Mandatory(_) | Entry::Simd => return Err(Exception::InvalidOpcode),
+        Entry::Op(def) => def,
+        Entry::Group(group) => {
+            let byte = cursor.u8()?;
+            modrm_byte = Some(byte);
+            group[((byte >> 3) & 0x07) as usize].ok_or(Exception::InvalidOpcode)?
+        }
+        Entry::Escape(escape) => {
+            let byte = cursor.u8()?;
+            modrm_byte = Some(byte);
+            x87(escape, byte).ok_or(Exception::InvalidOpcode)?
+        }
+    };
+    if modrm_byte.is_none() && needs_modrm(specs) {
+        modrm_byte = Some(cursor.u8()?);
+    }
+    let register_form = modrm_byte.is_some_and(|byte| byte >> 6 == 3);
+
+    let mut evex_controls = None;
+    if let Some(payload) = evex_payload {
+        let mut controls = Evex {
+            mask: payload & 0x07,
+            zeroing: payload & 0x80 != 0,
+            ..Evex::default()
+        };
+        let mut length = payload >> 5 & 0x03;
+        // EVEX.b broadcasts a memory operand, or between registers makes the vector 512
+        // bits and EVEX.L'L the rounding mode
+        let mut broadcast = false;
+        if payload & 0x10 != 0 {
+            if !register_form && attr.contains(Attr::BCST) {
+                broadcast = true;
+            } else if register_form && attr.contains(Attr::ER) {
+                controls.rounding = Some(length);
+                length = 2;
+            } else if register_form && attr.contains(Attr::SAE) {
+                controls.sae = true;
+                length = 2;
+            } else {
+                return Err(Exception::InvalidOpcode);
+            }
+        }
+        match length {
+            0 => {}
+            1 => prefixes |= Prefixes::VEX_L,
+            2 => prefixes |= Prefixes::EVEX_L2,
+            _ if attr.contains(Attr::LIG) => {}
+            _ => return Err(Exception::InvalidOpcode),
+        }
+        evex_controls = Some((controls, broadcast));
+    }
+    if attr.contains(Attr::LIG) {
+        prefixes.remove(Prefixes::VEX_L | Prefixes::EVEX_L2);
+    }
+    // Whether the vector is wider than 128 bits
+    let long = prefixes.intersects(Prefixes::VEX_L | Prefixes::EVEX_L2);
+    if (attr.contains(Attr::I64) && mode == Mode::Bits64)
+        || (attr.contains(Attr::O64) && mode != Mode::Bits64)
+        || (attr.contains(Attr::L0) && long)
+        || (attr.contains(Attr::L1) && !long)
+        || (attr.contains(Attr::L2) && !prefixes.contains(Prefixes::EVEX_L2))
+    {
+        return Err(Exception::InvalidOpcode);
+    }
+
+    let operand_size = if mode == Mode::Bits64 {
+        if attr.contains(Attr::F64)
+            || prefixes.contains(Prefixes::REX_W)
+            || (attr.contains(Attr::D64) && !prefixes.contains(Prefixes::OPSIZE))
+        {
+            OperandSize::R64
+        } else if prefixes.contains(Prefixes::OPSIZE) {
+            OperandSize::R16
+        } else {
+            OperandSize::R32
+        }
+    } else if (mode == Mode::Bits32) != prefixes.contains(Prefixes::OPSIZE) {
+        // 66 switches between the 16- and 32-bit sizes, whichever the default
+        OperandSize::R32
+    } else {
+        OperandSize::R16
+    };
+    let address_size = match (mode, prefixes.contains(Prefixes::ADDRSIZE)) {
+        (Mode::Bits64, true) | (Mode::Bits16, true) | (Mode::Bits32, false) => OperandSize::R32,
+        (Mode::Bits64, false) => OperandSize::R64,
+        _ => OperandSize::R16,
+    };
+
+    let vector_size = if prefixes.contains(Prefixes::EVEX_L2) {
+        OperandSize::R512
+    } else if long {
+        OperandSize::R256
+    } else {
+        OperandSize::R128
+    };
+    let broadcast = evex_controls.is_some_and(|(_, broadcast)| broadcast);
+    let evex_controls = evex_controls.map(|(controls, _)| Evex {
+        vector_length: (!attr.intersects(Attr::LIG | Attr::L0)).then_some(vector_size),
+        ..controls
+    });
+    let vector_index = if specs.contains(&VSx) {
+        Some(vector_size)
+    } else if specs.contains(&VSdq) && vector_size == OperandSize::R512 {
+        // EVEX doubleword indices of quadword elements take half a ZMM register
+        Some(OperandSize::R256)
+    } else if specs.contains(&VSdq) {
+        Some(OperandSize::R128)
+    } else {
+        None
+    };
+    let mut modrm = match modrm_byte {
+        Some(byte) if needs_modrm(specs) => Some(decode_modrm(
+            &mut cursor,
+            byte,
+            prefixes,
+            mode,
+            address_size,
+            vector_index,
+        )?),
+        _ => None,
+    };
+    if let (true, Some(ModRM::Memory(mem))) = (vector_index.is_some(), &mut modrm) {
+        // EVEX.V' extends a VSIB index to the upper sixteen vector registers, rather than
+        // VEX.vvvv, which a gather or scatter doesn't use
+        if vvvv & 16 != 0 {
+            mem.index = mem.index.map(|index| index | 16);
+            vvvv &= 0x0F;
+        }
+    }
+    let reg = modrm_byte.map_or(0, |byte| {
+        ((byte >> 3) & 0x07)
+            | if prefixes.contains(Prefixes::REX_R) {
+                8
+            } else {
+                0
+            }
+    });
+
+    // Encodings whose meaning depends on more than the opcode tables capture
+    match (map, opcode) {
+        (OpcodeMap::Primary, 0x90) if prefixes.contains(Prefixes::REX_B) => mnemonic = Xchg,
+        (OpcodeMap::Primary, 0x90) if prefixes.contains(Prefixes::REP) => mnemonic = Pause,
+        (OpcodeMap::Primary, 0x63) if mode != Mode::Bits64 => mnemonic = Arpl,
+        // CS can't be loaded by MOV
+        (OpcodeMap::Primary, 0x8E) if reg & 0x07 == 1 => return Err(Exception::InvalidOpcode),
+        (OpcodeMap::Primary, 0x98) => {
+            mnemonic = match operand_size {
+                OperandSize::R16 => Cbw,
+                OperandSize::R32 => Cwde,
+                _ => Cdqe,
+            }
+        }
+        (OpcodeMap::Primary, 0x99) => {
+            mnemonic = match operand_size {
+                OperandSize::R16 => Cwd,
+                OperandSize::R32 => Cdq,
+                _ => Cqo,
+            }
+        }
+        (OpcodeMap::Primary, 0xCF) => {
+            mnemonic = match operand_size {
+                OperandSize::R16 => Iret,
+                OperandSize::R32 => Iretd,
+                _ => Iretq,
+            }
+        }
+        (OpcodeMap::Map0F, 0x6E | 0x7E) if prefixes.contains(Prefixes::REX_W) => mnemonic = Movq,
+        (OpcodeMap::Map0F, 0x77) if vex && long => mnemonic = Vzeroall,
+        // KMOV only stores an opmask register to memory
+        (OpcodeMap::Map0F, 0x91) if vex && register_form => return Err(Exception::InvalidOpcode),
+        (OpcodeMap::Map0F3A, 0x16) if prefixes.contains(Prefixes::REX_W) => mnemonic = Pextrq,
+        (OpcodeMap::Map0F3A, 0x22) if prefixes.contains(Prefixes::REX_W) => mnemonic = Pinsrq,
+        // The register forms of MOVLPS and MOVHPS move between the halves of two registers
+        (OpcodeMap::Map0F, 0x12) if mandatory == 0 && matches!(modrm, Some(ModRM::Register(_))) => {
+            mnemonic = Movhlps
+        }
+        (OpcodeMap::Map0F, 0x16) if mandatory == 0 && matches!(modrm, Some(ModRM::Register(_))) => {
+            mnemonic = Movlhps
+        }
+        // RDRAND and RDSEED only write registers
+        (OpcodeMap::Map0F, 0xC7) if reg & 0x07 >= 6 && !register_form => {
+            return Err(Exception::InvalidOpcode)
+        }
+        (OpcodeMap::Map0F, 0xAE) if mandatory == 0 => {
+            let register = modrm_byte.is_some_and(|byte| byte >> 6 == 3);
+            match (register, reg & 0x07) {
+                (true, 5) => mnemonic = Lfence,
+                (true, 6) => mnemonic = Mfence,
+                (true, 7) => mnemonic = Sfence,
+                (false, _) => {}
+                _ => return Err(Exception::InvalidOpcode),
+            }
+        }
+        (OpcodeMap::Map0F, 0x1E) if prefixes.contains(Prefixes::REP) => match modrm_byte {
+            Some(0xFA) => mnemonic = Endbr64,
+            Some(0xFB) => mnemonic = Endbr32,
+            _ => {}
+        },
+        (OpcodeMap::Map0F, 0x01) if matches!(modrm, Some(ModRM::Register(_))) => {
+            mnemonic = match modrm_byte {
+                Some(0xD0) => Xgetbv,
+                Some(0xD1) => Xsetbv,
+                Some(0xF8) if mode == Mode::Bits64 => Swapgs,
+                Some(0xF9) => Rdtscp,
+                // SMSW and LMSW have register forms
+                Some(byte) if (byte >> 3) & 0x07 == 4 => Smsw,
+                Some(byte) if (byte >> 3) & 0x07 == 6 => Lmsw,
+                _ => return Err(Exception::InvalidOpcode),
+            };
+        }
+        _ => {}
+    }
+    let specs: &[Spec] = match mnemonic {
+        Xchg if opcode == 0x90 => &[Zv, Ax],
+        Arpl => &[Ew, Gw],
+        Xgetbv | Xsetbv | Swapgs | Rdtscp | Endbr64 | Endbr32 | Lfence | Mfence | Sfence => &[],
+        Cmpxchg8b if prefixes.contains(Prefixes::REX_W) => {
+            mnemonic = Cmpxchg16b;
+            &[Mdq]
+        }
+        // VMOVSS and VMOVSD between registers merge with a VEX.vvvv source
+        Movss | Movsd if avx && matches!(modrm, Some(ModRM::Register(_))) => {
+            match (mnemonic, opcode) {
+                (Movss, 0x10) => &[Vd, Hx, Wd],
+                (Movss, _) => &[Wd, Hx, Vd],
+                (_, 0x10) => &[Vq, Hx, Wq],
+                _ => &[Wq, Hx, Vq],
+            }
+        }
+        // The 256-bit VMOVDDUP duplicates both even elements of a whole vector
+        Movddup if long => &[Vx, Wx],
+        _ => specs,
+    };
+    if avx && vvvv != 0 && !specs.iter().any(|spec| matches!(spec, By | Hx | Hdq | Kh)) {
+        // VEX.vvvv must be 1111b when it doesn't name an operand
+        return Err(Exception::InvalidOpcode);
+    }
+    // With a VEX.vvvv source, the destination of a vector operation isn't also its first
+    // source, and neither is it for the shifts by an immediate, which VEX.vvvv names
+    let nds = avx && (matches!(specs, [Hx, ..]) || matches!(specs.get(1), Some(Hx | Hdq)));
+    // Opmask operands are as wide as a mask instruction's suffix says, and the compares
+    // write whole registers
+    let mask_size = match mnemonic.name().as_bytes() {
+        [b'K', .., b'b'] => OperandSize::R8,
+        [b'K', .., b'w'] => OperandSize::R16,
+        [b'K', .., b'd'] => OperandSize::R32,
+        _ => OperandSize::R64,
+    };
+    let vreg = reg | high_reg;
+
+    let mut operands = [Operand::None; 4];
+    for (slot, spec) in operands.iter_mut().zip(specs) {
+        let gpr = |n: u8, size: OperandSize| {
+            if size == OperandSize::R8 && !prefixes.contains(Prefixes::REX) && (4..8).contains(&n) {
+                Operand::Register(Register::HighByte(n - 4), size)
+            } else {
+                Operand::Register(Register::Gpr(n), size)
+            }
+        };
+        let rm = |size: OperandSize| match modrm.expect("ModR/M operand without a ModR/M byte") {
+            ModRM::Register(n) => gpr(n, size),
+            ModRM::Memory(mem) => Operand::Memory(mem, size),
+        };
+        let mem_only =
+            |size: OperandSize| match modrm.expect("ModR/M operand without a ModR/M byte") {
+                ModRM::Register(_) => Err(Exception::InvalidOpcode),
+                ModRM::Memory(mem) => Ok(Operand::Memory(mem, size)),
+            };
+        let implied = |base: u8| MemoryOperand {
+            base: Some(base),
+            index: None,
+            scale: 1,
+            displacement: 0,
+            rip_relative: false,
+            address_size,
+            vector_index: None,
+            segment: None,
+        };
+        let opcode_reg = (opcode & 0x07)
+            | if prefixes.contains(Prefixes::REX_B) {
+                8
+            } else {
+                0
+            };
+        let mmx = |n: u8| Operand::Register(Register::Mm(n & 0x07), OperandSize::R64);
+        let xmm = |n: u8, size: OperandSize| Operand::Register(Register::Vector(n), size);
+        let opmask = |n: u8| Operand::Register(Register::Opmask(n & 0x07), mask_size);
+        let dq_size = if prefixes.contains(Prefixes::REX_W) {
+            OperandSize::R64
+        } else {
+            OperandSize::R32
+        };
+        let imm_size = match operand_size {
+            OperandSize::R16 => OperandSize::R16,
+            _ => OperandSize::R32,
+        };
+        // Control and debug registers are moved at 32 bits outside 64-bit mode, as is
+        // INVPCID's type
+        let control_size = match mode {
+            Mode::Bits64 => OperandSize::R64,
+            _ => OperandSize::R32,
+        };
+
+        *slot = match spec {
+            Eb => rm(OperandSize::R8),
+            Ew => rm(OperandSize::R16),
+            Ed => rm(OperandSize::R32),
+            Ev => rm(operand_size),
+            Gb => gpr(reg, OperandSize::R8),
+            Gw => gpr(reg, OperandSize::R16),
+            Gv => gpr(reg, operand_size),
+            Ey => rm(dq_size),
+            Gy => gpr(reg, dq_size),
+            Gq => gpr(reg, control_size),
+            By => gpr(vvvv, dq_size),
+            M | Mp => mem_only(operand_size)?,
+            Mb => mem_only(OperandSize::R8)?,
+            Mw => mem_only(OperandSize::R16)?,
+            Md => mem_only(OperandSize::R32)?,
+            Mt => mem_only(OperandSize::R80)?,
+            Ms | Mq => mem_only(OperandSize::R64)?,
+            Mdq => mem_only(OperandSize::R128)?,
+            RvMw => match modrm.expect("ModR/M operand without a ModR/M byte") {
+                ModRM::Register(n) => gpr(n, operand_size),
+                ModRM::Memory(mem) => Operand::Memory(mem, OperandSize::R16),
+            },
+            Rq => match modrm {
+                Some(ModRM::Register(n)) => Operand::Register(Register::Gpr(n), control_size),
+                _ => return Err(Exception::InvalidOpcode),
+            },
+            Sw => {
+                if reg & 0x07 > 5 {
+                    return Err(Exception::InvalidOpcode);
+                }
+                Operand::Register(Register::Segment(reg & 0x07), OperandSize::R16)
+            }
+            Cq => Operand::Register(Register::Control(reg), control_size),
+            Dq => Operand::Register(Register::Debug(reg), control_size),
+            Ib => Operand::Immediate(cursor.u8()? as u64, OperandSize::R8),
+            Ibs => Operand::Immediate(cursor.u8()? as i8 as i64 as u64, operand_size),
+            Iw => Operand::Immediate(cursor.u16()? as u64, OperandSize::R16),
+            Iz => match imm_size {
+                OperandSize::R16 => {
+                    Operand::Immediate(cursor.u16()? as i16 as i64 as u64, operand_size)
+                }
+                _ => Operand::Immediate(cursor.u32()? as i32 as i64 as u64, operand_size),
+            },
+            Iv => match operand_size {
+                OperandSize::R64 => Operand::Immediate(cursor.u64()?, operand_size),
+                OperandSize::R16 => Operand::Immediate(cursor.u16()? as u64, operand_size),
+                _ => Operand::Immediate(cursor.u32()? as u64, operand_size),
+            },
+            Jb => Operand::Relative(cursor.u8()? as i8 as i64),
+            Jz => match operand_size {
+                OperandSize::R16 => Operand::Relative(cursor.u16()? as i16 as i64),
+                _ => Operand::Relative(cursor.u32()? as i32 as i64),
+            },
+            Ap => {
+                let offset = match operand_size {
+                    OperandSize::R16 => cursor.u16()? as u64,
+                    _ => cursor.u32()? as u64,
+                };
+                Operand::Far(cursor.u16()?, offset)
+            }
+            Al => Operand::Register(Register::Gpr(0), OperandSize::R8),
+            Ax => Operand::Register(Register::Gpr(0), operand_size),
+            Aw => Operand::Register(Register::Gpr(0), OperandSize::R16),
+            Cl => Operand::Register(Register::Gpr(1), OperandSize::R8),
+            Dx => Operand::Register(Register::Gpr(2), OperandSize::R16),
+            Zb => gpr(opcode_reg, OperandSize::R8),
+            Zv => Operand::Register(Register::Gpr(opcode_reg), operand_size),
+            Ob | Ov => {
+                let offset = match address_size {
+                    OperandSize::R64 => cursor.u64()?,
+                    OperandSize::R32 => cursor.u32()? as u64,
+                    _ => cursor.u16()? as u64,
+                };
+                let mem = MemoryOperand {
+                    base: None,
+                    index: None,
+                    scale: 1,
+                    displacement: offset as i64,
+                    rip_relative: false,
+                    address_size,
+                    vector_index: None,
+                    segment: None,
+                };
+                Operand::Memory(
+                    mem,
+                    if *spec == Ob {
+                        OperandSize::R8
+                    } else {
+                        operand_size
+                    },
+                )
+            }
+            Xb => Operand::Memory(implied(6), OperandSize::R8),
+            Xv => Operand::Memory(implied(6), operand_size),
+            Yb => Operand::Memory(implied(7), OperandSize::R8),
+            Yv => Operand::Memory(implied(7), operand_size),
+            One => Operand::Immediate(1, OperandSize::R8),
+            Es => Operand::Register(Register::Segment(0), OperandSize::R16),
+            Cs => Operand::Register(Register::Segment(1), OperandSize::R16),
+            Ss => Operand::Register(Register::Segment(2), OperandSize::R16),
+            Ds => Operand::Register(Register::Segment(3), OperandSize::R16),
+            Fs => Operand::Register(Register::Segment(4), OperandSize::R16),
+            Gs => Operand::Register(Register::Segment(5), OperandSize::R16),
+            St0 => Operand::Register(Register::St(0), OperandSize::R80),
+            // Stack registers ignore REX.B
+            StI => Operand::Register(
+                Register::St(modrm_byte.unwrap_or(0) & 0x07),
+                OperandSize::R80,
+            ),
+            // MMX registers ignore REX.R and REX.B
+            Pq => mmx(reg),
+            Qq | Qd => match modrm.expect("ModR/M operand without a ModR/M byte") {
+                ModRM::Register(n) => mmx(n),
+                ModRM::Memory(mem) if *spec == Qq => Operand::Memory(mem, OperandSize::R64),
+                ModRM::Memory(mem) => Operand::Memory(mem, OperandSize::R32),
+            },
+            Nq => match modrm {
+                Some(ModRM::Register(n)) => mmx(n),
+                _ => return Err(Exception::InvalidOpcode),
+            },
+            Vx => xmm(vreg, vector_size),
+            Vq => xmm(vreg, OperandSize::R64),
+            Vd => xmm(vreg, OperandSize::R32),
+            Vdq => xmm(vreg, OperandSize::R128),
+            Vh => xmm(vreg, vector_fraction(vector_size, 2)),
+            Wx | Wq | Wd | Ww | Wb | Wdq | Wh | Wf | We => {
+                let size = match spec {
+                    Wx => vector_size,
+                    Wq => OperandSize::R64,
+                    Wd => OperandSize::R32,
+                    Ww => OperandSize::R16,
+                    Wb => OperandSize::R8,
+                    Wh => vector_fraction(vector_size, 2),
+                    Wf => vector_fraction(vector_size, 4),
+                    We => vector_fraction(vector_size, 8),
+                    _ => OperandSize::R128,
+                };
+                match modrm.expect("ModR/M operand without a ModR/M byte") {
+                    ModRM::Register(n) => xmm(n | high_rm, size),
+                    ModRM::Memory(mem) if broadcast => Operand::Broadcast(mem, element_size, size),
+                    ModRM::Memory(mem) => Operand::Memory(mem, size),
+                }
+            }
+            Ux => match modrm {
+                Some(ModRM::Register(n)) => xmm(n | high_rm, vector_size),
+                _ => return Err(Exception::InvalidOpcode),
+            },
+            Mx => mem_only(vector_size)?,
+            Mh => mem_only(vector_fraction(vector_size, 2))?,
+            Hx => xmm(vvvv, vector_size),
+            Hdq => xmm(vvvv, OperandSize::R128),
+            Lx => xmm(cursor.u8()? >> 4, vector_size),
+            VSx | VSdq => mem_only(element_size)?,
+            My => mem_only(dq_size)?,
+            RdMw | RdMb => match modrm.expect("ModR/M operand without a ModR/M byte") {
+                ModRM::Register(n) => gpr(n, OperandSize::R32),
+                ModRM::Memory(mem) if *spec == RdMw => Operand::Memory(mem, OperandSize::R16),
+                ModRM::Memory(mem) => Operand::Memory(mem, OperandSize::R8),
+            },
+            UxMd => match modrm.expect("ModR/M operand without a ModR/M byte") {
+                ModRM::Register(n) => xmm(n, OperandSize::R128),
+                ModRM::Memory(mem) => Operand::Memory(mem, OperandSize::R32),
+            },
+            Xmm0 => xmm(0, OperandSize::R128),
+            Kg => opmask(reg),
+            Kh => opmask(vvvv),
+            Kr => match modrm {
+                Some(ModRM::Register(n)) => opmask(n),
+                _ => return Err(Exception::InvalidOpcode),
+            },
+            Ke => match modrm.expect("ModR/M operand without a ModR/M byte") {
+                ModRM::Register(n) => opmask(n),
+                ModRM::Memory(mem) => Operand::Memory(mem, mask_size),
+            },
+            Ry => match modrm {
+                Some(ModRM::Register(n)) => Operand::Register(Register::Gpr(n), dq_size),
+                _ => return Err(Exception::InvalidOpcode),
+            },
+        };
+    }
+
+    // An override applies to every memory operand but the destination of a string
+    // instruction, which is always in ES (though ES only has a base outside 64-bit mode)
+    for (operand, spec) in operands.iter_mut().zip(specs) {
+        if let Operand::Memory(mem, _) | Operand::Broadcast(mem, _, _) = operand {
+            if !matches!(spec, Yb | Yv) {
+                mem.segment = segment;
+            } else if mode != Mode::Bits64 {
+                mem.segment = Some(0);
+            }
+        }
+    }
+
+    if let Some(controls) = evex_controls {
+        // Stores and writes to opmask registers can only merge
+        if controls.zeroing
+            && matches!(
+                operands[0],
+                Operand::Memory(..) | Operand::Register(Register::Opmask(_), _)
+            )
+        {
+            return Err(Exception::InvalidOpcode);
+        }
+        // Gathers and scatters clear their opmask as elements complete, so need one other
+        // than k0, and can only merge
+        if vector_index.is_some() && (controls.mask == 0 || controls.zeroing) {
+            return Err(Exception::InvalidOpcode);
+        }
+        // An 8-bit displacement counts in units of the memory access (disp8*N), which for
+        // the compresses and expands, accessing only the elements they select, is one
+        if modrm_byte.is_some_and(|byte| byte >> 6 == 1) {
+            let element = match mnemonic {
+                Vcompressps | Vexpandps | Vpcompressd | Vpexpandd => Some(4),
+                Vcompresspd | Vexpandpd | Vpcompressq | Vpexpandq => Some(8),
+                _ => None,
+            };
+            for operand in &mut operands {
+                if let Operand::Memory(mem, size) | Operand::Broadcast(mem, size, _) = operand {
+                    mem.displacement *= element.unwrap_or(size.bytes()) as i64;
+                }
+            }
+        }
+    }
+
+    if prefixes.contains(Prefixes::LOCK) {
+        // LOCK is only allowed on read-modify-write instructions with a memory destination
+        let lockable = matches!(
+            mnemonic,
+            Add | Adc
+                | And
+                | Btc
+                | Btr
+                | Bts
+                | Cmpxchg
+                | Cmpxchg8b
+                | Cmpxchg16b
+                | Dec
+                | Inc
+                | Neg
+                | Not
+                | Or
+                | Sbb
+                | Sub
+                | Xadd
+                | Xchg
+                | Xor
+        );
+        if !lockable || !matches!(operands[0], Operand::Memory(..)) {
+            return Err(Exception::InvalidOpcode);
+        }
+    }
+
+    let condition = match mnemonic {
+        Jcc | Setcc | Cmovcc => opcode & 0x0F,
+        // FCMOVB, FCMOVE, FCMOVBE and FCMOVU, negated by DB rather than DA
+        Fcmovcc => [0x2, 0x4, 0x6, 0xA][(reg & 0x03) as usize] | (opcode & 0x01),
+        _ => 0,
+    };
+
+    Ok(Instruction {
+        address,
+        length: cursor.length,
+        prefixes,
+        segment,
+        map,
+        opcode,
+        mnemonic,
+        condition,
+        operand_size,
+        address_size,
+        modrm: modrm_byte,
+        evex: evex_controls,
+        operands,
+        operand_count: specs.len() as u8,
+        nds,
+    })
+}
+
+const GPR_NAMES: [[&str; 16]; 4] = [
+    [
+        "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+        "r13b", "r14b", "r15b",
+    ],
+    [
+        "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w",
+        "r13w", "r14w", "r15w",
+    ],
+    [
+        "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d",
+        "r12d", "r13d", "r14d", "r15d",
+    ],
+    [
+        "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12",
+        "r13", "r14", "r15",
+    ],
+];
+
+const SEGMENT_NAMES: [&str; 6] = ["es", "cs", "ss", "ds", "fs", "gs"];
+
+const CONDITION_NAMES: [&str; 16] = [
+    "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+
+fn gpr_name(n: u8, size: OperandSize) -> &'static str {
+    let row = match size {
+        OperandSize::R8 => 0,
+        OperandSize::R16 => 1,
+        OperandSize::R32 => 2,
+        OperandSize::R64 => 3,
+        OperandSize::R80 | OperandSize::R128 | OperandSize::R256 | OperandSize::R512 => {
+            unreachable!("there are no general-purpose registers that wide")
+        }
+    };
+    GPR_NAMES[row][n as usize]
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Register::Gpr(n) => f.write_str(gpr_name(n, OperandSize::R64)),
+            Register::HighByte(n) => f.write_str(["ah", "ch", "dh", "bh"][n as usize]),
+            Register::Segment(n) => f.write_str(SEGMENT_NAMES[n as usize]),
+            Register::Control(n) => write!(f, "cr{}", n),
+            Register::Debug(n) => write!(f, "dr{}", n),
+            Register::St(0) => f.write_str("st"),
+            Register::St(n) => write!(f, "st({})", n),
+            Register::Mm(n) => write!(f, "mm{}", n),
+            Register::Vector(n) => write!(f, "xmm{}", n),
+            Register::Opmask(n) => write!(f, "k{}", n),
+        }
+    }
+}
+
+impl fmt::Display for MemoryOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[")?;
+        let mut first = true;
+        if self.rip_relative {
+            f.write_str(if self.address_size == OperandSize::R32 {
+                "eip"
+            } else {
+                "rip"
+            })?;
+            first = false;
+        }
+        if let Some(base) = self.base {
+            f.write_str(gpr_name(base, self.address_size))?;
+            first = false;
+        }
+        if let Some(index) = self.index {
+            if !first {
+                f.write_str("+")?;
+            }
+            match self.vector_index {
+                Some(OperandSize::R512) => write!(f, "zmm{}*{}", index, self.scale)?,
+                Some(OperandSize::R256) => write!(f, "ymm{}*{}", index, self.scale)?,
+                Some(_) => write!(f, "xmm{}*{}", index, self.scale)?,
+                // 16-bit addressing has no scale
+                None if self.address_size == OperandSize::R16 => {
+                    f.write_str(gpr_name(index, OperandSize::R16))?
+                }
+                None => write!(f, "{}*{}", gpr_name(index, self.address_size), self.scale)?,
+            }
+            first = false;
+        }
+        if first {
+            write!(
+                f,
+                "{:#x}",
+                self.displacement as u64 & self.address_size.mask()
+            )?;
+        } else if self.displacement < 0 {
+            write!(f, "-{:#x}", self.displacement.unsigned_abs())?;
+        } else if self.displacement > 0 {
+            write!(f, "+{:#x}", self.displacement)?;
+        }
+        f.write_str("]")
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name().to_lowercase())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefixes.contains(Prefixes::LOCK) {
+            f.write_str("lock ")?;
+        }
+        if matches!(self.mnemonic, Movs | Cmps | Stos | Lods | Scas | Ins | Outs) {
+            if self.prefixes.contains(Prefixes::REP) {
+                f.write_str(if matches!(self.mnemonic, Cmps | Scas) {
+                    "repe "
+                } else {
+                    "rep "
+                })?;
+            } else if self.prefixes.contains(Prefixes::REPNE) {
+                f.write_str("repne ")?;
+            }
+        }
+        match self.mnemonic {
+            Jcc => write!(f, "j{}", CONDITION_NAMES[self.condition as usize])?,
+            Setcc => write!(f, "set{}", CONDITION_NAMES[self.condition as usize])?,
+            Cmovcc => write!(f, "cmov{}", CONDITION_NAMES[self.condition as usize])?,
+            Fcmovcc => {
+                let negated = if self.condition & 0x01 != 0 { "n" } else { "" };
+                let condition = match self.condition & !0x01 {
+                    0x2 => "b",
+                    0x4 => "e",
+                    0x6 => "be",
+                    _ => "u",
+                };
+                write!(f, "fcmov{}{}", negated, condition)?
+            }
+            // The AVX forms of SSE instructions share their mnemonics
+            mnemonic
+                if self.prefixes.intersects(Prefixes::VEX | Prefixes::EVEX)
+                    && self.is_sse()
+                    && !mnemonic.name().starts_with('V') =>
+            {
+                write!(f, "v{}", mnemonic)?
+            }
+            mnemonic => write!(f, "{}", mnemonic)?,
+        }
+        let memory = |f: &mut fmt::Formatter<'_>, mem: &MemoryOperand, size| {
+            let ptr = match size {
+                OperandSize::R8 => "byte",
+                OperandSize::R16 => "word",
+                OperandSize::R32 => "dword",
+                OperandSize::R64 => "qword",
+                OperandSize::R80 => "tbyte",
+                OperandSize::R128 => "xmmword",
+                OperandSize::R256 => "ymmword",
+                OperandSize::R512 => "zmmword",
+            };
+            if let Some(sreg) = mem.segment {
+                write!(f, "{} ptr {}:{}", ptr, SEGMENT_NAMES[sreg as usize], mem)
+            } else {
+                write!(f, "{} ptr {}", ptr, mem)
+            }
+        };
+        for (i, operand) in self.operands().iter().enumerate() {
+            f.write_str(if i == 0 { " " } else { ", " })?;
+            match *operand {
+                Operand::None => {}
+                Operand::Register(Register::Gpr(n), size) => f.write_str(gpr_name(n, size))?,
+                Operand::Register(Register::Vector(n), OperandSize::R256) => write!(f, "ymm{}", n)?,
+                Operand::Register(Register::Vector(n), OperandSize::R512) => write!(f, "zmm{}", n)?,
+                Operand::Register(reg, _) => write!(f, "{}", reg)?,
+                Operand::Memory(mem, size) => memory(f, &mem, size)?,
+                Operand::Broadcast(mem, element, size) => {
+                    memory(f, &mem, element)?;
+                    write!(f, "{{1to{}}}", size.bytes() / element.bytes())?
+                }
+                Operand::Immediate(value, size) => write!(f, "{:#x}", value & size.mask())?,
+                Operand::Relative(offset) => write!(
+                    f,
+                    "{:#x}",
+                    self.next_address().wrapping_add(offset as u64) & self.operand_size.mask()
+                )?,
+                Operand::Far(selector, offset) => write!(f, "{:#x}:{:#x}", selector, offset)?,
+            }
+            // The opmask and zeroing of an EVEX destination
+            if let (0, Some(evex)) = (i, self.evex) {
+                if evex.mask != 0 {
+                    write!(f, "{{k{}}}", evex.mask)?;
+                }
+                if evex.zeroing {
+                    f.write_str("{z}")?;
+                }
+            }
+        }
+        if let Some(evex) = self.evex {
+            if let Some(rounding) = evex.rounding {
+                let mode = ["rn", "rd", "ru", "rz"][rounding as usize];
+                write!(f, ", {{{}-sae}}", mode)?;
+            } else if evex.sae {
+                f.write_str(", {sae}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bytes(bytes: &[u8]) -> Result<Instruction, Exception> {
+        decode_bytes_in(Mode::Bits64, bytes)
+    }
+
+    fn decode_bytes_in(mode: Mode, bytes: &[u8]) -> Result<Instruction, Exception> {
+        let bytes = bytes.to_vec();
+        decode(0x1000, mode, &mut |addr| {
+            Ok(bytes.get((addr - 0x1000) as usize).copied().unwrap_or(0))
+        })
+    }
+
+    fn disassemble(bytes: &[u8]) -> String {
+        disassemble_in(Mode::Bits64, bytes)
+    }
+
+    fn disassemble_in(mode: Mode, bytes: &[u8]) -> String {
+        let insn = decode_bytes_in(mode, bytes).unwrap();
+        assert_eq!(insn.length as usize, bytes.len());
+        insn.to_string()
+    }
+
+    #[test]
+    fn disassembles_start_sequence() {
+        assert_eq!(disassemble(&[0xF3, 0x0F, 0x1E, 0xFA]), "endbr64");
+        assert_eq!(disassemble(&[0x31, 0xED]), "xor ebp, ebp");
+        assert_eq!(disassemble(&[0x49, 0x89, 0xD1]), "mov r9, rdx");
+        assert_eq!(disassemble(&[0x5E]), "pop rsi");
+        assert_eq!(
+            disassemble(&[0x48, 0x83, 0xE4, 0xF0]),
+            "and rsp, 0xfffffffffffffff0"
+        );
+        assert_eq!(
+            disassemble(&[0x4C, 0x8D, 0x05, 0x66, 0x01, 0x00, 0x00]),
+            "lea r8, qword ptr [rip+0x166]"
+        );
+        assert_eq!(
+            disassemble(&[0xFF, 0x15, 0x72, 0x2F, 0x00, 0x00]),
+            "call qword ptr [rip+0x2f72]"
+        );
+    }
+
+    #[test]
+    fn byte_registers_depend_on_rex() {
+        assert_eq!(disassemble(&[0x88, 0xE0]), "mov al, ah");
+        assert_eq!(disassemble(&[0x40, 0x88, 0xE0]), "mov al, spl");
+    }
+
+    #[test]
+    fn operand_and_address_size_prefixes() {
+        assert_eq!(disassemble(&[0x66, 0x89, 0x08]), "mov word ptr [rax], cx");
+        assert_eq!(
+            disassemble(&[0x67, 0x8B, 0x41, 0xF8]),
+            "mov eax, dword ptr [ecx-0x8]"
+        );
+        assert_eq!(
+            disassemble(&[0x48, 0xB8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]),
+            "mov rax, 0x1122334455667788"
+        );
+    }
+
+    #[test]
+    fn relative_branches_resolve_from_next_instruction() {
+        assert_eq!(disassemble(&[0x74, 0x02]), "je 0x1004");
+        assert_eq!(disassemble(&[0xE8, 0xFB, 0xFF, 0xFF, 0xFF]), "call 0x1000");
+    }
+
+    #[test]
+    fn rex_before_legacy_prefix_is_ignored() {
+        let insn = decode_bytes(&[0x48, 0x66, 0x89, 0x08]).unwrap();
+        assert_eq!(insn.operand_size, OperandSize::R16);
+    }
+
+    #[test]
+    fn decodes_32_and_16_bit_code() {
+        let disassemble32 = |bytes: &[u8]| disassemble_in(Mode::Bits32, bytes);
+        // One-byte INC and DEC instead of REX
+        assert_eq!(disassemble32(&[0x40]), "inc eax");
+        assert_eq!(disassemble32(&[0x4F]), "dec edi");
+        assert_eq!(disassemble32(&[0x55]), "push ebp");
+        assert_eq!(
+            disassemble32(&[0x8B, 0x45, 0x08]),
+            "mov eax, dword ptr [ebp+0x8]"
+        );
+        // No RIP-relative addressing
+        assert_eq!(
+            disassemble32(&[0x8B, 0x05, 0x00, 0x20, 0x00, 0x00]),
+            "mov eax, dword ptr [0x2000]"
+        );
+        assert_eq!(disassemble32(&[0x66, 0x8B, 0x00]), "mov ax, word ptr [eax]");
+        assert_eq!(
+            disassemble32(&[0x2E, 0x8B, 0x00]),
+            "mov eax, dword ptr cs:[eax]"
+        );
+        assert_eq!(
+            disassemble32(&[0xA1, 0x00, 0x30, 0x00, 0x00]),
+            "mov eax, dword ptr [0x3000]"
+        );
+        assert_eq!(
+            disassemble32(&[0xE8, 0xFB, 0xFF, 0xFF, 0xFF]),
+            "call 0x1000"
+        );
+        assert_eq!(disassemble32(&[0x66, 0xE8, 0xFC, 0xEF]), "call 0x0");
+        assert_eq!(disassemble32(&[0xC2, 0x08, 0x00]), "ret 0x8");
+
+        // 16-bit addressing
+        assert_eq!(
+            disassemble32(&[0x67, 0x8B, 0x00]),
+            "mov eax, dword ptr [bx+si]"
+        );
+        assert_eq!(
+            disassemble32(&[0x67, 0x8B, 0x46, 0xFE]),
+            "mov eax, dword ptr [bp-0x2]"
+        );
+        assert_eq!(
+            disassemble32(&[0x67, 0x8B, 0x06, 0x34, 0x12]),
+            "mov eax, dword ptr [0x1234]"
+        );
+        assert_eq!(
+            disassemble_in(Mode::Bits16, &[0xB8, 0x34, 0x12]),
+            "mov ax, 0x1234"
+        );
+        assert_eq!(
+            disassemble_in(Mode::Bits16, &[0x8B, 0x07]),
+            "mov ax, word ptr [bx]"
+        );
+        assert_eq!(disassemble_in(Mode::Bits16, &[0x66, 0x40]), "inc eax");
+
+        // The instructions 64-bit mode dropped
+        assert_eq!(disassemble32(&[0x60]), "pusha");
+        assert_eq!(disassemble32(&[0x1E]), "push ds");
+        assert_eq!(disassemble32(&[0x27]), "daa");
+        assert_eq!(disassemble32(&[0xD4, 0x0A]), "aam 0xa");
+        assert_eq!(disassemble32(&[0xD6]), "salc");
+        assert_eq!(disassemble32(&[0x63, 0xC8]), "arpl ax, cx");
+        assert_eq!(
+            disassemble32(&[0x9A, 0x78, 0x56, 0x34, 0x12, 0x23, 0x00]),
+            "callf 0x23:0x12345678"
+        );
+        // C4, C5 and 62 are LES, LDS and BOUND unless they'd have a register operand
+        assert_eq!(disassemble32(&[0xC4, 0x00]), "les eax, dword ptr [eax]");
+        assert_eq!(disassemble32(&[0x62, 0x00]), "bound eax, dword ptr [eax]");
+        assert_eq!(disassemble32(&[0xC5, 0xF8, 0x77]), "vzeroupper");
+        assert_eq!(
+            disassemble32(&[0xC4, 0xC1, 0x7C, 0x58, 0xC1]),
+            "vaddps ymm0, ymm0, ymm1"
+        );
+        assert_eq!(decode_bytes(&[0x27]), Err(Exception::InvalidOpcode));
+
+        // And the ones only 64-bit mode has
+        for bytes in [&[0x0F, 0x01, 0xF8][..], &[0xF3, 0x0F, 0xAE, 0xC0]] {
+            assert_eq!(
+                decode_bytes_in(Mode::Bits32, bytes),
+                Err(Exception::InvalidOpcode)
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_real_mode_code() {
+        let disassemble16 = |bytes: &[u8]| disassemble_in(Mode::Bits16, bytes);
+        // String destinations are in ES, which only has a base outside 64-bit mode
+        assert_eq!(disassemble16(&[0xAA]), "stos byte ptr es:[di], al");
+        assert_eq!(
+            disassemble16(&[0x2E, 0xA5]),
+            "movs word ptr es:[di], word ptr cs:[si]"
+        );
+        assert_eq!(disassemble(&[0xAA]), "stos byte ptr [rdi], al");
+        assert_eq!(
+            disassemble16(&[0x26, 0x8B, 0x46, 0x02]),
+            "mov ax, word ptr es:[bp+0x2]"
+        );
+        // Far transfers with 16-bit offsets, and 32-bit ones with 0x66
+        assert_eq!(
+            disassemble16(&[0xEA, 0x00, 0x7C, 0x00, 0x00]),
+            "jmpf 0x0:0x7c00"
+        );
+        assert_eq!(
+            disassemble16(&[0x66, 0xEA, 0x78, 0x56, 0x34, 0x12, 0x08, 0x00]),
+            "jmpf 0x8:0x12345678"
+        );
+        assert_eq!(
+            disassemble16(&[0xFF, 0x1E, 0x00, 0x20]),
+            "callf word ptr [0x2000]"
+        );
+        assert_eq!(disassemble16(&[0xCB]), "retf");
+        assert_eq!(disassemble16(&[0xCD, 0x10]), "int 0x10");
+        assert_eq!(disassemble16(&[0xCF]), "iret");
+        assert_eq!(disassemble16(&[0x66, 0xCF]), "iretd");
+        assert_eq!(
+            disassemble16(&[0x67, 0x8B, 0x04, 0x24]),
+            "mov ax, word ptr [esp]"
+        );
+    }
+
+    #[test]
+    fn decodes_system_instructions() {
+        let disassemble32 = |bytes: &[u8]| disassemble_in(Mode::Bits32, bytes);
+        // Control registers are 32 bits wide outside 64-bit mode
+        assert_eq!(disassemble32(&[0x0F, 0x20, 0xC0]), "mov eax, cr0");
+        assert_eq!(disassemble(&[0x0F, 0x22, 0xD8]), "mov cr3, rax");
+        assert_eq!(
+            disassemble32(&[0x0F, 0x01, 0x15, 0x00, 0x50, 0x00, 0x00]),
+            "lgdt qword ptr [0x5000]"
+        );
+        assert_eq!(disassemble32(&[0x0F, 0x00, 0xD8]), "ltr ax");
+        assert_eq!(disassemble32(&[0x0F, 0x03, 0xD8]), "lsl ebx, ax");
+        assert_eq!(disassemble(&[0x0F, 0x30]), "wrmsr");
+        assert_eq!(disassemble(&[0x0F, 0x01, 0xD1]), "xsetbv");
+        assert_eq!(disassemble(&[0x0F, 0x01, 0x38]), "invlpg byte ptr [rax]");
+        // INVPCID's type register is 64 bits wide in 64-bit mode, whatever REX.W says
+        assert_eq!(
+            disassemble(&[0x66, 0x0F, 0x38, 0x82, 0x08]),
+            "invpcid rcx, xmmword ptr [rax]"
+        );
+        assert_eq!(
+            disassemble32(&[0x66, 0x0F, 0x38, 0x82, 0x08]),
+            "invpcid ecx, xmmword ptr [eax]"
+        );
+    }
+
+    #[test]
+    fn mandatory_prefixes_select_the_opcode() {
+        assert_eq!(disassemble(&[0x0F, 0xBD, 0xC1]), "bsr eax, ecx");
+        assert_eq!(
+            disassemble(&[0xF3, 0x48, 0x0F, 0xBD, 0xC1]),
+            "lzcnt rax, rcx"
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0xF3, 0x0F, 0xB8, 0xC1]),
+            "popcnt ax, cx"
+        );
+        assert_eq!(
+            decode_bytes(&[0x0F, 0xB8, 0xC1]),
+            Err(Exception::InvalidOpcode)
+        );
+    }
+
+    #[test]
+    fn lock_prefix_needs_a_memory_read_modify_write() {
+        assert_eq!(
+            disassemble(&[0xF0, 0x83, 0x00, 0x01]),
+            "lock add dword ptr [rax], 0x1"
+        );
+        assert_eq!(
+            disassemble(&[0xF0, 0x48, 0x0F, 0xC7, 0x0F]),
+            "lock cmpxchg16b xmmword ptr [rdi]"
+        );
+        assert_eq!(
+            decode_bytes(&[0xF0, 0x83, 0xC0, 0x01]),
+            Err(Exception::InvalidOpcode)
+        );
+        assert_eq!(
+            decode_bytes(&[0xF0, 0x89, 0x08]),
+            Err(Exception::InvalidOpcode)
+        );
+        assert_eq!(
+            decode_bytes(&[0x0F, 0xC7, 0xC8]),
+            Err(Exception::InvalidOpcode)
+        );
+    }
+
+    #[test]
+    fn fs_and_gs_overrides_and_bases() {
+        assert_eq!(
+            disassemble(&[0x64, 0x48, 0x8B, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]),
+            "mov rax, qword ptr fs:[0x28]"
+        );
+        assert_eq!(
+            disassemble(&[0x65, 0x48, 0x89, 0x48, 0x10]),
+            "mov qword ptr gs:[rax+0x10], rcx"
+        );
+        // The destination of a string instruction is always in ES, and the other overrides
+        // are ignored in 64-bit mode
+        assert_eq!(
+            disassemble(&[0x64, 0xA4]),
+            "movs byte ptr [rdi], byte ptr fs:[rsi]"
+        );
+        assert_eq!(disassemble(&[0x2E, 0x8B, 0x00]), "mov eax, dword ptr [rax]");
+        assert_eq!(disassemble(&[0xF3, 0x48, 0x0F, 0xAE, 0xC0]), "rdfsbase rax");
+        assert_eq!(disassemble(&[0xF3, 0x0F, 0xAE, 0xD9]), "wrgsbase ecx");
+        assert_eq!(disassemble(&[0x0F, 0x01, 0xF8]), "swapgs");
+        assert_eq!(
+            decode_bytes(&[0xF3, 0x0F, 0xAE, 0x00]),
+            Err(Exception::InvalidOpcode)
+        );
+    }
+
+    #[test]
+    fn segment_register_moves() {
+        assert_eq!(disassemble(&[0x8C, 0x20]), "mov word ptr [rax], fs");
+        assert_eq!(disassemble(&[0x8C, 0xE0]), "mov eax, fs");
+        assert_eq!(decode_bytes(&[0x8E, 0xC8]), Err(Exception::InvalidOpcode));
+    }
+
+    #[test]
+    fn vex_encoded_avx() {
+        assert_eq!(
+            disassemble(&[0xC5, 0xF4, 0x58, 0xC2]),
+            "vaddps ymm0, ymm1, ymm2"
+        );
+        assert_eq!(
+            disassemble(&[0xC5, 0xF2, 0x58, 0x00]),
+            "vaddss xmm0, xmm1, dword ptr [rax]"
+        );
+        assert_eq!(
+            disassemble(&[0xC5, 0xF2, 0x10, 0xC2]),
+            "vmovss xmm0, xmm1, xmm2"
+        );
+        assert_eq!(
+            disassemble(&[0xC5, 0xFE, 0x6F, 0x0F]),
+            "vmovdqu ymm1, ymmword ptr [rdi]"
+        );
+        assert_eq!(
+            disassemble(&[0xC5, 0xFD, 0x5A, 0xC1]),
+            "vcvtpd2ps xmm0, ymm1"
+        );
+        assert_eq!(
+            disassemble(&[0xC5, 0xFD, 0x72, 0xD1, 0x04]),
+            "vpsrld ymm0, ymm1, 0x4"
+        );
+        assert_eq!(disassemble(&[0xC5, 0xF8, 0x77]), "vzeroupper");
+        assert_eq!(disassemble(&[0xC5, 0xFC, 0x77]), "vzeroall");
+        assert_eq!(
+            disassemble(&[0xC4, 0xE3, 0xFD, 0x00, 0xC1, 0x1B]),
+            "vpermq ymm0, ymm1, 0x1b"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE3, 0x71, 0x4A, 0xC2, 0x30]),
+            "vblendvps xmm0, xmm1, xmm2, xmm3"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE2, 0x6D, 0x90, 0x04, 0x88]),
+            "vpgatherdd ymm0, dword ptr [rax+ymm1*4], ymm2"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE2, 0xE9, 0x90, 0x04, 0x88]),
+            "vpgatherdq xmm0, qword ptr [rax+xmm1*4], xmm2"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE2, 0x75, 0xB8, 0xC2]),
+            "vfmadd231ps ymm0, ymm1, ymm2"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE2, 0xF1, 0xA9, 0x00]),
+            "vfmadd213sd xmm0, xmm1, qword ptr [rax]"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE2, 0x7D, 0x13, 0xC1]),
+            "vcvtph2ps ymm0, xmm1"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE3, 0x7D, 0x1D, 0xC1, 0x04]),
+            "vcvtps2ph xmm1, ymm0, 0x4"
+        );
+        // VMOVD with VEX.L, VPERMD without it, a gather without a SIB byte, the blends on
+        // XMM0 and an unused VEX.vvvv that isn't 1111b
+        for bytes in [
+            &[0xC5, 0xFD, 0x6E, 0xC0][..],
+            &[0xC4, 0xE2, 0x71, 0x36, 0xC2],
+            &[0xC4, 0xE2, 0x6D, 0x90, 0x00],
+            &[0xC4, 0xE2, 0x71, 0x14, 0xC2],
+            &[0xC5, 0xF0, 0x28, 0xC1],
+        ] {
+            assert_eq!(decode_bytes(bytes), Err(Exception::InvalidOpcode));
+        }
+    }
+
+    #[test]
+    fn cryptographic_and_random_number_instructions() {
+        assert_eq!(
+            disassemble(&[0x66, 0x0F, 0x38, 0xDC, 0xC1]),
+            "aesenc xmm0, xmm1"
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0F, 0x3A, 0xDF, 0xCA, 0x01]),
+            "aeskeygenassist xmm1, xmm2, 0x1"
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0F, 0x3A, 0x44, 0x00, 0x11]),
+            "pclmulqdq xmm0, xmmword ptr [rax], 0x11"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE2, 0x71, 0xDC, 0xC2]),
+            "vaesenc xmm0, xmm1, xmm2"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE2, 0x79, 0xDB, 0xC1]),
+            "vaesimc xmm0, xmm1"
+        );
+        assert_eq!(
+            disassemble(&[0x0F, 0x38, 0xCB, 0xCA]),
+            "sha256rnds2 xmm1, xmm2, xmm0"
+        );
+        assert_eq!(
+            disassemble(&[0x0F, 0x3A, 0xCC, 0xC1, 0x03]),
+            "sha1rnds4 xmm0, xmm1, 0x3"
+        );
+        assert_eq!(disassemble(&[0x0F, 0xC7, 0xF0]), "rdrand eax");
+        assert_eq!(disassemble(&[0x66, 0x0F, 0xC7, 0xF0]), "rdrand ax");
+        assert_eq!(disassemble(&[0x48, 0x0F, 0xC7, 0xF9]), "rdseed rcx");
+        // RDRAND to memory, the SHA instructions with a 66 prefix and the 256-bit VAESENC
+        // and VPCLMULQDQ, which came with later extensions
+        for bytes in [
+            &[0x0F, 0xC7, 0x30][..],
+            &[0x66, 0x0F, 0x38, 0xC8, 0xC1],
+            &[0xC4, 0xE2, 0x75, 0xDC, 0xC2],
+            &[0xC4, 0xE3, 0x75, 0x44, 0xC2, 0x00],
+        ] {
+            assert_eq!(decode_bytes(bytes), Err(Exception::InvalidOpcode));
+        }
+    }
+
+    #[test]
+    fn evex_encoded_avx512() {
+        assert_eq!(
+            disassemble(&[0x62, 0xF1, 0x74, 0x48, 0x58, 0xC2]),
+            "vaddps zmm0, zmm1, zmm2"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF1, 0x74, 0xC9, 0x58, 0xC2]),
+            "vaddps zmm0{k1}{z}, zmm1, zmm2"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF1, 0x74, 0x58, 0x58, 0x00]),
+            "vaddps zmm0, zmm1, dword ptr [rax]{1to16}"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF1, 0x74, 0x78, 0x58, 0xC2]),
+            "vaddps zmm0, zmm1, zmm2, {rz-sae}"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xA1, 0x74, 0x40, 0x58, 0xC2]),
+            "vaddps zmm16, zmm17, zmm18"
+        );
+        // disp8*N: the displacement is scaled by the size of the memory operand
+        assert_eq!(
+            disassemble(&[0x62, 0xF1, 0x7C, 0x48, 0x10, 0x40, 0x01]),
+            "vmovups zmm0, zmmword ptr [rax+0x40]"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF1, 0x7D, 0x48, 0x76, 0xC9]),
+            "vpcmpeqd k1, zmm0, zmm1"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF3, 0x75, 0x48, 0x25, 0xC2, 0xCA]),
+            "vpternlogd zmm0, zmm1, zmm2, 0xca"
+        );
+        assert_eq!(disassemble(&[0xC5, 0xF8, 0x90, 0xCA]), "kmovw k1, k2");
+        assert_eq!(
+            disassemble(&[0xC5, 0xF5, 0x4B, 0xE2]),
+            "kunpckbw k4, k1, k2"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE3, 0xF9, 0x30, 0xE9, 0x04]),
+            "kshiftrw k5, k1, 0x4"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF2, 0x7E, 0x58, 0x27, 0x48, 0x01]),
+            "vptestnmd k1, zmm0, dword ptr [rax+0x4]{1to16}"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF2, 0x7D, 0x48, 0x5B, 0x48, 0x01]),
+            "vbroadcasti32x8 zmm1, ymmword ptr [rax+0x20]"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF2, 0x7E, 0x48, 0x38, 0xD2]),
+            "vpmovm2d zmm2, k2"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF2, 0x7E, 0x48, 0x32, 0xD1]),
+            "vpmovqb xmm1, zmm2"
+        );
+        // The compresses and expands scale a disp8 by the element size
+        assert_eq!(
+            disassemble(&[0x62, 0x72, 0x7D, 0x49, 0x8B, 0x48, 0x10]),
+            "vpcompressd zmmword ptr [rax+0x40]{k1}, zmm9"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xB1, 0x5D, 0x40, 0x72, 0xCD, 0x08]),
+            "vprold zmm20, zmm21, 0x8"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0x03, 0x95, 0x40, 0x43, 0xE6, 0x1B]),
+            "vshufi64x2 zmm28, zmm29, zmm30, 0x1b"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0x71, 0xFF, 0x48, 0x7A, 0xF9]),
+            "vcvtuqq2ps ymm15, zmm1"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF1, 0xEF, 0x08, 0x7B, 0xC8]),
+            "vcvtusi2sd xmm1, xmm2, rax"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF3, 0x7D, 0x18, 0x08, 0xCA, 0x11]),
+            "vrndscaleps zmm1, zmm2, 0x11, {sae}"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF3, 0xD5, 0x08, 0x55, 0xE6, 0x00]),
+            "vfixupimmsd xmm4, xmm5, xmm6, 0x0"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF3, 0x7D, 0x58, 0x66, 0x08, 0x01]),
+            "vfpclassps k1, dword ptr [rax]{1to16}, 0x1"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF2, 0x7D, 0x49, 0x90, 0x0C, 0x90]),
+            "vpgatherdd zmm1{k1}, dword ptr [rax+zmm2*4]"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF2, 0x7D, 0x42, 0x91, 0x1C, 0xE0]),
+            "vpgatherqd ymm3{k2}, dword ptr [rax+zmm20*8]"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF2, 0xFD, 0x4B, 0x92, 0x24, 0xD0]),
+            "vgatherdpd zmm4{k3}, qword ptr [rax+ymm2*8]"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF2, 0xFD, 0x4C, 0xA1, 0x74, 0xD0, 0x02]),
+            "vpscatterqq qword ptr [rax+zmm2*8+0x10]{k4}, zmm6"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xF2, 0xFD, 0x58, 0xC4, 0x38]),
+            "vpconflictq zmm7, qword ptr [rax]{1to8}"
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0x52, 0xFD, 0xCD, 0x44, 0xCA]),
+            "vplzcntq zmm9{k5}{z}, zmm10"
+        );
+        // A 1024-bit vector, zeroing a store, EVEX.b on a register form of an instruction
+        // without rounding control, and a gather under k0 and one zeroing
+        for bytes in [
+            &[0x62, 0xF1, 0x74, 0x68, 0x58, 0xC2][..],
+            &[0x62, 0xF1, 0x7C, 0xC9, 0x11, 0x00],
+            &[0x62, 0xF1, 0x75, 0x58, 0xFE, 0xC2],
+            &[0x62, 0xF2, 0x7D, 0x48, 0x90, 0x0C, 0x90],
+            &[0x62, 0xF2, 0x7D, 0xC9, 0x90, 0x0C, 0x90],
+        ] {
+            assert_eq!(decode_bytes(bytes), Err(Exception::InvalidOpcode));
+        }
+    }
+
+    #[test]
+    fn vex_encoded_bmi() {
+        assert_eq!(
+            disassemble(&[0xC4, 0xE2, 0x60, 0xF2, 0xC1]),
+            "andn eax, ebx, ecx"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE2, 0xF1, 0xF7, 0xC3]),
+            "shlx rax, rbx, rcx"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE3, 0x7B, 0xF0, 0xC1, 0x05]),
+            "rorx eax, ecx, 0x5"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0x42, 0xB3, 0xF6, 0xC2]),
+            "mulx r8, r9, r10"
+        );
+        assert_eq!(
+            disassemble(&[0xC4, 0xE2, 0x70, 0xF3, 0xC8]),
+            "blsr ecx, eax"
+        );
+        // VEX.L set, an unused VEX.vvvv that isn't 1111b, and a VEX after a legacy prefix
+        assert_eq!(
+            decode_bytes(&[0xC4, 0xE2, 0x64, 0xF2, 0xC1]),
+            Err(Exception::InvalidOpcode)
+        );
+        assert_eq!(
+            decode_bytes(&[0xC4, 0xE3, 0x73, 0xF0, 0xC1, 0x05]),
+            Err(Exception::InvalidOpcode)
+        );
+        assert_eq!(
+            decode_bytes(&[0x66, 0xC4, 0xE2, 0x60, 0xF2, 0xC1]),
+            Err(Exception::InvalidOpcode)
+        );
+    }
+
+    #[test]
+    fn x87_escapes() {
+        assert_eq!(
+            disassemble(&[0xDD, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00]),
+            "fld qword ptr [0x3000]"
+        );
+        assert_eq!(disassemble(&[0xDB, 0x2C, 0x24]), "fld tbyte ptr [rsp]");
+        assert_eq!(disassemble(&[0xDE, 0x0B]), "fimul word ptr [rbx]");
+        assert_eq!(disassemble(&[0xD8, 0xC1]), "fadd st, st(1)");
+        assert_eq!(disassemble(&[0xDC, 0xE9]), "fsub st(1), st");
+        assert_eq!(disassemble(&[0xDE, 0xF9]), "fdivp st(1), st");
+        assert_eq!(disassemble(&[0xDB, 0xF1]), "fcomi st, st(1)");
+        assert_eq!(disassemble(&[0xDA, 0xC1]), "fcmovb st, st(1)");
+        assert_eq!(disassemble(&[0xDB, 0xD9]), "fcmovnu st, st(1)");
+        assert_eq!(disassemble(&[0x41, 0xD9, 0xC9]), "fxch st(1)");
+        assert_eq!(disassemble(&[0xD9, 0xE8]), "fld1");
+        assert_eq!(disassemble(&[0xDF, 0xE0]), "fnstsw ax");
+        assert_eq!(decode_bytes(&[0xD9, 0x08]), Err(Exception::InvalidOpcode));
+        assert_eq!(decode_bytes(&[0xD9, 0xD8]), Err(Exception::InvalidOpcode));
+    }
+
+    #[test]
+    fn mmx() {
+        assert_eq!(disassemble(&[0x0F, 0xFC, 0xC1]), "paddb mm0, mm1");
+        assert_eq!(
+            disassemble(&[0x0F, 0x6F, 0x00]),
+            "movq mm0, qword ptr [rax]"
+        );
+        assert_eq!(disassemble(&[0x0F, 0x6E, 0xC8]), "movd mm1, eax");
+        assert_eq!(disassemble(&[0x48, 0x0F, 0x7E, 0xC8]), "movq rax, mm1");
+        assert_eq!(
+            disassemble(&[0x0F, 0x60, 0x0B]),
+            "punpcklbw mm1, dword ptr [rbx]"
+        );
+        assert_eq!(disassemble(&[0x0F, 0x71, 0xD2, 0x03]), "psrlw mm2, 0x3");
+        assert_eq!(disassemble(&[0x0F, 0x77]), "emms");
+        // The immediate shifts only take a register
+        assert_eq!(
+            decode_bytes(&[0x0F, 0x71, 0x10, 0x03]),
+            Err(Exception::InvalidOpcode)
+        );
+    }
+
+    #[test]
+    fn sse() {
+        assert_eq!(disassemble(&[0x0F, 0x58, 0xC1]), "addps xmm0, xmm1");
+        assert_eq!(disassemble(&[0x66, 0x0F, 0x58, 0xC1]), "addpd xmm0, xmm1");
+        assert_eq!(
+            disassemble(&[0xF3, 0x0F, 0x58, 0x00]),
+            "addss xmm0, dword ptr [rax]"
+        );
+        assert_eq!(
+            disassemble(&[0xF2, 0x44, 0x0F, 0x10, 0x08]),
+            "movsd xmm9, qword ptr [rax]"
+        );
+        assert_eq!(disassemble(&[0x66, 0x0F, 0xEF, 0xC0]), "pxor xmm0, xmm0");
+        assert_eq!(
+            disassemble(&[0x66, 0x0F, 0x6F, 0x06]),
+            "movdqa xmm0, xmmword ptr [rsi]"
+        );
+        assert_eq!(disassemble(&[0x0F, 0x12, 0xC1]), "movhlps xmm0, xmm1");
+        assert_eq!(
+            disassemble(&[0xF2, 0x48, 0x0F, 0x2C, 0xC1]),
+            "cvttsd2si rax, xmm1"
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0F, 0x73, 0xDA, 0x04]),
+            "psrldq xmm2, 0x4"
+        );
+        assert_eq!(
+            disassemble(&[0x0F, 0xC6, 0xC1, 0x1B]),
+            "shufps xmm0, xmm1, 0x1b"
+        );
+        assert_eq!(disassemble(&[0x0F, 0xAE, 0xF8]), "sfence");
+        assert_eq!(disassemble(&[0x0F, 0xAE, 0x10]), "ldmxcsr dword ptr [rax]");
+        // /4-/6 are XSAVE, XRSTOR and XSAVEOPT in memory, and the fences or nothing otherwise
+        assert_eq!(disassemble(&[0x0F, 0xAE, 0x21]), "xsave byte ptr [rcx]");
+        assert_eq!(
+            disassemble(&[0x48, 0x0F, 0xAE, 0x29]),
+            "xrstor byte ptr [rcx]"
+        );
+        assert_eq!(disassemble(&[0x0F, 0xAE, 0x31]), "xsaveopt byte ptr [rcx]");
+        assert_eq!(disassemble(&[0x0F, 0xC7, 0x21]), "xsavec byte ptr [rcx]");
+        assert_eq!(disassemble(&[0x0F, 0xAE, 0xE8]), "lfence");
+        assert_eq!(
+            decode_bytes(&[0x0F, 0xAE, 0xE0]),
+            Err(Exception::InvalidOpcode)
+        );
+        // MOVNTDQ only stores to memory
+        assert_eq!(
+            decode_bytes(&[0x66, 0x0F, 0xE7, 0xC0]),
+            Err(Exception::InvalidOpcode)
+        );
+    }
+
+    #[test]
+    fn sse3_through_sse42() {
+        assert_eq!(disassemble(&[0xF2, 0x0F, 0x7C, 0xC1]), "haddps xmm0, xmm1");
+        assert_eq!(
+            disassemble(&[0xF2, 0x0F, 0xF0, 0x06]),
+            "lddqu xmm0, xmmword ptr [rsi]"
+        );
+        assert_eq!(disassemble(&[0x0F, 0x38, 0x00, 0xC1]), "pshufb mm0, mm1");
+        assert_eq!(
+            disassemble(&[0x66, 0x0F, 0x38, 0x10, 0xCA]),
+            "pblendvb xmm1, xmm2, xmm0"
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0F, 0x38, 0x30, 0x00]),
+            "pmovzxbw xmm0, qword ptr [rax]"
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0F, 0x3A, 0x14, 0xC0, 0x05]),
+            "pextrb eax, xmm0, 0x5"
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0F, 0x3A, 0x20, 0x08, 0x01]),
+            "pinsrb xmm1, byte ptr [rax], 0x1"
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x48, 0x0F, 0x3A, 0x16, 0xCA, 0x01]),
+            "pextrq rdx, xmm1, 0x1"
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0F, 0x3A, 0x63, 0x07, 0x08]),
+            "pcmpistri xmm0, xmmword ptr [rdi], 0x8"
+        );
+        assert_eq!(
+            disassemble(&[0xF2, 0x0F, 0x38, 0xF0, 0x06]),
+            "crc32 eax, byte ptr [rsi]"
+        );
+        assert_eq!(
+            disassemble(&[0xF2, 0x48, 0x0F, 0x38, 0xF1, 0xC1]),
+            "crc32 rax, rcx"
+        );
+        // MOVBE is still there without the F2 prefix
+        assert_eq!(
+            disassemble(&[0x0F, 0x38, 0xF0, 0x06]),
+            "movbe eax, dword ptr [rsi]"
+        );
+    }
+
+    #[test]
+    fn invalid_and_overlong_encodings_fault() {
+        assert_eq!(decode_bytes(&[0x06]), Err(Exception::InvalidOpcode));
+        assert_eq!(decode_bytes(&[0x8F, 0xC8]), Err(Exception::InvalidOpcode));
+        assert_eq!(
+            decode_bytes(&[0x66; 15]),
+            Err(Exception::GeneralProtection(0))
+        );
+    }
+}
@@ -0,0 +1,1307 @@
+//! Opcode tables for the MMX, SSE, AVX and AVX-512 families, whose 0F, 0F38 and 0F3A
+//! opcodes are further selected by a mandatory prefix (none, 66, F3 or F2).
+
+use super::Mnemonic::*;
+use super::Spec::*;
+use super::{def, def_attr, op, op_attr, Attr, Def, Entry, OpcodeMap, Spec};
+
+static GROUP12_MMX: [Option<Def>; 8] = [
+    None,
+    None,
+    def(Psrlw, &[Nq, Ib]),
+    None,
+    def(Psraw, &[Nq, Ib]),
+    None,
+    def(Psllw, &[Nq, Ib]),
+    None,
+];
+
+static GROUP13_MMX: [Option<Def>; 8] = [
+    None,
+    None,
+    def(Psrld, &[Nq, Ib]),
+    None,
+    def(Psrad, &[Nq, Ib]),
+    None,
+    def(Pslld, &[Nq, Ib]),
+    None,
+];
+
+static GROUP14_MMX: [Option<Def>; 8] = [
+    None,
+    None,
+    def(Psrlq, &[Nq, Ib]),
+    None,
+    None,
+    None,
+    def(Psllq, &[Nq, Ib]),
+    None,
+];
+
+static GROUP12_SSE: [Option<Def>; 8] = [
+    None,
+    None,
+    def(Psrlw, &[Ux, Ib]),
+    None,
+    def(Psraw, &[Ux, Ib]),
+    None,
+    def(Psllw, &[Ux, Ib]),
+    None,
+];
+
+static GROUP13_SSE: [Option<Def>; 8] = [
+    None,
+    None,
+    def(Psrld, &[Ux, Ib]),
+    None,
+    def(Psrad, &[Ux, Ib]),
+    None,
+    def(Pslld, &[Ux, Ib]),
+    None,
+];
+
+static GROUP14_SSE: [Option<Def>; 8] = [
+    None,
+    None,
+    def(Psrlq, &[Ux, Ib]),
+    def(Psrldq, &[Ux, Ib]),
+    None,
+    None,
+    def(Psllq, &[Ux, Ib]),
+    def(Pslldq, &[Ux, Ib]),
+];
+
+// The AVX shifts by an immediate write the register VEX.vvvv names
+static GROUP12_VEX: [Option<Def>; 8] = [
+    None,
+    None,
+    def(Psrlw, &[Hx, Ux, Ib]),
+    None,
+    def(Psraw, &[Hx, Ux, Ib]),
+    None,
+    def(Psllw, &[Hx, Ux, Ib]),
+    None,
+];
+
+static GROUP13_VEX: [Option<Def>; 8] = [
+    None,
+    None,
+    def(Psrld, &[Hx, Ux, Ib]),
+    None,
+    def(Psrad, &[Hx, Ux, Ib]),
+    None,
+    def(Pslld, &[Hx, Ux, Ib]),
+    None,
+];
+
+static GROUP14_VEX: [Option<Def>; 8] = [
+    None,
+    None,
+    def(Psrlq, &[Hx, Ux, Ib]),
+    def(Psrldq, &[Hx, Ux, Ib]),
+    None,
+    None,
+    def(Psllq, &[Hx, Ux, Ib]),
+    def(Pslldq, &[Hx, Ux, Ib]),
+];
+
+// The AVX-512 shifts by an immediate can shift a memory operand, and EVEX.W selects between
+// the doubleword and quadword forms
+static GROUP12_EVEX: [Option<Def>; 8] = [
+    None,
+    None,
+    def(Psrlw, &[Hx, Wx, Ib]),
+    None,
+    def(Psraw, &[Hx, Wx, Ib]),
+    None,
+    def(Psllw, &[Hx, Wx, Ib]),
+    None,
+];
+
+static GROUP13_EVEX: [Option<Def>; 8] = [
+    def_attr(Vprord, &[Hx, Wx, Ib], Attr::BCST),
+    def_attr(Vprold, &[Hx, Wx, Ib], Attr::BCST),
+    def_attr(Psrld, &[Hx, Wx, Ib], Attr::BCST),
+    None,
+    def_attr(Psrad, &[Hx, Wx, Ib], Attr::BCST),
+    None,
+    def_attr(Pslld, &[Hx, Wx, Ib], Attr::BCST),
+    None,
+];
+
+static GROUP13_EVEX_W1: [Option<Def>; 8] = [
+    def_attr(Vprorq, &[Hx, Wx, Ib], Attr::BCST),
+    def_attr(Vprolq, &[Hx, Wx, Ib], Attr::BCST),
+    None,
+    None,
+    def_attr(Vpsraq, &[Hx, Wx, Ib], Attr::BCST),
+    None,
+    None,
+    None,
+];
+
+static GROUP14_EVEX: [Option<Def>; 8] = [
+    None,
+    None,
+    None,
+    def(Psrldq, &[Hx, Wx, Ib]),
+    None,
+    None,
+    None,
+    def(Pslldq, &[Hx, Wx, Ib]),
+];
+
+static GROUP14_EVEX_W1: [Option<Def>; 8] = [
+    None,
+    None,
+    def_attr(Psrlq, &[Hx, Wx, Ib], Attr::BCST),
+    def(Psrldq, &[Hx, Wx, Ib]),
+    None,
+    None,
+    def_attr(Psllq, &[Hx, Wx, Ib], Attr::BCST),
+    def(Pslldq, &[Hx, Wx, Ib]),
+];
+
+/// Looks up a two-byte (0F) SIMD opcode by its mandatory prefix: 0 for none, then 66, F3
+/// and F2.
+pub(super) fn map_0f(opcode: u8, mandatory: u8) -> Entry {
+    match (opcode, mandatory) {
+        (0x10, 0) => op(Movups, &[Vx, Wx]),
+        (0x10, 1) => op(Movupd, &[Vx, Wx]),
+        (0x10, 2) => op(Movss, &[Vd, Wd]),
+        (0x10, 3) => op(Movsd, &[Vq, Wq]),
+        (0x11, 0) => op(Movups, &[Wx, Vx]),
+        (0x11, 1) => op(Movupd, &[Wx, Vx]),
+        (0x11, 2) => op(Movss, &[Wd, Vd]),
+        (0x11, 3) => op(Movsd, &[Wq, Vq]),
+        // MOVHLPS with a register operand
+        (0x12, 0) => op(Movlps, &[Vq, Wq]),
+        (0x12, 1) => op(Movlpd, &[Vq, Mq]),
+        (0x12, 2) => op(Movsldup, &[Vx, Wx]),
+        (0x12, 3) => op(Movddup, &[Vx, Wq]),
+        (0x13, 0) => op(Movlps, &[Mq, Vq]),
+        (0x13, 1) => op(Movlpd, &[Mq, Vq]),
+        (0x14, 0) => op(Unpcklps, &[Vx, Wx]),
+        (0x14, 1) => op(Unpcklpd, &[Vx, Wx]),
+        (0x15, 0) => op(Unpckhps, &[Vx, Wx]),
+        (0x15, 1) => op(Unpckhpd, &[Vx, Wx]),
+        // MOVLHPS with a register operand
+        (0x16, 0) => op(Movhps, &[Vq, Wq]),
+        (0x16, 1) => op(Movhpd, &[Vq, Mq]),
+        (0x16, 2) => op(Movshdup, &[Vx, Wx]),
+        (0x17, 0) => op(Movhps, &[Mq, Vq]),
+        (0x17, 1) => op(Movhpd, &[Mq, Vq]),
+        (0x28, 0) => op(Movaps, &[Vx, Wx]),
+        (0x28, 1) => op(Movapd, &[Vx, Wx]),
+        (0x29, 0) => op(Movaps, &[Wx, Vx]),
+        (0x29, 1) => op(Movapd, &[Wx, Vx]),
+        (0x2A, 0) => op(Cvtpi2ps, &[Vq, Qq]),
+        (0x2A, 1) => op(Cvtpi2pd, &[Vx, Qq]),
+        (0x2A, 2) => op(Cvtsi2ss, &[Vd, Ey]),
+        (0x2A, 3) => op(Cvtsi2sd, &[Vq, Ey]),
+        (0x2B, 0) => op(Movntps, &[Mdq, Vx]),
+        (0x2B, 1) => op(Movntpd, &[Mdq, Vx]),
+        (0x2C, 0) => op(Cvttps2pi, &[Pq, Wq]),
+        (0x2C, 1) => op(Cvttpd2pi, &[Pq, Wx]),
+        (0x2C, 2) => op(Cvttss2si, &[Gy, Wd]),
+        (0x2C, 3) => op(Cvttsd2si, &[Gy, Wq]),
+        (0x2D, 0) => op(Cvtps2pi, &[Pq, Wq]),
+        (0x2D, 1) => op(Cvtpd2pi, &[Pq, Wx]),
+        (0x2D, 2) => op(Cvtss2si, &[Gy, Wd]),
+        (0x2D, 3) => op(Cvtsd2si, &[Gy, Wq]),
+        (0x2E, 0) => op(Ucomiss, &[Vd, Wd]),
+        (0x2E, 1) => op(Ucomisd, &[Vq, Wq]),
+        (0x2F, 0) => op(Comiss, &[Vd, Wd]),
+        (0x2F, 1) => op(Comisd, &[Vq, Wq]),
+        (0x50, 0) => op(Movmskps, &[Gy, Ux]),
+        (0x50, 1) => op(Movmskpd, &[Gy, Ux]),
+        (0x51, 0) => op(Sqrtps, &[Vx, Wx]),
+        (0x51, 1) => op(Sqrtpd, &[Vx, Wx]),
+        (0x51, 2) => op(Sqrtss, &[Vd, Wd]),
+        (0x51, 3) => op(Sqrtsd, &[Vq, Wq]),
+        (0x52, 0) => op(Rsqrtps, &[Vx, Wx]),
+        (0x52, 2) => op(Rsqrtss, &[Vd, Wd]),
+        (0x53, 0) => op(Rcpps, &[Vx, Wx]),
+        (0x53, 2) => op(Rcpss, &[Vd, Wd]),
+        (0x54, 0) => op(Andps, &[Vx, Wx]),
+        (0x54, 1) => op(Andpd, &[Vx, Wx]),
+        (0x55, 0) => op(Andnps, &[Vx, Wx]),
+        (0x55, 1) => op(Andnpd, &[Vx, Wx]),
+        (0x56, 0) => op(Orps, &[Vx, Wx]),
+        (0x56, 1) => op(Orpd, &[Vx, Wx]),
+        (0x57, 0) => op(Xorps, &[Vx, Wx]),
+        (0x57, 1) => op(Xorpd, &[Vx, Wx]),
+        (0x58, _) => arithmetic(mandatory, [Addps, Addpd, Addss, Addsd]),
+        (0x59, _) => arithmetic(mandatory, [Mulps, Mulpd, Mulss, Mulsd]),
+        (0x5A, 0) => op(Cvtps2pd, &[Vx, Wq]),
+        (0x5A, 1) => op(Cvtpd2ps, &[Vx, Wx]),
+        (0x5A, 2) => op(Cvtss2sd, &[Vq, Wd]),
+        (0x5A, 3) => op(Cvtsd2ss, &[Vd, Wq]),
+        (0x5B, 0) => op(Cvtdq2ps, &[Vx, Wx]),
+        (0x5B, 1) => op(Cvtps2dq, &[Vx, Wx]),
+        (0x5B, 2) => op(Cvttps2dq, &[Vx, Wx]),
+        (0x5C, _) => arithmetic(mandatory, [Subps, Subpd, Subss, Subsd]),
+        (0x5D, _) => arithmetic(mandatory, [Minps, Minpd, Minss, Minsd]),
+        (0x5E, _) => arithmetic(mandatory, [Divps, Divpd, Divss, Divsd]),
+        (0x5F, _) => arithmetic(mandatory, [Maxps, Maxpd, Maxss, Maxsd]),
+        (0x60, 0) => op(Punpcklbw, &[Pq, Qd]),
+        (0x61, 0) => op(Punpcklwd, &[Pq, Qd]),
+        (0x62, 0) => op(Punpckldq, &[Pq, Qd]),
+        (0x63, 0) => op(Packsswb, &[Pq, Qq]),
+        (0x64, 0) => op(Pcmpgtb, &[Pq, Qq]),
+        (0x65, 0) => op(Pcmpgtw, &[Pq, Qq]),
+        (0x66, 0) => op(Pcmpgtd, &[Pq, Qq]),
+        (0x67, 0) => op(Packuswb, &[Pq, Qq]),
+        (0x68, 0) => op(Punpckhbw, &[Pq, Qq]),
+        (0x69, 0) => op(Punpckhwd, &[Pq, Qq]),
+        (0x6A, 0) => op(Punpckhdq, &[Pq, Qq]),
+        (0x6B, 0) => op(Packssdw, &[Pq, Qq]),
+        (0x6C, 1) => op(Punpcklqdq, &[Vx, Wx]),
+        (0x6D, 1) => op(Punpckhqdq, &[Vx, Wx]),
+        // MOVQ with REX.W
+        (0x6E, 0) => op(Movd, &[Pq, Ey]),
+        (0x6E, 1) => op(Movd, &[Vx, Ey]),
+        (0x6F, 0) => op(Movq, &[Pq, Qq]),
+        (0x6F, 1) => op(Movdqa, &[Vx, Wx]),
+        (0x6F, 2) => op(Movdqu, &[Vx, Wx]),
+        (0x70, 0) => op(Pshufw, &[Pq, Qq, Ib]),
+        (0x70, 1) => op(Pshufd, &[Vx, Wx, Ib]),
+        (0x70, 2) => op(Pshufhw, &[Vx, Wx, Ib]),
+        (0x70, 3) => op(Pshuflw, &[Vx, Wx, Ib]),
+        (0x71, 0) => Entry::Group(&GROUP12_MMX),
+        (0x72, 0) => Entry::Group(&GROUP13_MMX),
+        (0x73, 0) => Entry::Group(&GROUP14_MMX),
+        (0x71, 1) => Entry::Group(&GROUP12_SSE),
+        (0x72, 1) => Entry::Group(&GROUP13_SSE),
+        (0x73, 1) => Entry::Group(&GROUP14_SSE),
+        (0x74, 0) => op(Pcmpeqb, &[Pq, Qq]),
+        (0x75, 0) => op(Pcmpeqw, &[Pq, Qq]),
+        (0x76, 0) => op(Pcmpeqd, &[Pq, Qq]),
+        (0x77, 0) => op(Emms, &[]),
+        (0x7C, 1) => op(Haddpd, &[Vx, Wx]),
+        (0x7C, 3) => op(Haddps, &[Vx, Wx]),
+        (0x7D, 1) => op(Hsubpd, &[Vx, Wx]),
+        (0x7D, 3) => op(Hsubps, &[Vx, Wx]),
+        (0x7E, 0) => op(Movd, &[Ey, Pq]),
+        (0x7E, 1) => op(Movd, &[Ey, Vx]),
+        (0x7E, 2) => op(Movq, &[Vx, Wq]),
+        (0x7F, 0) => op(Movq, &[Qq, Pq]),
+        (0x7F, 1) => op(Movdqa, &[Wx, Vx]),
+        (0x7F, 2) => op(Movdqu, &[Wx, Vx]),
+        (0xC2, _) => match [Cmpps, Cmppd, Cmpss, Cmpsd][mandatory as usize] {
+            Cmpss => op(Cmpss, &[Vd, Wd, Ib]),
+            Cmpsd => op(Cmpsd, &[Vq, Wq, Ib]),
+            mnemonic => op(mnemonic, &[Vx, Wx, Ib]),
+        },
+        (0xC4, 0) => op(Pinsrw, &[Pq, RdMw, Ib]),
+        (0xC4, 1) => op(Pinsrw, &[Vx, RdMw, Ib]),
+        (0xC5, 0) => op(Pextrw, &[Gy, Nq, Ib]),
+        (0xC5, 1) => op(Pextrw, &[Gy, Ux, Ib]),
+        (0xC6, 0) => op(Shufps, &[Vx, Wx, Ib]),
+        (0xC6, 1) => op(Shufpd, &[Vx, Wx, Ib]),
+        (0xD0, 1) => op(Addsubpd, &[Vx, Wx]),
+        (0xD0, 3) => op(Addsubps, &[Vx, Wx]),
+        (0xD1, 0) => op(Psrlw, &[Pq, Qq]),
+        (0xD2, 0) => op(Psrld, &[Pq, Qq]),
+        (0xD3, 0) => op(Psrlq, &[Pq, Qq]),
+        (0xD4, 0) => op(Paddq, &[Pq, Qq]),
+        (0xD5, 0) => op(Pmullw, &[Pq, Qq]),
+        (0xD6, 1) => op(Movq, &[Wq, Vq]),
+        (0xD6, 2) => op(Movq2dq, &[Vx, Nq]),
+        (0xD6, 3) => op(Movdq2q, &[Pq, Ux]),
+        (0xD7, 0) => op(Pmovmskb, &[Gy, Nq]),
+        (0xD7, 1) => op(Pmovmskb, &[Gy, Ux]),
+        (0xD8, 0) => op(Psubusb, &[Pq, Qq]),
+        (0xD9, 0) => op(Psubusw, &[Pq, Qq]),
+        (0xDA, 0) => op(Pminub, &[Pq, Qq]),
+        (0xDB, 0) => op(Pand, &[Pq, Qq]),
+        (0xDC, 0) => op(Paddusb, &[Pq, Qq]),
+        (0xDD, 0) => op(Paddusw, &[Pq, Qq]),
+        (0xDE, 0) => op(Pmaxub, &[Pq, Qq]),
+        (0xDF, 0) => op(Pandn, &[Pq, Qq]),
+        (0xE0, 0) => op(Pavgb, &[Pq, Qq]),
+        (0xE1, 0) => op(Psraw, &[Pq, Qq]),
+        (0xE2, 0) => op(Psrad, &[Pq, Qq]),
+        (0xE3, 0) => op(Pavgw, &[Pq, Qq]),
+        (0xE4, 0) => op(Pmulhuw, &[Pq, Qq]),
+        (0xE5, 0) => op(Pmulhw, &[Pq, Qq]),
+        (0xE6, 1) => op(Cvttpd2dq, &[Vx, Wx]),
+        (0xE6, 2) => op(Cvtdq2pd, &[Vx, Wq]),
+        (0xE6, 3) => op(Cvtpd2dq, &[Vx, Wx]),
+        (0xE7, 0) => op(Movntq, &[Mq, Pq]),
+        (0xE7, 1) => op(Movntdq, &[Mdq, Vx]),
+        (0xE8, 0) => op(Psubsb, &[Pq, Qq]),
+        (0xE9, 0) => op(Psubsw, &[Pq, Qq]),
+        (0xEA, 0) => op(Pminsw, &[Pq, Qq]),
+        (0xEB, 0) => op(Por, &[Pq, Qq]),
+        (0xEC, 0) => op(Paddsb, &[Pq, Qq]),
+        (0xED, 0) => op(Paddsw, &[Pq, Qq]),
+        (0xEE, 0) => op(Pmaxsw, &[Pq, Qq]),
+        (0xEF, 0) => op(Pxor, &[Pq, Qq]),
+        (0xF0, 3) => op(Lddqu, &[Vx, Mdq]),
+        (0xF1, 0) => op(Psllw, &[Pq, Qq]),
+        (0xF2, 0) => op(Pslld, &[Pq, Qq]),
+        (0xF3, 0) => op(Psllq, &[Pq, Qq]),
+        (0xF4, 0) => op(Pmuludq, &[Pq, Qq]),
+        (0xF5, 0) => op(Pmaddwd, &[Pq, Qq]),
+        (0xF6, 0) => op(Psadbw, &[Pq, Qq]),
+        (0xF7, 0) => op(Maskmovq, &[Pq, Nq]),
+        (0xF7, 1) => op(Maskmovdqu, &[Vx, Ux]),
+        (0xF8, 0) => op(Psubb, &[Pq, Qq]),
+        (0xF9, 0) => op(Psubw, &[Pq, Qq]),
+        (0xFA, 0) => op(Psubd, &[Pq, Qq]),
+        (0xFB, 0) => op(Psubq, &[Pq, Qq]),
+        (0xFC, 0) => op(Paddb, &[Pq, Qq]),
+        (0xFD, 0) => op(Paddw, &[Pq, Qq]),
+        (0xFE, 0) => op(Paddd, &[Pq, Qq]),
+        // The other packed integer instructions take XMM operands with a 66 prefix
+        (_, 1) => widen(map_0f(opcode, 0)),
+        _ => Entry::Invalid,
+    }
+}
+
+/// Looks up a three-byte (0F38) SIMD opcode by its mandatory prefix.
+pub(super) fn map_0f38(opcode: u8, mandatory: u8) -> Entry {
+    match (opcode, mandatory) {
+        (0x00, 0) => op(Pshufb, &[Pq, Qq]),
+        (0x01, 0) => op(Phaddw, &[Pq, Qq]),
+        (0x02, 0) => op(Phaddd, &[Pq, Qq]),
+        (0x03, 0) => op(Phaddsw, &[Pq, Qq]),
+        (0x04, 0) => op(Pmaddubsw, &[Pq, Qq]),
+        (0x05, 0) => op(Phsubw, &[Pq, Qq]),
+        (0x06, 0) => op(Phsubd, &[Pq, Qq]),
+        (0x07, 0) => op(Phsubsw, &[Pq, Qq]),
+        (0x08, 0) => op(Psignb, &[Pq, Qq]),
+        (0x09, 0) => op(Psignw, &[Pq, Qq]),
+        (0x0A, 0) => op(Psignd, &[Pq, Qq]),
+        (0x0B, 0) => op(Pmulhrsw, &[Pq, Qq]),
+        (0x1C, 0) => op(Pabsb, &[Pq, Qq]),
+        (0x1D, 0) => op(Pabsw, &[Pq, Qq]),
+        (0x1E, 0) => op(Pabsd, &[Pq, Qq]),
+        (0x00..=0x0B | 0x1C..=0x1E, 1) => widen(map_0f38(opcode, 0)),
+        (0x10, 1) => op(Pblendvb, &[Vx, Wx, Xmm0]),
+        (0x14, 1) => op(Blendvps, &[Vx, Wx, Xmm0]),
+        (0x15, 1) => op(Blendvpd, &[Vx, Wx, Xmm0]),
+        (0x17, 1) => op(Ptest, &[Vx, Wx]),
+        (0x20, 1) => op(Pmovsxbw, &[Vx, Wq]),
+        (0x21, 1) => op(Pmovsxbd, &[Vx, Wd]),
+        (0x22, 1) => op(Pmovsxbq, &[Vx, Ww]),
+        (0x23, 1) => op(Pmovsxwd, &[Vx, Wq]),
+        (0x24, 1) => op(Pmovsxwq, &[Vx, Wd]),
+        (0x25, 1) => op(Pmovsxdq, &[Vx, Wq]),
+        (0x28, 1) => op(Pmuldq, &[Vx, Wx]),
+        (0x29, 1) => op(Pcmpeqq, &[Vx, Wx]),
+        (0x2A, 1) => op(Movntdqa, &[Vx, Mdq]),
+        (0x2B, 1) => op(Packusdw, &[Vx, Wx]),
+        (0x30, 1) => op(Pmovzxbw, &[Vx, Wq]),
+        (0x31, 1) => op(Pmovzxbd, &[Vx, Wd]),
+        (0x32, 1) => op(Pmovzxbq, &[Vx, Ww]),
+        (0x33, 1) => op(Pmovzxwd, &[Vx, Wq]),
+        (0x34, 1) => op(Pmovzxwq, &[Vx, Wd]),
+        (0x35, 1) => op(Pmovzxdq, &[Vx, Wq]),
+        (0x37, 1) => op(Pcmpgtq, &[Vx, Wx]),
+        (0x38, 1) => op(Pminsb, &[Vx, Wx]),
+        (0x39, 1) => op(Pminsd, &[Vx, Wx]),
+        (0x3A, 1) => op(Pminuw, &[Vx, Wx]),
+        (0x3B, 1) => op(Pminud, &[Vx, Wx]),
+        (0x3C, 1) => op(Pmaxsb, &[Vx, Wx]),
+        (0x3D, 1) => op(Pmaxsd, &[Vx, Wx]),
+        (0x3E, 1) => op(Pmaxuw, &[Vx, Wx]),
+        (0x3F, 1) => op(Pmaxud, &[Vx, Wx]),
+        (0x40, 1) => op(Pmulld, &[Vx, Wx]),
+        (0x41, 1) => op(Phminposuw, &[Vx, Wx]),
+        (0xC8, 0) => op(Sha1nexte, &[Vx, Wx]),
+        (0xC9, 0) => op(Sha1msg1, &[Vx, Wx]),
+        (0xCA, 0) => op(Sha1msg2, &[Vx, Wx]),
+        (0xCB, 0) => op(Sha256rnds2, &[Vx, Wx, Xmm0]),
+        (0xCC, 0) => op(Sha256msg1, &[Vx, Wx]),
+        (0xCD, 0) => op(Sha256msg2, &[Vx, Wx]),
+        (0xDB, 1) => op(Aesimc, &[Vx, Wx]),
+        (0xDC, 1) => op(Aesenc, &[Vx, Wx]),
+        (0xDD, 1) => op(Aesenclast, &[Vx, Wx]),
+        (0xDE, 1) => op(Aesdec, &[Vx, Wx]),
+        (0xDF, 1) => op(Aesdeclast, &[Vx, Wx]),
+        _ => Entry::Invalid,
+    }
+}
+
+/// Looks up a three-byte (0F3A) SIMD opcode by its mandatory prefix. All of them take an
+/// immediate.
+pub(super) fn map_0f3a(opcode: u8, mandatory: u8) -> Entry {
+    match (opcode, mandatory) {
+        (0x0F, 0) => op(Palignr, &[Pq, Qq, Ib]),
+        (0x0F, 1) => op(Palignr, &[Vx, Wx, Ib]),
+        (0x08, 1) => op(Roundps, &[Vx, Wx, Ib]),
+        (0x09, 1) => op(Roundpd, &[Vx, Wx, Ib]),
+        (0x0A, 1) => op(Roundss, &[Vd, Wd, Ib]),
+        (0x0B, 1) => op(Roundsd, &[Vq, Wq, Ib]),
+        (0x0C, 1) => op(Blendps, &[Vx, Wx, Ib]),
+        (0x0D, 1) => op(Blendpd, &[Vx, Wx, Ib]),
+        (0x0E, 1) => op(Pblendw, &[Vx, Wx, Ib]),
+        (0x14, 1) => op(Pextrb, &[RdMb, Vx, Ib]),
+        (0x15, 1) => op(Pextrw, &[RdMw, Vx, Ib]),
+        // PEXTRQ and PINSRQ with REX.W
+        (0x16, 1) => op(Pextrd, &[Ey, Vx, Ib]),
+        (0x17, 1) => op(Extractps, &[Ed, Vx, Ib]),
+        (0x20, 1) => op(Pinsrb, &[Vx, RdMb, Ib]),
+        (0x21, 1) => op(Insertps, &[Vx, UxMd, Ib]),
+        (0x22, 1) => op(Pinsrd, &[Vx, Ey, Ib]),
+        (0x40, 1) => op(Dpps, &[Vx, Wx, Ib]),
+        (0x41, 1) => op(Dppd, &[Vx, Wx, Ib]),
+        (0x42, 1) => op(Mpsadbw, &[Vx, Wx, Ib]),
+        (0x44, 1) => op(Pclmulqdq, &[Vx, Wx, Ib]),
+        (0x60, 1) => op(Pcmpestrm, &[Vx, Wx, Ib]),
+        (0x61, 1) => op(Pcmpestri, &[Vx, Wx, Ib]),
+        (0x62, 1) => op(Pcmpistrm, &[Vx, Wx, Ib]),
+        (0x63, 1) => op(Pcmpistri, &[Vx, Wx, Ib]),
+        (0xCC, 0) => op(Sha1rnds4, &[Vx, Wx, Ib]),
+        (0xDF, 1) => op(Aeskeygenassist, &[Vx, Wx, Ib]),
+        _ => Entry::Invalid,
+    }
+}
+
+/// The XMM form of an instruction on MMX registers, as a 66 prefix selects.
+fn widen(entry: Entry) -> Entry {
+    match entry {
+        Entry::Op((mnemonic, [Pq, Qq] | [Pq, Qd], attr)) => Entry::Op((mnemonic, &[Vx, Wx], attr)),
+        _ => Entry::Invalid,
+    }
+}
+
+/// The packed single, packed double, scalar single and scalar double forms of an arithmetic
+/// opcode, by mandatory prefix.
+fn arithmetic(mandatory: u8, [ps, pd, ss, sd]: [super::Mnemonic; 4]) -> Entry {
+    match mandatory {
+        0 => op(ps, &[Vx, Wx]),
+        1 => op(pd, &[Vx, Wx]),
+        2 => op(ss, &[Vd, Wd]),
+        _ => op(sd, &[Vq, Wq]),
+    }
+}
+
+/// Looks up a VEX-encoded SIMD opcode by its map and VEX.pp: the AVX forms of the SSE
+/// instructions, and the instructions AVX and AVX2 added. `w` is VEX.W.
+pub(super) fn vex(map: OpcodeMap, opcode: u8, mandatory: u8, w: bool) -> Entry {
+    match map {
+        OpcodeMap::Map0F => vex_0f(opcode, mandatory, w),
+        OpcodeMap::Map0F38 if mandatory == 1 => vex_0f38(opcode, w),
+        OpcodeMap::Map0F3A if mandatory == 1 => vex_0f3a(opcode, w),
+        _ => Entry::Invalid,
+    }
+}
+
+fn vex_0f(opcode: u8, mandatory: u8, w: bool) -> Entry {
+    match (opcode, mandatory) {
+        // MOVHLPS with a register operand
+        (0x12, 0) => op_attr(Movlps, &[Vq, Hx, Wq], Attr::L0),
+        (0x12, 1) => op_attr(Movlpd, &[Vq, Hx, Mq], Attr::L0),
+        // MOVLHPS with a register operand
+        (0x16, 0) => op_attr(Movhps, &[Vx, Hx, Wq], Attr::L0),
+        (0x16, 1) => op_attr(Movhpd, &[Vx, Hx, Mq], Attr::L0),
+        (0x2A, 2) => op_attr(Cvtsi2ss, &[Vd, Hx, Ey], Attr::LIG),
+        (0x2A, 3) => op_attr(Cvtsi2sd, &[Vq, Hx, Ey], Attr::LIG),
+        (0x2B, 0) => op(Movntps, &[Mx, Vx]),
+        (0x2B, 1) => op(Movntpd, &[Mx, Vx]),
+        // The AVX-512 opmask instructions
+        (0x41, 0 | 1) => opmask(mandatory, w, [Kandb, Kandw, Kandd, Kandq], &[Kg, Kh, Kr]),
+        (0x42, 0 | 1) => opmask(
+            mandatory,
+            w,
+            [Kandnb, Kandnw, Kandnd, Kandnq],
+            &[Kg, Kh, Kr],
+        ),
+        (0x44, 0 | 1) => opmask(mandatory, w, [Knotb, Knotw, Knotd, Knotq], &[Kg, Kr]),
+        (0x45, 0 | 1) => opmask(mandatory, w, [Korb, Korw, Kord, Korq], &[Kg, Kh, Kr]),
+        (0x46, 0 | 1) => opmask(
+            mandatory,
+            w,
+            [Kxnorb, Kxnorw, Kxnord, Kxnorq],
+            &[Kg, Kh, Kr],
+        ),
+        (0x47, 0 | 1) => opmask(mandatory, w, [Kxorb, Kxorw, Kxord, Kxorq], &[Kg, Kh, Kr]),
+        (0x4A, 0 | 1) => opmask(mandatory, w, [Kaddb, Kaddw, Kaddd, Kaddq], &[Kg, Kh, Kr]),
+        // KUNPCK only comes in the widths it doubles
+        (0x4B, 0 | 1) => {
+            let mnemonic = match (mandatory, w) {
+                (1, false) => Kunpckbw,
+                (0, false) => Kunpckwd,
+                (0, true) => Kunpckdq,
+                _ => return Entry::Invalid,
+            };
+            op_attr(mnemonic, &[Kg, Kh, Kr], Attr::L1)
+        }
+        (0x90, 0 | 1) => opmask(mandatory, w, [Kmovb, Kmovw, Kmovd, Kmovq], &[Kg, Ke]),
+        // The register form is invalid
+        (0x91, 0 | 1) => opmask(mandatory, w, [Kmovb, Kmovw, Kmovd, Kmovq], &[Ke, Kg]),
+        // Moves to and from general-purpose registers select the width by the prefix alone,
+        // then F2 and VEX.W for doublewords and quadwords
+        (0x92 | 0x93, _) => {
+            let mnemonic = match (mandatory, w) {
+                (0, false) => Kmovw,
+                (1, false) => Kmovb,
+                (3, false) => Kmovd,
+                (3, true) => Kmovq,
+                _ => return Entry::Invalid,
+            };
+            let specs: &'static [Spec] = if opcode == 0x92 { &[Kg, Ry] } else { &[Gy, Kr] };
+            op_attr(mnemonic, specs, Attr::L0)
+        }
+        (0x98, 0 | 1) => opmask(
+            mandatory,
+            w,
+            [Kortestb, Kortestw, Kortestd, Kortestq],
+            &[Kg, Kr],
+        ),
+        (0x99, 0 | 1) => opmask(mandatory, w, [Ktestb, Ktestw, Ktestd, Ktestq], &[Kg, Kr]),
+        (0x5A, 0) => op(Cvtps2pd, &[Vx, Wh]),
+        (0x5A, 1) => op(Cvtpd2ps, &[Vdq, Wx]),
+        (0x71, 1) => Entry::Group(&GROUP12_VEX),
+        (0x72, 1) => Entry::Group(&GROUP13_VEX),
+        (0x73, 1) => Entry::Group(&GROUP14_VEX),
+        // VZEROALL with VEX.L
+        (0x77, 0) => op(Vzeroupper, &[]),
+        (0xC4, 1) => op_attr(Pinsrw, &[Vx, Hx, RdMw, Ib], Attr::L0),
+        (0xD1..=0xD3 | 0xE1 | 0xE2 | 0xF1..=0xF3, 1) => match map_0f(opcode, 1) {
+            // The count is always the low quadword of an XMM register or 128-bit operand
+            Entry::Op((mnemonic, ..)) => op(mnemonic, &[Vx, Hx, Wdq]),
+            entry => entry,
+        },
+        (0xE6, 1) => op(Cvttpd2dq, &[Vdq, Wx]),
+        (0xE6, 2) => op(Cvtdq2pd, &[Vx, Wh]),
+        (0xE6, 3) => op(Cvtpd2dq, &[Vdq, Wx]),
+        (0xE7, 1) => op(Movntdq, &[Mx, Vx]),
+        (0xF0, 3) => op(Lddqu, &[Vx, Mx]),
+        _ => vex_form(map_0f(opcode, mandatory)),
+    }
+}
+
+fn vex_0f38(opcode: u8, w: bool) -> Entry {
+    match opcode {
+        0x0C => op(Vpermilps, &[Vx, Hx, Wx]),
+        0x0D => op(Vpermilpd, &[Vx, Hx, Wx]),
+        0x0E => op(Vtestps, &[Vx, Wx]),
+        0x0F => op(Vtestpd, &[Vx, Wx]),
+        0x13 if !w => op(Vcvtph2ps, &[Vx, Wh]),
+        0x16 => op_attr(Vpermps, &[Vx, Hx, Wx], Attr::L1),
+        0x18 => op(Vbroadcastss, &[Vx, Wd]),
+        0x19 => op_attr(Vbroadcastsd, &[Vx, Wq], Attr::L1),
+        0x1A => op_attr(Vbroadcastf128, &[Vx, Mdq], Attr::L1),
+        0x20 => op(Pmovsxbw, &[Vx, Wh]),
+        0x21 => op(Pmovsxbd, &[Vx, Wf]),
+        0x22 => op(Pmovsxbq, &[Vx, We]),
+        0x23 => op(Pmovsxwd, &[Vx, Wh]),
+        0x24 => op(Pmovsxwq, &[Vx, Wf]),
+        0x25 => op(Pmovsxdq, &[Vx, Wh]),
+        0x2A => op(Movntdqa, &[Vx, Mx]),
+        0x2C => op(Vmaskmovps, &[Vx, Hx, Mx]),
+        0x2D => op(Vmaskmovpd, &[Vx, Hx, Mx]),
+        0x2E => op(Vmaskmovps, &[Mx, Hx, Vx]),
+        0x2F => op(Vmaskmovpd, &[Mx, Hx, Vx]),
+        0x30 => op(Pmovzxbw, &[Vx, Wh]),
+        0x31 => op(Pmovzxbd, &[Vx, Wf]),
+        0x32 => op(Pmovzxbq, &[Vx, We]),
+        0x33 => op(Pmovzxwd, &[Vx, Wh]),
+        0x34 => op(Pmovzxwq, &[Vx, Wf]),
+        0x35 => op(Pmovzxdq, &[Vx, Wh]),
+        0x36 => op_attr(Vpermd, &[Vx, Hx, Wx], Attr::L1),
+        0x45 => op(if w { Vpsrlvq } else { Vpsrlvd }, &[Vx, Hx, Wx]),
+        0x46 if !w => op(Vpsravd, &[Vx, Hx, Wx]),
+        0x47 => op(if w { Vpsllvq } else { Vpsllvd }, &[Vx, Hx, Wx]),
+        0x58 => op(Vpbroadcastd, &[Vx, Wd]),
+        0x59 => op(Vpbroadcastq, &[Vx, Wq]),
+        0x5A => op_attr(Vbroadcasti128, &[Vx, Mdq], Attr::L1),
+        0x78 => op(Vpbroadcastb, &[Vx, Wb]),
+        0x79 => op(Vpbroadcastw, &[Vx, Ww]),
+        0x8C => op(if w { Vpmaskmovq } else { Vpmaskmovd }, &[Vx, Hx, Mx]),
+        0x8E => op(if w { Vpmaskmovq } else { Vpmaskmovd }, &[Mx, Hx, Vx]),
+        // The gathers: the destination, the VSIB operand and the mask in VEX.vvvv. Doubleword
+        // indices of quadword elements only fill an XMM register, and quadword indices of
+        // doubleword elements only fill one with their results.
+        0x90 if w => op(Vpgatherdq, &[Vx, VSdq, Hx]),
+        0x90 => op(Vpgatherdd, &[Vx, VSx, Hx]),
+        0x91 if w => op(Vpgatherqq, &[Vx, VSx, Hx]),
+        0x91 => op(Vpgatherqd, &[Vdq, VSx, Hdq]),
+        0x92 if w => op(Vgatherdpd, &[Vx, VSdq, Hx]),
+        0x92 => op(Vgatherdps, &[Vx, VSx, Hx]),
+        0x93 if w => op(Vgatherqpd, &[Vx, VSx, Hx]),
+        0x93 => op(Vgatherqps, &[Vdq, VSx, Hdq]),
+        0x96..=0x9F | 0xA6..=0xAF | 0xB6..=0xBF => fma(opcode, w),
+        _ => vex_form(map_0f38(opcode, 1)),
+    }
+}
+
+fn vex_0f3a(opcode: u8, w: bool) -> Entry {
+    match opcode {
+        0x00 if w => op_attr(Vpermq, &[Vx, Wx, Ib], Attr::L1),
+        0x01 if w => op_attr(Vpermpd, &[Vx, Wx, Ib], Attr::L1),
+        0x02 => op(Vpblendd, &[Vx, Hx, Wx, Ib]),
+        0x04 => op(Vpermilps, &[Vx, Wx, Ib]),
+        0x05 => op(Vpermilpd, &[Vx, Wx, Ib]),
+        0x06 => op_attr(Vperm2f128, &[Vx, Hx, Wx, Ib], Attr::L1),
+        0x18 => op_attr(Vinsertf128, &[Vx, Hx, Wdq, Ib], Attr::L1),
+        0x19 => op_attr(Vextractf128, &[Wdq, Vx, Ib], Attr::L1),
+        0x1D if !w => op(Vcvtps2ph, &[Wh, Vx, Ib]),
+        // The opmask shifts, their width selected by the opcode's low bit and VEX.W
+        0x30 => op_attr(if w { Kshiftrw } else { Kshiftrb }, &[Kg, Kr, Ib], Attr::L0),
+        0x31 => op_attr(if w { Kshiftrq } else { Kshiftrd }, &[Kg, Kr, Ib], Attr::L0),
+        0x32 => op_attr(if w { Kshiftlw } else { Kshiftlb }, &[Kg, Kr, Ib], Attr::L0),
+        0x33 => op_attr(if w { Kshiftlq } else { Kshiftld }, &[Kg, Kr, Ib], Attr::L0),
+        0x38 => op_attr(Vinserti128, &[Vx, Hx, Wdq, Ib], Attr::L1),
+        0x39 => op_attr(Vextracti128, &[Wdq, Vx, Ib], Attr::L1),
+        0x46 => op_attr(Vperm2i128, &[Vx, Hx, Wx, Ib], Attr::L1),
+        // The variable blends name their mask in the immediate rather than using XMM0
+        0x4A => op(Blendvps, &[Vx, Hx, Wx, Lx]),
+        0x4B => op(Blendvpd, &[Vx, Hx, Wx, Lx]),
+        0x4C => op(Pblendvb, &[Vx, Hx, Wx, Lx]),
+        _ => vex_form(map_0f3a(opcode, 1)),
+    }
+}
+
+/// The FMA3 instructions that add or subtract alike in every element, by the low nibble of
+/// their opcode halved (8 and 9, A and B, C and D, E and F; the odd ones are the scalar
+/// forms), then the high one (9, A and B for the 132, 213 and 231 operand orders), then PS,
+/// PD, SS and SD.
+static FMA: [[[super::Mnemonic; 4]; 3]; 4] = [
+    [
+        [Vfmadd132ps, Vfmadd132pd, Vfmadd132ss, Vfmadd132sd],
+        [Vfmadd213ps, Vfmadd213pd, Vfmadd213ss, Vfmadd213sd],
+        [Vfmadd231ps, Vfmadd231pd, Vfmadd231ss, Vfmadd231sd],
+    ],
+    [
+        [Vfmsub132ps, Vfmsub132pd, Vfmsub132ss, Vfmsub132sd],
+        [Vfmsub213ps, Vfmsub213pd, Vfmsub213ss, Vfmsub213sd],
+        [Vfmsub231ps, Vfmsub231pd, Vfmsub231ss, Vfmsub231sd],
+    ],
+    [
+        [Vfnmadd132ps, Vfnmadd132pd, Vfnmadd132ss, Vfnmadd132sd],
+        [Vfnmadd213ps, Vfnmadd213pd, Vfnmadd213ss, Vfnmadd213sd],
+        [Vfnmadd231ps, Vfnmadd231pd, Vfnmadd231ss, Vfnmadd231sd],
+    ],
+    [
+        [Vfnmsub132ps, Vfnmsub132pd, Vfnmsub132ss, Vfnmsub132sd],
+        [Vfnmsub213ps, Vfnmsub213pd, Vfnmsub213ss, Vfnmsub213sd],
+        [Vfnmsub231ps, Vfnmsub231pd, Vfnmsub231ss, Vfnmsub231sd],
+    ],
+];
+
+/// VFMADDSUB (low nibble 6) and VFMSUBADD (7), which alternate between subtracting and
+/// adding, by operand order as in [`FMA`], then PS and PD.
+static FMA_ALTERNATING: [[[super::Mnemonic; 2]; 3]; 2] = [
+    [
+        [Vfmaddsub132ps, Vfmaddsub132pd],
+        [Vfmaddsub213ps, Vfmaddsub213pd],
+        [Vfmaddsub231ps, Vfmaddsub231pd],
+    ],
+    [
+        [Vfmsubadd132ps, Vfmsubadd132pd],
+        [Vfmsubadd213ps, Vfmsubadd213pd],
+        [Vfmsubadd231ps, Vfmsubadd231pd],
+    ],
+];
+
+/// An FMA3 instruction, its precision selected by VEX.W.
+fn fma(opcode: u8, w: bool) -> Entry {
+    let (low, order) = ((opcode & 0x0F) as usize, (opcode >> 4) as usize - 9);
+    let scalar = low >= 8 && low & 1 != 0;
+    let mnemonic = match low {
+        6 | 7 => FMA_ALTERNATING[low - 6][order][w as usize],
+        _ => FMA[low / 2 - 4][order][w as usize + 2 * scalar as usize],
+    };
+    match (scalar, w) {
+        (false, _) => op(mnemonic, &[Vx, Hx, Wx]),
+        (true, false) => op_attr(mnemonic, &[Vd, Hx, Wd], Attr::LIG),
+        (true, true) => op_attr(mnemonic, &[Vq, Hx, Wq], Attr::LIG),
+    }
+}
+
+/// An opmask instruction of the width VEX.pp and VEX.W select: a word or quadword without a
+/// prefix, and a byte or doubleword with 66. Those with two sources need VEX.L set, and the
+/// others clear.
+fn opmask(
+    mandatory: u8,
+    w: bool,
+    [b, wd, d, q]: [super::Mnemonic; 4],
+    specs: &'static [Spec],
+) -> Entry {
+    let mnemonic = match (mandatory, w) {
+        (0, false) => wd,
+        (0, true) => q,
+        (1, false) => b,
+        _ => d,
+    };
+    let attr = if specs.len() == 3 { Attr::L1 } else { Attr::L0 };
+    op_attr(mnemonic, specs, attr)
+}
+
+/// Looks up an EVEX-encoded SIMD opcode by its map and EVEX.pp: the AVX-512 forms of the
+/// AVX instructions, and the instructions AVX-512 added. `w` is EVEX.W, which most of them
+/// require to match their element size.
+pub(super) fn evex(map: OpcodeMap, opcode: u8, mandatory: u8, w: bool) -> Entry {
+    match map {
+        OpcodeMap::Map0F => evex_0f(opcode, mandatory, w),
+        OpcodeMap::Map0F38 if mandatory == 1 => evex_0f38(opcode, w),
+        OpcodeMap::Map0F38 if mandatory == 2 => evex_0f38_f3(opcode, w),
+        OpcodeMap::Map0F3A if mandatory == 1 => evex_0f3a(opcode, w),
+        _ => Entry::Invalid,
+    }
+}
+
+fn evex_0f(opcode: u8, mandatory: u8, w: bool) -> Entry {
+    // The double-precision forms (66 and F2) need EVEX.W set and the single-precision ones
+    // clear, and only packed ones can broadcast
+    let double = matches!(mandatory, 1 | 3);
+    let packed = mandatory < 2;
+    let vex = || vex_0f(opcode, mandatory, w);
+    match (opcode, mandatory) {
+        (0x10 | 0x11, _) | (0x28 | 0x29, 0 | 1) if w == double => vex(),
+        (0x12 | 0x16, 2) if !w => vex(),
+        (0x12, 3) if w => vex(),
+        (0x14 | 0x15 | 0x54..=0x57 | 0xC6, 0 | 1) if w == double => evex_form(vex(), Attr::BCST),
+        (0x2A | 0x2D, 2 | 3) => evex_form(vex(), Attr::ER),
+        (0x2C, 2 | 3) => evex_form(vex(), Attr::SAE),
+        (0x2E | 0x2F, 0 | 1) if w == double => evex_form(vex(), Attr::SAE),
+        (0x51 | 0x58 | 0x59 | 0x5C | 0x5E, _) if w == double && packed => {
+            evex_form(vex(), Attr::BCST | Attr::ER)
+        }
+        (0x51 | 0x58 | 0x59 | 0x5C | 0x5E, _) if w == double => evex_form(vex(), Attr::ER),
+        (0x5D | 0x5F, _) if w == double && packed => evex_form(vex(), Attr::BCST | Attr::SAE),
+        (0x5D | 0x5F, _) if w == double => evex_form(vex(), Attr::SAE),
+        (0x5A, 0) if !w => op_attr(Cvtps2pd, &[Vx, Wh], Attr::BCST | Attr::SAE),
+        (0x5A, 1) if w => op_attr(Cvtpd2ps, &[Vh, Wx], Attr::BCST | Attr::ER),
+        (0x5A, 2) if !w => evex_form(vex(), Attr::SAE),
+        (0x5A, 3) if w => evex_form(vex(), Attr::ER),
+        (0x5B, 0 | 1) if !w => evex_form(vex(), Attr::BCST | Attr::ER),
+        (0x5B, 2) if !w => evex_form(vex(), Attr::BCST | Attr::SAE),
+        // Bytes and words, whatever EVEX.W
+        (0x60 | 0x61 | 0x63 | 0x67..=0x69 | 0xC4 | 0xC5 | 0xD1 | 0xD5 | 0xD8..=0xDA, 1)
+        | (0xDC..=0xDE | 0xE0 | 0xE1 | 0xE3..=0xE5 | 0xE8..=0xEA | 0xEC..=0xEE, 1)
+        | (0xF1 | 0xF5 | 0xF6 | 0xF8 | 0xF9 | 0xFC | 0xFD, 1) => vex(),
+        (0x62 | 0x6A | 0x6B | 0x70 | 0xFA | 0xFE, 1) if !w => evex_form(vex(), Attr::BCST),
+        (0x6C | 0x6D | 0xD4 | 0xF4 | 0xFB, 1) if w => evex_form(vex(), Attr::BCST),
+        (0x70, 2 | 3) => vex(),
+        (0xD2 | 0xE2 | 0xF2, 1) if !w => vex(),
+        (0xD3 | 0xF3, 1) if w => vex(),
+        (0xE2, 1) => op(Vpsraq, &[Vx, Hx, Wdq]),
+        (0x64 | 0x65 | 0x74 | 0x75, 1) => compare_form(map_0f(opcode, 1), Attr::NONE),
+        (0x66 | 0x76, 1) if !w => compare_form(map_0f(opcode, 1), Attr::BCST),
+        (0xC2, _) if w == double && packed => {
+            compare_form(map_0f(opcode, mandatory), Attr::BCST | Attr::SAE)
+        }
+        (0xC2, _) if w == double => compare_form(map_0f(opcode, mandatory), Attr::SAE),
+        // MOVQ with EVEX.W
+        (0x6E | 0x7E, 1) => vex(),
+        (0x7E, 2) | (0xD6, 1) if w => vex(),
+        (0x6F | 0x7F, 1..=3) => {
+            let mnemonic = match (mandatory, w) {
+                (1, false) => Vmovdqa32,
+                (1, true) => Vmovdqa64,
+                (2, false) => Vmovdqu32,
+                (2, true) => Vmovdqu64,
+                (3, false) => Vmovdqu8,
+                _ => Vmovdqu16,
+            };
+            op(mnemonic, if opcode == 0x6F { &[Vx, Wx] } else { &[Wx, Vx] })
+        }
+        (0x71, 1) => Entry::Group(&GROUP12_EVEX),
+        (0x72, 1) if w => Entry::Group(&GROUP13_EVEX_W1),
+        (0x72, 1) => Entry::Group(&GROUP13_EVEX),
+        (0x73, 1) if w => Entry::Group(&GROUP14_EVEX_W1),
+        (0x73, 1) => Entry::Group(&GROUP14_EVEX),
+        (0xDB | 0xDF | 0xEB | 0xEF, 1) => {
+            let [d, q] = match opcode {
+                0xDB => [Vpandd, Vpandq],
+                0xDF => [Vpandnd, Vpandnq],
+                0xEB => [Vpord, Vporq],
+                _ => [Vpxord, Vpxorq],
+            };
+            op_attr(if w { q } else { d }, &[Vx, Hx, Wx], Attr::BCST)
+        }
+        (0xE6, 1) if w => op_attr(Cvttpd2dq, &[Vh, Wx], Attr::BCST | Attr::SAE),
+        (0xE6, 2) if !w => op_attr(Cvtdq2pd, &[Vx, Wh], Attr::BCST),
+        (0xE6, 3) if w => op_attr(Cvtpd2dq, &[Vh, Wx], Attr::BCST | Attr::ER),
+        (0xE7, 1) if !w => vex(),
+        // The conversions to and from unsigned and quadword integers. The truncating ones
+        // suppress exceptions where the others take a rounding mode.
+        (0x5B, 0) if w => op_attr(Vcvtqq2ps, &[Vh, Wx], Attr::BCST | Attr::ER),
+        (0xE6, 2) if w => op_attr(Vcvtqq2pd, &[Vx, Wx], Attr::BCST | Attr::ER),
+        (0x78, 0) if w => op_attr(Vcvttpd2udq, &[Vh, Wx], Attr::BCST | Attr::SAE),
+        (0x78, 0) => op_attr(Vcvttps2udq, &[Vx, Wx], Attr::BCST | Attr::SAE),
+        (0x79, 0) if w => op_attr(Vcvtpd2udq, &[Vh, Wx], Attr::BCST | Attr::ER),
+        (0x79, 0) => op_attr(Vcvtps2udq, &[Vx, Wx], Attr::BCST | Attr::ER),
+        (0x78, 1) if w => op_attr(Vcvttpd2uqq, &[Vx, Wx], Attr::BCST | Attr::SAE),
+        (0x78, 1) => op_attr(Vcvttps2uqq, &[Vx, Wh], Attr::BCST | Attr::SAE),
+        (0x79, 1) if w => op_attr(Vcvtpd2uqq, &[Vx, Wx], Attr::BCST | Attr::ER),
+        (0x79, 1) => op_attr(Vcvtps2uqq, &[Vx, Wh], Attr::BCST | Attr::ER),
+        (0x7A, 1) if w => op_attr(Vcvttpd2qq, &[Vx, Wx], Attr::BCST | Attr::SAE),
+        (0x7A, 1) => op_attr(Vcvttps2qq, &[Vx, Wh], Attr::BCST | Attr::SAE),
+        (0x7B, 1) if w => op_attr(Vcvtpd2qq, &[Vx, Wx], Attr::BCST | Attr::ER),
+        (0x7B, 1) => op_attr(Vcvtps2qq, &[Vx, Wh], Attr::BCST | Attr::ER),
+        (0x7A, 2) if w => op_attr(Vcvtuqq2pd, &[Vx, Wx], Attr::BCST | Attr::ER),
+        (0x7A, 2) => op_attr(Vcvtudq2pd, &[Vx, Wh], Attr::BCST),
+        (0x7A, 3) if w => op_attr(Vcvtuqq2ps, &[Vh, Wx], Attr::BCST | Attr::ER),
+        (0x7A, 3) => op_attr(Vcvtudq2ps, &[Vx, Wx], Attr::BCST | Attr::ER),
+        (0x78, 2) => op_attr(Vcvttss2usi, &[Gy, Wd], Attr::LIG | Attr::SAE),
+        (0x78, 3) => op_attr(Vcvttsd2usi, &[Gy, Wq], Attr::LIG | Attr::SAE),
+        (0x79, 2) => op_attr(Vcvtss2usi, &[Gy, Wd], Attr::LIG | Attr::ER),
+        (0x79, 3) => op_attr(Vcvtsd2usi, &[Gy, Wq], Attr::LIG | Attr::ER),
+        (0x7B, 2) => op_attr(Vcvtusi2ss, &[Vd, Hx, Ey], Attr::LIG | Attr::ER),
+        (0x7B, 3) => op_attr(Vcvtusi2sd, &[Vq, Hx, Ey], Attr::LIG | Attr::ER),
+        _ => Entry::Invalid,
+    }
+}
+
+fn evex_0f38(opcode: u8, w: bool) -> Entry {
+    let vex = || vex_0f38(opcode, w);
+    // EVEX.W selects between the doubleword and quadword forms of these
+    let by_w = |d, q| op_attr(if w { q } else { d }, &[Vx, Hx, Wx], Attr::BCST);
+    match opcode {
+        // Bytes and words, and the extensions to doublewords and quadwords
+        0x00
+        | 0x04
+        | 0x0B
+        | 0x1C
+        | 0x1D
+        | 0x20..=0x24
+        | 0x30..=0x34
+        | 0x38
+        | 0x3A
+        | 0x3C
+        | 0x3E
+        | 0x78
+        | 0x79 => vex(),
+        0x25 | 0x35 | 0x18 | 0x58 if !w => vex(),
+        0x19 | 0x59 if w => vex(),
+        // The broadcasts of pairs of doublewords, and of groups of two, four and eight
+        // elements by EVEX.W
+        0x19 => op_attr(Vbroadcastf32x2, &[Vx, Wq], Attr::L1),
+        0x59 => op(Vbroadcasti32x2, &[Vx, Wq]),
+        0x1A => op_attr(
+            if w { Vbroadcastf64x2 } else { Vbroadcastf32x4 },
+            &[Vx, Mdq],
+            Attr::L1,
+        ),
+        0x5A => op_attr(
+            if w { Vbroadcasti64x2 } else { Vbroadcasti32x4 },
+            &[Vx, Mdq],
+            Attr::L1,
+        ),
+        0x1B => op_attr(
+            if w { Vbroadcastf64x4 } else { Vbroadcastf32x8 },
+            &[Vx, Mh],
+            Attr::L2,
+        ),
+        0x5B => op_attr(
+            if w { Vbroadcasti64x4 } else { Vbroadcasti32x8 },
+            &[Vx, Mh],
+            Attr::L2,
+        ),
+        // From a general-purpose register
+        0x7A if !w => op(Vpbroadcastb, &[Vx, Ry]),
+        0x7B if !w => op(Vpbroadcastw, &[Vx, Ry]),
+        0x7C => op(if w { Vpbroadcastq } else { Vpbroadcastd }, &[Vx, Ry]),
+        0x0C | 0x1E | 0x2B if !w => evex_form(vex(), Attr::BCST),
+        0x0D | 0x28 if w => evex_form(vex(), Attr::BCST),
+        0x1F if w => op_attr(Vpabsq, &[Vx, Wx], Attr::BCST),
+        0x13 if !w => evex_form(vex(), Attr::SAE),
+        // FMA3, which only broadcasts in its packed forms
+        0x96..=0x9F | 0xA6..=0xAF | 0xB6..=0xBF => match vex() {
+            entry @ Entry::Op((_, [Vx, ..], _)) => evex_form(entry, Attr::BCST | Attr::ER),
+            entry => evex_form(entry, Attr::ER),
+        },
+        0x29 | 0x37 if w => compare_form(map_0f38(opcode, 1), Attr::BCST),
+        0x26 => op(if w { Vptestmw } else { Vptestmb }, &[Kg, Hx, Wx]),
+        0x27 => op_attr(
+            if w { Vptestmq } else { Vptestmd },
+            &[Kg, Hx, Wx],
+            Attr::BCST,
+        ),
+        0x16 => op_attr(
+            if w { Vpermpd } else { Vpermps },
+            &[Vx, Hx, Wx],
+            Attr::L1 | Attr::BCST,
+        ),
+        0x36 => op_attr(
+            if w { Vpermq } else { Vpermd },
+            &[Vx, Hx, Wx],
+            Attr::L1 | Attr::BCST,
+        ),
+        0x39 => by_w(Pminsd, Vpminsq),
+        0x3B => by_w(Pminud, Vpminuq),
+        0x3D => by_w(Pmaxsd, Vpmaxsq),
+        0x3F => by_w(Pmaxud, Vpmaxuq),
+        0x40 => by_w(Pmulld, Vpmullq),
+        0x45 => by_w(Vpsrlvd, Vpsrlvq),
+        0x46 => by_w(Vpsravd, Vpsravq),
+        0x47 => by_w(Vpsllvd, Vpsllvq),
+        0x14 => by_w(Vprorvd, Vprorvq),
+        0x15 => by_w(Vprolvd, Vprolvq),
+        0x10 if w => op(Vpsrlvw, &[Vx, Hx, Wx]),
+        0x11 if w => op(Vpsravw, &[Vx, Hx, Wx]),
+        0x12 if w => op(Vpsllvw, &[Vx, Hx, Wx]),
+        0x64 => by_w(Vpblendmd, Vpblendmq),
+        0x65 => by_w(Vblendmps, Vblendmpd),
+        0x66 => op(if w { Vpblendmw } else { Vpblendmb }, &[Vx, Hx, Wx]),
+        // The permutes of two tables, overwriting the indices (VPERMI2) or the first table
+        // (VPERMT2)
+        0x75 if w => op(Vpermi2w, &[Vx, Hx, Wx]),
+        0x76 => by_w(Vpermi2d, Vpermi2q),
+        0x77 => by_w(Vpermi2ps, Vpermi2pd),
+        0x7D if w => op(Vpermt2w, &[Vx, Hx, Wx]),
+        0x7E => by_w(Vpermt2d, Vpermt2q),
+        0x7F => by_w(Vpermt2ps, Vpermt2pd),
+        0x8D if w => op(Vpermw, &[Vx, Hx, Wx]),
+        // The selected elements packed together at the bottom, and spread back out
+        0x88 => op(if w { Vexpandpd } else { Vexpandps }, &[Vx, Wx]),
+        0x89 => op(if w { Vpexpandq } else { Vpexpandd }, &[Vx, Wx]),
+        0x8A => op(if w { Vcompresspd } else { Vcompressps }, &[Wx, Vx]),
+        0x8B => op(if w { Vpcompressq } else { Vpcompressd }, &[Wx, Vx]),
+        0x2C => evex_form(by_w(Vscalefps, Vscalefpd), Attr::ER),
+        0x2D => evex_scalar([Vscalefss, Vscalefsd], w, false, Attr::ER),
+        0x42 => op_attr(
+            if w { Vgetexppd } else { Vgetexpps },
+            &[Vx, Wx],
+            Attr::BCST | Attr::SAE,
+        ),
+        0x43 => evex_scalar([Vgetexpss, Vgetexpsd], w, false, Attr::SAE),
+        // Approximations to within 2^-14
+        0x4C => op_attr(if w { Vrcp14pd } else { Vrcp14ps }, &[Vx, Wx], Attr::BCST),
+        0x4D => evex_scalar([Vrcp14ss, Vrcp14sd], w, false, Attr::NONE),
+        0x4E => op_attr(
+            if w { Vrsqrt14pd } else { Vrsqrt14ps },
+            &[Vx, Wx],
+            Attr::BCST,
+        ),
+        0x4F => evex_scalar([Vrsqrt14ss, Vrsqrt14sd], w, false, Attr::NONE),
+        // The gathers and scatters, under the opmask rather than a mask vector, by doubleword
+        // or quadword indices
+        0x90 if w => op(Vpgatherdq, &[Vx, VSdq]),
+        0x90 => op(Vpgatherdd, &[Vx, VSx]),
+        0x91 if w => op(Vpgatherqq, &[Vx, VSx]),
+        0x91 => op(Vpgatherqd, &[Vh, VSx]),
+        0x92 if w => op(Vgatherdpd, &[Vx, VSdq]),
+        0x92 => op(Vgatherdps, &[Vx, VSx]),
+        0x93 if w => op(Vgatherqpd, &[Vx, VSx]),
+        0x93 => op(Vgatherqps, &[Vh, VSx]),
+        0xA0 if w => op(Vpscatterdq, &[VSdq, Vx]),
+        0xA0 => op(Vpscatterdd, &[VSx, Vx]),
+        0xA1 if w => op(Vpscatterqq, &[VSx, Vx]),
+        0xA1 => op(Vpscatterqd, &[VSx, Vh]),
+        0xA2 if w => op(Vscatterdpd, &[VSdq, Vx]),
+        0xA2 => op(Vscatterdps, &[VSx, Vx]),
+        0xA3 if w => op(Vscatterqpd, &[VSx, Vx]),
+        0xA3 => op(Vscatterqps, &[VSx, Vh]),
+        // Conflict detection, and leading zero counts
+        0xC4 => op_attr(
+            if w { Vpconflictq } else { Vpconflictd },
+            &[Vx, Wx],
+            Attr::BCST,
+        ),
+        0x44 => op_attr(if w { Vplzcntq } else { Vplzcntd }, &[Vx, Wx], Attr::BCST),
+        _ => Entry::Invalid,
+    }
+}
+
+/// The EVEX-encoded instructions of the 0F38 map with an F3 prefix: the narrowing moves,
+/// VPTESTNM, the moves between opmask registers and vectors, and the broadcasts of opmask
+/// registers.
+fn evex_0f38_f3(opcode: u8, w: bool) -> Entry {
+    match opcode {
+        // Truncating, then with signed and unsigned saturation, by rows of the opcode
+        0x10..=0x15 | 0x20..=0x25 | 0x30..=0x35 if !w => {
+            let (mnemonics, specs): (_, &'static [Spec]) = match opcode & 0x0F {
+                0 => ([Vpmovuswb, Vpmovswb, Vpmovwb], &[Wh, Vx]),
+                1 => ([Vpmovusdb, Vpmovsdb, Vpmovdb], &[Wf, Vx]),
+                2 => ([Vpmovusqb, Vpmovsqb, Vpmovqb], &[We, Vx]),
+                3 => ([Vpmovusdw, Vpmovsdw, Vpmovdw], &[Wh, Vx]),
+                4 => ([Vpmovusqw, Vpmovsqw, Vpmovqw], &[Wf, Vx]),
+                _ => ([Vpmovusqd, Vpmovsqd, Vpmovqd], &[Wh, Vx]),
+            };
+            op(mnemonics[(opcode >> 4) as usize - 1], specs)
+        }
+        0x26 => op(if w { Vptestnmw } else { Vptestnmb }, &[Kg, Hx, Wx]),
+        0x27 => op_attr(
+            if w { Vptestnmq } else { Vptestnmd },
+            &[Kg, Hx, Wx],
+            Attr::BCST,
+        ),
+        0x28 => op(if w { Vpmovm2w } else { Vpmovm2b }, &[Vx, Kr]),
+        0x29 => op(if w { Vpmovw2m } else { Vpmovb2m }, &[Kg, Ux]),
+        0x2A if w => op(Vpbroadcastmb2q, &[Vx, Kr]),
+        0x38 => op(if w { Vpmovm2q } else { Vpmovm2d }, &[Vx, Kr]),
+        0x39 => op(if w { Vpmovq2m } else { Vpmovd2m }, &[Kg, Ux]),
+        0x3A if !w => op(Vpbroadcastmw2d, &[Vx, Kr]),
+        _ => Entry::Invalid,
+    }
+}
+
+fn evex_0f3a(opcode: u8, w: bool) -> Entry {
+    let vex = || vex_0f3a(opcode, w);
+    // The comparisons with a predicate in the immediate, signed and unsigned
+    let compare = |d, q, attr| op_attr(if w { q } else { d }, &[Kg, Hx, Wx, Ib], attr);
+    match opcode {
+        0x00 | 0x01 if w => evex_form(vex(), Attr::BCST),
+        0x04 if !w => evex_form(vex(), Attr::BCST),
+        0x05 if w => evex_form(vex(), Attr::BCST),
+        0x0F | 0x14..=0x17 | 0x20..=0x22 => vex(),
+        0x1D if !w => evex_form(vex(), Attr::SAE),
+        // Quarters of vectors as four doublewords or two quadwords, and halves as eight
+        // doublewords or four quadwords, by EVEX.W
+        0x18 => op_attr(
+            if w { Vinsertf64x2 } else { Vinsertf32x4 },
+            &[Vx, Hx, Wdq, Ib],
+            Attr::L1,
+        ),
+        0x19 => op_attr(
+            if w { Vextractf64x2 } else { Vextractf32x4 },
+            &[Wdq, Vx, Ib],
+            Attr::L1,
+        ),
+        0x1A => op_attr(
+            if w { Vinsertf64x4 } else { Vinsertf32x8 },
+            &[Vx, Hx, Wh, Ib],
+            Attr::L2,
+        ),
+        0x1B => op_attr(
+            if w { Vextractf64x4 } else { Vextractf32x8 },
+            &[Wh, Vx, Ib],
+            Attr::L2,
+        ),
+        0x38 => op_attr(
+            if w { Vinserti64x2 } else { Vinserti32x4 },
+            &[Vx, Hx, Wdq, Ib],
+            Attr::L1,
+        ),
+        0x39 => op_attr(
+            if w { Vextracti64x2 } else { Vextracti32x4 },
+            &[Wdq, Vx, Ib],
+            Attr::L1,
+        ),
+        0x3A => op_attr(
+            if w { Vinserti64x4 } else { Vinserti32x8 },
+            &[Vx, Hx, Wh, Ib],
+            Attr::L2,
+        ),
+        0x3B => op_attr(
+            if w { Vextracti64x4 } else { Vextracti32x8 },
+            &[Wh, Vx, Ib],
+            Attr::L2,
+        ),
+        0x03 => op_attr(
+            if w { Valignq } else { Valignd },
+            &[Vx, Hx, Wx, Ib],
+            Attr::BCST,
+        ),
+        // Any 128 bits of the first source in the low half of the result, and any of the
+        // second in the high half
+        0x23 => op_attr(
+            if w { Vshuff64x2 } else { Vshuff32x4 },
+            &[Vx, Hx, Wx, Ib],
+            Attr::L1 | Attr::BCST,
+        ),
+        0x43 => op_attr(
+            if w { Vshufi64x2 } else { Vshufi32x4 },
+            &[Vx, Hx, Wx, Ib],
+            Attr::L1 | Attr::BCST,
+        ),
+        0x42 if !w => op(Vdbpsadbw, &[Vx, Hx, Wx, Ib]),
+        // The floating-point operations taking their controls from the immediate
+        0x08 if !w => op_attr(Vrndscaleps, &[Vx, Wx, Ib], Attr::BCST | Attr::SAE),
+        0x09 if w => op_attr(Vrndscalepd, &[Vx, Wx, Ib], Attr::BCST | Attr::SAE),
+        0x0A if !w => evex_scalar([Vrndscaless, Vrndscalesd], w, true, Attr::SAE),
+        0x0B if w => evex_scalar([Vrndscaless, Vrndscalesd], w, true, Attr::SAE),
+        0x26 => op_attr(
+            if w { Vgetmantpd } else { Vgetmantps },
+            &[Vx, Wx, Ib],
+            Attr::BCST | Attr::SAE,
+        ),
+        0x27 => evex_scalar([Vgetmantss, Vgetmantsd], w, true, Attr::SAE),
+        0x50 => op_attr(
+            if w { Vrangepd } else { Vrangeps },
+            &[Vx, Hx, Wx, Ib],
+            Attr::BCST | Attr::SAE,
+        ),
+        0x51 => evex_scalar([Vrangess, Vrangesd], w, true, Attr::SAE),
+        0x54 => op_attr(
+            if w { Vfixupimmpd } else { Vfixupimmps },
+            &[Vx, Hx, Wx, Ib],
+            Attr::BCST | Attr::SAE,
+        ),
+        0x55 => evex_scalar([Vfixupimmss, Vfixupimmsd], w, true, Attr::SAE),
+        0x56 => op_attr(
+            if w { Vreducepd } else { Vreduceps },
+            &[Vx, Wx, Ib],
+            Attr::BCST | Attr::SAE,
+        ),
+        0x57 => evex_scalar([Vreducess, Vreducesd], w, true, Attr::SAE),
+        0x66 => op_attr(
+            if w { Vfpclasspd } else { Vfpclassps },
+            &[Kg, Wx, Ib],
+            Attr::BCST,
+        ),
+        0x67 if w => op_attr(Vfpclasssd, &[Kg, Wq, Ib], Attr::LIG),
+        0x67 => op_attr(Vfpclassss, &[Kg, Wd, Ib], Attr::LIG),
+        0x1E => compare(Vpcmpud, Vpcmpuq, Attr::BCST),
+        0x1F => compare(Vpcmpd, Vpcmpq, Attr::BCST),
+        0x3E => compare(Vpcmpub, Vpcmpuw, Attr::NONE),
+        0x3F => compare(Vpcmpb, Vpcmpw, Attr::NONE),
+        0x25 => op_attr(
+            if w { Vpternlogq } else { Vpternlogd },
+            &[Vx, Hx, Wx, Ib],
+            Attr::BCST,
+        ),
+        _ => Entry::Invalid,
+    }
+}
+
+/// The EVEX form of an AVX instruction, with what EVEX.b may do for it.
+fn evex_form(entry: Entry, attr: Attr) -> Entry {
+    match entry {
+        Entry::Op((mnemonic, specs, vex_attr)) => op_attr(mnemonic, specs, vex_attr | attr),
+        entry => entry,
+    }
+}
+
+/// A scalar AVX-512 floating-point instruction, single or double precision by EVEX.W, with
+/// an immediate operand if `imm` is set.
+fn evex_scalar(mnemonics: [super::Mnemonic; 2], w: bool, imm: bool, attr: Attr) -> Entry {
+    let specs: &'static [Spec] = match (w, imm) {
+        (false, false) => &[Vd, Hx, Wd],
+        (true, false) => &[Vq, Hx, Wq],
+        (false, true) => &[Vd, Hx, Wd, Ib],
+        (true, true) => &[Vq, Hx, Wq, Ib],
+    };
+    op_attr(mnemonics[w as usize], specs, attr | Attr::LIG)
+}
+
+/// The AVX-512 form of an SSE comparison, which writes its result to an opmask register.
+fn compare_form(entry: Entry, attr: Attr) -> Entry {
+    match entry {
+        Entry::Op((mnemonic, [Vx, Wx], _)) => op_attr(mnemonic, &[Kg, Hx, Wx], attr),
+        Entry::Op((mnemonic, [Vx, Wx, Ib], _)) => op_attr(mnemonic, &[Kg, Hx, Wx, Ib], attr),
+        Entry::Op((mnemonic, [Vd, Wd, Ib], _)) => {
+            op_attr(mnemonic, &[Kg, Hx, Wd, Ib], attr | Attr::LIG)
+        }
+        Entry::Op((mnemonic, [Vq, Wq, Ib], _)) => {
+            op_attr(mnemonic, &[Kg, Hx, Wq, Ib], attr | Attr::LIG)
+        }
+        _ => Entry::Invalid,
+    }
+}
+
+/// The AVX form of an SSE instruction. Instructions computing a vector result take their
+/// first source from VEX.vvvv rather than the destination, unless they have only one
+/// source; the MMX forms and the blends on XMM0 have no VEX encoding.
+fn vex_form(entry: Entry) -> Entry {
+    let Entry::Op((mnemonic, specs, _)) = entry else {
+        return Entry::Invalid;
+    };
+    if specs
+        .iter()
+        .any(|spec| matches!(spec, Pq | Qq | Qd | Nq | Xmm0))
+    {
+        return Entry::Invalid;
+    }
+    let specs: &'static [Spec] = match specs {
+        _ if is_unary(mnemonic) => specs,
+        [Vx, Wx] => &[Vx, Hx, Wx],
+        [Vx, Wx, Ib] => &[Vx, Hx, Wx, Ib],
+        [Vd, Wd] => &[Vd, Hx, Wd],
+        [Vq, Wq] => &[Vq, Hx, Wq],
+        [Vq, Wd] => &[Vq, Hx, Wd],
+        [Vd, Wq] => &[Vd, Hx, Wq],
+        [Vd, Wd, Ib] => &[Vd, Hx, Wd, Ib],
+        [Vq, Wq, Ib] => &[Vq, Hx, Wq, Ib],
+        [Vx, RdMb, Ib] => &[Vx, Hx, RdMb, Ib],
+        [Vx, Ey, Ib] => &[Vx, Hx, Ey, Ib],
+        [Vx, UxMd, Ib] => &[Vx, Hx, UxMd, Ib],
+        _ => specs,
+    };
+    let attr = if matches!(specs, [Vd | Vq, ..] | [Gy, Wd | Wq]) {
+        Attr::LIG
+    } else if is_128_bit(mnemonic) {
+        Attr::L0
+    } else {
+        Attr::NONE
+    };
+    op_attr(mnemonic, specs, attr)
+}
+
+/// Whether the AVX form of an SSE instruction keeps a single source: the moves (MOVSS and
+/// MOVSD from memory; between registers they merge), and the operations on one vector.
+fn is_unary(mnemonic: super::Mnemonic) -> bool {
+    matches!(
+        mnemonic,
+        Movss
+            | Movsd
+            | Movups
+            | Movupd
+            | Movaps
+            | Movapd
+            | Movdqa
+            | Movdqu
+            | Movsldup
+            | Movshdup
+            | Sqrtps
+            | Sqrtpd
+            | Rsqrtps
+            | Rcpps
+            | Cvtdq2ps
+            | Cvtps2dq
+            | Cvttps2dq
+            | Comiss
+            | Comisd
+            | Ucomiss
+            | Ucomisd
+            | Ptest
+            | Pabsb
+            | Pabsw
+            | Pabsd
+            | Phminposuw
+            | Pshufd
+            | Pshufhw
+            | Pshuflw
+            | Roundps
+            | Roundpd
+            | Pcmpestri
+            | Pcmpestrm
+            | Pcmpistri
+            | Pcmpistrm
+            | Aesimc
+            | Aeskeygenassist
+    )
+}
+
+/// Whether the AVX form of an SSE instruction only exists for 128-bit vectors.
+fn is_128_bit(mnemonic: super::Mnemonic) -> bool {
+    matches!(
+        mnemonic,
+        Movlps
+            | Movlpd
+            | Movhps
+            | Movhpd
+            | Movd
+            | Movq
+            | Maskmovdqu
+            | Pinsrb
+            | Pinsrw
+            | Pinsrd
+            | Pextrb
+            | Pextrw
+            | Pextrd
+            | Extractps
+            | Insertps
+            | Phminposuw
+            | Pcmpestri
+            | Pcmpestrm
+            | Pcmpistri
+            | Pcmpistrm
+            | Dppd
+            | Aesimc
+            | Aesenc
+            | Aesenclast
+            | Aesdec
+            | Aesdeclast
+            | Aeskeygenassist
+            | Pclmulqdq
+    )
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+
+/// An architectural exception raised while decoding or executing an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    /// #DE
+    DivideError,
+    /// #DB
+    Debug,
+    /// #BP
+    Breakpoint,
+    /// #OF
+    Overflow,
+    /// #BR
+    BoundRange,
+    /// #UD
+    InvalidOpcode,
+    /// #NM
+    DeviceNotAvailable,
+    /// #DF
+    DoubleFault,
+    /// #TS, with its error code
+    InvalidTss(u16),
+    /// #NP, with its error code
+    SegmentNotPresent(u16),
+    /// #SS, with its error code
+    StackFault(u16),
+    /// #GP, with its error code
+    GeneralProtection(u16),
+    /// #PF, with its error code. The faulting linear address goes in CR2.
+    PageFault(u32),
+    /// #MF
+    FloatingPoint,
+    /// #AC
+    AlignmentCheck,
+    /// #XM
+    SimdFloatingPoint,
+}
+
+impl Exception {
+    /// The interrupt vector the exception is delivered through.
+    pub fn vector(&self) -> u8 {
+        match self {
+            Exception::DivideError => 0,
+            Exception::Debug => 1,
+            Exception::Breakpoint => 3,
+            Exception::Overflow => 4,
+            Exception::BoundRange => 5,
+            Exception::InvalidOpcode => 6,
+            Exception::DeviceNotAvailable => 7,
+            Exception::DoubleFault => 8,
+            Exception::InvalidTss(_) => 10,
+            Exception::SegmentNotPresent(_) => 11,
+            Exception::StackFault(_) => 12,
+            Exception::GeneralProtection(_) => 13,
+            Exception::PageFault(_) => 14,
+            Exception::FloatingPoint => 16,
+            Exception::AlignmentCheck => 17,
+            Exception::SimdFloatingPoint => 19,
+        }
+    }
+
+    /// The error code pushed alongside the exception, if it has one.
+    pub fn error_code(&self) -> Option<u32> {
+        match *self {
+            Exception::InvalidTss(code)
+            | Exception::SegmentNotPresent(code)
+            | Exception::StackFault(code)
+            | Exception::GeneralProtection(code) => Some(code as u32),
+            Exception::PageFault(code) => Some(code),
+            Exception::DoubleFault | Exception::AlignmentCheck => Some(0),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Exception::DivideError => "#DE",
+            Exception::Debug => "#DB",
+            Exception::Breakpoint => "#BP",
+            Exception::Overflow => "#OF",
+            Exception::BoundRange => "#BR",
+            Exception::InvalidOpcode => "#UD",
+            Exception::DeviceNotAvailable => "#NM",
+            Exception::DoubleFault => "#DF",
+            Exception::InvalidTss(_) => "#TS",
+            Exception::SegmentNotPresent(_) => "#NP",
+            Exception::StackFault(_) => "#SS",
+            Exception::GeneralProtection(_) => "#GP",
+            Exception::PageFault(_) => "#PF",
+            Exception::FloatingPoint => "#MF",
+            Exception::AlignmentCheck => "#AC",
+            Exception::SimdFloatingPoint => "#XM",
+        };
+        match self.error_code() {
+            Some(code) => write!(f, "{}({:#x})", name, code),
+            None => f.write_str(name),
+        }
+    }
+}
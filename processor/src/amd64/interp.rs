@@ -1,164 +1,726 @@
+use super::cpu_model::{CpuModel, Feature};
+use super::decode::{
+    self, Instruction, MemoryOperand, Mnemonic, Mode, Operand, OperandSize, Register,
+};
+use super::exception::Exception;
 use crate::ProcessorImplementation;
-use bitflags::bitflags;
+use descriptor::{Access, Descriptor};
 use file_loader::MemoryMap;
 
 use bytemuck::Zeroable;
 use file_loader::Registers;
 
-#[derive(PartialEq)]
-pub enum OperandSize {
-    R8,
-    R16,
-    R32,
-    R64,
-}
-
 pub struct Amd64Interp {
     regs: Registers,
+    /// Exception that stopped execution, if any
+    fault: Option<Exception>,
+    /// Print each instruction to stderr before executing it
+    pub trace: bool,
+    /// The processor being emulated, which decides what CPUID reports and which extensions
+    /// are available
+    model: CpuModel,
+    /// The generator RDRAND and RDSEED draw from
+    random: random::Random,
+    /// The time-stamp counter, which counts the instructions run rather than host time so
+    /// that a program sees the same timings on every run
+    tsc: u64,
+    /// The IA32_TSC_AUX MSR, which RDTSCP reads along with the counter
+    tsc_aux: u64,
+    /// The GS base SWAPGS exchanges with GS's (the IA32_KERNEL_GS_BASE MSR)
+    kernel_gs_base: u64,
+    /// The kind of code being run: 64-bit, 32-bit for an IA-32 program, or 16-bit for real
+    /// mode
+    mode: Mode,
+    /// Whether address line 20 is enabled. With the A20 gate closed, real-mode addresses
+    /// past 1 MiB wrap around to 0 as they did on the 8086.
+    a20: bool,
+    /// The keyboard controller, whose output port is one way to open the A20 gate
+    keyboard: io::KeyboardController,
+    /// Set by HLT, which stops the processor for good since nothing can interrupt it
+    halted: bool,
+    /// The hidden part of ES, CS, SS, DS, FS and GS, loaded from their descriptors. Their
+    /// bases are also in `regs.sr_base`, which is what addresses are formed with.
+    segments: [Descriptor; 6],
+    /// The descriptor table registers and the task register
+    tables: descriptor::Tables,
+    /// The IA32_EFER MSR
+    efer: u64,
+    /// XCR0, the XSAVE state components the operating system has enabled
+    xcr0: u64,
+    /// DR0-DR7. Breakpoints aren't modeled, so they only hold what was moved to them.
+    debug: [u64; 8],
+    /// The translations paging has made, until the page tables change and it's flushed
+    tlb: paging::Tlb,
+    /// Whether the program runs as a Linux user process: there are no descriptor tables in
+    /// its memory, only the GDT entries Linux gives user code, and nothing to deliver
+    /// exceptions to but the system call layer
+    linux_user: bool,
 }
 
 impl Amd64Interp {
     pub fn new() -> Amd64Interp {
+        Amd64Interp::with_model(CpuModel::default())
+    }
+
+    /// A processor emulating `model` rather than the default x86-64-v3 one.
+    pub fn with_model(model: CpuModel) -> Amd64Interp {
         Amd64Interp {
             regs: Registers::zeroed(),
+            fault: None,
+            trace: false,
+            model,
+            random: random::Random::new(random::DEFAULT_SEED),
+            tsc: 0,
+            tsc_aux: 0,
+            kernel_gs_base: 0,
+            mode: Mode::Bits64,
+            a20: true,
+            keyboard: io::KeyboardController::default(),
+            halted: false,
+            segments: [Descriptor::default(); 6],
+            tables: descriptor::Tables::default(),
+            efer: 0,
+            xcr0: system::XCR0_X87,
+            debug: system::DEBUG_RESET,
+            tlb: paging::Tlb::default(),
+            linux_user: false,
         }
     }
 
-    pub fn modrm<T>(&mut self, map: &mut dyn MemoryMap, size: OperandSize, rex_r: bool, function: T)
-    where
-        T: Fn(u64, u64, OperandSize) -> u64,
-    {
-        let modrm_byte = map.read_u8(self.regs.rip);
-        self.regs.rip += 1;
-        let mut src =
-            self.regs.gprs[(((modrm_byte >> 3) & 0x07) + if rex_r { 8 } else { 0 }) as usize];
-        let saved;
-        let dst = match modrm_byte & 0xC0 {
-            0x00 => {
-                saved = match modrm_byte & 7 {
-                    4 => panic!("SIB not implemented!"),
-                    5 => {
-                        let offset = map.read_u32(self.regs.rip) as i64 as u64;
-                        self.regs.rip += 4;
-                        self.regs.rip + offset
-                    }
-                    _ => self.regs.gprs[(modrm_byte & 7) as usize],
-                };
-                map.read_u32(saved) as u64
+    pub fn model(&self) -> &CpuModel {
+        &self.model
+    }
+
+    /// The exception that stopped the processor, if one did.
+    pub fn fault(&self) -> Option<Exception> {
+        self.fault
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Whether the processor is in real mode: CR0.PE is clear.
+    pub fn real_mode(&self) -> bool {
+        self.regs.cr[0] & segment::CR0_PE == 0
+    }
+
+    pub fn a20(&self) -> bool {
+        self.a20
+    }
+
+    /// Opens or closes the A20 gate, as a program would through the keyboard controller or
+    /// port 0x92.
+    pub fn set_a20(&mut self, enabled: bool) {
+        self.a20 = enabled;
+    }
+
+    /// Computes the linear address of a memory operand: its offset plus the base of its
+    /// segment. `rip` must already point past the end of the instruction.
+    pub fn effective_address(&self, mem: &MemoryOperand) -> u64 {
+        self.linear(Self::segment_of(mem), self.address_offset(mem))
+    }
+
+    /// The segment register a memory operand is in: the one an override names, or SS for
+    /// addresses based on rSP or rBP and DS for the rest.
+    fn segment_of(mem: &MemoryOperand) -> usize {
+        match (mem.segment, mem.base) {
+            (Some(sreg), _) => sreg as usize,
+            (None, Some(4 | 5)) => segment::SS,
+            (None, _) => segment::DS,
+        }
+    }
+
+    /// Computes the linear address of a memory operand `bytes` long, checking the access
+    /// against its segment's limit and type first.
+    fn checked_address(
+        &self,
+        mem: &MemoryOperand,
+        bytes: u64,
+        access: Access,
+    ) -> Result<u64, Exception> {
+        self.checked_element(mem, 0, bytes, access)
+    }
+
+    /// Like [`Self::checked_address`], for `bytes` at `displacement` past the operand's
+    /// offset: an element of a masked move, or one a gather or scatter indexes.
+    fn checked_element(
+        &self,
+        mem: &MemoryOperand,
+        displacement: u64,
+        bytes: u64,
+        access: Access,
+    ) -> Result<u64, Exception> {
+        let sreg = Self::segment_of(mem);
+        let offset = self.address_offset(mem).wrapping_add(displacement) & mem.address_size.mask();
+        self.check_access(sreg, offset, bytes, access)?;
+        Ok(self.linear(sreg, offset))
+    }
+
+    /// The offset of a memory operand into its segment, as LEA computes it.
+    pub fn address_offset(&self, mem: &MemoryOperand) -> u64 {
+        let mut addr = if mem.rip_relative { self.regs.rip } else { 0 };
+        if let Some(base) = mem.base {
+            addr = addr.wrapping_add(self.regs.gprs[base as usize]);
+        }
+        // The elements of a VSIB index are applied by the gathers themselves
+        if let (Some(index), None) = (mem.index, mem.vector_index) {
+            addr = addr.wrapping_add(self.regs.gprs[index as usize].wrapping_mul(mem.scale as u64));
+        }
+        addr = addr.wrapping_add(mem.displacement as u64);
+        addr & mem.address_size.mask()
+    }
+
+    /// The linear address of `offset` in segment register `sreg`. In 64-bit mode only FS
+    /// and GS have bases.
+    fn linear(&self, sreg: usize, offset: u64) -> u64 {
+        match self.mode {
+            Mode::Bits64 if sreg < segment::FS => offset,
+            Mode::Bits64 => offset.wrapping_add(self.regs.sr_base[sreg]),
+            _ => self.linear_address(offset.wrapping_add(self.regs.sr_base[sreg])),
+        }
+    }
+
+    /// Wraps a linear address around the address space, which outside 64-bit mode is 4 GiB,
+    /// or 1 MiB with the A20 gate closed.
+    fn linear_address(&self, addr: u64) -> u64 {
+        match self.mode {
+            Mode::Bits64 => addr,
+            _ if !self.a20 => addr & 0xFFEF_FFFF,
+            _ => addr & 0xFFFF_FFFF,
+        }
+    }
+
+    /// The width of the stack pointer: RSP in 64-bit mode, and otherwise ESP or SP by the
+    /// stack segment's B bit.
+    fn stack_size(&self) -> OperandSize {
+        match self.mode {
+            Mode::Bits64 => OperandSize::R64,
+            _ if self.segments[segment::SS].has(descriptor::BIG) => OperandSize::R32,
+            _ => OperandSize::R16,
+        }
+    }
+
+    /// Reads general-purpose register `n` at the given size.
+    pub fn read_gpr(&self, n: u8, size: OperandSize) -> u64 {
+        self.regs.gprs[n as usize] & size.mask()
+    }
+
+    /// Writes general-purpose register `n` at the given size. 8- and 16-bit writes leave the
+    /// rest of the register alone, while 32-bit writes zero-extend into the upper half.
+    pub fn write_gpr(&mut self, n: u8, size: OperandSize, value: u64) {
+        let reg = &mut self.regs.gprs[n as usize];
+        *reg = match size {
+            OperandSize::R32 | OperandSize::R64 => value & size.mask(),
+            _ => (*reg & !size.mask()) | (value & size.mask()),
+        };
+    }
+
+    /// Reads a value of the given size from guest memory, at a linear address.
+    pub(crate) fn read_memory(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        addr: u64,
+        size: OperandSize,
+    ) -> Result<u64, Exception> {
+        let user = self.cpl() == 3;
+        self.read_memory_as(map, addr, size, user)
+    }
+
+    /// Reads a value of the given size as an access from CPL 3 (`user`) or a more
+    /// privileged level, which decides what pages it may read.
+    fn read_memory_as(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        addr: u64,
+        size: OperandSize,
+        user: bool,
+    ) -> Result<u64, Exception> {
+        let bytes = match size {
+            OperandSize::R80 | OperandSize::R128 | OperandSize::R256 | OperandSize::R512 => {
+                unreachable!("{:?} values don't fit in a u64", size)
             }
-            0xC0 => {
-                saved = (modrm_byte & 0x7) as u64;
-                let result = &mut self.regs.gprs[saved as usize];
-                if size == OperandSize::R32 {
-                    *result &= 0x00000000FFFFFFFF; // Zero out higher half
+            _ => size.bytes() as usize,
+        };
+        let mut addr = addr;
+        if self.paging() {
+            let pieces = self.translate_range(map, addr, bytes, Access::Read, user)?;
+            if pieces.len() > 1 {
+                // The value straddles two pages
+                let mut buf = [0; 8];
+                let mut done = 0;
+                for (addr, len) in pieces {
+                    map.read_bytes(addr, &mut buf[done..done + len]);
+                    done += len;
                 }
-                *result
+                return Ok(u64::from_le_bytes(buf));
             }
-            _ => panic!("Unrecognized ModR/M dst in {:#04X}", modrm_byte),
+            addr = pieces[0].0;
+        }
+        Ok(match size {
+            OperandSize::R8 => map.read_u8(addr) as u64,
+            OperandSize::R16 => map.read_u16(addr) as u64,
+            OperandSize::R32 => map.read_u32(addr) as u64,
+            _ => map.read_u64(addr),
+        })
+    }
+
+    /// Writes a value of the given size to guest memory, at a linear address.
+    pub(crate) fn write_memory(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        addr: u64,
+        size: OperandSize,
+        value: u64,
+    ) -> Result<(), Exception> {
+        let user = self.cpl() == 3;
+        self.write_memory_as(map, addr, size, value, user)
+    }
+
+    /// Writes a value of the given size as an access from CPL 3 (`user`) or a more
+    /// privileged level.
+    fn write_memory_as(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        addr: u64,
+        size: OperandSize,
+        value: u64,
+        user: bool,
+    ) -> Result<(), Exception> {
+        let bytes = match size {
+            OperandSize::R80 | OperandSize::R128 | OperandSize::R256 | OperandSize::R512 => {
+                unreachable!("{:?} values don't fit in a u64", size)
+            }
+            _ => size.bytes() as usize,
         };
+        let mut addr = addr;
+        if self.paging() {
+            let pieces = self.translate_range(map, addr, bytes, Access::Write, user)?;
+            if pieces.len() > 1 {
+                let data = value.to_le_bytes();
+                let mut done = 0;
+                for (addr, len) in pieces {
+                    map.write_bytes(addr, &data[done..done + len]);
+                    done += len;
+                }
+                return Ok(());
+            }
+            addr = pieces[0].0;
+        }
+        match size {
+            OperandSize::R8 => map.write_u8(addr, value as u8),
+            OperandSize::R16 => map.write_u16(addr, value as u16),
+            OperandSize::R32 => map.write_u32(addr, value as u32),
+            _ => map.write_u64(addr, value),
+        }
+        Ok(())
+    }
 
-        if size == OperandSize::R32 {
-            src &= 0x00000000FFFFFFFF;
+    /// Reads a run of bytes from guest memory.
+    pub(crate) fn read_bytes(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        addr: u64,
+        buf: &mut [u8],
+    ) -> Result<(), Exception> {
+        if !self.paging() {
+            map.read_bytes(addr, buf);
+            return Ok(());
         }
-        let result = function(dst, src, size);
-        match modrm_byte & 0xC0 {
-            0x00 => map.write_u32(saved, result as u32),
-            0xC0 => self.regs.gprs[saved as usize] = result,
-            _ => panic!("Unrecognized ModR/M dst in {:#04X}", modrm_byte),
+        let user = self.cpl() == 3;
+        let mut done = 0;
+        for (addr, len) in self.translate_range(map, addr, buf.len(), Access::Read, user)? {
+            map.read_bytes(addr, &mut buf[done..done + len]);
+            done += len;
         }
+        Ok(())
     }
 
-    pub fn modrmdet<T>(&mut self, map: &mut dyn MemoryMap, size: OperandSize, mut function: T)
-    where
-        T: FnMut(u64, u8, OperandSize) -> u64,
-    {
-        let modrm_byte = map.read_u8(self.regs.rip);
-        self.regs.rip += 1;
-        let determ = ((modrm_byte >> 3) & 0x07) as u8;
-        let saved;
-        let dst = match modrm_byte & 0xC0 {
-            0x00 => {
-                saved = match modrm_byte & 7 {
-                    4 => panic!("SIB not implemented!"),
-                    5 => {
-                        let offset = map.read_u32(self.regs.rip) as i64 as u64;
-                        self.regs.rip += 4;
-                        self.regs.rip + offset
-                    }
-                    _ => self.regs.gprs[(modrm_byte & 7) as usize],
-                };
-                map.read_u32(saved) as u64
+    /// Writes a run of bytes to guest memory.
+    pub(crate) fn write_bytes(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        addr: u64,
+        data: &[u8],
+    ) -> Result<(), Exception> {
+        if !self.paging() {
+            map.write_bytes(addr, data);
+            return Ok(());
+        }
+        let user = self.cpl() == 3;
+        let mut done = 0;
+        for (addr, len) in self.translate_range(map, addr, data.len(), Access::Write, user)? {
+            map.write_bytes(addr, &data[done..done + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn read_operand(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        operand: &Operand,
+    ) -> Result<u64, Exception> {
+        match *operand {
+            Operand::Register(Register::Gpr(n), size) => Ok(self.read_gpr(n, size)),
+            Operand::Register(Register::HighByte(n), _) => {
+                Ok((self.regs.gprs[n as usize] >> 8) & 0xFF)
             }
-            0xC0 => {
-                saved = (modrm_byte & 0x7) as u64;
-                let result = &mut self.regs.gprs[saved as usize];
-                if size == OperandSize::R32 {
-                    *result &= 0x00000000FFFFFFFF; // Zero out higher half
-                }
-                *result
+            Operand::Register(Register::Segment(n), _) => Ok(self.regs.sr[n as usize] as u64),
+            Operand::Register(Register::Mm(n), _) => Ok(self.regs.fpu.st[n as usize].significand),
+            Operand::Register(Register::Opmask(n), size) => {
+                Ok(self.regs.k[n as usize] & size.mask())
             }
-            _ => panic!("Unrecognized ModR/M dst in {:#04X}", modrm_byte),
-        };
-        let result = function(dst, determ, size);
-        match modrm_byte & 0xC0 {
-            0x00 => map.write_u32(saved, result as u32),
-            0xC0 => self.regs.gprs[saved as usize] = result,
-            _ => panic!("Unrecognized ModR/M dst in {:#04X}", modrm_byte),
+            Operand::Memory(mem, size) => {
+                let addr = self.checked_address(&mem, size.bytes(), Access::Read)?;
+                self.read_memory(map, addr, size)
+            }
+            Operand::Immediate(value, size) => Ok(value & size.mask()),
+            // An operand the instruction's module should have handled itself
+            _ => Err(Exception::InvalidOpcode),
         }
     }
 
-    pub fn modrmimm<T>(&mut self, map: &mut dyn MemoryMap, size: OperandSize, function: T)
-    where
-        T: Fn(u64, u8, i8, OperandSize) -> u64,
-    {
-        let modrm_byte = map.read_u8(self.regs.rip);
-        self.regs.rip += 1;
-        let determ = ((modrm_byte >> 3) & 0x07) as u8;
-        let dst = match modrm_byte & 0xC0 {
-            0xC0 => {
-                let result = &mut self.regs.gprs[(modrm_byte & 0x7) as usize];
-                if size == OperandSize::R32 {
-                    *result &= 0x00000000FFFFFFFF; // Zero out higher half
-                }
-                *result
+    fn write_operand(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        operand: &Operand,
+        value: u64,
+    ) -> Result<(), Exception> {
+        match *operand {
+            Operand::Register(Register::Gpr(n), size) => self.write_gpr(n, size, value),
+            Operand::Register(Register::HighByte(n), _) => {
+                let reg = &mut self.regs.gprs[n as usize];
+                *reg = (*reg & !0xFF00) | ((value & 0xFF) << 8);
             }
-            _ => panic!("Unrecognized ModR/M dst in {:#04X}", modrm_byte),
-        };
-        let imm = map.read_u8(self.regs.rip) as i8;
-        self.regs.rip += 1;
-        let result = function(dst, determ, imm, size);
-        match modrm_byte & 0xC0 {
-            0xC0 => self.regs.gprs[(modrm_byte & 0x7) as usize] = result,
-            _ => panic!("Unrecognized ModR/M dst in {:#04X}", modrm_byte),
+            Operand::Register(Register::Segment(n), _) => {
+                return self.load_segment(map, n as usize, value as u16)
+            }
+            // MMX writes set the exponent and sign to all ones, so the register reads back
+            // as a NaN to x87 code
+            Operand::Register(Register::Mm(n), _) => {
+                let reg = &mut self.regs.fpu.st[n as usize];
+                reg.significand = value;
+                reg.sign_exponent = 0xFFFF;
+            }
+            // Opmask writes clear the rest of the register
+            Operand::Register(Register::Opmask(n), size) => {
+                self.regs.k[n as usize] = value & size.mask()
+            }
+            Operand::Memory(mem, size) => {
+                let addr = self.checked_address(&mem, size.bytes(), Access::Write)?;
+                return self.write_memory(map, addr, size, value);
+            }
+            _ => return Err(Exception::InvalidOpcode),
         }
+        Ok(())
     }
 
-    pub fn modrmlea(&mut self, map: &mut dyn MemoryMap, size: OperandSize, rex_r: bool) {
-        let modrm_byte = map.read_u8(self.regs.rip);
-        self.regs.rip += 1;
+    /// Pushes a value of the given size (16 or 64 bits in 64-bit mode, 16 or 32 otherwise)
+    /// onto the stack.
+    fn push(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        value: u64,
+        size: OperandSize,
+    ) -> Result<(), Exception> {
+        let stack_size = self.stack_size();
+        let rsp = self.read_gpr(4, stack_size).wrapping_sub(size.bytes()) & stack_size.mask();
+        self.check_access(segment::SS, rsp, size.bytes(), Access::Write)?;
+        self.write_memory(map, self.linear(segment::SS, rsp), size, value)?;
+        self.move_stack_pointer(rsp);
+        Ok(())
+    }
 
-        let result = match modrm_byte & 0xC0 {
-            0x00 => match modrm_byte & 7 {
-                4 => panic!("SIB not implemented!"),
-                5 => {
-                    let offset = map.read_u32(self.regs.rip) as i64 as u64;
-                    self.regs.rip += 4;
-                    self.regs.rip + offset
-                }
-                _ => self.regs.gprs[(modrm_byte & 7) as usize],
-            },
-            0xC0 => panic!("Can't LEA a register!"),
-            _ => panic!("Unrecognized ModR/M dst in {:#04X}", modrm_byte),
-        };
+    /// Pops a value of the given size off the stack.
+    fn pop(&mut self, map: &mut dyn MemoryMap, size: OperandSize) -> Result<u64, Exception> {
+        let rsp = self.read_gpr(4, self.stack_size());
+        self.check_access(segment::SS, rsp, size.bytes(), Access::Read)?;
+        let value = self.read_memory(map, self.linear(segment::SS, rsp), size)?;
+        self.move_stack_pointer(rsp.wrapping_add(size.bytes()));
+        Ok(value)
+    }
 
-        let dst =
-            &mut self.regs.gprs[(((modrm_byte >> 3) & 0x07) + if rex_r { 8 } else { 0 }) as usize];
+    /// Sets the stack pointer, wrapping it around at its width.
+    fn move_stack_pointer(&mut self, rsp: u64) {
+        self.write_gpr(4, self.stack_size(), rsp);
+    }
 
-        *dst = result;
+    /// Decodes and executes one instruction. On error, `rip` is left pointing at the
+    /// faulting instruction.
+    fn step(&mut self, map: &mut dyn MemoryMap) -> Result<(), Exception> {
+        let user = self.cpl() == 3;
+        let mut insn = decode::decode(self.regs.rip, self.mode, &mut |addr| {
+            let linear = self.linear(segment::CS, addr);
+            let addr = self.translate(map, linear, Access::Execute, user)?;
+            Ok(map.read_u8(addr))
+        })?;
+        self.check_access(
+            segment::CS,
+            insn.address,
+            insn.length as u64,
+            Access::Execute,
+        )?;
+        self.check_extension(&mut insn)?;
+        if self.trace {
+            eprintln!("{:#018x}: {}", insn.address, insn);
+        }
+        self.tsc = self.tsc.wrapping_add(1);
+        // The instruction pointer wraps around at the width of the mode's addresses
+        self.regs.rip = insn.next_address() & self.mode.address_size().mask();
+        let locked = atomic::is_locked(&insn);
+        if locked {
+            map.lock_bus();
+        }
+        let result = self.execute(map, &insn);
+        if locked {
+            map.unlock_bus();
+        }
+        result.inspect_err(|_| self.regs.rip = insn.address)
+    }
 
-        if size == OperandSize::R32 {
-            *dst &= 0x00000000FFFFFFFF;
+    /// Raises #UD for instructions from extensions the model doesn't have.
+    fn check_extension(&self, insn: &mut Instruction) -> Result<(), Exception> {
+        if Feature::also_required_by(insn).is_some_and(|feature| !self.model.has(feature)) {
+            return Err(Exception::InvalidOpcode);
+        }
+        let Some(feature) = Feature::required_by(insn) else {
+            return Ok(());
+        };
+        // LAHF and SAHF were only missing from the first processors' 64-bit mode
+        if feature == Feature::LahfLm && self.mode != Mode::Bits64 {
+            return Ok(());
+        }
+        if self.model.has(feature) {
+            return Ok(());
+        }
+        // Without LZCNT and TZCNT their encodings are BSR and BSF with an ignored REP prefix
+        insn.mnemonic = match insn.mnemonic {
+            Mnemonic::Lzcnt => Mnemonic::Bsr,
+            Mnemonic::Tzcnt => Mnemonic::Bsf,
+            _ => return Err(Exception::InvalidOpcode),
+        };
+        Ok(())
+    }
+
+    fn execute(&mut self, map: &mut dyn MemoryMap, insn: &Instruction) -> Result<(), Exception> {
+        match insn.mnemonic {
+            // Memory accesses are already performed in order and there are no caches to flush,
+            // nor another logical processor for a spin-wait to yield to
+            Mnemonic::Nop
+            | Mnemonic::Pause
+            | Mnemonic::Endbr32
+            | Mnemonic::Endbr64
+            | Mnemonic::Lfence
+            | Mnemonic::Mfence
+            | Mnemonic::Sfence
+            | Mnemonic::Clflush => {}
+            // RSM only returns from system-management mode, which is never entered. The MSRs
+            // SYSCALL and SYSENTER take their targets from aren't modeled, so they fault as
+            // with them unset: SYSCALL and SYSRET as with EFER.SCE clear, and SYSENTER and
+            // SYSEXIT as with a null IA32_SYSENTER_CS.
+            Mnemonic::Ud0
+            | Mnemonic::Ud1
+            | Mnemonic::Ud2
+            | Mnemonic::Rsm
+            | Mnemonic::Syscall
+            | Mnemonic::Sysret => return Err(Exception::InvalidOpcode),
+            Mnemonic::Sysenter | Mnemonic::Sysexit => return Err(Exception::GeneralProtection(0)),
+            Mnemonic::In | Mnemonic::Out | Mnemonic::Ins | Mnemonic::Outs => {
+                return self.execute_io(map, insn)
+            }
+            Mnemonic::Add
+            | Mnemonic::Adc
+            | Mnemonic::Sub
+            | Mnemonic::Sbb
+            | Mnemonic::Cmp
+            | Mnemonic::And
+            | Mnemonic::Or
+            | Mnemonic::Xor
+            | Mnemonic::Inc
+            | Mnemonic::Dec
+            | Mnemonic::Neg
+            | Mnemonic::Not
+            | Mnemonic::Mul
+            | Mnemonic::Imul
+            | Mnemonic::Div
+            | Mnemonic::Idiv => return self.execute_alu(map, insn),
+            Mnemonic::Shl
+            | Mnemonic::Shr
+            | Mnemonic::Sar
+            | Mnemonic::Rol
+            | Mnemonic::Ror
+            | Mnemonic::Rcl
+            | Mnemonic::Rcr
+            | Mnemonic::Shld
+            | Mnemonic::Shrd => return self.execute_shift(map, insn),
+            Mnemonic::Mov
+                if insn.operands().iter().any(|op| {
+                    matches!(
+                        op,
+                        Operand::Register(Register::Control(_) | Register::Debug(_), _)
+                    )
+                }) =>
+            {
+                return self.execute_system(map, insn)
+            }
+            Mnemonic::Mov
+            | Mnemonic::Movnti
+            | Mnemonic::Movzx
+            | Mnemonic::Movsx
+            | Mnemonic::Movsxd
+            | Mnemonic::Movbe
+            | Mnemonic::Bswap
+            | Mnemonic::Xchg
+            | Mnemonic::Lea
+            | Mnemonic::Xlat
+            | Mnemonic::Cbw
+            | Mnemonic::Cwde
+            | Mnemonic::Cdqe
+            | Mnemonic::Cwd
+            | Mnemonic::Cdq
+            | Mnemonic::Cqo
+            | Mnemonic::Push
+            | Mnemonic::Pop
+            | Mnemonic::Enter
+            | Mnemonic::Leave
+            | Mnemonic::Pushf
+            | Mnemonic::Popf
+            | Mnemonic::Lahf
+            | Mnemonic::Sahf
+            | Mnemonic::Pusha
+            | Mnemonic::Popa => return self.execute_data(map, insn),
+            Mnemonic::Daa
+            | Mnemonic::Das
+            | Mnemonic::Aaa
+            | Mnemonic::Aas
+            | Mnemonic::Aam
+            | Mnemonic::Aad
+            | Mnemonic::Salc
+            | Mnemonic::Bound
+            | Mnemonic::Arpl => return self.execute_legacy(map, insn),
+            Mnemonic::Cmpxchg | Mnemonic::Cmpxchg8b | Mnemonic::Cmpxchg16b | Mnemonic::Xadd => {
+                return self.execute_atomic(map, insn)
+            }
+            Mnemonic::Bt
+            | Mnemonic::Bts
+            | Mnemonic::Btr
+            | Mnemonic::Btc
+            | Mnemonic::Bsf
+            | Mnemonic::Bsr
+            | Mnemonic::Popcnt
+            | Mnemonic::Lzcnt
+            | Mnemonic::Tzcnt
+            | Mnemonic::Andn
+            | Mnemonic::Bextr
+            | Mnemonic::Blsi
+            | Mnemonic::Blsmsk
+            | Mnemonic::Blsr
+            | Mnemonic::Bzhi
+            | Mnemonic::Pdep
+            | Mnemonic::Pext
+            | Mnemonic::Shlx
+            | Mnemonic::Shrx
+            | Mnemonic::Sarx
+            | Mnemonic::Rorx
+            | Mnemonic::Mulx
+            | Mnemonic::Crc32 => return self.execute_bits(map, insn),
+            Mnemonic::Movs | Mnemonic::Cmps | Mnemonic::Stos | Mnemonic::Lods | Mnemonic::Scas => {
+                return self.execute_string(map, insn)
+            }
+            Mnemonic::Clc => self.regs.rflags &= !flags::CF,
+            Mnemonic::Stc => self.regs.rflags |= flags::CF,
+            Mnemonic::Cmc => self.regs.rflags ^= flags::CF,
+            Mnemonic::Cld => self.regs.rflags &= !flags::DF,
+            Mnemonic::Std => self.regs.rflags |= flags::DF,
+            Mnemonic::Jmp
+            | Mnemonic::Jcc
+            | Mnemonic::Call
+            | Mnemonic::Ret
+            | Mnemonic::Loop
+            | Mnemonic::Loope
+            | Mnemonic::Loopne
+            | Mnemonic::Jrcxz
+            | Mnemonic::Setcc
+            | Mnemonic::Cmovcc
+            | Mnemonic::Test => return self.execute_control(map, insn),
+            Mnemonic::Cpuid
+            | Mnemonic::Xgetbv
+            | Mnemonic::Xsetbv
+            | Mnemonic::Lgdt
+            | Mnemonic::Lidt
+            | Mnemonic::Sgdt
+            | Mnemonic::Sidt
+            | Mnemonic::Lldt
+            | Mnemonic::Ltr
+            | Mnemonic::Sldt
+            | Mnemonic::Str
+            | Mnemonic::Lmsw
+            | Mnemonic::Smsw
+            | Mnemonic::Clts
+            | Mnemonic::Rdmsr
+            | Mnemonic::Wrmsr
+            | Mnemonic::Rdtsc
+            | Mnemonic::Rdtscp
+            | Mnemonic::Rdpmc
+            | Mnemonic::Invd
+            | Mnemonic::Wbinvd
+            | Mnemonic::Invlpg
+            | Mnemonic::Invpcid
+            | Mnemonic::Lar
+            | Mnemonic::Lsl
+            | Mnemonic::Verr
+            | Mnemonic::Verw => return self.execute_system(map, insn),
+            Mnemonic::Rdrand | Mnemonic::Rdseed => return self.execute_random(map, insn),
+            Mnemonic::Rdfsbase
+            | Mnemonic::Rdgsbase
+            | Mnemonic::Wrfsbase
+            | Mnemonic::Wrgsbase
+            | Mnemonic::Swapgs => return self.execute_segment(map, insn),
+            Mnemonic::Jmpf
+            | Mnemonic::Callf
+            | Mnemonic::Retf
+            | Mnemonic::Les
+            | Mnemonic::Lds
+            | Mnemonic::Lss
+            | Mnemonic::Lfs
+            | Mnemonic::Lgs => return self.execute_far(map, insn),
+            Mnemonic::Int
+            | Mnemonic::Int3
+            | Mnemonic::Int1
+            | Mnemonic::Into
+            | Mnemonic::Iret
+            | Mnemonic::Iretd
+            | Mnemonic::Iretq
+            | Mnemonic::Cli
+            | Mnemonic::Sti
+            | Mnemonic::Hlt => return self.execute_interrupt(map, insn),
+            mnemonic if mnemonic.is_x87() => return self.execute_x87(map, insn),
+            Mnemonic::Ldmxcsr
+            | Mnemonic::Stmxcsr
+            | Mnemonic::Fxsave
+            | Mnemonic::Fxrstor
+            | Mnemonic::Xsave
+            | Mnemonic::Xsaveopt
+            | Mnemonic::Xsavec
+            | Mnemonic::Xrstor
+            | Mnemonic::Vzeroupper
+            | Mnemonic::Vzeroall
+            // With a memory source these name no XMM register at all
+            | Mnemonic::Cvtss2si
+            | Mnemonic::Cvttss2si
+            | Mnemonic::Cvtsd2si
+            | Mnemonic::Cvttsd2si
+            | Mnemonic::Vcvtss2usi
+            | Mnemonic::Vcvttss2usi
+            | Mnemonic::Vcvtsd2usi
+            | Mnemonic::Vcvttsd2usi => return self.execute_sse(map, insn),
+            _ if insn.is_mmx() => return self.execute_mmx(map, insn),
+            _ if insn.is_sse() || insn.is_opmask() => return self.execute_sse(map, insn),
+            // Decoded, but not executed by anything
+            _ => return Err(Exception::InvalidOpcode),
         }
+        Ok(())
     }
 }
 
@@ -168,193 +730,117 @@ impl Default for Amd64Interp {
     }
 }
 
-bitflags! {
-    struct Prefixes: u64 {
-        const NONE = 0b00000000;
-        const REP = 0b00000001;
-        const OPSIZE = 0b00000010;
-        const REX = 0b00000100;
-        const REX_B = 0b00001000;
-        const REX_X = 0b00010000;
-        const REX_R = 0b00100000;
-        const REX_W = 0b01000000;
-    }
-}
-
 impl ProcessorImplementation for Amd64Interp {
     fn init(&mut self, map: &mut dyn MemoryMap) {
         self.regs.rip = map.entry_point();
         self.regs.gprs[4] = map.starting_stack();
+        self.regs.rflags = flags::RESERVED | flags::IF;
+        match map.bits() {
+            16 => {
+                // Real mode as the BIOS leaves it for a boot sector, A20 gate closed, with
+                // the IVT at 0. The code and stack are addressed from the segments holding
+                // them, and the data segments start out the same as the code's.
+                self.a20 = false;
+                self.tables.gdtr.limit = 0xFFFF;
+                self.tables.idtr.limit = 0xFFFF;
+                let cs = ((self.regs.rip >> 4) & 0xF000) as u16;
+                let ss = ((self.regs.gprs[4] >> 4) & 0xF000) as u16;
+                for (sreg, selector) in [cs, cs, ss, cs, cs, cs].into_iter().enumerate() {
+                    let attributes = match sreg {
+                        segment::CS => descriptor::CODE | descriptor::READABLE,
+                        _ => descriptor::WRITABLE,
+                    };
+                    self.set_segment(sreg, selector, Descriptor::real_mode(selector, attributes));
+                }
+                self.regs.rip &= 0xFFFF;
+                self.regs.gprs[4] &= 0xFFFF;
+            }
+            bits => {
+                // A Linux user process, in long mode for a 64-bit program, on the code and
+                // data selectors Linux gives it. FS and GS start out flat for a system call
+                // layer to point at thread-local storage.
+                self.linux_user = true;
+                self.regs.cr[0] |= segment::CR0_PE;
+                if bits == 64 {
+                    self.efer = system::EFER_LME | system::EFER_LMA;
+                }
+                let cs = if bits == 64 { 0x33 } else { 0x23 };
+                let data =
+                    Descriptor::from_raw(descriptor::linux_user_descriptor(0x2B).unwrap(), 0);
+                for (sreg, selector) in [0x2B, cs, 0x2B, 0x2B, 0, 0].into_iter().enumerate() {
+                    let descriptor = match descriptor::linux_user_descriptor(selector) {
+                        Some(raw) => Descriptor::from_raw(raw, 0),
+                        None => data,
+                    };
+                    self.set_segment(sreg, selector, descriptor);
+                }
+                // 64-bit code runs with null data segments, as Linux leaves them
+                if bits == 64 {
+                    for sreg in [segment::ES, segment::DS] {
+                        self.set_segment(sreg, 0, Descriptor::unusable());
+                    }
+                }
+            }
+        }
+        self.reset_fpu();
+        self.regs.fpu.mxcsr = simd::DEFAULT_MXCSR;
+        self.regs.cr[4] = simd::CR4_OSFXSR | simd::CR4_OSXMMEXCPT;
+        if self.model.has(Feature::Osxsave) {
+            self.regs.cr[4] |= simd::CR4_OSXSAVE;
+            self.xcr0 = self.model.xcr0();
+        }
+        if self.model.has(Feature::Fsgsbase) {
+            self.regs.cr[4] |= segment::CR4_FSGSBASE;
+        }
     }
 
     fn running(&self) -> bool {
-        true
+        self.fault.is_none() && !self.halted
     }
 
     fn tick(&mut self, map: &mut dyn MemoryMap) {
-        let mut prefixes = Prefixes::NONE;
-        let mut done = false;
-        while !done {
-            let instr = map.read_u8(self.regs.rip);
-            dbg!(self.regs.rip, instr);
-            self.regs.rip += 1;
-            match instr {
-                0x0F => {
-                    let instr2 = map.read_u8(self.regs.rip);
-                    self.regs.rip += 1;
-                    match instr2 {
-                        0x1E => {
-                            let _ = map.read_u8(self.regs.rip); // ModR/M
-                            self.regs.rip += 1;
-                            done = true // It's either a NOP or an ENDBR64, neither of which we care about
-                        }
-                        _ => panic!("Unrecognized instruction 0x0F{:02X}", instr2),
-                    }
-                }
-                0x31 => {
-                    // XOR r/m16/32/64, r16/32/64
-                    self.modrm(
-                        map,
-                        if prefixes.contains(Prefixes::REX_W) {
-                            OperandSize::R32
-                        } else {
-                            OperandSize::R64
-                        },
-                        prefixes.contains(Prefixes::REX_R),
-                        |a, b, _| a ^ b,
-                    );
-                }
-                0x40 => prefixes |= Prefixes::REX,
-                0x41 => prefixes |= Prefixes::REX | Prefixes::REX_B,
-                0x42 => prefixes |= Prefixes::REX | Prefixes::REX_X,
-                0x43 => prefixes |= Prefixes::REX | Prefixes::REX_X | Prefixes::REX_B,
-                0x44 => prefixes |= Prefixes::REX | Prefixes::REX_R,
-                0x45 => prefixes |= Prefixes::REX | Prefixes::REX_R | Prefixes::REX_B,
-                0x46 => prefixes |= Prefixes::REX | Prefixes::REX_R | Prefixes::REX_X,
-                0x47 => {
-                    prefixes |= Prefixes::REX | Prefixes::REX_R | Prefixes::REX_X | Prefixes::REX_B
-                }
-                0x48 => prefixes |= Prefixes::REX | Prefixes::REX_W,
-                0x49 => prefixes |= Prefixes::REX | Prefixes::REX_W | Prefixes::REX_B,
-                0x4A => prefixes |= Prefixes::REX | Prefixes::REX_W | Prefixes::REX_X,
-                0x4B => {
-                    prefixes |= Prefixes::REX | Prefixes::REX_W | Prefixes::REX_X | Prefixes::REX_B
-                }
-                0x4C => prefixes |= Prefixes::REX | Prefixes::REX_W | Prefixes::REX_R,
-                0x4D => {
-                    prefixes |= Prefixes::REX | Prefixes::REX_W | Prefixes::REX_R | Prefixes::REX_B
-                }
-                0x4E => {
-                    prefixes |= Prefixes::REX | Prefixes::REX_W | Prefixes::REX_R | Prefixes::REX_X
-                }
-                0x4F => {
-                    prefixes |= Prefixes::REX
-                        | Prefixes::REX_W
-                        | Prefixes::REX_R
-                        | Prefixes::REX_X
-                        | Prefixes::REX_B
-                }
-                0x50 => {
-                    self.regs.gprs[4] -= 8;
-                    map.write_u64(
-                        self.regs.gprs[4], /* rsp */
-                        self.regs.gprs[0], /* rax */
-                    );
-                }
-                0x54 => {
-                    self.regs.gprs[4] -= 8;
-                    map.write_u64(
-                        self.regs.gprs[4], /* rsp */
-                        self.regs.gprs[4], /* rsp */
-                    );
-                }
-                0x5E => {
-                    self.regs.gprs[6] /* rsi */ = map.read_u64(self.regs.gprs[4] /* rsp */);
-                    self.regs.gprs[4] += 8;
-                }
-                0x66 => prefixes |= Prefixes::OPSIZE,
-                0x83 => {
-                    self.modrmimm(
-                        map,
-                        if prefixes.contains(Prefixes::REX_W) {
-                            OperandSize::R32
-                        } else {
-                            OperandSize::R64
-                        },
-                        |a, determ, b, _| match determ {
-                            0 => a + b as u64,
-                            1 => a | b as u64,
-                            2 => a + b as u64,
-                            3 => a - b as u64,
-                            4 => a & b as u64,
-                            5 => a - b as u64,
-                            6 => a ^ b as u64,
-                            7 => a,
-                            _ => unreachable!("determ should be between 0 and 7 inclusive"),
-                        },
-                    );
-                }
-                0x89 => {
-                    // MOV r/m16/32/64, r16/32/64
-                    self.modrm(
-                        map,
-                        if prefixes.contains(Prefixes::REX_W) {
-                            OperandSize::R32
-                        } else {
-                            OperandSize::R64
-                        },
-                        prefixes.contains(Prefixes::REX_R),
-                        |_, b, _| b,
-                    );
-                }
-                0x8D => self.modrmlea(
-                    map,
-                    if prefixes.contains(Prefixes::REX_W) {
-                        OperandSize::R32
-                    } else {
-                        OperandSize::R64
-                    },
-                    prefixes.contains(Prefixes::REX_R),
-                ),
-                0xF3 => prefixes |= Prefixes::REP, // REP / REPZ
-                0xFF => {
-                    let mut addr_real = 0;
-                    let mut det_real = 0;
-                    self.modrmdet(
-                        map,
-                        if prefixes.contains(Prefixes::REX_W) {
-                            OperandSize::R32
-                        } else {
-                            OperandSize::R64
-                        },
-                        |a, determ, _| match determ {
-                            0 => a + 1,
-                            1 => a - 1,
-                            x if x <= 7 => {
-                                addr_real = a;
-                                det_real = determ;
-                                a
-                            }
-                            _ => unreachable!("determ should be between 0 and 7 inclusive"),
-                        },
-                    );
-                    match det_real {
-                        0 | 1 => {} // Already handled
-                        2 => {
-                            self.regs.gprs[4] -= 8;
-                            map.write_u64(self.regs.gprs[4] /* rsp */, self.regs.rip);
-                            self.regs.rip = addr_real;
-                        }
-                        x if x <= 7 => panic!("AAAA FF "),
-                        _ => unreachable!("determ should be between 0 and 7 inclusive"),
-                    }
-                }
-                _ => panic!(
-                    "Unrecognized instruction {:#04X} at address {:#016X}",
-                    instr,
-                    self.regs.rip - 1
-                ),
+        if let Err(e) = self.step(map) {
+            if !self.linux_user {
+                // Exceptions go through the IVT or IDT like any other interrupt, with the
+                // faulting instruction as the return address. Failing to deliver a double
+                // fault shuts the processor down.
+                let Err(e) = self.deliver_exception(map, e) else {
+                    return;
+                };
+                eprintln!("Triple fault at address {:#016X}", self.regs.rip);
+                self.fault = Some(e);
+                return;
             }
+            // There's no IDT to deliver through in user mode, so this ends the program
+            eprintln!(
+                "Unhandled exception {} at address {:#016X}",
+                e, self.regs.rip
+            );
+            self.fault = Some(e);
         }
     }
 }
+
+mod alu;
+mod atomic;
+mod bits;
+mod control;
+mod data;
+mod descriptor;
+pub mod flags;
+mod interrupt;
+mod io;
+mod legacy;
+mod mmx;
+mod packed;
+mod paging;
+mod random;
+pub mod segment;
+mod shift;
+mod simd;
+mod string;
+mod system;
+mod x87;
+
+#[cfg(test)]
+mod tests;
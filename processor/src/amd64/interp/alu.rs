@@ -0,0 +1,164 @@
+//! Integer arithmetic and logic: ADD through CMP, INC/DEC, NEG/NOT, and the multiply and
+//! divide instructions.
+
+use super::{flags, Amd64Interp};
+use crate::amd64::decode::{Instruction, Mnemonic, OperandSize};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+impl Amd64Interp {
+    pub(super) fn execute_alu(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let dst = insn.operand(0);
+        let src = insn.operand(1);
+        let size = dst.size().unwrap();
+        match insn.mnemonic {
+            Mnemonic::Add | Mnemonic::Adc | Mnemonic::Sub | Mnemonic::Sbb | Mnemonic::Cmp => {
+                let a = self.read_operand(map, &dst)?;
+                let b = self.read_operand(map, &src)?;
+                let carry = self.regs.rflags & flags::CF != 0;
+                let rflags = &mut self.regs.rflags;
+                let result = match insn.mnemonic {
+                    Mnemonic::Add => flags::add(a, b, false, size, rflags),
+                    Mnemonic::Adc => flags::add(a, b, carry, size, rflags),
+                    Mnemonic::Sub | Mnemonic::Cmp => flags::sub(a, b, false, size, rflags),
+                    _ => flags::sub(a, b, carry, size, rflags),
+                };
+                if insn.mnemonic != Mnemonic::Cmp {
+                    self.write_operand(map, &dst, result)?;
+                }
+            }
+            Mnemonic::And | Mnemonic::Or | Mnemonic::Xor => {
+                let a = self.read_operand(map, &dst)?;
+                let b = self.read_operand(map, &src)?;
+                let result = match insn.mnemonic {
+                    Mnemonic::And => a & b,
+                    Mnemonic::Or => a | b,
+                    _ => a ^ b,
+                };
+                let result = flags::logic(result, size, &mut self.regs.rflags);
+                self.write_operand(map, &dst, result)?;
+            }
+            Mnemonic::Inc => {
+                let a = self.read_operand(map, &dst)?;
+                let result = flags::inc(a, size, &mut self.regs.rflags);
+                self.write_operand(map, &dst, result)?;
+            }
+            Mnemonic::Dec => {
+                let a = self.read_operand(map, &dst)?;
+                let result = flags::dec(a, size, &mut self.regs.rflags);
+                self.write_operand(map, &dst, result)?;
+            }
+            Mnemonic::Neg => {
+                let a = self.read_operand(map, &dst)?;
+                let result = flags::sub(0, a, false, size, &mut self.regs.rflags);
+                self.write_operand(map, &dst, result)?;
+            }
+            Mnemonic::Not => {
+                // NOT affects no flags
+                let a = self.read_operand(map, &dst)?;
+                self.write_operand(map, &dst, !a)?;
+            }
+            Mnemonic::Imul if insn.operands().len() > 1 => {
+                // Two- and three-operand forms keep only the truncated product
+                let (a, b) = match insn.operand(2).size() {
+                    Some(_) => (
+                        self.read_operand(map, &src)?,
+                        self.read_operand(map, &insn.operand(2))?,
+                    ),
+                    None => (self.read_operand(map, &dst)?, self.read_operand(map, &src)?),
+                };
+                let product = flags::sign_extend(a, size) as i64 as i128
+                    * flags::sign_extend(b, size) as i64 as i128;
+                let low = product as u64 & size.mask();
+                let overflow = flags::sign_extend(low, size) as i64 as i128 != product;
+                flags::multiply(low, overflow, size, &mut self.regs.rflags);
+                self.write_operand(map, &dst, low)?;
+            }
+            Mnemonic::Mul | Mnemonic::Imul => {
+                let a = self.read_gpr(0, size);
+                let b = self.read_operand(map, &dst)?;
+                let (product, overflow) = if insn.mnemonic == Mnemonic::Mul {
+                    let product = a as u128 * b as u128;
+                    (product, product >> size.bits() != 0)
+                } else {
+                    let product = flags::sign_extend(a, size) as i64 as i128
+                        * flags::sign_extend(b, size) as i64 as i128;
+                    // Signed overflow: the product isn't just the sign extension of its low half
+                    let low = flags::sign_extend(product as u64 & size.mask(), size);
+                    (product as u128, low as i64 as i128 != product)
+                };
+                let low = product as u64 & size.mask();
+                let high = (product >> size.bits()) as u64 & size.mask();
+                flags::multiply(low, overflow, size, &mut self.regs.rflags);
+                self.write_wide(size, low, high);
+            }
+            Mnemonic::Div | Mnemonic::Idiv => {
+                let divisor = self.read_operand(map, &dst)?;
+                if divisor == 0 {
+                    return Err(Exception::DivideError);
+                }
+                let (low, high) = self.read_wide(size);
+                let dividend = (high as u128) << size.bits() | low as u128;
+                let (quotient, remainder) = if insn.mnemonic == Mnemonic::Div {
+                    let quotient = dividend / divisor as u128;
+                    if quotient > size.mask() as u128 {
+                        return Err(Exception::DivideError);
+                    }
+                    (quotient as u64, (dividend % divisor as u128) as u64)
+                } else {
+                    // Sign-extend the double-width dividend from its top bit
+                    let shift = 128 - 2 * size.bits();
+                    let dividend = ((dividend << shift) as i128) >> shift;
+                    let divisor = flags::sign_extend(divisor, size) as i64 as i128;
+                    // i128::MIN / -1 overflows even the double width
+                    let quotient = dividend
+                        .checked_div(divisor)
+                        .ok_or(Exception::DivideError)?;
+                    let min = -(1i128 << (size.bits() - 1));
+                    let max = (1i128 << (size.bits() - 1)) - 1;
+                    if quotient < min || quotient > max {
+                        return Err(Exception::DivideError);
+                    }
+                    (
+                        quotient as u64 & size.mask(),
+                        (dividend % divisor) as u64 & size.mask(),
+                    )
+                };
+                // The flags are undefined after a divide; like Intel, leave them be
+                self.write_wide(size, quotient, remainder);
+            }
+            _ => unreachable!("{} is not an ALU instruction", insn.mnemonic),
+        }
+        Ok(())
+    }
+
+    /// Reads the implicit double-width accumulator (AX, DX:AX, EDX:EAX or RDX:RAX) of a
+    /// multiply or divide as its (low, high) halves.
+    fn read_wide(&self, size: OperandSize) -> (u64, u64) {
+        match size {
+            OperandSize::R8 => (
+                self.read_gpr(0, size),
+                self.read_gpr(0, OperandSize::R16) >> 8,
+            ),
+            _ => (self.read_gpr(0, size), self.read_gpr(2, size)),
+        }
+    }
+
+    /// Writes the implicit double-width accumulator of a multiply or divide. For byte
+    /// operands the halves land in AL and AH.
+    fn write_wide(&mut self, size: OperandSize, low: u64, high: u64) {
+        match size {
+            OperandSize::R8 => {
+                self.write_gpr(0, OperandSize::R16, (high & 0xFF) << 8 | (low & 0xFF))
+            }
+            _ => {
+                self.write_gpr(0, size, low);
+                self.write_gpr(2, size, high);
+            }
+        }
+    }
+}
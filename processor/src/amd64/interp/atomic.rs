@@ -0,0 +1,90 @@
+//! Compare-and-exchange, exchange-and-add, and when the bus is locked around an instruction.
+
+use super::descriptor::Access;
+use super::{flags, Amd64Interp};
+use crate::amd64::decode::{Instruction, Mnemonic, Operand, OperandSize, Prefixes};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+/// Whether `insn` must run with the bus locked: it has a LOCK prefix (which the decoder only
+/// accepts on memory read-modify-writes), or it's an XCHG with memory, which always locks.
+pub(super) fn is_locked(insn: &Instruction) -> bool {
+    insn.prefixes.contains(Prefixes::LOCK)
+        || (insn.mnemonic == Mnemonic::Xchg
+            && insn
+                .operands()
+                .iter()
+                .any(|operand| matches!(operand, Operand::Memory(..))))
+}
+
+impl Amd64Interp {
+    pub(super) fn execute_atomic(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let dst = insn.operand(0);
+        let src = insn.operand(1);
+        let size = dst.size().unwrap();
+        match insn.mnemonic {
+            Mnemonic::Cmpxchg => {
+                let accumulator = self.read_gpr(0, size);
+                let current = self.read_operand(map, &dst)?;
+                flags::sub(accumulator, current, false, size, &mut self.regs.rflags);
+                if accumulator == current {
+                    let value = self.read_operand(map, &src)?;
+                    self.write_operand(map, &dst, value)?;
+                } else {
+                    // The destination is written back unchanged, as the locked cycle requires
+                    self.write_operand(map, &dst, current)?;
+                    self.write_gpr(0, size, current);
+                }
+            }
+            Mnemonic::Xadd => {
+                let a = self.read_operand(map, &dst)?;
+                let b = self.read_operand(map, &src)?;
+                let sum = flags::add(a, b, false, size, &mut self.regs.rflags);
+                self.write_operand(map, &dst, sum)?;
+                // The source gets the old destination, unless they're the same register
+                if src != dst {
+                    self.write_operand(map, &src, a)?;
+                }
+            }
+            Mnemonic::Cmpxchg8b | Mnemonic::Cmpxchg16b => {
+                let Operand::Memory(mem, _) = dst else {
+                    unreachable!("CMPXCHG8B/16B only take memory operands")
+                };
+                let half = match insn.mnemonic {
+                    Mnemonic::Cmpxchg8b => OperandSize::R32,
+                    _ => OperandSize::R64,
+                };
+                // Both halves are written back, whether or not they compared equal
+                let addr = self.checked_address(&mem, 2 * half.bytes(), Access::Write)?;
+                if half == OperandSize::R64 && !addr.is_multiple_of(16) {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                let high_addr =
+                    self.checked_element(&mem, half.bytes(), half.bytes(), Access::Write)?;
+                let low = self.read_memory(map, addr, half)?;
+                let high = self.read_memory(map, high_addr, half)?;
+                let equal = low == self.read_gpr(0, half) && high == self.read_gpr(2, half);
+                let (new_low, new_high) = if equal {
+                    (self.read_gpr(3, half), self.read_gpr(1, half))
+                } else {
+                    (low, high)
+                };
+                self.write_memory(map, addr, half, new_low)?;
+                self.write_memory(map, high_addr, half, new_high)?;
+                if equal {
+                    self.regs.rflags |= flags::ZF;
+                } else {
+                    self.write_gpr(0, half, low);
+                    self.write_gpr(2, half, high);
+                    self.regs.rflags &= !flags::ZF;
+                }
+            }
+            mnemonic => unreachable!("{} is not an atomic instruction", mnemonic),
+        }
+        Ok(())
+    }
+}
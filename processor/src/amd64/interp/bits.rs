@@ -0,0 +1,185 @@
+//! Bit tests and scans, population and zero counts, the BMI1/BMI2 group, and CRC32.
+
+use super::{flags, Amd64Interp};
+use crate::amd64::decode::{Instruction, Mnemonic, Operand, OperandSize};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+/// Deposits the low bits of `source` into the positions of the set bits of `mask`.
+fn pdep(source: u64, mask: u64) -> u64 {
+    let (mut result, mut mask, mut bit) = (0, mask, 0);
+    while mask != 0 {
+        let lowest = mask & mask.wrapping_neg();
+        if source & (1 << bit) != 0 {
+            result |= lowest;
+        }
+        mask ^= lowest;
+        bit += 1;
+    }
+    result
+}
+
+/// Gathers the bits of `source` selected by `mask` into the low bits of the result.
+fn pext(source: u64, mask: u64) -> u64 {
+    let (mut result, mut mask, mut bit) = (0, mask, 0);
+    while mask != 0 {
+        let lowest = mask & mask.wrapping_neg();
+        if source & lowest != 0 {
+            result |= 1 << bit;
+        }
+        mask ^= lowest;
+        bit += 1;
+    }
+    result
+}
+
+/// The reflected CRC-32C (Castagnoli) polynomial
+const CRC32C: u32 = 0x82F6_3B78;
+
+/// Accumulates the low `bytes` bytes of `data` into `crc`, least significant byte first.
+fn crc32c(mut crc: u32, data: u64, bytes: usize) -> u32 {
+    for byte in data.to_le_bytes().iter().take(bytes) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (CRC32C & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+/// The low `n` bits of a value, or all of it if `n` is at least 64.
+fn low_bits(value: u64, n: u64) -> u64 {
+    if n >= 64 {
+        value
+    } else {
+        value & ((1 << n) - 1)
+    }
+}
+
+impl Amd64Interp {
+    pub(super) fn execute_bits(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let dst = insn.operand(0);
+        let src = insn.operand(1);
+        let size = dst.size().unwrap();
+        let bits = size.bits() as u64;
+        let result = match insn.mnemonic {
+            Mnemonic::Bt | Mnemonic::Bts | Mnemonic::Btr | Mnemonic::Btc => {
+                let offset = self.read_operand(map, &src)?;
+                let (target, bit) = match (dst, src) {
+                    // A register bit offset into memory is signed and can address any bit
+                    // within ±2^63 of the operand, not just the operand itself
+                    (Operand::Memory(mut mem, size), Operand::Register(..)) => {
+                        let offset = flags::sign_extend(offset, size) as i64;
+                        let delta = (offset >> size.bits().trailing_zeros()) * size.bytes() as i64;
+                        mem.displacement = mem.displacement.wrapping_add(delta);
+                        (Operand::Memory(mem, size), offset as u64 & (bits - 1))
+                    }
+                    _ => (dst, offset & (bits - 1)),
+                };
+                let value = self.read_operand(map, &target)?;
+                flags::bit_test(value >> bit & 1 != 0, &mut self.regs.rflags);
+                let value = match insn.mnemonic {
+                    Mnemonic::Bt => return Ok(()),
+                    Mnemonic::Bts => value | 1 << bit,
+                    Mnemonic::Btr => value & !(1 << bit),
+                    _ => value ^ 1 << bit,
+                };
+                return self.write_operand(map, &target, value);
+            }
+            Mnemonic::Bsf | Mnemonic::Bsr => {
+                let value = self.read_operand(map, &src)?;
+                let index = (value != 0).then(|| {
+                    if insn.mnemonic == Mnemonic::Bsf {
+                        value.trailing_zeros() as u64
+                    } else {
+                        63 - value.leading_zeros() as u64
+                    }
+                });
+                flags::bit_scan(index, size, &mut self.regs.rflags);
+                match index {
+                    Some(index) => index,
+                    // The destination is left untouched, like AMD documents and Intel does
+                    None => return Ok(()),
+                }
+            }
+            Mnemonic::Popcnt => {
+                let value = self.read_operand(map, &src)?;
+                flags::popcnt(value, size, &mut self.regs.rflags)
+            }
+            Mnemonic::Lzcnt | Mnemonic::Tzcnt => {
+                let value = self.read_operand(map, &src)?;
+                let count = if value == 0 {
+                    bits
+                } else if insn.mnemonic == Mnemonic::Lzcnt {
+                    value.leading_zeros() as u64 - (64 - bits)
+                } else {
+                    value.trailing_zeros() as u64
+                };
+                flags::zero_count(count, value == 0, &mut self.regs.rflags);
+                count
+            }
+            Mnemonic::Crc32 => {
+                // The accumulator is 32 bits even with REX.W, and no flags change
+                let crc = self.read_operand(map, &dst)? as u32;
+                let value = self.read_operand(map, &src)?;
+                crc32c(crc, value, src.size().unwrap().bytes() as usize) as u64
+            }
+            _ => return self.execute_bmi(map, insn),
+        };
+        self.write_operand(map, &dst, result)
+    }
+
+    /// The VEX-encoded BMI1 and BMI2 instructions, which all operate on 32 or 64 bits.
+    fn execute_bmi(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let dst = insn.operand(0);
+        let size = dst.size().unwrap();
+        let bits = size.bits() as u64;
+        let a = self.read_operand(map, &insn.operand(1))?;
+        let b = match insn.operand(2) {
+            Operand::None => 0,
+            operand => self.read_operand(map, &operand)?,
+        };
+        let rflags = &mut self.regs.rflags;
+        let result = match insn.mnemonic {
+            Mnemonic::Andn => flags::bmi(!a & b, false, size, rflags),
+            Mnemonic::Bextr => {
+                let (start, len) = (b & 0xFF, (b >> 8) & 0xFF);
+                let field = if start >= bits { 0 } else { a >> start };
+                flags::bmi(low_bits(field, len), false, size, rflags)
+            }
+            Mnemonic::Blsi => flags::bmi(a.wrapping_neg() & a, a != 0, size, rflags),
+            Mnemonic::Blsmsk => flags::bmi(a.wrapping_sub(1) ^ a, a == 0, size, rflags),
+            Mnemonic::Blsr => flags::bmi(a.wrapping_sub(1) & a, a == 0, size, rflags),
+            Mnemonic::Bzhi => {
+                let n = b & 0xFF;
+                flags::bmi(low_bits(a, n), n > bits - 1, size, rflags)
+            }
+            Mnemonic::Pdep => pdep(a, b),
+            Mnemonic::Pext => pext(a, b),
+            Mnemonic::Shlx => a << (b & (bits - 1)),
+            Mnemonic::Shrx => a >> (b & (bits - 1)),
+            Mnemonic::Sarx => ((flags::sign_extend(a, size) as i64) >> (b & (bits - 1))) as u64,
+            Mnemonic::Rorx => match size {
+                OperandSize::R64 => a.rotate_right(b as u32 & 63),
+                _ => (a as u32).rotate_right(b as u32 & 31) as u64,
+            },
+            Mnemonic::Mulx => {
+                // The implicit multiplicand is rDX; the low half goes to VEX.vvvv and the high
+                // half to ModR/M.reg, which wins if both name the same register
+                let product = self.read_gpr(2, size) as u128 * b as u128;
+                self.write_operand(map, &insn.operand(1), product as u64)?;
+                (product >> bits) as u64
+            }
+            mnemonic => unreachable!("{} is not a bit-manipulation instruction", mnemonic),
+        };
+        self.write_operand(map, &dst, result & size.mask())
+    }
+}
@@ -0,0 +1,112 @@
+//! Near control transfers and condition evaluation.
+
+use super::{flags, Amd64Interp};
+use crate::amd64::decode::{Instruction, Mnemonic, Operand, OperandSize, Register};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+impl Amd64Interp {
+    /// Evaluates condition code `cc` (the low nibble of a Jcc, SETcc or CMOVcc opcode)
+    /// against the current flags.
+    pub fn condition(&self, cc: u8) -> bool {
+        let rflags = self.regs.rflags;
+        let set = |flag| rflags & flag != 0;
+        let result = match cc >> 1 {
+            0 => set(flags::OF),
+            1 => set(flags::CF),
+            2 => set(flags::ZF),
+            3 => set(flags::CF) || set(flags::ZF),
+            4 => set(flags::SF),
+            5 => set(flags::PF),
+            6 => set(flags::SF) != set(flags::OF),
+            _ => set(flags::ZF) || set(flags::SF) != set(flags::OF),
+        };
+        // Odd condition codes are the negations of the even ones before them
+        result != (cc & 1 != 0)
+    }
+
+    /// Resolves the destination of a relative or indirect near branch, which wraps around
+    /// at the operand size.
+    fn branch_target(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<u64, Exception> {
+        match insn.operand(0) {
+            Operand::Relative(offset) => {
+                Ok(self.regs.rip.wrapping_add(offset as u64) & insn.operand_size.mask())
+            }
+            target => self.read_operand(map, &target),
+        }
+    }
+
+    pub(super) fn execute_control(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let dst = insn.operand(0);
+        let src = insn.operand(1);
+        match insn.mnemonic {
+            Mnemonic::Jmp => self.regs.rip = self.branch_target(map, insn)?,
+            Mnemonic::Jcc => {
+                if self.condition(insn.condition) {
+                    self.regs.rip = self.branch_target(map, insn)?;
+                }
+            }
+            Mnemonic::Call => {
+                let target = self.branch_target(map, insn)?;
+                self.push(map, self.regs.rip, insn.operand_size)?;
+                self.regs.rip = target;
+            }
+            Mnemonic::Ret => {
+                self.regs.rip = self.pop(map, insn.operand_size)?;
+                if let Some(bytes) = insn.immediate() {
+                    // The callee pops its arguments (stdcall)
+                    let rsp = self.read_gpr(4, self.stack_size());
+                    self.move_stack_pointer(rsp.wrapping_add(bytes));
+                }
+            }
+            Mnemonic::Loop | Mnemonic::Loope | Mnemonic::Loopne | Mnemonic::Jrcxz => {
+                // The counter is RCX or ECX depending on the address size
+                let counter = Operand::Register(Register::Gpr(1), insn.address_size);
+                let mut count = self.read_operand(map, &counter)?;
+                if insn.mnemonic != Mnemonic::Jrcxz {
+                    count = count.wrapping_sub(1) & insn.address_size.mask();
+                    self.write_operand(map, &counter, count)?;
+                }
+                let zf = self.regs.rflags & flags::ZF != 0;
+                let taken = match insn.mnemonic {
+                    Mnemonic::Loop => count != 0,
+                    Mnemonic::Loope => count != 0 && zf,
+                    Mnemonic::Loopne => count != 0 && !zf,
+                    _ => count == 0,
+                };
+                if taken {
+                    self.regs.rip = self.branch_target(map, insn)?;
+                }
+            }
+            Mnemonic::Setcc => {
+                let value = self.condition(insn.condition) as u64;
+                self.write_operand(map, &dst, value)?;
+            }
+            Mnemonic::Cmovcc => {
+                // The source is read (and can fault) whether or not the move happens
+                let value = self.read_operand(map, &src)?;
+                if self.condition(insn.condition) {
+                    self.write_operand(map, &dst, value)?;
+                } else if insn.operand_size == OperandSize::R32 {
+                    // A 32-bit CMOV always writes its destination, zeroing the upper half
+                    let value = self.read_operand(map, &dst)?;
+                    self.write_operand(map, &dst, value)?;
+                }
+            }
+            Mnemonic::Test => {
+                let result = self.read_operand(map, &dst)? & self.read_operand(map, &src)?;
+                flags::logic(result, dst.size().unwrap(), &mut self.regs.rflags);
+            }
+            _ => unreachable!("{} is not a control-flow instruction", insn.mnemonic),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! Data movement: MOV and its extending and byte-swapping forms, XCHG, the sign-extension
+//! conversions, and the stack (PUSH/POP, PUSHA/POPA, ENTER/LEAVE, PUSHF/POPF) and flag
+//! transfers.
+
+use super::descriptor::Access;
+use super::{flags, segment, Amd64Interp};
+use crate::amd64::decode::{Instruction, MemoryOperand, Mnemonic, Operand, OperandSize};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+/// The RFLAGS bits POPF can change at CPL 0. Below that IOPL stays as it is, and so does IF
+/// unless CPL <= IOPL.
+const POPF_MASK: u64 = flags::STATUS
+    | flags::TF
+    | flags::IF
+    | flags::DF
+    | flags::IOPL
+    | flags::NT
+    | flags::AC
+    | flags::ID;
+
+impl Amd64Interp {
+    pub(super) fn execute_data(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let dst = insn.operand(0);
+        let src = insn.operand(1);
+        let size = insn.operand_size;
+        match insn.mnemonic {
+            Mnemonic::Mov | Mnemonic::Movnti | Mnemonic::Movzx => {
+                let value = self.read_operand(map, &src)?;
+                self.write_operand(map, &dst, value)?;
+            }
+            Mnemonic::Movsx | Mnemonic::Movsxd => {
+                let value = self.read_operand(map, &src)?;
+                let value = flags::sign_extend(value, src.size().unwrap());
+                self.write_operand(map, &dst, value)?;
+            }
+            Mnemonic::Movbe => {
+                let value = self.read_operand(map, &src)?;
+                let value = value.swap_bytes() >> (64 - size.bits());
+                self.write_operand(map, &dst, value)?;
+            }
+            Mnemonic::Bswap => {
+                let value = self.read_operand(map, &dst)?;
+                let value = match size {
+                    // Undefined by the SDM; real processors clear the register
+                    OperandSize::R16 => 0,
+                    _ => value.swap_bytes() >> (64 - size.bits()),
+                };
+                self.write_operand(map, &dst, value)?;
+            }
+            Mnemonic::Xchg => {
+                let a = self.read_operand(map, &dst)?;
+                let b = self.read_operand(map, &src)?;
+                // Write the memory side first so a fault leaves the register untouched
+                if let Operand::Memory(..) = src {
+                    self.write_operand(map, &src, a)?;
+                    self.write_operand(map, &dst, b)?;
+                } else {
+                    self.write_operand(map, &dst, b)?;
+                    self.write_operand(map, &src, a)?;
+                }
+            }
+            Mnemonic::Lea => match src {
+                Operand::Memory(mem, _) => {
+                    // The offset alone, without a segment base
+                    let offset = self.address_offset(&mem);
+                    self.write_operand(map, &dst, offset)?;
+                }
+                _ => unreachable!("LEA always has a memory source"),
+            },
+            Mnemonic::Xlat => {
+                let table = MemoryOperand {
+                    base: Some(3),
+                    index: None,
+                    scale: 1,
+                    displacement: self.read_gpr(0, OperandSize::R8) as i64,
+                    rip_relative: false,
+                    address_size: insn.address_size,
+                    vector_index: None,
+                    segment: insn.segment,
+                };
+                let value = self.read_operand(map, &Operand::Memory(table, OperandSize::R8))?;
+                self.write_gpr(0, OperandSize::R8, value);
+            }
+            Mnemonic::Cbw | Mnemonic::Cwde | Mnemonic::Cdqe => {
+                let half = match size {
+                    OperandSize::R16 => OperandSize::R8,
+                    OperandSize::R32 => OperandSize::R16,
+                    _ => OperandSize::R32,
+                };
+                let value = flags::sign_extend(self.read_gpr(0, half), half);
+                self.write_gpr(0, size, value);
+            }
+            Mnemonic::Cwd | Mnemonic::Cdq | Mnemonic::Cqo => {
+                let negative = self.read_gpr(0, size) >> (size.bits() - 1) != 0;
+                self.write_gpr(2, size, if negative { u64::MAX } else { 0 });
+            }
+            Mnemonic::Push => {
+                let value = self.read_operand(map, &dst)?;
+                self.push(map, value, size)?;
+            }
+            Mnemonic::Pop => {
+                let rsp = self.regs.gprs[4];
+                let value = self.pop(map, size)?;
+                // A memory destination is addressed with the incremented rSP
+                self.write_operand(map, &dst, value)
+                    .inspect_err(|_| self.regs.gprs[4] = rsp)?;
+            }
+            Mnemonic::Enter => {
+                let (rsp, rbp) = (self.regs.gprs[4], self.regs.gprs[5]);
+                self.enter(map, insn)
+                    .inspect_err(|_| (self.regs.gprs[4], self.regs.gprs[5]) = (rsp, rbp))?;
+            }
+            Mnemonic::Leave => {
+                let rbp = self.read_gpr(5, self.stack_size());
+                self.check_access(segment::SS, rbp, size.bytes(), Access::Read)?;
+                let value = self.read_memory(map, self.linear(segment::SS, rbp), size)?;
+                self.move_stack_pointer(rbp.wrapping_add(size.bytes()));
+                self.write_gpr(5, size, value);
+            }
+            Mnemonic::Pusha => {
+                let rsp = self.regs.gprs[4];
+                self.pusha(map, size)
+                    .inspect_err(|_| self.regs.gprs[4] = rsp)?;
+            }
+            Mnemonic::Popa => {
+                let gprs = self.regs.gprs;
+                self.popa(map, size)
+                    .inspect_err(|_| self.regs.gprs = gprs)?;
+            }
+            Mnemonic::Pushf => {
+                // VM and RF always read as 0 in the pushed image
+                let value = self.regs.rflags & !(flags::RF | flags::VM);
+                self.push(map, value, size)?;
+            }
+            Mnemonic::Popf => {
+                let value = self.pop(map, size)?;
+                let mask = self.writable_flags(POPF_MASK) & size.mask();
+                self.regs.rflags = (self.regs.rflags & !mask) | (value & mask);
+            }
+            Mnemonic::Lahf => {
+                let value = self.regs.rflags & (flags::STATUS & !flags::OF) | flags::RESERVED;
+                let rax = &mut self.regs.gprs[0];
+                *rax = (*rax & !0xFF00) | (value & 0xFF) << 8;
+            }
+            Mnemonic::Sahf => {
+                let mask = flags::STATUS & !flags::OF;
+                let value = self.regs.gprs[0] >> 8;
+                self.regs.rflags = (self.regs.rflags & !mask) | (value & mask);
+            }
+            mnemonic => unreachable!("{} is not a data movement instruction", mnemonic),
+        }
+        Ok(())
+    }
+
+    /// ENTER: pushes rBP, copies `level - 1` outer frame pointers for a nested procedure,
+    /// then allocates `alloc` bytes of locals.
+    fn enter(&mut self, map: &mut dyn MemoryMap, insn: &Instruction) -> Result<(), Exception> {
+        let size = insn.operand_size;
+        let stack_size = self.stack_size();
+        let alloc = self.read_operand(map, &insn.operand(0))?;
+        let level = self.read_operand(map, &insn.operand(1))? & 0x1F;
+        self.push(map, self.regs.gprs[5], size)?;
+        let frame = self.read_gpr(4, stack_size);
+        if level > 0 {
+            let mut rbp = self.read_gpr(5, stack_size);
+            for _ in 1..level {
+                rbp = rbp.wrapping_sub(size.bytes()) & stack_size.mask();
+                self.check_access(segment::SS, rbp, size.bytes(), Access::Read)?;
+                let outer = self.read_memory(map, self.linear(segment::SS, rbp), size)?;
+                self.push(map, outer, size)?;
+            }
+            self.push(map, frame, size)?;
+        }
+        self.write_gpr(5, size, frame);
+        let rsp = self.read_gpr(4, stack_size);
+        self.move_stack_pointer(rsp.wrapping_sub(alloc));
+        Ok(())
+    }
+
+    /// PUSHA: pushes the eight general-purpose registers in encoding order, with the stack
+    /// pointer as it was before the first push.
+    fn pusha(&mut self, map: &mut dyn MemoryMap, size: OperandSize) -> Result<(), Exception> {
+        let registers = self.regs.gprs;
+        for value in &registers[..8] {
+            self.push(map, *value, size)?;
+        }
+        Ok(())
+    }
+
+    /// POPA: pops the registers PUSHA pushed, discarding the stack pointer's.
+    fn popa(&mut self, map: &mut dyn MemoryMap, size: OperandSize) -> Result<(), Exception> {
+        for n in (0..8).rev() {
+            let value = self.pop(map, size)?;
+            if n != 4 {
+                self.write_gpr(n, size, value);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,483 @@
+//! Protected-mode segmentation: the descriptor tables (GDT, LDT and IDT) and the task
+//! register, the segment and gate descriptors they hold, the hidden descriptor caches behind
+//! the segment registers, and the type, limit and privilege checks made against them.
+
+use super::segment::{CS, DS, ES, FS, GS, SS};
+use super::system::EFER_LMA;
+use super::Amd64Interp;
+use crate::amd64::decode::{Mode, OperandSize};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+// Descriptor attributes: the access byte and the flags nibble of a descriptor's upper dword
+// (bits 40-47 and 52-55), packed together
+pub(super) const ACCESSED: u32 = 1 << 0;
+/// Writable for a data segment, readable for a code segment
+pub(super) const WRITABLE: u32 = 1 << 1;
+pub(super) const READABLE: u32 = 1 << 1;
+/// Expand-down for a data segment, conforming for a code segment
+pub(super) const EXPAND_DOWN: u32 = 1 << 2;
+pub(super) const CONFORMING: u32 = 1 << 2;
+pub(super) const CODE: u32 = 1 << 3;
+/// A code or data segment rather than a system descriptor
+pub(super) const SEGMENT: u32 = 1 << 4;
+pub(super) const PRESENT: u32 = 1 << 7;
+pub(super) const LONG: u32 = 1 << 13;
+/// The D/B bit: 32-bit code, a 32-bit stack pointer, or a 4 GiB expand-down segment
+pub(super) const BIG: u32 = 1 << 14;
+pub(super) const GRANULARITY: u32 = 1 << 15;
+/// Not in any descriptor: marks a segment register loaded with a null selector
+pub(super) const UNUSABLE: u32 = 1 << 16;
+
+// System descriptor and gate types. The 32-bit forms (which are also long mode's) have
+// bit 3 set; a TSS's bit 1 marks it busy.
+pub(super) const TSS16: u32 = 0x1;
+pub(super) const LDT: u32 = 0x2;
+pub(super) const TSS_BUSY: u32 = 0x2;
+pub(super) const CALL_GATE16: u32 = 0x4;
+pub(super) const INTERRUPT_GATE16: u32 = 0x6;
+pub(super) const TRAP_GATE16: u32 = 0x7;
+pub(super) const TSS: u32 = 0x9;
+pub(super) const CALL_GATE: u32 = 0xC;
+pub(super) const INTERRUPT_GATE: u32 = 0xE;
+pub(super) const TRAP_GATE: u32 = 0xF;
+
+/// A segment descriptor, decoded: what a segment register's hidden part caches when its
+/// selector is loaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Descriptor {
+    pub base: u64,
+    /// The last valid offset, already scaled by the granularity bit
+    pub limit: u32,
+    pub attributes: u32,
+}
+
+impl Descriptor {
+    /// Decodes an 8-byte descriptor. Long mode's 16-byte system descriptors keep the upper
+    /// half of their base in the next 8 bytes, `upper`; it's 0 for the others.
+    pub fn from_raw(raw: u64, upper: u64) -> Descriptor {
+        let attributes = ((raw >> 40) & 0xF0FF) as u32;
+        let limit = (raw & 0xFFFF) as u32 | ((raw >> 32) as u32 & 0xF_0000);
+        Descriptor {
+            base: ((raw >> 16) & 0xFF_FFFF) | ((raw >> 32) & 0xFF00_0000) | (upper << 32),
+            limit: if attributes & GRANULARITY != 0 {
+                (limit << 12) | 0xFFF
+            } else {
+                limit
+            },
+            attributes,
+        }
+    }
+
+    /// A 64K segment as real mode leaves it after reset, at `selector` * 16.
+    pub const fn real_mode(selector: u16, attributes: u32) -> Descriptor {
+        Descriptor {
+            base: (selector as u64) << 4,
+            limit: 0xFFFF,
+            attributes: attributes | SEGMENT | PRESENT | ACCESSED,
+        }
+    }
+
+    /// What a segment register holds after a null selector is loaded into it.
+    pub const fn unusable() -> Descriptor {
+        Descriptor {
+            base: 0,
+            limit: 0,
+            attributes: UNUSABLE,
+        }
+    }
+
+    /// The type field: the low four bits of the attributes.
+    pub fn kind(&self) -> u32 {
+        self.attributes & 0xF
+    }
+
+    pub fn dpl(&self) -> u8 {
+        ((self.attributes >> 5) & 3) as u8
+    }
+
+    /// Whether all of `bits` are set in the attributes.
+    pub fn has(&self, bits: u32) -> bool {
+        self.attributes & bits == bits
+    }
+
+    pub fn is_code(&self) -> bool {
+        self.has(SEGMENT | CODE)
+    }
+
+    pub fn is_data(&self) -> bool {
+        self.has(SEGMENT) && !self.has(CODE)
+    }
+
+    /// A system descriptor (a gate, the LDT or a TSS) of type `kind`.
+    pub fn is_system(&self, kind: u32) -> bool {
+        !self.has(SEGMENT) && self.kind() == kind
+    }
+}
+
+/// A call, interrupt or trap gate: an entry point into a code segment that may be more
+/// privileged than the caller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Gate {
+    pub selector: u16,
+    pub offset: u64,
+    /// How many stack parameters a call gate copies to an inner privilege level's stack
+    pub params: u8,
+    /// The interrupt stack table entry a long-mode interrupt gate switches to, if nonzero
+    pub ist: u8,
+    pub attributes: u32,
+}
+
+impl Gate {
+    /// Decodes a gate. `upper` is the second half of a long-mode gate, or 0.
+    pub fn from_raw(raw: u64, upper: u64) -> Gate {
+        Gate {
+            selector: (raw >> 16) as u16,
+            offset: (raw & 0xFFFF) | ((raw >> 32) & 0xFFFF_0000) | ((upper & 0xFFFF_FFFF) << 32),
+            params: ((raw >> 32) & 0x1F) as u8,
+            ist: ((raw >> 32) & 0x7) as u8,
+            attributes: ((raw >> 40) & 0xFF) as u32,
+        }
+    }
+
+    pub fn kind(&self) -> u32 {
+        self.attributes & 0xF
+    }
+
+    pub fn dpl(&self) -> u8 {
+        ((self.attributes >> 5) & 3) as u8
+    }
+
+    pub fn present(&self) -> bool {
+        self.attributes & PRESENT != 0
+    }
+
+    /// The size of the values a transfer through the gate pushes: 16-bit gates push words
+    /// and 32-bit ones dwords, while long mode's gates are all 64-bit.
+    pub fn size(&self, long_mode: bool) -> OperandSize {
+        match (long_mode, self.kind() & 0x8 != 0) {
+            (true, _) => OperandSize::R64,
+            (false, true) => OperandSize::R32,
+            (false, false) => OperandSize::R16,
+        }
+    }
+}
+
+/// The GDTR or IDTR: the linear base address and limit of a descriptor table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TableRegister {
+    pub base: u64,
+    pub limit: u16,
+}
+
+/// The descriptor table registers and the task register.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Tables {
+    pub gdtr: TableRegister,
+    pub idtr: TableRegister,
+    /// The LDT's selector and its cached descriptor, which isn't present without one
+    pub ldtr: u16,
+    pub ldt: Descriptor,
+    /// The current task's TSS selector and its cached descriptor
+    pub tr: u16,
+    pub tss: Descriptor,
+}
+
+/// The GDT entries Linux gives user code: 32-bit code at 0x23, data at 0x2B and 64-bit
+/// code at 0x33, all at privilege level 3, and the per-CPU segment at 0x7B whose limit is
+/// the CPU number (0, here) for LSL to read.
+pub(super) fn linux_user_descriptor(selector: u16) -> Option<u64> {
+    match selector >> 3 {
+        4 => Some(0x00CF_FB00_0000_FFFF),
+        5 => Some(0x00CF_F300_0000_FFFF),
+        6 => Some(0x00AF_FB00_0000_FFFF),
+        15 => Some(0x0040_F500_0000_0000),
+        _ => None,
+    }
+}
+
+/// Whether an access reads, writes or fetches instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Amd64Interp {
+    /// Whether long mode is active (EFER.LMA).
+    pub(super) fn long_mode(&self) -> bool {
+        self.efer & EFER_LMA != 0
+    }
+
+    /// Sets the mode instructions are decoded in from CS's descriptor: 64-bit for a long
+    /// mode code segment with L set, and otherwise 32- or 16-bit by its D bit.
+    pub(super) fn update_mode(&mut self) {
+        let cs = self.segments[CS];
+        self.mode = if self.long_mode() && cs.has(LONG) {
+            Mode::Bits64
+        } else if cs.has(BIG) {
+            Mode::Bits32
+        } else {
+            Mode::Bits16
+        };
+    }
+
+    /// Sets segment register `sreg` and its hidden part.
+    pub(super) fn set_segment(&mut self, sreg: usize, selector: u16, descriptor: Descriptor) {
+        self.regs.sr[sreg] = selector;
+        self.regs.sr_base[sreg] = descriptor.base;
+        self.segments[sreg] = descriptor;
+        if sreg == CS {
+            self.update_mode();
+        }
+    }
+
+    /// The linear address of the `bytes`-byte descriptor `selector` indexes, in the LDT if
+    /// its TI bit is set and the GDT otherwise. Raises #GP(selector) past the table's end.
+    fn descriptor_address(&self, selector: u16, bytes: u64) -> Result<u64, Exception> {
+        let fault = Exception::GeneralProtection(selector & !3);
+        let (base, limit) = if selector & 4 != 0 {
+            if !self.tables.ldt.has(PRESENT) {
+                return Err(fault);
+            }
+            (self.tables.ldt.base, self.tables.ldt.limit as u64)
+        } else {
+            (self.tables.gdtr.base, self.tables.gdtr.limit as u64)
+        };
+        let offset = (selector & !7) as u64;
+        if offset + bytes - 1 > limit {
+            return Err(fault);
+        }
+        Ok(base.wrapping_add(offset))
+    }
+
+    /// Reads the descriptor `selector` indexes: 8 bytes, and another 8 for `wide`, long
+    /// mode's system descriptors and gates. Returns both halves.
+    pub(super) fn read_descriptor(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        selector: u16,
+        wide: bool,
+    ) -> Result<(u64, u64), Exception> {
+        if self.linux_user {
+            return linux_user_descriptor(selector)
+                .map(|raw| (raw, 0))
+                .ok_or(Exception::GeneralProtection(selector & !3));
+        }
+        let addr = self.descriptor_address(selector, if wide { 16 } else { 8 })?;
+        let raw = self.read_system(map, addr, OperandSize::R64)?;
+        let upper = match wide {
+            true => self.read_system(map, addr + 8, OperandSize::R64)?,
+            false => 0,
+        };
+        Ok((raw, upper))
+    }
+
+    /// Sets the accessed bit (or, for a TSS, the busy bit) of the descriptor `selector`
+    /// indexes, as loading it does.
+    pub(super) fn mark_descriptor(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        selector: u16,
+        bit: u32,
+    ) -> Result<(), Exception> {
+        if self.linux_user {
+            return Ok(());
+        }
+        let addr = self.descriptor_address(selector, 8)? + 5;
+        let access = self.read_system(map, addr, OperandSize::R8)?;
+        if access & bit as u64 == 0 {
+            self.write_system(map, addr, OperandSize::R8, access | bit as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that `selector` may be loaded into data or stack segment register `sreg` at
+    /// privilege level `cpl`, and returns its descriptor. A null selector gives an unusable
+    /// segment, except in SS outside 64-bit mode. Failed checks raise #GP with the selector,
+    /// or #SS for a stack segment that isn't present and #NP for any other.
+    pub(super) fn check_segment_load(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        sreg: usize,
+        selector: u16,
+        cpl: u8,
+    ) -> Result<Descriptor, Exception> {
+        if selector & !3 == 0 {
+            // 64-bit mode's inner privilege levels may run on a null SS
+            if sreg == SS && (self.mode != Mode::Bits64 || cpl == 3 || selector & 3 != cpl as u16) {
+                return Err(Exception::GeneralProtection(0));
+            }
+            return Ok(Descriptor::unusable());
+        }
+        let error = selector & !3;
+        let rpl = (selector & 3) as u8;
+        let descriptor = Descriptor::from_raw(self.read_descriptor(map, selector, false)?.0, 0);
+        let dpl = descriptor.dpl();
+        if sreg == SS {
+            if rpl != cpl || !descriptor.is_data() || !descriptor.has(WRITABLE) || dpl != cpl {
+                return Err(Exception::GeneralProtection(error));
+            }
+            if !descriptor.has(PRESENT) {
+                return Err(Exception::StackFault(error));
+            }
+        } else {
+            let readable = descriptor.is_data() || descriptor.has(SEGMENT | CODE | READABLE);
+            // Conforming code may be read from any privilege level
+            let conforming = descriptor.has(SEGMENT | CODE | CONFORMING);
+            if !readable || (!conforming && (rpl > dpl || cpl > dpl)) {
+                return Err(Exception::GeneralProtection(error));
+            }
+            if !descriptor.has(PRESENT) {
+                return Err(Exception::SegmentNotPresent(error));
+            }
+        }
+        self.mark_descriptor(map, selector, ACCESSED)?;
+        Ok(Descriptor {
+            attributes: descriptor.attributes | ACCESSED,
+            ..descriptor
+        })
+    }
+
+    /// Reads the code segment descriptor a far transfer targets. Raises #GP(0) for a null
+    /// selector, #GP(selector) if it isn't a code segment and #NP(selector) if it isn't
+    /// present; the privilege checks are the caller's.
+    pub(super) fn code_descriptor(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        selector: u16,
+    ) -> Result<Descriptor, Exception> {
+        if selector & !3 == 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let descriptor = Descriptor::from_raw(self.read_descriptor(map, selector, false)?.0, 0);
+        if !descriptor.is_code() {
+            return Err(Exception::GeneralProtection(selector & !3));
+        }
+        if !descriptor.has(PRESENT) {
+            return Err(Exception::SegmentNotPresent(selector & !3));
+        }
+        self.mark_descriptor(map, selector, ACCESSED)?;
+        Ok(Descriptor {
+            attributes: descriptor.attributes | ACCESSED,
+            ..descriptor
+        })
+    }
+
+    /// Enters code segment `selector` at privilege level `cpl`, which becomes the RPL of CS.
+    pub(super) fn enter_code_segment(&mut self, selector: u16, descriptor: Descriptor, cpl: u8) {
+        self.set_segment(CS, (selector & !3) | cpl as u16, descriptor);
+    }
+
+    /// After a return to an outer privilege level, nulls the data segment registers the new
+    /// level couldn't have loaded, so it can't use them to reach the inner level's data.
+    pub(super) fn clear_inner_segments(&mut self) {
+        let cpl = self.cpl();
+        for sreg in [ES, DS, FS, GS] {
+            let segment = self.segments[sreg];
+            let conforming = segment.has(SEGMENT | CODE | CONFORMING);
+            if !segment.has(UNUSABLE) && !conforming && segment.dpl() < cpl {
+                self.set_segment(sreg, 0, Descriptor::unusable());
+            }
+        }
+    }
+
+    /// The stack a transfer to inner privilege level `dpl` switches to, from the current
+    /// TSS: its SS selector and descriptor and its stack pointer. Long mode's TSS has no SS,
+    /// so that's a null selector, and `ist` picks one of its interrupt stacks instead if
+    /// nonzero. Raises #TS if the TSS is too short or its SS can't be loaded.
+    pub(super) fn inner_stack(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        dpl: u8,
+        ist: u8,
+    ) -> Result<(u16, Descriptor, u64), Exception> {
+        let tss = self.tables.tss;
+        let fault = Exception::InvalidTss(self.tables.tr & !3);
+        let (offset, size, ss_offset) = if self.long_mode() {
+            match ist {
+                0 => (4 + 8 * dpl as u64, OperandSize::R64, None),
+                _ => (0x1C + 8 * ist as u64, OperandSize::R64, None),
+            }
+        } else if tss.kind() & 0x8 != 0 {
+            (
+                4 + 8 * dpl as u64,
+                OperandSize::R32,
+                Some(8 + 8 * dpl as u64),
+            )
+        } else {
+            (
+                2 + 4 * dpl as u64,
+                OperandSize::R16,
+                Some(4 + 4 * dpl as u64),
+            )
+        };
+        let end = (offset + size.bytes()).max(ss_offset.map_or(0, |ss_offset| ss_offset + 2));
+        if !tss.has(PRESENT) || end - 1 > tss.limit as u64 {
+            return Err(fault);
+        }
+        let sp = self.read_system(map, tss.base + offset, size)?;
+        let Some(ss_offset) = ss_offset else {
+            return Ok((dpl as u16, Descriptor::unusable(), sp));
+        };
+        let ss = self.read_system(map, tss.base + ss_offset, OperandSize::R16)? as u16;
+        let descriptor = self
+            .check_segment_load(map, SS, ss, dpl)
+            .map_err(|e| match e {
+                Exception::StackFault(_) => e,
+                _ => Exception::InvalidTss(ss & !3),
+            })?;
+        Ok((ss, descriptor, sp))
+    }
+
+    /// Checks an access of `bytes` bytes at `offset` in segment `sreg` against its cached
+    /// descriptor: its limit and, in protected mode, its type. Raises #GP(0), or #SS(0) for
+    /// the stack segment. 64-bit mode doesn't check segment limits.
+    pub(super) fn check_access(
+        &self,
+        sreg: usize,
+        offset: u64,
+        bytes: u64,
+        access: Access,
+    ) -> Result<(), Exception> {
+        if self.mode == Mode::Bits64 {
+            return Ok(());
+        }
+        let fault = match sreg {
+            SS => Exception::StackFault(0),
+            _ => Exception::GeneralProtection(0),
+        };
+        let segment = &self.segments[sreg];
+        if segment.has(UNUSABLE) {
+            return Err(fault);
+        }
+        if !self.real_mode() {
+            let allowed = match access {
+                Access::Read => segment.is_data() || segment.has(READABLE),
+                Access::Write => segment.is_data() && segment.has(WRITABLE),
+                Access::Execute => segment.is_code(),
+            };
+            if !allowed {
+                return Err(fault);
+            }
+        }
+        let last = offset.wrapping_add(bytes - 1);
+        let limit = segment.limit as u64;
+        let within = if segment.is_data() && segment.has(EXPAND_DOWN) {
+            // Valid offsets are the ones above the limit
+            let upper = if segment.has(BIG) {
+                0xFFFF_FFFF
+            } else {
+                0xFFFF
+            };
+            offset > limit && last <= upper
+        } else {
+            last <= limit
+        };
+        if !within {
+            return Err(fault);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,462 @@
+//! RFLAGS bits and the status-flag results of integer instructions.
+//!
+//! Each helper takes its operands at the given operand size, returns the (masked) result
+//! and updates only the status flags the instruction defines in `rflags`, leaving the
+//! system and control bits (TF, IF, DF, ...) alone.
+//!
+//! Flags the SDM leaves undefined are set the way Intel processors (and Bochs, which
+//! models them) do: AF is cleared by logical operations, shifts and multiplies, SF, ZF
+//! and PF of a multiply reflect the low half of the product, and OF of a multi-bit shift
+//! or rotate follows the same formula as the single-bit case.
+
+use crate::amd64::decode::OperandSize;
+
+pub const CF: u64 = 1 << 0;
+/// Bit 1 of RFLAGS is reserved and always reads as 1
+pub const RESERVED: u64 = 1 << 1;
+pub const PF: u64 = 1 << 2;
+pub const AF: u64 = 1 << 4;
+pub const ZF: u64 = 1 << 6;
+pub const SF: u64 = 1 << 7;
+pub const TF: u64 = 1 << 8;
+pub const IF: u64 = 1 << 9;
+pub const DF: u64 = 1 << 10;
+pub const OF: u64 = 1 << 11;
+/// I/O privilege level, a two-bit field
+pub const IOPL: u64 = 3 << 12;
+pub const NT: u64 = 1 << 14;
+/// Resume flag: suppresses instruction breakpoints for one instruction
+pub const RF: u64 = 1 << 16;
+/// Virtual-8086 mode
+pub const VM: u64 = 1 << 17;
+pub const AC: u64 = 1 << 18;
+pub const ID: u64 = 1 << 21;
+
+/// The arithmetic status flags
+pub const STATUS: u64 = CF | PF | AF | ZF | SF | OF;
+
+fn sign_bit(size: OperandSize) -> u64 {
+    1 << (size.bits() - 1)
+}
+
+fn flag(set: bool, bit: u64) -> u64 {
+    if set {
+        bit
+    } else {
+        0
+    }
+}
+
+/// PF is set when the low byte of the result has an even number of set bits.
+pub fn parity(result: u64) -> u64 {
+    flag((result as u8).count_ones().is_multiple_of(2), PF)
+}
+
+/// The SF, ZF and PF bits describing `result`.
+pub fn szp(result: u64, size: OperandSize) -> u64 {
+    let result = result & size.mask();
+    flag(result & sign_bit(size) != 0, SF) | flag(result == 0, ZF) | parity(result)
+}
+
+fn update(rflags: &mut u64, mask: u64, flags: u64) {
+    *rflags = (*rflags & !mask) | (flags & mask);
+}
+
+/// ADD and ADC.
+pub fn add(a: u64, b: u64, carry: bool, size: OperandSize, rflags: &mut u64) -> u64 {
+    let (a, b) = (a & size.mask(), b & size.mask());
+    let full = a as u128 + b as u128 + carry as u128;
+    let result = full as u64 & size.mask();
+    let flags = flag(full > size.mask() as u128, CF)
+        | ((a ^ b ^ result) & AF)
+        | flag((a ^ result) & (b ^ result) & sign_bit(size) != 0, OF)
+        | szp(result, size);
+    update(rflags, STATUS, flags);
+    result
+}
+
+/// SUB, SBB, CMP and NEG (as `0 - a`).
+pub fn sub(a: u64, b: u64, borrow: bool, size: OperandSize, rflags: &mut u64) -> u64 {
+    let (a, b) = (a & size.mask(), b & size.mask());
+    let result = a.wrapping_sub(b).wrapping_sub(borrow as u64) & size.mask();
+    let flags = flag((a as u128) < b as u128 + borrow as u128, CF)
+        | ((a ^ b ^ result) & AF)
+        | flag((a ^ b) & (a ^ result) & sign_bit(size) != 0, OF)
+        | szp(result, size);
+    update(rflags, STATUS, flags);
+    result
+}
+
+/// AND, OR, XOR and TEST: CF and OF are cleared.
+pub fn logic(result: u64, size: OperandSize, rflags: &mut u64) -> u64 {
+    let result = result & size.mask();
+    update(rflags, STATUS, szp(result, size));
+    result
+}
+
+/// INC, which leaves CF alone.
+pub fn inc(a: u64, size: OperandSize, rflags: &mut u64) -> u64 {
+    let a = a & size.mask();
+    let result = a.wrapping_add(1) & size.mask();
+    let flags = ((a ^ result) & AF) | flag(result == sign_bit(size), OF) | szp(result, size);
+    update(rflags, STATUS & !CF, flags);
+    result
+}
+
+/// DEC, which leaves CF alone.
+pub fn dec(a: u64, size: OperandSize, rflags: &mut u64) -> u64 {
+    let a = a & size.mask();
+    let result = a.wrapping_sub(1) & size.mask();
+    let flags = ((a ^ result) & AF) | flag(a == sign_bit(size), OF) | szp(result, size);
+    update(rflags, STATUS & !CF, flags);
+    result
+}
+
+/// Masks a shift or rotate count the way the processor does: to 6 bits for 64-bit
+/// operands and 5 bits otherwise.
+pub fn mask_count(count: u64, size: OperandSize) -> u32 {
+    if size == OperandSize::R64 {
+        (count & 0x3F) as u32
+    } else {
+        (count & 0x1F) as u32
+    }
+}
+
+/// Returns bit `n` of `value`, or 0 when `n` lies beyond the 64-bit value.
+fn bit(value: u64, n: u32) -> u64 {
+    value.checked_shr(n).unwrap_or(0) & 1
+}
+
+fn shift_flags(result: u64, cf: u64, of: bool, size: OperandSize, rflags: &mut u64) {
+    update(
+        rflags,
+        STATUS,
+        flag(cf != 0, CF) | flag(of, OF) | szp(result, size),
+    );
+}
+
+/// SHL/SAL by an already-masked count. A zero count leaves the flags untouched.
+pub fn shl(a: u64, count: u32, size: OperandSize, rflags: &mut u64) -> u64 {
+    let a = a & size.mask();
+    if count == 0 {
+        return a;
+    }
+    let result = a.checked_shl(count).unwrap_or(0) & size.mask();
+    let cf = if count <= size.bits() {
+        bit(a, size.bits() - count)
+    } else {
+        0
+    };
+    let msb = result & sign_bit(size) != 0;
+    shift_flags(result, cf, msb ^ (cf != 0), size, rflags);
+    result
+}
+
+/// SHR by an already-masked count. A zero count leaves the flags untouched.
+pub fn shr(a: u64, count: u32, size: OperandSize, rflags: &mut u64) -> u64 {
+    let a = a & size.mask();
+    if count == 0 {
+        return a;
+    }
+    let result = a.checked_shr(count).unwrap_or(0);
+    let cf = bit(a, count - 1);
+    // The SDM defines OF as the original sign bit for a count of 1; that is the XOR of
+    // the result's two top bits, which is what Intel reports for larger counts too
+    let of = (result ^ (result << 1)) & sign_bit(size) != 0;
+    shift_flags(result, cf, of, size, rflags);
+    result
+}
+
+/// SAR by an already-masked count. A zero count leaves the flags untouched.
+pub fn sar(a: u64, count: u32, size: OperandSize, rflags: &mut u64) -> u64 {
+    let a = a & size.mask();
+    if count == 0 {
+        return a;
+    }
+    let signed = sign_extend(a, size) as i64;
+    let result = (signed >> count.min(63)) as u64 & size.mask();
+    let cf = (signed >> (count - 1).min(63)) as u64 & 1;
+    shift_flags(result, cf, false, size, rflags);
+    result
+}
+
+fn rotate_flags(cf: bool, of: bool, rflags: &mut u64) {
+    update(rflags, CF | OF, flag(cf, CF) | flag(of, OF));
+}
+
+/// ROL by an already-masked count. Only CF and OF are affected, and only when the masked
+/// count is non-zero.
+pub fn rol(a: u64, count: u32, size: OperandSize, rflags: &mut u64) -> u64 {
+    let a = a & size.mask();
+    if count == 0 {
+        return a;
+    }
+    let n = count % size.bits();
+    let result = if n == 0 {
+        a
+    } else {
+        ((a << n) | (a >> (size.bits() - n))) & size.mask()
+    };
+    let cf = result & 1 != 0;
+    rotate_flags(cf, (result & sign_bit(size) != 0) ^ cf, rflags);
+    result
+}
+
+/// ROR by an already-masked count. Only CF and OF are affected, and only when the masked
+/// count is non-zero.
+pub fn ror(a: u64, count: u32, size: OperandSize, rflags: &mut u64) -> u64 {
+    let a = a & size.mask();
+    if count == 0 {
+        return a;
+    }
+    let n = count % size.bits();
+    let result = if n == 0 {
+        a
+    } else {
+        ((a >> n) | (a << (size.bits() - n))) & size.mask()
+    };
+    let cf = result & sign_bit(size) != 0;
+    rotate_flags(cf, (result ^ (result << 1)) & sign_bit(size) != 0, rflags);
+    result
+}
+
+/// RCL by an already-masked count, rotating through CF.
+pub fn rcl(a: u64, count: u32, size: OperandSize, rflags: &mut u64) -> u64 {
+    let a = a & size.mask();
+    let n = count % (size.bits() + 1);
+    if n == 0 {
+        return a;
+    }
+    // Rotate the (bits + 1)-wide value CF:a
+    let bits = size.bits() + 1;
+    let wide = ((*rflags & CF) as u128) << size.bits() | a as u128;
+    let wide_mask = (1u128 << bits) - 1;
+    let rotated = ((wide << n) | (wide >> (bits - n))) & wide_mask;
+    let result = rotated as u64 & size.mask();
+    let cf = (rotated >> size.bits()) & 1 != 0;
+    rotate_flags(cf, (result & sign_bit(size) != 0) ^ cf, rflags);
+    result
+}
+
+/// RCR by an already-masked count, rotating through CF.
+pub fn rcr(a: u64, count: u32, size: OperandSize, rflags: &mut u64) -> u64 {
+    let a = a & size.mask();
+    let n = count % (size.bits() + 1);
+    if n == 0 {
+        return a;
+    }
+    let bits = size.bits() + 1;
+    let wide = ((*rflags & CF) as u128) << size.bits() | a as u128;
+    let wide_mask = (1u128 << bits) - 1;
+    let rotated = ((wide >> n) | (wide << (bits - n))) & wide_mask;
+    let result = rotated as u64 & size.mask();
+    let cf = (rotated >> size.bits()) & 1 != 0;
+    rotate_flags(cf, (result ^ (result << 1)) & sign_bit(size) != 0, rflags);
+    result
+}
+
+/// Lays out the bits SHLD and SHRD shift through, returning them with their width. 16-bit
+/// operands get the hardware's 32-bit shifter behavior (bits of `a` re-enter after `fill`)
+/// for the counts above 16 that the SDM leaves undefined.
+fn double_shift_source(a: u64, fill: u64, left: bool, size: OperandSize) -> (u128, u32) {
+    let bits = size.bits();
+    let fill = fill & size.mask();
+    match size {
+        OperandSize::R8 | OperandSize::R16 => {
+            let wide = (a as u128) << (2 * bits) | (fill as u128) << bits | a as u128;
+            (wide, 3 * bits)
+        }
+        _ if left => ((a as u128) << bits | fill as u128, 2 * bits),
+        _ => ((fill as u128) << bits | a as u128, 2 * bits),
+    }
+}
+
+/// SHLD by an already-masked count, filling from the top of `fill`.
+pub fn shld(a: u64, fill: u64, count: u32, size: OperandSize, rflags: &mut u64) -> u64 {
+    let a = a & size.mask();
+    if count == 0 {
+        return a;
+    }
+    let (wide, width) = double_shift_source(a, fill, true, size);
+    let result = (wide >> (width - size.bits() - count)) as u64 & size.mask();
+    let cf = (wide >> (width - count)) as u64 & 1;
+    let of = (result & sign_bit(size) != 0) ^ (cf != 0);
+    shift_flags(result, cf, of, size, rflags);
+    result
+}
+
+/// SHRD by an already-masked count, filling from the bottom of `fill`.
+pub fn shrd(a: u64, fill: u64, count: u32, size: OperandSize, rflags: &mut u64) -> u64 {
+    let a = a & size.mask();
+    if count == 0 {
+        return a;
+    }
+    let (wide, _) = double_shift_source(a, fill, false, size);
+    let result = (wide >> count) as u64 & size.mask();
+    let cf = (wide >> (count - 1)) as u64 & 1;
+    let of = (result ^ (result << 1)) & sign_bit(size) != 0;
+    shift_flags(result, cf, of, size, rflags);
+    result
+}
+
+/// BT, BTS, BTR and BTC: CF is the selected bit and the other status flags are unchanged.
+pub fn bit_test(bit: bool, rflags: &mut u64) {
+    update(rflags, CF, flag(bit, CF));
+}
+
+/// BSF and BSR. A zero source only sets ZF; otherwise ZF is cleared and the remaining flags
+/// describe the bit index as a logical result would.
+pub fn bit_scan(index: Option<u64>, size: OperandSize, rflags: &mut u64) {
+    match index {
+        Some(index) => update(rflags, STATUS, szp(index, size) & !ZF),
+        None => *rflags |= ZF,
+    }
+}
+
+/// LZCNT and TZCNT: CF reports a zero source and ZF a zero count.
+pub fn zero_count(count: u64, source_zero: bool, rflags: &mut u64) {
+    update(rflags, STATUS, flag(source_zero, CF) | flag(count == 0, ZF));
+}
+
+/// POPCNT: ZF reports a zero source and every other status flag is cleared.
+pub fn popcnt(source: u64, size: OperandSize, rflags: &mut u64) -> u64 {
+    let source = source & size.mask();
+    update(rflags, STATUS, flag(source == 0, ZF));
+    source.count_ones() as u64
+}
+
+/// ANDN, BEXTR, BLSI, BLSMSK, BLSR and BZHI: SF, ZF and PF describe the result, CF is
+/// instruction-specific and OF is cleared.
+pub fn bmi(result: u64, carry: bool, size: OperandSize, rflags: &mut u64) -> u64 {
+    let result = result & size.mask();
+    update(rflags, STATUS, szp(result, size) | flag(carry, CF));
+    result
+}
+
+/// Sign-extends a value of the given size to 64 bits.
+pub fn sign_extend(value: u64, size: OperandSize) -> u64 {
+    let shift = 64 - size.bits();
+    (((value << shift) as i64) >> shift) as u64
+}
+
+/// MUL and IMUL: CF and OF report that the product didn't fit in the low half.
+pub fn multiply(low: u64, overflow: bool, size: OperandSize, rflags: &mut u64) {
+    update(
+        rflags,
+        STATUS,
+        flag(overflow, CF) | flag(overflow, OF) | szp(low, size),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use OperandSize::*;
+
+    fn status(rflags: u64) -> u64 {
+        rflags & STATUS
+    }
+
+    #[test]
+    fn add_carry_overflow_and_adjust() {
+        let mut rflags = RESERVED | DF;
+        assert_eq!(add(0x7F, 1, false, R8, &mut rflags), 0x80);
+        assert_eq!(status(rflags), OF | SF | AF);
+        assert_eq!(rflags & DF, DF);
+
+        assert_eq!(add(0xFF, 1, false, R8, &mut rflags), 0);
+        assert_eq!(status(rflags), CF | ZF | PF | AF);
+
+        assert_eq!(add(u64::MAX, 0, true, R64, &mut rflags), 0);
+        assert_eq!(status(rflags), CF | ZF | PF | AF);
+    }
+
+    #[test]
+    fn sub_borrow_and_compare() {
+        let mut rflags = 0;
+        assert_eq!(sub(0, 1, false, R32, &mut rflags), 0xFFFF_FFFF);
+        assert_eq!(status(rflags), CF | SF | PF | AF);
+
+        assert_eq!(sub(0x8000, 1, false, R16, &mut rflags), 0x7FFF);
+        assert_eq!(status(rflags), OF | PF | AF);
+
+        assert_eq!(sub(5, 4, true, R64, &mut rflags), 0);
+        assert_eq!(status(rflags), ZF | PF);
+    }
+
+    #[test]
+    fn inc_dec_preserve_carry() {
+        let mut rflags = CF;
+        assert_eq!(inc(0x7FFF_FFFF, R32, &mut rflags), 0x8000_0000);
+        assert_eq!(status(rflags), CF | OF | SF | AF | PF);
+        assert_eq!(dec(1, R64, &mut rflags), 0);
+        assert_eq!(status(rflags), CF | ZF | PF);
+    }
+
+    #[test]
+    fn logic_clears_carry_and_overflow() {
+        let mut rflags = CF | OF | AF;
+        assert_eq!(logic(0x1_0000_0000, R32, &mut rflags), 0);
+        assert_eq!(status(rflags), ZF | PF);
+    }
+
+    #[test]
+    fn shifts() {
+        let mut rflags = 0;
+        assert_eq!(shl(0x81, 1, R8, &mut rflags), 0x02);
+        assert_eq!(status(rflags), CF | OF);
+
+        // A zero count doesn't touch flags
+        assert_eq!(shl(0x81, 0, R8, &mut rflags), 0x81);
+        assert_eq!(status(rflags), CF | OF);
+
+        assert_eq!(shr(0x81, 1, R8, &mut rflags), 0x40);
+        assert_eq!(status(rflags), CF | OF);
+
+        assert_eq!(sar(0x81, 1, R8, &mut rflags), 0xC0);
+        assert_eq!(status(rflags), CF | SF | PF);
+
+        // 8-bit operands can be shifted past their width
+        assert_eq!(shl(0xFF, 9, R8, &mut rflags), 0);
+        assert_eq!(status(rflags) & CF, 0);
+        assert_eq!(sar(0x80, 20, R8, &mut rflags), 0xFF);
+        assert_eq!(status(rflags) & CF, CF);
+    }
+
+    #[test]
+    fn rotates() {
+        let mut rflags = ZF;
+        assert_eq!(rol(0x80, 1, R8, &mut rflags), 0x01);
+        assert_eq!(status(rflags), ZF | CF | OF);
+
+        assert_eq!(ror(0x01, 1, R8, &mut rflags), 0x80);
+        assert_eq!(status(rflags), ZF | CF | OF);
+
+        let mut rflags = CF;
+        assert_eq!(rcl(0x80, 1, R8, &mut rflags), 0x01);
+        assert_eq!(status(rflags), CF | OF);
+        // RCL by 9 on a byte is a full turn through CF
+        assert_eq!(rcl(0x5A, 9, R8, &mut rflags), 0x5A);
+
+        let mut rflags = CF;
+        assert_eq!(rcr(0x01, 1, R8, &mut rflags), 0x80);
+        assert_eq!(status(rflags), CF | OF);
+    }
+
+    #[test]
+    fn double_precision_shifts() {
+        let mut rflags = 0;
+        assert_eq!(shld(0x1234, 0xABCD, 4, R16, &mut rflags), 0x234A);
+        assert_eq!(status(rflags) & CF, CF);
+        assert_eq!(shrd(0x1234, 0xABCD, 4, R16, &mut rflags), 0xD123);
+        assert_eq!(status(rflags) & CF, 0);
+        assert_eq!(
+            shld(0x8000_0000_0000_0000, 0x3 << 62, 2, R64, &mut rflags),
+            3
+        );
+        assert_eq!(status(rflags) & CF, 0);
+        assert_eq!(shrd(0x1, 0x3, 63, R64, &mut rflags), 0x6);
+        // Counts past 16 pull bits of the destination back in
+        assert_eq!(shld(0x1234, 0xABCD, 20, R16, &mut rflags), 0xBCD1);
+    }
+}
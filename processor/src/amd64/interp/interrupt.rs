@@ -0,0 +1,253 @@
+//! Interrupts: INT, INT3, INT1 and INTO, IRET, and the IF and HLT instructions that go with
+//! them, and the delivery of interrupts and exceptions. Real mode delivers them through the
+//! interrupt vector table, and protected and long mode through the IDT's interrupt and trap
+//! gates. A Linux user program has no IDT to deliver through, so its INTs fault instead.
+
+use super::descriptor::{
+    Gate, CONFORMING, INTERRUPT_GATE, INTERRUPT_GATE16, TRAP_GATE, TRAP_GATE16,
+};
+use super::segment::{CS, SS};
+use super::{flags, Amd64Interp};
+use crate::amd64::decode::{Instruction, Mnemonic, OperandSize};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+/// The RFLAGS bits IRET restores at CPL 0. Below that IOPL stays as it is, and so does IF
+/// unless CPL <= IOPL.
+const IRET_MASK: u64 = flags::STATUS
+    | flags::TF
+    | flags::IF
+    | flags::DF
+    | flags::IOPL
+    | flags::NT
+    | flags::AC
+    | flags::ID;
+
+impl Amd64Interp {
+    /// The I/O privilege level, which bounds the CPL that may change IF.
+    pub(super) fn iopl(&self) -> u8 {
+        ((self.regs.rflags & flags::IOPL) >> 12) as u8
+    }
+
+    /// The RFLAGS bits of `mask` that POPF or IRET may change at the current privilege
+    /// level.
+    pub(super) fn writable_flags(&self, mask: u64) -> u64 {
+        let mut mask = mask;
+        if self.cpl() > self.iopl() {
+            mask &= !flags::IF;
+        }
+        if self.cpl() > 0 {
+            mask &= !flags::IOPL;
+        }
+        mask
+    }
+
+    pub(super) fn execute_interrupt(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        let vector = match insn.mnemonic {
+            Int => Some(insn.immediate().unwrap() as u8),
+            Int3 => Some(Exception::Breakpoint.vector()),
+            Int1 => Some(Exception::Debug.vector()),
+            Into if self.regs.rflags & flags::OF != 0 => Some(Exception::Overflow.vector()),
+            _ => None,
+        };
+        if let Some(vector) = vector {
+            if self.real_mode() {
+                return self.real_mode_interrupt(map, vector);
+            }
+            if !self.linux_user {
+                // INT1 is delivered like a debug exception, without the gate's DPL check
+                let software = insn.mnemonic != Int1;
+                return self.protected_mode_interrupt(map, vector, None, software);
+            }
+            return Err(match insn.mnemonic {
+                Int3 => Exception::Breakpoint,
+                Int1 => Exception::Debug,
+                Into => Exception::Overflow,
+                // As if the vector's gate were out of the program's reach
+                _ => Exception::GeneralProtection((vector as u16) << 3 | 2),
+            });
+        }
+        match insn.mnemonic {
+            Into => {}
+            Iret | Iretd | Iretq => {
+                let size = insn.operand_size;
+                // A 16-bit IRET leaves the upper flags alone
+                let mask = self.writable_flags(IRET_MASK) & size.mask();
+                let value = self.far_return(map, size, 0, true)?;
+                self.regs.rflags = (self.regs.rflags & !mask) | (value & mask);
+            }
+            Cli | Sti => {
+                if self.cpl() > self.iopl() {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                if insn.mnemonic == Cli {
+                    self.regs.rflags &= !flags::IF;
+                } else {
+                    self.regs.rflags |= flags::IF;
+                }
+            }
+            Hlt => {
+                if self.cpl() != 0 {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                self.halted = true;
+            }
+            mnemonic => unreachable!("{} is not an interrupt instruction", mnemonic),
+        }
+        Ok(())
+    }
+
+    /// Delivers an exception raised by the instruction at `rip`, which its handler returns
+    /// to. An exception raised while delivering it becomes a double fault, and one raised
+    /// while delivering that shuts the processor down: that's the error returned.
+    pub(super) fn deliver_exception(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        exception: Exception,
+    ) -> Result<(), Exception> {
+        let mut deliver = |this: &mut Self, exception: Exception| {
+            if this.real_mode() {
+                this.real_mode_interrupt(map, exception.vector())
+            } else {
+                let error_code = exception.error_code();
+                this.protected_mode_interrupt(map, exception.vector(), error_code, false)
+            }
+        };
+        deliver(self, exception)
+            .or_else(|_| deliver(self, Exception::DoubleFault))
+            .map_err(|_| Exception::DoubleFault)
+    }
+
+    /// Delivers interrupt `vector` in real mode: pushes FLAGS, CS and IP, clears IF, TF and
+    /// AC, and jumps to the handler whose far pointer (offset, then segment) is entry
+    /// `vector` of the interrupt vector table, which the IDTR locates.
+    pub(super) fn real_mode_interrupt(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        vector: u8,
+    ) -> Result<(), Exception> {
+        let entry = vector as u64 * 4;
+        if entry + 3 > self.tables.idtr.limit as u64 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let entry = self.tables.idtr.base + entry;
+        let offset = self.read_memory(map, entry, OperandSize::R16)?;
+        let selector = self.read_memory(map, entry + 2, OperandSize::R16)?;
+        let saved = self.save_segments();
+        let (cs, ip) = (self.regs.sr[CS], self.regs.rip);
+        self.push(map, self.regs.rflags, OperandSize::R16)
+            .and_then(|_| self.push(map, cs as u64, OperandSize::R16))
+            .and_then(|_| self.push(map, ip, OperandSize::R16))
+            .and_then(|_| {
+                let cs = self.segments[CS];
+                self.jump_within(selector as u16, cs, offset, OperandSize::R16)
+            })
+            .inspect_err(|_| self.restore_segments(saved))?;
+        self.regs.rflags &= !(flags::IF | flags::TF | flags::AC);
+        Ok(())
+    }
+
+    /// Delivers interrupt `vector` through the IDT in protected or long mode, pushing
+    /// `error_code` if the exception has one. `software` interrupts (INT n, INT3, INTO) may
+    /// only use gates whose DPL the current privilege level is within. A handler in a more
+    /// privileged nonconforming segment runs on the stack the TSS gives its level, with the
+    /// interrupted stack pointer saved on it. Task gates aren't supported.
+    pub(super) fn protected_mode_interrupt(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        vector: u8,
+        error_code: Option<u32>,
+        software: bool,
+    ) -> Result<(), Exception> {
+        let (saved, rflags) = (self.save_segments(), self.regs.rflags);
+        self.protected_mode_interrupt_unchecked(map, vector, error_code, software)
+            .inspect_err(|_| {
+                self.restore_segments(saved);
+                self.regs.rflags = rflags;
+            })
+    }
+
+    fn protected_mode_interrupt_unchecked(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        vector: u8,
+        error_code: Option<u32>,
+        software: bool,
+    ) -> Result<(), Exception> {
+        // Errors about the IDT entry itself give its index with the IDT bit set, and the EXT
+        // bit for events the program didn't raise itself
+        let idt_error = (vector as u16) << 3 | 2 | if software { 0 } else { 1 };
+        let long_mode = self.long_mode();
+        let bytes = if long_mode { 16 } else { 8 };
+        let idtr = self.tables.idtr;
+        if vector as u64 * bytes + bytes - 1 > idtr.limit as u64 {
+            return Err(Exception::GeneralProtection(idt_error));
+        }
+        let entry = idtr.base + vector as u64 * bytes;
+        let raw = self.read_system(map, entry, OperandSize::R64)?;
+        let upper = match long_mode {
+            true => self.read_system(map, entry + 8, OperandSize::R64)?,
+            false => 0,
+        };
+        let gate = Gate::from_raw(raw, upper);
+        let usable = match gate.kind() {
+            INTERRUPT_GATE | TRAP_GATE => true,
+            INTERRUPT_GATE16 | TRAP_GATE16 => !long_mode,
+            _ => false,
+        };
+        let cpl = self.cpl();
+        if !usable || (software && gate.dpl() < cpl) {
+            return Err(Exception::GeneralProtection(idt_error));
+        }
+        if !gate.present() {
+            return Err(Exception::SegmentNotPresent(idt_error));
+        }
+        let target = self.code_descriptor(map, gate.selector)?;
+        let dpl = target.dpl();
+        if dpl > cpl {
+            return Err(Exception::GeneralProtection(gate.selector & !3));
+        }
+        let size = gate.size(long_mode);
+        let (cs, ip, rflags) = (self.regs.sr[CS], self.regs.rip, self.regs.rflags);
+        let (old_ss, old_sp) = (self.regs.sr[SS], self.read_gpr(4, self.stack_size()));
+        let inner = !target.has(CONFORMING) && dpl < cpl;
+        let new_cpl = if inner { dpl } else { cpl };
+        if inner || (long_mode && gate.ist != 0) {
+            let ist = if long_mode { gate.ist } else { 0 };
+            let (ss, ss_descriptor, sp) = self.inner_stack(map, new_cpl, ist)?;
+            self.enter_code_segment(gate.selector, target, new_cpl);
+            if inner {
+                self.set_segment(SS, ss, ss_descriptor);
+            }
+            self.move_stack_pointer(sp);
+        } else {
+            self.enter_code_segment(gate.selector, target, new_cpl);
+        }
+        if long_mode {
+            // Long mode aligns the stack and always saves the interrupted one
+            self.regs.gprs[4] &= !0xF;
+        }
+        if inner || long_mode {
+            self.push(map, old_ss as u64, size)?;
+            self.push(map, old_sp, size)?;
+        }
+        self.push(map, rflags, size)?;
+        self.push(map, cs as u64, size)?;
+        self.push(map, ip, size)?;
+        if let Some(code) = error_code {
+            self.push(map, code as u64, size)?;
+        }
+        self.jump_within(gate.selector, target, gate.offset, size)?;
+        self.regs.rflags &= !(flags::TF | flags::NT | flags::RF | flags::VM);
+        if gate.kind() & 1 == 0 {
+            // Interrupt gates, unlike trap gates, hold off further interrupts
+            self.regs.rflags &= !flags::IF;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+//! Port I/O: IN, OUT, INS and OUTS, against the little of a PC's I/O space that's modeled,
+//! the two ways of opening the A20 gate. One is bit 1 of system control port A (0x92); the
+//! other is the keyboard controller's output port, written with its 0xD1 command or switched
+//! by the 0xDD and 0xDF ones. Other ports read as all ones, as nothing drives the bus, and
+//! ignore writes.
+
+use super::Amd64Interp;
+use crate::amd64::decode::{Instruction, Mnemonic, OperandSize};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+const KEYBOARD_DATA: u16 = 0x60;
+const KEYBOARD_COMMAND: u16 = 0x64;
+const SYSTEM_CONTROL_A: u16 = 0x92;
+
+/// The A20 bit of system control port A and of the keyboard controller's output port
+const A20: u8 = 1 << 1;
+/// Keyboard controller status: a byte is waiting in the output buffer
+const OUTPUT_FULL: u8 = 1 << 0;
+/// Keyboard controller status: the self-test passed
+const SYSTEM_FLAG: u8 = 1 << 2;
+/// The output port's reset line, which is active low
+const NOT_RESET: u8 = 1 << 0;
+
+/// The state of the keyboard controller's command interface.
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct KeyboardController {
+    /// A command waiting for its data byte
+    command: Option<u8>,
+    /// The byte a command left for port 0x60 to read
+    output: Option<u8>,
+}
+
+impl Amd64Interp {
+    pub(super) fn execute_io(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        // The TSS's I/O permission bitmap isn't consulted, so below IOPL every port is shut
+        if self.cpl() > self.iopl() {
+            return Err(Exception::GeneralProtection(0));
+        }
+        match insn.mnemonic {
+            Mnemonic::In => {
+                let port = self.read_operand(map, &insn.operand(1))? as u16;
+                let dst = insn.operand(0);
+                let value = self.port_in(port, dst.size().unwrap());
+                self.write_operand(map, &dst, value)
+            }
+            Mnemonic::Out => {
+                let port = self.read_operand(map, &insn.operand(0))? as u16;
+                let src = insn.operand(1);
+                let value = self.read_operand(map, &src)?;
+                self.port_out(port, src.size().unwrap(), value);
+                Ok(())
+            }
+            _ => self.execute_string(map, insn),
+        }
+    }
+
+    /// Reads `size` from `port`, a byte at a time from consecutive ports.
+    pub(super) fn port_in(&mut self, port: u16, size: OperandSize) -> u64 {
+        (0..size.bytes()).fold(0, |value, i| {
+            value | (self.port_in_byte(port.wrapping_add(i as u16)) as u64) << (8 * i)
+        })
+    }
+
+    /// Writes `size` to `port`, a byte at a time to consecutive ports.
+    pub(super) fn port_out(&mut self, port: u16, size: OperandSize, value: u64) {
+        for i in 0..size.bytes() {
+            self.port_out_byte(port.wrapping_add(i as u16), (value >> (8 * i)) as u8);
+        }
+    }
+
+    fn port_in_byte(&mut self, port: u16) -> u8 {
+        match port {
+            KEYBOARD_DATA => self.keyboard.output.take().unwrap_or(0),
+            KEYBOARD_COMMAND => SYSTEM_FLAG | self.keyboard.output.map_or(0, |_| OUTPUT_FULL),
+            SYSTEM_CONTROL_A => self.a20_bit(),
+            _ => 0xFF,
+        }
+    }
+
+    fn port_out_byte(&mut self, port: u16, value: u8) {
+        match port {
+            KEYBOARD_DATA => {
+                // Any write ends a command; the data byte of a write-output-port one sets the
+                // output port
+                let command = self.keyboard.command.take();
+                if command == Some(0xD1) {
+                    self.a20 = value & A20 != 0;
+                }
+            }
+            KEYBOARD_COMMAND => match value {
+                0xD0 => self.keyboard.output = Some(NOT_RESET | self.a20_bit()),
+                0xD1 => self.keyboard.command = Some(value),
+                0xDD => self.a20 = false,
+                0xDF => self.a20 = true,
+                _ => {}
+            },
+            // Bit 0 would reset the processor, which isn't modeled
+            SYSTEM_CONTROL_A => self.a20 = value & A20 != 0,
+            _ => {}
+        }
+    }
+
+    fn a20_bit(&self) -> u8 {
+        if self.a20 {
+            A20
+        } else {
+            0
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! The instructions 64-bit mode dropped, which IA-32 programs can still use: the decimal
+//! adjustments, BOUND, SALC and ARPL. INTO is with the other interrupts.
+
+use super::descriptor::Access;
+use super::{flags, Amd64Interp};
+use crate::amd64::decode::{Instruction, Mnemonic, Operand, OperandSize};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+impl Amd64Interp {
+    pub(super) fn execute_legacy(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let al = self.read_gpr(0, OperandSize::R8);
+        let cf = self.regs.rflags & flags::CF != 0;
+        let af = self.regs.rflags & flags::AF != 0;
+        match insn.mnemonic {
+            Mnemonic::Daa | Mnemonic::Das => {
+                // Corrects each digit of the packed BCD result of an addition or
+                // subtraction in AL, low digit first
+                let add = insn.mnemonic == Mnemonic::Daa;
+                let adjust = |value: u8, by: u8| {
+                    if add {
+                        value.overflowing_add(by)
+                    } else {
+                        value.overflowing_sub(by)
+                    }
+                };
+                let (mut result, mut carry) = (al as u8, false);
+                let low = al & 0x0F > 9 || af;
+                if low {
+                    let (value, overflow) = adjust(result, 0x06);
+                    (result, carry) = (value, cf || overflow);
+                }
+                if al > 0x99 || cf {
+                    result = adjust(result, 0x60).0;
+                    carry = true;
+                } else if add {
+                    // Unlike DAS, DAA drops the carry out of the low digit
+                    carry = false;
+                }
+                self.write_gpr(0, OperandSize::R8, result as u64);
+                let rflags = flags::szp(result as u64, OperandSize::R8)
+                    | if carry { flags::CF } else { 0 }
+                    | if low { flags::AF } else { 0 };
+                self.regs.rflags = (self.regs.rflags & !flags::STATUS) | rflags;
+            }
+            Mnemonic::Aaa | Mnemonic::Aas => {
+                // Corrects the unpacked BCD result of an addition or subtraction in AL,
+                // carrying into or borrowing from AH. The other status flags are undefined
+                // and left alone.
+                let ax = self.read_gpr(0, OperandSize::R16);
+                let adjust = al & 0x0F > 9 || af;
+                let ax = match (adjust, insn.mnemonic) {
+                    (false, _) => ax,
+                    (true, Mnemonic::Aaa) => ax.wrapping_add(0x106),
+                    (true, _) => ax.wrapping_sub(6).wrapping_sub(0x100),
+                };
+                self.write_gpr(0, OperandSize::R16, ax & 0xFF0F);
+                let mask = flags::CF | flags::AF;
+                let set = if adjust { mask } else { 0 };
+                self.regs.rflags = (self.regs.rflags & !mask) | set;
+            }
+            Mnemonic::Aam | Mnemonic::Aad => {
+                // Splits AL into, or joins AH and AL from, two unpacked digits in the base
+                // the immediate gives, usually 10
+                let base = insn.immediate().unwrap();
+                let ax = match insn.mnemonic {
+                    Mnemonic::Aam if base == 0 => return Err(Exception::DivideError),
+                    Mnemonic::Aam => ((al / base) << 8) | (al % base),
+                    _ => {
+                        let ah = self.read_gpr(0, OperandSize::R16) >> 8;
+                        (al + ah * base) & 0xFF
+                    }
+                };
+                self.write_gpr(0, OperandSize::R16, ax);
+                let rflags = flags::szp(ax, OperandSize::R8);
+                self.regs.rflags = (self.regs.rflags & !flags::STATUS) | rflags;
+            }
+            Mnemonic::Salc => {
+                let value = if cf { 0xFF } else { 0 };
+                self.write_gpr(0, OperandSize::R8, value);
+            }
+            Mnemonic::Bound => {
+                // The array index must lie between the signed bounds at the memory operand,
+                // lower then upper
+                let size = insn.operand_size;
+                let Operand::Memory(mem, _) = insn.operand(1) else {
+                    unreachable!("BOUND always has a memory operand")
+                };
+                let index = self.read_operand(map, &insn.operand(0))?;
+                let addr = self.checked_address(&mem, 2 * size.bytes(), Access::Read)?;
+                let lower = self.read_memory(map, addr, size)?;
+                let upper_addr =
+                    self.checked_element(&mem, size.bytes(), size.bytes(), Access::Read)?;
+                let upper = self.read_memory(map, upper_addr, size)?;
+                let signed = |value| flags::sign_extend(value, size) as i64;
+                if signed(index) < signed(lower) || signed(index) > signed(upper) {
+                    return Err(Exception::BoundRange);
+                }
+            }
+            Mnemonic::Arpl => {
+                // Raises the requested privilege level of the destination selector to the
+                // source's, for a callee to check pointers it's passed
+                let dst = insn.operand(0);
+                let selector = self.read_operand(map, &dst)?;
+                let rpl = self.read_operand(map, &insn.operand(1))? & 3;
+                let adjust = selector & 3 < rpl;
+                if adjust {
+                    self.write_operand(map, &dst, selector & !3 | rpl)?;
+                }
+                self.regs.rflags =
+                    (self.regs.rflags & !flags::ZF) | if adjust { flags::ZF } else { 0 };
+            }
+            mnemonic => unreachable!("{} is not a legacy instruction", mnemonic),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+//! MMX instructions. The eight MMX registers are the significands of the x87 physical
+//! registers, so using them takes over the x87 stack until EMMS hands it back.
+
+use super::x87::{CR0_EM, CR0_TS, ERROR_SUMMARY};
+use super::Amd64Interp;
+use crate::amd64::decode::{Instruction, Mnemonic};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+impl Amd64Interp {
+    pub(super) fn execute_mmx(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let cr0 = self.regs.cr[0];
+        if cr0 & CR0_EM != 0 {
+            return Err(Exception::InvalidOpcode);
+        }
+        if cr0 & CR0_TS != 0 {
+            return Err(Exception::DeviceNotAvailable);
+        }
+        // Conversions between MMX and XMM registers need SSE enabled as well
+        if insn.is_sse() {
+            self.sse_available()?;
+        }
+        if self.regs.fpu.fsw & ERROR_SUMMARY != 0 {
+            return Err(Exception::FloatingPoint);
+        }
+        if insn.mnemonic == Mnemonic::Emms {
+            self.regs.fpu.ftw = 0xFFFF;
+            return Ok(());
+        }
+        // Every other MMX instruction resets TOP and marks the whole stack valid
+        self.set_top(0);
+        self.regs.fpu.ftw = 0;
+
+        self.execute_vector(map, insn)
+    }
+}
@@ -0,0 +1,632 @@
+//! Packed integer arithmetic and data movement on vectors of any width, shared by MMX and
+//! SSE.
+//!
+//! Vectors are little-endian byte images. Instructions that move data between lanes
+//! (unpacks, packs, horizontal arithmetic and byte shuffles) work independently on each
+//! 16-byte block, and on the whole vector when it is narrower than that, as MMX registers
+//! are.
+
+use crate::amd64::decode::Mnemonic;
+
+/// Lane `i` of `width` bytes, zero-extended.
+pub(super) fn lane(vector: &[u8], i: usize, width: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes[..width].copy_from_slice(&vector[i * width..(i + 1) * width]);
+    u64::from_le_bytes(bytes)
+}
+
+pub(super) fn set_lane(vector: &mut [u8], i: usize, width: usize, value: u64) {
+    vector[i * width..(i + 1) * width].copy_from_slice(&value.to_le_bytes()[..width]);
+}
+
+/// A lane value sign-extended from `width` bytes.
+pub(super) fn signed(value: u64, width: usize) -> i64 {
+    let shift = 64 - 8 * width;
+    ((value << shift) as i64) >> shift
+}
+
+fn saturate_signed(value: i64, width: usize) -> u64 {
+    let max = i64::MAX >> (64 - 8 * width);
+    value.clamp(!max, max) as u64
+}
+
+fn saturate_unsigned(value: i64, width: usize) -> u64 {
+    let max = u64::MAX >> (64 - 8 * width);
+    value.clamp(0, max as i64) as u64
+}
+
+/// Applies `f` to each pair of corresponding lanes.
+fn lanewise(a: &[u8], b: &[u8], width: usize, f: impl Fn(u64, u64) -> u64) -> Vec<u8> {
+    let mut result = vec![0; a.len()];
+    for i in 0..a.len() / width {
+        set_lane(
+            &mut result,
+            i,
+            width,
+            f(lane(a, i, width), lane(b, i, width)),
+        );
+    }
+    result
+}
+
+/// Sets each lane to all ones where `f` holds for the pair of lanes, and clears it elsewhere.
+fn compare(a: &[u8], b: &[u8], width: usize, f: impl Fn(u64, u64) -> bool) -> Vec<u8> {
+    lanewise(a, b, width, |x, y| if f(x, y) { u64::MAX } else { 0 })
+}
+
+/// Interleaves the low (or high) halves of each block of `a` and `b`.
+fn unpack(a: &[u8], b: &[u8], width: usize, high: bool) -> Vec<u8> {
+    let block = a.len().min(16);
+    let lanes = block / width;
+    let mut result = vec![0; a.len()];
+    for start in (0..a.len()).step_by(block) {
+        let first = if high { lanes / 2 } else { 0 };
+        for i in 0..lanes / 2 {
+            let source = start / width + first + i;
+            let target = start / width + 2 * i;
+            set_lane(&mut result, target, width, lane(a, source, width));
+            set_lane(&mut result, target + 1, width, lane(b, source, width));
+        }
+    }
+    result
+}
+
+/// Narrows each block of `a` then `b` from `width`-byte lanes to half that, saturating.
+fn pack(a: &[u8], b: &[u8], width: usize, saturate: fn(i64, usize) -> u64) -> Vec<u8> {
+    let block = a.len().min(16);
+    let lanes = block / width;
+    let mut result = vec![0; a.len()];
+    for start in (0..a.len()).step_by(block) {
+        for (half, source) in [a, b].into_iter().enumerate() {
+            for i in 0..lanes {
+                let value = signed(lane(source, start / width + i, width), width);
+                let target = start * 2 / width + half * lanes + i;
+                set_lane(&mut result, target, width / 2, saturate(value, width / 2));
+            }
+        }
+    }
+    result
+}
+
+/// Combines adjacent pairs of lanes, those of each block of `a` then those of `b`.
+fn horizontal(a: &[u8], b: &[u8], width: usize, f: impl Fn(u64, u64) -> u64) -> Vec<u8> {
+    let block = a.len().min(16);
+    let lanes = block / width;
+    let mut result = vec![0; a.len()];
+    for start in (0..a.len()).step_by(block) {
+        for (half, source) in [a, b].into_iter().enumerate() {
+            for i in 0..lanes / 2 {
+                let first = start / width + 2 * i;
+                let value = f(lane(source, first, width), lane(source, first + 1, width));
+                set_lane(
+                    &mut result,
+                    start / width + half * lanes / 2 + i,
+                    width,
+                    value,
+                );
+            }
+        }
+    }
+    result
+}
+
+/// Sign- or zero-extends the low lanes of `b` from `from` bytes to `to` bytes, as many as
+/// fill a vector as wide as `a`.
+fn extend(a: &[u8], b: &[u8], from: usize, to: usize, sign: bool) -> Vec<u8> {
+    let mut result = vec![0; a.len()];
+    for i in 0..a.len() / to {
+        let value = lane(b, i, from);
+        let value = if sign {
+            signed(value, from) as u64
+        } else {
+            value
+        };
+        set_lane(&mut result, i, to, value);
+    }
+    result
+}
+
+/// Negates, zeroes or keeps each lane of `a` as the corresponding lane of `b` is negative,
+/// zero or positive.
+fn sign(a: &[u8], b: &[u8], width: usize) -> Vec<u8> {
+    lanewise(a, b, width, |x, y| match signed(y, width).signum() {
+        -1 => x.wrapping_neg(),
+        0 => 0,
+        _ => x,
+    })
+}
+
+/// Shifts each lane by the count in the low quadword of `b`. Counts of the lane width or
+/// more clear logical shifts and fill arithmetic ones with the sign.
+fn shift(a: &[u8], b: &[u8], width: usize, mnemonic: Mnemonic) -> Vec<u8> {
+    let count = lane(b, 0, 8);
+    let bits = 8 * width as u64;
+    let mask = u64::MAX >> (64 - bits);
+    let mut result = vec![0; a.len()];
+    for i in 0..a.len() / width {
+        let value = lane(a, i, width);
+        let shifted = match mnemonic {
+            _ if count >= bits && !is_arithmetic_shift(mnemonic) => 0,
+            Mnemonic::Psllw | Mnemonic::Pslld | Mnemonic::Psllq => value << count,
+            Mnemonic::Psrlw | Mnemonic::Psrld | Mnemonic::Psrlq => value >> count,
+            _ => (signed(value, width) >> count.min(bits - 1)) as u64,
+        };
+        set_lane(&mut result, i, width, shifted & mask);
+    }
+    result
+}
+
+/// Shifts each block right (or left) by the number of bytes in the low quadword of `b`.
+fn shift_bytes(a: &[u8], b: &[u8], left: bool) -> Vec<u8> {
+    let count = lane(b, 0, 8).min(16) as usize;
+    let mut result = vec![0; a.len()];
+    for (source, target) in a.chunks(16).zip(result.chunks_mut(16)) {
+        if left {
+            target[count..].copy_from_slice(&source[..16 - count]);
+        } else {
+            target[..16 - count].copy_from_slice(&source[count..]);
+        }
+    }
+    result
+}
+
+/// Rotates a lane of `width` bytes left (or right) by `count`, modulo the lane width.
+fn rotate(value: u64, count: u64, width: usize, left: bool) -> u64 {
+    let bits = 8 * width as u64;
+    let count = if left {
+        count % bits
+    } else {
+        (bits - count % bits) % bits
+    };
+    (value << count | value >> ((bits - count) % bits)) & (u64::MAX >> (64 - bits))
+}
+
+fn is_arithmetic_shift(mnemonic: Mnemonic) -> bool {
+    matches!(
+        mnemonic,
+        Mnemonic::Psraw | Mnemonic::Psrad | Mnemonic::Vpsraq
+    )
+}
+
+/// Computes `a op b` for a packed integer instruction, where `a` is the destination and `b`
+/// the source. Both vectors have the same width.
+pub(super) fn integer(mnemonic: Mnemonic, a: &[u8], b: &[u8]) -> Vec<u8> {
+    use Mnemonic::*;
+    match mnemonic {
+        Paddb => lanewise(a, b, 1, u64::wrapping_add),
+        Paddw => lanewise(a, b, 2, u64::wrapping_add),
+        Paddd => lanewise(a, b, 4, u64::wrapping_add),
+        Paddq => lanewise(a, b, 8, u64::wrapping_add),
+        Paddsb => lanewise(a, b, 1, |x, y| {
+            saturate_signed(signed(x, 1) + signed(y, 1), 1)
+        }),
+        Paddsw => lanewise(a, b, 2, |x, y| {
+            saturate_signed(signed(x, 2) + signed(y, 2), 2)
+        }),
+        Paddusb => lanewise(a, b, 1, |x, y| saturate_unsigned((x + y) as i64, 1)),
+        Paddusw => lanewise(a, b, 2, |x, y| saturate_unsigned((x + y) as i64, 2)),
+        Psubb => lanewise(a, b, 1, u64::wrapping_sub),
+        Psubw => lanewise(a, b, 2, u64::wrapping_sub),
+        Psubd => lanewise(a, b, 4, u64::wrapping_sub),
+        Psubq => lanewise(a, b, 8, u64::wrapping_sub),
+        Psubsb => lanewise(a, b, 1, |x, y| {
+            saturate_signed(signed(x, 1) - signed(y, 1), 1)
+        }),
+        Psubsw => lanewise(a, b, 2, |x, y| {
+            saturate_signed(signed(x, 2) - signed(y, 2), 2)
+        }),
+        Psubusb => lanewise(a, b, 1, |x, y| saturate_unsigned(x as i64 - y as i64, 1)),
+        Psubusw => lanewise(a, b, 2, |x, y| saturate_unsigned(x as i64 - y as i64, 2)),
+        Pmullw => lanewise(a, b, 2, u64::wrapping_mul),
+        Pmulhw => lanewise(a, b, 2, |x, y| ((signed(x, 2) * signed(y, 2)) >> 16) as u64),
+        Pmulhuw => lanewise(a, b, 2, |x, y| (x * y) >> 16),
+        Pmuludq => lanewise(a, b, 8, |x, y| (x & 0xFFFF_FFFF) * (y & 0xFFFF_FFFF)),
+        Pavgb => lanewise(a, b, 1, |x, y| (x + y + 1) >> 1),
+        Pavgw => lanewise(a, b, 2, |x, y| (x + y + 1) >> 1),
+        Pminub => lanewise(a, b, 1, u64::min),
+        Pmaxub => lanewise(a, b, 1, u64::max),
+        Pminsw => lanewise(a, b, 2, |x, y| signed(x, 2).min(signed(y, 2)) as u64),
+        Pmaxsw => lanewise(a, b, 2, |x, y| signed(x, 2).max(signed(y, 2)) as u64),
+        // Sums the absolute differences of the bytes of each quadword
+        Psadbw => lanewise(a, b, 8, |x, y| {
+            let (x, y) = (x.to_le_bytes(), y.to_le_bytes());
+            x.iter().zip(y).map(|(&x, y)| x.abs_diff(y) as u64).sum()
+        }),
+        Pmaddwd => lanewise(a, b, 4, |x, y| {
+            let low = signed(x & 0xFFFF, 2) * signed(y & 0xFFFF, 2);
+            let high = signed(x >> 16, 2) * signed(y >> 16, 2);
+            low.wrapping_add(high) as u64
+        }),
+        Pcmpeqb => compare(a, b, 1, |x, y| x == y),
+        Pcmpeqw => compare(a, b, 2, |x, y| x == y),
+        Pcmpeqd => compare(a, b, 4, |x, y| x == y),
+        Pcmpgtb => compare(a, b, 1, |x, y| signed(x, 1) > signed(y, 1)),
+        Pcmpgtw => compare(a, b, 2, |x, y| signed(x, 2) > signed(y, 2)),
+        Pcmpgtd => compare(a, b, 4, |x, y| signed(x, 4) > signed(y, 4)),
+        // The floating-point logic and unpack instructions only move bits around
+        Pand | Andps | Andpd | Vpandd | Vpandq => lanewise(a, b, 8, |x, y| x & y),
+        Pandn | Andnps | Andnpd | Vpandnd | Vpandnq => lanewise(a, b, 8, |x, y| !x & y),
+        Por | Orps | Orpd | Vpord | Vporq => lanewise(a, b, 8, |x, y| x | y),
+        Pxor | Xorps | Xorpd | Vpxord | Vpxorq => lanewise(a, b, 8, |x, y| x ^ y),
+        Punpcklbw => unpack(a, b, 1, false),
+        Punpcklwd => unpack(a, b, 2, false),
+        Punpckldq | Unpcklps => unpack(a, b, 4, false),
+        Punpcklqdq | Unpcklpd => unpack(a, b, 8, false),
+        Punpckhbw => unpack(a, b, 1, true),
+        Punpckhwd => unpack(a, b, 2, true),
+        Punpckhdq | Unpckhps => unpack(a, b, 4, true),
+        Punpckhqdq | Unpckhpd => unpack(a, b, 8, true),
+        Packsswb => pack(a, b, 2, saturate_signed),
+        Packssdw => pack(a, b, 4, saturate_signed),
+        Packuswb => pack(a, b, 2, saturate_unsigned),
+        Packusdw => pack(a, b, 4, saturate_unsigned),
+        Phaddw => horizontal(a, b, 2, u64::wrapping_add),
+        Phaddd => horizontal(a, b, 4, u64::wrapping_add),
+        Phaddsw => horizontal(a, b, 2, |x, y| {
+            saturate_signed(signed(x, 2) + signed(y, 2), 2)
+        }),
+        Phsubw => horizontal(a, b, 2, u64::wrapping_sub),
+        Phsubd => horizontal(a, b, 4, u64::wrapping_sub),
+        Phsubsw => horizontal(a, b, 2, |x, y| {
+            saturate_signed(signed(x, 2) - signed(y, 2), 2)
+        }),
+        // Unsigned bytes of the destination times signed bytes of the source
+        Pmaddubsw => lanewise(a, b, 2, |x, y| {
+            let low = (x & 0xFF) as i64 * signed(y & 0xFF, 1);
+            let high = (x >> 8) as i64 * signed(y >> 8, 1);
+            saturate_signed(low + high, 2)
+        }),
+        Pmulhrsw => lanewise(a, b, 2, |x, y| {
+            ((((signed(x, 2) * signed(y, 2)) >> 14) + 1) >> 1) as u64
+        }),
+        Pmuldq => lanewise(a, b, 8, |x, y| {
+            signed(x & 0xFFFF_FFFF, 4).wrapping_mul(signed(y & 0xFFFF_FFFF, 4)) as u64
+        }),
+        Pmulld => lanewise(a, b, 4, u64::wrapping_mul),
+        Psignb => sign(a, b, 1),
+        Psignw => sign(a, b, 2),
+        Psignd => sign(a, b, 4),
+        Pabsb => lanewise(a, b, 1, |_, y| signed(y, 1).unsigned_abs()),
+        Pabsw => lanewise(a, b, 2, |_, y| signed(y, 2).unsigned_abs()),
+        Pabsd => lanewise(a, b, 4, |_, y| signed(y, 4).unsigned_abs()),
+        Pminsb => lanewise(a, b, 1, |x, y| signed(x, 1).min(signed(y, 1)) as u64),
+        Pmaxsb => lanewise(a, b, 1, |x, y| signed(x, 1).max(signed(y, 1)) as u64),
+        Pminsd => lanewise(a, b, 4, |x, y| signed(x, 4).min(signed(y, 4)) as u64),
+        Pmaxsd => lanewise(a, b, 4, |x, y| signed(x, 4).max(signed(y, 4)) as u64),
+        Pminuw => lanewise(a, b, 2, u64::min),
+        Pmaxuw => lanewise(a, b, 2, u64::max),
+        Pminud => lanewise(a, b, 4, u64::min),
+        Pmaxud => lanewise(a, b, 4, u64::max),
+        Pcmpeqq => compare(a, b, 8, |x, y| x == y),
+        Pcmpgtq => compare(a, b, 8, |x, y| (x as i64) > (y as i64)),
+        Pmovsxbw => extend(a, b, 1, 2, true),
+        Pmovsxbd => extend(a, b, 1, 4, true),
+        Pmovsxbq => extend(a, b, 1, 8, true),
+        Pmovsxwd => extend(a, b, 2, 4, true),
+        Pmovsxwq => extend(a, b, 2, 8, true),
+        Pmovsxdq => extend(a, b, 4, 8, true),
+        Pmovzxbw => extend(a, b, 1, 2, false),
+        Pmovzxbd => extend(a, b, 1, 4, false),
+        Pmovzxbq => extend(a, b, 1, 8, false),
+        Pmovzxwd => extend(a, b, 2, 4, false),
+        Pmovzxwq => extend(a, b, 2, 8, false),
+        Pmovzxdq => extend(a, b, 4, 8, false),
+        Pshufb => {
+            // Each byte of the source picks a byte of the same block of the destination, or
+            // zero if its top bit is set
+            let block = a.len().min(16);
+            let mut result = vec![0; a.len()];
+            for (i, &select) in b.iter().enumerate() {
+                if select & 0x80 == 0 {
+                    let start = i - i % block;
+                    result[i] = a[start + (select as usize & (block - 1))];
+                }
+            }
+            result
+        }
+        Phminposuw => {
+            // The smallest word and the index of its first occurrence, in each block
+            let mut result = vec![0; a.len()];
+            for start in (0..a.len()).step_by(16) {
+                let (index, value) = (0..8)
+                    .map(|i| lane(&b[start..], i, 2))
+                    .enumerate()
+                    .min_by_key(|&(_, value)| value)
+                    .unwrap();
+                set_lane(&mut result[start..], 0, 4, value | (index as u64) << 16);
+            }
+            result
+        }
+        Movsldup | Movshdup => {
+            let odd = (mnemonic == Movshdup) as usize;
+            let mut result = vec![0; a.len()];
+            for i in 0..a.len() / 4 {
+                set_lane(&mut result, i, 4, lane(b, (i & !1) | odd, 4));
+            }
+            result
+        }
+        Movddup => {
+            let mut result = vec![0; a.len()];
+            for i in 0..a.len() / 8 {
+                set_lane(&mut result, i, 8, lane(b, i & !1, 8));
+            }
+            result
+        }
+        Psllw | Psrlw | Psraw => shift(a, b, 2, mnemonic),
+        Pslld | Psrld | Psrad => shift(a, b, 4, mnemonic),
+        Psllq | Psrlq => shift(a, b, 8, mnemonic),
+        Pslldq => shift_bytes(a, b, true),
+        Psrldq => shift_bytes(a, b, false),
+        // AVX2's shifts by a count for each lane
+        Vpsllvd => lanewise(a, b, 4, |x, y| if y < 32 { x << y } else { 0 }),
+        Vpsllvq => lanewise(a, b, 8, |x, y| if y < 64 { x << y } else { 0 }),
+        Vpsrlvd => lanewise(a, b, 4, |x, y| if y < 32 { x >> y } else { 0 }),
+        Vpsrlvq => lanewise(a, b, 8, |x, y| if y < 64 { x >> y } else { 0 }),
+        Vpsravd => lanewise(a, b, 4, |x, y| (signed(x, 4) >> y.min(31)) as u64),
+        // And AVX512BW's on words
+        Vpsllvw => lanewise(a, b, 2, |x, y| if y < 16 { x << y } else { 0 }),
+        Vpsrlvw => lanewise(a, b, 2, |x, y| if y < 16 { x >> y } else { 0 }),
+        Vpsravw => lanewise(a, b, 2, |x, y| (signed(x, 2) >> y.min(15)) as u64),
+        // AVX-512's quadword forms of what only went up to doublewords
+        Vpsraq => shift(a, b, 8, mnemonic),
+        Vpsravq => lanewise(a, b, 8, |x, y| (x as i64 >> y.min(63)) as u64),
+        Vpabsq => lanewise(a, b, 8, |_, y| (y as i64).unsigned_abs()),
+        Vpminsq => lanewise(a, b, 8, |x, y| (x as i64).min(y as i64) as u64),
+        Vpmaxsq => lanewise(a, b, 8, |x, y| (x as i64).max(y as i64) as u64),
+        Vpminuq => lanewise(a, b, 8, u64::min),
+        Vpmaxuq => lanewise(a, b, 8, u64::max),
+        Vpmullq => lanewise(a, b, 8, u64::wrapping_mul),
+        // The rotates, by the immediate (in the low quadword of `b`, like the shift counts)
+        // or by a count for each lane
+        Vprold | Vprord | Vprolq | Vprorq => {
+            let width = if matches!(mnemonic, Vprold | Vprord) {
+                4
+            } else {
+                8
+            };
+            let count = lane(b, 0, 8);
+            let left = matches!(mnemonic, Vprold | Vprolq);
+            lanewise(a, b, width, |x, _| rotate(x, count, width, left))
+        }
+        Vprolvd => lanewise(a, b, 4, |x, y| rotate(x, y, 4, true)),
+        Vprolvq => lanewise(a, b, 8, |x, y| rotate(x, y, 8, true)),
+        Vprorvd => lanewise(a, b, 4, |x, y| rotate(x, y, 4, false)),
+        Vprorvq => lanewise(a, b, 8, |x, y| rotate(x, y, 8, false)),
+        _ => unreachable!("{:?} is not a packed integer instruction", mnemonic),
+    }
+}
+
+/// Computes an instruction whose immediate selects elements: the shuffles (VPERMILPS and
+/// VPERMILPD among them, and AVX-512's of whole blocks), fixed blends, PALIGNR and VALIGN,
+/// and MPSADBW and VDBPSADBW, whose immediates pick what they compare.
+pub(super) fn shuffle(mnemonic: Mnemonic, a: &[u8], b: &[u8], imm: u8) -> Vec<u8> {
+    let select = |i: usize, bits: usize| (imm as usize >> (i * bits)) & ((1 << bits) - 1);
+    let mut result = a.to_vec();
+    match mnemonic {
+        Mnemonic::Pshufw => {
+            for i in 0..4 {
+                set_lane(&mut result, i, 2, lane(b, select(i, 2), 2));
+            }
+        }
+        Mnemonic::Pshufd
+        | Mnemonic::Vpermilps
+        | Mnemonic::Pshufhw
+        | Mnemonic::Pshuflw
+        | Mnemonic::Shufps => {
+            for start in (0..a.len()).step_by(16) {
+                let block = &b[start..start + 16];
+                let target = &mut result[start..start + 16];
+                match mnemonic {
+                    Mnemonic::Pshufd | Mnemonic::Vpermilps => {
+                        for i in 0..4 {
+                            set_lane(target, i, 4, lane(block, select(i, 2), 4));
+                        }
+                    }
+                    Mnemonic::Pshuflw | Mnemonic::Pshufhw => {
+                        // One half of the block is shuffled and the other copied
+                        target.copy_from_slice(block);
+                        let half = if mnemonic == Mnemonic::Pshufhw { 4 } else { 0 };
+                        for i in 0..4 {
+                            set_lane(target, half + i, 2, lane(block, half + select(i, 2), 2));
+                        }
+                    }
+                    _ => {
+                        // The low half comes from the destination, the high half from the
+                        // source
+                        let own: Vec<u8> = target.to_vec();
+                        for i in 0..4 {
+                            let from: &[u8] = if i < 2 { &own } else { block };
+                            set_lane(target, i, 4, lane(from, select(i, 2), 4));
+                        }
+                    }
+                }
+            }
+        }
+        Mnemonic::Shufpd => {
+            for (block, start) in (0..a.len()).step_by(16).enumerate() {
+                let low = lane(&a[start..], select(2 * block, 1), 8);
+                let high = lane(&b[start..], select(2 * block + 1, 1), 8);
+                set_lane(&mut result[start..], 0, 8, low);
+                set_lane(&mut result[start..], 1, 8, high);
+            }
+        }
+        Mnemonic::Vpermilpd => {
+            // One selector bit for each element, choosing within its own block
+            for i in 0..a.len() / 8 {
+                let element = lane(&b[i / 2 * 16..], select(i, 1), 8);
+                set_lane(&mut result, i, 8, element);
+            }
+        }
+        Mnemonic::Palignr => {
+            // Each block of the destination above the same block of the source, shifted right
+            // by whole bytes
+            let block = a.len().min(16);
+            for start in (0..a.len()).step_by(block) {
+                let pair = [&b[start..start + block], &a[start..start + block]].concat();
+                for i in 0..block {
+                    let source = imm as usize + i;
+                    result[start + i] = if source < 2 * block { pair[source] } else { 0 };
+                }
+            }
+        }
+        Mnemonic::Pblendw | Mnemonic::Blendps | Mnemonic::Blendpd | Mnemonic::Vpblendd => {
+            let width = match mnemonic {
+                Mnemonic::Pblendw => 2,
+                Mnemonic::Blendps | Mnemonic::Vpblendd => 4,
+                _ => 8,
+            };
+            // PBLENDW reuses its eight selector bits for each block
+            let lanes = a.len() / width;
+            let bits = lanes.min(8);
+            for i in 0..lanes {
+                if select(i % bits, 1) == 1 {
+                    set_lane(&mut result, i, width, lane(b, i, width));
+                }
+            }
+        }
+        Mnemonic::Mpsadbw => {
+            // Sums of absolute differences between a doubleword of the source and each of
+            // eight overlapping doublewords of the destination, block by block with three
+            // selector bits for each
+            for (block, start) in (0..a.len()).step_by(16).enumerate() {
+                let a_offset = start + 4 * select(3 * block + 2, 1);
+                let b_offset = start + 4 * ((imm as usize >> (3 * block)) & 3);
+                for i in 0..8 {
+                    let sum: u64 = (0..4)
+                        .map(|j| a[a_offset + i + j].abs_diff(b[b_offset + j]) as u64)
+                        .sum();
+                    set_lane(&mut result[start..], i, 2, sum);
+                }
+            }
+        }
+        Mnemonic::Valignd | Mnemonic::Valignq => {
+            // The first source above the second, shifted right by whole elements
+            let width = if mnemonic == Mnemonic::Valignd { 4 } else { 8 };
+            let count = a.len() / width;
+            let pair = [b, a].concat();
+            let shift = imm as usize & (count - 1);
+            for i in 0..count {
+                set_lane(&mut result, i, width, lane(&pair, shift + i, width));
+            }
+        }
+        Mnemonic::Vshuff32x4
+        | Mnemonic::Vshuff64x2
+        | Mnemonic::Vshufi32x4
+        | Mnemonic::Vshufi64x2 => {
+            // The low half of the blocks from the first source, the high half from the
+            // second, by two selector bits each (one for 256-bit vectors)
+            let blocks = a.len() / 16;
+            let bits = blocks / 2;
+            for i in 0..blocks {
+                let from = if i < blocks / 2 { a } else { b };
+                let start = 16 * select(i, bits);
+                result[16 * i..16 * (i + 1)].copy_from_slice(&from[start..start + 16]);
+            }
+        }
+        Mnemonic::Vdbpsadbw => {
+            // Sums of absolute differences of doublewords of the first source with four
+            // overlapping doublewords of the second, each block of which the immediate
+            // shuffles first as PSHUFD does
+            let b = shuffle(Mnemonic::Pshufd, b, b, imm);
+            for start in (0..a.len()).step_by(8) {
+                for i in 0..4 {
+                    let sum: u64 = (0..4)
+                        .map(|j| a[start + 4 * (i / 2) + j].abs_diff(b[start + i + j]) as u64)
+                        .sum();
+                    set_lane(&mut result[start..], i, 2, sum);
+                }
+            }
+        }
+        _ => unreachable!("{:?} is not a shuffle", mnemonic),
+    }
+    result
+}
+
+/// Takes each lane from `b` where the sign bit of the corresponding lane of `mask` is set,
+/// and from `a` elsewhere (the variable blends).
+pub(super) fn blend(a: &[u8], b: &[u8], mask: &[u8], width: usize) -> Vec<u8> {
+    let mut result = a.to_vec();
+    for i in 0..a.len() / width {
+        if lane(mask, i, width) >> (8 * width - 1) != 0 {
+            set_lane(&mut result, i, width, lane(b, i, width));
+        }
+    }
+    result
+}
+
+/// Gathers the sign bit of each `width`-byte lane into the low bits of the result.
+pub(super) fn sign_mask(vector: &[u8], width: usize) -> u64 {
+    (0..vector.len() / width).fold(0, |mask, i| {
+        mask | (lane(vector, i, width) >> (8 * width - 1) & 1) << i
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saturation() {
+        assert_eq!(saturate_signed(200, 1), 0x7F);
+        assert_eq!(saturate_signed(-200, 1), 0xFFFF_FFFF_FFFF_FF80);
+        assert_eq!(saturate_unsigned(-1, 2), 0);
+        assert_eq!(saturate_unsigned(70000, 2), 0xFFFF);
+    }
+
+    #[test]
+    fn unpack_and_pack_work_per_block() {
+        let a: Vec<u8> = (0..32).collect();
+        let b: Vec<u8> = (100..132).collect();
+        let low = integer(Mnemonic::Punpckldq, &a, &b);
+        assert_eq!(&low[..8], &[0, 1, 2, 3, 100, 101, 102, 103]);
+        assert_eq!(&low[16..24], &[16, 17, 18, 19, 116, 117, 118, 119]);
+
+        let words = [0x0080u16, 0xFF00, 0x0001, 0x7FFF]
+            .map(u16::to_le_bytes)
+            .concat();
+        assert_eq!(
+            integer(Mnemonic::Packuswb, &words, &words),
+            [0x80, 0, 1, 0xFF, 0x80, 0, 1, 0xFF]
+        );
+        assert_eq!(
+            integer(Mnemonic::Packsswb, &words, &words),
+            [0x7F, 0x80, 1, 0x7F, 0x7F, 0x80, 1, 0x7F]
+        );
+    }
+
+    #[test]
+    fn shuffles() {
+        let a: Vec<u8> = (0..16).collect();
+        let b: Vec<u8> = (16..32).collect();
+        // Reverse the doublewords of the source
+        assert_eq!(
+            shuffle(Mnemonic::Pshufd, &a, &b, 0x1B),
+            [28, 29, 30, 31, 24, 25, 26, 27, 20, 21, 22, 23, 16, 17, 18, 19]
+        );
+        // Destination elements 1 and 0, then source elements 3 and 2
+        assert_eq!(
+            shuffle(Mnemonic::Shufps, &a, &b, 0xB1),
+            [4, 5, 6, 7, 0, 1, 2, 3, 28, 29, 30, 31, 24, 25, 26, 27]
+        );
+        assert_eq!(
+            shuffle(Mnemonic::Shufpd, &a, &b, 0x01),
+            [8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23]
+        );
+        assert_eq!(sign_mask(&[0x80, 0x7F, 0xFF, 0], 1), 0b101);
+    }
+
+    #[test]
+    fn shift_counts_past_the_lane_width() {
+        let a = 0x8000_4000_0001_FFFFu64.to_le_bytes();
+        let count = |n: u64| n.to_le_bytes();
+        assert_eq!(integer(Mnemonic::Psrlw, &a, &count(16)), [0; 8]);
+        assert_eq!(
+            integer(Mnemonic::Psraw, &a, &count(100)),
+            0xFFFF_0000_0000_FFFFu64.to_le_bytes()
+        );
+        assert_eq!(
+            integer(Mnemonic::Psllq, &a, &count(4)),
+            0x0004_0000_001F_FFF0u64.to_le_bytes()
+        );
+    }
+}
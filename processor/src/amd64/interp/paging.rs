@@ -0,0 +1,315 @@
+//! Paging: the translation of linear addresses to physical ones through the 4-level page
+//! tables CR3 roots (5-level with CR4.LA57), the TLB caching those translations, and the page
+//! faults raised when a page isn't mapped or its entries forbid an access. Only long mode's
+//! paging is modeled; ELF programs run with it off, on the addresses they were linked at.
+
+use super::descriptor::Access;
+use super::system::EFER_NXE;
+use super::Amd64Interp;
+use crate::amd64::cpu_model::Feature;
+use crate::amd64::decode::OperandSize;
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+use std::collections::HashMap;
+
+// CR0
+pub(super) const CR0_WP: u64 = 1 << 16;
+pub(super) const CR0_PG: u64 = 1 << 31;
+
+// CR4
+pub(super) const CR4_PAE: u64 = 1 << 5;
+pub(super) const CR4_PGE: u64 = 1 << 7;
+pub(super) const CR4_LA57: u64 = 1 << 12;
+pub(super) const CR4_PCIDE: u64 = 1 << 17;
+
+/// The low bits of CR3 that select the PCID when CR4.PCIDE is set
+pub(super) const PCID_MASK: u64 = 0xFFF;
+/// Bit 63 of a value moved to CR3 with PCIDs enabled asks to keep the TLB entries of the
+/// PCID it selects
+pub(super) const CR3_NO_FLUSH: u64 = 1 << 63;
+
+// Page table entries
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const USER: u64 = 1 << 2;
+const ACCESSED: u64 = 1 << 5;
+const DIRTY: u64 = 1 << 6;
+/// A 1 GiB page in a PDPT entry, or a 2 MiB one in a page directory entry
+const PAGE_SIZE: u64 = 1 << 7;
+const GLOBAL: u64 = 1 << 8;
+const NO_EXECUTE: u64 = 1 << 63;
+/// The physical address bits of an entry, at most 52 of them
+const ADDRESS: u64 = 0x000F_FFFF_FFFF_F000;
+
+// Page fault error codes
+const PF_PRESENT: u32 = 1 << 0;
+const PF_WRITE: u32 = 1 << 1;
+const PF_USER: u32 = 1 << 2;
+const PF_RESERVED: u32 = 1 << 3;
+const PF_FETCH: u32 = 1 << 4;
+
+/// The PCID global translations are filed under, since they apply to every PCID
+const GLOBAL_PCID: u16 = u16::MAX;
+
+/// A cached translation of a 4K linear page.
+#[derive(Clone, Copy, Debug)]
+struct TlbEntry {
+    /// The physical address of the page
+    frame: u64,
+    /// The linear address and size of the page the translation is from, which may be a
+    /// large one: INVLPG anywhere in it flushes all of its 4K pages
+    base: u64,
+    size: u64,
+    /// The permissions every level of the walk allowed
+    writable: bool,
+    user: bool,
+    executable: bool,
+    /// Whether the page is already dirty, so writes to it needn't walk the tables again
+    dirty: bool,
+}
+
+/// The translation lookaside buffer, indexed by linear page number and PCID.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Tlb {
+    entries: HashMap<(u64, u16), TlbEntry>,
+}
+
+impl Tlb {
+    fn lookup(&self, page: u64, pcid: u16) -> Option<TlbEntry> {
+        let entry = self.entries.get(&(page, pcid));
+        entry
+            .or_else(|| self.entries.get(&(page, GLOBAL_PCID)))
+            .copied()
+    }
+
+    /// Flushes the translations of the page containing `addr`, in every PCID.
+    pub(super) fn flush_address(&mut self, addr: u64) {
+        self.entries
+            .retain(|_, entry| addr.wrapping_sub(entry.base) >= entry.size);
+    }
+
+    /// Flushes the translations of the page containing `addr` in `pcid`, global ones aside.
+    pub(super) fn flush_pcid_address(&mut self, pcid: u16, addr: u64) {
+        self.entries.retain(|&(_, entry_pcid), entry| {
+            entry_pcid != pcid || addr.wrapping_sub(entry.base) >= entry.size
+        });
+    }
+
+    /// Flushes every translation in `pcid`, global ones aside.
+    pub(super) fn flush_pcid(&mut self, pcid: u16) {
+        self.entries
+            .retain(|&(_, entry_pcid), _| entry_pcid != pcid);
+    }
+
+    /// Flushes every translation but the global ones.
+    pub(super) fn flush_non_global(&mut self) {
+        self.entries.retain(|&(_, pcid), _| pcid == GLOBAL_PCID);
+    }
+
+    pub(super) fn flush_all(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl Amd64Interp {
+    /// Whether paging is on (CR0.PG).
+    pub(super) fn paging(&self) -> bool {
+        self.regs.cr[0] & CR0_PG != 0
+    }
+
+    /// The current PCID: the low bits of CR3 if CR4.PCIDE is set, or 0.
+    pub(super) fn pcid(&self) -> u16 {
+        match self.regs.cr[4] & CR4_PCIDE {
+            0 => 0,
+            _ => (self.regs.cr[3] & PCID_MASK) as u16,
+        }
+    }
+
+    /// The width of linear addresses with paging on: 57 bits with 5-level paging and 48
+    /// with 4.
+    fn paged_address_bits(&self) -> u32 {
+        match self.regs.cr[4] & CR4_LA57 {
+            0 => 48,
+            _ => 57,
+        }
+    }
+
+    /// Translates linear address `addr` to a physical one for an access by code at CPL 3
+    /// (`user`) or a more privileged level. Without paging the two are the same. A failed
+    /// translation raises #PF, with the address in CR2, or #GP(0) for a non-canonical
+    /// address.
+    pub(super) fn translate(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        addr: u64,
+        access: Access,
+        user: bool,
+    ) -> Result<u64, Exception> {
+        if !self.paging() {
+            return Ok(addr);
+        }
+        let unused = 64 - self.paged_address_bits();
+        if ((addr << unused) as i64 >> unused) as u64 != addr {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let (page, pcid) = (addr >> 12, self.pcid());
+        if let Some(entry) = self.tlb.lookup(page, pcid) {
+            // A hit that doesn't allow the access walks the tables again, which either
+            // finds they've changed or raises the fault
+            let dirty_enough = access != Access::Write || entry.dirty;
+            if dirty_enough && self.permits(&entry, access, user) {
+                return Ok(entry.frame | (addr & 0xFFF));
+            }
+        }
+        let (entry, global) = self.walk(map, addr, access, user)?;
+        let key = match global {
+            true => (page, GLOBAL_PCID),
+            false => (page, pcid),
+        };
+        self.tlb.entries.insert(key, entry);
+        Ok(entry.frame | (addr & 0xFFF))
+    }
+
+    /// Whether a translation allows an access. Supervisor code may write read-only pages
+    /// unless CR0.WP is set.
+    fn permits(&self, entry: &TlbEntry, access: Access, user: bool) -> bool {
+        let privileged = user && !entry.user;
+        let allowed = match access {
+            Access::Read => true,
+            Access::Write => entry.writable || (!user && self.regs.cr[0] & CR0_WP == 0),
+            Access::Execute => entry.executable,
+        };
+        allowed && !privileged
+    }
+
+    /// Walks the page tables to translate `addr`, setting the accessed bits of the entries
+    /// used and, for a write, the dirty bit of the last. Returns the translation and
+    /// whether it's global.
+    fn walk(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        addr: u64,
+        access: Access,
+        user: bool,
+    ) -> Result<(TlbEntry, bool), Exception> {
+        let nxe = self.efer & EFER_NXE != 0;
+        let mut reserved = ADDRESS & !((1 << self.model.physical_address_bits) - 1);
+        if !nxe {
+            reserved |= NO_EXECUTE;
+        }
+        let levels = if self.regs.cr[4] & CR4_LA57 != 0 {
+            5
+        } else {
+            4
+        };
+        let mut table = self.regs.cr[3] & ADDRESS;
+        let (mut writable, mut user_page, mut executable) = (true, true, true);
+        let mut used = Vec::with_capacity(levels);
+        let fault = |this: &mut Self, code: u32| {
+            let fetch = access == Access::Execute && nxe;
+            this.regs.cr[2] = addr;
+            Exception::PageFault(
+                code | if access == Access::Write { PF_WRITE } else { 0 }
+                    | if user { PF_USER } else { 0 }
+                    | if fetch { PF_FETCH } else { 0 },
+            )
+        };
+        for level in (1..=levels).rev() {
+            let shift = 12 + 9 * (level as u32 - 1);
+            let entry_addr = table + ((addr >> shift) & 0x1FF) * 8;
+            let entry = map.read_u64(entry_addr);
+            if entry & PRESENT == 0 {
+                return Err(fault(self, 0));
+            }
+            let large = entry & PAGE_SIZE != 0 && level > 1;
+            // Large pages are 2 MiB or 1 GiB, and their frames have to be aligned to that;
+            // bit 12 of their entries is the PAT bit
+            let misaligned = large && entry & ADDRESS & ((1 << shift) - 1) & !(1 << 12) != 0;
+            let bad_size =
+                large && (level > 3 || (level == 3 && !self.model.has(Feature::Pdpe1gb)));
+            if entry & reserved != 0 || misaligned || bad_size {
+                return Err(fault(self, PF_PRESENT | PF_RESERVED));
+            }
+            writable &= entry & WRITABLE != 0;
+            user_page &= entry & USER != 0;
+            executable &= !(nxe && entry & NO_EXECUTE != 0);
+            used.push((entry_addr, entry));
+            if level == 1 || large {
+                let size = 1u64 << shift;
+                let translation = TlbEntry {
+                    frame: (entry & ADDRESS & !(size - 1)) | (addr & (size - 1) & !0xFFF),
+                    base: addr & !(size - 1),
+                    size,
+                    writable,
+                    user: user_page,
+                    executable,
+                    dirty: entry & DIRTY != 0 || access == Access::Write,
+                };
+                if !self.permits(&translation, access, user) {
+                    return Err(fault(self, PF_PRESENT));
+                }
+                // The tables are only marked once the access is known to be allowed
+                for (i, &(entry_addr, entry)) in used.iter().enumerate() {
+                    let mut marked = entry | ACCESSED;
+                    if i == used.len() - 1 && access == Access::Write {
+                        marked |= DIRTY;
+                    }
+                    if marked != entry {
+                        map.write_u64(entry_addr, marked);
+                    }
+                }
+                let global = entry & GLOBAL != 0 && self.regs.cr[4] & CR4_PGE != 0;
+                return Ok((translation, global));
+            }
+            table = entry & ADDRESS;
+        }
+        unreachable!("the last level of the page tables maps pages")
+    }
+
+    /// Translates the `bytes` bytes at `addr`, which may straddle pages, and returns the
+    /// physical address and length of each piece. Every page is translated before any is
+    /// accessed, so an access that faults has no effect.
+    pub(super) fn translate_range(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        addr: u64,
+        bytes: usize,
+        access: Access,
+        user: bool,
+    ) -> Result<Vec<(u64, usize)>, Exception> {
+        if !self.paging() {
+            return Ok(vec![(addr, bytes)]);
+        }
+        let mut pieces = Vec::new();
+        let mut done = 0;
+        while done < bytes {
+            let linear = addr.wrapping_add(done as u64);
+            let len = (0x1000 - (linear & 0xFFF) as usize).min(bytes - done);
+            pieces.push((self.translate(map, linear, access, user)?, len));
+            done += len;
+        }
+        Ok(pieces)
+    }
+
+    /// Reads memory as a supervisor-mode access whatever the CPL, as the processor's own
+    /// accesses to the descriptor tables and the TSS are.
+    pub(super) fn read_system(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        addr: u64,
+        size: OperandSize,
+    ) -> Result<u64, Exception> {
+        self.read_memory_as(map, addr, size, false)
+    }
+
+    /// Writes memory as a supervisor-mode access, like [`Amd64Interp::read_system`].
+    pub(super) fn write_system(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        addr: u64,
+        size: OperandSize,
+        value: u64,
+    ) -> Result<(), Exception> {
+        self.write_memory_as(map, addr, size, value, false)
+    }
+}
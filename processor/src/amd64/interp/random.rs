@@ -0,0 +1,51 @@
+//! RDRAND and RDSEED, which draw from a deterministic generator rather than the host's
+//! entropy so that a program behaves the same each time it's run with the same seed.
+
+use super::{flags, Amd64Interp};
+use crate::amd64::decode::Instruction;
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+/// The seed the generator starts from until [`Amd64Interp::seed_random`] sets another.
+pub(super) const DEFAULT_SEED: u64 = 0x853C_49E6_748F_EA9B;
+
+/// SplitMix64: a counter stepped by the golden ratio and scrambled, which passes the usual
+/// statistical tests and can start from any seed, zero included.
+#[derive(Clone, Debug)]
+pub(super) struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub(super) fn new(seed: u64) -> Random {
+        Random { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ z >> 30).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ z >> 27).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ z >> 31
+    }
+}
+
+impl Amd64Interp {
+    /// Restarts the generator RDRAND and RDSEED draw from at `seed`.
+    pub fn seed_random(&mut self, seed: u64) {
+        self.random = Random::new(seed);
+    }
+
+    /// RDRAND and RDSEED: write a random number to the destination and set CF to say it's
+    /// valid, which it always is; the other status flags are cleared.
+    pub(super) fn execute_random(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let value = self.random.next();
+        self.write_operand(map, &insn.operand(0), value)?;
+        self.regs.rflags = (self.regs.rflags & !flags::STATUS) | flags::CF;
+        Ok(())
+    }
+}
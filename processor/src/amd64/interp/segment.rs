@@ -0,0 +1,442 @@
+//! Segmentation. In real mode each segment starts at its selector times 16, and in protected
+//! mode where its descriptor says; segment loads, the far pointer loads (LDS, LES, LSS, LFS,
+//! LGS) and the far transfers (far JMP, CALL and RET, through call gates to more privileged
+//! code) set them. In 64-bit mode all that's left are the FS and GS bases: the thread-local
+//! storage of user programs and the per-CPU data of kernels. They're read and written by
+//! RDFSBASE, WRFSBASE, RDGSBASE and WRGSBASE, exchanged by SWAPGS, and set by a system call
+//! layer through [`Amd64Interp::arch_prctl`].
+
+use super::descriptor::{Descriptor, Gate, CALL_GATE, CALL_GATE16, CONFORMING};
+use super::Amd64Interp;
+use crate::amd64::decode::{Instruction, MemoryOperand, Mnemonic, Mode, Operand, OperandSize};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+// CR0
+pub(super) const CR0_PE: u64 = 1 << 0;
+
+// CR4
+pub(super) const CR4_FSGSBASE: u64 = 1 << 16;
+
+// Segment registers
+pub(super) const ES: usize = 0;
+pub(super) const CS: usize = 1;
+pub(super) const SS: usize = 2;
+pub(super) const DS: usize = 3;
+pub(super) const FS: usize = 4;
+pub(super) const GS: usize = 5;
+
+/// The segment state a far transfer changes, to put back if it faults partway.
+#[derive(Clone, Copy)]
+pub(super) struct SavedSegments {
+    sr: [u16; 8],
+    sr_base: [u64; 8],
+    segments: [Descriptor; 6],
+    rsp: u64,
+    rip: u64,
+    mode: Mode,
+}
+
+/// The codes of Linux's `arch_prctl` system call that set and get the segment bases.
+pub const ARCH_SET_GS: u64 = 0x1001;
+pub const ARCH_SET_FS: u64 = 0x1002;
+pub const ARCH_GET_FS: u64 = 0x1003;
+pub const ARCH_GET_GS: u64 = 0x1004;
+
+// The errors `arch_prctl` returns, negated
+const EPERM: i64 = 1;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+
+impl Amd64Interp {
+    /// Whether `addr` is canonical: its bits above the model's linear address width are
+    /// copies of the highest one within it.
+    pub fn is_canonical(&self, addr: u64) -> bool {
+        let unused = 64 - self.model.linear_address_bits as u32;
+        ((addr << unused) as i64 >> unused) as u64 == addr
+    }
+
+    /// The current privilege level: the low bits of the CS selector, or 0 in real mode.
+    pub(super) fn cpl(&self) -> u8 {
+        if self.real_mode() {
+            return 0;
+        }
+        (self.regs.sr[CS] & 3) as u8
+    }
+
+    /// Loads data or stack segment register `sreg` with `selector`. In real mode that only
+    /// sets its base, leaving the limit and attributes as they were; in protected mode the
+    /// descriptor is checked and cached.
+    pub(super) fn load_segment(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        sreg: usize,
+        selector: u16,
+    ) -> Result<(), Exception> {
+        if self.real_mode() {
+            let descriptor = Descriptor {
+                base: (selector as u64) << 4,
+                ..self.segments[sreg]
+            };
+            self.set_segment(sreg, selector, descriptor);
+            return Ok(());
+        }
+        // CS only changes through far transfers
+        if sreg == CS {
+            return Err(Exception::InvalidOpcode);
+        }
+        let descriptor = self.check_segment_load(map, sreg, selector, self.cpl())?;
+        self.set_segment(sreg, selector, descriptor);
+        Ok(())
+    }
+
+    /// Reads a far pointer, either an immediate or an offset of `size` followed by a 16-bit
+    /// selector in memory. Returns the selector and the offset.
+    fn far_pointer(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        operand: &Operand,
+        size: OperandSize,
+    ) -> Result<(u16, u64), Exception> {
+        match *operand {
+            Operand::Far(selector, offset) => Ok((selector, offset)),
+            Operand::Memory(mem, _) => {
+                let offset = self.read_operand(map, &Operand::Memory(mem, size))?;
+                let selector = MemoryOperand {
+                    displacement: mem.displacement.wrapping_add(size.bytes() as i64),
+                    ..mem
+                };
+                let selector =
+                    self.read_operand(map, &Operand::Memory(selector, OperandSize::R16))?;
+                Ok((selector as u16, offset))
+            }
+            _ => unreachable!("far pointers are immediates or in memory"),
+        }
+    }
+
+    pub(super) fn save_segments(&self) -> SavedSegments {
+        SavedSegments {
+            sr: self.regs.sr,
+            sr_base: self.regs.sr_base,
+            segments: self.segments,
+            rsp: self.regs.gprs[4],
+            rip: self.regs.rip,
+            mode: self.mode,
+        }
+    }
+
+    pub(super) fn restore_segments(&mut self, saved: SavedSegments) {
+        self.regs.sr = saved.sr;
+        self.regs.sr_base = saved.sr_base;
+        self.segments = saved.segments;
+        self.regs.gprs[4] = saved.rsp;
+        self.regs.rip = saved.rip;
+        self.mode = saved.mode;
+    }
+
+    /// Jumps to `offset` in a code segment of the current privilege level, raising #GP(0)
+    /// past its limit.
+    pub(super) fn jump_within(
+        &mut self,
+        selector: u16,
+        descriptor: Descriptor,
+        offset: u64,
+        size: OperandSize,
+    ) -> Result<(), Exception> {
+        let offset = offset & size.mask();
+        if self.real_mode() {
+            self.load_segment_base(selector);
+        } else {
+            self.enter_code_segment(selector, descriptor, self.cpl());
+        }
+        if self.mode != Mode::Bits64 && offset > self.segments[CS].limit as u64 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        self.regs.rip = offset;
+        Ok(())
+    }
+
+    /// Loads CS in real mode, where only its base changes.
+    fn load_segment_base(&mut self, selector: u16) {
+        let descriptor = Descriptor {
+            base: (selector as u64) << 4,
+            ..self.segments[CS]
+        };
+        self.set_segment(CS, selector, descriptor);
+    }
+
+    /// A far JMP or CALL (`call`) to `offset` in `selector`. In protected mode the selector
+    /// may name a code segment, entered at the current privilege level, or a call gate,
+    /// through which a CALL may enter a more privileged one on the stack the TSS gives it.
+    /// Hardware task switches aren't supported.
+    pub(super) fn far_transfer(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        selector: u16,
+        offset: u64,
+        size: OperandSize,
+        call: bool,
+    ) -> Result<(), Exception> {
+        let saved = self.save_segments();
+        self.far_transfer_unchecked(map, selector, offset, size, call)
+            .inspect_err(|_| self.restore_segments(saved))
+    }
+
+    fn far_transfer_unchecked(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        selector: u16,
+        offset: u64,
+        size: OperandSize,
+        call: bool,
+    ) -> Result<(), Exception> {
+        let (cs, ip) = (self.regs.sr[CS], self.regs.rip);
+        if self.real_mode() {
+            if call {
+                self.push(map, cs as u64, size)?;
+                self.push(map, ip, size)?;
+            }
+            return self.jump_within(selector, self.segments[CS], offset, size);
+        }
+        if selector & !3 == 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let error = selector & !3;
+        let cpl = self.cpl();
+        let rpl = (selector & 3) as u8;
+        let (raw, _) = self.read_descriptor(map, selector, false)?;
+        let descriptor = Descriptor::from_raw(raw, 0);
+        if descriptor.is_code() {
+            let allowed = if descriptor.has(CONFORMING) {
+                descriptor.dpl() <= cpl
+            } else {
+                rpl <= cpl && descriptor.dpl() == cpl
+            };
+            if !allowed {
+                return Err(Exception::GeneralProtection(error));
+            }
+            let descriptor = self.code_descriptor(map, selector)?;
+            if call {
+                self.push(map, cs as u64, size)?;
+                self.push(map, ip, size)?;
+            }
+            return self.jump_within(selector, descriptor, offset, size);
+        }
+        // Long mode only has 64-bit call gates, which take the 32-bit gates' type
+        let gate = descriptor.is_system(CALL_GATE)
+            || (!self.long_mode() && descriptor.is_system(CALL_GATE16));
+        if !gate {
+            return Err(Exception::GeneralProtection(error));
+        }
+        let (raw, upper) = self.read_descriptor(map, selector, self.long_mode())?;
+        let gate = Gate::from_raw(raw, upper);
+        if gate.dpl() < cpl || gate.dpl() < rpl {
+            return Err(Exception::GeneralProtection(error));
+        }
+        if !gate.present() {
+            return Err(Exception::SegmentNotPresent(error));
+        }
+        let target = self.code_descriptor(map, gate.selector)?;
+        let dpl = target.dpl();
+        let inner = !target.has(CONFORMING) && dpl < cpl;
+        if dpl > cpl || (!call && !target.has(CONFORMING) && dpl != cpl) {
+            return Err(Exception::GeneralProtection(gate.selector & !3));
+        }
+        let size = gate.size(self.long_mode());
+        if !call {
+            return self.jump_within(gate.selector, target, gate.offset, size);
+        }
+        if inner {
+            // The inner level's stack gets the caller's stack pointer and parameters
+            let (ss, ss_descriptor, sp) = self.inner_stack(map, dpl, 0)?;
+            let (old_ss, old_sp) = (self.regs.sr[SS], self.read_gpr(4, self.stack_size()));
+            let params = (0..gate.params as u64)
+                .map(|i| {
+                    let offset = old_sp.wrapping_add(i * size.bytes()) & self.stack_size().mask();
+                    self.read_memory(map, self.linear(SS, offset), size)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            self.enter_code_segment(gate.selector, target, dpl);
+            self.set_segment(SS, ss, ss_descriptor);
+            self.move_stack_pointer(sp);
+            self.push(map, old_ss as u64, size)?;
+            self.push(map, old_sp, size)?;
+            for &param in params.iter().rev() {
+                self.push(map, param, size)?;
+            }
+        } else {
+            self.enter_code_segment(gate.selector, target, cpl);
+        }
+        self.push(map, cs as u64, size)?;
+        self.push(map, ip, size)?;
+        self.jump_within(gate.selector, target, gate.offset, size)
+    }
+
+    /// Pops the return address of a far RET or IRET (`iret`, which also pops the flags)
+    /// and returns to it, releasing `release` bytes of the callee's arguments. A return to
+    /// an outer privilege level also pops the stack pointer and SS it had, as does any IRET
+    /// in 64-bit mode. Returns the popped flags, for IRET to restore.
+    pub(super) fn far_return(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        size: OperandSize,
+        release: u64,
+        iret: bool,
+    ) -> Result<u64, Exception> {
+        let saved = self.save_segments();
+        self.far_return_unchecked(map, size, release, iret)
+            .inspect_err(|_| self.restore_segments(saved))
+    }
+
+    fn far_return_unchecked(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        size: OperandSize,
+        release: u64,
+        iret: bool,
+    ) -> Result<u64, Exception> {
+        let offset = self.pop(map, size)?;
+        let selector = self.pop(map, size)? as u16;
+        let flags = if iret { self.pop(map, size)? } else { 0 };
+        let release_from_stack = |this: &mut Self| {
+            let sp = this.read_gpr(4, this.stack_size());
+            this.move_stack_pointer(sp.wrapping_add(release));
+        };
+        if self.real_mode() {
+            self.jump_within(selector, self.segments[CS], offset, size)?;
+            release_from_stack(self);
+            return Ok(flags);
+        }
+        let cpl = self.cpl();
+        let rpl = (selector & 3) as u8;
+        if rpl < cpl {
+            return Err(Exception::GeneralProtection(selector & !3));
+        }
+        let target = self.code_descriptor(map, selector)?;
+        let allowed = if target.has(CONFORMING) {
+            target.dpl() <= rpl
+        } else {
+            target.dpl() == rpl
+        };
+        if !allowed {
+            return Err(Exception::GeneralProtection(selector & !3));
+        }
+        release_from_stack(self);
+        if rpl == cpl && !(iret && self.mode == Mode::Bits64) {
+            self.jump_within(selector, target, offset, size)?;
+            return Ok(flags);
+        }
+        let sp = self.pop(map, size)?;
+        let ss = self.pop(map, size)? as u16;
+        let ss_descriptor = self.check_segment_load(map, SS, ss, rpl)?;
+        self.enter_code_segment(selector, target, rpl);
+        self.set_segment(SS, ss, ss_descriptor);
+        self.move_stack_pointer(sp);
+        release_from_stack(self);
+        if rpl > cpl {
+            self.clear_inner_segments();
+        }
+        self.jump_within(selector, target, offset, size)?;
+        Ok(flags)
+    }
+
+    pub(super) fn execute_far(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        let size = insn.operand_size;
+        match insn.mnemonic {
+            Jmpf | Callf => {
+                let (selector, offset) = self.far_pointer(map, &insn.operand(0), size)?;
+                self.far_transfer(map, selector, offset, size, insn.mnemonic == Callf)?;
+            }
+            Retf => {
+                // RETF imm16 also releases the callee's arguments
+                let release = insn.immediate().unwrap_or(0);
+                self.far_return(map, size, release, false)?;
+            }
+            Les | Lds | Lss | Lfs | Lgs => {
+                let (selector, offset) = self.far_pointer(map, &insn.operand(1), size)?;
+                let sreg = match insn.mnemonic {
+                    Les => ES,
+                    Lds => DS,
+                    Lss => SS,
+                    Lfs => FS,
+                    _ => GS,
+                };
+                self.load_segment(map, sreg, selector)?;
+                self.write_operand(map, &insn.operand(0), offset)?;
+            }
+            mnemonic => unreachable!("{} is not a far transfer", mnemonic),
+        }
+        Ok(())
+    }
+
+    pub(super) fn execute_segment(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        if insn.mnemonic == Swapgs {
+            if self.cpl() != 0 {
+                return Err(Exception::GeneralProtection(0));
+            }
+            std::mem::swap(&mut self.regs.sr_base[GS], &mut self.kernel_gs_base);
+            return Ok(());
+        }
+        // The operating system has to enable the others, since it then has to preserve the
+        // bases user code sets
+        if self.regs.cr[4] & CR4_FSGSBASE == 0 {
+            return Err(Exception::InvalidOpcode);
+        }
+        let operand = insn.operand(0);
+        let sreg = match insn.mnemonic {
+            Rdfsbase | Wrfsbase => FS,
+            _ => GS,
+        };
+        match insn.mnemonic {
+            Rdfsbase | Rdgsbase => self.write_operand(map, &operand, self.regs.sr_base[sreg])?,
+            _ => {
+                let base = self.read_operand(map, &operand)?;
+                if !self.is_canonical(base) {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                self.regs.sr_base[sreg] = base;
+            }
+        }
+        Ok(())
+    }
+
+    /// Linux's `arch_prctl` system call, for a system call layer to forward to: sets the FS
+    /// or GS base to `addr`, which must be a user address, or stores it there. Returns 0, or
+    /// an error number negated.
+    pub fn arch_prctl(&mut self, map: &mut dyn MemoryMap, code: u64, addr: u64) -> i64 {
+        let sreg = match code {
+            ARCH_SET_FS | ARCH_GET_FS => FS,
+            ARCH_SET_GS | ARCH_GET_GS => GS,
+            _ => return -EINVAL,
+        };
+        match code {
+            ARCH_SET_FS | ARCH_SET_GS => {
+                // User addresses are the lower half of the canonical ones
+                if addr >> (self.model.linear_address_bits - 1) != 0 {
+                    return -EPERM;
+                }
+                self.regs.sr_base[sreg] = addr;
+            }
+            _ => {
+                let base = self.regs.sr_base[sreg];
+                if self
+                    .write_memory(map, addr, OperandSize::R64, base)
+                    .is_err()
+                {
+                    return -EFAULT;
+                }
+            }
+        }
+        0
+    }
+}
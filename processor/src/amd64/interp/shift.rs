@@ -0,0 +1,46 @@
+//! Shifts, rotates and the double-precision shifts SHLD/SHRD.
+
+use super::{flags, Amd64Interp};
+use crate::amd64::decode::{Instruction, Mnemonic};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+impl Amd64Interp {
+    pub(super) fn execute_shift(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let dst = insn.operand(0);
+        let size = dst.size().unwrap();
+        let a = self.read_operand(map, &dst)?;
+        let result = match insn.mnemonic {
+            Mnemonic::Shld | Mnemonic::Shrd => {
+                let fill = self.read_operand(map, &insn.operand(1))?;
+                let count = flags::mask_count(self.read_operand(map, &insn.operand(2))?, size);
+                let rflags = &mut self.regs.rflags;
+                if insn.mnemonic == Mnemonic::Shld {
+                    flags::shld(a, fill, count, size, rflags)
+                } else {
+                    flags::shrd(a, fill, count, size, rflags)
+                }
+            }
+            mnemonic => {
+                let count = flags::mask_count(self.read_operand(map, &insn.operand(1))?, size);
+                let rflags = &mut self.regs.rflags;
+                match mnemonic {
+                    Mnemonic::Shl => flags::shl(a, count, size, rflags),
+                    Mnemonic::Shr => flags::shr(a, count, size, rflags),
+                    Mnemonic::Sar => flags::sar(a, count, size, rflags),
+                    Mnemonic::Rol => flags::rol(a, count, size, rflags),
+                    Mnemonic::Ror => flags::ror(a, count, size, rflags),
+                    Mnemonic::Rcl => flags::rcl(a, count, size, rflags),
+                    Mnemonic::Rcr => flags::rcr(a, count, size, rflags),
+                    _ => unreachable!("{} is not a shift or rotate", mnemonic),
+                }
+            }
+        };
+        // A shift by a masked count of 0 still writes (and so zero-extends) a 32-bit register
+        self.write_operand(map, &dst, result)
+    }
+}
@@ -0,0 +1,781 @@
+//! SSE through SSE4.2, AVX2 and AVX-512: the XMM, YMM and ZMM registers, the opmask
+//! registers and MXCSR, and the vector instructions they share with MMX, whose operands are
+//! handled here as byte vectors of whatever size they name.
+
+use super::descriptor::Access;
+use super::x87::{CR0_EM, CR0_TS};
+use super::{flags, packed, Amd64Interp};
+use crate::amd64::decode::{
+    Evex, Instruction, MemoryOperand, Mnemonic, Operand, OperandSize, Prefixes, Register,
+};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+use std::cmp::Ordering;
+
+mod avx;
+mod avx512;
+mod crypto;
+mod float;
+mod pcmpstr;
+mod xsave;
+
+// MXCSR
+const MXCSR_EXCEPTIONS: u32 = 0x3F;
+const DAZ: u32 = 1 << 6;
+const MASK_SHIFT: u32 = 7;
+const RC: u32 = 3 << RC_SHIFT;
+const RC_SHIFT: u32 = 13;
+const FTZ: u32 = 1 << 15;
+/// The MXCSR bits software may set (MXCSR_MASK); setting any other raises #GP
+const MXCSR_MASK: u32 = 0xFFFF;
+/// Every exception masked, round to nearest
+pub(super) const DEFAULT_MXCSR: u32 = 0x1F80;
+
+// CR4
+pub(super) const CR4_OSFXSR: u64 = 1 << 9;
+pub(super) const CR4_OSXMMEXCPT: u64 = 1 << 10;
+pub(super) const CR4_OSXSAVE: u64 = 1 << 18;
+
+/// The XCR0 bits that enable the SSE and AVX state, both needed by VEX-encoded instructions
+const XCR0_SSE_AVX: u64 = 0b110;
+/// Those that EVEX-encoded and opmask instructions need besides: the opmask registers, the
+/// upper halves of ZMM0-ZMM15 and ZMM16-ZMM31
+const XCR0_AVX512: u64 = 0b1110_0110;
+
+/// Size of the FXSAVE area
+const FXSAVE_SIZE: usize = 512;
+
+impl Amd64Interp {
+    pub(super) fn execute_sse(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        match insn.mnemonic {
+            Mnemonic::Fxsave | Mnemonic::Fxrstor => {
+                if self.regs.cr[0] & (CR0_EM | CR0_TS) != 0 {
+                    return Err(Exception::DeviceNotAvailable);
+                }
+                self.fxsave(map, insn)
+            }
+            Mnemonic::Xsave | Mnemonic::Xsaveopt | Mnemonic::Xsavec | Mnemonic::Xrstor => {
+                self.xsave(map, insn)
+            }
+            _ if insn.prefixes.contains(Prefixes::EVEX) || insn.is_opmask() => {
+                self.avx_available(XCR0_AVX512)?;
+                self.execute_vector(map, insn)
+            }
+            _ if insn.prefixes.contains(Prefixes::VEX) => {
+                self.avx_available(XCR0_SSE_AVX)?;
+                match insn.mnemonic {
+                    Mnemonic::Vzeroupper | Mnemonic::Vzeroall => {
+                        self.vzero(insn.mnemonic == Mnemonic::Vzeroall);
+                        Ok(())
+                    }
+                    _ => self.execute_vector(map, insn),
+                }
+            }
+            _ => {
+                self.sse_available()?;
+                self.execute_vector(map, insn)
+            }
+        }
+    }
+
+    /// Faults unless the OS has enabled the given XCR0 state components through XSAVE, and
+    /// the FPU is this task's.
+    fn avx_available(&self, components: u64) -> Result<(), Exception> {
+        if self.regs.cr[4] & CR4_OSXSAVE == 0 || self.xcr0 & components != components {
+            return Err(Exception::InvalidOpcode);
+        }
+        if self.regs.cr[0] & CR0_TS != 0 {
+            return Err(Exception::DeviceNotAvailable);
+        }
+        Ok(())
+    }
+
+    /// Faults unless the OS has enabled SSE and the FPU is this task's.
+    pub(super) fn sse_available(&self) -> Result<(), Exception> {
+        if self.regs.cr[0] & CR0_EM != 0 || self.regs.cr[4] & CR4_OSFXSR == 0 {
+            return Err(Exception::InvalidOpcode);
+        }
+        if self.regs.cr[0] & CR0_TS != 0 {
+            return Err(Exception::DeviceNotAvailable);
+        }
+        Ok(())
+    }
+
+    fn vector(&self, n: u8) -> &[u8] {
+        bytemuck::bytes_of(&self.regs.zmm[n as usize])
+    }
+
+    fn vector_mut(&mut self, n: u8) -> &mut [u8] {
+        bytemuck::bytes_of_mut(&mut self.regs.zmm[n as usize])
+    }
+
+    /// Reads an operand of a vector instruction as bytes, as many as its size. Besides XMM
+    /// registers and memory that may be an MMX or general-purpose register, an immediate, or
+    /// an element in memory broadcast to the whole vector.
+    pub(super) fn read_vector(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        operand: &Operand,
+    ) -> Result<Vec<u8>, Exception> {
+        let size = operand.size().unwrap().bytes() as usize;
+        match *operand {
+            Operand::Register(Register::Vector(n), _) => Ok(self.vector(n)[..size].to_vec()),
+            Operand::Memory(mem, _) => {
+                let addr = self.checked_address(&mem, size as u64, Access::Read)?;
+                let mut data = vec![0; size];
+                self.read_bytes(map, addr, &mut data)?;
+                Ok(data)
+            }
+            Operand::Broadcast(mem, element, _) => {
+                let addr = self.checked_address(&mem, element.bytes(), Access::Read)?;
+                let mut data = vec![0; element.bytes() as usize];
+                self.read_bytes(map, addr, &mut data)?;
+                Ok(data.iter().cycle().take(size).copied().collect())
+            }
+            _ => Ok(self.read_operand(map, operand)?.to_le_bytes()[..size].to_vec()),
+        }
+    }
+
+    /// Writes `data` to an operand, truncated or zero-extended to its size. A register
+    /// operand narrower than its register leaves the rest of the register alone.
+    pub(super) fn write_vector(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        operand: &Operand,
+        data: &[u8],
+    ) -> Result<(), Exception> {
+        let size = operand.size().unwrap().bytes() as usize;
+        let mut bytes = data[..data.len().min(size)].to_vec();
+        bytes.resize(size, 0);
+        match *operand {
+            Operand::Register(Register::Vector(n), _) => {
+                self.vector_mut(n)[..size].copy_from_slice(&bytes);
+                Ok(())
+            }
+            Operand::Memory(mem, _) => {
+                let addr = self.checked_address(&mem, size as u64, Access::Write)?;
+                self.write_bytes(map, addr, &bytes)
+            }
+            _ => {
+                let mut value = [0; 8];
+                value[..size].copy_from_slice(&bytes);
+                self.write_operand(map, operand, u64::from_le_bytes(value))
+            }
+        }
+    }
+
+    /// Executes an SSE, AVX or MMX instruction once its availability has been checked.
+    pub(super) fn execute_vector(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        if insn.is_opmask() {
+            return self.execute_opmask(map, insn);
+        }
+        self.check_alignment(insn)?;
+        // The destination, the first source (the destination itself unless VEX.vvvv names
+        // another) and the second
+        let (dst, first, src) = if insn.is_nds() {
+            let first = match (insn.operand(0), insn.operand(1)) {
+                // A scalar operation reads as much of its first source as it writes
+                (Operand::Register(Register::Vector(_), size), Operand::Register(reg, _)) => {
+                    Operand::Register(reg, size)
+                }
+                (_, first) => first,
+            };
+            (insn.operand(0), first, insn.operand(2))
+        } else {
+            (insn.operand(0), insn.operand(0), insn.operand(1))
+        };
+        // What a VEX-encoded instruction leaves in the low 128 bits past a scalar result: the
+        // rest of its first source, or of the destination for FMA3, which reads it too
+        let merge = match (first, dst) {
+            (_, Operand::Register(Register::Vector(n), _)) if float::is_fma(insn.mnemonic) => {
+                self.vector(n)[..16].to_vec()
+            }
+            (Operand::Register(Register::Vector(n), _), _) if insn.is_nds() => {
+                self.vector(n)[..16].to_vec()
+            }
+            _ => vec![0; 16],
+        };
+        let masked_off = self.masked_off(insn, &dst, &first);
+        match insn.mnemonic {
+            Ldmxcsr => {
+                let value = self.read_operand(map, &dst)? as u32;
+                if value & !MXCSR_MASK != 0 {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                self.regs.fpu.mxcsr = value;
+            }
+            Stmxcsr => self.write_operand(map, &dst, self.regs.fpu.mxcsr as u64)?,
+            Movaps | Movups | Movapd | Movupd | Movdqa | Movdqu | Movntps | Movntpd | Movntdq
+            | Movntq | Movntdqa | Lddqu | Movlps | Movlpd | Movss | Movsd | Movd | Movq
+            | Movq2dq | Movdq2q | Vmovdqa32 | Vmovdqa64 | Vmovdqu8 | Vmovdqu16 | Vmovdqu32
+            | Vmovdqu64 => {
+                let data = self.read_masked(map, insn, &src)?;
+                // Every form of MOVD and MOVQ, and MOVSS and MOVSD from memory, clear the
+                // rest of an XMM destination
+                let zero_extend = match insn.mnemonic {
+                    Movd | Movq | Movq2dq => true,
+                    Movss | Movsd => matches!(src, Operand::Memory(..)),
+                    _ => false,
+                };
+                let dst = match dst {
+                    Operand::Register(Register::Vector(n), _) if zero_extend => {
+                        Operand::Register(Register::Vector(n), OperandSize::R128)
+                    }
+                    _ => dst,
+                };
+                self.write_masked(map, insn, &dst, &data)?;
+            }
+            Movhps | Movhpd | Movhlps | Movlhps => {
+                // Each moves a quadword into or out of the high half of an XMM register
+                let source = match src {
+                    Operand::Register(Register::Vector(n), _) => self.vector(n)[..16].to_vec(),
+                    _ => self.read_vector(map, &src)?,
+                };
+                let quadword = match (insn.mnemonic, dst) {
+                    (Movhlps, _) | (_, Operand::Memory(..)) => &source[8..16],
+                    _ => &source[..8],
+                };
+                match (insn.mnemonic, dst) {
+                    (Movhlps, _) | (_, Operand::Memory(..)) => {
+                        self.write_vector(map, &dst, quadword)?
+                    }
+                    (_, Operand::Register(Register::Vector(n), _)) => {
+                        // The VEX forms take the low half from their first source
+                        if insn.is_nds() {
+                            let low = self.read_vector(map, &first)?;
+                            self.vector_mut(n)[..8].copy_from_slice(&low[..8]);
+                        }
+                        self.vector_mut(n)[8..16].copy_from_slice(quadword)
+                    }
+                    _ => unreachable!("{} has an XMM or memory destination", insn),
+                }
+            }
+            Addps | Addpd | Addss | Addsd | Subps | Subpd | Subss | Subsd | Mulps | Mulpd
+            | Mulss | Mulsd | Divps | Divpd | Divss | Divsd | Minps | Minpd | Minss | Minsd
+            | Maxps | Maxpd | Maxss | Maxsd | Sqrtps | Sqrtpd | Sqrtss | Sqrtsd | Rcpps | Rcpss
+            | Rsqrtps | Rsqrtss | Addsubps | Addsubpd | Haddps | Haddpd | Hsubps | Hsubpd
+            | Vrcp14ps | Vrcp14pd | Vrcp14ss | Vrcp14sd | Vrsqrt14ps | Vrsqrt14pd | Vrsqrt14ss
+            | Vrsqrt14sd | Vgetexpps | Vgetexppd | Vgetexpss | Vgetexpsd | Vscalefps
+            | Vscalefpd | Vscalefss | Vscalefsd => {
+                let a = self.read_vector(map, &first)?;
+                let b = self.read_vector(map, &src)?;
+                let mut cx = self.float_context(insn);
+                let result = float::arithmetic(insn.mnemonic, &a, &b, &mut cx);
+                self.simd_exceptions(insn, &cx)?;
+                self.write_vector(map, &dst, &result)?;
+            }
+            mnemonic if float::is_fma(mnemonic) => {
+                let a = self.read_vector(map, &dst)?;
+                let b = self.read_vector(map, &first)?;
+                let c = self.read_vector(map, &src)?;
+                let mut cx = self.float_context(insn);
+                let result = float::fused_multiply_add(mnemonic, [&a, &b, &c], &mut cx);
+                self.simd_exceptions(insn, &cx)?;
+                self.write_vector(map, &dst, &result)?;
+            }
+            Vfixupimmps | Vfixupimmpd | Vfixupimmss | Vfixupimmsd => {
+                let d = self.read_vector(map, &dst)?;
+                let a = self.read_vector(map, &first)?;
+                let c = self.read_vector(map, &src)?;
+                let imm = insn.immediate().unwrap() as u8;
+                let mut cx = self.float_context(insn);
+                let result = float::fix_up(insn.mnemonic, [&d, &a, &c], imm, &mut cx);
+                self.simd_exceptions(insn, &cx)?;
+                self.write_vector(map, &dst, &result)?;
+            }
+            Vcvtph2ps | Vcvtps2ph => {
+                let source = self.read_vector(map, &src)?;
+                let mut cx = self.float_context(insn);
+                let result = match insn.mnemonic {
+                    Vcvtph2ps => {
+                        let len = dst.size().unwrap().bytes() as usize;
+                        float::half_to_single(&source, len, &mut cx)
+                    }
+                    _ => {
+                        let imm = insn.immediate().unwrap() as u8;
+                        float::single_to_half(&source, imm, &mut cx)
+                    }
+                };
+                self.simd_exceptions(insn, &cx)?;
+                self.write_masked(map, insn, &dst, &result)?;
+            }
+            Roundps | Roundpd | Roundss | Roundsd | Dpps | Dppd | Vrndscaleps | Vrndscalepd
+            | Vrndscaless | Vrndscalesd | Vreduceps | Vreducepd | Vreducess | Vreducesd
+            | Vgetmantps | Vgetmantpd | Vgetmantss | Vgetmantsd | Vrangeps | Vrangepd
+            | Vrangess | Vrangesd => {
+                let a = self.read_vector(map, &first)?;
+                let b = self.read_vector(map, &src)?;
+                let imm = insn.immediate().unwrap() as u8;
+                let mut cx = self.float_context(insn);
+                let result = match insn.mnemonic {
+                    Dpps | Dppd => float::dot_product(insn.mnemonic, &a, &b, imm, &mut cx),
+                    Vgetmantps | Vgetmantpd | Vgetmantss | Vgetmantsd => {
+                        float::mantissa(insn.mnemonic, &a, &b, imm, &mut cx)
+                    }
+                    Vrangeps | Vrangepd | Vrangess | Vrangesd => {
+                        float::range(insn.mnemonic, &a, &b, imm, &mut cx)
+                    }
+                    _ => float::round(insn.mnemonic, &a, &b, imm, &mut cx),
+                };
+                self.simd_exceptions(insn, &cx)?;
+                self.write_vector(map, &dst, &result)?;
+            }
+            // The sign bits of the elements, to a general-purpose register or, with AVX-512,
+            // an opmask register
+            Pmovmskb | Movmskps | Movmskpd | Vpmovb2m | Vpmovw2m | Vpmovd2m | Vpmovq2m => {
+                let vector = self.read_vector(map, &src)?;
+                let width = match insn.mnemonic {
+                    Pmovmskb | Vpmovb2m => 1,
+                    Vpmovw2m => 2,
+                    Movmskps | Vpmovd2m => 4,
+                    _ => 8,
+                };
+                self.write_operand(map, &dst, packed::sign_mask(&vector, width))?;
+            }
+            // The AVX-512 comparisons write an opmask register
+            _ if matches!(dst, Operand::Register(Register::Opmask(_), _)) => {
+                self.compare_into_mask(map, insn, &dst, &first, &src)?
+            }
+            Cmpps | Cmppd | Cmpss | Cmpsd => {
+                let a = self.read_vector(map, &first)?;
+                let b = self.read_vector(map, &src)?;
+                // VEX extended the predicate from three bits to five
+                let predicate = insn.immediate().unwrap() as u8
+                    & if insn.prefixes.contains(Prefixes::VEX) {
+                        0x1F
+                    } else {
+                        0x07
+                    };
+                let mut cx = self.float_context(insn);
+                let result = float::compare(insn.mnemonic, &a, &b, predicate, &mut cx);
+                self.simd_exceptions(insn, &cx)?;
+                self.write_vector(map, &dst, &result)?;
+            }
+            Comiss | Comisd | Ucomiss | Ucomisd => {
+                let a = self.read_vector(map, &first)?;
+                let b = self.read_vector(map, &src)?;
+                let mut cx = self.float_context(insn);
+                let ordering = float::ordered(insn.mnemonic, &a, &b, &mut cx);
+                self.simd_exceptions(insn, &cx)?;
+                let status = match ordering {
+                    Some(Ordering::Greater) => 0,
+                    Some(Ordering::Less) => flags::CF,
+                    Some(Ordering::Equal) => flags::ZF,
+                    None => flags::ZF | flags::PF | flags::CF,
+                };
+                self.regs.rflags = (self.regs.rflags & !flags::STATUS) | status;
+            }
+            Cvtps2pd | Cvtss2sd | Cvtpd2ps | Cvtsd2ss | Cvtdq2ps | Cvtpi2ps | Cvtdq2pd
+            | Cvtpi2pd | Cvtsi2ss | Cvtsi2sd | Cvtps2dq | Cvtps2pi | Cvttps2dq | Cvttps2pi
+            | Cvtpd2dq | Cvtpd2pi | Cvttpd2dq | Cvttpd2pi | Cvtss2si | Cvttss2si | Cvtsd2si
+            | Cvttsd2si | Vcvtps2udq | Vcvtpd2udq | Vcvttps2udq | Vcvttpd2udq | Vcvtudq2ps
+            | Vcvtudq2pd | Vcvtss2usi | Vcvtsd2usi | Vcvttss2usi | Vcvttsd2usi | Vcvtusi2ss
+            | Vcvtusi2sd | Vcvtps2qq | Vcvtpd2qq | Vcvttps2qq | Vcvttpd2qq | Vcvtps2uqq
+            | Vcvtpd2uqq | Vcvttps2uqq | Vcvttpd2uqq | Vcvtqq2ps | Vcvtqq2pd | Vcvtuqq2ps
+            | Vcvtuqq2pd => {
+                let source = self.read_vector(map, &src)?;
+                let len = dst.size().unwrap().bytes() as usize;
+                let mut cx = self.float_context(insn);
+                let result = float::convert(insn.mnemonic, &source, len, &mut cx);
+                self.simd_exceptions(insn, &cx)?;
+                // Packed destinations are cleared past the converted elements, while scalar
+                // ones name just the low element and keep the rest of the register
+                self.write_vector(map, &dst, &result)?;
+            }
+            Pshufw | Pshufd | Pshufhw | Pshuflw | Shufps | Shufpd | Palignr | Pblendw | Blendps
+            | Blendpd | Mpsadbw | Valignd | Valignq | Vshuff32x4 | Vshuff64x2 | Vshufi32x4
+            | Vshufi64x2 | Vdbpsadbw => {
+                let a = self.read_vector(map, &first)?;
+                let b = self.read_vector(map, &src)?;
+                let imm = insn.immediate().unwrap() as u8;
+                let result = packed::shuffle(insn.mnemonic, &a, &b, imm);
+                self.write_vector(map, &dst, &result)?;
+            }
+            Pblendvb | Blendvps | Blendvpd => {
+                let a = self.read_vector(map, &first)?;
+                let b = self.read_vector(map, &src)?;
+                // XMM0, or a register the immediate names
+                let mask = self.read_vector(map, insn.operands().last().unwrap())?;
+                let width = match insn.mnemonic {
+                    Pblendvb => 1,
+                    Blendvps => 4,
+                    _ => 8,
+                };
+                let result = packed::blend(&a, &b, &mask, width);
+                self.write_vector(map, &dst, &result)?;
+            }
+            Ptest => {
+                let a = self.read_vector(map, &first)?;
+                let b = self.read_vector(map, &src)?;
+                let none = |f: fn(u8, u8) -> u8| a.iter().zip(&b).all(|(&x, &y)| f(x, y) == 0);
+                let mut status = 0;
+                if none(|x, y| x & y) {
+                    status |= flags::ZF;
+                }
+                if none(|x, y| !x & y) {
+                    status |= flags::CF;
+                }
+                self.regs.rflags = (self.regs.rflags & !flags::STATUS) | status;
+            }
+            Pinsrb | Pinsrw | Pinsrd | Pinsrq => {
+                let mut vector = self.read_vector(map, &first)?;
+                let value = self.read_operand(map, &src)?;
+                let width = element_width(insn.mnemonic);
+                let lanes = vector.len() / width;
+                let i = insn.immediate().unwrap() as usize & (lanes - 1);
+                packed::set_lane(&mut vector, i, width, value);
+                self.write_vector(map, &dst, &vector)?;
+            }
+            Pextrb | Pextrw | Pextrd | Pextrq | Extractps => {
+                let vector = self.read_vector(map, &src)?;
+                let width = element_width(insn.mnemonic);
+                let lanes = vector.len() / width;
+                let i = insn.immediate().unwrap() as usize & (lanes - 1);
+                self.write_operand(map, &dst, packed::lane(&vector, i, width))?;
+            }
+            Insertps => {
+                // The immediate picks the source element of a register source, the element
+                // it replaces, and a mask of elements to clear
+                let mut vector = self.read_vector(map, &first)?;
+                let source = self.read_vector(map, &src)?;
+                let imm = insn.immediate().unwrap() as usize;
+                let element = match src {
+                    Operand::Register(..) => packed::lane(&source, imm >> 6, 4),
+                    _ => packed::lane(&source, 0, 4),
+                };
+                packed::set_lane(&mut vector, imm >> 4 & 3, 4, element);
+                for i in 0..4 {
+                    if imm >> i & 1 != 0 {
+                        packed::set_lane(&mut vector, i, 4, 0);
+                    }
+                }
+                self.write_vector(map, &dst, &vector)?;
+            }
+            Pcmpestri | Pcmpestrm | Pcmpistri | Pcmpistrm => {
+                let a = self.read_vector(map, &first)?;
+                let b = self.read_vector(map, &src)?;
+                let imm = insn.immediate().unwrap() as u8;
+                // The explicit lengths are in EAX and EDX, or RAX and RDX with REX.W
+                let size = if insn.prefixes.contains(Prefixes::REX_W) {
+                    OperandSize::R64
+                } else {
+                    OperandSize::R32
+                };
+                let length = |n| flags::sign_extend(self.read_gpr(n, size), size) as i64;
+                let lengths =
+                    matches!(insn.mnemonic, Pcmpestri | Pcmpestrm).then(|| (length(0), length(2)));
+                let comparison = pcmpstr::compare(&a, &b, lengths, imm);
+                self.regs.rflags = (self.regs.rflags & !flags::STATUS) | comparison.flags();
+                match insn.mnemonic {
+                    Pcmpestri | Pcmpistri => {
+                        self.write_gpr(1, OperandSize::R32, comparison.index())
+                    }
+                    _ => {
+                        let vex = insn.prefixes.contains(Prefixes::VEX);
+                        let ymm0 = self.vector_mut(0);
+                        ymm0[..16].copy_from_slice(&comparison.mask());
+                        if vex {
+                            ymm0[16..].fill(0);
+                        }
+                    }
+                }
+            }
+            Maskmovq | Maskmovdqu => {
+                let data = self.read_vector(map, &first)?;
+                let mask = self.read_vector(map, &src)?;
+                // The destination is implicit: DS:rDI, unless the segment is overridden
+                let mem = MemoryOperand {
+                    base: Some(7),
+                    index: None,
+                    scale: 1,
+                    displacement: 0,
+                    rip_relative: false,
+                    address_size: insn.address_size,
+                    vector_index: None,
+                    segment: insn.segment,
+                };
+                for (i, (&byte, &select)) in data.iter().zip(&mask).enumerate() {
+                    if select & 0x80 != 0 {
+                        let addr = self.checked_element(&mem, i as u64, 1, Access::Write)?;
+                        self.write_bytes(map, addr, &[byte])?;
+                    }
+                }
+            }
+            Vpgatherdd | Vpgatherdq | Vpgatherqd | Vpgatherqq | Vgatherdps | Vgatherdpd
+            | Vgatherqps | Vgatherqpd
+                if insn.evex.is_some() =>
+            {
+                self.execute_avx512(map, insn, &dst, &first, &src)?
+            }
+            Vbroadcastss | Vbroadcastsd | Vbroadcastf128 | Vbroadcasti128 | Vpbroadcastb
+            | Vpbroadcastw | Vpbroadcastd | Vpbroadcastq | Vinsertf128 | Vinserti128
+            | Vextractf128 | Vextracti128 | Vperm2f128 | Vperm2i128 | Vpermilps | Vpermilpd
+            | Vpermd | Vpermps | Vpermq | Vpermpd | Vpermw | Vpblendd | Vtestps | Vtestpd
+            | Vmaskmovps | Vmaskmovpd | Vpmaskmovd | Vpmaskmovq | Vpgatherdd | Vpgatherdq
+            | Vpgatherqd | Vpgatherqq | Vgatherdps | Vgatherdpd | Vgatherqps | Vgatherqpd
+            | Vinsertf32x4 | Vinserti32x4 | Vinsertf64x2 | Vinserti64x2 | Vinsertf64x4
+            | Vinserti64x4 | Vinsertf32x8 | Vinserti32x8 | Vextractf32x4 | Vextracti32x4
+            | Vextractf64x2 | Vextracti64x2 | Vextractf64x4 | Vextracti64x4 | Vextractf32x8
+            | Vextracti32x8 | Vbroadcastf32x2 | Vbroadcasti32x2 | Vbroadcastf32x4
+            | Vbroadcasti32x4 | Vbroadcastf64x2 | Vbroadcasti64x2 | Vbroadcastf32x8
+            | Vbroadcasti32x8 | Vbroadcastf64x4 | Vbroadcasti64x4 => {
+                self.execute_avx(map, insn, &dst, &first, &src)?
+            }
+            Vpternlogd | Vpternlogq | Vpblendmb | Vpblendmw | Vpblendmd | Vpblendmq | Vblendmps
+            | Vblendmpd | Vpmovm2b | Vpmovm2w | Vpmovm2d | Vpmovm2q | Vpbroadcastmb2q
+            | Vpbroadcastmw2d | Vpermi2w | Vpermi2d | Vpermi2q | Vpermi2ps | Vpermi2pd
+            | Vpermt2w | Vpermt2d | Vpermt2q | Vpermt2ps | Vpermt2pd | Vcompressps
+            | Vcompresspd | Vpcompressd | Vpcompressq | Vexpandps | Vexpandpd | Vpexpandd
+            | Vpexpandq | Vpmovwb | Vpmovdb | Vpmovqb | Vpmovdw | Vpmovqw | Vpmovqd | Vpmovswb
+            | Vpmovsdb | Vpmovsqb | Vpmovsdw | Vpmovsqw | Vpmovsqd | Vpmovuswb | Vpmovusdb
+            | Vpmovusqb | Vpmovusdw | Vpmovusqw | Vpmovusqd | Vpscatterdd | Vpscatterdq
+            | Vpscatterqd | Vpscatterqq | Vscatterdps | Vscatterdpd | Vscatterqps | Vscatterqpd
+            | Vpconflictd | Vpconflictq | Vplzcntd | Vplzcntq => {
+                self.execute_avx512(map, insn, &dst, &first, &src)?
+            }
+            Aesenc | Aesenclast | Aesdec | Aesdeclast | Aesimc | Aeskeygenassist | Pclmulqdq
+            | Sha1rnds4 | Sha1nexte | Sha1msg1 | Sha1msg2 | Sha256rnds2 | Sha256msg1
+            | Sha256msg2 => {
+                let a = self.read_vector(map, &first)?;
+                let b = self.read_vector(map, &src)?;
+                let imm = insn.immediate().unwrap_or(0) as u8;
+                let result = crypto::compute(insn.mnemonic, &a, &b, imm, self.vector(0));
+                self.write_vector(map, &dst, &result)?;
+            }
+            mnemonic => {
+                let a = self.read_vector(map, &first)?;
+                let mut b = self.read_vector(map, &src)?;
+                // Shift counts can be narrower than the vector they shift
+                b.resize(a.len(), 0);
+                let result = packed::integer(mnemonic, &a, &b);
+                self.write_vector(map, &dst, &result)?;
+            }
+        }
+        // VEX- and EVEX-encoded instructions clear a vector destination past its size, and
+        // past a scalar result keep the rest of the low 128 bits of their first source. An
+        // opmask then puts back or clears the elements it leaves out.
+        let flags_only = matches!(
+            insn.mnemonic,
+            Comiss
+                | Comisd
+                | Ucomiss
+                | Ucomisd
+                | Ptest
+                | Vtestps
+                | Vtestpd
+                | Pcmpestri
+                | Pcmpestrm
+                | Pcmpistri
+                | Pcmpistrm
+                | Maskmovdqu
+        );
+        if let (true, false, Operand::Register(Register::Vector(n), size)) = (
+            insn.prefixes.intersects(Prefixes::VEX | Prefixes::EVEX),
+            flags_only,
+            dst,
+        ) {
+            let size = size.bytes() as usize;
+            let vector = self.vector_mut(n);
+            if size < 16 {
+                vector[size..16].copy_from_slice(&merge[size..]);
+            }
+            vector[size.max(16)..].fill(0);
+            if let Some(masked_off) = masked_off {
+                self.apply_opmask(insn, n, size, &masked_off);
+            }
+        }
+        Ok(())
+    }
+
+    /// VZEROUPPER and VZEROALL: clear the upper halves of the YMM registers, or all of them.
+    fn vzero(&mut self, all: bool) {
+        for n in 0..16 {
+            let vector = self.vector_mut(n);
+            vector[if all { 0 } else { 16 }..].fill(0);
+        }
+    }
+
+    /// Legacy SSE instructions raise #GP(0) for 16-byte memory operands that aren't 16-byte
+    /// aligned, except for the explicitly unaligned loads and stores and the string
+    /// comparisons. VEX- and EVEX-encoded ones only require the explicitly aligned moves to
+    /// be aligned, to the size of the vector.
+    fn check_alignment(&self, insn: &Instruction) -> Result<(), Exception> {
+        use Mnemonic::*;
+        if insn.prefixes.intersects(Prefixes::VEX | Prefixes::EVEX) {
+            if !matches!(
+                insn.mnemonic,
+                Movaps
+                    | Movapd
+                    | Movdqa
+                    | Movntps
+                    | Movntpd
+                    | Movntdq
+                    | Movntdqa
+                    | Vmovdqa32
+                    | Vmovdqa64
+            ) {
+                return Ok(());
+            }
+            for operand in insn.operands() {
+                if let Operand::Memory(mem, size) = operand {
+                    if !self.effective_address(mem).is_multiple_of(size.bytes()) {
+                        return Err(Exception::GeneralProtection(0));
+                    }
+                }
+            }
+            return Ok(());
+        }
+        if matches!(
+            insn.mnemonic,
+            Movups | Movupd | Movdqu | Lddqu | Pcmpestri | Pcmpestrm | Pcmpistri | Pcmpistrm
+        ) {
+            return Ok(());
+        }
+        for operand in insn.operands() {
+            if let Operand::Memory(mem, OperandSize::R128) = operand {
+                if !self.effective_address(mem).is_multiple_of(16) {
+                    return Err(Exception::GeneralProtection(0));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The floating-point environment of an instruction: MXCSR's, unless EVEX overrides the
+    /// rounding mode, which like suppress-all-exceptions also masks every exception. Only
+    /// the elements its opmask selects raise exceptions.
+    fn float_context(&self, insn: &Instruction) -> float::Context {
+        let mxcsr = self.regs.fpu.mxcsr;
+        let mut cx = match insn.evex {
+            Some(Evex {
+                rounding: Some(rounding),
+                ..
+            }) => float::Context::new(
+                mxcsr & !RC | (rounding as u32) << RC_SHIFT | MXCSR_EXCEPTIONS << MASK_SHIFT,
+            ),
+            Some(Evex { sae: true, .. }) => {
+                float::Context::new(mxcsr | MXCSR_EXCEPTIONS << MASK_SHIFT)
+            }
+            _ => float::Context::new(mxcsr),
+        };
+        cx.opmask = self.opmask(insn);
+        cx
+    }
+
+    /// Accumulates the exceptions an instruction raised into MXCSR, and faults if any of
+    /// them is unmasked, in which case the destination isn't written. Under EVEX's
+    /// suppress-all-exceptions they aren't even recorded.
+    fn simd_exceptions(
+        &mut self,
+        insn: &Instruction,
+        cx: &float::Context,
+    ) -> Result<(), Exception> {
+        if insn
+            .evex
+            .is_some_and(|evex| evex.sae || evex.rounding.is_some())
+        {
+            return Ok(());
+        }
+        let raised = cx.env.flags.bits() as u32;
+        let mxcsr = self.regs.fpu.mxcsr | raised;
+        self.regs.fpu.mxcsr = mxcsr;
+        if raised & !(mxcsr >> MASK_SHIFT) & MXCSR_EXCEPTIONS == 0 {
+            return Ok(());
+        }
+        Err(if self.regs.cr[4] & CR4_OSXMMEXCPT != 0 {
+            Exception::SimdFloatingPoint
+        } else {
+            Exception::InvalidOpcode
+        })
+    }
+
+    /// FXSAVE and FXRSTOR: the x87, MXCSR and XMM state in a 16-byte aligned, 512-byte area.
+    /// REX.W selects the 64-bit layout of the x87 instruction and data pointers.
+    fn fxsave(&mut self, map: &mut dyn MemoryMap, insn: &Instruction) -> Result<(), Exception> {
+        let Operand::Memory(mem, _) = insn.operand(0) else {
+            unreachable!("{} has a memory operand", insn)
+        };
+        let access = match insn.mnemonic {
+            Mnemonic::Fxsave => Access::Write,
+            _ => Access::Read,
+        };
+        let addr = self.checked_address(&mem, FXSAVE_SIZE as u64, access)?;
+        if !addr.is_multiple_of(16) {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let wide = insn.prefixes.contains(Prefixes::REX_W);
+        let mut image = [0; FXSAVE_SIZE];
+        if insn.mnemonic == Mnemonic::Fxsave {
+            self.fxsave_x87(&mut image, wide);
+            image[24..28].copy_from_slice(&self.regs.fpu.mxcsr.to_le_bytes());
+            image[28..32].copy_from_slice(&MXCSR_MASK.to_le_bytes());
+            for n in 0..16 {
+                image[160 + 16 * n..][..16].copy_from_slice(&self.vector(n as u8)[..16]);
+            }
+            return self.write_bytes(map, addr, &image);
+        }
+        self.read_bytes(map, addr, &mut image)?;
+        let mxcsr = u32::from_le_bytes(image[24..28].try_into().unwrap());
+        if mxcsr & !MXCSR_MASK != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        self.fxrstor_x87(&image, wide);
+        self.regs.fpu.mxcsr = mxcsr;
+        for n in 0..16 {
+            self.vector_mut(n as u8)[..16].copy_from_slice(&image[160 + 16 * n..][..16]);
+        }
+        Ok(())
+    }
+}
+
+/// The size of the elements an instruction writes: what an insert or extract picks, and
+/// what each bit of an AVX-512 opmask selects. Mostly that's in the mnemonic's suffix.
+fn element_width(mnemonic: Mnemonic) -> usize {
+    use Mnemonic::*;
+    match mnemonic {
+        Punpcklbw | Punpckhbw | Palignr => return 1,
+        Punpcklwd | Punpckhwd => return 2,
+        // VPCOMPRESSD ends in "sd" without being a scalar double
+        Punpckldq | Punpckhdq | Movsldup | Movshdup | Vpcompressd => return 4,
+        Movddup | Psadbw => return 8,
+        _ => {}
+    }
+    let name = mnemonic.name();
+    // A conversion's elements are those it converts to
+    let conversion = name.starts_with("Cvt") || name.starts_with("Vcvt");
+    if let Some((_, to)) = name.rsplit_once('2').filter(|_| conversion) {
+        return match to {
+            "pd" | "sd" | "qq" | "uqq" => 8,
+            "ph" => 2,
+            _ => 4,
+        };
+    }
+    // VINSERTF32X4 and the like move parts of a vector, but mask their elements
+    let name = ["x2", "x4", "x8"]
+        .iter()
+        .find_map(|part| name.strip_suffix(part))
+        .unwrap_or(name);
+    if name.ends_with("ps") || name.ends_with("ss") || name.ends_with("32") {
+        4
+    } else if name.ends_with("pd") || name.ends_with("sd") || name.ends_with("64") {
+        8
+    } else if name.ends_with("16") {
+        2
+    } else {
+        match name.as_bytes()[name.len() - 1] {
+            b'b' | b'8' => 1,
+            b'w' => 2,
+            b'q' => 8,
+            _ => 4,
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! The instructions AVX and AVX2 added rather than re-encoded: broadcasts, moves between
+//! the parts of YMM (and with AVX-512, ZMM) registers, the cross-lane permutes, masked
+//! loads and stores, and the gathers.
+
+use super::super::descriptor::Access;
+use super::super::{flags, packed, Amd64Interp};
+use crate::amd64::decode::{Instruction, Mnemonic, Operand, Register};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+impl Amd64Interp {
+    /// Executes an AVX or AVX2 instruction given its destination and sources, as
+    /// [`Amd64Interp::execute_vector`] picks them out.
+    pub(super) fn execute_avx(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+        dst: &Operand,
+        first: &Operand,
+        src: &Operand,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        let size = dst.size().unwrap().bytes() as usize;
+        match insn.mnemonic {
+            Vbroadcastss | Vbroadcastsd | Vbroadcastf128 | Vbroadcasti128 | Vpbroadcastb
+            | Vpbroadcastw | Vpbroadcastd | Vpbroadcastq | Vbroadcastf32x2 | Vbroadcasti32x2
+            | Vbroadcastf32x4 | Vbroadcasti32x4 | Vbroadcastf64x2 | Vbroadcasti64x2
+            | Vbroadcastf32x8 | Vbroadcasti32x8 | Vbroadcastf64x4 | Vbroadcasti64x4 => {
+                let mut element = self.read_vector(map, src)?;
+                // Bytes and words broadcast from a general-purpose register are its low ones
+                match insn.mnemonic {
+                    Vpbroadcastb => element.truncate(1),
+                    Vpbroadcastw => element.truncate(2),
+                    _ => {}
+                }
+                let result: Vec<u8> = element.iter().cycle().take(size).copied().collect();
+                self.write_vector(map, dst, &result)?;
+            }
+            Vinsertf128 | Vinserti128 | Vinsertf32x4 | Vinserti32x4 | Vinsertf64x2
+            | Vinserti64x2 | Vinsertf64x4 | Vinserti64x4 | Vinsertf32x8 | Vinserti32x8 => {
+                // The immediate picks which part of the vector to replace
+                let mut vector = self.read_vector(map, first)?;
+                let part = self.read_vector(map, src)?;
+                let parts = vector.len() / part.len();
+                let start = part.len() * (insn.immediate().unwrap() as usize & (parts - 1));
+                vector[start..start + part.len()].copy_from_slice(&part);
+                self.write_vector(map, dst, &vector)?;
+            }
+            Vextractf128 | Vextracti128 | Vextractf32x4 | Vextracti32x4 | Vextractf64x2
+            | Vextracti64x2 | Vextractf64x4 | Vextracti64x4 | Vextractf32x8 | Vextracti32x8 => {
+                let vector = self.read_vector(map, src)?;
+                let parts = vector.len() / size;
+                let start = size * (insn.immediate().unwrap() as usize & (parts - 1));
+                self.write_masked(map, insn, dst, &vector[start..start + size])?;
+            }
+            Vperm2f128 | Vperm2i128 => {
+                // Each half of the result is any half of either source, or zero
+                let sources = [self.read_vector(map, first)?, self.read_vector(map, src)?];
+                let imm = insn.immediate().unwrap() as usize;
+                let mut result = vec![0; 32];
+                for (half, target) in result.chunks_mut(16).enumerate() {
+                    let control = imm >> (4 * half);
+                    if control & 0x08 == 0 {
+                        let start = 16 * (control & 1);
+                        target.copy_from_slice(&sources[control >> 1 & 1][start..start + 16]);
+                    }
+                }
+                self.write_vector(map, dst, &result)?;
+            }
+            Vpermilps | Vpermilpd | Vpblendd if insn.immediate().is_some() => {
+                let a = self.read_vector(map, first)?;
+                let b = self.read_vector(map, src)?;
+                let imm = insn.immediate().unwrap() as u8;
+                let result = packed::shuffle(insn.mnemonic, &a, &b, imm);
+                self.write_vector(map, dst, &result)?;
+            }
+            Vpermilps | Vpermilpd => {
+                // Elements of the first source picked within their block by the low bits
+                // (bit 1 for VPERMILPD) of the corresponding elements of the second
+                let data = self.read_vector(map, first)?;
+                let control = self.read_vector(map, src)?;
+                let (width, shift, mask) = if insn.mnemonic == Vpermilps {
+                    (4, 0, 3)
+                } else {
+                    (8, 1, 1)
+                };
+                let per_block = 16 / width;
+                let mut result = vec![0; size];
+                for i in 0..size / width {
+                    let select = (packed::lane(&control, i, width) >> shift) as usize & mask;
+                    let element = packed::lane(&data, i / per_block * per_block + select, width);
+                    packed::set_lane(&mut result, i, width, element);
+                }
+                self.write_vector(map, dst, &result)?;
+            }
+            Vpermq | Vpermpd if insn.immediate().is_some() => {
+                // Any quadword of the same 256 bits, by two bits of the immediate each
+                let table = self.read_vector(map, src)?;
+                let imm = insn.immediate().unwrap() as usize;
+                let mut result = vec![0; size];
+                for i in 0..size / 8 {
+                    let select = i & !3 | imm >> (2 * (i & 3)) & 3;
+                    packed::set_lane(&mut result, i, 8, packed::lane(&table, select, 8));
+                }
+                self.write_vector(map, dst, &result)?;
+            }
+            Vpermd | Vpermps | Vpermq | Vpermpd | Vpermw => {
+                // Any element of the second source, by the indices in the first
+                let indices = self.read_vector(map, first)?;
+                let table = self.read_vector(map, src)?;
+                let width = match insn.mnemonic {
+                    Vpermw => 2,
+                    Vpermd | Vpermps => 4,
+                    _ => 8,
+                };
+                let count = size / width;
+                let mut result = vec![0; size];
+                for i in 0..count {
+                    let select = packed::lane(&indices, i, width) as usize & (count - 1);
+                    let element = packed::lane(&table, select, width);
+                    packed::set_lane(&mut result, i, width, element);
+                }
+                self.write_vector(map, dst, &result)?;
+            }
+            Vtestps | Vtestpd => {
+                // PTEST on the sign bits alone
+                let a = self.read_vector(map, first)?;
+                let b = self.read_vector(map, src)?;
+                let width = if insn.mnemonic == Vtestps { 4 } else { 8 };
+                let (a, b) = (packed::sign_mask(&a, width), packed::sign_mask(&b, width));
+                let mut status = 0;
+                if a & b == 0 {
+                    status |= flags::ZF;
+                }
+                if !a & b == 0 {
+                    status |= flags::CF;
+                }
+                self.regs.rflags = (self.regs.rflags & !flags::STATUS) | status;
+            }
+            Vmaskmovps | Vmaskmovpd | Vpmaskmovd | Vpmaskmovq => {
+                self.masked_move(map, insn, dst, first, src)?
+            }
+            _ => self.gather(map, insn, dst, src)?,
+        }
+        Ok(())
+    }
+
+    /// VMASKMOV and VPMASKMOV: load or store the elements whose mask element, the first
+    /// source, has its sign bit set. Masked-off elements are zeroed by loads and left alone
+    /// by stores, and never fault.
+    fn masked_move(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+        dst: &Operand,
+        mask: &Operand,
+        src: &Operand,
+    ) -> Result<(), Exception> {
+        let width = match insn.mnemonic {
+            Mnemonic::Vmaskmovps | Mnemonic::Vpmaskmovd => 4,
+            _ => 8,
+        };
+        let mask = self.read_vector(map, mask)?;
+        let selected = |i: usize| packed::lane(&mask, i, width) >> (8 * width - 1) != 0;
+        match (dst, src) {
+            (Operand::Memory(mem, _), _) => {
+                let data = self.read_vector(map, src)?;
+                for i in (0..data.len() / width).filter(|&i| selected(i)) {
+                    let addr =
+                        self.checked_element(mem, (i * width) as u64, width as u64, Access::Write)?;
+                    self.write_bytes(map, addr, &data[i * width..(i + 1) * width])?;
+                }
+            }
+            (_, Operand::Memory(mem, size)) => {
+                let mut result = vec![0; size.bytes() as usize];
+                for i in (0..result.len() / width).filter(|&i| selected(i)) {
+                    let addr =
+                        self.checked_element(mem, (i * width) as u64, width as u64, Access::Read)?;
+                    self.read_bytes(map, addr, &mut result[i * width..(i + 1) * width])?;
+                }
+                self.write_vector(map, dst, &result)?;
+            }
+            _ => unreachable!("{} has a memory operand", insn),
+        }
+        Ok(())
+    }
+
+    /// The AVX2 gathers: load each element whose mask element has its sign bit set from the
+    /// address its index element selects, clearing the mask element as it completes, so a
+    /// fault part way leaves the elements already loaded done.
+    fn gather(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+        dst: &Operand,
+        src: &Operand,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        let (
+            Operand::Register(Register::Vector(d), dst_size),
+            Operand::Memory(mem, element_size),
+            Operand::Register(Register::Vector(m), _),
+        ) = (*dst, *src, insn.operand(2))
+        else {
+            unreachable!("{} is not a gather", insn)
+        };
+        let (index, index_size) = (mem.index.unwrap(), mem.vector_index.unwrap());
+        // The destination, index and mask must all be different registers
+        if d == m || d == index || m == index {
+            return Err(Exception::InvalidOpcode);
+        }
+        let width = element_size.bytes() as usize;
+        let index_width = match insn.mnemonic {
+            Vpgatherdd | Vpgatherdq | Vgatherdps | Vgatherdpd => 4,
+            _ => 8,
+        };
+        let count =
+            (index_size.bytes() as usize / index_width).min(dst_size.bytes() as usize / width);
+        for i in 0..count {
+            if packed::lane(self.vector(m), i, width) >> (8 * width - 1) == 0 {
+                continue;
+            }
+            let offset = packed::signed(
+                packed::lane(self.vector(index), i, index_width),
+                index_width,
+            );
+            let displacement = (offset as u64).wrapping_mul(mem.scale as u64);
+            let addr = self.checked_element(&mem, displacement, width as u64, Access::Read)?;
+            let mut value = vec![0; width];
+            self.read_bytes(map, addr, &mut value)?;
+            self.vector_mut(d)[i * width..(i + 1) * width].copy_from_slice(&value);
+            packed::set_lane(self.vector_mut(m), i, width, 0);
+        }
+        self.vector_mut(m).fill(0);
+        self.vector_mut(d)[count * width..].fill(0);
+        Ok(())
+    }
+}
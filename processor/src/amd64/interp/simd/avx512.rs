@@ -0,0 +1,542 @@
+//! AVX-512's opmask registers: the instructions on them, the comparisons that write them,
+//! and the masking of the elements of EVEX-encoded instructions' destinations, along with
+//! the few instructions AVX-512 added rather than re-encoded.
+
+use super::super::descriptor::Access;
+use super::super::{flags, packed, Amd64Interp};
+use super::{element_width, float};
+use crate::amd64::decode::{Instruction, Mnemonic, Operand, Register};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+impl Amd64Interp {
+    /// Executes an instruction on opmask registers alone: the logic operations, KADD,
+    /// KUNPCK, the shifts, KMOV, KORTEST and KTEST.
+    pub(super) fn execute_opmask(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        let dst = insn.operand(0);
+        match insn.mnemonic {
+            Kmovb | Kmovw | Kmovd | Kmovq => {
+                let value = self.read_operand(map, &insn.operand(1))?;
+                self.write_operand(map, &dst, value)?;
+            }
+            Knotb | Knotw | Knotd | Knotq => {
+                let value = self.read_operand(map, &insn.operand(1))?;
+                self.write_operand(map, &dst, !value)?;
+            }
+            Kortestb | Kortestw | Kortestd | Kortestq | Ktestb | Ktestw | Ktestd | Ktestq => {
+                // KORTEST sets ZF if the OR of the two is all zeros and CF if it's all ones,
+                // KTEST ZF if their AND is zero and CF if the second has no bits the first
+                // lacks
+                let mask = dst.size().unwrap().mask();
+                let a = self.read_operand(map, &dst)?;
+                let b = self.read_operand(map, &insn.operand(1))?;
+                let (zero, carry) = match insn.mnemonic {
+                    Kortestb | Kortestw | Kortestd | Kortestq => (a | b == 0, a | b == mask),
+                    _ => (a & b == 0, !a & b == 0),
+                };
+                let mut status = 0;
+                if zero {
+                    status |= flags::ZF;
+                }
+                if carry {
+                    status |= flags::CF;
+                }
+                self.regs.rflags = (self.regs.rflags & !flags::STATUS) | status;
+            }
+            Kshiftlb | Kshiftlw | Kshiftld | Kshiftlq | Kshiftrb | Kshiftrw | Kshiftrd
+            | Kshiftrq => {
+                // Counts of the width or more clear the destination
+                let value = self.read_operand(map, &insn.operand(1))?;
+                let count = insn.immediate().unwrap() as u32;
+                let result = match insn.mnemonic {
+                    _ if count >= dst.size().unwrap().bits() => 0,
+                    Kshiftlb | Kshiftlw | Kshiftld | Kshiftlq => value << count,
+                    _ => value >> count,
+                };
+                self.write_operand(map, &dst, result)?;
+            }
+            mnemonic => {
+                let a = self.read_operand(map, &insn.operand(1))?;
+                let b = self.read_operand(map, &insn.operand(2))?;
+                let result = match mnemonic {
+                    Kandb | Kandw | Kandd | Kandq => a & b,
+                    Kandnb | Kandnw | Kandnd | Kandnq => !a & b,
+                    Korb | Korw | Kord | Korq => a | b,
+                    Kxnorb | Kxnorw | Kxnord | Kxnorq => !(a ^ b),
+                    Kxorb | Kxorw | Kxord | Kxorq => a ^ b,
+                    Kaddb | Kaddw | Kaddd | Kaddq => a.wrapping_add(b),
+                    // The low halves of the two, the first source's above the second's
+                    Kunpckbw | Kunpckwd | Kunpckdq => {
+                        let half = dst.size().unwrap().bits() / 2;
+                        let low = u64::MAX >> (64 - half);
+                        (a & low) << half | b & low
+                    }
+                    _ => unreachable!("{} is not an opmask instruction", insn),
+                };
+                self.write_operand(map, &dst, result)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The opmask an EVEX-encoded instruction writes its destination under, or None if it
+    /// names k0 and so writes every element.
+    pub(super) fn opmask(&self, insn: &Instruction) -> Option<u64> {
+        let evex = insn.evex.filter(|evex| evex.mask != 0)?;
+        Some(self.regs.k[evex.mask as usize])
+    }
+
+    /// What the elements of a vector register destination its opmask leaves out become:
+    /// zero when zeroing, and otherwise their old value, or for the blends the element of
+    /// the first source. None when every element is written, or when the opmask selects
+    /// the source elements instead, as it does for the compresses.
+    pub(super) fn masked_off(
+        &self,
+        insn: &Instruction,
+        dst: &Operand,
+        first: &Operand,
+    ) -> Option<Vec<u8>> {
+        use Mnemonic::*;
+        let evex = insn.evex.filter(|evex| evex.mask != 0)?;
+        let Operand::Register(Register::Vector(n), _) = *dst else {
+            return None;
+        };
+        // The compresses pick their source elements, and the gathers merge as they go
+        if is_compress(insn.mnemonic) || insn.operands().iter().any(is_vsib) {
+            return None;
+        }
+        Some(match (insn.mnemonic, *first) {
+            _ if evex.zeroing => vec![0; 64],
+            (
+                Vpblendmb | Vpblendmw | Vpblendmd | Vpblendmq | Vblendmps | Vblendmpd,
+                Operand::Register(reg, _),
+            ) => {
+                let Register::Vector(m) = reg else {
+                    unreachable!("{} blends vectors", insn)
+                };
+                self.vector(m).to_vec()
+            }
+            _ => self.vector(n).to_vec(),
+        })
+    }
+
+    /// Puts back the elements an opmask leaves out of the first `size` bytes of vector
+    /// register `n`, once an instruction has written all of them.
+    pub(super) fn apply_opmask(
+        &mut self,
+        insn: &Instruction,
+        n: u8,
+        size: usize,
+        masked_off: &[u8],
+    ) {
+        let mask = self.opmask(insn).unwrap();
+        let width = element_width(insn.mnemonic);
+        // MOVSS and MOVSD between registers write a whole XMM register, but only their low
+        // element is masked
+        let count = match insn.mnemonic {
+            Mnemonic::Movss | Mnemonic::Movsd => 1,
+            _ => size / width,
+        };
+        let vector = self.vector_mut(n);
+        for i in (0..count).filter(|i| mask >> i & 1 == 0) {
+            let element = i * width..(i + 1) * width;
+            vector[element.clone()].copy_from_slice(&masked_off[element]);
+        }
+    }
+
+    /// Reads the source of a move, which if it's in memory is only read for the elements
+    /// the instruction's opmask selects, so that the others can't fault. They read as zero.
+    pub(super) fn read_masked(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+        operand: &Operand,
+    ) -> Result<Vec<u8>, Exception> {
+        let (Some(mask), Operand::Memory(mem, size)) = (self.opmask(insn), *operand) else {
+            return self.read_vector(map, operand);
+        };
+        let width = element_width(insn.mnemonic);
+        let mut data = vec![0; size.bytes() as usize];
+        for i in (0..data.len() / width).filter(|i| mask >> i & 1 != 0) {
+            let addr =
+                self.checked_element(&mem, (i * width) as u64, width as u64, Access::Read)?;
+            self.read_bytes(map, addr, &mut data[i * width..(i + 1) * width])?;
+        }
+        Ok(data)
+    }
+
+    /// Writes the destination of a move or extract, which if it's in memory is only written
+    /// for the elements the instruction's opmask selects.
+    pub(super) fn write_masked(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+        operand: &Operand,
+        data: &[u8],
+    ) -> Result<(), Exception> {
+        let (Some(mask), Operand::Memory(mem, size)) = (self.opmask(insn), *operand) else {
+            return self.write_vector(map, operand, data);
+        };
+        let width = element_width(insn.mnemonic);
+        for i in (0..size.bytes() as usize / width).filter(|i| mask >> i & 1 != 0) {
+            let addr =
+                self.checked_element(&mem, (i * width) as u64, width as u64, Access::Write)?;
+            self.write_bytes(map, addr, &data[i * width..(i + 1) * width])?;
+        }
+        Ok(())
+    }
+
+    /// The comparisons that write an opmask register: a bit for each element of the
+    /// sources, set where the comparison holds, and clear past the last element and where
+    /// the instruction's own opmask is clear.
+    pub(super) fn compare_into_mask(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+        dst: &Operand,
+        first: &Operand,
+        src: &Operand,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        let a = self.read_vector(map, first)?;
+        let b = self.read_vector(map, src)?;
+        let width = element_width(insn.mnemonic);
+        let count = match insn.mnemonic {
+            Cmpss | Cmpsd => 1,
+            _ => b.len() / width,
+        };
+        let bits = match insn.mnemonic {
+            Cmpps | Cmppd | Cmpss | Cmpsd => {
+                let predicate = insn.immediate().unwrap() as u8 & 0x1F;
+                let mut cx = self.float_context(insn);
+                let result = float::compare(insn.mnemonic, &a, &b, predicate, &mut cx);
+                self.simd_exceptions(insn, &cx)?;
+                packed::sign_mask(&result, width)
+            }
+            Vpcmpb | Vpcmpw | Vpcmpd | Vpcmpq | Vpcmpub | Vpcmpuw | Vpcmpud | Vpcmpuq => {
+                let predicate = insn.immediate().unwrap() & 7;
+                let signed = matches!(insn.mnemonic, Vpcmpb | Vpcmpw | Vpcmpd | Vpcmpq);
+                let value = |vector: &[u8], i| match packed::lane(vector, i, width) {
+                    x if signed => packed::signed(x, width) as i128,
+                    x => x as i128,
+                };
+                (0..count).fold(0, |bits, i| {
+                    let (x, y) = (value(&a, i), value(&b, i));
+                    let holds = match predicate {
+                        0 => x == y,
+                        1 => x < y,
+                        2 => x <= y,
+                        3 => false,
+                        4 => x != y,
+                        5 => x >= y,
+                        6 => x > y,
+                        _ => true,
+                    };
+                    bits | (holds as u64) << i
+                })
+            }
+            // Whether the AND of the elements is nonzero, or for VPTESTNM zero
+            Vptestmb | Vptestmw | Vptestmd | Vptestmq | Vptestnmb | Vptestnmw | Vptestnmd
+            | Vptestnmq => {
+                let zero = matches!(insn.mnemonic, Vptestnmb | Vptestnmw | Vptestnmd | Vptestnmq);
+                (0..count).fold(0, |bits, i| {
+                    let and = packed::lane(&a, i, width) & packed::lane(&b, i, width);
+                    bits | (((and == 0) == zero) as u64) << i
+                })
+            }
+            // VFPCLASS has no first source
+            Vfpclassps | Vfpclasspd | Vfpclassss | Vfpclasssd => {
+                let imm = insn.immediate().unwrap() as u8;
+                (0..count).fold(0, |bits, i| {
+                    let class = float::classify(insn.mnemonic, packed::lane(&b, i, width), imm);
+                    bits | (class as u64) << i
+                })
+            }
+            // PCMPEQ and PCMPGT
+            mnemonic => packed::sign_mask(&packed::integer(mnemonic, &a, &b), width),
+        };
+        let mut value = bits & (u64::MAX >> (64 - count));
+        if let Some(mask) = self.opmask(insn) {
+            value &= mask;
+        }
+        self.write_operand(map, dst, value)
+    }
+
+    /// Executes an instruction AVX-512 added on vectors, given its destination and sources
+    /// as [`Amd64Interp::execute_vector`] picks them out: VPTERNLOG, the blends, whose
+    /// opmask selects between their sources, the moves and broadcasts from opmask
+    /// registers, the permutes of two tables, the compresses and expands, and the
+    /// narrowing moves.
+    pub(super) fn execute_avx512(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+        dst: &Operand,
+        first: &Operand,
+        src: &Operand,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        match insn.mnemonic {
+            Vpternlogd | Vpternlogq => {
+                // Each bit of the result is the bit of the immediate indexed by the bits of
+                // the destination, the first source and the second, from high to low
+                let a = self.read_vector(map, dst)?;
+                let b = self.read_vector(map, first)?;
+                let c = self.read_vector(map, src)?;
+                let imm = insn.immediate().unwrap() as u8;
+                let result: Vec<u8> = (0..a.len())
+                    .map(|i| {
+                        (0..8)
+                            .filter(|index| imm >> index & 1 != 0)
+                            .fold(0, |byte, index| {
+                                let pick = |x: u8, bit| if index & bit != 0 { x } else { !x };
+                                byte | pick(a[i], 4) & pick(b[i], 2) & pick(c[i], 1)
+                            })
+                    })
+                    .collect();
+                self.write_vector(map, dst, &result)
+            }
+            Vpmovm2b | Vpmovm2w | Vpmovm2d | Vpmovm2q => {
+                // All ones in the elements whose bits are set, and zero in the others
+                let mask = self.read_operand(map, src)?;
+                let width = element_width(insn.mnemonic);
+                let mut result = vec![0; dst.size().unwrap().bytes() as usize];
+                for i in (0..result.len() / width).filter(|i| mask >> i & 1 != 0) {
+                    packed::set_lane(&mut result, i, width, u64::MAX);
+                }
+                self.write_vector(map, dst, &result)
+            }
+            Vpbroadcastmb2q | Vpbroadcastmw2d => {
+                // The low byte or word of the opmask register, zero-extended to each element
+                let (width, mask) = match insn.mnemonic {
+                    Vpbroadcastmb2q => (8, 0xFF),
+                    _ => (4, 0xFFFF),
+                };
+                let value = self.read_operand(map, src)? & mask;
+                let mut result = vec![0; dst.size().unwrap().bytes() as usize];
+                for i in 0..result.len() / width {
+                    packed::set_lane(&mut result, i, width, value);
+                }
+                self.write_vector(map, dst, &result)
+            }
+            Vpermi2w | Vpermi2d | Vpermi2q | Vpermi2ps | Vpermi2pd | Vpermt2w | Vpermt2d
+            | Vpermt2q | Vpermt2ps | Vpermt2pd => {
+                // Elements of either table by the indices, the bit above those that pick an
+                // element picking the table. VPERMI2 overwrites the indices and VPERMT2 the
+                // first table.
+                let (indices, table) = match insn.mnemonic {
+                    Vpermi2w | Vpermi2d | Vpermi2q | Vpermi2ps | Vpermi2pd => (dst, first),
+                    _ => (first, dst),
+                };
+                let indices = self.read_vector(map, indices)?;
+                let tables = [self.read_vector(map, table)?, self.read_vector(map, src)?];
+                let width = element_width(insn.mnemonic);
+                let count = indices.len() / width;
+                let mut result = vec![0; indices.len()];
+                for i in 0..count {
+                    let index = packed::lane(&indices, i, width) as usize;
+                    let element = packed::lane(&tables[(index / count) & 1], index % count, width);
+                    packed::set_lane(&mut result, i, width, element);
+                }
+                self.write_vector(map, dst, &result)
+            }
+            mnemonic if is_compress(mnemonic) => {
+                // The selected elements, packed together at the bottom of the destination.
+                // In memory only they are written, and in a register the rest is cleared
+                // when zeroing and otherwise kept.
+                let data = self.read_vector(map, src)?;
+                let width = element_width(mnemonic);
+                let mask = self.opmask(insn).unwrap_or(u64::MAX);
+                let selected: Vec<u8> = (0..data.len() / width)
+                    .filter(|i| mask >> i & 1 != 0)
+                    .flat_map(|i| data[i * width..(i + 1) * width].iter().copied())
+                    .collect();
+                if let Operand::Memory(mem, _) = dst {
+                    // Selecting nothing writes nothing, so it can't fault
+                    if selected.is_empty() {
+                        return Ok(());
+                    }
+                    let addr = self.checked_address(mem, selected.len() as u64, Access::Write)?;
+                    return self.write_bytes(map, addr, &selected);
+                }
+                let mut result = match insn.evex {
+                    Some(evex) if evex.zeroing => vec![0; data.len()],
+                    _ => self.read_vector(map, dst)?,
+                };
+                result[..selected.len()].copy_from_slice(&selected);
+                self.write_vector(map, dst, &result)
+            }
+            Vexpandps | Vexpandpd | Vpexpandd | Vpexpandq => {
+                // Consecutive elements of the source, to the selected elements in turn. Only
+                // as many are read from memory as are selected.
+                let width = element_width(insn.mnemonic);
+                let size = dst.size().unwrap().bytes() as usize;
+                let mask =
+                    self.opmask(insn).unwrap_or(u64::MAX) & (u64::MAX >> (64 - size / width));
+                let data = match *src {
+                    Operand::Memory(mem, _) => {
+                        let mut data = vec![0; mask.count_ones() as usize * width];
+                        if !data.is_empty() {
+                            let addr =
+                                self.checked_address(&mem, data.len() as u64, Access::Read)?;
+                            self.read_bytes(map, addr, &mut data)?;
+                        }
+                        data
+                    }
+                    _ => self.read_vector(map, src)?,
+                };
+                let mut result = vec![0; size];
+                for (next, i) in (0..size / width).filter(|i| mask >> i & 1 != 0).enumerate() {
+                    result[i * width..(i + 1) * width]
+                        .copy_from_slice(&data[next * width..(next + 1) * width]);
+                }
+                self.write_vector(map, dst, &result)
+            }
+            Vpmovwb | Vpmovdb | Vpmovqb | Vpmovdw | Vpmovqw | Vpmovqd | Vpmovswb | Vpmovsdb
+            | Vpmovsqb | Vpmovsdw | Vpmovsqw | Vpmovsqd | Vpmovuswb | Vpmovusdb | Vpmovusqb
+            | Vpmovusdw | Vpmovusqw | Vpmovusqd => {
+                // Each element truncated to the narrower width, or saturated to it as a
+                // signed or unsigned value. Only the selected ones are stored to memory.
+                let data = self.read_vector(map, src)?;
+                let name = insn.mnemonic.name();
+                let to = element_width(insn.mnemonic);
+                let from = match name.as_bytes()[name.len() - 2] {
+                    b'w' => 2,
+                    b'd' => 4,
+                    _ => 8,
+                };
+                let bits = 8 * to as u32;
+                let mut result = vec![0; data.len() / from * to];
+                for i in 0..data.len() / from {
+                    let value = packed::lane(&data, i, from);
+                    let narrowed = if name.starts_with("Vpmovus") {
+                        value.min(u64::MAX >> (64 - bits))
+                    } else if name.starts_with("Vpmovs") {
+                        let max = i64::MAX >> (64 - bits);
+                        packed::signed(value, from).clamp(!max, max) as u64
+                    } else {
+                        value
+                    };
+                    packed::set_lane(&mut result, i, to, narrowed);
+                }
+                self.write_masked(map, insn, dst, &result)
+            }
+            Vpgatherdd | Vpgatherdq | Vpgatherqd | Vpgatherqq | Vgatherdps | Vgatherdpd
+            | Vgatherqps | Vgatherqpd | Vpscatterdd | Vpscatterdq | Vpscatterqd | Vpscatterqq
+            | Vscatterdps | Vscatterdpd | Vscatterqps | Vscatterqpd => {
+                self.gather_scatter(map, insn, dst, src)
+            }
+            Vpconflictd | Vpconflictq => {
+                // Each element becomes a bitmask of the earlier elements equal to it
+                let data = self.read_vector(map, src)?;
+                let width = element_width(insn.mnemonic);
+                let mut result = vec![0; data.len()];
+                for i in 0..data.len() / width {
+                    let value = packed::lane(&data, i, width);
+                    let conflicts = (0..i)
+                        .filter(|&j| packed::lane(&data, j, width) == value)
+                        .fold(0, |conflicts, j| conflicts | 1 << j);
+                    packed::set_lane(&mut result, i, width, conflicts);
+                }
+                self.write_vector(map, dst, &result)
+            }
+            Vplzcntd | Vplzcntq => {
+                let data = self.read_vector(map, src)?;
+                let width = element_width(insn.mnemonic);
+                let mut result = vec![0; data.len()];
+                for i in 0..data.len() / width {
+                    // Counted in the element, not the 64 bits its lane comes back in
+                    let zeros =
+                        packed::lane(&data, i, width).leading_zeros() - (64 - 8 * width as u32);
+                    packed::set_lane(&mut result, i, width, zeros as u64);
+                }
+                self.write_vector(map, dst, &result)
+            }
+            // Elements the opmask leaves out come from the first source
+            _ => {
+                let data = self.read_vector(map, src)?;
+                self.write_vector(map, dst, &data)
+            }
+        }
+    }
+
+    /// The AVX-512 gathers and scatters: load or store each element the opmask selects at
+    /// the address its index element gives, clearing its opmask bit as it completes, so a
+    /// fault part way leaves the elements already moved done.
+    fn gather_scatter(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+        dst: &Operand,
+        src: &Operand,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        let (gather, n, size, mem, element_size) = match (*dst, *src) {
+            (Operand::Register(Register::Vector(n), size), Operand::Memory(mem, element_size)) => {
+                (true, n, size, mem, element_size)
+            }
+            (Operand::Memory(mem, element_size), Operand::Register(Register::Vector(n), size)) => {
+                (false, n, size, mem, element_size)
+            }
+            _ => unreachable!("{} is not a gather or scatter", insn),
+        };
+        let (index, index_size) = (mem.index.unwrap(), mem.vector_index.unwrap());
+        // A gather's destination can't also be its index
+        if gather && n == index {
+            return Err(Exception::InvalidOpcode);
+        }
+        let k = insn.evex.unwrap().mask as usize;
+        let width = element_size.bytes() as usize;
+        let index_width = match insn.mnemonic {
+            Vpgatherdd | Vpgatherdq | Vgatherdps | Vgatherdpd | Vpscatterdd | Vpscatterdq
+            | Vscatterdps | Vscatterdpd => 4,
+            _ => 8,
+        };
+        let count = (index_size.bytes() as usize / index_width).min(size.bytes() as usize / width);
+        let mask = self.regs.k[k];
+        let access = if gather { Access::Read } else { Access::Write };
+        for i in (0..count).filter(|i| mask >> i & 1 != 0) {
+            let offset = packed::signed(
+                packed::lane(self.vector(index), i, index_width),
+                index_width,
+            );
+            let displacement = (offset as u64).wrapping_mul(mem.scale as u64);
+            let addr = self.checked_element(&mem, displacement, width as u64, access)?;
+            let element = i * width..(i + 1) * width;
+            if gather {
+                let mut value = vec![0; width];
+                self.read_bytes(map, addr, &mut value)?;
+                self.vector_mut(n)[element].copy_from_slice(&value);
+            } else {
+                let value = self.vector(n)[element].to_vec();
+                self.write_bytes(map, addr, &value)?;
+            }
+            self.regs.k[k] &= !(1 << i);
+        }
+        self.regs.k[k] = 0;
+        Ok(())
+    }
+}
+
+/// Whether an instruction packs the elements its opmask selects into the low elements of
+/// its destination.
+fn is_compress(mnemonic: Mnemonic) -> bool {
+    matches!(
+        mnemonic,
+        Mnemonic::Vcompressps
+            | Mnemonic::Vcompresspd
+            | Mnemonic::Vpcompressd
+            | Mnemonic::Vpcompressq
+    )
+}
+
+/// Whether an operand is a VSIB memory reference, which a gather loads from or a scatter
+/// stores to.
+fn is_vsib(operand: &Operand) -> bool {
+    matches!(operand, Operand::Memory(mem, _) if mem.vector_index.is_some())
+}
@@ -0,0 +1,216 @@
+//! The instructions accelerating cryptography: the AES rounds and key expansion, the
+//! carry-less multiply GCM's hashing is built on, and the SHA-1 and SHA-256 rounds and
+//! message schedules.
+//!
+//! AES holds its state in column-major order, so byte `i` of an XMM register is row `i % 4`
+//! of column `i / 4`. The SHA instructions pack their working variables into doublewords
+//! from the most significant down: A in bits 127:96 of the first source, and so on.
+
+use crate::amd64::decode::Mnemonic;
+
+/// Computes a cryptographic instruction on the 128-bit first source `a` and second source
+/// `b`. SHA256RNDS2 takes its two message words, with the round constants added, from the
+/// low quadword of `xmm0`.
+pub(super) fn compute(mnemonic: Mnemonic, a: &[u8], b: &[u8], imm: u8, xmm0: &[u8]) -> [u8; 16] {
+    use Mnemonic::*;
+    let (a, b): ([u8; 16], [u8; 16]) = (a[..16].try_into().unwrap(), b[..16].try_into().unwrap());
+    match mnemonic {
+        Aesenc => xor(mix_columns(sub_bytes(shift_rows(a), &SBOX), FORWARD), b),
+        Aesenclast => xor(sub_bytes(shift_rows(a), &SBOX), b),
+        Aesdec => xor(
+            mix_columns(sub_bytes(inverse_shift_rows(a), &INVERSE_SBOX), INVERSE),
+            b,
+        ),
+        Aesdeclast => xor(sub_bytes(inverse_shift_rows(a), &INVERSE_SBOX), b),
+        Aesimc => mix_columns(b, INVERSE),
+        Aeskeygenassist => key_generation_assist(b, imm),
+        Pclmulqdq => {
+            let half = |vector: [u8; 16], bit| {
+                let start = if imm & bit != 0 { 8 } else { 0 };
+                u64::from_le_bytes(vector[start..start + 8].try_into().unwrap())
+            };
+            carryless_multiply(half(a, 0x01), half(b, 0x10)).to_le_bytes()
+        }
+        _ => from_dwords(sha(mnemonic, dwords(&a), dwords(&b), imm, dwords(xmm0))),
+    }
+}
+
+const fn multiply(mut a: u8, mut b: u8) -> u8 {
+    // Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1B } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+/// The S-box: each byte's multiplicative inverse in GF(2^8), through the affine
+/// transformation.
+const SBOX: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut x = 0;
+    while x < 256 {
+        // x^254 is the inverse of x, and 0 of 0
+        let (mut b, mut power, mut exponent) = (1, x as u8, 254);
+        while exponent != 0 {
+            if exponent & 1 != 0 {
+                b = multiply(b, power);
+            }
+            power = multiply(power, power);
+            exponent >>= 1;
+        }
+        table[x] =
+            b ^ b.rotate_left(1) ^ b.rotate_left(2) ^ b.rotate_left(3) ^ b.rotate_left(4) ^ 0x63;
+        x += 1;
+    }
+    table
+};
+
+const INVERSE_SBOX: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut x = 0;
+    while x < 256 {
+        table[SBOX[x] as usize] = x as u8;
+        x += 1;
+    }
+    table
+};
+
+/// The first row of the MixColumns matrix and of its inverse; each row after is the one
+/// before rotated right.
+const FORWARD: [u8; 4] = [2, 3, 1, 1];
+const INVERSE: [u8; 4] = [14, 11, 13, 9];
+
+fn xor(a: [u8; 16], b: [u8; 16]) -> [u8; 16] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+fn sub_bytes(state: [u8; 16], sbox: &[u8; 256]) -> [u8; 16] {
+    state.map(|byte| sbox[byte as usize])
+}
+
+/// Rotates row `r` left by `r` columns.
+fn shift_rows(state: [u8; 16]) -> [u8; 16] {
+    std::array::from_fn(|i| {
+        let (row, column) = (i % 4, i / 4);
+        state[row + 4 * ((column + row) % 4)]
+    })
+}
+
+fn inverse_shift_rows(state: [u8; 16]) -> [u8; 16] {
+    std::array::from_fn(|i| {
+        let (row, column) = (i % 4, i / 4);
+        state[row + 4 * ((column + 4 - row) % 4)]
+    })
+}
+
+fn mix_columns(state: [u8; 16], matrix: [u8; 4]) -> [u8; 16] {
+    std::array::from_fn(|i| {
+        let (row, column) = (i % 4, i / 4);
+        (0..4).fold(0, |sum, j| {
+            sum ^ multiply(matrix[(j + 4 - row) % 4], state[4 * column + j])
+        })
+    })
+}
+
+/// AESKEYGENASSIST: the S-box applied to doublewords 1 and 3 of the source, each as it is
+/// and rotated right a byte with the round constant in the immediate added.
+fn key_generation_assist(source: [u8; 16], imm: u8) -> [u8; 16] {
+    let [_, x1, _, x3] = dwords(&sub_bytes(source, &SBOX));
+    let rotate = |x: u32| x.rotate_right(8) ^ imm as u32;
+    from_dwords([x1, rotate(x1), x3, rotate(x3)])
+}
+
+/// Multiplies two polynomials over GF(2), with a bit for each coefficient.
+fn carryless_multiply(a: u64, b: u64) -> u128 {
+    (0..64)
+        .filter(|i| b >> i & 1 != 0)
+        .fold(0, |product, i| product ^ (a as u128) << i)
+}
+
+fn dwords(vector: &[u8]) -> [u32; 4] {
+    std::array::from_fn(|i| u32::from_le_bytes(vector[4 * i..4 * i + 4].try_into().unwrap()))
+}
+
+fn from_dwords(dwords: [u32; 4]) -> [u8; 16] {
+    std::array::from_fn(|i| dwords[i / 4].to_le_bytes()[i % 4])
+}
+
+/// The SHA instructions, on the doublewords of their sources, least significant first.
+fn sha(mnemonic: Mnemonic, a: [u32; 4], b: [u32; 4], imm: u8, xmm0: [u32; 4]) -> [u32; 4] {
+    use Mnemonic::*;
+    let [a0, a1, a2, a3] = a;
+    let [b0, b1, b2, b3] = b;
+    match mnemonic {
+        Sha1rnds4 => {
+            // Four rounds with the function and constant the immediate picks. The first
+            // message word has E already added, so E starts out as zero.
+            let (f, k): (fn(u32, u32, u32) -> u32, u32) = match imm & 3 {
+                0 => (|b, c, d| (b & c) ^ (!b & d), 0x5A82_7999),
+                1 => (|b, c, d| b ^ c ^ d, 0x6ED9_EBA1),
+                2 => (|b, c, d| (b & c) ^ (b & d) ^ (c & d), 0x8F1B_BCDC),
+                _ => (|b, c, d| b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let [w0, w1, w2, w3] = [b3, b2, b1, b0];
+            let (mut a, mut b, mut c, mut d, mut e) = (a3, a2, a1, a0, 0);
+            for w in [w0, w1, w2, w3] {
+                let next = f(b, c, d)
+                    .wrapping_add(a.rotate_left(5))
+                    .wrapping_add(w)
+                    .wrapping_add(e)
+                    .wrapping_add(k);
+                (e, d, c, b, a) = (d, c, b.rotate_left(30), a, next);
+            }
+            [d, c, b, a]
+        }
+        // The next E, from A of four rounds before, added to the first message word
+        Sha1nexte => [b0, b1, b2, b3.wrapping_add(a3.rotate_left(30))],
+        Sha1msg1 => [a0 ^ b2, a1 ^ b3, a2 ^ a0, a3 ^ a1],
+        Sha1msg2 => {
+            let w16 = (a3 ^ b2).rotate_left(1);
+            let w17 = (a2 ^ b1).rotate_left(1);
+            let w18 = (a1 ^ b0).rotate_left(1);
+            let w19 = (a0 ^ w16).rotate_left(1);
+            [w19, w18, w17, w16]
+        }
+        Sha256rnds2 => {
+            // Two rounds on A, B, E and F from the second source and C, D, G and H from the
+            // first, leaving the new A, B, E and F
+            let (mut a, mut b, mut c, mut d) = (b3, b2, a3, a2);
+            let (mut e, mut f, mut g, mut h) = (b1, b0, a1, a0);
+            for wk in [xmm0[0], xmm0[1]] {
+                let t1 = h
+                    .wrapping_add(e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25))
+                    .wrapping_add((e & f) ^ (!e & g))
+                    .wrapping_add(wk);
+                let t2 = (a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22))
+                    .wrapping_add((a & b) ^ (a & c) ^ (b & c));
+                (h, g, f, e) = (g, f, e, d.wrapping_add(t1));
+                (d, c, b, a) = (c, b, a, t1.wrapping_add(t2));
+            }
+            [f, e, b, a]
+        }
+        Sha256msg1 => {
+            let sigma0 = |x: u32| x.rotate_right(7) ^ x.rotate_right(18) ^ x >> 3;
+            [
+                a0.wrapping_add(sigma0(a1)),
+                a1.wrapping_add(sigma0(a2)),
+                a2.wrapping_add(sigma0(a3)),
+                a3.wrapping_add(sigma0(b0)),
+            ]
+        }
+        Sha256msg2 => {
+            let sigma1 = |x: u32| x.rotate_right(17) ^ x.rotate_right(19) ^ x >> 10;
+            let w16 = a0.wrapping_add(sigma1(b2));
+            let w17 = a1.wrapping_add(sigma1(b3));
+            let w18 = a2.wrapping_add(sigma1(w16));
+            let w19 = a3.wrapping_add(sigma1(w17));
+            [w16, w17, w18, w19]
+        }
+        _ => unreachable!("{:?} is not a cryptographic instruction", mnemonic),
+    }
+}
@@ -0,0 +1,987 @@
+//! SSE floating-point arithmetic, comparisons and conversions on packed and scalar single-
+//! and double-precision elements, under the rounding and exception controls of MXCSR, along
+//! with FMA3's fused multiply-adds, F16C's half-precision conversions and the operations
+//! AVX-512 added.
+
+use super::super::packed::{lane, set_lane, signed};
+use super::{DAZ, FTZ, MASK_SHIFT};
+use crate::amd64::decode::Mnemonic;
+use crate::amd64::softfloat::transcendental::PI;
+use crate::amd64::softfloat::{Class, Env, Flags, Float, Format, Rounding, DOUBLE, HALF, SINGLE};
+use std::cmp::Ordering;
+
+/// The environment an SSE instruction computes in, taken from MXCSR.
+pub(super) struct Context {
+    pub(super) env: Env,
+    /// Treat denormal operands as zeros (MXCSR.DAZ)
+    daz: bool,
+    /// Whether the element being computed has a denormal operand, and whether it has a NaN
+    /// one, which takes precedence
+    denormal: bool,
+    nan: bool,
+    /// The elements an EVEX opmask writes, None for all of them. The others raise no
+    /// exceptions.
+    pub(super) opmask: Option<u64>,
+}
+
+impl Context {
+    pub(super) fn new(mxcsr: u32) -> Context {
+        let mut env = Env::new(Rounding::from_bits(mxcsr >> 13));
+        env.masked = Flags::from_bits_truncate((mxcsr >> MASK_SHIFT) as u8);
+        env.flush_to_zero = mxcsr & FTZ != 0;
+        Context {
+            env,
+            daz: mxcsr & DAZ != 0,
+            denormal: false,
+            nan: false,
+            opmask: None,
+        }
+    }
+
+    /// Computes element `i` of a result. Its denormal operands only raise the denormal
+    /// exception if it has no NaN operand and raises no invalid exception, both of which
+    /// take precedence, and it raises none at all if the opmask leaves it out.
+    fn element<T>(&mut self, i: usize, compute: impl FnOnce(&mut Context) -> T) -> T {
+        let raised = std::mem::replace(&mut self.env.flags, Flags::empty());
+        self.denormal = false;
+        self.nan = false;
+        let result = compute(self);
+        if self.denormal && !self.nan && !self.env.flags.contains(Flags::INVALID) {
+            self.env.flags |= Flags::DENORMAL;
+        }
+        if self.opmask.is_some_and(|mask| mask >> i & 1 == 0) {
+            self.env.flags = Flags::empty();
+        }
+        self.env.flags |= raised;
+        result
+    }
+
+    /// Unpacks an operand of the element being computed. Denormals become zeros under DAZ,
+    /// and otherwise raise the denormal exception if `report` is set: conversions to
+    /// integers never raise it.
+    fn load(&mut self, format: Format, bits: u64, report: bool) -> Float {
+        let value = format.unpack(bits as u128);
+        self.nan |= value.is_nan();
+        if format.is_denormal(bits as u128) {
+            if self.daz {
+                return Float::zero(value.sign);
+            }
+            self.denormal |= report;
+        }
+        value
+    }
+
+    fn store(&mut self, format: Format, value: Float) -> u64 {
+        format.pack(value, &mut self.env) as u64
+    }
+}
+
+/// The element format of an arithmetic or comparison instruction, its width in bytes and
+/// whether only the low element takes part, from the suffix of its mnemonic.
+fn shape(mnemonic: Mnemonic) -> (Format, usize, bool) {
+    let name = mnemonic.name();
+    match &name[name.len() - 2..] {
+        "ps" => (SINGLE, 4, false),
+        "pd" => (DOUBLE, 8, false),
+        "ss" => (SINGLE, 4, true),
+        "sd" => (DOUBLE, 8, true),
+        _ => unreachable!("{} isn't a floating-point instruction", name),
+    }
+}
+
+/// The lanes of `a` an instruction computes: just the first for scalar forms, whose other
+/// elements pass through from the destination.
+fn lanes(a: &[u8], width: usize, scalar: bool) -> usize {
+    if scalar {
+        1
+    } else {
+        a.len() / width
+    }
+}
+
+/// ADD, SUB, MUL, DIV, MIN, MAX, SQRT, RCP and RSQRT, the SSE3 ADDSUB, HADD and HSUB, and
+/// AVX-512's VRCP14, VRSQRT14, VGETEXP and VSCALEF, with `a` the destination and `b` the
+/// source.
+pub(super) fn arithmetic(mnemonic: Mnemonic, a: &[u8], b: &[u8], cx: &mut Context) -> Vec<u8> {
+    use Mnemonic::*;
+    let (format, width, scalar) = shape(mnemonic);
+    let mut result = a.to_vec();
+    for i in 0..lanes(a, width, scalar) {
+        let (x_bits, y_bits) = match mnemonic {
+            Haddps | Haddpd | Hsubps | Hsubpd => {
+                // Adjacent pairs of elements from each block of the destination, then the
+                // source
+                let per_block = 16 / width;
+                let (start, k) = (i - i % per_block, i % per_block);
+                let (source, k) = if k < per_block / 2 {
+                    (a, k)
+                } else {
+                    (b, k - per_block / 2)
+                };
+                let first = start + 2 * k;
+                (lane(source, first, width), lane(source, first + 1, width))
+            }
+            _ => (lane(a, i, width), lane(b, i, width)),
+        };
+        let bits = cx.element(i, |cx| match mnemonic {
+            Rcpps | Rcpss | Rsqrtps | Rsqrtss | Vrcp14ps | Vrcp14pd | Vrcp14ss | Vrcp14sd
+            | Vrsqrt14ps | Vrsqrt14pd | Vrsqrt14ss | Vrsqrt14sd => {
+                approximate(mnemonic, format, y_bits, cx)
+            }
+            // The exponent of the source as a floating-point value: minus infinity for zeros
+            // and plus infinity for infinities of either sign
+            Vgetexpps | Vgetexppd | Vgetexpss | Vgetexpsd => {
+                let y = cx.load(format, y_bits, true);
+                let value = match y.class {
+                    Class::Zero => Float::infinity(true),
+                    Class::Infinity => Float::infinity(false),
+                    Class::Finite => Float::from_int(y.exponent as i128),
+                    _ => Float::propagate_nan(&[y], &mut cx.env).unwrap(),
+                };
+                cx.store(format, value)
+            }
+            // The destination times two to the power of the source, rounded down to an
+            // integer
+            Vscalefps | Vscalefpd | Vscalefss | Vscalefsd => {
+                let x = cx.load(format, x_bits, true);
+                let y = cx.load(format, y_bits, true);
+                let value = match (x.class, y.class) {
+                    _ if x.is_nan() || y.is_nan() => {
+                        Float::propagate_nan(&[x, y], &mut cx.env).unwrap()
+                    }
+                    (Class::Zero, Class::Infinity) if !y.sign => Float::invalid(&mut cx.env),
+                    (Class::Infinity, Class::Infinity) if y.sign => Float::invalid(&mut cx.env),
+                    (Class::Zero | Class::Infinity, _) => x,
+                    (_, Class::Infinity) if y.sign => Float::zero(x.sign),
+                    (_, Class::Infinity) => Float::infinity(x.sign),
+                    _ => {
+                        // Any power past the range of the format is as good as the next, and
+                        // rounding it raises nothing
+                        let mut quiet = Env::new(Rounding::Down);
+                        let power = y.round_to_integral(Rounding::Down, &mut quiet);
+                        let power = power.to_int(32, Rounding::Down, &mut quiet);
+                        let power =
+                            power.unwrap_or(if y.sign { i32::MIN } else { i32::MAX } as i64);
+                        x.scale(power as i32)
+                    }
+                };
+                cx.store(format, value)
+            }
+            Minps | Minpd | Minss | Minsd | Maxps | Maxpd | Maxss | Maxsd => {
+                let x = cx.load(format, x_bits, true);
+                let y = cx.load(format, y_bits, true);
+                let wanted = match mnemonic {
+                    Minps | Minpd | Minss | Minsd => Ordering::Less,
+                    _ => Ordering::Greater,
+                };
+                // NaNs and equal values, zeros of either sign included, give the source
+                let (value, bits) = if x.compare(y, true, &mut cx.env) == Some(wanted) {
+                    (x, x_bits)
+                } else {
+                    (y, y_bits)
+                };
+                // A denormal comes back as the zero DAZ took it for
+                if cx.daz && format.is_denormal(bits as u128) {
+                    cx.store(format, value)
+                } else {
+                    bits
+                }
+            }
+            Sqrtps | Sqrtpd | Sqrtss | Sqrtsd => {
+                let y = cx.load(format, y_bits, true);
+                let value = y.sqrt(&mut cx.env);
+                cx.store(format, value)
+            }
+            _ => {
+                let x = cx.load(format, x_bits, true);
+                let y = cx.load(format, y_bits, true);
+                let value = match mnemonic {
+                    Addps | Addpd | Addss | Addsd | Haddps | Haddpd => x.add(y, &mut cx.env),
+                    Subps | Subpd | Subss | Subsd | Hsubps | Hsubpd => x.sub(y, &mut cx.env),
+                    // Subtracts in the even elements and adds in the odd ones
+                    Addsubps | Addsubpd if i % 2 == 0 => x.sub(y, &mut cx.env),
+                    Addsubps | Addsubpd => x.add(y, &mut cx.env),
+                    Mulps | Mulpd | Mulss | Mulsd => x.mul(y, &mut cx.env),
+                    Divps | Divpd | Divss | Divsd => x.div(y, &mut cx.env),
+                    _ => unreachable!("{:?} isn't an arithmetic instruction", mnemonic),
+                };
+                cx.store(format, value)
+            }
+        });
+        set_lane(&mut result, i, width, bits);
+    }
+    result
+}
+
+/// Whether an instruction is one of FMA3's.
+pub(super) fn is_fma(mnemonic: Mnemonic) -> bool {
+    let name = mnemonic.name();
+    name.starts_with("Vfm") || name.starts_with("Vfnm")
+}
+
+/// The FMA3 instructions, on their three operands in order: the destination and the two
+/// sources. The digits of the mnemonic say which two are multiplied and which one is added
+/// to the product, each element rounded just once. VFNM negates the product, VFMSUB
+/// subtracts the addend, and VFMADDSUB and VFMSUBADD subtract it in the even and odd
+/// elements respectively, adding it in the others.
+pub(super) fn fused_multiply_add(
+    mnemonic: Mnemonic,
+    operands: [&[u8]; 3],
+    cx: &mut Context,
+) -> Vec<u8> {
+    let (format, width, scalar) = shape(mnemonic);
+    let name = mnemonic.name();
+    let (operation, order) = name[2..name.len() - 2].split_at(name.len() - 7);
+    let order: Vec<usize> = order.bytes().map(|digit| (digit - b'1') as usize).collect();
+    let mut result = operands[0].to_vec();
+    for i in 0..lanes(operands[0], width, scalar) {
+        let subtract = match operation {
+            "msub" | "nmsub" => true,
+            "maddsub" => i % 2 == 0,
+            "msubadd" => i % 2 == 1,
+            _ => false,
+        };
+        let bits = cx.element(i, |cx| {
+            let values = operands.map(|operand| cx.load(format, lane(operand, i, width), true));
+            let (x, y, z) = (values[order[0]], values[order[1]], values[order[2]]);
+            // A NaN is the first one in the order of the mnemonic's digits, the multiplicands
+            // then the addend, and never negated
+            let product_invalid = matches!(
+                (x.class, y.class),
+                (Class::Infinity, Class::Zero) | (Class::Zero, Class::Infinity)
+            );
+            let value = match Float::propagate_nan(&[x, y, z], &mut cx.env) {
+                Some(nan) => {
+                    if product_invalid {
+                        cx.env.flags |= Flags::INVALID;
+                    }
+                    nan
+                }
+                None => {
+                    let x = if operation.starts_with('n') {
+                        x.negate()
+                    } else {
+                        x
+                    };
+                    let z = if subtract { z.negate() } else { z };
+                    x.mul_add(y, z, &mut cx.env)
+                }
+            };
+            cx.store(format, value)
+        });
+        set_lane(&mut result, i, width, bits);
+    }
+    result
+}
+
+/// VCVTPH2PS: widens the half-precision elements of `source` to single precision, as many
+/// as fill `len` bytes. That's exact: denormal halves are converted whatever DAZ says, and
+/// only signaling NaNs raise an exception.
+pub(super) fn half_to_single(source: &[u8], len: usize, cx: &mut Context) -> Vec<u8> {
+    let mut result = vec![0; len];
+    for i in 0..len / 4 {
+        let value = HALF.unpack(lane(source, i, 2) as u128);
+        let value = Float::propagate_nan(&[value], &mut cx.env).unwrap_or(value);
+        set_lane(&mut result, i, 4, cx.store(SINGLE, value));
+    }
+    result
+}
+
+/// VCVTPS2PH: narrows the single-precision elements of `source` to half precision, rounded
+/// in the direction the low bits of `imm` give unless bit 2 defers to MXCSR. FTZ doesn't
+/// apply: tiny results become half-precision denormals.
+pub(super) fn single_to_half(source: &[u8], imm: u8, cx: &mut Context) -> Vec<u8> {
+    if imm & 0x04 == 0 {
+        cx.env.rounding = Rounding::from_bits(imm as u32);
+    }
+    cx.env.flush_to_zero = false;
+    let mut result = vec![0; source.len() / 2];
+    for i in 0..source.len() / 4 {
+        let bits = cx.element(i, |cx| {
+            let value = cx.load(SINGLE, lane(source, i, 4), true);
+            let value = Float::propagate_nan(&[value], &mut cx.env).unwrap_or(value);
+            cx.store(HALF, value)
+        });
+        set_lane(&mut result, i, 2, bits);
+    }
+    result
+}
+
+/// ROUNDPS, ROUNDPD, ROUNDSS and ROUNDSD: rounds each element of `b` to an integer, in the
+/// direction the low bits of `imm` give unless bit 2 defers to MXCSR. Bit 3 suppresses the
+/// precision exception. AVX-512's VRNDSCALE rounds to the number of fraction bits in the
+/// high nibble of `imm` instead, and VREDUCE gives what that rounding takes away.
+pub(super) fn round(mnemonic: Mnemonic, a: &[u8], b: &[u8], imm: u8, cx: &mut Context) -> Vec<u8> {
+    use Mnemonic::*;
+    let (format, width, scalar) = shape(mnemonic);
+    if imm & 0x04 == 0 {
+        cx.env.rounding = Rounding::from_bits(imm as u32);
+    }
+    let fraction_bits = match mnemonic {
+        Roundps | Roundpd | Roundss | Roundsd => 0,
+        _ => (imm >> 4) as i32,
+    };
+    let reduce = matches!(mnemonic, Vreduceps | Vreducepd | Vreducess | Vreducesd);
+    let mut result = a.to_vec();
+    for i in 0..lanes(a, width, scalar) {
+        let bits = cx.element(i, |cx| {
+            let value = cx.load(format, lane(b, i, width), false);
+            let rounding = cx.env.rounding;
+            let rounded = value
+                .scale(fraction_bits)
+                .round_to_integral(rounding, &mut cx.env)
+                .scale(-fraction_bits);
+            let value = match value.class {
+                Class::Infinity if reduce => Float::zero(false),
+                _ if reduce => value.sub(rounded, &mut cx.env),
+                _ => rounded,
+            };
+            cx.store(format, value)
+        });
+        set_lane(&mut result, i, width, bits);
+    }
+    if imm & 0x08 != 0 {
+        cx.env.flags.remove(Flags::INEXACT);
+    }
+    result
+}
+
+/// VGETMANT: the significand of each element of `b`, scaled into the interval the low two
+/// bits of `imm` pick: [1, 2), [1/2, 2), [1/2, 1) or [3/4, 3/2). Bit 2 clears the sign,
+/// and bit 3 makes negative elements invalid. Zeros and infinities give one.
+pub(super) fn mantissa(
+    mnemonic: Mnemonic,
+    a: &[u8],
+    b: &[u8],
+    imm: u8,
+    cx: &mut Context,
+) -> Vec<u8> {
+    let (format, width, scalar) = shape(mnemonic);
+    let mut result = a.to_vec();
+    for i in 0..lanes(a, width, scalar) {
+        let bits = cx.element(i, |cx| {
+            let value = cx.load(format, lane(b, i, width), true);
+            let sign = value.sign && imm & 0x04 == 0;
+            let value = match value.class {
+                _ if value.is_nan() => Float::propagate_nan(&[value], &mut cx.env).unwrap(),
+                _ if value.sign && imm & 0x08 != 0 => Float::invalid(&mut cx.env),
+                Class::Zero | Class::Infinity => Float::normalized(sign, 127, 1),
+                _ => {
+                    // In [1, 2), then halved for odd exponents, always, or from 3/2 up
+                    let exponent = match imm & 3 {
+                        0 => 0,
+                        1 => -(value.exponent & 1),
+                        2 => -1,
+                        _ => -((value.significand >> 126 & 1) as i32),
+                    };
+                    Float {
+                        sign,
+                        exponent,
+                        ..value
+                    }
+                }
+            };
+            cx.store(format, value)
+        });
+        set_lane(&mut result, i, width, bits);
+    }
+    result
+}
+
+/// VRANGE: the smaller or larger of each pair of elements by the low bit of `imm`, by value
+/// or, if bit 1 is set, by magnitude, with negative zeros and negative values of the same
+/// magnitude the smaller. Bits 2 and 3 then give it the sign of `a`, its own, or a positive
+/// or negative one.
+pub(super) fn range(mnemonic: Mnemonic, a: &[u8], b: &[u8], imm: u8, cx: &mut Context) -> Vec<u8> {
+    let (format, width, scalar) = shape(mnemonic);
+    let mut result = a.to_vec();
+    for i in 0..lanes(a, width, scalar) {
+        let bits = cx.element(i, |cx| {
+            let x = cx.load(format, lane(a, i, width), true);
+            let y = cx.load(format, lane(b, i, width), true);
+            if let Some(nan) = Float::propagate_nan(&[x, y], &mut cx.env) {
+                return cx.store(format, nan);
+            }
+            let (p, q) = if imm & 0x02 != 0 {
+                (x.abs(), y.abs())
+            } else {
+                (x, y)
+            };
+            let ordering = p.compare(q, false, &mut cx.env).unwrap();
+            let ordering = ordering.then(y.sign.cmp(&x.sign));
+            let larger = imm & 0x01 != 0;
+            let value = if (ordering == Ordering::Greater) == larger {
+                x
+            } else {
+                y
+            };
+            let sign = match imm >> 2 & 3 {
+                0 => x.sign,
+                1 => value.sign,
+                2 => false,
+                _ => true,
+            };
+            cx.store(format, Float { sign, ..value })
+        });
+        set_lane(&mut result, i, width, bits);
+    }
+    result
+}
+
+/// VFIXUPIMM: replaces each element of `d` by what the element of `c` answers for the
+/// class of the element of `a`, by four bits per class: quiet NaN, signaling NaN, zero, one,
+/// minus infinity, plus infinity, negative and positive. The answer keeps the destination,
+/// passes the element of `a` through, quiets it, or gives one of twelve constants. The bits
+/// of `imm` raise divide-by-zero or invalid for some classes.
+pub(super) fn fix_up(
+    mnemonic: Mnemonic,
+    [d, a, c]: [&[u8]; 3],
+    imm: u8,
+    cx: &mut Context,
+) -> Vec<u8> {
+    let (format, width, scalar) = shape(mnemonic);
+    let mut constants = Env::new(Rounding::NearestEven);
+    let mut constant = |value: Float| format.pack(value, &mut constants) as u64;
+    let one = constant(Float::from_int(1));
+    let max = constant(Float::infinity(false)) - 1;
+    let sign = 1 << (8 * width - 1);
+    let mut result = d.to_vec();
+    for i in 0..lanes(d, width, scalar) {
+        let source = lane(a, i, width);
+        // Denormals are positive zeros under DAZ
+        let source = if cx.daz && format.is_denormal(source as u128) {
+            0
+        } else {
+            source
+        };
+        let value = format.unpack(source as u128);
+        let class = match value.class {
+            Class::QuietNaN => 0,
+            Class::SignalingNaN => 1,
+            Class::Zero => 2,
+            _ if source == one => 3,
+            Class::Infinity => 4 + !value.sign as usize,
+            Class::Finite => 6 + !value.sign as usize,
+        };
+        let raised = match class {
+            2 => [(0x01, Flags::DIVIDE_BY_ZERO), (0x02, Flags::INVALID)],
+            3 => [(0x04, Flags::DIVIDE_BY_ZERO), (0x08, Flags::INVALID)],
+            1 => [(0, Flags::empty()), (0x10, Flags::INVALID)],
+            4 => [(0, Flags::empty()), (0x20, Flags::INVALID)],
+            6 => [(0, Flags::empty()), (0x40, Flags::INVALID)],
+            5 => [(0, Flags::empty()), (0x80, Flags::INVALID)],
+            _ => [(0, Flags::empty()); 2],
+        };
+        cx.element(i, |cx| {
+            for (bit, flag) in raised {
+                if imm & bit != 0 {
+                    cx.env.flags |= flag;
+                }
+            }
+        });
+        let bits = match lane(c, i, width) >> (4 * class) & 0x0F {
+            0 => lane(d, i, width),
+            1 => source,
+            2 => constant(value.quiet()),
+            3 => constant(Float::default_nan()),
+            4 => constant(Float::infinity(true)),
+            5 => constant(Float::infinity(false)),
+            6 => constant(Float::infinity(value.sign)),
+            7 => sign,
+            8 => 0,
+            9 => sign | one,
+            10 => one,
+            11 => constant(Float::from_int(1).scale(-1)),
+            12 => constant(Float::from_int(90)),
+            13 => constant(PI.scale(-1)),
+            14 => max,
+            _ => sign | max,
+        };
+        set_lane(&mut result, i, width, bits);
+    }
+    result
+}
+
+/// VFPCLASS: whether an element is in any of the classes `imm` selects, by bit: quiet NaN,
+/// positive zero, negative zero, positive infinity, negative infinity, denormal, negative
+/// finite value and signaling NaN. DAZ doesn't apply.
+pub(super) fn classify(mnemonic: Mnemonic, bits: u64, imm: u8) -> bool {
+    let (format, ..) = shape(mnemonic);
+    let value = format.unpack(bits as u128);
+    let classes = match value.class {
+        Class::QuietNaN => 0x01,
+        Class::Zero => 0x02 << value.sign as u8,
+        Class::Infinity => 0x08 << value.sign as u8,
+        Class::Finite => {
+            ((format.is_denormal(bits as u128) as u8) << 5) | ((value.sign as u8) << 6)
+        }
+        Class::SignalingNaN => 0x80,
+    };
+    classes & imm != 0
+}
+
+/// DPPS and DPPD: within each block, multiplies the elements the high nibble of `imm`
+/// selects, sums the products pairwise and stores the sum in the elements its low nibble
+/// selects, clearing the others. Every product and partial sum is rounded.
+pub(super) fn dot_product(
+    mnemonic: Mnemonic,
+    a: &[u8],
+    b: &[u8],
+    imm: u8,
+    cx: &mut Context,
+) -> Vec<u8> {
+    let (format, width, _) = shape(mnemonic);
+    let lanes = 16 / width;
+    let mut result = vec![0; a.len()];
+    for start in (0..a.len()).step_by(16) {
+        let mut terms = (0..lanes)
+            .map(|i| {
+                if imm >> (4 + i) & 1 == 0 {
+                    return Float::zero(false);
+                }
+                let product = cx.element(i, |cx| {
+                    let x = cx.load(format, lane(&a[start..], i, width), true);
+                    let y = cx.load(format, lane(&b[start..], i, width), true);
+                    let product = x.mul(y, &mut cx.env);
+                    cx.store(format, product)
+                });
+                format.unpack(product as u128)
+            })
+            .collect::<Vec<_>>();
+        while terms.len() > 1 {
+            terms = terms
+                .chunks(2)
+                .map(|pair| {
+                    let sum = pair[0].add(pair[1], &mut cx.env);
+                    format.unpack(cx.store(format, sum) as u128)
+                })
+                .collect();
+        }
+        let sum = cx.store(format, terms[0]);
+        for i in 0..lanes {
+            if imm >> i & 1 != 0 {
+                set_lane(&mut result[start..], i, width, sum);
+            }
+        }
+    }
+    result
+}
+
+/// RCP and RSQRT, and AVX-512's VRCP14 and VRSQRT14. Hardware only promises 12 and 14 bits
+/// of precision; this gives the correctly rounded result. No exception is ever raised.
+/// The SSE forms flush denormal operands and results to zero; the AVX-512 ones only do as
+/// DAZ and FTZ say.
+fn approximate(mnemonic: Mnemonic, format: Format, bits: u64, cx: &Context) -> u64 {
+    use Mnemonic::*;
+    let sse = matches!(mnemonic, Rcpps | Rcpss | Rsqrtps | Rsqrtss);
+    let mut env = Env::new(Rounding::NearestEven);
+    env.flush_to_zero = sse || cx.env.flush_to_zero;
+    let mut value = format.unpack(bits as u128);
+    if format.is_denormal(bits as u128) && (sse || cx.daz) {
+        value = Float::zero(value.sign);
+    }
+    if matches!(
+        mnemonic,
+        Rsqrtps | Rsqrtss | Vrsqrt14ps | Vrsqrt14pd | Vrsqrt14ss | Vrsqrt14sd
+    ) {
+        value = value.sqrt(&mut env);
+    }
+    let one = Float::from_int(1);
+    format.pack(one.div(value, &mut env), &mut env) as u64
+}
+
+/// CMPPS and friends: sets each element to all ones where `predicate` holds, and to zero
+/// elsewhere. All 32 predicates are accepted; the legacy encodings only reach the first 8.
+pub(super) fn compare(
+    mnemonic: Mnemonic,
+    a: &[u8],
+    b: &[u8],
+    predicate: u8,
+    cx: &mut Context,
+) -> Vec<u8> {
+    let (format, width, scalar) = shape(mnemonic);
+    // The relation on ordered operands, the result on unordered ones, and whether quiet
+    // NaNs raise invalid, for the low four bits of the predicate
+    let (relation, unordered, signaling): (fn(Ordering) -> bool, bool, bool) =
+        match predicate & 0x0F {
+            0x0 => (|o| o == Ordering::Equal, false, false),
+            0x1 => (|o| o == Ordering::Less, false, true),
+            0x2 => (|o| o != Ordering::Greater, false, true),
+            0x3 => (|_| false, true, false),
+            0x4 => (|o| o != Ordering::Equal, true, false),
+            0x5 => (|o| o != Ordering::Less, true, true),
+            0x6 => (|o| o == Ordering::Greater, true, true),
+            0x7 => (|_| true, false, false),
+            0x8 => (|o| o == Ordering::Equal, true, false),
+            0x9 => (|o| o == Ordering::Less, true, true),
+            0xA => (|o| o != Ordering::Greater, true, true),
+            0xB => (|_| false, false, false),
+            0xC => (|o| o != Ordering::Equal, false, false),
+            0xD => (|o| o != Ordering::Less, false, true),
+            0xE => (|o| o == Ordering::Greater, false, true),
+            _ => (|_| true, true, false),
+        };
+    // The upper sixteen predicates flip whether quiet NaNs signal
+    let signaling = signaling != (predicate & 0x10 != 0);
+    let mut result = a.to_vec();
+    for i in 0..lanes(a, width, scalar) {
+        let holds = cx.element(i, |cx| {
+            let x = cx.load(format, lane(a, i, width), true);
+            let y = cx.load(format, lane(b, i, width), true);
+            match x.compare(y, signaling, &mut cx.env) {
+                Some(ordering) => relation(ordering),
+                None => unordered,
+            }
+        });
+        set_lane(&mut result, i, width, if holds { u64::MAX } else { 0 });
+    }
+    result
+}
+
+/// COMISS, COMISD, UCOMISS and UCOMISD: compares the low elements. The unordered forms only
+/// raise invalid for signaling NaNs.
+pub(super) fn ordered(
+    mnemonic: Mnemonic,
+    a: &[u8],
+    b: &[u8],
+    cx: &mut Context,
+) -> Option<Ordering> {
+    let (format, width, _) = shape(mnemonic);
+    let signaling = matches!(mnemonic, Mnemonic::Comiss | Mnemonic::Comisd);
+    cx.element(0, |cx| {
+        let x = cx.load(format, lane(a, 0, width), true);
+        let y = cx.load(format, lane(b, 0, width), true);
+        x.compare(y, signaling, &mut cx.env)
+    })
+}
+
+/// An element type of a conversion.
+#[derive(Clone, Copy)]
+enum Element {
+    Single,
+    Double,
+    Doubleword,
+    Quadword,
+    /// A general-purpose register or memory integer, as wide as the operand
+    Integer,
+}
+
+impl Element {
+    fn format(self) -> Option<Format> {
+        match self {
+            Element::Single => Some(SINGLE),
+            Element::Double => Some(DOUBLE),
+            Element::Doubleword | Element::Quadword | Element::Integer => None,
+        }
+    }
+
+    fn width(self, operand_len: usize) -> usize {
+        match self {
+            Element::Single | Element::Doubleword => 4,
+            Element::Double | Element::Quadword => 8,
+            Element::Integer => operand_len,
+        }
+    }
+}
+
+/// The source and destination element types of a conversion, whether it truncates rather
+/// than rounding as MXCSR says, and whether its integers are unsigned.
+fn conversion(mnemonic: Mnemonic) -> (Element, Element, bool, bool) {
+    use Element::*;
+    use Mnemonic::*;
+    match mnemonic {
+        Cvtps2pd | Cvtss2sd => (Single, Double, false, false),
+        Cvtpd2ps | Cvtsd2ss => (Double, Single, false, false),
+        Cvtdq2ps | Cvtpi2ps => (Doubleword, Single, false, false),
+        Cvtdq2pd | Cvtpi2pd => (Doubleword, Double, false, false),
+        Vcvtudq2ps => (Doubleword, Single, false, true),
+        Vcvtudq2pd => (Doubleword, Double, false, true),
+        Vcvtqq2ps => (Quadword, Single, false, false),
+        Vcvtqq2pd => (Quadword, Double, false, false),
+        Vcvtuqq2ps => (Quadword, Single, false, true),
+        Vcvtuqq2pd => (Quadword, Double, false, true),
+        Cvtsi2ss => (Integer, Single, false, false),
+        Cvtsi2sd => (Integer, Double, false, false),
+        Vcvtusi2ss => (Integer, Single, false, true),
+        Vcvtusi2sd => (Integer, Double, false, true),
+        Cvtps2dq | Cvtps2pi => (Single, Doubleword, false, false),
+        Cvttps2dq | Cvttps2pi => (Single, Doubleword, true, false),
+        Cvtpd2dq | Cvtpd2pi => (Double, Doubleword, false, false),
+        Cvttpd2dq | Cvttpd2pi => (Double, Doubleword, true, false),
+        Vcvtps2udq => (Single, Doubleword, false, true),
+        Vcvttps2udq => (Single, Doubleword, true, true),
+        Vcvtpd2udq => (Double, Doubleword, false, true),
+        Vcvttpd2udq => (Double, Doubleword, true, true),
+        Vcvtps2qq => (Single, Quadword, false, false),
+        Vcvttps2qq => (Single, Quadword, true, false),
+        Vcvtpd2qq => (Double, Quadword, false, false),
+        Vcvttpd2qq => (Double, Quadword, true, false),
+        Vcvtps2uqq => (Single, Quadword, false, true),
+        Vcvttps2uqq => (Single, Quadword, true, true),
+        Vcvtpd2uqq => (Double, Quadword, false, true),
+        Vcvttpd2uqq => (Double, Quadword, true, true),
+        Cvtss2si => (Single, Integer, false, false),
+        Cvttss2si => (Single, Integer, true, false),
+        Cvtsd2si => (Double, Integer, false, false),
+        Cvttsd2si => (Double, Integer, true, false),
+        Vcvtss2usi => (Single, Integer, false, true),
+        Vcvttss2usi => (Single, Integer, true, true),
+        Vcvtsd2usi => (Double, Integer, false, true),
+        Vcvttsd2usi => (Double, Integer, true, true),
+        _ => unreachable!("{:?} isn't a conversion", mnemonic),
+    }
+}
+
+/// Converts as many elements of `source` as fit into a `len`-byte result, leaving the rest
+/// of it zero. Integers that can't represent a value become the integer indefinite, the
+/// most negative one, or for unsigned integers all ones.
+pub(super) fn convert(mnemonic: Mnemonic, source: &[u8], len: usize, cx: &mut Context) -> Vec<u8> {
+    let (from, to, truncate, unsigned) = conversion(mnemonic);
+    let (from_width, to_width) = (from.width(source.len()), to.width(len));
+    let mut result = vec![0; len];
+    for i in 0..(source.len() / from_width).min(len / to_width) {
+        let bits = lane(source, i, from_width);
+        let bits = cx.element(i, |cx| {
+            let value = match from.format() {
+                Some(format) => cx.load(format, bits, to.format().is_some()),
+                None if unsigned => Float::from_int(bits as i128),
+                None => Float::from_int(signed(bits, from_width) as i128),
+            };
+            match to.format() {
+                Some(format) => {
+                    // Raises invalid for signaling NaNs, which are then stored quiet
+                    let value = Float::propagate_nan(&[value], &mut cx.env).unwrap_or(value);
+                    cx.store(format, value)
+                }
+                None => {
+                    let rounding = if truncate {
+                        Rounding::TowardZero
+                    } else {
+                        cx.env.rounding
+                    };
+                    let int_bits = 8 * to_width as u32;
+                    if unsigned {
+                        let int = value.to_uint(int_bits, rounding, &mut cx.env);
+                        int.unwrap_or(u64::MAX >> (64 - int_bits))
+                    } else {
+                        match value.to_int(int_bits, rounding, &mut cx.env) {
+                            Some(int) => int as u64,
+                            None => 1 << (int_bits - 1),
+                        }
+                    }
+                }
+            }
+        });
+        set_lane(&mut result, i, to_width, bits);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MXCSR: u32 = 0x1F80;
+
+    fn singles(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn scalar_forms_keep_the_upper_elements() {
+        let mut cx = Context::new(MXCSR);
+        let a = singles(&[1.0, 2.0, 3.0, 4.0]);
+        let b = singles(&[10.0, 20.0, 30.0, 40.0]);
+        assert_eq!(
+            arithmetic(Mnemonic::Addss, &a, &b, &mut cx),
+            singles(&[11.0, 2.0, 3.0, 4.0])
+        );
+        assert_eq!(
+            arithmetic(Mnemonic::Mulps, &a, &b, &mut cx),
+            singles(&[10.0, 40.0, 90.0, 160.0])
+        );
+        assert!(cx.env.flags.is_empty());
+    }
+
+    #[test]
+    fn min_and_max_return_the_source_for_nans_and_zeros() {
+        let mut cx = Context::new(MXCSR);
+        let a = singles(&[f32::NAN, 0.0, 1.0, -0.0]);
+        let b = singles(&[1.0, f32::NAN, 2.0, 0.0]);
+        assert_eq!(
+            arithmetic(Mnemonic::Minps, &a, &b, &mut cx),
+            singles(&[1.0, f32::NAN, 1.0, 0.0])
+        );
+        assert_eq!(cx.env.flags, Flags::INVALID);
+    }
+
+    #[test]
+    fn min_and_max_return_denormals_flushed_by_daz() {
+        let mut cx = Context::new(MXCSR | DAZ);
+        let a = singles(&[
+            f32::from_bits(0x007F_FFFF),
+            f32::from_bits(0x8000_0001),
+            1.0,
+            1.0,
+        ]);
+        let b = singles(&[1.0, 1.0, 2.0, -2.0]);
+        assert_eq!(
+            arithmetic(Mnemonic::Minps, &a, &b, &mut cx),
+            singles(&[0.0, -0.0, 1.0, -2.0])
+        );
+        let one = 1u64.to_le_bytes();
+        let result = arithmetic(Mnemonic::Minsd, &2f64.to_le_bytes(), &one, &mut cx);
+        assert_eq!(result, 0u64.to_le_bytes());
+        assert!(cx.env.flags.is_empty());
+    }
+
+    #[test]
+    fn compare_predicates() {
+        let a = singles(&[1.0, 2.0, f32::NAN, 4.0]);
+        let b = singles(&[1.0, 1.0, 0.0, 5.0]);
+        let lanes = |predicate| {
+            let mut cx = Context::new(MXCSR);
+            let result = compare(Mnemonic::Cmpps, &a, &b, predicate, &mut cx);
+            let mask = (0..4).map(|i| lane(&result, i, 4) != 0).collect::<Vec<_>>();
+            (mask, cx.env.flags.contains(Flags::INVALID))
+        };
+        assert_eq!(lanes(0), (vec![true, false, false, false], false));
+        assert_eq!(lanes(1), (vec![false, false, false, true], true));
+        assert_eq!(lanes(4), (vec![false, true, true, true], false));
+        assert_eq!(lanes(7), (vec![true, true, false, true], false));
+        assert_eq!(lanes(0x0E), (vec![false, true, false, false], true));
+        assert_eq!(lanes(0x10), (vec![true, false, false, false], true));
+    }
+
+    #[test]
+    fn conversions_to_integers_round_per_mxcsr_and_saturate_to_indefinite() {
+        let mut cx = Context::new(MXCSR);
+        let source = singles(&[2.5, -1.5, 3e9, f32::NAN]);
+        let result = convert(Mnemonic::Cvtps2dq, &source, 16, &mut cx);
+        let lanes = (0..4).map(|i| lane(&result, i, 4)).collect::<Vec<_>>();
+        assert_eq!(lanes, [2, (-2i32) as u32 as u64, 0x8000_0000, 0x8000_0000]);
+        let result = convert(Mnemonic::Cvttps2dq, &source, 16, &mut cx);
+        assert_eq!(lane(&result, 0, 4), 2);
+        assert_eq!(lane(&result, 1, 4), (-1i32) as u32 as u64);
+        assert_eq!(cx.env.flags, Flags::INVALID | Flags::INEXACT);
+    }
+
+    #[test]
+    fn fused_multiply_add_rounds_once() {
+        // (1 + 2^-12)^2 - (1 + 2^-11) is 2^-24, which rounding the product first would lose
+        let x = singles(&[1.0 + 2f32.powi(-12); 4]);
+        let z = singles(&[1.0 + 2f32.powi(-11); 4]);
+        let mut cx = Context::new(MXCSR);
+        let result = fused_multiply_add(Mnemonic::Vfmsub213ps, [&x, &x, &z], &mut cx);
+        assert_eq!(result, singles(&[2f32.powi(-24); 4]));
+        assert!(cx.env.flags.is_empty());
+        // Subtracting in the even elements and adding in the odd ones, and negating the
+        // product
+        let (a, b, c) = (singles(&[2.0; 4]), singles(&[3.0; 4]), singles(&[1.0; 4]));
+        let result = fused_multiply_add(Mnemonic::Vfmaddsub231ps, [&c, &a, &b], &mut cx);
+        assert_eq!(result, singles(&[5.0, 7.0, 5.0, 7.0]));
+        let result = fused_multiply_add(Mnemonic::Vfnmadd132ss, [&a, &c, &b], &mut cx);
+        assert_eq!(result, singles(&[-5.0, 2.0, 2.0, 2.0]));
+    }
+
+    #[test]
+    fn fused_multiply_add_propagates_nans_in_digit_order() {
+        let nan = |payload: u32| singles(&[f32::from_bits(0x7FC0_0000 | payload); 4]);
+        let one = singles(&[1.0; 4]);
+        let (nan1, nan2, nan3) = (nan(1), nan(2), nan(3));
+        let cases: &[(Mnemonic, [&[u8]; 3], u32)] = &[
+            // 132 multiplies the first and third operands and adds the second
+            (Mnemonic::Vfmadd132ss, [&one, &nan2, &nan3], 3),
+            (Mnemonic::Vfmadd132ss, [&nan1, &nan2, &nan3], 1),
+            (Mnemonic::Vfmadd132ss, [&one, &nan2, &one], 2),
+            (Mnemonic::Vfmadd213ss, [&nan1, &nan2, &nan3], 2),
+            (Mnemonic::Vfmadd213ss, [&nan1, &one, &nan3], 1),
+            (Mnemonic::Vfmadd213ss, [&one, &one, &nan3], 3),
+            (Mnemonic::Vfmadd231ss, [&nan1, &one, &nan3], 3),
+            (Mnemonic::Vfmadd231ss, [&nan1, &nan2, &one], 2),
+            (Mnemonic::Vfmadd231ss, [&nan1, &one, &one], 1),
+        ];
+        for &(mnemonic, operands, payload) in cases {
+            let mut cx = Context::new(MXCSR);
+            let result = fused_multiply_add(mnemonic, operands, &mut cx);
+            let expected = 0x7FC0_0000 | payload as u64;
+            assert_eq!(
+                lane(&result, 0, 4),
+                expected,
+                "{:?} {:02x?}",
+                mnemonic,
+                operands
+            );
+        }
+    }
+
+    #[test]
+    fn half_precision_conversions() {
+        let halves: Vec<u8> = [0x3C00u16, 0x0001, 0xFC00, 0x7E00]
+            .iter()
+            .flat_map(|half| half.to_le_bytes())
+            .collect();
+        let mut cx = Context::new(MXCSR | DAZ);
+        let result = half_to_single(&halves, 16, &mut cx);
+        assert_eq!(
+            result,
+            singles(&[1.0, 2f32.powi(-24), f32::NEG_INFINITY, f32::NAN])
+        );
+        assert!(cx.env.flags.is_empty());
+        // 65520 is halfway between the largest half and the next power of two
+        let source = singles(&[65520.0, 1.0 / 3.0, 2f32.powi(-25), -0.0]);
+        let to_half = |imm| {
+            let mut cx = Context::new(MXCSR | FTZ);
+            let result = single_to_half(&source, imm, &mut cx);
+            let lanes: Vec<u64> = (0..4).map(|i| lane(&result, i, 2)).collect();
+            (lanes, cx.env.flags)
+        };
+        let all = Flags::OVERFLOW | Flags::UNDERFLOW | Flags::INEXACT;
+        assert_eq!(to_half(0), (vec![0x7C00, 0x3555, 0x0000, 0x8000], all));
+        // Rounding toward zero, 65520 is in range
+        let tiny = Flags::UNDERFLOW | Flags::INEXACT;
+        assert_eq!(to_half(3), (vec![0x7BFF, 0x3555, 0x0000, 0x8000], tiny));
+        assert_eq!(to_half(2), (vec![0x7C00, 0x3556, 0x0001, 0x8000], all));
+    }
+
+    #[test]
+    fn denormals_are_zeros() {
+        let tiny = singles(&[f32::from_bits(1)]);
+        let mut cx = Context::new(MXCSR);
+        let result = arithmetic(Mnemonic::Addss, &tiny, &tiny, &mut cx);
+        assert_eq!(lane(&result, 0, 4), 2);
+        assert_eq!(cx.env.flags, Flags::DENORMAL);
+        let mut cx = Context::new(MXCSR | DAZ);
+        let result = arithmetic(Mnemonic::Addss, &tiny, &tiny, &mut cx);
+        assert_eq!(lane(&result, 0, 4), 0);
+        assert!(cx.env.flags.is_empty());
+    }
+
+    #[test]
+    fn nans_and_invalid_operations_take_precedence_over_denormals() {
+        let bits =
+            |lanes: &[u32]| singles(&lanes.iter().map(|&b| f32::from_bits(b)).collect::<Vec<_>>());
+        // A denormal beside a quiet NaN in one element, and beside another denormal in the
+        // next
+        let a = bits(&[0x007F_FFFF, 0x007F_FFFF]);
+        let b = bits(&[0xFFC0_0000, 0x007F_FFFF]);
+        let mut cx = Context::new(MXCSR);
+        let result = arithmetic(Mnemonic::Addps, &a[..4], &b[..4], &mut cx);
+        assert_eq!(lane(&result, 0, 4), 0xFFC0_0000);
+        assert!(cx.env.flags.is_empty());
+        let result = arithmetic(Mnemonic::Addps, &a, &b, &mut cx);
+        assert_eq!(lane(&result, 1, 4), 0x00FF_FFFE);
+        assert_eq!(cx.env.flags, Flags::DENORMAL);
+        // The square root of a negative denormal is invalid
+        let mut cx = Context::new(MXCSR);
+        arithmetic(Mnemonic::Sqrtss, &a, &bits(&[0x8000_0001]), &mut cx);
+        assert_eq!(cx.env.flags, Flags::INVALID);
+        let mut cx = Context::new(MXCSR);
+        let snan = bits(&[0x7F80_0001]);
+        assert_eq!(
+            ordered(Mnemonic::Ucomiss, &snan, &bits(&[0x8000_0001]), &mut cx),
+            None
+        );
+        assert_eq!(cx.env.flags, Flags::INVALID);
+    }
+}
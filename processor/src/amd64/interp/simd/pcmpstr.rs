@@ -0,0 +1,216 @@
+//! The SSE4.2 string comparisons PCMPESTRI, PCMPESTRM, PCMPISTRI and PCMPISTRM.
+//!
+//! Each compares the elements of two strings held in XMM operands, bytes or words, signed
+//! or unsigned, and aggregates the comparisons into one bit per element of the second
+//! string. The explicit-length forms take the string lengths from rAX and rDX; the
+//! implicit-length forms end each string at its first zero element. Elements past the end
+//! of a string are invalid and compare by fixed rules that depend on the aggregation.
+
+use super::super::flags;
+use super::super::packed::{lane, signed};
+
+/// Aggregation, in bits 2-3 of the immediate.
+const EQUAL_ANY: u8 = 0;
+const RANGES: u8 = 1;
+const EQUAL_EACH: u8 = 2;
+const EQUAL_ORDERED: u8 = 3;
+
+/// Polarity, in bits 4-5 of the immediate.
+const NEGATIVE: u8 = 1;
+const MASKED_NEGATIVE: u8 = 3;
+
+/// The result of a string comparison.
+pub(super) struct Comparison {
+    /// One bit per element of the second string (IntRes2)
+    result: u16,
+    /// Number of elements in an operand: 16 bytes or 8 words
+    elements: usize,
+    a_len: usize,
+    b_len: usize,
+    imm: u8,
+}
+
+/// Compares the strings in `a` and `b` under the control of `imm`. `lengths` holds the
+/// signed lengths of the explicit-length forms, whose magnitudes are used, capped at the
+/// number of elements.
+pub(super) fn compare(a: &[u8], b: &[u8], lengths: Option<(i64, i64)>, imm: u8) -> Comparison {
+    let width = if imm & 0x01 != 0 { 2 } else { 1 };
+    let elements = 16 / width;
+    let element = |vector: &[u8], i: usize| {
+        let value = lane(vector, i, width);
+        if imm & 0x02 != 0 {
+            signed(value, width)
+        } else {
+            value as i64
+        }
+    };
+    let length = |vector: &[u8], explicit: Option<i64>| match explicit {
+        Some(len) => len.unsigned_abs().min(elements as u64) as usize,
+        None => (0..elements)
+            .position(|i| lane(vector, i, width) == 0)
+            .unwrap_or(elements),
+    };
+    let a_len = length(a, lengths.map(|(len, _)| len));
+    let b_len = length(b, lengths.map(|(_, len)| len));
+
+    let aggregation = imm >> 2 & 0x03;
+    // Element j of `a` against element i of `b` (BoolRes)
+    let matches = |j: usize, i: usize| match (j < a_len, i < b_len) {
+        (true, true) if aggregation == RANGES => {
+            // Even elements of `a` are lower bounds and odd ones upper bounds
+            if j.is_multiple_of(2) {
+                element(a, j) <= element(b, i)
+            } else {
+                element(a, j) >= element(b, i)
+            }
+        }
+        (true, true) => element(a, j) == element(b, i),
+        // A needle that has run out matches whatever is left of the haystack
+        (false, true) => aggregation == EQUAL_ORDERED,
+        (true, false) => false,
+        (false, false) => aggregation == EQUAL_EACH || aggregation == EQUAL_ORDERED,
+    };
+    let intermediate = (0..elements).fold(0u32, |bits, i| {
+        let bit = match aggregation {
+            EQUAL_ANY => (0..elements).any(|j| matches(j, i)),
+            RANGES => (0..elements / 2).any(|k| matches(2 * k, i) && matches(2 * k + 1, i)),
+            EQUAL_EACH => matches(i, i),
+            _ => (0..elements - i).all(|k| matches(k, i + k)),
+        };
+        bits | (bit as u32) << i
+    });
+    let result = match imm >> 4 & 0x03 {
+        NEGATIVE => !intermediate & ((1 << elements) - 1),
+        // Only the elements of `b` within the string are negated
+        MASKED_NEGATIVE => intermediate ^ ((1 << b_len) - 1),
+        _ => intermediate,
+    };
+    Comparison {
+        result: result as u16,
+        elements,
+        a_len,
+        b_len,
+        imm,
+    }
+}
+
+impl Comparison {
+    /// CF, ZF, SF and OF; AF and PF are cleared.
+    pub(super) fn flags(&self) -> u64 {
+        let flag = |condition: bool, flag: u64| if condition { flag } else { 0 };
+        flag(self.result != 0, flags::CF)
+            | flag(self.b_len < self.elements, flags::ZF)
+            | flag(self.a_len < self.elements, flags::SF)
+            | flag(self.result & 1 != 0, flags::OF)
+    }
+
+    /// The PCMPxSTRI result for ECX: the index of the lowest set result bit, or of the
+    /// highest when bit 6 of the immediate is set, or the number of elements if none is.
+    pub(super) fn index(&self) -> u64 {
+        if self.result == 0 {
+            self.elements as u64
+        } else if self.imm & 0x40 != 0 {
+            15 - self.result.leading_zeros() as u64
+        } else {
+            self.result.trailing_zeros() as u64
+        }
+    }
+
+    /// The PCMPxSTRM result for XMM0: the result bits, or with bit 6 of the immediate set,
+    /// each bit expanded to a whole element.
+    pub(super) fn mask(&self) -> [u8; 16] {
+        let mut mask = [0; 16];
+        if self.imm & 0x40 == 0 {
+            mask[..2].copy_from_slice(&self.result.to_le_bytes());
+            return mask;
+        }
+        let width = 16 / self.elements;
+        for i in 0..self.elements {
+            if self.result >> i & 1 != 0 {
+                mask[i * width..(i + 1) * width].fill(0xFF);
+            }
+        }
+        mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(text: &str) -> Vec<u8> {
+        let mut bytes = text.as_bytes().to_vec();
+        bytes.resize(16, 0);
+        bytes
+    }
+
+    #[test]
+    fn equal_any_finds_characters_from_a_set() {
+        let found = compare(&string("aeiou"), &string("hello world"), None, 0x00);
+        assert_eq!(found.result, 0b0000_1001_0010);
+        assert_eq!(found.index(), 1);
+        assert_eq!(found.flags(), flags::CF | flags::ZF | flags::SF);
+    }
+
+    #[test]
+    fn ranges_match_between_bounds() {
+        // Bytes outside 'a'-'z', negated: the first non-lowercase character
+        let found = compare(&string("az"), &string("abcDef"), None, 0x14);
+        assert_eq!(found.result, 0xFFC8);
+        assert_eq!(found.index(), 3);
+        // Masked negative leaves the elements past the end of the string alone
+        let found = compare(&string("az"), &string("abcDef"), None, 0x34);
+        assert_eq!(found.result, 0b00_1000);
+    }
+
+    #[test]
+    fn equal_each_compares_strings() {
+        // Negated: the first position where the strings differ
+        let found = compare(&string("hello"), &string("help"), None, 0x18);
+        assert_eq!(found.index(), 3);
+        let found = compare(&string("same"), &string("same"), None, 0x18);
+        assert_eq!(found.index(), 16);
+        assert_eq!(found.flags() & flags::CF, 0);
+    }
+
+    #[test]
+    fn equal_ordered_finds_substrings() {
+        let found = compare(&string("lo"), &string("hello, lo"), None, 0x0C);
+        assert_eq!(found.result, 0b1000_1000);
+        assert_eq!(found.index(), 3);
+        // The needle can't run off the end of the haystack
+        let found = compare(&string("lox"), &string("hello"), None, 0x0C);
+        assert_eq!(found.result, 0);
+        let found = compare(&string("ox"), &string("hello"), None, 0x0C);
+        assert_eq!(found.result, 0);
+        let found = compare(&string("o"), &string("hello"), None, 0x4C);
+        assert_eq!(found.index(), 4);
+    }
+
+    #[test]
+    fn explicit_lengths_and_words() {
+        let words = |values: &[u16]| {
+            let mut bytes = values
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<_>>();
+            bytes.resize(16, 0);
+            bytes
+        };
+        // Signed words, equal any, with the zero in `a` counted as part of the set
+        let found = compare(
+            &words(&[0, 0xFFFF]),
+            &words(&[5, 0, 0xFFFF]),
+            Some((2, -3)),
+            0x03,
+        );
+        assert_eq!(found.result, 0b110);
+        assert_eq!(found.flags(), flags::CF | flags::ZF | flags::SF);
+        // Expanded mask
+        let found = compare(&words(&[7]), &words(&[7, 1, 7]), Some((1, 3)), 0x41);
+        assert_eq!(
+            found.mask(),
+            [0xFF, 0xFF, 0, 0, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+}
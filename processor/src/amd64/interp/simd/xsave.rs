@@ -0,0 +1,255 @@
+//! XSAVE, XSAVEOPT, XSAVEC and XRSTOR: the state components XCR0 enables, saved to and
+//! restored from a 64-byte aligned area. Its first 512 bytes are laid out as FXSAVE's, then
+//! comes a 64-byte header, then the components past SSE, either at the offsets CPUID leaf
+//! 0xD reports (the standard format) or packed in order of their numbers (the compacted
+//! format XSAVEC writes).
+//!
+//! Which components are in their initial configuration isn't tracked, so each one saved is
+//! marked in XSTATE_BV as in use, as the architecture allows.
+
+use super::super::descriptor::Access;
+use super::super::x87::CR0_TS;
+use super::super::Amd64Interp;
+use super::{CR4_OSXSAVE, DEFAULT_MXCSR, FXSAVE_SIZE, MXCSR_MASK};
+use crate::amd64::cpu_model::{self, Feature, XSAVE_COMPONENTS};
+use crate::amd64::decode::{Instruction, MemoryOperand, Mnemonic, Operand, OperandSize, Prefixes};
+use crate::amd64::exception::Exception;
+use bytemuck::Zeroable;
+use file_loader::MemoryMap;
+
+/// The header's size, and the offset of the first component past it
+const HEADER_SIZE: usize = 64;
+/// XCOMP_BV bit 63, set in an area in the compacted format
+const COMPACTED: u64 = 1 << 63;
+/// The x87 component; the SSE one is bit 1 and the AVX one bit 2
+const X87: u64 = 1;
+const SSE: u64 = 1 << 1;
+/// The components MXCSR is saved and restored with
+const MXCSR_USERS: u64 = 0b110;
+
+impl Amd64Interp {
+    pub(super) fn xsave(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        if self.regs.cr[4] & CR4_OSXSAVE == 0 {
+            return Err(Exception::InvalidOpcode);
+        }
+        if self.regs.cr[0] & CR0_TS != 0 {
+            return Err(Exception::DeviceNotAvailable);
+        }
+        let Operand::Memory(mem, _) = insn.operand(0) else {
+            unreachable!("{} has a memory operand", insn)
+        };
+        // The requested-feature bitmap: the components EDX:EAX asks for that XCR0 enables
+        let requested =
+            self.read_gpr(2, OperandSize::R32) << 32 | self.read_gpr(0, OperandSize::R32);
+        let rfbm = requested & self.xcr0;
+        let wide = insn.prefixes.contains(Prefixes::REX_W);
+        if insn.mnemonic == Mnemonic::Xrstor {
+            return self.xrstor(map, &mem, rfbm, wide);
+        }
+
+        let compacted = insn.mnemonic == Mnemonic::Xsavec;
+        let size = self.xsave_area_size(rfbm, compacted);
+        let addr = self.checked_address(&mem, size as u64, Access::Write)?;
+        if !addr.is_multiple_of(64) {
+            return Err(Exception::GeneralProtection(0));
+        }
+        // What isn't saved is left as it was
+        let mut image = vec![0; size];
+        self.read_bytes(map, addr, &mut image)?;
+        if rfbm & X87 != 0 {
+            let mut legacy = [0; FXSAVE_SIZE];
+            self.fxsave_x87(&mut legacy, wide);
+            // Bytes 24-31 hold MXCSR, which belongs to the SSE and AVX components
+            image[..24].copy_from_slice(&legacy[..24]);
+            image[32..160].copy_from_slice(&legacy[32..160]);
+        }
+        if rfbm & MXCSR_USERS != 0 {
+            image[24..28].copy_from_slice(&self.regs.fpu.mxcsr.to_le_bytes());
+            image[28..32].copy_from_slice(&MXCSR_MASK.to_le_bytes());
+        }
+        if rfbm & SSE != 0 {
+            for n in 0..16 {
+                image[160 + 16 * n..][..16].copy_from_slice(&self.vector(n as u8)[..16]);
+            }
+        }
+        for (component, offset) in extended_layout(rfbm, compacted) {
+            let state = self.component_state(component);
+            image[offset..offset + state.len()].copy_from_slice(&state);
+        }
+        let header = &mut image[FXSAVE_SIZE..FXSAVE_SIZE + HEADER_SIZE];
+        if compacted {
+            header.fill(0);
+            header[..8].copy_from_slice(&rfbm.to_le_bytes());
+            header[8..16].copy_from_slice(&(rfbm | COMPACTED).to_le_bytes());
+        } else {
+            let xstate_bv = u64::from_le_bytes(header[..8].try_into().unwrap());
+            header[..8].copy_from_slice(&(xstate_bv | rfbm).to_le_bytes());
+        }
+        self.write_bytes(map, addr, &image)
+    }
+
+    /// XRSTOR: loads the components in `rfbm` that XSTATE_BV marks as saved, and puts the
+    /// rest of them in their initial configuration. The area is checked in full before any
+    /// state changes.
+    fn xrstor(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        mem: &MemoryOperand,
+        rfbm: u64,
+        wide: bool,
+    ) -> Result<(), Exception> {
+        let fault = Err(Exception::GeneralProtection(0));
+        let addr = self.checked_address(mem, (FXSAVE_SIZE + HEADER_SIZE) as u64, Access::Read)?;
+        if !addr.is_multiple_of(64) {
+            return fault;
+        }
+        let mut header = [0; HEADER_SIZE];
+        self.read_bytes(map, addr + FXSAVE_SIZE as u64, &mut header)?;
+        let xstate_bv = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let xcomp_bv = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let xcr0 = self.xcr0;
+        let compacted = xcomp_bv & COMPACTED != 0;
+        let valid = if compacted {
+            let components = xcomp_bv & !COMPACTED;
+            self.model.has(Feature::Xsavec)
+                && components & !xcr0 == 0
+                && xstate_bv & !components == 0
+                && header[16..].iter().all(|&byte| byte == 0)
+        } else {
+            xstate_bv & !xcr0 == 0 && header[8..24].iter().all(|&byte| byte == 0)
+        };
+        if !valid {
+            return fault;
+        }
+
+        let layout_bits = if compacted {
+            xcomp_bv & !COMPACTED
+        } else {
+            xcr0
+        };
+        let size = self.xsave_area_size(layout_bits, compacted);
+        let addr = self.checked_address(mem, size as u64, Access::Read)?;
+        let mut image = vec![0; size];
+        self.read_bytes(map, addr, &mut image)?;
+        // The compacted format only has MXCSR if it saved one of the components it goes with
+        let mxcsr = if compacted && xstate_bv & MXCSR_USERS == 0 {
+            DEFAULT_MXCSR
+        } else {
+            u32::from_le_bytes(image[24..28].try_into().unwrap())
+        };
+        if rfbm & MXCSR_USERS != 0 && mxcsr & !MXCSR_MASK != 0 {
+            return fault;
+        }
+
+        let restored = rfbm & xstate_bv;
+        if rfbm & X87 != 0 {
+            if restored & X87 != 0 {
+                self.fxrstor_x87(&image, wide);
+            } else {
+                self.reset_fpu();
+                self.regs.fpu.st = Zeroable::zeroed();
+            }
+        }
+        if rfbm & MXCSR_USERS != 0 {
+            self.regs.fpu.mxcsr = mxcsr;
+        }
+        if rfbm & SSE != 0 {
+            for n in 0..16 {
+                let xmm = match restored & SSE {
+                    0 => [0; 16],
+                    _ => image[160 + 16 * n..][..16].try_into().unwrap(),
+                };
+                self.vector_mut(n as u8)[..16].copy_from_slice(&xmm);
+            }
+        }
+        let layout = extended_layout(layout_bits, compacted);
+        for &(component, size, _) in &XSAVE_COMPONENTS {
+            if rfbm & 1 << component == 0 {
+                continue;
+            }
+            let size = size as usize;
+            let state = match layout.iter().find(|&&(saved, _)| saved == component) {
+                Some(&(_, offset)) if restored & 1 << component != 0 => {
+                    image[offset..offset + size].to_vec()
+                }
+                _ => vec![0; size],
+            };
+            self.set_component_state(component, &state);
+        }
+        Ok(())
+    }
+
+    /// The size of an area holding the components in `components`, which in the standard
+    /// format is always that of every component XCR0 enables.
+    fn xsave_area_size(&self, components: u64, compacted: bool) -> usize {
+        if !compacted {
+            return cpu_model::xsave_size(self.xcr0) as usize;
+        }
+        XSAVE_COMPONENTS
+            .iter()
+            .filter(|&&(component, ..)| components & 1 << component != 0)
+            .fold(FXSAVE_SIZE + HEADER_SIZE, |size, &(_, bytes, _)| {
+                size + bytes as usize
+            })
+    }
+
+    /// The registers making up state component `component` past SSE, as they're saved.
+    fn component_state(&self, component: u32) -> Vec<u8> {
+        match component {
+            2 => (0..16)
+                .flat_map(|n| self.vector(n)[16..32].to_vec())
+                .collect(),
+            5 => self.regs.k.iter().flat_map(|k| k.to_le_bytes()).collect(),
+            6 => (0..16)
+                .flat_map(|n| self.vector(n)[32..64].to_vec())
+                .collect(),
+            7 => (16..32).flat_map(|n| self.vector(n).to_vec()).collect(),
+            _ => unreachable!("no state component {}", component),
+        }
+    }
+
+    fn set_component_state(&mut self, component: u32, state: &[u8]) {
+        match component {
+            2 => {
+                for (n, chunk) in state.chunks(16).enumerate() {
+                    self.vector_mut(n as u8)[16..32].copy_from_slice(chunk);
+                }
+            }
+            5 => {
+                for (k, chunk) in self.regs.k.iter_mut().zip(state.chunks(8)) {
+                    *k = u64::from_le_bytes(chunk.try_into().unwrap());
+                }
+            }
+            6 => {
+                for (n, chunk) in state.chunks(32).enumerate() {
+                    self.vector_mut(n as u8)[32..64].copy_from_slice(chunk);
+                }
+            }
+            7 => {
+                for (n, chunk) in state.chunks(64).enumerate() {
+                    self.vector_mut(16 + n as u8).copy_from_slice(chunk);
+                }
+            }
+            _ => unreachable!("no state component {}", component),
+        }
+    }
+}
+
+/// Where each of the components past SSE in `components` is in the area: at its standard
+/// offset, or right after the one before it in the compacted format.
+fn extended_layout(components: u64, compacted: bool) -> Vec<(u32, usize)> {
+    let mut next = FXSAVE_SIZE + HEADER_SIZE;
+    XSAVE_COMPONENTS
+        .iter()
+        .filter(|&&(component, ..)| components & 1 << component != 0)
+        .map(|&(component, size, offset)| {
+            let offset = if compacted { next } else { offset as usize };
+            next += size as usize;
+            (component, offset)
+        })
+        .collect()
+}
@@ -0,0 +1,168 @@
+//! String instructions (MOVS, CMPS, STOS, LODS, SCAS, and the port I/O ones, INS and OUTS)
+//! and the REP/REPE/REPNE prefixes.
+
+use super::descriptor::Access;
+use super::{flags, Amd64Interp};
+use crate::amd64::decode::{Instruction, MemoryOperand, Mnemonic, Operand, OperandSize, Prefixes};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+/// Largest run of bytes the `rep movs`/`rep stos` fast path moves at once.
+const BULK_CHUNK: u64 = 0x10000;
+
+impl Amd64Interp {
+    pub(super) fn execute_string(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        if !insn.prefixes.intersects(Prefixes::REP | Prefixes::REPNE) {
+            return self.string_iteration(map, insn);
+        }
+        // The count register is rCX at the address size, not the operand size
+        let counter = insn.address_size;
+        if matches!(insn.mnemonic, Mnemonic::Movs | Mnemonic::Stos)
+            && self.regs.rflags & flags::DF == 0
+        {
+            self.bulk_string(map, insn)?;
+        }
+        while self.read_gpr(1, counter) != 0 {
+            self.string_iteration(map, insn)?;
+            let count = self.read_gpr(1, counter) - 1;
+            self.write_gpr(1, counter, count);
+            if matches!(insn.mnemonic, Mnemonic::Cmps | Mnemonic::Scas) {
+                // F3 is REPE for the comparing forms, and F2 is REPNE
+                let zf = self.regs.rflags & flags::ZF != 0;
+                if zf == insn.prefixes.contains(Prefixes::REPNE) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes one element of a string instruction and steps rSI/rDI. The registers are only
+    /// updated once every memory access has succeeded, so a fault can restart the instruction.
+    fn string_iteration(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let (first, second) = (insn.operand(0), insn.operand(1));
+        match insn.mnemonic {
+            Mnemonic::Movs | Mnemonic::Stos | Mnemonic::Lods => {
+                let value = self.read_operand(map, &second)?;
+                self.write_operand(map, &first, value)?;
+            }
+            Mnemonic::Cmps | Mnemonic::Scas => {
+                let a = self.read_operand(map, &first)?;
+                let b = self.read_operand(map, &second)?;
+                let size = first.size().unwrap();
+                flags::sub(a, b, false, size, &mut self.regs.rflags);
+            }
+            Mnemonic::Ins => {
+                let port = self.read_operand(map, &second)? as u16;
+                let value = self.port_in(port, first.size().unwrap());
+                self.write_operand(map, &first, value)?;
+            }
+            Mnemonic::Outs => {
+                let port = self.read_operand(map, &first)? as u16;
+                let value = self.read_operand(map, &second)?;
+                self.port_out(port, second.size().unwrap(), value);
+            }
+            mnemonic => unreachable!("{} is not a string instruction", mnemonic),
+        }
+        for operand in insn.operands() {
+            if let Operand::Memory(mem, size) = *operand {
+                self.advance_string_pointer(mem.base.unwrap(), mem.address_size, size.bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves rSI or rDI to the next element, backwards if DF is set.
+    fn advance_string_pointer(&mut self, reg: u8, address_size: OperandSize, bytes: u64) {
+        let pointer = self.read_gpr(reg, address_size);
+        let pointer = if self.regs.rflags & flags::DF == 0 {
+            pointer.wrapping_add(bytes)
+        } else {
+            pointer.wrapping_sub(bytes)
+        };
+        self.write_gpr(reg, address_size, pointer);
+    }
+
+    /// Runs as much of a forward `rep movs` or `rep stos` as it can through the map's bulk
+    /// accessors, leaving rCX, rSI and rDI as the element-by-element loop would. Anything it
+    /// can't prove equivalent (address wrap-around, a destination overlapping just above the
+    /// source) is left for that loop.
+    fn bulk_string(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let counter = insn.address_size;
+        let Operand::Memory(dst, size) = insn.operand(0) else {
+            unreachable!("string destinations are always memory")
+        };
+        let bytes = size.bytes();
+        let total = self.read_gpr(1, counter).saturating_mul(bytes);
+        // Neither the offsets nor the linear addresses they map to may wrap around, and the
+        // whole run has to be within its segment
+        let contiguous = |this: &Self, mem: &MemoryOperand, access: Access| {
+            let offset = this.address_offset(mem);
+            let end = offset.checked_add(total);
+            let addr = this.effective_address(mem);
+            end.is_some_and(|end| end <= counter.mask())
+                && addr
+                    .checked_add(total)
+                    .is_some_and(|end| this.linear_address(end) == end)
+                && total != 0
+                && this
+                    .check_access(Self::segment_of(mem), offset, total, access)
+                    .is_ok()
+        };
+        if !contiguous(self, &dst, Access::Write) {
+            return Ok(());
+        }
+        let dst_addr = self.effective_address(&dst);
+        let source = match insn.operand(1) {
+            Operand::Memory(src, _) => {
+                let src_addr = self.effective_address(&src);
+                if !contiguous(self, &src, Access::Read)
+                    || (dst_addr > src_addr && dst_addr < src_addr + total)
+                {
+                    return Ok(());
+                }
+                Some(src)
+            }
+            _ => None,
+        };
+        let fill = self.read_gpr(0, size).to_le_bytes();
+        let mut buf = Vec::new();
+        while self.read_gpr(1, counter) != 0 {
+            let count = self.read_gpr(1, counter).min(BULK_CHUNK / bytes);
+            let len = (count * bytes) as usize;
+            buf.resize(len, 0);
+            match source {
+                Some(src) => {
+                    let addr = self.effective_address(&src);
+                    self.read_bytes(map, addr, &mut buf)?;
+                }
+                None => {
+                    for element in buf.chunks_exact_mut(bytes as usize) {
+                        element.copy_from_slice(&fill[..bytes as usize]);
+                    }
+                }
+            }
+            let addr = self.effective_address(&dst);
+            self.write_bytes(map, addr, &buf)?;
+            if let Some(src) = source {
+                self.advance_string_pointer(src.base.unwrap(), src.address_size, len as u64);
+            }
+            self.advance_string_pointer(dst.base.unwrap(), dst.address_size, len as u64);
+            let remaining = self.read_gpr(1, counter) - count;
+            self.write_gpr(1, counter, remaining);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,597 @@
+//! Processor identification and control: CPUID, XGETBV and XSETBV, the control registers
+//! (MOV to and from them, LMSW, SMSW and CLTS), the debug registers, the model-specific
+//! registers (RDMSR, WRMSR), the time-stamp and performance counters (RDTSC, RDTSCP, RDPMC),
+//! cache and TLB invalidation (INVD, WBINVD, INVLPG, INVPCID), the descriptor table registers
+//! (LGDT, LIDT, SGDT, SIDT, LLDT, SLDT) and task register (LTR, STR), and the instructions
+//! that inspect a descriptor without loading it (LAR, LSL, VERR, VERW).
+
+use super::descriptor::{
+    Descriptor, CALL_GATE, CALL_GATE16, CODE, CONFORMING, LDT, PRESENT, READABLE, SEGMENT, TSS,
+    TSS16, TSS_BUSY, WRITABLE,
+};
+use super::paging::{CR0_PG, CR3_NO_FLUSH, CR4_LA57, CR4_PAE, CR4_PCIDE, CR4_PGE, PCID_MASK};
+use super::segment::{self, CR0_PE};
+use super::simd::CR4_OSXSAVE;
+use super::{flags, Amd64Interp};
+use crate::amd64::cpu_model::{self, Feature};
+use crate::amd64::decode::{
+    Instruction, MemoryOperand, Mnemonic, Mode, Operand, OperandSize, Register,
+};
+use crate::amd64::exception::Exception;
+use file_loader::MemoryMap;
+
+// CR0
+const CR0_TS: u64 = 1 << 3;
+/// The CR0 bits LMSW writes: PE, MP, EM and TS
+const MSW_MASK: u64 = 0xF;
+
+// CR4
+const CR4_TSD: u64 = 1 << 2;
+const CR4_DE: u64 = 1 << 3;
+
+// XCR0
+/// The x87 state, which is always enabled
+pub(super) const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+/// The opmask registers and both parts of the ZMM state, which are enabled together
+const XCR0_AVX512: u64 = 0b1110_0000;
+
+// Debug registers
+/// DR0-DR7 at reset: DR6 and DR7 have bits that always read as 1
+pub(super) const DEBUG_RESET: [u64; 8] = [0, 0, 0, 0, 0, 0, DR6_FIXED, DR7_FIXED];
+const DR6_FIXED: u64 = 0xFFFF_0FF0;
+/// The DR6 bits software can write: the breakpoint, BD, BS and BT flags
+const DR6_WRITABLE: u64 = 0xE00F;
+const DR7_FIXED: u64 = 1 << 10;
+/// DR7 bits 11, 12, 14 and 15, which read as 0
+const DR7_RESERVED: u64 = 0xD800;
+
+// Model-specific registers
+const MSR_TSC: u32 = 0x10;
+const MSR_EFER: u32 = 0xC000_0080;
+const MSR_FS_BASE: u32 = 0xC000_0100;
+const MSR_GS_BASE: u32 = 0xC000_0101;
+const MSR_KERNEL_GS_BASE: u32 = 0xC000_0102;
+const MSR_TSC_AUX: u32 = 0xC000_0103;
+
+// IA32_EFER
+pub(super) const EFER_SCE: u64 = 1 << 0;
+pub(super) const EFER_LME: u64 = 1 << 8;
+pub(super) const EFER_LMA: u64 = 1 << 10;
+pub(super) const EFER_NXE: u64 = 1 << 11;
+
+impl Amd64Interp {
+    pub(super) fn execute_system(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        // XCR0 is only there once the operating system has turned XSAVE on
+        if matches!(insn.mnemonic, Xgetbv | Xsetbv) && self.regs.cr[4] & CR4_OSXSAVE == 0 {
+            return Err(Exception::InvalidOpcode);
+        }
+        // Everything but CPUID, XGETBV and the stores is for the operating system alone, and
+        // so is the time-stamp counter with CR4.TSD set
+        let privileged = match insn.mnemonic {
+            Cpuid | Xgetbv | Sgdt | Sidt | Sldt | Str | Smsw | Lar | Lsl | Verr | Verw => false,
+            Rdtsc | Rdtscp => self.regs.cr[4] & CR4_TSD != 0,
+            _ => true,
+        };
+        if privileged && self.cpl() != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        // The LDT, the task register and the descriptor checks don't exist in real mode
+        let protected = matches!(
+            insn.mnemonic,
+            Lldt | Ltr | Sldt | Str | Lar | Lsl | Verr | Verw
+        );
+        if self.real_mode() && protected {
+            return Err(Exception::InvalidOpcode);
+        }
+        match insn.mnemonic {
+            Cpuid => {
+                let leaf = self.read_gpr(0, OperandSize::R32) as u32;
+                let subleaf = self.read_gpr(1, OperandSize::R32) as u32;
+                let [eax, ebx, ecx, edx] = self.cpuid(leaf, subleaf);
+                self.write_gpr(0, OperandSize::R32, eax as u64);
+                self.write_gpr(3, OperandSize::R32, ebx as u64);
+                self.write_gpr(1, OperandSize::R32, ecx as u64);
+                self.write_gpr(2, OperandSize::R32, edx as u64);
+            }
+            Xgetbv => {
+                // XCR0 is the only extended control register
+                if self.read_gpr(1, OperandSize::R32) != 0 {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                self.write_gpr(0, OperandSize::R32, self.xcr0 & 0xFFFF_FFFF);
+                self.write_gpr(2, OperandSize::R32, self.xcr0 >> 32);
+            }
+            Xsetbv => {
+                let value =
+                    self.read_gpr(2, OperandSize::R32) << 32 | self.read_gpr(0, OperandSize::R32);
+                // x87 can't be turned off, AVX needs SSE, and the AVX-512 components go
+                // together and need AVX
+                let avx512 = value & XCR0_AVX512;
+                let valid = value & !self.model.xcr0() == 0
+                    && value & XCR0_X87 != 0
+                    && (value & XCR0_AVX == 0 || value & XCR0_SSE != 0)
+                    && (avx512 == 0 || (avx512 == XCR0_AVX512 && value & XCR0_AVX != 0));
+                if self.read_gpr(1, OperandSize::R32) != 0 || !valid {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                self.xcr0 = value;
+            }
+            Mov => {
+                let (dest, src) = (insn.operand(0), insn.operand(1));
+                match (dest, src) {
+                    (Operand::Register(Register::Control(n), _), _) => {
+                        let value = self.read_operand(map, &src)?;
+                        self.write_control(n as usize, value)?;
+                    }
+                    (_, Operand::Register(Register::Control(n), _)) => {
+                        let value = self.read_control(n as usize)?;
+                        self.write_operand(map, &dest, value)?;
+                    }
+                    (Operand::Register(Register::Debug(n), _), _) => {
+                        let value = self.read_operand(map, &src)?;
+                        self.write_debug(n as usize, value)?;
+                    }
+                    (_, Operand::Register(Register::Debug(n), _)) => {
+                        let value = self.debug[self.debug_register(n as usize)?];
+                        self.write_operand(map, &dest, value)?;
+                    }
+                    _ => unreachable!("MOV with a control or debug register"),
+                }
+            }
+            Lmsw => {
+                // LMSW can set PE but not clear it
+                let value = self.read_operand(map, &insn.operand(0))? & MSW_MASK;
+                let cr0 = self.regs.cr[0];
+                self.write_control(0, (cr0 & !MSW_MASK) | value | (cr0 & CR0_PE))?;
+            }
+            Smsw => self.write_operand(map, &insn.operand(0), self.regs.cr[0])?,
+            Clts => self.regs.cr[0] &= !CR0_TS,
+            Rdmsr => {
+                let value = self.read_msr(self.read_gpr(1, OperandSize::R32) as u32)?;
+                self.write_gpr(0, OperandSize::R32, value & 0xFFFF_FFFF);
+                self.write_gpr(2, OperandSize::R32, value >> 32);
+            }
+            Wrmsr => {
+                let value =
+                    self.read_gpr(2, OperandSize::R32) << 32 | self.read_gpr(0, OperandSize::R32);
+                self.write_msr(self.read_gpr(1, OperandSize::R32) as u32, value)?;
+            }
+            Rdtsc | Rdtscp => {
+                if insn.mnemonic == Rdtscp {
+                    self.write_gpr(1, OperandSize::R32, self.tsc_aux);
+                }
+                self.write_gpr(0, OperandSize::R32, self.tsc & 0xFFFF_FFFF);
+                self.write_gpr(2, OperandSize::R32, self.tsc >> 32);
+            }
+            // There are no performance counters, so every counter number is out of range
+            Rdpmc => return Err(Exception::GeneralProtection(0)),
+            // Nor are there caches to write back or discard
+            Invd | Wbinvd => {}
+            Invlpg => {
+                // The address isn't accessed, so it needn't be mapped or within the limit
+                // of its segment
+                let Operand::Memory(mem, _) = insn.operand(0) else {
+                    unreachable!("INVLPG takes a memory operand")
+                };
+                let addr = self.effective_address(&mem);
+                self.tlb.flush_address(addr);
+            }
+            Invpcid => self.invalidate_pcid(map, insn)?,
+            Lgdt | Lidt => {
+                let mem = table_operand(&insn.operand(0));
+                let limit = self.read_operand(map, &Operand::Memory(mem, OperandSize::R16))?;
+                let base = self.read_operand(
+                    map,
+                    &Operand::Memory(displaced(mem), self.table_base_size()),
+                )?;
+                // A 16-bit LGDT or LIDT only loads 24 bits of the base
+                let base = match insn.operand_size {
+                    OperandSize::R16 if self.mode != Mode::Bits64 => base & 0xFF_FFFF,
+                    _ => base,
+                };
+                let table = match insn.mnemonic {
+                    Lgdt => &mut self.tables.gdtr,
+                    _ => &mut self.tables.idtr,
+                };
+                table.base = base;
+                table.limit = limit as u16;
+            }
+            Sgdt | Sidt => {
+                let mem = table_operand(&insn.operand(0));
+                let table = match insn.mnemonic {
+                    Sgdt => self.tables.gdtr,
+                    _ => self.tables.idtr,
+                };
+                let limit = Operand::Memory(mem, OperandSize::R16);
+                self.write_operand(map, &limit, table.limit as u64)?;
+                let base = Operand::Memory(displaced(mem), self.table_base_size());
+                self.write_operand(map, &base, table.base)?;
+            }
+            Lldt => {
+                let selector = self.read_operand(map, &insn.operand(0))? as u16;
+                let descriptor = self.system_descriptor(map, selector, &[LDT])?;
+                self.tables.ldtr = selector;
+                self.tables.ldt = descriptor;
+            }
+            Ltr => {
+                let selector = self.read_operand(map, &insn.operand(0))? as u16;
+                if selector & !3 == 0 {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                let kinds: &[u32] = if self.long_mode() {
+                    &[TSS]
+                } else {
+                    &[TSS, TSS16]
+                };
+                let descriptor = self.system_descriptor(map, selector, kinds)?;
+                // Loading a TSS marks it busy, so no other task register can load it
+                self.mark_descriptor(map, selector, TSS_BUSY)?;
+                self.tables.tr = selector;
+                self.tables.tss = Descriptor {
+                    attributes: descriptor.attributes | TSS_BUSY,
+                    ..descriptor
+                };
+            }
+            Sldt => self.write_operand(map, &insn.operand(0), self.tables.ldtr as u64)?,
+            Str => self.write_operand(map, &insn.operand(0), self.tables.tr as u64)?,
+            Lar | Lsl | Verr | Verw => {
+                let source = match insn.mnemonic {
+                    Lar | Lsl => insn.operand(1),
+                    _ => insn.operand(0),
+                };
+                let selector = self.read_operand(map, &source)? as u16;
+                let result = self.inspect_descriptor(map, insn.mnemonic, selector)?;
+                if let Some(value) = result {
+                    if let Lar | Lsl = insn.mnemonic {
+                        self.write_operand(map, &insn.operand(0), value)?;
+                    }
+                    self.regs.rflags |= flags::ZF;
+                } else {
+                    self.regs.rflags &= !flags::ZF;
+                }
+            }
+            mnemonic => unreachable!("{} is not a system instruction", mnemonic),
+        }
+        Ok(())
+    }
+
+    /// The size of the base a descriptor table register is stored with and loaded from:
+    /// 8 bytes in 64-bit mode, and 4 otherwise.
+    fn table_base_size(&self) -> OperandSize {
+        match self.mode {
+            Mode::Bits64 => OperandSize::R64,
+            _ => OperandSize::R32,
+        }
+    }
+
+    /// What CPUID reports: the model's answer, but with OSXSAVE following CR4 and the XSAVE
+    /// area sized for the components XCR0 enables.
+    fn cpuid(&self, leaf: u32, subleaf: u32) -> [u32; 4] {
+        let mut words = self.model.cpuid(leaf, subleaf);
+        let (osxsave_leaf, _, reg, bit) = Feature::Osxsave.location();
+        if leaf == osxsave_leaf {
+            words[reg] &= !(1 << bit);
+            if self.regs.cr[4] & CR4_OSXSAVE != 0 {
+                words[reg] |= 1 << bit;
+            }
+        }
+        if leaf == 0xD && subleaf == 0 && self.model.has(Feature::Xsave) {
+            words[1] = cpu_model::xsave_size(self.xcr0);
+        }
+        words
+    }
+
+    fn read_control(&self, n: usize) -> Result<u64, Exception> {
+        match n {
+            0 | 2 | 3 | 4 => Ok(self.regs.cr[n]),
+            // CR8 is only reachable from 64-bit mode; it holds the task priority
+            8 if self.mode == Mode::Bits64 => Ok(self.regs.cr[8]),
+            _ => Err(Exception::InvalidOpcode),
+        }
+    }
+
+    /// The index in `debug` of debug register `n`. DR4 and DR5 are the old names of DR6
+    /// and DR7, unless CR4.DE retires them; there are no registers past DR7.
+    fn debug_register(&self, n: usize) -> Result<usize, Exception> {
+        match n {
+            0..=3 | 6 | 7 => Ok(n),
+            4 | 5 if self.regs.cr[4] & CR4_DE == 0 => Ok(n + 2),
+            _ => Err(Exception::InvalidOpcode),
+        }
+    }
+
+    /// Writes debug register `n`, keeping the bits of DR6 and DR7 that are fixed. Their
+    /// upper halves are reserved.
+    fn write_debug(&mut self, n: usize, value: u64) -> Result<(), Exception> {
+        let n = self.debug_register(n)?;
+        self.debug[n] = match n {
+            6 | 7 if value >> 32 != 0 => return Err(Exception::GeneralProtection(0)),
+            6 => value & DR6_WRITABLE | DR6_FIXED,
+            7 => value & !DR7_RESERVED | DR7_FIXED,
+            _ => value,
+        };
+        Ok(())
+    }
+
+    /// Writes control register `n`. Setting CR0.PE enters protected mode, where the
+    /// segment registers keep the descriptors cached in real mode until they're reloaded,
+    /// and clearing it goes back to real mode. Setting CR0.PG with EFER.LME set activates
+    /// long mode. Changing the page tables or how they're read flushes the TLB.
+    fn write_control(&mut self, n: usize, value: u64) -> Result<(), Exception> {
+        let fault = Err(Exception::GeneralProtection(0));
+        let cr4 = self.regs.cr[4];
+        match n {
+            0 => {
+                // Paging needs protected mode, and the upper half is reserved
+                if value >> 32 != 0 || (value & CR0_PG != 0 && value & CR0_PE == 0) {
+                    return fault;
+                }
+                let paging = value & CR0_PG != 0;
+                if paging != self.paging() {
+                    if !paging && self.mode == Mode::Bits64 {
+                        return fault;
+                    }
+                    // Only long mode's paging is modeled, which needs PAE's wide entries
+                    if paging && (self.efer & EFER_LME == 0 || cr4 & CR4_PAE == 0) {
+                        return fault;
+                    }
+                    if paging {
+                        self.efer |= EFER_LMA;
+                    } else {
+                        self.efer &= !EFER_LMA;
+                    }
+                    self.tlb.flush_all();
+                }
+                self.regs.cr[0] = value;
+                self.update_mode();
+            }
+            2 => self.regs.cr[2] = value,
+            3 => {
+                // With PCIDs, bit 63 keeps the new PCID's translations instead of flushing
+                // them; global ones are kept either way
+                let keep = cr4 & CR4_PCIDE != 0 && value & CR3_NO_FLUSH != 0;
+                let value = value & !CR3_NO_FLUSH;
+                if value >> self.model.physical_address_bits != 0 {
+                    return fault;
+                }
+                self.regs.cr[3] = value;
+                if cr4 & CR4_PCIDE == 0 {
+                    self.tlb.flush_non_global();
+                } else if !keep {
+                    self.tlb.flush_pcid(self.pcid());
+                }
+            }
+            4 => {
+                let changed = cr4 ^ value;
+                let unsupported = [
+                    (CR4_PCIDE, Feature::Pcid),
+                    (CR4_LA57, Feature::La57),
+                    (CR4_PGE, Feature::Pge),
+                    (segment::CR4_FSGSBASE, Feature::Fsgsbase),
+                    (CR4_OSXSAVE, Feature::Xsave),
+                ]
+                .iter()
+                .any(|&(bit, feature)| value & bit != 0 && !self.model.has(feature));
+                // Long mode can't turn off PAE or switch between 4- and 5-level paging, and
+                // only enables PCIDs while CR3 selects PCID 0
+                let long_mode = self.long_mode();
+                let enables_pcid = changed & value & CR4_PCIDE != 0;
+                if unsupported
+                    || (long_mode && (value & CR4_PAE == 0 || changed & CR4_LA57 != 0))
+                    || (enables_pcid && (!long_mode || self.regs.cr[3] & PCID_MASK != 0))
+                {
+                    return fault;
+                }
+                self.regs.cr[4] = value;
+                if changed & (CR4_PAE | CR4_PGE | CR4_LA57 | CR4_PCIDE) != 0 {
+                    self.tlb.flush_all();
+                }
+            }
+            // CR8 is the task priority
+            8 if self.mode == Mode::Bits64 => {
+                if value >> 4 != 0 {
+                    return fault;
+                }
+                self.regs.cr[8] = value;
+            }
+            _ => return Err(Exception::InvalidOpcode),
+        }
+        Ok(())
+    }
+
+    /// Reads model-specific register `msr`, raising #GP(0) for any the model doesn't have.
+    fn read_msr(&self, msr: u32) -> Result<u64, Exception> {
+        Ok(match msr {
+            MSR_EFER => self.efer,
+            MSR_TSC => self.tsc,
+            MSR_TSC_AUX if self.model.has(Feature::Rdtscp) => self.tsc_aux,
+            MSR_FS_BASE => self.regs.sr_base[segment::FS],
+            MSR_GS_BASE => self.regs.sr_base[segment::GS],
+            MSR_KERNEL_GS_BASE => self.kernel_gs_base,
+            _ => return Err(Exception::GeneralProtection(0)),
+        })
+    }
+
+    fn write_msr(&mut self, msr: u32, value: u64) -> Result<(), Exception> {
+        let fault = Err(Exception::GeneralProtection(0));
+        match msr {
+            MSR_EFER => {
+                // LMA is read-only, and LME can't change while paging is on
+                let value = (value & !EFER_LMA) | (self.efer & EFER_LMA);
+                let supported = [
+                    (EFER_SCE, Feature::Syscall),
+                    (EFER_LME, Feature::Lm),
+                    (EFER_NXE, Feature::Nx),
+                ];
+                let reserved = supported
+                    .iter()
+                    .filter(|&&(_, feature)| self.model.has(feature))
+                    .fold(!EFER_LMA, |reserved, &(bit, _)| reserved & !bit);
+                let changed = value ^ self.efer;
+                if value & reserved != 0 || (changed & EFER_LME != 0 && self.paging()) {
+                    return fault;
+                }
+                if changed & EFER_NXE != 0 {
+                    self.tlb.flush_all();
+                }
+                self.efer = value;
+            }
+            MSR_TSC => self.tsc = value,
+            // Only the low half of IA32_TSC_AUX is implemented
+            MSR_TSC_AUX if self.model.has(Feature::Rdtscp) && value >> 32 == 0 => {
+                self.tsc_aux = value
+            }
+            MSR_FS_BASE | MSR_GS_BASE | MSR_KERNEL_GS_BASE => {
+                if !self.is_canonical(value) {
+                    return fault;
+                }
+                match msr {
+                    MSR_FS_BASE => self.regs.sr_base[segment::FS] = value,
+                    MSR_GS_BASE => self.regs.sr_base[segment::GS] = value,
+                    _ => self.kernel_gs_base = value,
+                }
+            }
+            _ => return fault,
+        }
+        Ok(())
+    }
+
+    /// INVPCID: flushes the translations of one address in a PCID (type 0), of a PCID
+    /// (type 1), or of every PCID with (2) or without (3) the global ones. The descriptor
+    /// in memory holds the PCID and then the address.
+    fn invalidate_pcid(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let kind = self.read_operand(map, &insn.operand(0))?;
+        let Operand::Memory(mem, _) = insn.operand(1) else {
+            unreachable!("INVPCID descriptors are in memory")
+        };
+        let pcid = self.read_operand(map, &Operand::Memory(mem, OperandSize::R64))?;
+        let addr = MemoryOperand {
+            displacement: mem.displacement.wrapping_add(8),
+            ..mem
+        };
+        let addr = self.read_operand(map, &Operand::Memory(addr, OperandSize::R64))?;
+        let pcids = self.regs.cr[4] & CR4_PCIDE != 0;
+        // Without PCIDs enabled, the only one there is is 0
+        if kind > 3 || pcid > PCID_MASK || (kind < 2 && !pcids && pcid != 0) {
+            return Err(Exception::GeneralProtection(0));
+        }
+        match kind {
+            0 => {
+                if !self.is_canonical(addr) {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                self.tlb.flush_pcid_address(pcid as u16, addr);
+            }
+            1 => self.tlb.flush_pcid(pcid as u16),
+            2 => self.tlb.flush_all(),
+            _ => self.tlb.flush_non_global(),
+        }
+        Ok(())
+    }
+
+    /// Reads the system descriptor LLDT or LTR loads, which must be in the GDT and of one
+    /// of `kinds`, or present. Loading a null selector into LDTR leaves no LDT.
+    fn system_descriptor(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        selector: u16,
+        kinds: &[u32],
+    ) -> Result<Descriptor, Exception> {
+        if selector & !3 == 0 {
+            return Ok(Descriptor::default());
+        }
+        let error = selector & !3;
+        if selector & 4 != 0 {
+            return Err(Exception::GeneralProtection(error));
+        }
+        let (raw, upper) = self.read_descriptor(map, selector, self.long_mode())?;
+        let descriptor = Descriptor::from_raw(raw, upper);
+        if !kinds.iter().any(|&kind| descriptor.is_system(kind)) {
+            return Err(Exception::GeneralProtection(error));
+        }
+        if !descriptor.has(PRESENT) {
+            return Err(Exception::SegmentNotPresent(error));
+        }
+        Ok(descriptor)
+    }
+
+    /// What LAR, LSL, VERR or VERW report about `selector`: the access rights or limit of
+    /// its descriptor (or 0 for VERR and VERW) if the instruction accepts its type and it's
+    /// visible at the current privilege level and the selector's RPL, or None.
+    fn inspect_descriptor(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        mnemonic: Mnemonic,
+        selector: u16,
+    ) -> Result<Option<u64>, Exception> {
+        if selector & !3 == 0 {
+            return Ok(None);
+        }
+        let raw = match self.read_descriptor(map, selector, false) {
+            Ok((raw, _)) => raw,
+            // Past the end of its table
+            Err(Exception::GeneralProtection(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let descriptor = Descriptor::from_raw(raw, 0);
+        let accepted = if descriptor.has(SEGMENT) {
+            match mnemonic {
+                Mnemonic::Verr => !descriptor.has(CODE) || descriptor.has(READABLE),
+                Mnemonic::Verw => descriptor.is_data() && descriptor.has(WRITABLE),
+                _ => true,
+            }
+        } else {
+            // LSL takes the LDT and TSSs, which have limits, and LAR call gates as well.
+            // Long mode has done away with the 16-bit types.
+            let (kind, legacy) = (descriptor.kind(), !self.long_mode());
+            let tss = kind & !TSS_BUSY;
+            let limited = kind == LDT || tss == TSS || (legacy && tss == TSS16);
+            match mnemonic {
+                Mnemonic::Lar => limited || kind == CALL_GATE || (legacy && kind == CALL_GATE16),
+                Mnemonic::Lsl => limited,
+                _ => false,
+            }
+        };
+        // Conforming code segments are visible from every privilege level
+        let conforming = descriptor.has(SEGMENT | CODE | CONFORMING);
+        let dpl = descriptor.dpl();
+        let visible = conforming || (dpl >= self.cpl() && dpl >= (selector & 3) as u8);
+        if !accepted || !visible {
+            return Ok(None);
+        }
+        Ok(Some(match mnemonic {
+            // The type, DPL and present bits and the upper flags nibble
+            Mnemonic::Lar => (raw >> 32) & 0x00F0_FF00,
+            Mnemonic::Lsl => descriptor.limit as u64,
+            _ => 0,
+        }))
+    }
+}
+
+/// The memory operand of a descriptor table instruction, which holds a 16-bit limit
+/// followed by the base.
+fn table_operand(operand: &Operand) -> MemoryOperand {
+    match *operand {
+        Operand::Memory(mem, _) => mem,
+        _ => unreachable!("descriptor table operands are in memory"),
+    }
+}
+
+/// `mem` two bytes further on, where a descriptor table's base follows its limit.
+fn displaced(mem: MemoryOperand) -> MemoryOperand {
+    MemoryOperand {
+        displacement: mem.displacement.wrapping_add(2),
+        ..mem
+    }
+}
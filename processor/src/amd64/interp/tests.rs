@@ -0,0 +1,3650 @@
+use super::*;
+use file_loader::shared::SharedMemoryMap;
+
+const CODE_BASE: u64 = 0x1000;
+const STACK_TOP: u64 = 0xF000;
+
+/// A flat memory map for feeding small instruction sequences to the interpreter, as a 64-bit
+/// or an IA-32 program in 64K, or as real-mode code in all the memory it can address.
+struct TestMap {
+    mem: Vec<u8>,
+    bits: u8,
+}
+
+impl TestMap {
+    fn new(code: &[u8], bits: u8) -> TestMap {
+        // Real mode can address 1 MiB, and the high memory area past it with A20 enabled
+        let mut mem = vec![0; if bits == 16 { 0x11_0000 } else { 0x10000 }];
+        mem[CODE_BASE as usize..CODE_BASE as usize + code.len()].copy_from_slice(code);
+        TestMap { mem, bits }
+    }
+}
+
+impl MemoryMap for TestMap {
+    fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    fn read_u8(&self, addr: u64) -> u8 {
+        self.mem[addr as usize]
+    }
+
+    fn write_u8(&mut self, addr: u64, data: u8) {
+        self.mem[addr as usize] = data;
+    }
+
+    fn entry_point(&self) -> u64 {
+        CODE_BASE
+    }
+
+    fn starting_stack(&self) -> u64 {
+        STACK_TOP
+    }
+}
+
+/// Runs `code` until execution falls off its end, after letting `setup` seed the registers.
+fn run<F: FnOnce(&mut Amd64Interp)>(code: &[u8], setup: F) -> (Amd64Interp, TestMap) {
+    run_with_data(code, &[], setup)
+}
+
+/// Like [`run`], but first copies each `(address, bytes)` pair into memory.
+fn run_with_data<F: FnOnce(&mut Amd64Interp)>(
+    code: &[u8],
+    data: &[(u64, &[u8])],
+    setup: F,
+) -> (Amd64Interp, TestMap) {
+    run_program(TestMap::new(code, 64), code, data, setup)
+}
+
+/// Like [`run`], but runs `code` as an IA-32 program, in 32-bit mode.
+fn run32<F: FnOnce(&mut Amd64Interp)>(code: &[u8], setup: F) -> (Amd64Interp, TestMap) {
+    run_program(TestMap::new(code, 32), code, &[], setup)
+}
+
+/// Like [`run_with_data`], but runs `code` in real mode, from 0000:1000 with the stack at
+/// 0000:F000.
+fn run16<F: FnOnce(&mut Amd64Interp)>(
+    code: &[u8],
+    data: &[(u64, &[u8])],
+    setup: F,
+) -> (Amd64Interp, TestMap) {
+    run_program(TestMap::new(code, 16), code, data, setup)
+}
+
+fn run_program<F: FnOnce(&mut Amd64Interp)>(
+    mut map: TestMap,
+    code: &[u8],
+    data: &[(u64, &[u8])],
+    setup: F,
+) -> (Amd64Interp, TestMap) {
+    for &(addr, bytes) in data {
+        map.write_bytes(addr, bytes);
+    }
+    let mut cpu = Amd64Interp::new();
+    cpu.init(&mut map);
+    setup(&mut cpu);
+    while cpu.running() && cpu.regs.rip < CODE_BASE + code.len() as u64 {
+        cpu.tick(&mut map);
+    }
+    (cpu, map)
+}
+
+/// Swaps in `model` from a test's setup, which runs after init, with XCR0 enabling every
+/// component the model has as init would have.
+fn use_model(cpu: &mut Amd64Interp, model: CpuModel) {
+    cpu.xcr0 = model.xcr0();
+    cpu.model = model;
+}
+
+#[test]
+fn lea_sib_disp8() {
+    // lea rdx, [rax+rcx*8+0x10]
+    let (cpu, _) = run(&[0x48, 0x8D, 0x54, 0xC8, 0x10], |cpu| {
+        cpu.regs.gprs[0] = 0x1000;
+        cpu.regs.gprs[1] = 3;
+    });
+    assert_eq!(cpu.regs.gprs[2], 0x1000 + 3 * 8 + 0x10);
+}
+
+#[test]
+fn lea_rip_relative_is_relative_to_next_instruction() {
+    // lea rdi, [rip+0x20]
+    let (cpu, _) = run(&[0x48, 0x8D, 0x3D, 0x20, 0x00, 0x00, 0x00], |_| {});
+    assert_eq!(cpu.regs.gprs[7], CODE_BASE + 7 + 0x20);
+}
+
+#[test]
+fn sib_without_base_or_index() {
+    // lea rax, [0x2000] via SIB with base=101, index=100
+    let (cpu, _) = run(&[0x48, 0x8D, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00], |_| {});
+    assert_eq!(cpu.regs.gprs[0], 0x2000);
+}
+
+#[test]
+fn rex_extended_base_and_index() {
+    // lea rax, [r13+r12*2+0x100] (r13 as base needs an explicit displacement)
+    let (cpu, _) = run(&[0x4B, 0x8D, 0x84, 0x65, 0x00, 0x01, 0x00, 0x00], |cpu| {
+        cpu.regs.gprs[13] = 0x4000;
+        cpu.regs.gprs[12] = 0x10;
+    });
+    assert_eq!(cpu.regs.gprs[0], 0x4000 + 0x20 + 0x100);
+}
+
+#[test]
+fn address_size_override_truncates() {
+    // lea rax, [eax-8]
+    let (cpu, _) = run(&[0x67, 0x48, 0x8D, 0x40, 0xF8], |cpu| {
+        cpu.regs.gprs[0] = 0x1_0000_0004;
+    });
+    assert_eq!(cpu.regs.gprs[0], 0xFFFF_FFFC);
+}
+
+#[test]
+fn store_through_negative_displacement() {
+    // mov [rbp-0x8], rax
+    let (_, map) = run(&[0x48, 0x89, 0x45, 0xF8], |cpu| {
+        cpu.regs.gprs[5] = 0x3008;
+        cpu.regs.gprs[0] = 0x0123_4567_DEAD_BEEF;
+    });
+    assert_eq!(map.read_u64(0x3000), 0x0123_4567_DEAD_BEEF);
+}
+
+#[test]
+fn invalid_opcode_stops_the_processor() {
+    // nop; ud2
+    let (cpu, _) = run(&[0x90, 0x0F, 0x0B, 0x90], |_| {});
+    assert!(!cpu.running());
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    assert_eq!(cpu.regs.rip, CODE_BASE + 1);
+}
+
+#[test]
+fn instructions_nothing_executes_raise_invalid_opcode() {
+    let decode_at = |bytes: &[u8]| {
+        decode::decode(0, Mode::Bits64, &mut |addr| Ok(bytes[addr as usize])).unwrap()
+    };
+    let mut map = TestMap::new(&[], 64);
+    let mut cpu = Amd64Interp::new();
+    cpu.init(&mut map);
+    // A mnemonic the decoder knows that no module executes in this form, and an integer
+    // instruction given vector operands: addps xmm0, xmm1 as ADD
+    let mut insn = decode_at(&[0x90]);
+    insn.mnemonic = Mnemonic::Vfmadd132pd;
+    assert_eq!(cpu.execute(&mut map, &insn), Err(Exception::InvalidOpcode));
+    let mut insn = decode_at(&[0x0F, 0x58, 0xC1]);
+    insn.mnemonic = Mnemonic::Add;
+    assert_eq!(cpu.execute(&mut map, &insn), Err(Exception::InvalidOpcode));
+}
+
+#[test]
+fn pause_is_a_nop() {
+    // pause; mov eax, 1
+    let (cpu, _) = run(&[0xF3, 0x90, 0xB8, 0x01, 0x00, 0x00, 0x00], |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[0], 1);
+}
+
+#[test]
+fn endbr_is_a_nop_in_either_mode() {
+    // endbr32; endbr64
+    let code = [0xF3, 0x0F, 0x1E, 0xFB, 0xF3, 0x0F, 0x1E, 0xFA];
+    let (cpu, _) = run(&code, |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.rip, CODE_BASE + 8);
+    let (cpu, _) = run32(&code, |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.rip, CODE_BASE + 8);
+}
+
+#[test]
+fn arithmetic_sets_flags_and_cmp_only_compares() {
+    // cmp rax, rbx; adc rcx, 0
+    let (cpu, _) = run(&[0x48, 0x39, 0xD8, 0x48, 0x83, 0xD1, 0x00], |cpu| {
+        cpu.regs.gprs[0] = 1;
+        cpu.regs.gprs[3] = 2;
+        cpu.regs.gprs[1] = 10;
+    });
+    assert_eq!(cpu.regs.gprs[0], 1);
+    // The borrow from the CMP carries into the ADC
+    assert_eq!(cpu.regs.gprs[1], 11);
+    assert_eq!(cpu.regs.rflags & flags::STATUS, 0);
+    assert_eq!(cpu.regs.rflags & flags::IF, flags::IF);
+}
+
+#[test]
+fn xor_self_sets_zero_flag() {
+    // xor ebp, ebp
+    let (cpu, _) = run(&[0x31, 0xED], |cpu| cpu.regs.gprs[5] = 0x1234);
+    assert_eq!(cpu.regs.gprs[5], 0);
+    assert_eq!(cpu.regs.rflags & flags::STATUS, flags::ZF | flags::PF);
+}
+
+#[test]
+fn loop_accumulates() {
+    // mov ecx, 5; xor eax, eax; l: add eax, ecx; loop l
+    let code = [
+        0xB9, 0x05, 0x00, 0x00, 0x00, 0x31, 0xC0, 0x01, 0xC8, 0xE2, 0xFC,
+    ];
+    let (cpu, _) = run(&code, |_| {});
+    assert_eq!(cpu.regs.gprs[0], 15);
+    assert_eq!(cpu.regs.gprs[1], 0);
+}
+
+#[test]
+fn call_and_ret() {
+    let code = [
+        0xE8, 0x06, 0x00, 0x00, 0x00, // call f
+        0x48, 0x83, 0xC3, 0x01, // add rbx, 1
+        0xEB, 0x05, // jmp end
+        0x48, 0x83, 0xC0, 0x07, // f: add rax, 7
+        0xC3, // ret
+    ];
+    let (cpu, _) = run(&code, |_| {});
+    assert_eq!(cpu.regs.gprs[0], 7);
+    assert_eq!(cpu.regs.gprs[3], 1);
+    assert_eq!(cpu.regs.gprs[4], STACK_TOP);
+}
+
+#[test]
+fn signed_conditional_branch() {
+    let code = [
+        0x48, 0x39, 0xD8, // cmp rax, rbx
+        0x7C, 0x04, // jl +4
+        0x48, 0x83, 0xC1, 0x01, // add rcx, 1 (skipped)
+        0x48, 0x83, 0xC2, 0x01, // add rdx, 1
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.regs.gprs[0] = -1i64 as u64;
+        cpu.regs.gprs[3] = 1;
+    });
+    assert_eq!(cpu.regs.gprs[1], 0);
+    assert_eq!(cpu.regs.gprs[2], 1);
+}
+
+#[test]
+fn setcc_and_cmovcc() {
+    let code = [
+        0x85, 0xC0, // test eax, eax
+        0x0F, 0x94, 0xC3, // sete bl
+        0xB9, 0x09, 0x00, 0x00, 0x00, // mov ecx, 9
+        0x0F, 0x45, 0xD1, // cmovne edx, ecx
+        0x0F, 0x44, 0xF1, // cmove esi, ecx
+    ];
+    let (cpu, _) = run(&code, |cpu| cpu.regs.gprs[2] = 0xFFFF_FFFF_0000_0003);
+    assert_eq!(cpu.regs.gprs[3] & 0xFF, 1);
+    // A 32-bit CMOV zero-extends its destination even when the move doesn't happen
+    assert_eq!(cpu.regs.gprs[2], 3);
+    assert_eq!(cpu.regs.gprs[6], 9);
+}
+
+#[test]
+fn byte_registers_with_and_without_rex() {
+    let code = [
+        0x88, 0xDC, // mov ah, bl
+        0x40, 0x88, 0xDE, // mov sil, bl
+        0x41, 0x88, 0xD8, // mov r8b, bl
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.regs.gprs[0] = 0x1111_1111_1111_1111;
+        cpu.regs.gprs[3] = 0xAB;
+        cpu.regs.gprs[6] = 0x2222_2222_2222_2222;
+        cpu.regs.gprs[8] = 0x3333_3333_3333_3333;
+    });
+    assert_eq!(cpu.regs.gprs[0], 0x1111_1111_1111_AB11);
+    assert_eq!(cpu.regs.gprs[6], 0x2222_2222_2222_22AB);
+    assert_eq!(cpu.regs.gprs[8], 0x3333_3333_3333_33AB);
+}
+
+#[test]
+fn partial_register_writes() {
+    let code = [
+        0x66, 0x83, 0xC0, 0x01, // add ax, 1
+        0x83, 0xC3, 0x01, // add ebx, 1
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.regs.gprs[0] = 0xAAAA_AAAA_AAAA_FFFF;
+        cpu.regs.gprs[3] = 0xBBBB_BBBB_FFFF_FFFF;
+    });
+    // 16-bit writes keep bits 16-63, 32-bit writes zero them
+    assert_eq!(cpu.regs.gprs[0], 0xAAAA_AAAA_AAAA_0000);
+    assert_eq!(cpu.regs.gprs[3], 0);
+    assert_eq!(cpu.regs.rflags & flags::CF, flags::CF);
+}
+
+#[test]
+fn memory_operands_use_operand_width() {
+    let code = [
+        0x80, 0x03, 0x01, // add byte ptr [rbx], 1
+        0x66, 0x89, 0x4B, 0x08, // mov word ptr [rbx+8], cx
+        0x48, 0x8B, 0x53, 0x10, // mov rdx, qword ptr [rbx+16]
+    ];
+    let (cpu, map) = run(&code, |cpu| {
+        cpu.regs.gprs[3] = 0x3000;
+        cpu.regs.gprs[1] = 0x5555_6666;
+    });
+    assert_eq!(map.read_u64(0x3000), 0x01);
+    assert_eq!(map.read_u64(0x3008), 0x6666);
+    assert_eq!(cpu.regs.gprs[2], 0);
+}
+
+#[test]
+fn widening_multiply() {
+    let code = [
+        0x48, 0xF7, 0xE3, // mul rbx
+        0xF6, 0xE9, // imul cl
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.regs.gprs[0] = u64::MAX;
+        cpu.regs.gprs[3] = 2;
+        cpu.regs.gprs[1] = 0x80; // -128
+    });
+    // RDX:RAX = 0x1:0xFFFF_FFFF_FFFF_FFFE, then AX = (int8)0xFE * -128 = 256
+    assert_eq!(cpu.regs.gprs[2], 1);
+    assert_eq!(cpu.regs.gprs[0] & 0xFFFF, 0x0100);
+    assert_eq!(
+        cpu.regs.rflags & (flags::CF | flags::OF),
+        flags::CF | flags::OF
+    );
+}
+
+#[test]
+fn truncating_multiply_forms() {
+    let code = [
+        0x48, 0x0F, 0xAF, 0xC3, // imul rax, rbx
+        0x6B, 0xD1, 0xFD, // imul edx, ecx, -3
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.regs.gprs[0] = 6;
+        cpu.regs.gprs[3] = -7i64 as u64;
+        cpu.regs.gprs[1] = 5;
+    });
+    assert_eq!(cpu.regs.gprs[0], -42i64 as u64);
+    assert_eq!(cpu.regs.gprs[2], -15i32 as u32 as u64);
+    assert_eq!(cpu.regs.rflags & (flags::CF | flags::OF), 0);
+}
+
+#[test]
+fn divide_and_remainder() {
+    let code = [
+        0xF7, 0xF3, // div ebx
+        0x31, 0xD2, // xor edx, edx
+        0x48, 0xF7, 0xF9, // idiv rcx
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.regs.gprs[2] = 1;
+        cpu.regs.gprs[0] = 5; // EDX:EAX = 0x1_0000_0005
+        cpu.regs.gprs[3] = 0x10;
+        cpu.regs.gprs[1] = -3i64 as u64;
+    });
+    // 0x1_0000_0005 / 0x10 = 0x1000_0000 r 5, then 0x1000_0000 / -3
+    assert_eq!(cpu.regs.gprs[0], (0x1000_0000i64 / -3) as u64);
+    assert_eq!(cpu.regs.gprs[2], (0x1000_0000i64 % -3) as u64);
+}
+
+#[test]
+fn divide_errors() {
+    // div ecx with ecx = 0
+    let (cpu, _) = run(&[0xF7, 0xF1], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::DivideError));
+    assert_eq!(cpu.regs.rip, CODE_BASE);
+
+    // Quotient overflow: AX = 0x1000 / 2 doesn't fit in AL
+    let (cpu, _) = run(&[0xF6, 0xF3], |cpu| {
+        cpu.regs.gprs[0] = 0x1000;
+        cpu.regs.gprs[3] = 2;
+    });
+    assert_eq!(cpu.fault(), Some(Exception::DivideError));
+
+    // The one signed overflow: INT64_MIN / -1
+    let (cpu, _) = run(&[0x48, 0xF7, 0xFB], |cpu| {
+        cpu.regs.gprs[0] = 0x8000_0000_0000_0000;
+        cpu.regs.gprs[2] = u64::MAX;
+        cpu.regs.gprs[3] = u64::MAX;
+    });
+    assert_eq!(cpu.fault(), Some(Exception::DivideError));
+}
+
+#[test]
+fn idiv_of_the_most_negative_double_width_dividend() {
+    // idiv rbx with RDX:RAX = i128::MIN and RBX = -1, which overflows even the double width
+    let (cpu, _) = run(&[0x48, 0xF7, 0xFB], |cpu| {
+        cpu.regs.gprs[0] = 0;
+        cpu.regs.gprs[2] = 0x8000_0000_0000_0000;
+        cpu.regs.gprs[3] = u64::MAX;
+    });
+    assert_eq!(cpu.fault(), Some(Exception::DivideError));
+    assert_eq!(cpu.regs.rip, CODE_BASE);
+}
+
+#[test]
+fn neg_and_not() {
+    let code = [
+        0xF7, 0xD8, // neg eax
+        0x48, 0xF7, 0xD3, // not rbx
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.regs.gprs[0] = 1;
+        cpu.regs.gprs[3] = 0xFF;
+    });
+    assert_eq!(cpu.regs.gprs[0], 0xFFFF_FFFF);
+    assert_eq!(cpu.regs.gprs[3], !0xFF);
+    assert_eq!(cpu.regs.rflags & flags::CF, flags::CF);
+}
+
+#[test]
+fn shift_group_encodings() {
+    let code = [
+        0x48, 0xC1, 0xE0, 0x04, // shl rax, 4
+        0xD1, 0xFB, // sar ebx, 1
+        0x48, 0xD3, 0xC2, // rol rdx, cl
+        0xC0, 0xD6, 0x01, // rcl sil, 1 (REX-less: dh)
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.regs.gprs[0] = 0x0F00_0000_0000_0001;
+        cpu.regs.gprs[3] = 0x8000_0001;
+        cpu.regs.gprs[2] = 0x8000_0000_0000_0001;
+        cpu.regs.gprs[1] = 65; // masked to 1
+    });
+    assert_eq!(cpu.regs.gprs[0], 0xF000_0000_0000_0010);
+    assert_eq!(cpu.regs.gprs[3], 0xC000_0000);
+    // ROL moves bit 63 into bit 0 and CF; RCL then rotates CF into DH
+    assert_eq!(cpu.regs.gprs[2] & 0xFF, 0x03);
+    assert_eq!((cpu.regs.gprs[2] >> 8) & 0xFF, 0x01);
+}
+
+#[test]
+fn zero_count_leaves_flags_but_zero_extends() {
+    let code = [
+        0x48, 0x83, 0xF8, 0x00, // cmp rax, 0 (sets ZF)
+        0xD3, 0xE3, // shl ebx, cl with cl = 0
+    ];
+    let (cpu, _) = run(&code, |cpu| cpu.regs.gprs[3] = 0xFFFF_FFFF_0000_0001);
+    assert_eq!(cpu.regs.gprs[3], 1);
+    assert_eq!(cpu.regs.rflags & flags::ZF, flags::ZF);
+}
+
+#[test]
+fn double_precision_shift_encodings() {
+    let code = [
+        0x48, 0x0F, 0xA4, 0xD8, 0x08, // shld rax, rbx, 8
+        0x0F, 0xAD, 0xD9, // shrd ecx, ebx, cl
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.regs.gprs[0] = 0x0011_2233_4455_6677;
+        cpu.regs.gprs[3] = 0xAABB_CCDD_EEFF_0099;
+        cpu.regs.gprs[1] = 0x0000_0010;
+    });
+    assert_eq!(cpu.regs.gprs[0], 0x1122_3344_5566_77AA);
+    // ECX = 0x10 shifted right by 16, filled from EBX's low half
+    assert_eq!(cpu.regs.gprs[1], 0x0099_0000);
+}
+
+#[test]
+fn rep_movsb_copies_and_advances() {
+    let src: Vec<u8> = (0..200).collect();
+    // rep movsb
+    let (cpu, map) = run_with_data(&[0xF3, 0xA4], &[(0x3000, &src)], |cpu| {
+        cpu.regs.gprs[6] = 0x3000;
+        cpu.regs.gprs[7] = 0x5000;
+        cpu.regs.gprs[1] = 200;
+    });
+    assert_eq!(&map.mem[0x5000..0x50C8], &src[..]);
+    assert_eq!(cpu.regs.gprs[1], 0);
+    assert_eq!(cpu.regs.gprs[6], 0x30C8);
+    assert_eq!(cpu.regs.gprs[7], 0x50C8);
+}
+
+#[test]
+fn rep_movsb_overlap_replicates_pattern() {
+    // Copying onto the next byte smears the first byte forward, element by element
+    let (_, map) = run_with_data(&[0xF3, 0xA4], &[(0x3000, &[0xAB, 1, 2, 3, 4])], |cpu| {
+        cpu.regs.gprs[6] = 0x3000;
+        cpu.regs.gprs[7] = 0x3001;
+        cpu.regs.gprs[1] = 4;
+    });
+    assert_eq!(&map.mem[0x3000..0x3005], &[0xAB; 5]);
+}
+
+#[test]
+fn stos_forwards_and_backwards() {
+    let code = [
+        0xF3, 0x48, 0xAB, // rep stosq
+        0xFD, // std
+        0xAB, // stosd
+        0xFC, // cld
+    ];
+    let (cpu, map) = run(&code, |cpu| {
+        cpu.regs.gprs[0] = 0x1122_3344_5566_7788;
+        cpu.regs.gprs[7] = 0x3000;
+        cpu.regs.gprs[1] = 3;
+    });
+    assert_eq!(map.read_u64(0x3000), 0x1122_3344_5566_7788);
+    assert_eq!(map.read_u64(0x3010), 0x1122_3344_5566_7788);
+    assert_eq!(map.read_u32(0x3018), 0x5566_7788);
+    assert_eq!(cpu.regs.gprs[7], 0x3014);
+    assert_eq!(cpu.regs.rflags & flags::DF, 0);
+}
+
+#[test]
+fn repne_scasb_finds_terminator() {
+    // The strlen idiom: repne scasb with rcx = -1
+    let (cpu, _) = run_with_data(&[0xF2, 0xAE], &[(0x3000, b"hello\0")], |cpu| {
+        cpu.regs.gprs[0] = 0;
+        cpu.regs.gprs[7] = 0x3000;
+        cpu.regs.gprs[1] = u64::MAX;
+    });
+    assert_eq!(cpu.regs.gprs[7], 0x3006);
+    assert_eq!(!cpu.regs.gprs[1] - 1, 5);
+    assert_eq!(cpu.regs.rflags & flags::ZF, flags::ZF);
+}
+
+#[test]
+fn repe_cmpsb_stops_at_mismatch() {
+    let data: [(u64, &[u8]); 2] = [(0x3000, b"abcX"), (0x4000, b"abcY")];
+    let (cpu, _) = run_with_data(&[0xF3, 0xA6], &data, |cpu| {
+        cpu.regs.gprs[6] = 0x3000;
+        cpu.regs.gprs[7] = 0x4000;
+        cpu.regs.gprs[1] = 10;
+    });
+    assert_eq!(cpu.regs.gprs[1], 6);
+    assert_eq!(cpu.regs.gprs[6], 0x3004);
+    assert_eq!(cpu.regs.rflags & (flags::ZF | flags::CF), flags::CF);
+}
+
+#[test]
+fn lodsw_merges_into_ax() {
+    // lodsw
+    let (cpu, _) = run_with_data(&[0x66, 0xAD], &[(0x3000, &[0x34, 0x12])], |cpu| {
+        cpu.regs.gprs[0] = 0xFFFF_FFFF_FFFF_FFFF;
+        cpu.regs.gprs[6] = 0x3000;
+    });
+    assert_eq!(cpu.regs.gprs[0], 0xFFFF_FFFF_FFFF_1234);
+    assert_eq!(cpu.regs.gprs[6], 0x3002);
+}
+
+#[test]
+fn bit_test_memory_offset_reaches_beyond_operand() {
+    let code = [
+        0x0F, 0xAB, 0x08, // bts dword ptr [rax], ecx
+        0x48, 0x0F, 0xA3, 0x10, // bt qword ptr [rax], rdx
+    ];
+    let (cpu, map) = run(&code, |cpu| {
+        cpu.regs.gprs[0] = 0x3008;
+        cpu.regs.gprs[1] = 35;
+        cpu.regs.gprs[2] = u64::MAX; // bit 63 of the qword below [rax]
+    });
+    assert_eq!(map.read_u32(0x300C), 1 << 3);
+    assert_eq!(cpu.regs.rflags & flags::CF, 0);
+    let (cpu, _) = run_with_data(&code[3..], &[(0x3007, &[0x80])], |cpu| {
+        cpu.regs.gprs[0] = 0x3008;
+        cpu.regs.gprs[2] = u64::MAX;
+    });
+    assert_eq!(cpu.regs.rflags & flags::CF, flags::CF);
+}
+
+#[test]
+fn bit_scans_and_counts() {
+    let code = [
+        0x0F, 0xBD, 0xC1, // bsr eax, ecx
+        0xF3, 0x0F, 0xBC, 0xD1, // tzcnt edx, ecx
+        0xF3, 0x0F, 0xB8, 0xD9, // popcnt ebx, ecx
+        0xF3, 0x0F, 0xBD, 0xF7, // lzcnt esi, edi (edi = 0)
+    ];
+    let (cpu, _) = run(&code, |cpu| cpu.regs.gprs[1] = 0x0001_0F00);
+    assert_eq!(cpu.regs.gprs[0], 16);
+    assert_eq!(cpu.regs.gprs[2], 8);
+    assert_eq!(cpu.regs.gprs[3], 5);
+    assert_eq!(cpu.regs.gprs[6], 32);
+    assert_eq!(cpu.regs.rflags & (flags::CF | flags::ZF), flags::CF);
+}
+
+#[test]
+fn bmi2_deposit_extract_and_multiply() {
+    let code = [
+        0xC4, 0xE2, 0xE3, 0xF5, 0xC1, // pdep rax, rbx, rcx
+        0xC4, 0xE2, 0xE2, 0xF5, 0xF1, // pext rsi, rbx, rcx
+        0xC4, 0x42, 0xB3, 0xF6, 0xC2, // mulx r8, r9, r10
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.regs.gprs[3] = 0b1011;
+        cpu.regs.gprs[1] = 0xF0F0;
+        cpu.regs.gprs[2] = u64::MAX;
+        cpu.regs.gprs[10] = 2;
+    });
+    assert_eq!(cpu.regs.gprs[0], 0xB0);
+    assert_eq!(cpu.regs.gprs[6], 0);
+    assert_eq!(cpu.regs.gprs[8], 1);
+    assert_eq!(cpu.regs.gprs[9], u64::MAX - 1);
+}
+
+#[test]
+fn function_prologue_and_epilogue() {
+    let code = [
+        0x55, // push rbp
+        0x48, 0x89, 0xE5, // mov rbp, rsp
+        0x48, 0x83, 0xEC, 0x10, // sub rsp, 0x10
+        0xC7, 0x45, 0xFC, 0x2A, 0x00, 0x00, 0x00, // mov dword ptr [rbp-0x4], 0x2a
+        0x8B, 0x45, 0xFC, // mov eax, dword ptr [rbp-0x4]
+        0xC9, // leave
+    ];
+    let (cpu, _) = run(&code, |cpu| cpu.regs.gprs[5] = 0x1234);
+    assert_eq!(cpu.regs.gprs[0], 0x2A);
+    assert_eq!(cpu.regs.gprs[4], STACK_TOP);
+    assert_eq!(cpu.regs.gprs[5], 0x1234);
+}
+
+#[test]
+fn enter_nested_frame() {
+    // enter 0x20, 1
+    let (cpu, map) = run(&[0xC8, 0x20, 0x00, 0x01], |cpu| cpu.regs.gprs[5] = 0x1234);
+    assert_eq!(cpu.regs.gprs[5], STACK_TOP - 8);
+    assert_eq!(cpu.regs.gprs[4], STACK_TOP - 16 - 0x20);
+    assert_eq!(map.read_u64(STACK_TOP - 8), 0x1234);
+    assert_eq!(map.read_u64(STACK_TOP - 16), STACK_TOP - 8);
+}
+
+#[test]
+fn extending_moves_and_conversions() {
+    let code = [
+        0x0F, 0xB6, 0xC1, // movzx eax, cl
+        0x48, 0x0F, 0xBE, 0xD1, // movsx rdx, cl
+        0x48, 0x63, 0xF7, // movsxd rsi, edi
+        0x66, 0x98, // cbw
+        0x48, 0x99, // cqo (rax is positive, so rdx is cleared)
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.regs.gprs[1] = 0x80;
+        cpu.regs.gprs[7] = 0x8000_0000;
+    });
+    assert_eq!(cpu.regs.gprs[0], 0xFF80);
+    assert_eq!(cpu.regs.gprs[2], 0);
+    assert_eq!(cpu.regs.gprs[6], 0xFFFF_FFFF_8000_0000);
+
+    // movsx rdx, cl on its own
+    let (cpu, _) = run(&code[3..7], |cpu| cpu.regs.gprs[1] = 0x80);
+    assert_eq!(cpu.regs.gprs[2], 0xFFFF_FFFF_FFFF_FF80);
+}
+
+#[test]
+fn exchanges_and_byte_swaps() {
+    let code = [
+        0x48, 0x93, // xchg rbx, rax
+        0x0F, 0xC9, // bswap ecx
+        0x0F, 0x38, 0xF0, 0x17, // movbe edx, dword ptr [rdi]
+    ];
+    let (cpu, _) = run_with_data(&code, &[(0x3000, &[0x11, 0x22, 0x33, 0x44])], |cpu| {
+        cpu.regs.gprs[0] = 1;
+        cpu.regs.gprs[3] = 2;
+        cpu.regs.gprs[1] = 0xFFFF_FFFF_1122_3344;
+        cpu.regs.gprs[7] = 0x3000;
+    });
+    assert_eq!((cpu.regs.gprs[0], cpu.regs.gprs[3]), (2, 1));
+    assert_eq!(cpu.regs.gprs[1], 0x4433_2211);
+    assert_eq!(cpu.regs.gprs[2], 0x1122_3344);
+}
+
+#[test]
+fn faulting_xchg_leaves_the_register() {
+    let code = [
+        0x66, 0xB8, 0x28, 0x00, // mov ax, 0x28
+        0x8E, 0xD8, // mov ds, ax, the read-only segment
+        0x66, 0xB8, 0x34, 0x12, // mov ax, 0x1234
+        0x66, 0x87, 0x05, 0x10, 0x00, 0x00, 0x00, // xchg [0x10], ax
+    ];
+    let (cpu, _) = run_protected(&code, &[(0x8010, &[0xEF, 0xBE])]);
+    assert_eq!(cpu.regs.gprs[1] & 0xFF, 13);
+    assert_eq!(cpu.regs.gprs[0] & 0xFFFF, 0x1234);
+}
+
+#[test]
+fn push_immediates_and_pop_to_memory() {
+    let code = [
+        0x6A, 0xFE, // push -2
+        0x68, 0x78, 0x56, 0x34, 0x12, // push 0x12345678
+        0x8F, 0x07, // pop qword ptr [rdi]
+        0x59, // pop rcx
+    ];
+    let (cpu, map) = run(&code, |cpu| cpu.regs.gprs[7] = 0x3000);
+    assert_eq!(map.read_u64(0x3000), 0x1234_5678);
+    assert_eq!(cpu.regs.gprs[1], (-2i64) as u64);
+    assert_eq!(cpu.regs.gprs[4], STACK_TOP);
+}
+
+#[test]
+fn flag_transfers() {
+    let code = [
+        0xB4, 0xD5, // mov ah, 0xd5
+        0x9E, // sahf
+        0x9C, // pushf
+        0x5B, // pop rbx
+        0x6A, 0x00, // push 0
+        0x9D, // popf
+        0x9F, // lahf
+    ];
+    let (cpu, _) = run(&code, |_| {});
+    assert_eq!(cpu.regs.gprs[3] & 0xFF, 0xD7);
+    // POPF can't clear IF at CPL 3
+    assert_eq!(cpu.regs.rflags, flags::RESERVED | flags::IF);
+    assert_eq!(cpu.regs.gprs[0] >> 8 & 0xFF, 0x02);
+}
+
+#[test]
+fn cmpxchg_success_and_failure() {
+    let code = [
+        0xF0, 0x0F, 0xB1, 0x0F, // lock cmpxchg dword ptr [rdi], ecx
+        0x0F, 0x94, 0xC2, // sete dl
+        0xF0, 0x0F, 0xB1, 0x0F, // lock cmpxchg dword ptr [rdi], ecx
+    ];
+    let (cpu, map) = run_with_data(&code, &[(0x3000, &[5, 0, 0, 0])], |cpu| {
+        cpu.regs.gprs[0] = 5;
+        cpu.regs.gprs[1] = 9;
+        cpu.regs.gprs[7] = 0x3000;
+    });
+    assert_eq!(map.read_u32(0x3000), 9);
+    assert_eq!(cpu.regs.gprs[2], 1);
+    // The second attempt sees 9, not 5, and loads it into EAX
+    assert_eq!(cpu.regs.gprs[0], 9);
+    assert_eq!(cpu.regs.rflags & flags::ZF, 0);
+}
+
+#[test]
+fn xadd_returns_old_value() {
+    // lock xadd qword ptr [rdi], rax
+    let (cpu, map) = run_with_data(&[0xF0, 0x48, 0x0F, 0xC1, 0x07], &[(0x3000, &[40])], |cpu| {
+        cpu.regs.gprs[0] = 2;
+        cpu.regs.gprs[7] = 0x3000;
+    });
+    assert_eq!(map.read_u64(0x3000), 42);
+    assert_eq!(cpu.regs.gprs[0], 40);
+}
+
+#[test]
+fn cmpxchg16b_requires_alignment() {
+    // lock cmpxchg16b xmmword ptr [rdi]
+    let code = [0xF0, 0x48, 0x0F, 0xC7, 0x0F];
+    let (cpu, map) = run(&code, |cpu| {
+        cpu.regs.gprs[3] = 0x1111;
+        cpu.regs.gprs[1] = 0x2222;
+        cpu.regs.gprs[7] = 0x3000;
+    });
+    assert_eq!(
+        (map.read_u64(0x3000), map.read_u64(0x3008)),
+        (0x1111, 0x2222)
+    );
+    assert_eq!(cpu.regs.rflags & flags::ZF, flags::ZF);
+
+    let (cpu, _) = run(&code, |cpu| cpu.regs.gprs[7] = 0x3008);
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+    assert_eq!(cpu.regs.rip, CODE_BASE);
+}
+
+#[test]
+fn locked_adds_are_atomic_across_processors() {
+    let code = [
+        0xB9, 0x20, 0x4E, 0x00, 0x00, // mov ecx, 20000
+        0xF0, 0x48, 0x83, 0x07, 0x01, // lock add qword ptr [rdi], 1
+        0xE2, 0xF9, // loop -7
+    ];
+    let memory = SharedMemoryMap::new(Box::new(TestMap::new(&code, 64)));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let mut map = memory.clone();
+            std::thread::spawn(move || {
+                let mut cpu = Amd64Interp::new();
+                cpu.init(&mut map);
+                cpu.regs.gprs[7] = 0x3000;
+                while cpu.running() && cpu.regs.rip < CODE_BASE + code.len() as u64 {
+                    cpu.tick(&mut map);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(memory.read_u64(0x3000), 80000);
+}
+
+#[test]
+fn cpuid_reports_the_model() {
+    // cpuid
+    let (cpu, _) = run(&[0x0F, 0xA2], |cpu| {
+        cpu.model = CpuModel::skylake();
+        cpu.regs.gprs[0] = 0xFFFF_FFFF_0000_0001;
+        cpu.regs.gprs[3] = u64::MAX;
+    });
+    assert_eq!(cpu.regs.gprs[0], 0x506E3);
+    assert_eq!(cpu.regs.gprs[3] >> 32, 0);
+    assert_ne!(cpu.regs.gprs[1] & 1 << 23, 0, "POPCNT");
+    assert_ne!(cpu.regs.gprs[2] & 1 << 26, 0, "SSE2");
+}
+
+#[test]
+fn missing_extensions_raise_invalid_opcode() {
+    // andn eax, ebx, ecx
+    let (cpu, _) = run(&[0xC4, 0xE2, 0x60, 0xF2, 0xC1], |cpu| {
+        cpu.model = CpuModel::x86_64_v1();
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    assert_eq!(cpu.regs.rip, CODE_BASE);
+
+    // xgetbv
+    let (cpu, _) = run(&[0x0F, 0x01, 0xD0], |cpu| {
+        cpu.model = CpuModel::x86_64_v3().without(Feature::Xsave);
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn lzcnt_without_abm_is_bsr() {
+    // lzcnt eax, ecx
+    let (cpu, _) = run(&[0xF3, 0x0F, 0xBD, 0xC1], |cpu| {
+        cpu.model = CpuModel::x86_64_v1();
+        cpu.regs.gprs[1] = 0x100;
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[0], 8);
+}
+
+#[test]
+fn xgetbv_reads_xcr0() {
+    // xgetbv
+    let (cpu, _) = run(&[0x0F, 0x01, 0xD0], |cpu| cpu.model = CpuModel::skylake());
+    assert_eq!(cpu.regs.gprs[0], 0b111);
+    assert_eq!(cpu.regs.gprs[2], 0);
+}
+
+#[test]
+fn xsetbv_enables_state_components() {
+    let code = [
+        0x31, 0xC9, // xor ecx, ecx
+        0x31, 0xD2, // xor edx, edx
+        0xB8, 0x03, 0x00, 0x00, 0x00, // mov eax, 3
+        0x0F, 0x01, 0xD1, // xsetbv
+        0x0F, 0x01, 0xD0, // xgetbv
+        0x89, 0xC6, // mov esi, eax
+        0xB8, 0x0D, 0x00, 0x00, 0x00, // mov eax, 0xd
+        0x31, 0xC9, // xor ecx, ecx
+        0x0F, 0xA2, // cpuid
+    ];
+    // With AVX's state turned off, the XSAVE area shrinks to the legacy part and the header
+    let (cpu, _) = run_protected(&code, &[]);
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[6], 0b11);
+    assert_eq!(cpu.regs.gprs[3], 576);
+    assert_eq!(
+        cpu.regs.gprs[1], 832,
+        "ECX still covers every supported component"
+    );
+
+    // AVX without SSE, AVX-512 on a model without it, and x87 off are all refused
+    for value in [0b101, 0xE7, 0b110] {
+        let mut code = code[..12].to_vec();
+        code[5] = value;
+        let (cpu, _) = run_protected(&code, &[]);
+        assert_eq!(cpu.regs.gprs[1] & 0xFF, 13, "{:#x}", value);
+        assert_eq!(cpu.xcr0, 0b111);
+    }
+    // So is XSETBV outside ring 0
+    let (cpu, _) = run(&code[..12], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+
+    // Instructions whose state XCR0 leaves off are undefined: vaddps ymm0, ymm0, ymm1
+    let (cpu, _) = run(&[0xC5, 0xFC, 0x58, 0xC1], |cpu| cpu.xcr0 = 0b11);
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn xcr0_needs_cr4_osxsave() {
+    // mov eax, 1; cpuid; xgetbv
+    let code = [0xB8, 0x01, 0x00, 0x00, 0x00, 0x0F, 0xA2, 0x0F, 0x01, 0xD0];
+    let (cpu, _) = run(&code[..7], |_| {});
+    assert_ne!(cpu.regs.gprs[1] & 1 << 27, 0);
+    // CPUID's OSXSAVE bit follows CR4, and XGETBV and XSETBV are undefined without it
+    let (cpu, _) = run(&code, |cpu| cpu.regs.cr[4] &= !simd::CR4_OSXSAVE);
+    assert_eq!(cpu.regs.gprs[1] & 1 << 27, 0);
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    let (cpu, _) = run(&[0x0F, 0x01, 0xD1], |cpu| {
+        cpu.regs.cr[4] &= !simd::CR4_OSXSAVE
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+/// An 80-bit value as stored in memory by FSTP m80.
+fn read_tbyte(map: &TestMap, addr: u64) -> (u16, u64) {
+    (map.read_u16(addr + 8), map.read_u64(addr))
+}
+
+#[test]
+fn x87_double_arithmetic() {
+    // fld qword ptr [0x3000]; fadd qword ptr [0x3008]; fstp qword ptr [0x3010]
+    let code = [
+        0xDD, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00, 0xDC, 0x04, 0x25, 0x08, 0x30, 0x00, 0x00, 0xDD,
+        0x1C, 0x25, 0x10, 0x30, 0x00, 0x00,
+    ];
+    let (cpu, map) = run_with_data(
+        &code,
+        &[
+            (0x3000, &1.5f64.to_le_bytes()),
+            (0x3008, &2.25f64.to_le_bytes()),
+        ],
+        |_| {},
+    );
+    assert_eq!(f64::from_bits(map.read_u64(0x3010)), 3.75);
+    assert_eq!(cpu.regs.fpu.ftw, 0xFFFF);
+    assert_eq!(cpu.regs.fpu.fsw, 0);
+}
+
+#[test]
+fn x87_precision_control() {
+    // fldcw [0x3000]; fld1; fild dword ptr [0x3004]; fdivp st(1), st;
+    // fstp tbyte ptr [0x3010]
+    let code = [
+        0xD9, 0x2C, 0x25, 0x00, 0x30, 0x00, 0x00, 0xD9, 0xE8, 0xDB, 0x04, 0x25, 0x04, 0x30, 0x00,
+        0x00, 0xDE, 0xF9, 0xDB, 0x3C, 0x25, 0x10, 0x30, 0x00, 0x00,
+    ];
+    let third = |fcw: u16| {
+        let (cpu, map) = run_with_data(
+            &code,
+            &[(0x3000, &fcw.to_le_bytes()), (0x3004, &3u32.to_le_bytes())],
+            |_| {},
+        );
+        assert_eq!(cpu.fault(), None);
+        read_tbyte(&map, 0x3010)
+    };
+    assert_eq!(third(0x037F), (0x3FFD, 0xAAAA_AAAA_AAAA_AAAB));
+    assert_eq!(third(0x027F), (0x3FFD, 0xAAAA_AAAA_AAAA_A800));
+    assert_eq!(third(0x007F), (0x3FFD, 0xAAAA_AB00_0000_0000));
+    // Round toward zero
+    assert_eq!(third(0x0F7F), (0x3FFD, 0xAAAA_AAAA_AAAA_AAAA));
+}
+
+#[test]
+fn x87_integer_rounding_and_bcd() {
+    // fld qword ptr [0x3000]; fist dword ptr [0x3010]; fldcw [0x3008];
+    // fist dword ptr [0x3014]; fisttp dword ptr [0x3018]; fild word ptr [0x300A];
+    // fbstp tbyte ptr [0x3020]
+    let code = [
+        0xDD, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00, 0xDB, 0x14, 0x25, 0x10, 0x30, 0x00, 0x00, 0xD9,
+        0x2C, 0x25, 0x08, 0x30, 0x00, 0x00, 0xDB, 0x14, 0x25, 0x14, 0x30, 0x00, 0x00, 0xDB, 0x0C,
+        0x25, 0x18, 0x30, 0x00, 0x00, 0xDF, 0x04, 0x25, 0x0A, 0x30, 0x00, 0x00, 0xDF, 0x34, 0x25,
+        0x20, 0x30, 0x00, 0x00,
+    ];
+    let (cpu, map) = run_with_data(
+        &code,
+        &[
+            (0x3000, &(-2.5f64).to_le_bytes()),
+            // Round down, then -1234
+            (0x3008, &[0x7F, 0x07, 0x2E, 0xFB]),
+        ],
+        |_| {},
+    );
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(map.read_u32(0x3010) as i32, -2);
+    assert_eq!(map.read_u32(0x3014) as i32, -3);
+    assert_eq!(map.read_u32(0x3018) as i32, -2);
+    let mut bcd = [0; 10];
+    map.read_bytes(0x3020, &mut bcd);
+    assert_eq!(bcd, [0x34, 0x12, 0, 0, 0, 0, 0, 0, 0, 0x80]);
+    assert_ne!(cpu.regs.fpu.fsw & 0x20, 0, "inexact");
+}
+
+#[test]
+fn fcomi_sets_eflags() {
+    // fld qword ptr [0x3000]; fld qword ptr [0x3008]; fcomi st, st(1); setae al;
+    // fucomip st, st(1); sete bl; fld qword ptr [0x3010]; fucomi st, st(1)
+    let code = [
+        0xDD, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00, 0xDD, 0x04, 0x25, 0x08, 0x30, 0x00, 0x00, 0xDB,
+        0xF1, 0x0F, 0x93, 0xC0, 0xDF, 0xE9, 0x0F, 0x94, 0xC3, 0xDD, 0x04, 0x25, 0x10, 0x30, 0x00,
+        0x00, 0xDB, 0xE9,
+    ];
+    let (cpu, _) = run_with_data(
+        &code,
+        &[
+            (0x3000, &1.0f64.to_le_bytes()),
+            (0x3008, &2.0f64.to_le_bytes()),
+            (0x3010, &f64::NAN.to_le_bytes()),
+        ],
+        |cpu| cpu.regs.rflags |= flags::OF | flags::SF,
+    );
+    assert_eq!(cpu.regs.gprs[0] & 0xFF, 1);
+    assert_eq!(cpu.regs.gprs[3] & 0xFF, 0);
+    let status = cpu.regs.rflags & flags::STATUS;
+    assert_eq!(status, flags::ZF | flags::PF | flags::CF);
+    // A quiet NaN doesn't trouble FUCOMI
+    assert_eq!(cpu.regs.fpu.fsw & 0x01, 0);
+}
+
+#[test]
+fn x87_stack_faults() {
+    // fadd st, st(1) with an empty stack
+    let (cpu, _) = run(&[0xD8, 0xC1], |_| {});
+    assert_eq!(cpu.regs.fpu.fsw & 0x3FF, 0x41, "IE and SF, with C1 clear");
+    assert_eq!(cpu.regs.fpu.st[0].sign_exponent, 0xFFFF);
+    assert_eq!(cpu.regs.fpu.st[0].significand, 0xC000_0000_0000_0000);
+
+    // Nine fld1s; fnstsw ax
+    let mut code = [0xD9, 0xE8].repeat(9);
+    code.extend([0xDF, 0xE0]);
+    let (cpu, _) = run(&code, |_| {});
+    assert_eq!(cpu.regs.gprs[0] & 0x3FF, 0x241, "IE and SF, with C1 set");
+    assert_eq!(cpu.regs.gprs[0] >> 11 & 7, 7, "TOP");
+    assert_eq!(cpu.regs.fpu.ftw, 0x8000, "all valid but the indefinite");
+}
+
+#[test]
+fn unmasked_exceptions_fault_on_the_next_wait() {
+    // fldcw [0x3000]; fld1; fldz; fdivp st(1), st; fwait
+    let code = [
+        0xD9, 0x2C, 0x25, 0x00, 0x30, 0x00, 0x00, 0xD9, 0xE8, 0xD9, 0xEE, 0xDE, 0xF9, 0x9B,
+    ];
+    let (cpu, _) = run_with_data(&code, &[(0x3000, &0x037Bu16.to_le_bytes())], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::FloatingPoint));
+    assert_eq!(cpu.regs.rip, CODE_BASE + 13);
+    assert_eq!(cpu.regs.fpu.fsw & 0x8084, 0x8084, "ZE, ES and B");
+    assert_eq!(cpu.regs.fpu.fip, CODE_BASE + 11);
+    assert_eq!(cpu.regs.fpu.fop, 0x6F9);
+    // The division was suppressed, so nothing was popped
+    assert_eq!(cpu.regs.fpu.fsw >> 11 & 7, 6);
+}
+
+#[test]
+fn x87_transcendentals() {
+    // fld qword ptr [0x3000]; fsin; fstp qword ptr [0x3010]; fld1; fild dword ptr [0x3008];
+    // fyl2x; fistp dword ptr [0x3018]; fld qword ptr [0x3000]; fptan; fstp st(0);
+    // fstp qword ptr [0x3020]
+    let code = [
+        0xDD, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00, 0xD9, 0xFE, 0xDD, 0x1C, 0x25, 0x10, 0x30, 0x00,
+        0x00, 0xD9, 0xE8, 0xDB, 0x04, 0x25, 0x08, 0x30, 0x00, 0x00, 0xD9, 0xF1, 0xDB, 0x1C, 0x25,
+        0x18, 0x30, 0x00, 0x00, 0xDD, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00, 0xD9, 0xF2, 0xDD, 0xD8,
+        0xDD, 0x1C, 0x25, 0x20, 0x30, 0x00, 0x00,
+    ];
+    let (cpu, map) = run_with_data(
+        &code,
+        &[
+            (0x3000, &0.5f64.to_le_bytes()),
+            (0x3008, &8u32.to_le_bytes()),
+        ],
+        |_| {},
+    );
+    assert_eq!(cpu.fault(), None);
+    let sin = f64::from_bits(map.read_u64(0x3010));
+    assert!((sin - 0.5f64.sin()).abs() <= f64::EPSILON, "{sin}");
+    assert_eq!(map.read_u32(0x3018), 3);
+    let tan = f64::from_bits(map.read_u64(0x3020));
+    assert!((tan - 0.5f64.tan()).abs() <= f64::EPSILON, "{tan}");
+    assert_eq!(cpu.regs.fpu.ftw, 0xFFFF);
+
+    // fld qword ptr [0x3000]; fsin
+    let code = [0xDD, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00, 0xD9, 0xFE];
+    let (cpu, _) = run_with_data(&code, &[(0x3000, &1.0e20f64.to_le_bytes())], |_| {});
+    assert_ne!(cpu.regs.fpu.fsw & 0x400, 0, "C2: out of range");
+    assert_eq!(cpu.regs.fpu.st[7].significand, 0xAD78_EBC5_AC62_0000);
+}
+
+#[test]
+fn x87_state_save_and_restore() {
+    // fld1; fldpi; fcmovb st, st(1); fldpi; fnsave [0x3000]; fnstsw ax; frstor [0x3000];
+    // fstp tbyte ptr [0x3100]; fstp tbyte ptr [0x3110]
+    let code = [
+        0xD9, 0xE8, 0xD9, 0xEB, 0xDA, 0xC1, 0xD9, 0xEB, 0xDD, 0x34, 0x25, 0x00, 0x30, 0x00, 0x00,
+        0xDF, 0xE0, 0xDD, 0x24, 0x25, 0x00, 0x30, 0x00, 0x00, 0xDB, 0x3C, 0x25, 0x00, 0x31, 0x00,
+        0x00, 0xDB, 0x3C, 0x25, 0x10, 0x31, 0x00, 0x00,
+    ];
+    let (cpu, map) = run(&code, |cpu| cpu.regs.rflags |= flags::CF);
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(map.read_u16(0x3000), 0x037F);
+    assert_eq!(map.read_u16(0x3008), 0x03FF, "tag word");
+    assert_eq!(cpu.regs.gprs[0] & 0xFFFF, 0, "FNSAVE reinitializes");
+    assert_eq!(read_tbyte(&map, 0x3100), (0x4000, 0xC90F_DAA2_2168_C235));
+    assert_eq!(read_tbyte(&map, 0x3110), (0x3FFF, 0x8000_0000_0000_0000));
+    assert_eq!(cpu.regs.fpu.ftw, 0x3FFF);
+}
+
+/// Runs `op` on MM0 and MM1 loaded from RAX and RCX, returning MM0.
+fn mmx(op: &[u8], a: u64, b: u64) -> u64 {
+    // movq mm0, rax; movq mm1, rcx; <op>; movq rax, mm0
+    let code = [
+        &[0x48, 0x0F, 0x6E, 0xC0, 0x48, 0x0F, 0x6E, 0xC9],
+        op,
+        &[0x48, 0x0F, 0x7E, 0xC0],
+    ];
+    let (cpu, _) = run(&code.concat(), |cpu| {
+        cpu.regs.gprs[0] = a;
+        cpu.regs.gprs[1] = b;
+    });
+    assert_eq!(cpu.fault(), None);
+    cpu.regs.gprs[0]
+}
+
+#[test]
+fn mmx_packed_arithmetic() {
+    // paddb mm0, mm1
+    assert_eq!(
+        mmx(
+            &[0x0F, 0xFC, 0xC1],
+            0xFF7F_0001_0203_0405,
+            0x0101_0101_0101_0101
+        ),
+        0x0080_0102_0304_0506
+    );
+    // paddsw mm0, mm1
+    assert_eq!(
+        mmx(
+            &[0x0F, 0xED, 0xC1],
+            0x7FFF_8000_0001_FFFF,
+            0x0001_FFFF_0002_FFFF
+        ),
+        0x7FFF_8000_0003_FFFE
+    );
+    // pmullw mm0, mm1
+    assert_eq!(
+        mmx(
+            &[0x0F, 0xD5, 0xC1],
+            0x0003_0100_FFFF_0002,
+            0x0005_0100_0002_8000
+        ),
+        0x000F_0000_FFFE_0000
+    );
+    // pmaddwd mm0, mm1
+    assert_eq!(
+        mmx(
+            &[0x0F, 0xF5, 0xC1],
+            0x0002_0003_FFFF_0004,
+            0x0005_0006_0007_0008
+        ),
+        0x0000_001C_0000_0019
+    );
+    // pcmpgtw mm0, mm1
+    assert_eq!(
+        mmx(
+            &[0x0F, 0x65, 0xC1],
+            0x0001_8000_0005_0000,
+            0x0000_0000_0005_FFFF
+        ),
+        0xFFFF_0000_0000_FFFF
+    );
+}
+
+#[test]
+fn mmx_unpack_pack_and_shift() {
+    // punpcklbw mm0, mm1
+    assert_eq!(
+        mmx(
+            &[0x0F, 0x60, 0xC1],
+            0x0807_0605_0403_0201,
+            0x1110_0F0E_0D0C_0B0A
+        ),
+        0x0D04_0C03_0B02_0A01
+    );
+    // packuswb mm0, mm1
+    assert_eq!(
+        mmx(&[0x0F, 0x67, 0xC1], 0x0100_00FF_FFFF_0010, 0),
+        0xFFFF_0010
+    );
+    // psrlw mm0, 3
+    assert_eq!(
+        mmx(&[0x0F, 0x71, 0xD0, 0x03], 0x8000_0010_FFFF_0008, 0),
+        0x1000_0002_1FFF_0001
+    );
+    // psllq mm0, mm1 with a count past the lane width
+    assert_eq!(mmx(&[0x0F, 0xF3, 0xC1], u64::MAX, 64), 0);
+}
+
+#[test]
+fn mmx_moves() {
+    // movq mm0, [0x3000]; movq [0x3008], mm0; movd [0x3010], mm0; movd mm1, eax; movq rdx, mm1
+    let code = [
+        0x0F, 0x6F, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00, 0x0F, 0x7F, 0x04, 0x25, 0x08, 0x30, 0x00,
+        0x00, 0x0F, 0x7E, 0x04, 0x25, 0x10, 0x30, 0x00, 0x00, 0x0F, 0x6E, 0xC8, 0x48, 0x0F, 0x7E,
+        0xCA,
+    ];
+    let value = 0x1122_3344_5566_7788u64.to_le_bytes();
+    let (cpu, map) = run_with_data(&code, &[(0x3000, &value), (0x3010, &[0xFF; 8])], |cpu| {
+        cpu.regs.gprs[0] = 0xDEAD_BEEF_8765_4321;
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(map.read_u64(0x3008), 0x1122_3344_5566_7788);
+    assert_eq!(map.read_u64(0x3010), 0xFFFF_FFFF_5566_7788);
+    assert_eq!(
+        cpu.regs.gprs[2], 0x8765_4321,
+        "movd zero-extends into the MMX register"
+    );
+}
+
+#[test]
+fn mmx_registers_alias_the_x87_stack() {
+    // fld1; movd mm1, eax
+    let (cpu, _) = run(&[0xD9, 0xE8, 0x0F, 0x6E, 0xC8], |cpu| {
+        cpu.regs.gprs[0] = 0x1234
+    });
+    assert_eq!(cpu.regs.fpu.fsw >> 11 & 7, 0, "TOP");
+    assert_eq!(cpu.regs.fpu.ftw, 0);
+    assert_eq!(cpu.regs.fpu.st[1].significand, 0x1234);
+    assert_eq!(cpu.regs.fpu.st[1].sign_exponent, 0xFFFF);
+
+    // movd mm1, eax; emms; fld1
+    let (cpu, _) = run(&[0x0F, 0x6E, 0xC8, 0x0F, 0x77, 0xD9, 0xE8], |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.fpu.fsw >> 11 & 7, 7, "TOP");
+    assert_eq!(cpu.regs.fpu.ftw, 0x3FFF);
+    assert_eq!(cpu.regs.fpu.st[7].significand, 0x8000_0000_0000_0000);
+}
+
+#[test]
+fn mmx_unavailable() {
+    // paddb mm0, mm1
+    let (cpu, _) = run(&[0x0F, 0xFC, 0xC1], |cpu| cpu.regs.cr[0] |= 1 << 2);
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    let (cpu, _) = run(&[0x0F, 0xFC, 0xC1], |cpu| cpu.regs.cr[0] |= 1 << 3);
+    assert_eq!(cpu.fault(), Some(Exception::DeviceNotAvailable));
+}
+
+/// The low 128 bits of an XMM register.
+fn xmm(cpu: &Amd64Interp, n: usize) -> u128 {
+    u128::from_le_bytes(
+        bytemuck::bytes_of(&cpu.regs.zmm[n])[..16]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+fn set_xmm(cpu: &mut Amd64Interp, n: usize, value: u128) {
+    bytemuck::bytes_of_mut(&mut cpu.regs.zmm[n])[..16].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Packs four singles into an XMM image, element 0 lowest.
+fn singles(values: [f32; 4]) -> u128 {
+    values
+        .iter()
+        .rev()
+        .fold(0, |acc, value| acc << 32 | value.to_bits() as u128)
+}
+
+/// Packs two doubles into an XMM image, element 0 lowest.
+fn doubles(values: [f64; 2]) -> u128 {
+    (values[1].to_bits() as u128) << 64 | values[0].to_bits() as u128
+}
+
+#[test]
+fn sse_integer_compare_and_moves() {
+    // movdqa xmm0, [0x3000]; pcmpeqb xmm0, [0x3010]; pmovmskb eax, xmm0;
+    // movdqu [0x3021], xmm0
+    let code = [
+        0x66, 0x0F, 0x6F, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00, 0x66, 0x0F, 0x74, 0x04, 0x25, 0x10,
+        0x30, 0x00, 0x00, 0x66, 0x0F, 0xD7, 0xC0, 0xF3, 0x0F, 0x7F, 0x04, 0x25, 0x21, 0x30, 0x00,
+        0x00,
+    ];
+    let a: Vec<u8> = (0..16).collect();
+    let mut b = a.clone();
+    b[3] = 0xFF;
+    b[15] = 0xFF;
+    let (cpu, map) = run_with_data(&code, &[(0x3000, &a), (0x3010, &b)], |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[0], 0x7FF7);
+    assert_eq!(map.read_u32(0x3021), 0x00FF_FFFF);
+    assert_eq!(map.read_u64(0x3029), 0x00FF_FFFF_FFFF_FFFF);
+}
+
+#[test]
+fn maskmovdqu_stores_through_the_segment() {
+    // maskmovdqu fs:[rdi], xmm0, xmm1
+    let code = [0x64, 0x66, 0x0F, 0xF7, 0xC1];
+    let (cpu, map) = run_with_data(&code, &[(0x3010, &[0x55; 16])], |cpu| {
+        cpu.regs.sr_base[4] = 0x3000;
+        cpu.regs.gprs[7] = 0x10;
+        set_xmm(cpu, 0, 0x0F0E_0D0C_0B0A_0908_0706_0504_0302_0100);
+        set_xmm(cpu, 1, 0x8000_0000_0000_0000_0000_0000_00FF_7F80);
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(map.read_u32(0x3010), 0x5502_5500);
+    assert_eq!(map.read_u64(0x3014), 0x5555_5555_5555_5555);
+    assert_eq!(map.read_u32(0x301C), 0x0F55_5555);
+}
+
+#[test]
+fn sse_float_arithmetic() {
+    // addps xmm0, xmm1; addsd xmm2, xmm3; sqrtss xmm4, xmm4
+    let code = [
+        0x0F, 0x58, 0xC1, 0xF2, 0x0F, 0x58, 0xD3, 0xF3, 0x0F, 0x51, 0xE4,
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        set_xmm(cpu, 0, singles([1.0, 2.0, 3.0, 4.0]));
+        set_xmm(cpu, 1, singles([0.5, 0.25, -3.0, 1e30]));
+        set_xmm(cpu, 2, doubles([1.5, 7.0]));
+        set_xmm(cpu, 3, doubles([2.25, 100.0]));
+        set_xmm(cpu, 4, singles([2.0, 5.0, 6.0, 7.0]));
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(xmm(&cpu, 0), singles([1.5, 2.25, 0.0, 1e30]));
+    assert_eq!(xmm(&cpu, 2), doubles([3.75, 7.0]), "scalar upper half kept");
+    assert_eq!(xmm(&cpu, 4), singles([2f32.sqrt(), 5.0, 6.0, 7.0]));
+    assert_eq!(cpu.regs.fpu.mxcsr, 0x1F80 | 0x20, "inexact accumulates");
+}
+
+#[test]
+fn sse_conversions_follow_mxcsr_rounding() {
+    // ldmxcsr [0x3000]; cvtsd2si eax, xmm0; cvttsd2si ecx, xmm0; cvtsi2sd xmm1, rdx;
+    // stmxcsr [0x3004]
+    let code = [
+        0x0F, 0xAE, 0x14, 0x25, 0x00, 0x30, 0x00, 0x00, 0xF2, 0x0F, 0x2D, 0xC0, 0xF2, 0x0F, 0x2C,
+        0xC8, 0xF2, 0x48, 0x0F, 0x2A, 0xCA, 0x0F, 0xAE, 0x1C, 0x25, 0x04, 0x30, 0x00, 0x00,
+    ];
+    let round_down = 0x1F80u32 | 1 << 13;
+    let (cpu, map) = run_with_data(&code, &[(0x3000, &round_down.to_le_bytes())], |cpu| {
+        set_xmm(cpu, 0, doubles([-1.5, 0.0]));
+        set_xmm(cpu, 1, doubles([0.0, 9.0]));
+        cpu.regs.gprs[2] = -7i64 as u64;
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[0], -2i32 as u32 as u64);
+    assert_eq!(cpu.regs.gprs[1], -1i32 as u32 as u64);
+    assert_eq!(xmm(&cpu, 1), doubles([-7.0, 9.0]));
+    assert_eq!(map.read_u32(0x3004), round_down | 0x20);
+
+    // ldmxcsr [0x3000] with a reserved bit set
+    let (cpu, _) = run_with_data(&code[..8], &[(0x3000, &[0, 0, 1, 0])], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+}
+
+#[test]
+fn conversions_to_integers_read_memory_sources() {
+    // cvttsd2si eax, qword [rbx]; vcvtss2si rcx, dword [rbx + 8];
+    // vcvttsd2usi rdx, qword [rbx]; vcvtss2usi esi, dword [rbx + 8]
+    let code = [
+        0xF2, 0x0F, 0x2C, 0x03, 0xC4, 0xE1, 0xFA, 0x2D, 0x4B, 0x08, 0x62, 0xF1, 0xFF, 0x08, 0x78,
+        0x13, 0x62, 0xF1, 0x7E, 0x08, 0x79, 0x73, 0x02,
+    ];
+    let mut data = 3e9f64.to_le_bytes().to_vec();
+    data.extend_from_slice(&7.5f32.to_le_bytes());
+    let (cpu, _) = run_with_data(&code, &[(0x3000, &data)], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        cpu.regs.gprs[3] = 0x3000;
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(
+        cpu.regs.gprs[0], 0x8000_0000,
+        "out of range, so the integer indefinite"
+    );
+    assert_eq!(cpu.regs.gprs[1], 8);
+    assert_eq!(cpu.regs.gprs[2], 3_000_000_000);
+    assert_eq!(cpu.regs.gprs[6], 8);
+    assert_eq!(cpu.regs.fpu.mxcsr, 0x1F80 | 0x21);
+}
+
+#[test]
+fn sse_denormals_and_flush_to_zero() {
+    // mulss xmm0, xmm1
+    let tiny = f32::MIN_POSITIVE;
+    let setup = |mxcsr: u32| {
+        move |cpu: &mut Amd64Interp| {
+            cpu.regs.fpu.mxcsr = mxcsr;
+            set_xmm(cpu, 0, singles([tiny, 0.0, 0.0, 0.0]));
+            set_xmm(cpu, 1, singles([0.5, 0.0, 0.0, 0.0]));
+        }
+    };
+    let (cpu, _) = run(&[0xF3, 0x0F, 0x59, 0xC1], setup(0x1F80));
+    assert_eq!(xmm(&cpu, 0) as u32, (tiny / 2.0).to_bits());
+    assert_eq!(
+        cpu.regs.fpu.mxcsr & 0x3F,
+        0x00,
+        "exact denormal results don't underflow"
+    );
+    let (cpu, _) = run(&[0xF3, 0x0F, 0x59, 0xC1], setup(0x1F80 | 1 << 15));
+    assert_eq!(xmm(&cpu, 0) as u32, 0);
+    assert_eq!(
+        cpu.regs.fpu.mxcsr & 0x3F,
+        0x30,
+        "flushing is underflow and inexact"
+    );
+    // mulss xmm0, xmm0 with a denormal operand, with and without DAZ
+    let (cpu, _) = run(&[0xF3, 0x0F, 0x59, 0xC0], |cpu| {
+        set_xmm(cpu, 0, f32::from_bits(1).to_bits() as u128)
+    });
+    assert_eq!(cpu.regs.fpu.mxcsr & 0x3F, 0x32);
+    let (cpu, _) = run(&[0xF3, 0x0F, 0x59, 0xC0], |cpu| {
+        cpu.regs.fpu.mxcsr |= 1 << 6;
+        set_xmm(cpu, 0, f32::from_bits(1).to_bits() as u128)
+    });
+    assert_eq!(cpu.regs.fpu.mxcsr & 0x3F, 0);
+    assert_eq!(xmm(&cpu, 0), 0);
+}
+
+#[test]
+fn sse_unmasked_exceptions_fault_without_writing() {
+    // divss xmm0, xmm1 with divide-by-zero unmasked
+    let setup = |cpu: &mut Amd64Interp| {
+        cpu.regs.fpu.mxcsr = 0x1F80 & !(1 << 9);
+        set_xmm(cpu, 0, singles([1.0, 0.0, 0.0, 0.0]));
+    };
+    let (cpu, _) = run(&[0xF3, 0x0F, 0x5E, 0xC1], setup);
+    assert_eq!(cpu.fault(), Some(Exception::SimdFloatingPoint));
+    assert_eq!(xmm(&cpu, 0), singles([1.0, 0.0, 0.0, 0.0]));
+    assert_eq!(cpu.regs.fpu.mxcsr & 0x3F, 0x04);
+    assert_eq!(cpu.regs.rip, CODE_BASE);
+    let (cpu, _) = run(&[0xF3, 0x0F, 0x5E, 0xC1], |cpu| {
+        setup(cpu);
+        cpu.regs.cr[4] &= !(1 << 10);
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn sse_alignment_and_availability() {
+    // movaps xmm0, [0x3008]
+    let (cpu, _) = run(&[0x0F, 0x28, 0x04, 0x25, 0x08, 0x30, 0x00, 0x00], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+    // movups xmm0, [0x3008]
+    let (cpu, _) = run_with_data(
+        &[0x0F, 0x10, 0x04, 0x25, 0x08, 0x30, 0x00, 0x00],
+        &[(0x3008, &[0xAB; 16])],
+        |_| {},
+    );
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(xmm(&cpu, 0), u128::from_le_bytes([0xAB; 16]));
+    // addps xmm0, xmm1
+    let (cpu, _) = run(&[0x0F, 0x58, 0xC1], |cpu| cpu.regs.cr[4] &= !(1 << 9));
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    let (cpu, _) = run(&[0x0F, 0x58, 0xC1], |cpu| cpu.regs.cr[0] |= 1 << 3);
+    assert_eq!(cpu.fault(), Some(Exception::DeviceNotAvailable));
+    // addpd xmm0, xmm1 needs SSE2, pminub mm0, mm1 only SSE
+    let (cpu, _) = run(&[0x66, 0x0F, 0x58, 0xC1], |cpu| {
+        cpu.model = CpuModel::x86_64_v1().without(Feature::Sse2);
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    let (cpu, _) = run(&[0x0F, 0xDA, 0xC1], |cpu| {
+        cpu.model = CpuModel::x86_64_v1().without(Feature::Sse2);
+    });
+    assert_eq!(cpu.fault(), None);
+}
+
+#[test]
+fn sse_scalar_moves_and_shuffles() {
+    // movss xmm0, [0x3000]; movss xmm1, xmm0; pshufd xmm2, xmm3, 0x1b; comisd xmm4, xmm5
+    let code = [
+        0xF3, 0x0F, 0x10, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00, 0xF3, 0x0F, 0x10, 0xC8, 0x66, 0x0F,
+        0x70, 0xD3, 0x1B, 0x66, 0x0F, 0x2F, 0xE5,
+    ];
+    let (cpu, _) = run_with_data(&code, &[(0x3000, &2.5f32.to_le_bytes())], |cpu| {
+        set_xmm(cpu, 0, u128::MAX);
+        set_xmm(cpu, 1, u128::MAX);
+        set_xmm(cpu, 3, 0x0000_0004_0000_0003_0000_0002_0000_0001);
+        set_xmm(cpu, 4, doubles([1.0, 0.0]));
+        set_xmm(cpu, 5, doubles([2.0, 0.0]));
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(
+        xmm(&cpu, 0),
+        2.5f32.to_bits() as u128,
+        "loads zero the rest"
+    );
+    assert_eq!(xmm(&cpu, 1), !0xFFFF_FFFF | 2.5f32.to_bits() as u128);
+    assert_eq!(xmm(&cpu, 2), 0x0000_0001_0000_0002_0000_0003_0000_0004);
+    assert_eq!(cpu.regs.rflags & flags::STATUS, flags::CF);
+
+    // comisd xmm4, xmm5 with a NaN
+    let (cpu, _) = run(&[0x66, 0x0F, 0x2F, 0xE5], |cpu| {
+        set_xmm(cpu, 5, doubles([f64::NAN, 0.0]))
+    });
+    assert_eq!(
+        cpu.regs.rflags & flags::STATUS,
+        flags::ZF | flags::PF | flags::CF
+    );
+    assert_eq!(cpu.regs.fpu.mxcsr & 1, 1, "comisd signals on quiet NaNs");
+}
+
+#[test]
+fn fxsave_and_fxrstor_round_trip() {
+    // fld1; fxsave [0x4000]; pxor xmm5, xmm5; fninit; fxrstor [0x4000]
+    let code = [
+        0xD9, 0xE8, 0x0F, 0xAE, 0x04, 0x25, 0x00, 0x40, 0x00, 0x00, 0x66, 0x0F, 0xEF, 0xED, 0xDB,
+        0xE3, 0x0F, 0xAE, 0x0C, 0x25, 0x00, 0x40, 0x00, 0x00,
+    ];
+    let (cpu, map) = run(&code, |cpu| {
+        cpu.regs.fpu.mxcsr = 0x1F80 | 1 << 15;
+        set_xmm(cpu, 5, 0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210);
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(map.read_u32(0x4018), 0x1F80 | 1 << 15, "MXCSR");
+    assert_eq!(map.read_u32(0x401C), 0xFFFF, "MXCSR_MASK");
+    assert_eq!(map.read_u8(0x4004), 0x80, "abridged tag word");
+    assert_eq!(map.read_u64(0x4020), 0x8000_0000_0000_0000, "ST(0)");
+    assert_eq!(map.read_u64(0x4000 + 160 + 5 * 16), 0xFEDC_BA98_7654_3210);
+    assert_eq!(xmm(&cpu, 5), 0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210);
+    assert_eq!(cpu.regs.fpu.fsw >> 11 & 7, 7, "TOP");
+    assert_eq!(cpu.regs.fpu.ftw, 0x3FFF);
+
+    // fxsave [0x4008]
+    let (cpu, _) = run(&[0x0F, 0xAE, 0x04, 0x25, 0x08, 0x40, 0x00, 0x00], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+}
+
+#[test]
+fn xsave_and_xrstor_round_trip() {
+    // xsave [0x4000]; vzeroall; xrstor [0x4000]
+    let code = [
+        0x0F, 0xAE, 0x24, 0x25, 0x00, 0x40, 0x00, 0x00, 0xC5, 0xFC, 0x77, 0x0F, 0xAE, 0x2C, 0x25,
+        0x00, 0x40, 0x00, 0x00,
+    ];
+    let (cpu, map) = run(&code, |cpu| {
+        cpu.regs.gprs[0] = 0b111;
+        set_ymm_dwords(cpu, 1, [1, 2, 3, 4, 5, 6, 7, 8]);
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(map.read_u64(0x4200), 0b111, "XSTATE_BV");
+    assert_eq!(map.read_u64(0x4208), 0, "XCOMP_BV");
+    assert_eq!(map.read_u32(0x4000 + 160 + 16), 1);
+    assert_eq!(map.read_u32(0x4000 + 576 + 16), 5);
+    assert_eq!(ymm_dwords(&cpu, 1), [1, 2, 3, 4, 5, 6, 7, 8]);
+
+    // xsavec [0x4000] of SSE and AVX alone, then xrstor [0x4000] of x87 as well, which
+    // initializes the FPU since the compacted area doesn't hold it
+    let code = [
+        0x0F, 0xC7, 0x24, 0x25, 0x00, 0x40, 0x00, 0x00, 0xC5, 0xFC, 0x77, 0xB0, 0x07, 0x0F, 0xAE,
+        0x2C, 0x25, 0x00, 0x40, 0x00, 0x00,
+    ];
+    let (cpu, map) = run(&code, |cpu| {
+        cpu.model = CpuModel::skylake();
+        cpu.regs.gprs[0] = 0b110;
+        cpu.regs.fpu.fcw = 0x27F;
+        set_ymm_dwords(cpu, 1, [1, 2, 3, 4, 5, 6, 7, 8]);
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(map.read_u64(0x4200), 0b110, "XSTATE_BV");
+    assert_eq!(map.read_u64(0x4208), 1 << 63 | 0b110, "XCOMP_BV");
+    assert_eq!(map.read_u32(0x4000 + 576 + 16), 5);
+    assert_eq!(ymm_dwords(&cpu, 1), [1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(cpu.regs.fpu.fcw, 0x37F);
+
+    // Without XSAVEC, a misaligned area, and without CR4.OSXSAVE
+    let (cpu, _) = run(&code[..8], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    let (cpu, _) = run(&[0x0F, 0xAE, 0x24, 0x25, 0x10, 0x40, 0x00, 0x00], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+    let (cpu, _) = run(&[0x0F, 0xAE, 0x24, 0x25, 0x00, 0x40, 0x00, 0x00], |cpu| {
+        cpu.regs.cr[4] &= !(1 << 18)
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn ssse3_shuffles_and_blends() {
+    // pshufb xmm0, xmm1; pblendvb xmm2, xmm3, xmm0
+    let code = [0x66, 0x0F, 0x38, 0x00, 0xC1, 0x66, 0x0F, 0x38, 0x10, 0xD3];
+    let (cpu, _) = run(&code, |cpu| {
+        set_xmm(cpu, 0, 0x8F8E_8D8C_8B8A_8988_8786_8584_8382_8180);
+        // Reverse the bytes, zeroing every fourth one
+        set_xmm(cpu, 1, 0x0001_0203_8405_0607_8809_0A0B_8C0D_0E0F);
+        set_xmm(cpu, 2, 0);
+        set_xmm(cpu, 3, u128::MAX);
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(xmm(&cpu, 0), 0x8081_8283_0085_8687_0089_8A8B_008D_8E8F);
+    assert_eq!(xmm(&cpu, 2), 0xFFFF_FFFF_00FF_FFFF_00FF_FFFF_00FF_FFFF);
+
+    // pshufb mm0, mm1 needs SSSE3
+    let (cpu, _) = run(&[0x0F, 0x38, 0x00, 0xC1], |cpu| {
+        cpu.model = CpuModel::x86_64_v1();
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn sse41_round_ptest_and_extend() {
+    // roundsd xmm0, xmm1, 0x01; roundps xmm2, xmm3, 0x0a; ptest xmm4, xmm5;
+    // pmovzxbw xmm6, xmm7
+    let code = [
+        0x66, 0x0F, 0x3A, 0x0B, 0xC1, 0x01, 0x66, 0x0F, 0x3A, 0x08, 0xD3, 0x0A, 0x66, 0x0F, 0x38,
+        0x17, 0xE5, 0x66, 0x0F, 0x38, 0x30, 0xF7,
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        set_xmm(cpu, 0, doubles([0.0, 8.0]));
+        set_xmm(cpu, 1, doubles([-2.5, 1.0]));
+        set_xmm(cpu, 3, singles([1.25, -1.25, 2.0, -0.5]));
+        set_xmm(cpu, 4, 0xF0);
+        set_xmm(cpu, 5, 0x0F);
+        set_xmm(cpu, 7, 0x80FF_0102);
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(xmm(&cpu, 0), doubles([-3.0, 8.0]));
+    assert_eq!(xmm(&cpu, 2), singles([2.0, -1.0, 2.0, -0.0]));
+    assert_eq!(cpu.regs.fpu.mxcsr & 0x3F, 0x20, "only ROUNDSD was inexact");
+    assert_eq!(cpu.regs.rflags & flags::STATUS, flags::ZF);
+    assert_eq!(xmm(&cpu, 6), 0x0080_00FF_0001_0002);
+}
+
+#[test]
+fn sse41_inserts_and_extracts() {
+    // pextrb eax, xmm0, 5; pinsrd xmm1, ecx, 2; pextrq rdx, xmm1, 1; insertps xmm2, xmm3, 0x98
+    let code = [
+        0x66, 0x0F, 0x3A, 0x14, 0xC0, 0x05, 0x66, 0x0F, 0x3A, 0x22, 0xC9, 0x02, 0x66, 0x48, 0x0F,
+        0x3A, 0x16, 0xCA, 0x01, 0x66, 0x0F, 0x3A, 0x21, 0xD3, 0x98,
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.regs.gprs[0] = u64::MAX;
+        cpu.regs.gprs[1] = 0x1234_5678;
+        set_xmm(cpu, 0, 0xAB << 40);
+        set_xmm(cpu, 1, u128::MAX);
+        set_xmm(cpu, 2, 0x4444_4444_3333_3333_2222_2222_1111_1111);
+        set_xmm(cpu, 3, 0xDDDD_DDDD_CCCC_CCCC_BBBB_BBBB_AAAA_AAAA);
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[0], 0xAB);
+    assert_eq!(cpu.regs.gprs[2], 0xFFFF_FFFF_1234_5678);
+    assert_eq!(xmm(&cpu, 2), 0x3333_3333_CCCC_CCCC_1111_1111);
+}
+
+#[test]
+fn sse42_string_compare_and_crc32() {
+    // pxor xmm0, xmm0; pcmpistri xmm0, [0x3003], 0x08
+    let code = [
+        0x66, 0x0F, 0xEF, 0xC0, 0x66, 0x0F, 0x3A, 0x63, 0x04, 0x25, 0x03, 0x30, 0x00, 0x00, 0x08,
+    ];
+    let (cpu, _) = run_with_data(&code, &[(0x3003, b"strlen\0")], |_| {});
+    assert_eq!(cpu.fault(), None, "no alignment requirement");
+    assert_eq!(cpu.regs.gprs[1], 6);
+    assert_eq!(
+        cpu.regs.rflags & flags::STATUS,
+        flags::CF | flags::ZF | flags::SF
+    );
+
+    // crc32 rax, qword ptr [rsi]; crc32 eax, byte ptr [rsi+8]; not eax
+    let code = [
+        0xF2, 0x48, 0x0F, 0x38, 0xF1, 0x06, 0xF2, 0x0F, 0x38, 0xF0, 0x46, 0x08, 0xF7, 0xD0,
+    ];
+    let (cpu, _) = run_with_data(&code, &[(0x3000, b"123456789")], |cpu| {
+        cpu.regs.gprs[0] = 0xFFFF_FFFF;
+        cpu.regs.gprs[6] = 0x3000;
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[0], 0xE306_9283, "the CRC-32C check value");
+    let (cpu, _) = run(&code[6..12], |cpu| cpu.model = CpuModel::x86_64_v1());
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+/// A whole YMM register as doublewords, element 0 first.
+fn ymm_dwords(cpu: &Amd64Interp, n: usize) -> [u32; 8] {
+    zmm_dwords(cpu, n)[..8].try_into().unwrap()
+}
+
+fn set_ymm_dwords(cpu: &mut Amd64Interp, n: usize, values: [u32; 8]) {
+    bytemuck::bytes_of_mut(&mut cpu.regs.zmm[n])[..32].copy_from_slice(bytemuck::bytes_of(&values));
+}
+
+/// A whole ZMM register as doublewords, element 0 first.
+fn zmm_dwords(cpu: &Amd64Interp, n: usize) -> [u32; 16] {
+    bytemuck::cast(cpu.regs.zmm[n])
+}
+
+fn set_zmm_dwords(cpu: &mut Amd64Interp, n: usize, values: [u32; 16]) {
+    cpu.regs.zmm[n] = bytemuck::cast(values);
+}
+
+/// The doublewords of a ZMM register holding `values` in its low elements, and zeros above.
+fn zmm_from<T: bytemuck::Pod>(values: &[T]) -> [u32; 16] {
+    let mut bytes = [0u8; 64];
+    let values: &[u8] = bytemuck::cast_slice(values);
+    bytes[..values.len()].copy_from_slice(values);
+    bytemuck::cast(bytes)
+}
+
+#[test]
+fn avx_128_bit_forms_clear_the_upper_half() {
+    // addps xmm0, xmm1; vaddps xmm2, xmm2, xmm1; vaddss xmm3, xmm4, xmm1
+    let code = [
+        0x0F, 0x58, 0xC1, 0xC5, 0xE8, 0x58, 0xD1, 0xC5, 0xDA, 0x58, 0xD9,
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        for n in 0..5 {
+            set_ymm_dwords(cpu, n, [0xAAAA_AAAA; 8]);
+        }
+        set_xmm(cpu, 0, singles([1.0, 2.0, 3.0, 4.0]));
+        set_xmm(cpu, 1, singles([1.0; 4]));
+        set_xmm(cpu, 2, singles([1.0, 2.0, 3.0, 4.0]));
+        set_xmm(cpu, 4, singles([5.0, 6.0, 7.0, 8.0]));
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(xmm(&cpu, 0), singles([2.0, 3.0, 4.0, 5.0]));
+    assert_eq!(ymm_dwords(&cpu, 0)[4..], [0xAAAA_AAAA; 4]);
+    assert_eq!(xmm(&cpu, 2), singles([2.0, 3.0, 4.0, 5.0]));
+    assert_eq!(ymm_dwords(&cpu, 2)[4..], [0; 4]);
+    // The rest of the low half comes from the VEX.vvvv source
+    assert_eq!(xmm(&cpu, 3), singles([6.0, 6.0, 7.0, 8.0]));
+    assert_eq!(ymm_dwords(&cpu, 3)[4..], [0; 4]);
+
+    // vzeroupper; vzeroall
+    let (cpu, _) = run(&[0xC5, 0xF8, 0x77], |cpu| set_ymm_dwords(cpu, 9, [7; 8]));
+    assert_eq!(ymm_dwords(&cpu, 9), [7, 7, 7, 7, 0, 0, 0, 0]);
+    let (cpu, _) = run(&[0xC5, 0xFC, 0x77], |cpu| set_ymm_dwords(cpu, 9, [7; 8]));
+    assert_eq!(ymm_dwords(&cpu, 9), [0; 8]);
+}
+
+#[test]
+fn avx_256_bit_arithmetic_and_availability() {
+    // vmovups ymm0, [0x3000]; vaddps ymm1, ymm0, ymm0; vmovups [0x3020], ymm1
+    let code = [
+        0xC5, 0xFC, 0x10, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00, 0xC5, 0xFC, 0x58, 0xC8, 0xC5, 0xFC,
+        0x11, 0x0C, 0x25, 0x20, 0x30, 0x00, 0x00,
+    ];
+    let data: Vec<u8> = (1..=8).flat_map(|i| (i as f32).to_le_bytes()).collect();
+    let (cpu, map) = run_with_data(&code, &[(0x3000, &data)], |_| {});
+    assert_eq!(cpu.fault(), None);
+    let doubled: Vec<u8> = (1..=8)
+        .flat_map(|i| (2.0 * i as f32).to_le_bytes())
+        .collect();
+    assert_eq!(bytemuck::bytes_of(&map.read_ymmword(0x3020)), &doubled[..]);
+    assert_eq!(bytemuck::bytes_of(&map.read_ymmword(0x3000)), &data[..]);
+
+    // vaddps ymm1, ymm0, ymm0 without AVX, or with XSAVE not enabled
+    let (cpu, _) = run(&code[9..13], |cpu| cpu.model = CpuModel::x86_64_v2());
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    let (cpu, _) = run(&code[9..13], |cpu| cpu.regs.cr[4] &= !(1 << 18));
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    // vpaddd ymm0, ymm1, ymm2 needs AVX2, but vpaddd xmm0, xmm1, xmm2 only AVX
+    let (cpu, _) = run(&[0xC5, 0xF5, 0xFE, 0xC2], |cpu| {
+        cpu.model = CpuModel::x86_64_v3().without(Feature::Avx2);
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    let (cpu, _) = run(&[0xC5, 0xF1, 0xFE, 0xC2], |cpu| {
+        cpu.model = CpuModel::x86_64_v3().without(Feature::Avx2);
+    });
+    assert_eq!(cpu.fault(), None);
+    // vmovaps ymm0, [0x3010] must be 32-byte aligned, vaddps ymm1, ymm0, [0x3010] needn't be
+    let (cpu, _) = run(
+        &[0xC5, 0xFC, 0x28, 0x04, 0x25, 0x10, 0x30, 0x00, 0x00],
+        |_| {},
+    );
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+    let (cpu, _) = run(
+        &[0xC5, 0xFC, 0x58, 0x0C, 0x25, 0x10, 0x30, 0x00, 0x00],
+        |_| {},
+    );
+    assert_eq!(cpu.fault(), None);
+}
+
+#[test]
+fn avx2_permutes_broadcasts_and_gathers() {
+    // vpermd ymm0, ymm1, ymm2; vperm2f128 ymm3, ymm1, ymm2, 0x21;
+    // vpgatherdd ymm4, [rax+ymm5*4], ymm6; vpbroadcastd ymm7, xmm2
+    let code = [
+        0xC4, 0xE2, 0x75, 0x36, 0xC2, 0xC4, 0xE3, 0x75, 0x06, 0xDA, 0x21, 0xC4, 0xE2, 0x4D, 0x90,
+        0x24, 0xA8, 0xC4, 0xE2, 0x7D, 0x58, 0xFA,
+    ];
+    let table: Vec<u8> = (0..8u32).flat_map(|i| (i * 100).to_le_bytes()).collect();
+    let (cpu, _) = run_with_data(&code, &[(0x3000, &table)], |cpu| {
+        cpu.regs.gprs[0] = 0x3000;
+        set_ymm_dwords(cpu, 1, [7, 6, 5, 4, 3, 2, 1, 0]);
+        set_ymm_dwords(cpu, 2, [10, 11, 12, 13, 14, 15, 16, 17]);
+        set_ymm_dwords(cpu, 4, [0xFFFF_FFFF; 8]);
+        set_ymm_dwords(cpu, 5, [0, 2, 4, 6, 1, 3, 5, 7]);
+        let mut mask = [0x8000_0000; 8];
+        mask[3] = 0;
+        set_ymm_dwords(cpu, 6, mask);
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(ymm_dwords(&cpu, 0), [17, 16, 15, 14, 13, 12, 11, 10]);
+    assert_eq!(ymm_dwords(&cpu, 3), [3, 2, 1, 0, 10, 11, 12, 13]);
+    // The masked-off element keeps its value, and the mask is consumed
+    assert_eq!(
+        ymm_dwords(&cpu, 4),
+        [0, 200, 400, 0xFFFF_FFFF, 100, 300, 500, 700]
+    );
+    assert_eq!(ymm_dwords(&cpu, 6), [0; 8]);
+    assert_eq!(ymm_dwords(&cpu, 7), [10; 8]);
+
+    // The gather's destination can't also be its index
+    let (cpu, _) = run(&[0xC4, 0xE2, 0x4D, 0x90, 0x2C, 0xA8], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn fma_and_f16c() {
+    // vfmadd231ss xmm0, xmm1, xmm2; vcvtps2ph xmm3, xmm0, 0x0
+    let code = [
+        0xC4, 0xE2, 0x71, 0xB9, 0xC2, 0xC4, 0xE3, 0x79, 0x1D, 0xC3, 0x00,
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        set_ymm_dwords(cpu, 0, [0xAAAA_AAAA; 8]);
+        set_xmm(cpu, 0, singles([10.0, 20.0, 30.0, 40.0]));
+        set_xmm(cpu, 1, singles([2.0, 5.0, 5.0, 5.0]));
+        set_xmm(cpu, 2, singles([3.0, 7.0, 7.0, 7.0]));
+    });
+    assert_eq!(cpu.fault(), None);
+    // The rest of the low 128 bits are the destination's
+    assert_eq!(xmm(&cpu, 0), singles([16.0, 20.0, 30.0, 40.0]));
+    assert_eq!(ymm_dwords(&cpu, 0)[4..], [0; 4]);
+    assert_eq!(xmm(&cpu, 3), 0x5100_4F80_4D00_4C00);
+
+    let (cpu, _) = run(&code[..5], |cpu| {
+        cpu.model = CpuModel::x86_64_v3().without(Feature::Fma);
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    let (cpu, _) = run(&code[5..], |cpu| {
+        cpu.model = CpuModel::x86_64_v3().without(Feature::F16c);
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn avx512_masking_broadcasts_and_opmasks() {
+    // kmovw k1, eax; vpaddd zmm0{k1}, zmm1, dword ptr [0x3000]{1to16};
+    // vpaddd zmm2{k1}{z}, zmm1, zmm1; vpcmpgtd k2, zmm1, zmm2; kortestw k1, k2;
+    // kmovw ecx, k2
+    let code = [
+        0xC5, 0xF8, 0x92, 0xC8, 0x62, 0xF1, 0x75, 0x59, 0xFE, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00,
+        0x62, 0xF1, 0x75, 0xC9, 0xFE, 0xD1, 0x62, 0xF1, 0x75, 0x48, 0x66, 0xD2, 0xC5, 0xF8, 0x98,
+        0xCA, 0xC5, 0xF8, 0x93, 0xCA,
+    ];
+    let (cpu, _) = run_with_data(&code, &[(0x3000, &100u32.to_le_bytes())], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        cpu.regs.gprs[0] = 0x00FF;
+        set_zmm_dwords(cpu, 0, [0xAAAA_AAAA; 16]);
+        set_zmm_dwords(cpu, 1, std::array::from_fn(|i| i as u32));
+        set_zmm_dwords(cpu, 2, [0xAAAA_AAAA; 16]);
+    });
+    assert_eq!(cpu.fault(), None);
+    let merged: [u32; 16] =
+        std::array::from_fn(|i| if i < 8 { i as u32 + 100 } else { 0xAAAA_AAAA });
+    assert_eq!(zmm_dwords(&cpu, 0), merged);
+    let zeroed: [u32; 16] = std::array::from_fn(|i| if i < 8 { 2 * i as u32 } else { 0 });
+    assert_eq!(zmm_dwords(&cpu, 2), zeroed);
+    assert_eq!(cpu.regs.k[2], 0xFF00);
+    assert_eq!(cpu.regs.gprs[1], 0xFF00);
+    assert_eq!(cpu.regs.rflags & flags::STATUS, flags::CF);
+
+    // vpaddd ymm0, ymm1, ymm2 needs AVX512VL as well as AVX512F, vpaddd zmm0, zmm1, zmm2
+    // only AVX512F
+    let (cpu, _) = run(&[0x62, 0xF1, 0x75, 0x28, 0xFE, 0xC2], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4().without(Feature::Avx512vl));
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    let (cpu, _) = run(&[0x62, 0xF1, 0x75, 0x48, 0xFE, 0xC2], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4().without(Feature::Avx512vl));
+    });
+    assert_eq!(cpu.fault(), None);
+    let (cpu, _) = run(&[0x62, 0xF1, 0x75, 0x48, 0xFE, 0xC2], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn avx512_opmask_arithmetic_shifts_and_tests() {
+    // kmovw k1, eax; kmovw k2, ecx; kaddw k3, k1, k2; kunpckbw k4, k1, k2;
+    // kshiftrw k5, k1, 0x4; kshiftlq k6, k1, 0x40; ktestw k1, k2; kmovw edx, k3;
+    // kunpckdq k7, k1, k2; kshiftlb k1, k1, 0x1
+    let code = [
+        0xC5, 0xF8, 0x92, 0xC8, 0xC5, 0xF8, 0x92, 0xD1, 0xC5, 0xF4, 0x4A, 0xDA, 0xC5, 0xF5, 0x4B,
+        0xE2, 0xC4, 0xE3, 0xF9, 0x30, 0xE9, 0x04, 0xC4, 0xE3, 0xF9, 0x33, 0xF1, 0x40, 0xC5, 0xF8,
+        0x99, 0xCA, 0xC5, 0xF8, 0x93, 0xD3, 0xC4, 0xE1, 0xF4, 0x4B, 0xFA, 0xC4, 0xE3, 0x79, 0x32,
+        0xC9, 0x01,
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        cpu.regs.gprs[0] = 0x12F0;
+        cpu.regs.gprs[1] = 0x0210;
+        cpu.regs.k[6] = u64::MAX;
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.k[3], 0x1500);
+    assert_eq!(cpu.regs.gprs[2], 0x1500);
+    assert_eq!(cpu.regs.k[4], 0xF010);
+    assert_eq!(cpu.regs.k[5], 0x12F);
+    // Shifting by the width or more clears the destination
+    assert_eq!(cpu.regs.k[6], 0);
+    assert_eq!(cpu.regs.k[7], 0x12F0_0000_0210);
+    // A byte result clears the rest of the register
+    assert_eq!(cpu.regs.k[1], 0xE0);
+    // KTEST: the AND of the two isn't zero, but k2 has no bits k1 lacks
+    assert_eq!(cpu.regs.rflags & flags::STATUS, flags::CF);
+
+    // KADDW came with AVX512DQ, and KSHIFTRW with AVX512F
+    let (cpu, _) = run(&code[8..12], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4().without(Feature::Avx512dq));
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    let (cpu, _) = run(&code[16..22], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4().without(Feature::Avx512dq));
+    });
+    assert_eq!(cpu.fault(), None);
+}
+
+#[test]
+fn avx512_broadcasts_tests_and_mask_moves() {
+    // vpbroadcastb zmm0, esi; vptestnmb k1, zmm0, zmm1; vptestmd k2, zmm1, zmm1;
+    // vpmovm2d zmm2, k2; vpmovb2m k3, zmm3; vpbroadcastmw2d zmm9, k2;
+    // vbroadcasti32x4 zmm4{k2}{z}, xmmword ptr [0x3000];
+    // vbroadcasti64x4 zmm5, ymmword ptr [0x3000]; vinserti64x2 zmm6, zmm6, xmm7, 0x3;
+    // vextracti32x8 ymm8, zmm5, 0x1; vpbroadcastq zmm10, rsi
+    let code = [
+        0x62, 0xF2, 0x7D, 0x48, 0x7A, 0xC6, 0x62, 0xF2, 0x7E, 0x48, 0x26, 0xC9, 0x62, 0xF2, 0x75,
+        0x48, 0x27, 0xD1, 0x62, 0xF2, 0x7E, 0x48, 0x38, 0xD2, 0x62, 0xF2, 0x7E, 0x48, 0x29, 0xDB,
+        0x62, 0x72, 0x7E, 0x48, 0x3A, 0xCA, 0x62, 0xF2, 0x7D, 0xCA, 0x5A, 0x24, 0x25, 0x00, 0x30,
+        0x00, 0x00, 0x62, 0xF2, 0xFD, 0x48, 0x5B, 0x2C, 0x25, 0x00, 0x30, 0x00, 0x00, 0x62, 0xF3,
+        0xCD, 0x48, 0x38, 0xF7, 0x03, 0x62, 0xD3, 0x7D, 0x48, 0x3B, 0xE8, 0x01, 0x62, 0x72, 0xFD,
+        0x48, 0x7C, 0xD6,
+    ];
+    let data: [u32; 8] = std::array::from_fn(|i| i as u32 + 1);
+    let (cpu, _) = run_with_data(&code, &[(0x3000, bytemuck::bytes_of(&data))], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        cpu.regs.gprs[6] = 0xFFFF_FFFF_1234_56AB;
+        set_zmm_dwords(cpu, 1, std::array::from_fn(|i| (i < 8) as u32));
+        set_zmm_dwords(cpu, 3, [0x8000_0080; 16]);
+        set_zmm_dwords(cpu, 4, [0xAAAA_AAAA; 16]);
+        set_zmm_dwords(cpu, 6, [0xAAAA_AAAA; 16]);
+        set_zmm_dwords(cpu, 7, std::array::from_fn(|i| i as u32 + 100));
+        set_zmm_dwords(cpu, 8, [0xAAAA_AAAA; 16]);
+    });
+    assert_eq!(cpu.fault(), None);
+    // Only the low byte of the general-purpose register is broadcast
+    assert_eq!(zmm_dwords(&cpu, 0), [0xABAB_ABAB; 16]);
+    assert_eq!(cpu.regs.k[1], 0xFFFF_FFFF_EEEE_EEEE);
+    assert_eq!(cpu.regs.k[2], 0x00FF);
+    let expanded: [u32; 16] = std::array::from_fn(|i| if i < 8 { u32::MAX } else { 0 });
+    assert_eq!(zmm_dwords(&cpu, 2), expanded);
+    assert_eq!(cpu.regs.k[3], 0x9999_9999_9999_9999);
+    assert_eq!(zmm_dwords(&cpu, 9), [0xFF; 16]);
+    let tiled: [u32; 16] = std::array::from_fn(|i| if i < 8 { i as u32 % 4 + 1 } else { 0 });
+    assert_eq!(zmm_dwords(&cpu, 4), tiled);
+    let tiled: [u32; 16] = std::array::from_fn(|i| i as u32 % 8 + 1);
+    assert_eq!(zmm_dwords(&cpu, 5), tiled);
+    let inserted: [u32; 16] = std::array::from_fn(|i| {
+        if i >= 12 {
+            i as u32 - 12 + 100
+        } else {
+            0xAAAA_AAAA
+        }
+    });
+    assert_eq!(zmm_dwords(&cpu, 6), inserted);
+    let extracted: [u32; 16] = std::array::from_fn(|i| if i < 8 { i as u32 + 1 } else { 0 });
+    assert_eq!(zmm_dwords(&cpu, 8), extracted);
+    let quadwords: [u32; 16] =
+        std::array::from_fn(|i| if i % 2 == 0 { 0x1234_56AB } else { u32::MAX });
+    assert_eq!(zmm_dwords(&cpu, 10), quadwords);
+
+    // VPBROADCASTB came with AVX512BW, VPMOVM2D with AVX512DQ and VPBROADCASTMW2D with
+    // AVX512CD
+    for (range, feature) in [
+        (0..6, Feature::Avx512bw),
+        (18..24, Feature::Avx512dq),
+        (30..36, Feature::Avx512cd),
+    ] {
+        let (cpu, _) = run(&code[range.clone()], |cpu| {
+            use_model(cpu, CpuModel::x86_64_v4().without(feature));
+        });
+        assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+        let (cpu, _) = run(&code[range], |cpu| use_model(cpu, CpuModel::x86_64_v4()));
+        assert_eq!(cpu.fault(), None);
+    }
+}
+
+#[test]
+fn avx512_narrowing_moves_compresses_and_two_table_permutes() {
+    // vpmovqb xmm1, zmm2; vpmovsdw ymm3, zmm4; vpmovusqd ymm5, zmm6;
+    // vpmovwb xmmword ptr [rax]{k1}, ymm7; vpcompressd zmm8{k1}{z}, zmm9;
+    // vpcompressd zmmword ptr [rax+0x40]{k1}, zmm9;
+    // vpexpandd zmm10{k1}, zmmword ptr [rax+0x80]; vpermt2d zmm11, zmm12, zmm13;
+    // vpermi2q zmm14, zmm15, zmm16; vpermw zmm17, zmm18, zmm19
+    let code = [
+        0x62, 0xF2, 0x7E, 0x48, 0x32, 0xD1, 0x62, 0xF2, 0x7E, 0x48, 0x23, 0xE3, 0x62, 0xF2, 0x7E,
+        0x48, 0x15, 0xF5, 0x62, 0xF2, 0x7E, 0x29, 0x30, 0x38, 0x62, 0x52, 0x7D, 0xC9, 0x8B, 0xC8,
+        0x62, 0x72, 0x7D, 0x49, 0x8B, 0x48, 0x10, 0x62, 0x72, 0x7D, 0x49, 0x89, 0x50, 0x20, 0x62,
+        0x52, 0x1D, 0x48, 0x7E, 0xDD, 0x62, 0x32, 0x85, 0x48, 0x76, 0xF0, 0x62, 0xA2, 0xED, 0x40,
+        0x8D, 0xCB,
+    ];
+    let mask = 0x8421u64;
+    let selected = |i: usize| mask >> i & 1 != 0;
+    let qwords =
+        |f: fn(usize) -> u64| -> [u32; 16] { bytemuck::cast(std::array::from_fn::<u64, 8, _>(f)) };
+    // Only the four doublewords the expand reads follow the filler
+    let mut data = vec![0xEE; 0x80];
+    data.extend([1u32, 2, 3, 4].iter().flat_map(|x| x.to_le_bytes()));
+    let (cpu, map) = run_with_data(&code, &[(0x3000, &data)], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        cpu.regs.gprs[0] = 0x3000;
+        cpu.regs.k[1] = mask;
+        set_zmm_dwords(cpu, 2, qwords(|i| 0x1234_5678_9ABC_DE00 | i as u64));
+        let saturating = [70000, -70000, 5, -5];
+        set_zmm_dwords(cpu, 4, std::array::from_fn(|i| saturating[i % 4] as u32));
+        set_zmm_dwords(cpu, 6, qwords(|i| if i % 2 == 0 { u64::MAX } else { 7 }));
+        cpu.regs.zmm[7] = bytemuck::cast(std::array::from_fn::<u16, 32, _>(|i| {
+            0x100 | (6 * i as u16)
+        }));
+        set_zmm_dwords(cpu, 8, [0xAAAA_AAAA; 16]);
+        set_zmm_dwords(cpu, 9, std::array::from_fn(|i| 0x100 + i as u32));
+        set_zmm_dwords(cpu, 10, [0xAAAA_AAAA; 16]);
+        set_zmm_dwords(cpu, 11, std::array::from_fn(|i| 1000 + i as u32));
+        set_zmm_dwords(
+            cpu,
+            12,
+            std::array::from_fn(|i| 0x100 | ((3 * i as u32) % 32)),
+        );
+        set_zmm_dwords(cpu, 13, std::array::from_fn(|i| 2000 + i as u32));
+        set_zmm_dwords(cpu, 14, qwords(|i| (5 * i as u64) % 16));
+        set_zmm_dwords(cpu, 15, qwords(|i| 100 + i as u64));
+        set_zmm_dwords(cpu, 16, qwords(|i| 200 + i as u64));
+        cpu.regs.zmm[18] = bytemuck::cast(std::array::from_fn::<u16, 32, _>(|i| 31 - i as u16));
+        cpu.regs.zmm[19] = bytemuck::cast(std::array::from_fn::<u16, 32, _>(|i| 0x100 + i as u16));
+    });
+    assert_eq!(cpu.fault(), None);
+    // Truncated, then saturated as signed and as unsigned values
+    let truncated: [u32; 16] = std::array::from_fn(|i| match i {
+        0 => 0x0302_0100,
+        1 => 0x0706_0504,
+        _ => 0,
+    });
+    assert_eq!(zmm_dwords(&cpu, 1), truncated);
+    let signed: [u32; 16] = std::array::from_fn(|i| match i {
+        8.. => 0,
+        _ if i % 2 == 0 => 0x8000_7FFF,
+        _ => 0xFFFB_0005,
+    });
+    assert_eq!(zmm_dwords(&cpu, 3), signed);
+    let unsigned: [u32; 16] = std::array::from_fn(|i| match i {
+        8.. => 0,
+        _ if i % 2 == 0 => u32::MAX,
+        _ => 7,
+    });
+    assert_eq!(zmm_dwords(&cpu, 5), unsigned);
+    // Only the selected bytes and doublewords are stored
+    let mut stored = [0u8; 0x90];
+    map.read_bytes(0x3000, &mut stored);
+    let bytes: [u8; 16] = std::array::from_fn(|i| if selected(i) { 6 * i as u8 } else { 0xEE });
+    assert_eq!(stored[..16], bytes);
+    let packed = [0x100u32, 0x105, 0x10A, 0x10F];
+    let compressed: Vec<u8> = packed.iter().flat_map(|x| x.to_le_bytes()).collect();
+    assert_eq!(stored[0x40..0x50], compressed[..]);
+    assert_eq!(stored[0x50..0x80], [0xEE; 0x30]);
+    let zeroed: [u32; 16] = std::array::from_fn(|i| packed.get(i).copied().unwrap_or(0));
+    assert_eq!(zmm_dwords(&cpu, 8), zeroed);
+    let expanded: [u32; 16] = std::array::from_fn(|i| {
+        if selected(i) {
+            i as u32 / 5 + 1
+        } else {
+            0xAAAA_AAAA
+        }
+    });
+    assert_eq!(zmm_dwords(&cpu, 10), expanded);
+    // The bit above the index picks the table
+    let permuted: [u32; 16] = std::array::from_fn(|i| match (3 * i as u32) % 32 {
+        index @ ..16 => 1000 + index,
+        index => 2000 + index - 16,
+    });
+    assert_eq!(zmm_dwords(&cpu, 11), permuted);
+    let permuted = qwords(|i| match (5 * i as u64) % 16 {
+        index @ ..8 => 100 + index,
+        index => 200 + index - 8,
+    });
+    assert_eq!(zmm_dwords(&cpu, 14), permuted);
+    let words: [u16; 32] = bytemuck::cast(cpu.regs.zmm[17]);
+    assert_eq!(words, std::array::from_fn(|i| 0x100 + 31 - i as u16));
+
+    // Narrowing quadwords came with AVX512F, and words, like VPERMW, with AVX512BW
+    let (cpu, _) = run(&code[..6], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4().without(Feature::Avx512bw));
+    });
+    assert_eq!(cpu.fault(), None);
+    let (cpu, _) = run(&code[56..], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4().without(Feature::Avx512bw));
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn avx512_rotates_aligns_and_shuffles() {
+    // vprold zmm20, zmm21, 0x8; vprorvq zmm22, zmm23, zmm24; valignd zmm25, zmm26, zmm27, 0x3;
+    // vshufi64x2 zmm28, zmm29, zmm30, 0x1b; vpsllvw zmm29, zmm31, zmm0;
+    // vpsravw zmm30, zmm31, zmm0; vdbpsadbw zmm0, zmm1, zmm2, 0xe4;
+    // vpblendmb zmm3{k1}, zmm4, zmm5; vpshufhw zmm6, zmm7, 0x1b
+    let code = [
+        0x62, 0xB1, 0x5D, 0x40, 0x72, 0xCD, 0x08, 0x62, 0x82, 0xC5, 0x40, 0x14, 0xF0, 0x62, 0x03,
+        0x2D, 0x40, 0x03, 0xCB, 0x03, 0x62, 0x03, 0x95, 0x40, 0x43, 0xE6, 0x1B, 0x62, 0x62, 0x85,
+        0x40, 0x12, 0xE8, 0x62, 0x62, 0x85, 0x40, 0x11, 0xF0, 0x62, 0xF3, 0x75, 0x48, 0x42, 0xC2,
+        0xE4, 0x62, 0xF2, 0x5D, 0x49, 0x66, 0xDD, 0x62, 0xF1, 0x7E, 0x48, 0x70, 0xF7, 0x1B,
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        cpu.regs.k[1] = 0x5555_5555_5555_5555;
+        cpu.regs.zmm[0] = bytemuck::cast(std::array::from_fn::<u16, 32, _>(|i| i as u16));
+        set_zmm_dwords(
+            cpu,
+            1,
+            [0x0A0A_0A0A, 0x1414_1414].repeat(8).try_into().unwrap(),
+        );
+        cpu.regs.zmm[2] = bytemuck::cast(std::array::from_fn::<u8, 64, _>(|i| i as u8 % 8));
+        set_zmm_dwords(cpu, 4, [0x4444_4444; 16]);
+        set_zmm_dwords(cpu, 5, [0x5555_5555; 16]);
+        cpu.regs.zmm[7] = bytemuck::cast(std::array::from_fn::<u16, 32, _>(|i| i as u16));
+        set_zmm_dwords(cpu, 21, std::array::from_fn(|i| 0x8000_0001 + i as u32));
+        set_zmm_dwords(cpu, 23, bytemuck::cast([0x0123_4567_89AB_CDEFu64; 8]));
+        let counts: [u64; 8] = std::array::from_fn(|i| 9 * i as u64 + 64);
+        set_zmm_dwords(cpu, 24, bytemuck::cast(counts));
+        set_zmm_dwords(cpu, 26, std::array::from_fn(|i| 100 + i as u32));
+        set_zmm_dwords(cpu, 27, std::array::from_fn(|i| 200 + i as u32));
+        set_zmm_dwords(cpu, 29, std::array::from_fn(|i| 300 + i as u32));
+        set_zmm_dwords(cpu, 30, std::array::from_fn(|i| 400 + i as u32));
+        cpu.regs.zmm[31] = bytemuck::cast([0x8001u16; 32]);
+    });
+    assert_eq!(cpu.fault(), None);
+    let rotated: [u32; 16] = std::array::from_fn(|i| (0x8000_0001 + i as u32).rotate_left(8));
+    assert_eq!(zmm_dwords(&cpu, 20), rotated);
+    // Counts are modulo the element width
+    let rotated: [u64; 8] =
+        std::array::from_fn(|i| 0x0123_4567_89AB_CDEFu64.rotate_right(9 * i as u32));
+    assert_eq!(
+        zmm_dwords(&cpu, 22),
+        bytemuck::cast::<_, [u32; 16]>(rotated)
+    );
+    let aligned: [u32; 16] = std::array::from_fn(|i| {
+        if i < 13 {
+            203 + i as u32
+        } else {
+            100 + i as u32 - 13
+        }
+    });
+    assert_eq!(zmm_dwords(&cpu, 25), aligned);
+    let blocks = [312, 308, 404, 400];
+    let shuffled: [u32; 16] = std::array::from_fn(|i| blocks[i / 4] + i as u32 % 4);
+    assert_eq!(zmm_dwords(&cpu, 28), shuffled);
+    let words: [u16; 32] = bytemuck::cast(cpu.regs.zmm[29]);
+    assert_eq!(
+        words,
+        std::array::from_fn(|i| if i < 16 { 0x8001 << i } else { 0 })
+    );
+    let words: [u16; 32] = bytemuck::cast(cpu.regs.zmm[30]);
+    assert_eq!(
+        words,
+        std::array::from_fn(|i| (0x8001u16 as i16 >> i.min(15)) as u16)
+    );
+    let words: [u16; 32] = bytemuck::cast(cpu.regs.zmm[0]);
+    assert_eq!(words, std::array::from_fn(|i| [34, 30, 66, 62][i % 4]));
+    assert_eq!(zmm_dwords(&cpu, 3), [0x4455_4455; 16]);
+    let words: [u16; 32] = bytemuck::cast(cpu.regs.zmm[6]);
+    let shuffled: [u16; 32] = std::array::from_fn(|i| match i % 8 {
+        ..4 => i as u16,
+        j => (i - j) as u16 + 11 - j as u16,
+    });
+    assert_eq!(words, shuffled);
+
+    // vshuff32x4 ymm1, ymm2, ymm3, 0x2: a selector bit for each half, and AVX512VL
+    let code = [0x62, 0xF3, 0x6D, 0x28, 0x23, 0xCB, 0x02];
+    let (cpu, _) = run(&code, |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        set_zmm_dwords(cpu, 1, [0xAAAA_AAAA; 16]);
+        set_ymm_dwords(cpu, 2, std::array::from_fn(|i| 10 + i as u32));
+        set_ymm_dwords(cpu, 3, std::array::from_fn(|i| 20 + i as u32));
+    });
+    assert_eq!(cpu.fault(), None);
+    let shuffled: [u32; 16] = std::array::from_fn(|i| match i {
+        ..4 => 10 + i as u32,
+        4..8 => 20 + i as u32,
+        _ => 0,
+    });
+    assert_eq!(zmm_dwords(&cpu, 1), shuffled);
+    let (cpu, _) = run(&code, |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4().without(Feature::Avx512vl));
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn avx512_unsigned_and_quadword_conversions() {
+    // vcvtps2udq zmm1, zmm2; vcvttpd2udq ymm3, zmm4; vcvtudq2ps zmm5{k1}, zmm6;
+    // vcvtudq2pd zmm7, ymm8; vcvtqq2pd zmm9, zmm10; vcvttpd2qq zmm11, zmm12;
+    // vcvtps2uqq zmm13, ymm14; vcvtuqq2ps ymm15, zmm16; vcvtusi2sd xmm17, xmm18, rax;
+    // vcvttsd2usi rbx, xmm19; vcvtss2usi ecx, xmm20; vcvtps2udq zmm21, zmm22, {rd-sae}
+    let code = [
+        0x62, 0xF1, 0x7C, 0x48, 0x79, 0xCA, 0x62, 0xF1, 0xFC, 0x48, 0x78, 0xDC, 0x62, 0xF1, 0x7F,
+        0x49, 0x7A, 0xEE, 0x62, 0xD1, 0x7E, 0x48, 0x7A, 0xF8, 0x62, 0x51, 0xFE, 0x48, 0xE6, 0xCA,
+        0x62, 0x51, 0xFD, 0x48, 0x7A, 0xDC, 0x62, 0x51, 0x7D, 0x48, 0x79, 0xEE, 0x62, 0x31, 0xFF,
+        0x48, 0x7A, 0xF8, 0x62, 0xE1, 0xEF, 0x00, 0x7B, 0xC8, 0x62, 0xB1, 0xFF, 0x08, 0x78, 0xDB,
+        0x62, 0xB1, 0x7E, 0x08, 0x79, 0xCC, 0x62, 0xA1, 0x7C, 0x38, 0x79, 0xEE,
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        cpu.regs.gprs[0] = u64::MAX;
+        cpu.regs.gprs[1] = u64::MAX;
+        cpu.regs.k[1] = 0b11;
+        set_zmm_dwords(cpu, 2, zmm_from(&[3e9f32, -1.0, 2.5, -0.25]));
+        set_zmm_dwords(cpu, 4, zmm_from(&[4294967295.9f64, 1.5]));
+        set_zmm_dwords(cpu, 5, [0x1111_1111; 16]);
+        set_zmm_dwords(cpu, 6, zmm_from(&[0x0000_0007_FFFF_FFFFu64]));
+        set_zmm_dwords(cpu, 8, zmm_from(&[0x0000_0001_8000_0000u64]));
+        set_zmm_dwords(cpu, 10, zmm_from(&[-3i64 as u64, (1 << 53) + 1]));
+        set_zmm_dwords(cpu, 12, zmm_from(&[-2.9f64, 1e19]));
+        set_zmm_dwords(cpu, 14, zmm_from(&[1e10f32]));
+        set_zmm_dwords(cpu, 16, zmm_from(&[u64::MAX, 5]));
+        set_zmm_dwords(cpu, 18, zmm_from(&[0.0f64, 42.0, 7.0]));
+        set_zmm_dwords(cpu, 19, zmm_from(&[1.8e19f64]));
+        set_zmm_dwords(cpu, 20, zmm_from(&[-1.0f32]));
+        set_zmm_dwords(cpu, 22, zmm_from(&[3.5f32, 0.5]));
+    });
+    assert_eq!(cpu.fault(), None);
+    // Negative values that don't round to zero become all ones
+    assert_eq!(
+        zmm_dwords(&cpu, 1),
+        zmm_from(&[3_000_000_000u32, u32::MAX, 2])
+    );
+    assert_eq!(zmm_dwords(&cpu, 3), zmm_from(&[u32::MAX, 1]));
+    let mut expected = [0x1111_1111; 16];
+    expected[..2].copy_from_slice(&[4294967296.0f32.to_bits(), 7.0f32.to_bits()]);
+    assert_eq!(zmm_dwords(&cpu, 5), expected);
+    assert_eq!(zmm_dwords(&cpu, 7), zmm_from(&[2147483648.0f64, 1.0]));
+    assert_eq!(
+        zmm_dwords(&cpu, 9),
+        zmm_from(&[-3.0f64, 9007199254740992.0])
+    );
+    assert_eq!(zmm_dwords(&cpu, 11), zmm_from(&[-2i64 as u64, 1 << 63]));
+    assert_eq!(zmm_dwords(&cpu, 13), zmm_from(&[10_000_000_000u64]));
+    assert_eq!(
+        zmm_dwords(&cpu, 15),
+        zmm_from(&[18446744073709551616.0f32, 5.0])
+    );
+    assert_eq!(
+        zmm_dwords(&cpu, 17),
+        zmm_from(&[18446744073709551616.0f64, 42.0])
+    );
+    assert_eq!(cpu.regs.gprs[3], 18_000_000_000_000_000_000);
+    assert_eq!(cpu.regs.gprs[1], u32::MAX as u64);
+    assert_eq!(zmm_dwords(&cpu, 21), zmm_from(&[3u32, 0]));
+    // Invalid and precision, but nothing from the conversion with exceptions suppressed
+    assert_eq!(cpu.regs.fpu.mxcsr, 0x1F80 | 0x21);
+
+    // vcvtqq2pd zmm9, zmm10 came with AVX512DQ
+    let (cpu, _) = run(&[0x62, 0x51, 0xFE, 0x48, 0xE6, 0xCA], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4().without(Feature::Avx512dq));
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn avx512_rounding_scaling_fix_ups_and_classes() {
+    // vrndscaleps zmm1, zmm2, 0x11; vrndscalesd xmm3, xmm4, xmm5, 0xb; vgetexpps zmm6, zmm7;
+    // vgetmantpd zmm8, zmm9, 0x5; vscalefpd zmm10, zmm11, zmm12;
+    // vfixupimmps zmm13, zmm14, zmm15, 0x0; vrcp14ps zmm16, zmm17; vrsqrt14pd zmm18, zmm19;
+    // vreduceps zmm20, zmm21, 0x10; vrangeps zmm22, zmm23, zmm24, 0x5;
+    // vrangesd xmm25, xmm26, xmm27, 0xa; vfpclassps k1, zmm28, 0xa4; vfpclasssd k2, xmm29, 0x40
+    let code = [
+        0x62, 0xF3, 0x7D, 0x48, 0x08, 0xCA, 0x11, 0x62, 0xF3, 0xDD, 0x08, 0x0B, 0xDD, 0x0B, 0x62,
+        0xF2, 0x7D, 0x48, 0x42, 0xF7, 0x62, 0x53, 0xFD, 0x48, 0x26, 0xC1, 0x05, 0x62, 0x52, 0xA5,
+        0x48, 0x2C, 0xD4, 0x62, 0x53, 0x0D, 0x48, 0x54, 0xEF, 0x00, 0x62, 0xA2, 0x7D, 0x48, 0x4C,
+        0xC1, 0x62, 0xA2, 0xFD, 0x48, 0x4E, 0xD3, 0x62, 0xA3, 0x7D, 0x48, 0x56, 0xE5, 0x10, 0x62,
+        0x83, 0x45, 0x40, 0x50, 0xF0, 0x05, 0x62, 0x03, 0xAD, 0x00, 0x51, 0xCB, 0x0A, 0x62, 0x93,
+        0x7D, 0x48, 0x66, 0xCC, 0xA4, 0x62, 0x93, 0xFD, 0x08, 0x67, 0xD5, 0x40,
+    ];
+    let denormal = f32::from_bits(1);
+    let (cpu, _) = run(&code, |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        set_zmm_dwords(cpu, 2, zmm_from(&[1.3f32, -1.3, 2.75, f32::INFINITY]));
+        set_zmm_dwords(cpu, 4, zmm_from(&[0.0f64, 9.0]));
+        set_zmm_dwords(cpu, 5, zmm_from(&[-2.7f64]));
+        set_zmm_dwords(
+            cpu,
+            7,
+            zmm_from(&[8.0f32, 0.75, 0.0, f32::NEG_INFINITY, denormal]),
+        );
+        set_zmm_dwords(cpu, 9, zmm_from(&[-12.0f64, 20.0]));
+        set_zmm_dwords(cpu, 11, zmm_from(&[3.0f64, 1.0, 0.0, 5.0]));
+        let powers = [2.7f64, -1.5, f64::INFINITY, f64::NEG_INFINITY];
+        set_zmm_dwords(cpu, 12, zmm_from(&powers));
+        set_zmm_dwords(cpu, 13, [0x1111_1111; 16]);
+        let values = [f32::NAN, 0.0, 1.0, f32::NEG_INFINITY, -5.0, 5.0];
+        set_zmm_dwords(cpu, 14, zmm_from(&values));
+        set_zmm_dwords(cpu, 15, [0x010E_DA08; 16]);
+        set_zmm_dwords(cpu, 17, zmm_from(&[4.0f32]));
+        set_zmm_dwords(cpu, 19, zmm_from(&[4.0f64, -1.0]));
+        set_zmm_dwords(cpu, 21, zmm_from(&[1.3f32, f32::INFINITY, 2.25]));
+        set_zmm_dwords(cpu, 23, zmm_from(&[-0.0f32, 3.0]));
+        set_zmm_dwords(cpu, 24, zmm_from(&[0.0f32, -4.0]));
+        set_zmm_dwords(cpu, 26, zmm_from(&[-3.0f64, 6.0]));
+        set_zmm_dwords(cpu, 27, zmm_from(&[4.0f64]));
+        let classes = [f32::NAN, f32::from_bits(0x7F80_0001), 1.0, -denormal, -0.0];
+        set_zmm_dwords(cpu, 28, zmm_from(&classes));
+        set_zmm_dwords(cpu, 29, zmm_from(&[-2.0f64]));
+    });
+    assert_eq!(cpu.fault(), None);
+    // Rounded down to halves, and towards zero
+    let rounded = [1.0f32, -1.5, 2.5, f32::INFINITY];
+    assert_eq!(zmm_dwords(&cpu, 1), zmm_from(&rounded));
+    assert_eq!(zmm_dwords(&cpu, 3), zmm_from(&[-2.0f64, 9.0]));
+    // The elements left zero take part too
+    let filled = |start: &[f32], rest: f32| {
+        let mut values = [rest; 16];
+        values[..start.len()].copy_from_slice(start);
+        zmm_from(&values)
+    };
+    let exponents = [3.0, -1.0, f32::NEG_INFINITY, f32::INFINITY, -149.0];
+    assert_eq!(zmm_dwords(&cpu, 6), filled(&exponents, f32::NEG_INFINITY));
+    let mut mantissas = [1.0f64; 8];
+    mantissas[..2].copy_from_slice(&[0.75, 1.25]);
+    assert_eq!(zmm_dwords(&cpu, 8), zmm_from(&mantissas));
+    let default_nan = f64::from_bits(0xFFF8_0000_0000_0000);
+    assert_eq!(
+        zmm_dwords(&cpu, 10),
+        zmm_from(&[12.0f64, 0.25, default_nan, 0.0])
+    );
+    let mut fixed = filled(
+        &[0.0, 1.0, std::f32::consts::FRAC_PI_2, f32::MAX, -5.0],
+        1.0,
+    );
+    fixed[5] = 0x1111_1111;
+    assert_eq!(zmm_dwords(&cpu, 13), fixed);
+    assert_eq!(zmm_dwords(&cpu, 16), filled(&[0.25], f32::INFINITY));
+    let mut roots = [f64::INFINITY; 8];
+    roots[..2].copy_from_slice(&[0.5, default_nan]);
+    assert_eq!(zmm_dwords(&cpu, 18), zmm_from(&roots));
+    assert_eq!(zmm_dwords(&cpu, 20), zmm_from(&[1.3f32 - 1.5, 0.0, 0.25]));
+    assert_eq!(zmm_dwords(&cpu, 22), zmm_from(&[0.0f32, 3.0]));
+    assert_eq!(zmm_dwords(&cpu, 25), zmm_from(&[3.0f64, 6.0]));
+    assert_eq!(cpu.regs.k[1], 0b11010);
+    assert_eq!(cpu.regs.k[2], 1);
+    // Invalid from VSCALEFPD, denormal from VGETEXPPS and precision from VRNDSCALEPS
+    assert_eq!(cpu.regs.fpu.mxcsr, 0x1F80 | 0x23);
+
+    // vrangeps zmm22, zmm23, zmm24, 0x5 came with AVX512DQ
+    let (cpu, _) = run(&code[59..66], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4().without(Feature::Avx512dq));
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn avx512_gathers_scatters_conflicts_and_leading_zeros() {
+    // vpgatherdd zmm1{k1}, [rax+zmm2*4]; vpgatherqd ymm3{k2}, [rax+zmm20*8];
+    // vpscatterdd [rax+zmm4*4+0x100]{k3}, zmm5; vpconflictd zmm7, zmm8;
+    // vplzcntq zmm9{k5}{z}, zmm10
+    let code = [
+        0x62, 0xF2, 0x7D, 0x49, 0x90, 0x0C, 0x90, 0x62, 0xF2, 0x7D, 0x42, 0x91, 0x1C, 0xE0, 0x62,
+        0xF2, 0x7D, 0x4B, 0xA0, 0x6C, 0xA0, 0x40, 0x62, 0xD2, 0x7D, 0x48, 0xC4, 0xF8, 0x62, 0x52,
+        0xFD, 0xCD, 0x44, 0xCA,
+    ];
+    let table: Vec<u8> = (0..0x60u32).flat_map(|i| (100 + i).to_le_bytes()).collect();
+    let (cpu, map) = run_with_data(&code, &[(0x3000, &table)], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        cpu.regs.gprs[0] = 0x3000;
+        cpu.regs.k[1] = 0x5555;
+        cpu.regs.k[2] = 0xFF;
+        cpu.regs.k[3] = 0xFFFF;
+        cpu.regs.k[5] = 0x0F;
+        set_zmm_dwords(cpu, 1, [0xAAAA_AAAA; 16]);
+        set_zmm_dwords(cpu, 2, std::array::from_fn(|i| 2 * i as u32));
+        set_zmm_dwords(cpu, 3, [0xBBBB_BBBB; 16]);
+        set_zmm_dwords(
+            cpu,
+            20,
+            zmm_from(&std::array::from_fn::<u64, 8, _>(|i| 7 - i as u64)),
+        );
+        set_zmm_dwords(cpu, 4, std::array::from_fn(|i| i as u32 / 2));
+        set_zmm_dwords(cpu, 5, std::array::from_fn(|i| 1000 + i as u32));
+        set_zmm_dwords(cpu, 8, std::array::from_fn(|i| i as u32 % 3));
+        set_zmm_dwords(
+            cpu,
+            10,
+            zmm_from(&std::array::from_fn::<u64, 8, _>(|i| 1 << (8 * i))),
+        );
+    });
+    assert_eq!(cpu.fault(), None);
+    // The elements the opmask leaves out keep their values, and the opmask is consumed
+    let gathered: [u32; 16] = std::array::from_fn(|i| {
+        if i % 2 == 0 {
+            100 + 2 * i as u32
+        } else {
+            0xAAAA_AAAA
+        }
+    });
+    assert_eq!(zmm_dwords(&cpu, 1), gathered);
+    assert_eq!(cpu.regs.k[1], 0);
+    // Quadword indices gather half a vector
+    let gathered: [u32; 16] = std::array::from_fn(|i| match i {
+        ..8 => 114 - 2 * i as u32,
+        _ => 0,
+    });
+    assert_eq!(zmm_dwords(&cpu, 3), gathered);
+    assert_eq!(cpu.regs.k[2], 0);
+    // Of the elements scattered to the same address, the last one stays
+    let mut scattered = [0u8; 0x40];
+    map.read_bytes(0x3100, &mut scattered);
+    let scattered: [u32; 16] = bytemuck::cast(scattered);
+    let expected: [u32; 16] = std::array::from_fn(|i| match i {
+        ..8 => 1001 + 2 * i as u32,
+        _ => 164 + i as u32,
+    });
+    assert_eq!(scattered, expected);
+    assert_eq!(cpu.regs.k[3], 0);
+    let conflicts: [u32; 16] = std::array::from_fn(|i| {
+        (0..i)
+            .filter(|j| j % 3 == i % 3)
+            .fold(0, |conflicts, j| conflicts | 1 << j)
+    });
+    assert_eq!(zmm_dwords(&cpu, 7), conflicts);
+    let zeros = zmm_from(&std::array::from_fn::<u64, 8, _>(|i| match i {
+        ..4 => 63 - 8 * i as u64,
+        _ => 0,
+    }));
+    assert_eq!(zmm_dwords(&cpu, 9), zeros);
+
+    // A gather's destination can't also be its index: vpgatherdd zmm2{k1}, [rax+zmm2*4]
+    let (cpu, _) = run(&[0x62, 0xF2, 0x7D, 0x49, 0x90, 0x14, 0x90], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        cpu.regs.k[1] = 1;
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    // VPCONFLICT and VPLZCNT came with AVX512CD
+    let (cpu, _) = run(&code[22..28], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4().without(Feature::Avx512cd));
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn avx512_rounding_override_and_masked_stores() {
+    // vaddps zmm4, zmm5, zmm6, {ru-sae}
+    let (cpu, _) = run(&[0x62, 0xF1, 0x54, 0x58, 0x58, 0xE6], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        set_zmm_dwords(cpu, 5, [1.0f32.to_bits(); 16]);
+        set_zmm_dwords(cpu, 6, [(1.0f32 / (1 << 30) as f32).to_bits(); 16]);
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(zmm_dwords(&cpu, 4), [0x3F80_0001; 16]);
+    assert_eq!(cpu.regs.fpu.mxcsr, 0x1F80, "no exception recorded");
+
+    // kmovw k1, eax; vmovdqu32 zmmword ptr [0x3000]{k1}, zmm0;
+    // vmovdqu32 zmm3{k1}{z}, zmmword ptr [0x3040]
+    let code = [
+        0xC5, 0xF8, 0x92, 0xC8, 0x62, 0xF1, 0x7E, 0x49, 0x7F, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00,
+        0x62, 0xF1, 0x7E, 0xC9, 0x6F, 0x1C, 0x25, 0x40, 0x30, 0x00, 0x00,
+    ];
+    let (cpu, map) = run_with_data(&code, &[(0x3000, &[0xEE; 128])], |cpu| {
+        use_model(cpu, CpuModel::x86_64_v4());
+        cpu.regs.gprs[0] = 0b0101;
+        set_zmm_dwords(cpu, 0, [0x1234_5678; 16]);
+        set_zmm_dwords(cpu, 3, [0xAAAA_AAAA; 16]);
+    });
+    assert_eq!(cpu.fault(), None);
+    let mut stored = [0u8; 64];
+    map.read_bytes(0x3000, &mut stored);
+    let stored: [u32; 16] = bytemuck::cast(stored);
+    let expected: [u32; 16] = std::array::from_fn(|i| match i {
+        0 | 2 => 0x1234_5678,
+        _ => 0xEEEE_EEEE,
+    });
+    assert_eq!(stored, expected);
+    let loaded: [u32; 16] = std::array::from_fn(|i| match i {
+        0 | 2 => 0xEEEE_EEEE,
+        _ => 0,
+    });
+    assert_eq!(zmm_dwords(&cpu, 3), loaded);
+}
+
+#[test]
+fn avx512_masked_off_elements_raise_no_exceptions() {
+    // kmovw k1, eax; vaddps zmm0{k1}, zmm1, zmm2; vcvtps2dq zmm3{k1}, zmm2
+    let code = [
+        0xC5, 0xF8, 0x92, 0xC8, 0x62, 0xF1, 0x74, 0x49, 0x58, 0xC2, 0x62, 0xF1, 0x7D, 0x49, 0x5B,
+        0xDA,
+    ];
+    // Every element but the first overflows the addition and the conversion
+    let mut first = [f32::MAX.to_bits(); 16];
+    let mut second = first;
+    first[0] = 1.0f32.to_bits();
+    second[0] = 2.0f32.to_bits();
+    let run_masked = |mask: u64| {
+        run(&code, |cpu| {
+            use_model(cpu, CpuModel::x86_64_v4());
+            cpu.regs.gprs[0] = mask;
+            set_zmm_dwords(cpu, 1, first);
+            set_zmm_dwords(cpu, 2, second);
+            set_zmm_dwords(cpu, 3, [0xAAAA_AAAA; 16]);
+        })
+    };
+    let (cpu, _) = run_masked(1);
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.fpu.mxcsr, 0x1F80);
+    assert_eq!(zmm_dwords(&cpu, 0)[0], 3.0f32.to_bits());
+    let converted: [u32; 16] = std::array::from_fn(|i| if i == 0 { 2 } else { 0xAAAA_AAAA });
+    assert_eq!(zmm_dwords(&cpu, 3), converted);
+    // Invalid, overflow and precision once they're selected
+    let (cpu, _) = run_masked(0xFFFF);
+    assert_eq!(cpu.regs.fpu.mxcsr, 0x1F80 | 0x29);
+
+    // kmovw k1, eax; vcmpgess k2{k1}, xmm1, xmm3: a signaling NaN in a masked-off element
+    let code = [
+        0xC5, 0xF8, 0x92, 0xC8, 0x62, 0xF1, 0x76, 0x09, 0xC2, 0xD3, 0x0D,
+    ];
+    for mask in [0, 1] {
+        let (cpu, _) = run(&code, |cpu| {
+            use_model(cpu, CpuModel::x86_64_v4());
+            cpu.regs.gprs[0] = mask;
+            set_zmm_dwords(cpu, 3, [0x7F80_0001; 16]);
+        });
+        assert_eq!(cpu.fault(), None);
+        assert_eq!(cpu.regs.k[2], 0);
+        assert_eq!(cpu.regs.fpu.mxcsr, 0x1F80 | mask as u32);
+    }
+}
+
+#[test]
+fn aes_pclmulqdq_and_sha() {
+    // aesenc xmm0, xmm1; aeskeygenassist xmm2, xmm3, 0x1; aesimc xmm4, xmm5;
+    // pclmulqdq xmm6, xmm7, 0x11
+    let code = [
+        0x66, 0x0F, 0x38, 0xDC, 0xC1, 0x66, 0x0F, 0x3A, 0xDF, 0xD3, 0x01, 0x66, 0x0F, 0x38, 0xDB,
+        0xE5, 0x66, 0x0F, 0x3A, 0x44, 0xF7, 0x11,
+    ];
+    let bytes = |hex: [u8; 16]| u128::from_le_bytes(hex);
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.model = CpuModel::skylake();
+        // The state at the start of the first round of the FIPS-197 appendix B example, and
+        // the key of the first round
+        set_xmm(
+            cpu,
+            0,
+            bytes([
+                0x19, 0x3D, 0xE3, 0xBE, 0xA0, 0xF4, 0xE2, 0x2B, 0x9A, 0xC6, 0x8D, 0x2A, 0xE9, 0xF8,
+                0x48, 0x08,
+            ]),
+        );
+        set_xmm(
+            cpu,
+            1,
+            bytes([
+                0xA0, 0xFA, 0xFE, 0x17, 0x88, 0x54, 0x2C, 0xB1, 0x23, 0xA3, 0x39, 0x39, 0x2A, 0x6C,
+                0x76, 0x05,
+            ]),
+        );
+        // Its cipher key
+        set_xmm(
+            cpu,
+            3,
+            bytes([
+                0x2B, 0x7E, 0x15, 0x16, 0x28, 0xAE, 0xD2, 0xA6, 0xAB, 0xF7, 0x15, 0x88, 0x09, 0xCF,
+                0x4F, 0x3C,
+            ]),
+        );
+        set_xmm(
+            cpu,
+            5,
+            u128::from_le_bytes([0x8E, 0x4D, 0xA1, 0xBC].repeat(4).try_into().unwrap()),
+        );
+        set_xmm(cpu, 6, 0x8000_0000_0000_0001 << 64 | 0xFFFF);
+        set_xmm(cpu, 7, 3 << 64 | 0xFFFF);
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(
+        xmm(&cpu, 0),
+        bytes([
+            0xA4, 0x9C, 0x7F, 0xF2, 0x68, 0x9F, 0x35, 0x2B, 0x6B, 0x5B, 0xEA, 0x43, 0x02, 0x6A,
+            0x50, 0x49,
+        ])
+    );
+    // The high doubleword is the one the key expansion XORs into the first word of the next
+    // round key
+    assert_eq!(xmm(&cpu, 2), 0x01EB_848B_EB84_8A01_3424_B5E5_24B5_E434);
+    assert_eq!(
+        xmm(&cpu, 4),
+        u128::from_le_bytes([0xDB, 0x13, 0x53, 0x45].repeat(4).try_into().unwrap())
+    );
+    assert_eq!(xmm(&cpu, 6), 0x1_8000_0000_0000_0003);
+
+    // sha256rnds2 xmm1, xmm2; sha1rnds4 xmm3, xmm4, 0x0: the first rounds of hashing "abc"
+    // from the FIPS 180 examples
+    let code = [0x0F, 0x38, 0xCB, 0xCA, 0x0F, 0x3A, 0xCC, 0xDC, 0x00];
+    let dwords = |d: [u32; 4]| d.iter().rev().fold(0, |x, &d| x << 32 | d as u128);
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.model = CpuModel::skylake().with(Feature::Sha);
+        set_xmm(cpu, 0, dwords([0xA3EC_9318, 0x7137_4491, 0, 0]));
+        set_xmm(
+            cpu,
+            1,
+            dwords([0x5BE0_CD19, 0x1F83_D9AB, 0xA54F_F53A, 0x3C6E_F372]),
+        );
+        set_xmm(
+            cpu,
+            2,
+            dwords([0x9B05_688C, 0x510E_527F, 0xBB67_AE85, 0x6A09_E667]),
+        );
+        set_xmm(
+            cpu,
+            3,
+            dwords([0x1032_5476, 0x98BA_DCFE, 0xEFCD_AB89, 0x6745_2301]),
+        );
+        // W0 with E added
+        set_xmm(cpu, 4, dwords([0, 0, 0, 0x2535_4570]));
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(
+        xmm(&cpu, 1),
+        dwords([0xFA2A_4622, 0x78CE_7989, 0x5D6A_EBCD, 0x5A6A_D9AD])
+    );
+    assert_eq!(
+        xmm(&cpu, 3),
+        dwords([0xC045_BF0C, 0x6264_14DB, 0xA139_0F08, 0xCDD8_E11B])
+    );
+
+    // Skylake has no SHA, and x86-64-v3 no AES
+    let (cpu, _) = run(&code[..4], |cpu| cpu.model = CpuModel::skylake());
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    let (cpu, _) = run(&[0x66, 0x0F, 0x38, 0xDC, 0xC1], |cpu| {
+        cpu.model = CpuModel::x86_64_v3()
+    });
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn rdrand_is_reproducible() {
+    // rdrand rax; rdseed ecx; rdrand dx
+    let code = [
+        0x48, 0x0F, 0xC7, 0xF0, 0x0F, 0xC7, 0xF9, 0x66, 0x0F, 0xC7, 0xF2,
+    ];
+    let draw = |seed| {
+        let (cpu, _) = run(&code, |cpu| {
+            cpu.model = CpuModel::skylake();
+            cpu.seed_random(seed);
+            cpu.regs.gprs[1] = u64::MAX;
+            cpu.regs.gprs[2] = u64::MAX;
+            cpu.regs.rflags |= flags::ZF | flags::SF;
+        });
+        assert_eq!(cpu.fault(), None);
+        assert_eq!(cpu.regs.rflags & flags::STATUS, flags::CF);
+        // The 32-bit write clears the upper half, the 16-bit one leaves it alone
+        assert_eq!(cpu.regs.gprs[1] >> 32, 0);
+        assert_eq!(cpu.regs.gprs[2] >> 16, 0xFFFF_FFFF_FFFF);
+        [cpu.regs.gprs[0], cpu.regs.gprs[1], cpu.regs.gprs[2]]
+    };
+    assert_eq!(draw(1), draw(1));
+    assert_ne!(draw(1), draw(2));
+    let [a, b, _] = draw(1);
+    assert_ne!(a, b);
+
+    let (cpu, _) = run(&code[..4], |cpu| cpu.model = CpuModel::x86_64_v3());
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn time_stamp_counter_counts_instructions() {
+    // rdtsc; mov rbx, rax; nop; rdtscp
+    let code = [0x0F, 0x31, 0x48, 0x89, 0xC3, 0x90, 0x0F, 0x01, 0xF9];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.tsc = 0xFFFF_FFFF;
+        cpu.tsc_aux = 7;
+        cpu.regs.gprs[1] = u64::MAX;
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[3], 0);
+    assert_eq!(cpu.regs.gprs[2], 1);
+    assert_eq!(cpu.regs.gprs[0], 3);
+    assert_eq!(cpu.regs.gprs[1], 7);
+
+    // CR4.TSD keeps it from user mode, and RDTSCP needs its extension
+    let (cpu, _) = run(&code[..2], |cpu| cpu.regs.cr[4] |= 1 << 2);
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+    let (cpu, _) = run(&code[6..], |cpu| cpu.model.disable(Feature::Rdtscp));
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn fs_and_gs_bases() {
+    // wrfsbase rcx; mov rax, qword ptr fs:[0x28]; lea rdx, fs:[0x28]
+    let code = [
+        0xF3, 0x48, 0x0F, 0xAE, 0xD1, 0x64, 0x48, 0x8B, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00, 0x64,
+        0x48, 0x8D, 0x14, 0x25, 0x28, 0x00, 0x00, 0x00,
+    ];
+    let canary = 0x1122_3344_5566_7788u64.to_le_bytes();
+    let (cpu, _) = run_with_data(&code, &[(0x3028, &canary)], |cpu| {
+        // Set up after init, so CR4.FSGSBASE has to be set by hand
+        cpu.model = CpuModel::skylake();
+        cpu.regs.cr[4] |= segment::CR4_FSGSBASE;
+        cpu.regs.gprs[1] = 0x3000;
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.sr_base[4], 0x3000);
+    assert_eq!(cpu.regs.gprs[0], 0x1122_3344_5566_7788);
+    // LEA computes the offset alone
+    assert_eq!(cpu.regs.gprs[2], 0x28);
+
+    // Without FSGSBASE, and with a non-canonical base
+    let (cpu, _) = run(&code[..5], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+    let (cpu, _) = run(&code[..5], |cpu| {
+        cpu.model = CpuModel::skylake();
+        cpu.regs.cr[4] |= segment::CR4_FSGSBASE;
+        cpu.regs.gprs[1] = 0x0000_8000_0000_0000;
+    });
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+
+    // swapgs; rdgsbase rax; swapgs; rdgsbase rbx: only at privilege level 0
+    let code = [
+        0x0F, 0x01, 0xF8, 0xF3, 0x48, 0x0F, 0xAE, 0xC8, 0x0F, 0x01, 0xF8, 0xF3, 0x48, 0x0F, 0xAE,
+        0xCB,
+    ];
+    let (cpu, _) = run(&code, |cpu| {
+        cpu.model = CpuModel::skylake();
+        cpu.regs.cr[4] |= segment::CR4_FSGSBASE;
+        cpu.regs.sr[1] = 0x10;
+        cpu.regs.sr_base[5] = 0x7000;
+        cpu.regs.gprs[0] = u64::MAX;
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[0], 0);
+    assert_eq!(cpu.regs.gprs[3], 0x7000);
+    let (cpu, _) = run(&code[..3], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+
+    // What a system call layer does for arch_prctl
+    let (mut cpu, mut map) = run(&[], |_| {});
+    assert_eq!(cpu.arch_prctl(&mut map, segment::ARCH_SET_FS, 0x3000), 0);
+    assert_eq!(cpu.regs.sr_base[4], 0x3000);
+    assert_eq!(cpu.arch_prctl(&mut map, segment::ARCH_GET_FS, 0x4000), 0);
+    assert_eq!(map.read_u64(0x4000), 0x3000);
+    assert_eq!(
+        cpu.arch_prctl(&mut map, segment::ARCH_SET_GS, 0xFFFF_8000_0000_0000),
+        -1
+    );
+    assert_eq!(cpu.arch_prctl(&mut map, 0, 0), -22);
+}
+
+#[test]
+fn ia32_calling_conventions() {
+    // A cdecl call of a function adding its two stack arguments, which the caller pops, then
+    // the one-byte INC and DEC
+    let code = [
+        0x6A, 0x05, // push 5
+        0x6A, 0x03, // push 3
+        0xE8, 0x07, 0x00, 0x00, 0x00, // call 0x1010
+        0x83, 0xC4, 0x08, // add esp, 8
+        0x40, // inc eax
+        0x49, // dec ecx
+        0xEB, 0x0B, // jmp 0x101b
+        0x55, // push ebp
+        0x89, 0xE5, // mov ebp, esp
+        0x8B, 0x45, 0x08, // mov eax, dword ptr [ebp+0x8]
+        0x03, 0x45, 0x0C, // add eax, dword ptr [ebp+0xc]
+        0xC9, // leave
+        0xC3, // ret
+    ];
+    let (cpu, map) = run32(&code, |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.mode(), Mode::Bits32);
+    assert_eq!(cpu.regs.gprs[0], 9);
+    assert_eq!(cpu.regs.gprs[1], 0xFFFF_FFFF);
+    assert_eq!(cpu.regs.gprs[4], STACK_TOP);
+    // Arguments and return addresses take four bytes each
+    assert_eq!(map.read_u32(STACK_TOP - 4), 5);
+    assert_eq!(map.read_u32(STACK_TOP - 12), 0x1009);
+
+    // With stdcall the callee pops them, and leaves the frame pointer alone
+    let code = [
+        0x6A, 0x05, // push 5
+        0xE8, 0x02, 0x00, 0x00, 0x00, // call 0x1009
+        0xEB, 0x07, // jmp 0x1010
+        0x8B, 0x44, 0x24, 0x04, // mov eax, dword ptr [esp+0x4]
+        0xC2, 0x04, 0x00, // ret 4
+    ];
+    let (cpu, _) = run32(&code, |_| {});
+    assert_eq!(cpu.regs.gprs[0], 5);
+    assert_eq!(cpu.regs.gprs[4], STACK_TOP);
+}
+
+#[test]
+fn ia32_addresses_wrap_at_4_gib() {
+    // mov eax, dword ptr fs:[0x1100]; mov ebx, dword ptr [ecx+0x10]
+    let code = [0x64, 0xA1, 0x00, 0x11, 0x00, 0x00, 0x8B, 0x59, 0x10];
+    let (cpu, _) = run_program(
+        TestMap::new(&code, 32),
+        &code,
+        &[(0x100, &0x1234_5678u32.to_le_bytes()), (0x8, &[0xAB])],
+        |cpu| {
+            cpu.regs.sr_base[4] = 0xFFFF_F000;
+            cpu.regs.gprs[1] = 0xFFFF_FFF8;
+        },
+    );
+    assert_eq!(cpu.regs.gprs[0], 0x1234_5678);
+    assert_eq!(cpu.regs.gprs[3], 0xAB);
+}
+
+#[test]
+fn pusha_popa_and_segment_registers() {
+    // pusha; xor eax, eax; xor esi, esi; popa
+    let code = [0x60, 0x31, 0xC0, 0x31, 0xF6, 0x61];
+    let (cpu, map) = run32(&code, |cpu| {
+        for n in 0..8 {
+            if n != 4 {
+                cpu.regs.gprs[n] = 0x100 * n as u64 + 1;
+            }
+        }
+    });
+    assert_eq!(cpu.regs.gprs[0], 1);
+    assert_eq!(cpu.regs.gprs[6], 0x601);
+    assert_eq!(cpu.regs.gprs[4], STACK_TOP);
+    // EDI lowest, and ESP as it was before the first push
+    assert_eq!(map.read_u32(STACK_TOP - 32), 0x701);
+    assert_eq!(map.read_u32(STACK_TOP - 20), STACK_TOP as u32);
+
+    // push ds; pop es
+    let (cpu, _) = run32(&[0x1E, 0x07], |cpu| cpu.regs.sr[3] = 0x7B);
+    assert_eq!(cpu.regs.sr[0], 0x7B);
+    assert_eq!(cpu.regs.gprs[4], STACK_TOP);
+
+    let (cpu, _) = run(&[0x60], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::InvalidOpcode));
+}
+
+#[test]
+fn decimal_adjustments() {
+    let al = |code: &[u8], ax: u64| {
+        let (cpu, _) = run32(code, |cpu| cpu.regs.gprs[0] = ax);
+        (cpu.regs.gprs[0] & 0xFFFF, cpu.regs.rflags & flags::CF != 0)
+    };
+    // add al, 0x35; daa: 79 + 35 = 114
+    assert_eq!(al(&[0x04, 0x35, 0x27], 0x79), (0x14, true));
+    // sub al, 0x47; das: 35 - 47 = -12
+    assert_eq!(al(&[0x2C, 0x47, 0x2F], 0x35), (0x88, true));
+    // add al, 9; aaa: 8 + 9 = 17
+    assert_eq!(al(&[0x04, 0x09, 0x37], 0x08), (0x0107, true));
+    // sub al, 5; aas: 13 - 5 = 8
+    assert_eq!(al(&[0x2C, 0x05, 0x3F], 0x0203), (0x0108, true));
+    // aam; aad
+    assert_eq!(al(&[0xD4, 0x0A], 0x4F), (0x0709, false));
+    assert_eq!(al(&[0xD5, 0x0A], 0x0709), (0x4F, false));
+    // stc; salc
+    assert_eq!(al(&[0xF9, 0xD6], 0), (0xFF, true));
+
+    let (cpu, _) = run32(&[0xD4, 0x00], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::DivideError));
+}
+
+#[test]
+fn bound_into_and_arpl() {
+    // The bounds -1 and 10 at 0x3000, then bound eax, dword ptr [0x3000]
+    let code = [
+        0xC7, 0x05, 0x00, 0x30, 0x00, 0x00, 0xFF, 0xFF, 0xFF,
+        0xFF, // mov dword ptr [0x3000], -1
+        0xC7, 0x05, 0x04, 0x30, 0x00, 0x00, 0x0A, 0x00, 0x00,
+        0x00, // mov dword ptr [0x3004], 10
+        0x62, 0x05, 0x00, 0x30, 0x00, 0x00,
+    ];
+    for (index, fault) in [
+        (5, None),
+        (10, None),
+        (0xFFFF_FFFF, None),
+        (11, Some(Exception::BoundRange)),
+        (0xFFFF_FFFE, Some(Exception::BoundRange)),
+    ] {
+        let (cpu, _) = run32(&code, |cpu| cpu.regs.gprs[0] = index);
+        assert_eq!(cpu.fault(), fault);
+    }
+
+    // into
+    let (cpu, _) = run32(&[0xCE], |cpu| cpu.regs.rflags |= flags::OF);
+    assert_eq!(cpu.fault(), Some(Exception::Overflow));
+    let (cpu, _) = run32(&[0xCE], |_| {});
+    assert_eq!(cpu.fault(), None);
+
+    // arpl ax, cx
+    let (cpu, _) = run32(&[0x63, 0xC8], |cpu| {
+        cpu.regs.gprs[0] = 0x10;
+        cpu.regs.gprs[1] = 0x23;
+    });
+    assert_eq!(cpu.regs.gprs[0], 0x13);
+    assert_ne!(cpu.regs.rflags & flags::ZF, 0);
+}
+
+#[test]
+fn real_mode_segment_offset_addressing() {
+    let code = [
+        0xB8, 0x00, 0x20, // mov ax, 0x2000
+        0x8E, 0xD8, // mov ds, ax
+        0xC6, 0x06, 0x34, 0x00, 0x5A, // mov byte [0x34], 0x5a
+        0xB8, 0x00, 0x30, // mov ax, 0x3000
+        0x8E, 0xC0, // mov es, ax
+        0x26, 0xC7, 0x46, 0x02, 0xCD, 0xAB, // mov word es:[bp+2], 0xabcd
+        0xC6, 0x46, 0x00, 0x77, // mov byte [bp], 0x77 (in SS)
+        0x8D, 0x46, 0x04, // lea ax, [bp+4]
+        0x66, 0xB9, 0x78, 0x56, 0x34, 0x12, // mov ecx, 0x12345678
+        0x67, 0x89, 0x0C, 0x24, // mov [esp], cx
+    ];
+    let (cpu, map) = run16(&code, &[], |cpu| cpu.regs.gprs[5] = 0x10);
+    assert_eq!(cpu.fault(), None);
+    assert!(cpu.real_mode());
+    assert_eq!(cpu.regs.sr[3], 0x2000);
+    assert_eq!(cpu.regs.sr_base[3], 0x20000);
+    assert_eq!(map.mem[0x20034], 0x5A);
+    assert_eq!(map.read_u16(0x30012), 0xABCD);
+    assert_eq!(map.mem[0x10], 0x77);
+    assert_eq!(cpu.regs.gprs[0], 0x14);
+    assert_eq!(cpu.regs.gprs[1], 0x1234_5678);
+    assert_eq!(map.read_u16(STACK_TOP), 0x5678);
+}
+
+#[test]
+fn a20_gate_wraps_addresses_past_1_mib() {
+    let code = [
+        0xB8, 0xFF, 0xFF, // mov ax, 0xffff
+        0x8E, 0xC0, // mov es, ax
+        0x26, 0xC6, 0x06, 0x20, 0x00, 0xAB, // mov byte es:[0x20], 0xab
+        0xBB, 0xFF, 0xFF, // mov bx, 0xffff
+        0xC6, 0x47, 0x02, 0xCC, // mov byte [bx+2], 0xcc
+        0x50, // push ax
+    ];
+    // 0xFFFF:0x0020 is 0x100010, which wraps to 0x10 with the gate closed
+    let (cpu, map) = run16(&code, &[], |cpu| cpu.regs.gprs[4] = 0);
+    assert!(!cpu.a20());
+    assert_eq!(map.mem[0x10], 0xAB);
+    assert_eq!(map.mem[0x100010], 0);
+    // Offsets wrap around within their 64K segment either way, the stack pointer included
+    assert_eq!(map.mem[0x1], 0xCC);
+    assert_eq!(cpu.regs.gprs[4], 0xFFFE);
+    assert_eq!(map.read_u16(0xFFFE), 0xFFFF);
+
+    let (_, map) = run16(&code, &[], |cpu| cpu.set_a20(true));
+    assert_eq!(map.mem[0x10], 0);
+    assert_eq!(map.mem[0x100010], 0xAB);
+}
+
+#[test]
+fn programs_open_the_a20_gate_through_ports() {
+    // in al, 0x92; or al, 2; out 0x92, al
+    let (cpu, _) = run16(&[0xE4, 0x92, 0x0C, 0x02, 0xE6, 0x92], &[], |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert!(cpu.a20());
+
+    // Through the keyboard controller, then reading its output port back
+    let code = [
+        0xB0, 0xD1, // mov al, 0xd1
+        0xE6, 0x64, // out 0x64, al
+        0xB0, 0xDF, // mov al, 0xdf
+        0xE6, 0x60, // out 0x60, al
+        0xB0, 0xD0, // mov al, 0xd0
+        0xE6, 0x64, // out 0x64, al
+        0xE4, 0x64, // in al, 0x64
+        0x88, 0xC3, // mov bl, al
+        0xE4, 0x60, // in al, 0x60
+    ];
+    let (cpu, _) = run16(&code, &[], |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert!(cpu.a20());
+    assert_eq!(cpu.regs.gprs[3] & 0xFF, 0x05, "output buffer full");
+    assert_eq!(cpu.regs.gprs[0] & 0xFF, 0x03);
+
+    // rep insb from a port nothing drives; outsw to port 0x92, closing the gate again
+    let (cpu, map) = run16(&[0xF3, 0x6C, 0xBA, 0x92, 0x00, 0x6F], &[], |cpu| {
+        cpu.set_a20(true);
+        cpu.regs.gprs[1] = 4;
+        cpu.regs.gprs[2] = 0x1234;
+        cpu.regs.gprs[7] = 0x2000;
+        cpu.regs.gprs[6] = 0x3000;
+    });
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(map.mem[0x2000..0x2005], [0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+    assert_eq!(cpu.regs.gprs[7], 0x2004);
+    assert_eq!(cpu.regs.gprs[1], 0);
+    assert_eq!(cpu.regs.gprs[6], 0x3002);
+    assert!(!cpu.a20());
+}
+
+#[test]
+fn unmodeled_system_instructions_fault() {
+    // in al, dx above IOPL; syscall; sysenter; rdpmc; wbinvd at CPL 3; rsm
+    let cases: &[(&[u8], Exception)] = &[
+        (&[0xEC], Exception::GeneralProtection(0)),
+        (&[0x0F, 0x05], Exception::InvalidOpcode),
+        (&[0x0F, 0x34], Exception::GeneralProtection(0)),
+        (&[0x0F, 0x33], Exception::GeneralProtection(0)),
+        (&[0x0F, 0x09], Exception::GeneralProtection(0)),
+        (&[0x0F, 0xAA], Exception::InvalidOpcode),
+    ];
+    for &(code, fault) in cases {
+        let (cpu, _) = run(code, |_| {});
+        assert_eq!(cpu.fault(), Some(fault), "{:02x?}", code);
+    }
+    // wbinvd; invd at CPL 0
+    let (cpu, _) = run16(&[0x0F, 0x09, 0x0F, 0x08], &[], |_| {});
+    assert_eq!(cpu.fault(), None);
+}
+
+#[test]
+fn real_mode_far_transfers_and_interrupts() {
+    let code = [
+        0x9A, 0x00, 0x00, 0x00, 0x02, // callf 0x200:0
+        0xCD, 0x21, // int 0x21
+        0x6A, 0x07, // push 7
+        0xFF, 0x1E, 0x00, 0x05, // callf [0x500]
+        0xEA, 0x13, 0x10, 0x00, 0x00, // jmpf 0:0x1013
+        0xF4, // hlt
+    ];
+    let data: &[(u64, &[u8])] = &[
+        // Interrupt vector 0x21: 0x300:0x10
+        (0x84, &[0x10, 0x00, 0x00, 0x03]),
+        // A far pointer to 0x200:4
+        (0x500, &[0x04, 0x00, 0x00, 0x02]),
+        (
+            0x2000,
+            &[
+                0xB8, 0x34, 0x12, // mov ax, 0x1234
+                0xCB, // retf
+                0xBE, 0x01, 0x00, // mov si, 1
+                0xCA, 0x02, 0x00, // retf 2
+            ],
+        ),
+        (
+            0x3010,
+            &[
+                0x9C, // pushf
+                0x5A, // pop dx
+                0x89, 0xE5, // mov bp, sp
+                0xBB, 0x78, 0x56, // mov bx, 0x5678
+                0xCF, // iret
+            ],
+        ),
+    ];
+    let (cpu, _) = run16(&code, data, |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert!(cpu.running());
+    assert_eq!(cpu.regs.gprs[0], 0x1234);
+    assert_eq!(cpu.regs.gprs[3], 0x5678);
+    assert_eq!(cpu.regs.gprs[6], 1);
+    assert_eq!(cpu.regs.gprs[4], STACK_TOP);
+    assert_eq!(cpu.regs.sr[1], 0);
+    // The handler runs with IF clear, and IRET restores it
+    assert_eq!(cpu.regs.gprs[2] & flags::IF, 0);
+    assert_ne!(cpu.regs.rflags & flags::IF, 0);
+    // INT pushed FLAGS, CS and the return address
+    assert_eq!(cpu.regs.gprs[5], STACK_TOP - 6);
+}
+
+#[test]
+fn real_mode_exceptions_go_through_the_ivt() {
+    let code = [
+        0xB8, 0x0A, 0x00, // mov ax, 10
+        0xF6, 0xF1, // div cl
+        0xFA, // cli
+        0xF4, // hlt
+    ];
+    let data: &[(u64, &[u8])] = &[
+        // The #DE handler at 0x400:0 fixes the divisor, and IRET retries the division
+        (0, &[0x00, 0x00, 0x00, 0x04]),
+        (0x4000, &[0xB1, 0x01, 0xCF]), // mov cl, 1; iret
+    ];
+    let (cpu, _) = run16(&code, data, |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert!(!cpu.running());
+    assert_eq!(cpu.regs.gprs[0], 10);
+    assert_eq!(cpu.regs.gprs[1], 1);
+    assert_eq!(cpu.regs.rflags & flags::IF, 0);
+}
+
+#[test]
+fn interrupts_fault_in_user_mode() {
+    let (cpu, _) = run(&[0xCD, 0x80], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0x402)));
+    let (cpu, _) = run(&[0xCC], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::Breakpoint));
+    let (cpu, _) = run(&[0xF4], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+    let (cpu, _) = run32(&[0xFA], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+    // POPF can't clear IF at CPL 3 with IOPL 0
+    let (cpu, _) = run(&[0x6A, 0x00, 0x9D], |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert_ne!(cpu.regs.rflags & flags::IF, 0);
+}
+
+/// Descriptor tables for protected-mode tests: a GDT at 0x6000, an IDT at 0x6400 and a
+/// 32-bit TSS at 0x7000 whose ring 0 stack is 0x10:0xE000, with the handlers they point to
+/// below the code, and the pseudo-descriptors LGDT and LIDT load them from at 0x5000.
+fn protected_mode_tables() -> Vec<(u64, Vec<u8>)> {
+    let gdt: [u64; 9] = [
+        0,
+        0x00CF_9A00_0000_FFFF, // 0x08: flat ring 0 code
+        0x00CF_9200_0000_FFFF, // 0x10: flat ring 0 data
+        0x00CF_FA00_0000_FFFF, // 0x18: flat ring 3 code
+        0x00CF_F200_0000_FFFF, // 0x20: flat ring 3 data
+        0x0040_9000_8000_00FF, // 0x28: read-only, 256 bytes at 0x8000
+        0x00CF_1200_0000_FFFF, // 0x30: not present
+        0x0000_EC00_0008_0C00, // 0x38: call gate to 0x08:0xc00, callable from ring 3
+        0x0000_8900_7000_0067, // 0x40: the TSS
+    ];
+    let interrupt_gate = |offset: u64, dpl: u64| 0x0000_8E00_0008_0000 | dpl << 45 | offset;
+    let tables = [
+        (0x5000, vec![0x47, 0x00, 0x00, 0x60, 0x00, 0x00]),
+        (0x5006, vec![0xFF, 0x07, 0x00, 0x64, 0x00, 0x00]),
+        (0x6000, gdt.iter().flat_map(|d| d.to_le_bytes()).collect()),
+        (0x6458, interrupt_gate(0xE10, 0).to_le_bytes().to_vec()),
+        (0x6460, interrupt_gate(0xE20, 0).to_le_bytes().to_vec()),
+        (0x6468, interrupt_gate(0xE00, 0).to_le_bytes().to_vec()),
+        (0x6800, interrupt_gate(0xD00, 3).to_le_bytes().to_vec()),
+        (0x7004, vec![0x00, 0xE0, 0x00, 0x00, 0x10, 0x00]),
+        (0x0C00, vec![0x89, 0xE5, 0xCB]), // mov ebp, esp; retf
+        (0x0D00, vec![0x89, 0xE7, 0xCF]), // mov edi, esp; iretd
+        // #GP, #NP and #SS: pop ebx (the error code); mov cl, vector; hlt
+        (0x0E00, vec![0x5B, 0xB1, 0x0D, 0xF4]),
+        (0x0E10, vec![0x5B, 0xB1, 0x0B, 0xF4]),
+        (0x0E20, vec![0x5B, 0xB1, 0x0C, 0xF4]),
+    ];
+    tables.into_iter().collect()
+}
+
+/// Like [`run16`], but switches to protected mode with [`protected_mode_tables`] and loads
+/// the flat ring 0 data segment before running `code` as 32-bit code, from 0x1022.
+fn run_protected(code: &[u8], data: &[(u64, &[u8])]) -> (Amd64Interp, TestMap) {
+    run_protected_with(code, data, |_| {})
+}
+
+/// Like [`run_protected`], but first lets `setup` seed the processor.
+fn run_protected_with<F: FnOnce(&mut Amd64Interp)>(
+    code: &[u8],
+    data: &[(u64, &[u8])],
+    setup: F,
+) -> (Amd64Interp, TestMap) {
+    let mut program = vec![
+        0x0F, 0x01, 0x16, 0x00, 0x50, // lgdt [0x5000]
+        0x0F, 0x01, 0x1E, 0x06, 0x50, // lidt [0x5006]
+        0x0F, 0x20, 0xC0, // mov eax, cr0
+        0x0C, 0x01, // or al, 1
+        0x0F, 0x22, 0xC0, // mov cr0, eax
+        0xEA, 0x17, 0x10, 0x08, 0x00, // jmpf 0x8:0x1017
+        0xB8, 0x10, 0x00, 0x00, 0x00, // mov eax, 0x10
+        0x8E, 0xD8, // mov ds, ax
+        0x8E, 0xC0, // mov es, ax
+        0x8E, 0xD0, // mov ss, ax
+    ];
+    program.extend_from_slice(code);
+    let tables = protected_mode_tables();
+    let mut all: Vec<(u64, &[u8])> = tables.iter().map(|(a, b)| (*a, &b[..])).collect();
+    all.extend_from_slice(data);
+    run16(&program, &all, setup)
+}
+
+#[test]
+fn switches_to_protected_mode() {
+    let (cpu, map) = run_protected(&[], &[]);
+    assert_eq!(cpu.fault(), None);
+    assert!(!cpu.real_mode());
+    assert_eq!(cpu.mode(), Mode::Bits32);
+    assert_eq!(cpu.regs.sr[..4], [0x10, 0x08, 0x10, 0x10]);
+    assert_eq!(cpu.cpl(), 0);
+    assert_eq!(cpu.regs.cr[0] & 1, 1);
+    assert_eq!(cpu.regs.gprs[4], STACK_TOP);
+    assert_eq!(cpu.tables.gdtr.base, 0x6000);
+    // Loading the descriptors set their accessed bits
+    assert_eq!(map.mem[0x600D], 0x9B);
+    assert_eq!(map.mem[0x6015], 0x93);
+}
+
+#[test]
+fn segment_checks_fault() {
+    let mov_ds_ss = |selector: u8, sreg: u8| {
+        // mov ax, selector; mov sreg, ax
+        [0x66, 0xB8, selector, 0x00, 0x8E, 0xC0 | sreg << 3]
+    };
+    // Each raises a fault whose handler leaves its vector in CL and error code in EBX
+    let cases: &[(&[u8], u8, u64)] = &[
+        // Past the read-only segment's limit, and a write to it
+        (
+            &[
+                0x66, 0xB8, 0x28, 0x00, 0x8E, 0xD8, 0xA0, 0xFF, 0x00, 0x00, 0x00, 0xA1, 0xFE, 0x00,
+                0x00, 0x00,
+            ],
+            13,
+            0,
+        ),
+        (
+            &[
+                0x66, 0xB8, 0x28, 0x00, 0x8E, 0xD8, 0xA2, 0x10, 0x00, 0x00, 0x00,
+            ],
+            13,
+            0,
+        ),
+        (&mov_ds_ss(0x30, 3), 11, 0x30),
+        (&mov_ds_ss(0x30, 2), 12, 0x30),
+        // Code in SS, a ring 3 stack at ring 0, a selector past the GDT's limit
+        (&mov_ds_ss(0x08, 2), 13, 0x08),
+        (&mov_ds_ss(0x20, 2), 13, 0x20),
+        (&mov_ds_ss(0x48, 3), 13, 0x48),
+        // A null SS, and an access through a null ES
+        (&mov_ds_ss(0x00, 2), 13, 0),
+        (
+            &[0x31, 0xC0, 0x8E, 0xC0, 0x26, 0xA0, 0x00, 0x00, 0x00, 0x00],
+            13,
+            0,
+        ),
+    ];
+    for &(code, vector, error_code) in cases {
+        let (cpu, _) = run_protected(code, &[(0x80FF, &[0x5A])]);
+        assert_eq!(cpu.fault(), None, "{:02x?}", code);
+        assert!(!cpu.running());
+        assert_eq!(cpu.regs.gprs[1] & 0xFF, vector as u64, "{:02x?}", code);
+        assert_eq!(cpu.regs.gprs[3], error_code, "{:02x?}", code);
+    }
+    // The byte at the limit was readable
+    let (cpu, _) = run_protected(cases[0].0, &[(0x80FF, &[0x5A])]);
+    assert_eq!(cpu.regs.gprs[0] & 0xFF, 0x5A);
+}
+
+#[test]
+fn segment_checks_cover_every_operand_kind() {
+    // With the read-only segment in DS, each reads past its limit or writes to it, through
+    // x87, CMPXCHG8B, BOUND, FNSAVE, FRSTOR and SSE operands: fld dword [0x200];
+    // fst dword [0x10]; cmpxchg8b [0x10]; bound eax, [0xFC]; fnsave [0x10]; frstor [0xB0];
+    // and with CR4.OSFXSR set, movups xmm0, [0xF8]; movups [0x10], xmm0; and
+    // pcmpeqb xmm1, xmm1; mov edi, 0x10; maskmovdqu [edi], xmm0, xmm1
+    let set_osfxsr = [0x0F, 0x20, 0xE0, 0x80, 0xCC, 0x02, 0x0F, 0x22, 0xE0];
+    let cases: &[(&[u8], &[u8])] = &[
+        (&[], &[0xD9, 0x05, 0x00, 0x02, 0x00, 0x00]),
+        (&[], &[0xD9, 0x15, 0x10, 0x00, 0x00, 0x00]),
+        (&[], &[0x0F, 0xC7, 0x0D, 0x10, 0x00, 0x00, 0x00]),
+        (&[], &[0x62, 0x05, 0xFC, 0x00, 0x00, 0x00]),
+        (&[], &[0xDD, 0x35, 0x10, 0x00, 0x00, 0x00]),
+        (&[], &[0xDD, 0x25, 0xB0, 0x00, 0x00, 0x00]),
+        (&set_osfxsr, &[0x0F, 0x10, 0x05, 0xF8, 0x00, 0x00, 0x00]),
+        (&set_osfxsr, &[0x0F, 0x11, 0x05, 0x10, 0x00, 0x00, 0x00]),
+        (
+            &set_osfxsr,
+            &[
+                0x66, 0x0F, 0x74, 0xC9, 0xBF, 0x10, 0x00, 0x00, 0x00, 0x66, 0x0F, 0xF7, 0xC1,
+            ],
+        ),
+    ];
+    for &(setup, access) in cases {
+        // mov ax, 0x28; mov ds, ax
+        let code = [&[0x66, 0xB8, 0x28, 0x00, 0x8E, 0xD8], setup, access].concat();
+        let (cpu, _) = run_protected(&code, &[]);
+        assert_eq!(cpu.fault(), None, "{:02x?}", access);
+        assert_eq!(cpu.regs.gprs[1] & 0xFF, 13, "{:02x?}", access);
+        assert_eq!(cpu.regs.gprs[3], 0, "{:02x?}", access);
+    }
+}
+
+#[test]
+fn privilege_changes_through_gates() {
+    let code = [
+        0x66, 0xB8, 0x40, 0x00, // mov ax, 0x40
+        0x0F, 0x00, 0xD8, // ltr ax
+        0x6A, 0x23, // push 0x23
+        0x68, 0x00, 0x80, 0x00, 0x00, // push 0x8000
+        0x6A, 0x1B, // push 0x1b
+        0x68, 0x38, 0x10, 0x00, 0x00, // push 0x1038
+        0xCB, // retf, to ring 3
+        0x9A, 0x00, 0x00, 0x00, 0x00, 0x3B, 0x00, // callf 0x3b:0, through the call gate
+        0xCD, 0x80, // int 0x80
+        0xFA, // cli
+    ];
+    let (cpu, map) = run_protected(&code, &[]);
+    assert_eq!(cpu.fault(), None);
+    // The TSS is busy
+    assert_eq!(map.mem[0x6045], 0x8B);
+    // The call gate and INT switched to the ring 0 stack, saving the ring 3 one there
+    assert_eq!(cpu.regs.gprs[5], 0xE000 - 16);
+    assert_eq!(cpu.regs.gprs[7], 0xE000 - 20);
+    assert_eq!(map.read_u32(0xE000 - 4), 0x23);
+    assert_eq!(map.read_u32(0xE000 - 8), 0x8000);
+    // CLI faulted at ring 3, and the handler runs at ring 0
+    assert_eq!(cpu.regs.gprs[1] & 0xFF, 13);
+    assert_eq!(cpu.regs.gprs[3], 0);
+    assert_eq!(map.read_u32(0xE000 - 16), 0x1B);
+    assert_eq!(map.read_u32(0xE000 - 20), 0x1041);
+    assert_eq!(cpu.cpl(), 0);
+    assert_eq!(cpu.regs.sr[..4], [0, 0x08, 0x10, 0]);
+}
+
+#[test]
+fn unhandled_exceptions_triple_fault() {
+    // lidt [0x5010], an empty IDT; ud2
+    let (cpu, _) = run_protected(&[0x0F, 0x01, 0x1D, 0x10, 0x50, 0x00, 0x00, 0x0F, 0x0B], &[]);
+    assert_eq!(cpu.fault(), Some(Exception::DoubleFault));
+}
+
+#[test]
+fn descriptor_inspection_and_system_instructions_in_user_mode() {
+    let code = [
+        0xB8, 0x7B, 0x00, 0x00, 0x00, // mov eax, 0x7b
+        0x0F, 0x03, 0xD8, // lsl ebx, ax
+        0x0F, 0x02, 0xC8, // lar ecx, ax
+        0x0F, 0x94, 0xC2, // setz dl
+        0x0F, 0x00, 0xE8, // verw ax
+    ];
+    let (cpu, _) = run32(&code, |cpu| cpu.regs.gprs[3] = u64::MAX);
+    assert_eq!(cpu.fault(), None);
+    // Linux's per-CPU segment, whose limit is the CPU number
+    assert_eq!(cpu.regs.gprs[3], 0);
+    assert_eq!(cpu.regs.gprs[1], 0x0040_F500);
+    assert_eq!(cpu.regs.gprs[2], 1);
+    assert_eq!(cpu.regs.rflags & flags::ZF, 0);
+
+    // lgdt [0x5000]; mov eax, cr0: only at ring 0
+    let (cpu, _) = run32(&[0x0F, 0x01, 0x15, 0x00, 0x50, 0x00, 0x00], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+    let (cpu, _) = run32(&[0x0F, 0x20, 0xC0], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+}
+
+#[test]
+fn debug_registers_hold_what_is_moved_to_them() {
+    let code = [
+        0xB8, 0x78, 0x56, 0x34, 0x12, // mov eax, 0x12345678
+        0x0F, 0x23, 0xC0, // mov dr0, eax
+        0x0F, 0x21, 0xC3, // mov ebx, dr0
+        0x0F, 0x21, 0xE1, // mov ecx, dr4: DR6
+        0xB8, 0xFF, 0xFF, 0xFF, 0xFF, // mov eax, 0xffffffff
+        0x0F, 0x23, 0xF8, // mov dr7, eax
+        0x0F, 0x21, 0xFA, // mov edx, dr7
+    ];
+    let (cpu, _) = run_protected(&code, &[]);
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[3], 0x1234_5678);
+    assert_eq!(cpu.regs.gprs[1], 0xFFFF_0FF0);
+    assert_eq!(cpu.regs.gprs[2], 0xFFFF_27FF);
+
+    // mov rax, dr7: only at ring 0
+    let (cpu, _) = run(&[0x0F, 0x21, 0xF8], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+    let (cpu, _) = run(&[0x0F, 0x23, 0xF8], |_| {});
+    assert_eq!(cpu.fault(), Some(Exception::GeneralProtection(0)));
+}
+
+/// Page tables and descriptor tables for long-mode tests. The first 2 MiB are identity
+/// mapped by a large page, and the 4K pages after them are:
+/// - 0x200000: 0x30000, writable by the supervisor only
+/// - 0x201000: 0x31000, read-only but open to user mode
+/// - 0x202000: 0x32000, not executable
+/// - 0x203000: not present
+/// - 0x204000: an entry with a reserved bit set
+///
+/// The PML4 is at 0x20000, with a PML5 at 0x24000 over it for 5-level paging. The GDT at
+/// 0x6100 has 64-bit code and data for rings 0 and 3 and a TSS at 0x7100 whose ring 0
+/// stack is 0xE000, and the IDT at 0x6C00 sends #GP and #PF to handlers that leave the
+/// vector in DL, the error code in RBX and CR2 in RCX.
+fn long_mode_tables() -> Vec<(u64, Vec<u8>)> {
+    let gdt: [u64; 7] = [
+        0,
+        0x00AF_9A00_0000_FFFF, // 0x08: 64-bit ring 0 code
+        0x00CF_9200_0000_FFFF, // 0x10: ring 0 data
+        0x00CF_F200_0000_FFFF, // 0x18: ring 3 data
+        0x00AF_FA00_0000_FFFF, // 0x20: 64-bit ring 3 code
+        0x0000_8900_7100_0067, // 0x28: the TSS
+        0,
+    ];
+    let interrupt_gate = |offset: u64| (0x0000_8E00_0008_0000 | offset).to_le_bytes();
+    let entries = |entries: &[u64]| entries.iter().flat_map(|e| e.to_le_bytes()).collect();
+    let tables = [
+        (0x5010, vec![0x37, 0x00, 0x00, 0x61, 0x00, 0x00]),
+        (0x501A, vec![0xFF, 0x0F, 0x00, 0x6C, 0x00, 0x00]),
+        (0x6100, entries(&gdt)),
+        (0x6CD0, [interrupt_gate(0xF10), [0; 8]].concat()),
+        (0x6CE0, [interrupt_gate(0xF00), [0; 8]].concat()),
+        (0x7104, 0xE000u64.to_le_bytes().to_vec()),
+        // #PF: pop rbx; mov rcx, cr2; mov dl, 14; hlt. #GP: pop rbx; mov dl, 13; hlt
+        (0x0F00, vec![0x5B, 0x0F, 0x20, 0xD1, 0xB2, 0x0E, 0xF4]),
+        (0x0F10, vec![0x5B, 0xB2, 0x0D, 0xF4]),
+        (0x24000, entries(&[0x20007])),
+        (0x20000, entries(&[0x21007])),
+        (0x21000, entries(&[0x22007])),
+        (0x22000, entries(&[0x87, 0x23007])),
+        (
+            0x23000,
+            entries(&[
+                0x30003,
+                0x31005,
+                0x8000_0000_0003_2003,
+                0,
+                0x0004_0000_0003_4003,
+            ]),
+        ),
+    ];
+    tables.into_iter().collect()
+}
+
+/// Like [`run_protected_with`], but goes on to turn on paging with [`long_mode_tables`],
+/// setting the `cr4` bits along with PAE, EFER.NXE and CR0.WP, and runs `code` as 64-bit
+/// ring 0 code once the TSS is loaded. `data` is copied over the tables.
+fn run_long_mode<F: FnOnce(&mut Amd64Interp)>(
+    code: &[u8],
+    cr4: u32,
+    data: &[(u64, &[u8])],
+    setup: F,
+) -> (Amd64Interp, TestMap) {
+    let cr4 = cr4 | 0x20;
+    let cr3: u32 = if cr4 & 0x1000 != 0 { 0x24000 } else { 0x20000 };
+    let mut program = vec![
+        0x0F, 0x20, 0xE0, // mov eax, cr4
+        0x0D, // or eax, cr4
+    ];
+    program.extend_from_slice(&cr4.to_le_bytes());
+    program.extend_from_slice(&[
+        0x0F, 0x22, 0xE0, // mov cr4, eax
+        0xB8, // mov eax, cr3
+    ]);
+    program.extend_from_slice(&cr3.to_le_bytes());
+    program.extend_from_slice(&[
+        0x0F, 0x22, 0xD8, // mov cr3, eax
+        0xB9, 0x80, 0x00, 0x00, 0xC0, // mov ecx, 0xc0000080
+        0x0F, 0x32, // rdmsr
+        0x0D, 0x00, 0x09, 0x00, 0x00, // or eax, 0x900: LME and NXE
+        0x0F, 0x30, // wrmsr
+        0x0F, 0x20, 0xC0, // mov eax, cr0
+        0x0D, 0x00, 0x00, 0x01, 0x80, // or eax, 0x80010000: PG and WP
+        0x0F, 0x22, 0xC0, // mov cr0, eax
+        0x0F, 0x01, 0x15, 0x10, 0x50, 0x00, 0x00, // lgdt [0x5010]
+        0x0F, 0x01, 0x1D, 0x1A, 0x50, 0x00, 0x00, // lidt [0x501a]
+        0xEA, // jmpf 0x8:target
+    ]);
+    let target = CODE_BASE as u32 + 0x22 + program.len() as u32 + 6;
+    program.extend_from_slice(&target.to_le_bytes());
+    program.extend_from_slice(&[
+        0x08, 0x00, //
+        0x66, 0xB8, 0x28, 0x00, // mov ax, 0x28
+        0x0F, 0x00, 0xD8, // ltr ax
+    ]);
+    program.extend_from_slice(code);
+    let tables = long_mode_tables();
+    let mut all: Vec<(u64, &[u8])> = tables.iter().map(|(a, b)| (*a, &b[..])).collect();
+    all.extend_from_slice(data);
+    run_protected_with(&program, &all, setup)
+}
+
+#[test]
+fn paging_translates_and_marks_entries() {
+    let code = [
+        0xC6, 0x04, 0x25, 0x10, 0x00, 0x20, 0x00, 0x5A, // mov byte ptr [0x200010], 0x5a
+        0x8A, 0x04, 0x25, 0x00, 0x10, 0x20, 0x00, // mov al, byte ptr [0x201000]
+    ];
+    let (cpu, map) = run_long_mode(&code, 0, &[], |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.mode(), Mode::Bits64);
+    assert_eq!(cpu.regs.gprs[2] & 0xFF, 0);
+    assert_eq!(map.mem[0x30010], 0x5A);
+    // The write set the accessed and dirty bits, the read only the accessed ones
+    assert_eq!(map.read_u64(0x23000), 0x30063);
+    assert_eq!(map.read_u64(0x23008), 0x31025);
+    assert_eq!(map.read_u64(0x22008), 0x23027);
+    assert_eq!(map.read_u64(0x20000), 0x21027);
+    // The large page the code and tables are in is dirty from LTR marking the TSS busy
+    assert_eq!(map.read_u64(0x22000), 0xE7);
+}
+
+#[test]
+fn page_faults_report_the_access_and_address() {
+    // Each raises #PF or #GP, whose handler leaves the vector in DL, the error code in RBX
+    // and CR2 in RCX
+    let cases: &[(&[u8], u8, u64, u64)] = &[
+        // mov al, byte ptr [0x203000]: not present
+        (&[0x8A, 0x04, 0x25, 0x00, 0x30, 0x20, 0x00], 14, 0, 0x203000),
+        // mov byte ptr [0x201000], 1: read-only, with CR0.WP
+        (
+            &[0xC6, 0x04, 0x25, 0x00, 0x10, 0x20, 0x00, 0x01],
+            14,
+            3,
+            0x201000,
+        ),
+        // mov eax, 0x202000; jmp rax: not executable
+        (
+            &[0xB8, 0x00, 0x20, 0x20, 0x00, 0xFF, 0xE0],
+            14,
+            0x11,
+            0x202000,
+        ),
+        // mov eax, dword ptr [0x202ffe]: the second page isn't present
+        (&[0x8B, 0x04, 0x25, 0xFE, 0x2F, 0x20, 0x00], 14, 0, 0x203000),
+        // mov al, byte ptr [0x204000]: a reserved bit
+        (&[0x8A, 0x04, 0x25, 0x00, 0x40, 0x20, 0x00], 14, 9, 0x204000),
+        // movabs al, byte ptr [0x800000000000]: not canonical
+        (
+            &[0xA0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00],
+            13,
+            0,
+            0,
+        ),
+    ];
+    for &(code, vector, error_code, cr2) in cases {
+        let (mut cpu, mut map) = run_long_mode(code, 0, &[], |_| {});
+        // The jump leaves the code, so has to be run on to the handler by hand
+        for _ in 0..8 {
+            if cpu.running() {
+                cpu.tick(&mut map);
+            }
+        }
+        assert_eq!(cpu.fault(), None, "{:02x?}", code);
+        assert!(!cpu.running());
+        assert_eq!(cpu.regs.gprs[2] & 0xFF, vector as u64, "{:02x?}", code);
+        assert_eq!(cpu.regs.gprs[3], error_code, "{:02x?}", code);
+        if vector == 14 {
+            assert_eq!(cpu.regs.gprs[1], cr2, "{:02x?}", code);
+        }
+        // The faulting accesses left the page tables alone
+        assert_eq!(map.read_u64(0x23008), 0x31005, "{:02x?}", code);
+    }
+
+    // A user-mode access to a supervisor page
+    let code = [
+        0x6A, 0x1B, // push 0x1b
+        0x68, 0x00, 0x80, 0x00, 0x00, // push 0x8000
+        0x68, 0x02, 0x02, 0x00, 0x00, // push 0x202
+        0x6A, 0x23, // push 0x23
+        0x48, 0x8D, 0x05, 0x03, 0x00, 0x00, 0x00, // lea rax, [rip + 3]
+        0x50, // push rax
+        0x48, 0xCF, // iretq, to ring 3
+        0x8A, 0x04, 0x25, 0x00, 0x10, 0x20, 0x00, // mov al, byte ptr [0x201000]
+        0x8A, 0x04, 0x25, 0x00, 0x00, 0x20, 0x00, // mov al, byte ptr [0x200000]
+    ];
+    let (cpu, map) = run_long_mode(&code, 0, &[], |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[2] & 0xFF, 14);
+    assert_eq!(cpu.regs.gprs[3], 5);
+    assert_eq!(cpu.regs.gprs[1], 0x200000);
+    // Delivered on the TSS's ring 0 stack
+    assert_eq!(cpu.regs.gprs[4], 0xE000 - 40);
+    assert_eq!(map.read_u64(0xE000 - 8), 0x1B);
+    assert_eq!(map.read_u64(0xE000 - 16), 0x8000);
+    assert_eq!(map.read_u64(0xE000 - 32), 0x23);
+}
+
+#[test]
+fn tlb_keeps_translations_until_flushed() {
+    let stale_access = [
+        0x8A, 0x04, 0x25, 0x00, 0x00, 0x20, 0x00, // mov al, byte ptr [0x200000]
+        0x48, 0xC7, 0x04, 0x25, 0x00, 0x30, 0x02, 0x00, 0x00, 0x00, 0x00,
+        0x00, // mov qword ptr [0x23000], 0: unmap it
+        0x8A, 0x04, 0x25, 0x00, 0x00, 0x20, 0x00, // mov al, byte ptr [0x200000]
+        0xBE, 0x01, 0x00, 0x00, 0x00, // mov esi, 1
+    ];
+    let access = [0x8A, 0x04, 0x25, 0x00, 0x00, 0x20, 0x00]; // mov al, byte ptr [0x200000]
+    let flushes: &[&[u8]] = &[
+        // invlpg [0x200000]
+        &[0x0F, 0x01, 0x3C, 0x25, 0x00, 0x00, 0x20, 0x00],
+        // mov rax, cr3; mov cr3, rax
+        &[0x0F, 0x20, 0xD8, 0x0F, 0x22, 0xD8],
+        // xor eax, eax; invpcid rax, [0x5030]: an individual address
+        &[
+            0x31, 0xC0, 0x66, 0x0F, 0x38, 0x82, 0x04, 0x25, 0x30, 0x50, 0x00, 0x00,
+        ],
+    ];
+    let descriptor = [[0; 8], 0x200000u64.to_le_bytes()].concat();
+    for &flush in flushes {
+        let code = [&stale_access[..], flush, &access].concat();
+        let (cpu, _) = run_long_mode(&code, 0, &[(0x5030, &descriptor)], |cpu| {
+            cpu.model = CpuModel::skylake();
+        });
+        // The stale translation was used until the flush
+        assert_eq!(cpu.regs.gprs[6], 1, "{:02x?}", flush);
+        assert_eq!(cpu.regs.gprs[2] & 0xFF, 14, "{:02x?}", flush);
+        assert_eq!(cpu.regs.gprs[1], 0x200000, "{:02x?}", flush);
+    }
+
+    // Global pages survive a CR3 reload, but not a change to CR4.PGE
+    let global = 0x30103u64.to_le_bytes();
+    let code = [
+        &stale_access[..],
+        &[0x0F, 0x20, 0xD8, 0x0F, 0x22, 0xD8], // mov rax, cr3; mov cr3, rax
+        &access,
+        &[0xBF, 0x01, 0x00, 0x00, 0x00], // mov edi, 1
+        // mov rax, cr4; and eax, ~0x80; mov cr4, rax
+        &[
+            0x0F, 0x20, 0xE0, 0x25, 0x7F, 0xFF, 0xFF, 0xFF, 0x0F, 0x22, 0xE0,
+        ],
+        &access,
+    ]
+    .concat();
+    let (cpu, _) = run_long_mode(&code, 0x80, &[(0x23000, &global)], |_| {});
+    assert_eq!(cpu.regs.gprs[7], 1);
+    assert_eq!(cpu.regs.gprs[2] & 0xFF, 14);
+}
+
+#[test]
+fn pcids_keep_their_own_translations() {
+    let code = [
+        0x0F, 0x20, 0xE0, // mov rax, cr4
+        0x0D, 0x00, 0x00, 0x02, 0x00, // or eax, 0x20000: PCIDE
+        0x0F, 0x22, 0xE0, // mov cr4, rax
+        0x8A, 0x04, 0x25, 0x00, 0x00, 0x20, 0x00, // mov al, byte ptr [0x200000]
+        0x48, 0xC7, 0x04, 0x25, 0x00, 0x30, 0x02, 0x00, 0x00, 0x00, 0x00,
+        0x00, // mov qword ptr [0x23000], 0: unmap it
+        0xB8, 0x01, 0x00, 0x02, 0x00, // mov eax, 0x20001
+        0x0F, 0x22, 0xD8, // mov cr3, rax: PCID 1
+        0x48, 0xB8, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+        0x80, // movabs rax, 0x8000000000020000
+        0x0F, 0x22, 0xD8, // mov cr3, rax: back to PCID 0, keeping its translations
+        0x8A, 0x04, 0x25, 0x00, 0x00, 0x20, 0x00, // mov al, byte ptr [0x200000]
+        0xBE, 0x01, 0x00, 0x00, 0x00, // mov esi, 1
+        0xB8, 0x01, 0x00, 0x02, 0x00, // mov eax, 0x20001
+        0x0F, 0x22, 0xD8, // mov cr3, rax: PCID 1 again
+        0x8A, 0x04, 0x25, 0x00, 0x00, 0x20, 0x00, // mov al, byte ptr [0x200000]
+    ];
+    let (cpu, _) = run_long_mode(&code, 0, &[], |cpu| cpu.model = CpuModel::skylake());
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[6], 1);
+    assert_eq!(cpu.regs.gprs[2] & 0xFF, 14);
+    assert_eq!(cpu.regs.gprs[1], 0x200000);
+    assert_eq!(cpu.regs.cr[3], 0x20001);
+
+    // Without PCID support CR4.PCIDE is reserved
+    let (cpu, _) = run_long_mode(&code, 0, &[], |_| {});
+    assert_eq!(cpu.regs.gprs[2] & 0xFF, 13);
+    assert_eq!(cpu.regs.gprs[6], 0);
+}
+
+#[test]
+fn five_level_paging_widens_linear_addresses() {
+    let code = [
+        0xC6, 0x04, 0x25, 0x10, 0x00, 0x20, 0x00, 0x5A, // mov byte ptr [0x200010], 0x5a
+        // movabs al, byte ptr [0x800000000000]: canonical with 57 bits, but not mapped
+        0xA0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00,
+    ];
+    let la57 = |cpu: &mut Amd64Interp| {
+        cpu.model = CpuModel::skylake().with(Feature::La57);
+        cpu.model.linear_address_bits = 57;
+    };
+    let (cpu, map) = run_long_mode(&code, 0x1000, &[], la57);
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.cr[3], 0x24000);
+    assert_eq!(map.mem[0x30010], 0x5A);
+    assert_eq!(map.read_u64(0x24000), 0x20027);
+    assert_eq!(cpu.regs.gprs[2] & 0xFF, 14);
+    assert_eq!(cpu.regs.gprs[3], 0);
+    assert_eq!(cpu.regs.gprs[1], 0x8000_0000_0000);
+
+    // CR4.LA57 is reserved on a processor without it, so setting it raised #GP before
+    // paging was on
+    let (cpu, _) = run_long_mode(&code, 0x1000, &[], |_| {});
+    assert_eq!(cpu.fault(), None);
+    assert_eq!(cpu.regs.gprs[1] & 0xFF, 13);
+    assert_eq!(cpu.regs.cr[4] & 0x1000, 0);
+    assert!(!cpu.paging());
+}
@@ -0,0 +1,1151 @@
+//! The x87 floating-point unit: its register stack, control and status words, and the
+//! instructions that operate on them.
+//!
+//! Arithmetic is done in software on 80-bit extended-precision values, honouring the
+//! precision and rounding controls. Exceptions set their flags in the status word; a masked
+//! exception delivers its default result, while an unmasked one sets the error summary bit
+//! and raises #MF on the next waiting x87 instruction, as on hardware.
+
+use super::descriptor::Access;
+use super::flags;
+use super::Amd64Interp;
+use crate::amd64::decode::{Instruction, Mnemonic, Operand, OperandSize, Register};
+use crate::amd64::exception::Exception;
+use crate::amd64::softfloat::transcendental::{self, LN_2, LOG10_2, LOG2_10, LOG2_E, ONE, PI};
+use crate::amd64::softfloat::{
+    Class, Env, Flags, Float, Format, Rounding, DOUBLE, EXTENDED, SINGLE,
+};
+use file_loader::{MemoryMap, X87Register};
+use std::cmp::Ordering;
+
+// Status word
+const C0: u16 = 1 << 8;
+const C1: u16 = 1 << 9;
+const C2: u16 = 1 << 10;
+const C3: u16 = 1 << 14;
+const STACK_FAULT: u16 = 1 << 6;
+pub(super) const ERROR_SUMMARY: u16 = 1 << 7;
+const BUSY: u16 = 1 << 15;
+const TOP_SHIFT: u16 = 11;
+const EXCEPTIONS: u16 = 0x3F;
+
+// Tags
+const TAG_VALID: u16 = 0;
+const TAG_ZERO: u16 = 1;
+const TAG_SPECIAL: u16 = 2;
+const TAG_EMPTY: u16 = 3;
+
+// CR0
+const CR0_MP: u64 = 1 << 1;
+pub(super) const CR0_EM: u64 = 1 << 2;
+pub(super) const CR0_TS: u64 = 1 << 3;
+
+/// The control word after FNINIT: round to nearest, 64-bit precision, all exceptions masked.
+const DEFAULT_FCW: u16 = 0x037F;
+
+/// The "real indefinite" quiet NaN stored by masked invalid operations.
+const INDEFINITE: u128 = 0xFFFF_C000_0000_0000_0000;
+
+/// The packed BCD indefinite stored by a masked invalid FBSTP.
+const BCD_INDEFINITE: [u8; 10] = [0, 0, 0, 0, 0, 0, 0, 0xC0, 0xFF, 0xFF];
+
+/// The tag describing an encoded extended-precision value.
+fn tag_of(bits: u128) -> u16 {
+    let exponent = (bits >> 64) as u16 & 0x7FFF;
+    let significand = bits as u64;
+    match exponent {
+        0 if significand == 0 => TAG_ZERO,
+        // Denormals, NaNs, infinities and unsupported encodings
+        0 | 0x7FFF => TAG_SPECIAL,
+        _ if significand >> 63 == 0 => TAG_SPECIAL,
+        _ => TAG_VALID,
+    }
+}
+
+/// The format of a floating-point memory operand of the given size.
+fn memory_format(size: OperandSize) -> Format {
+    match size {
+        OperandSize::R32 => SINGLE,
+        OperandSize::R64 => DOUBLE,
+        _ => EXTENDED,
+    }
+}
+
+fn stack_index(operand: &Operand) -> u8 {
+    match *operand {
+        Operand::Register(Register::St(i), _) => i,
+        _ => unreachable!("{:?} is not an x87 stack register", operand),
+    }
+}
+
+fn st(i: u8) -> Operand {
+    Operand::Register(Register::St(i), OperandSize::R80)
+}
+
+/// Condition codes C3, C2 and C0 for a comparison, as FCOM and FTST set them.
+fn comparison_codes(ordering: Option<Ordering>) -> u16 {
+    match ordering {
+        Some(Ordering::Greater) => 0,
+        Some(Ordering::Less) => C0,
+        Some(Ordering::Equal) => C3,
+        None => C3 | C2 | C0,
+    }
+}
+
+fn bcd_to_int(bytes: &[u8; 10]) -> i128 {
+    let magnitude = bytes[..9].iter().rev().fold(0, |value, &byte| {
+        value * 100 + (byte >> 4) as i128 * 10 + (byte & 0x0F) as i128
+    });
+    if bytes[9] & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+fn int_to_bcd(sign: bool, mut magnitude: u64) -> [u8; 10] {
+    let mut bytes = [0; 10];
+    for byte in &mut bytes[..9] {
+        *byte = (magnitude % 10) as u8 | ((magnitude / 10 % 10) as u8) << 4;
+        magnitude /= 100;
+    }
+    bytes[9] = (sign as u8) << 7;
+    bytes
+}
+
+impl Amd64Interp {
+    /// Resets the x87 unit as FNINIT does.
+    pub(super) fn reset_fpu(&mut self) {
+        let fpu = &mut self.regs.fpu;
+        fpu.fcw = DEFAULT_FCW;
+        fpu.fsw = 0;
+        fpu.ftw = 0xFFFF;
+        fpu.fop = 0;
+        fpu.fip = 0;
+        fpu.fdp = 0;
+        fpu.fcs = 0;
+        fpu.fds = 0;
+    }
+
+    fn top(&self) -> u8 {
+        (self.regs.fpu.fsw >> TOP_SHIFT) as u8 & 0x07
+    }
+
+    pub(super) fn set_top(&mut self, top: u8) {
+        let fsw = &mut self.regs.fpu.fsw;
+        *fsw = (*fsw & !(0x07 << TOP_SHIFT)) | ((top as u16 & 0x07) << TOP_SHIFT);
+    }
+
+    /// The physical register holding ST(i).
+    fn physical(&self, i: u8) -> usize {
+        ((self.top() + i) & 0x07) as usize
+    }
+
+    fn tag(&self, i: u8) -> u16 {
+        (self.regs.fpu.ftw >> (2 * self.physical(i))) & 0x03
+    }
+
+    fn set_tag(&mut self, i: u8, tag: u16) {
+        let shift = 2 * self.physical(i);
+        let ftw = &mut self.regs.fpu.ftw;
+        *ftw = (*ftw & !(0x03 << shift)) | (tag << shift);
+    }
+
+    fn is_empty(&self, i: u8) -> bool {
+        self.tag(i) == TAG_EMPTY
+    }
+
+    /// The encoding of ST(i).
+    fn st_bits(&self, i: u8) -> u128 {
+        let reg = self.regs.fpu.st[self.physical(i)];
+        (reg.sign_exponent as u128) << 64 | reg.significand as u128
+    }
+
+    /// Stores an encoding into ST(i) and tags it to match.
+    fn set_st(&mut self, i: u8, bits: u128) {
+        self.regs.fpu.st[self.physical(i)] = X87Register {
+            significand: bits as u64,
+            sign_exponent: (bits >> 64) as u16,
+            reserved: [0; 3],
+        };
+        self.set_tag(i, tag_of(bits));
+    }
+
+    /// The value of ST(i), raising denormal if it is one.
+    fn st_value(&self, i: u8, env: &mut Env) -> Float {
+        let bits = self.st_bits(i);
+        if EXTENDED.is_denormal(bits) {
+            env.flags |= Flags::DENORMAL;
+        }
+        EXTENDED.unpack(bits)
+    }
+
+    /// Pushes an encoding, or the indefinite after a masked stack overflow.
+    fn x87_push(&mut self, mut bits: u128) {
+        if !self.is_empty(7) {
+            if !self.x87_stack_fault(true) {
+                return;
+            }
+            bits = INDEFINITE;
+        }
+        self.set_top(self.top().wrapping_sub(1));
+        self.set_st(0, bits);
+    }
+
+    fn x87_pop(&mut self) {
+        self.set_tag(0, TAG_EMPTY);
+        self.set_top(self.top() + 1);
+    }
+
+    /// Replaces condition codes C0-C3.
+    fn set_condition_codes(&mut self, codes: u16) {
+        let fsw = &mut self.regs.fpu.fsw;
+        *fsw = (*fsw & !(C0 | C1 | C2 | C3)) | codes;
+    }
+
+    /// The arithmetic environment set by the control word.
+    fn x87_env(&self) -> Env {
+        let fcw = self.regs.fpu.fcw;
+        Env {
+            masked: Flags::from_bits_truncate(fcw as u8),
+            larger_nan: true,
+            ..Env::new(Rounding::from_bits((fcw >> 10) as u32))
+        }
+    }
+
+    /// Significand bits kept by the precision control (24, 53 or 64).
+    fn precision(&self) -> u32 {
+        match (self.regs.fpu.fcw >> 8) & 0x03 {
+            0 => 24,
+            2 => 53,
+            _ => 64,
+        }
+    }
+
+    /// Sets or clears the error summary and busy bits to match the unmasked exceptions
+    /// pending in the status word.
+    fn update_error_summary(&mut self) {
+        let fpu = &mut self.regs.fpu;
+        if fpu.fsw & !fpu.fcw & EXCEPTIONS != 0 {
+            fpu.fsw |= ERROR_SUMMARY | BUSY;
+        } else {
+            fpu.fsw &= !(ERROR_SUMMARY | BUSY);
+        }
+    }
+
+    /// Merges the exceptions an operation raised into the status word, with C1 recording
+    /// whether the result was rounded up. Returns false if an unmasked exception suppresses
+    /// the result: invalid, denormal and divide-by-zero always do, while overflow and
+    /// underflow only do for memory destinations.
+    fn x87_raise(&mut self, env: &Env, memory: bool) -> bool {
+        let fsw = &mut self.regs.fpu.fsw;
+        *fsw |= env.flags.bits() as u16;
+        *fsw = (*fsw & !C1) | if env.rounded_up { C1 } else { 0 };
+        let unmasked = env.flags - env.masked;
+        if unmasked.is_empty() {
+            return true;
+        }
+        *fsw |= ERROR_SUMMARY | BUSY;
+        let suppressing = if memory {
+            Flags::all()
+        } else {
+            Flags::INVALID | Flags::DENORMAL | Flags::DIVIDE_BY_ZERO
+        };
+        !unmasked.intersects(suppressing)
+    }
+
+    /// Signals a stack overflow or underflow: invalid, with SF set and C1 telling which.
+    /// Returns true if the exception is masked, and the destination should take the
+    /// indefinite.
+    fn x87_stack_fault(&mut self, overflow: bool) -> bool {
+        let fpu = &mut self.regs.fpu;
+        fpu.fsw = (fpu.fsw & !C1) | Flags::INVALID.bits() as u16 | STACK_FAULT;
+        if overflow {
+            fpu.fsw |= C1;
+        }
+        if fpu.fcw & Flags::INVALID.bits() as u16 != 0 {
+            return true;
+        }
+        fpu.fsw |= ERROR_SUMMARY | BUSY;
+        false
+    }
+
+    /// Signals a stack underflow if any of the given registers is empty. Returns true if
+    /// they're all in use.
+    fn x87_check(&mut self, registers: &[u8]) -> Result<(), bool> {
+        if registers.iter().all(|&i| !self.is_empty(i)) {
+            return Ok(());
+        }
+        Err(self.x87_stack_fault(false))
+    }
+
+    /// Rounds a result to extended precision with `precision` significand bits. When
+    /// overflow or underflow is unmasked, the result is instead rebiased into range for
+    /// the trap handler, as the x87 does for register destinations.
+    fn x87_round(&self, value: Float, precision: u32, env: &mut Env) -> u128 {
+        const REBIAS: i32 = 24576;
+        let mut scratch = *env;
+        let bits = EXTENDED.pack_with_precision(value, precision, &mut scratch);
+        for (flag, rebias) in [(Flags::OVERFLOW, -REBIAS), (Flags::UNDERFLOW, REBIAS)] {
+            if scratch.flags.contains(flag) && !env.masked.contains(flag) {
+                let bits = EXTENDED.pack_with_precision(value.scale(rebias), precision, env);
+                env.flags |= flag;
+                return bits;
+            }
+        }
+        *env = scratch;
+        bits
+    }
+
+    /// Rounds a result into ST(i) and raises the exceptions it caused. Returns false if an
+    /// unmasked exception kept the result from being stored.
+    fn x87_store(&mut self, i: u8, value: Float, precision: u32, env: &mut Env) -> bool {
+        let bits = self.x87_round(value, precision, env);
+        if !self.x87_raise(env, false) {
+            return false;
+        }
+        self.set_st(i, bits);
+        true
+    }
+
+    /// Reads the encoding of a memory operand.
+    fn x87_read(&mut self, map: &mut dyn MemoryMap, operand: &Operand) -> Result<u128, Exception> {
+        let Operand::Memory(mem, size) = *operand else {
+            unreachable!("{:?} is not a memory operand", operand);
+        };
+        let addr = self.checked_address(&mem, size.bytes(), Access::Read)?;
+        if size == OperandSize::R80 {
+            let mut bytes = [0; 16];
+            self.read_bytes(map, addr, &mut bytes[..10])?;
+            return Ok(u128::from_le_bytes(bytes));
+        }
+        Ok(self.read_memory(map, addr, size)? as u128)
+    }
+
+    fn x87_write(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        operand: &Operand,
+        bits: u128,
+    ) -> Result<(), Exception> {
+        let Operand::Memory(mem, size) = *operand else {
+            unreachable!("{:?} is not a memory operand", operand);
+        };
+        let addr = self.checked_address(&mem, size.bytes(), Access::Write)?;
+        if size == OperandSize::R80 {
+            return self.write_bytes(map, addr, &bits.to_le_bytes()[..10]);
+        }
+        self.write_memory(map, addr, size, bits as u64)
+    }
+
+    /// The value of a source operand: a stack register, or a real or integer in memory.
+    fn x87_source(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        operand: &Operand,
+        integer: bool,
+        env: &mut Env,
+    ) -> Result<Float, Exception> {
+        let Operand::Memory(_, size) = *operand else {
+            return Ok(self.st_value(stack_index(operand), env));
+        };
+        let bits = self.x87_read(map, operand)?;
+        if integer {
+            let shift = 64 - size.bits();
+            return Ok(Float::from_int(
+                ((bits as u64) << shift) as i64 as i128 >> shift,
+            ));
+        }
+        let format = memory_format(size);
+        if format.is_denormal(bits) {
+            env.flags |= Flags::DENORMAL;
+        }
+        Ok(format.unpack(bits))
+    }
+
+    pub(super) fn execute_x87(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let cr0 = self.regs.cr[0];
+        let unavailable = match insn.mnemonic {
+            Mnemonic::Fwait => cr0 & (CR0_MP | CR0_TS) == CR0_MP | CR0_TS,
+            _ => cr0 & (CR0_EM | CR0_TS) != 0,
+        };
+        if unavailable {
+            return Err(Exception::DeviceNotAvailable);
+        }
+        let waiting = !matches!(
+            insn.mnemonic,
+            Mnemonic::Fninit
+                | Mnemonic::Fnclex
+                | Mnemonic::Fnstenv
+                | Mnemonic::Fnsave
+                | Mnemonic::Fnstsw
+                | Mnemonic::Fnstcw
+        );
+        // A pending unmasked exception is reported by the next waiting instruction; CR0.NE
+        // is assumed set, as any 64-bit operating system sets it
+        if waiting && self.regs.fpu.fsw & ERROR_SUMMARY != 0 {
+            return Err(Exception::FloatingPoint);
+        }
+        let control = matches!(
+            insn.mnemonic,
+            Mnemonic::Fwait | Mnemonic::Fldcw | Mnemonic::Fldenv | Mnemonic::Frstor
+        ) || !waiting;
+        if !control {
+            // Remember the instruction for exception handlers, which find it with FNSTENV
+            let fpu = &mut self.regs.fpu;
+            fpu.fip = insn.address;
+            fpu.fcs = self.regs.sr[1];
+            fpu.fop = (insn.opcode as u16 & 0x07) << 8 | insn.modrm.unwrap_or(0) as u16;
+            if let Some(&Operand::Memory(mem, _)) = insn
+                .operands()
+                .iter()
+                .find(|operand| matches!(operand, Operand::Memory(..)))
+            {
+                self.regs.fpu.fdp = self.effective_address(&mem);
+                self.regs.fpu.fds = self.regs.sr[insn.segment.unwrap_or(3) as usize];
+            }
+        }
+
+        match insn.mnemonic {
+            Mnemonic::Fwait | Mnemonic::Fnop => {}
+            Mnemonic::Fld | Mnemonic::Fild | Mnemonic::Fbld => return self.x87_load(map, insn),
+            Mnemonic::Fst
+            | Mnemonic::Fstp
+            | Mnemonic::Fist
+            | Mnemonic::Fistp
+            | Mnemonic::Fisttp
+            | Mnemonic::Fbstp => return self.x87_store_operand(map, insn),
+            Mnemonic::Fld1
+            | Mnemonic::Fldl2t
+            | Mnemonic::Fldl2e
+            | Mnemonic::Fldpi
+            | Mnemonic::Fldlg2
+            | Mnemonic::Fldln2
+            | Mnemonic::Fldz => {
+                let constant = match insn.mnemonic {
+                    Mnemonic::Fld1 => ONE,
+                    Mnemonic::Fldl2t => LOG2_10,
+                    Mnemonic::Fldl2e => LOG2_E,
+                    Mnemonic::Fldpi => PI,
+                    Mnemonic::Fldlg2 => LOG10_2,
+                    Mnemonic::Fldln2 => LN_2,
+                    _ => Float::zero(false),
+                };
+                // Rounded by RC, but never reported as inexact
+                let rounding = self.x87_env().rounding;
+                let bits = EXTENDED.pack(constant, &mut Env::new(rounding));
+                self.set_condition_codes(0);
+                self.x87_push(bits);
+            }
+            Mnemonic::Fxch => {
+                let i = stack_index(&insn.operand(0));
+                if let Err(masked) = self.x87_check(&[0, i]) {
+                    if !masked {
+                        return Ok(());
+                    }
+                    for j in [0, i] {
+                        if self.is_empty(j) {
+                            self.set_st(j, INDEFINITE);
+                        }
+                    }
+                }
+                let (a, b) = (self.st_bits(0), self.st_bits(i));
+                self.set_st(0, b);
+                self.set_st(i, a);
+                self.set_condition_codes(0);
+            }
+            Mnemonic::Fcmovcc => {
+                let i = stack_index(&insn.operand(1));
+                let flag = match insn.condition & !0x01 {
+                    0x2 => flags::CF,
+                    0x4 => flags::ZF,
+                    0x6 => flags::CF | flags::ZF,
+                    _ => flags::PF,
+                };
+                let holds = (self.regs.rflags & flag != 0) != (insn.condition & 0x01 != 0);
+                match self.x87_check(&[0, i]) {
+                    Ok(()) if holds => self.set_st(0, self.st_bits(i)),
+                    Ok(()) => {}
+                    Err(true) => self.set_st(0, INDEFINITE),
+                    Err(false) => {}
+                }
+            }
+            Mnemonic::Fchs | Mnemonic::Fabs => {
+                match self.x87_check(&[0]) {
+                    Ok(()) => {
+                        // Only the sign changes, so NaNs don't raise invalid
+                        let bits = self.st_bits(0);
+                        let sign = 1 << 79;
+                        let bits = match insn.mnemonic {
+                            Mnemonic::Fchs => bits ^ sign,
+                            _ => bits & !sign,
+                        };
+                        self.set_st(0, bits);
+                        self.set_condition_codes(0);
+                    }
+                    Err(true) => self.set_st(0, INDEFINITE),
+                    Err(false) => {}
+                }
+            }
+            Mnemonic::Fxam => {
+                let bits = self.st_bits(0);
+                let exponent = (bits >> 64) as u16 & 0x7FFF;
+                let significand = bits as u64;
+                let integer = significand >> 63 != 0;
+                let class = if self.is_empty(0) {
+                    C3 | C0
+                } else {
+                    match exponent {
+                        0 if significand == 0 => C3,
+                        0 => C3 | C2,
+                        0x7FFF if !integer => 0,
+                        0x7FFF if significand << 1 == 0 => C2 | C0,
+                        0x7FFF => C0,
+                        _ if !integer => 0,
+                        _ => C2,
+                    }
+                };
+                let sign = if bits >> 79 != 0 { C1 } else { 0 };
+                self.set_condition_codes(class | sign);
+            }
+            Mnemonic::Fadd
+            | Mnemonic::Faddp
+            | Mnemonic::Fiadd
+            | Mnemonic::Fsub
+            | Mnemonic::Fsubp
+            | Mnemonic::Fisub
+            | Mnemonic::Fsubr
+            | Mnemonic::Fsubrp
+            | Mnemonic::Fisubr
+            | Mnemonic::Fmul
+            | Mnemonic::Fmulp
+            | Mnemonic::Fimul
+            | Mnemonic::Fdiv
+            | Mnemonic::Fdivp
+            | Mnemonic::Fidiv
+            | Mnemonic::Fdivr
+            | Mnemonic::Fdivrp
+            | Mnemonic::Fidivr => return self.x87_arithmetic(map, insn),
+            Mnemonic::Fcom
+            | Mnemonic::Fcomp
+            | Mnemonic::Fcompp
+            | Mnemonic::Ficom
+            | Mnemonic::Ficomp
+            | Mnemonic::Fucom
+            | Mnemonic::Fucomp
+            | Mnemonic::Fucompp
+            | Mnemonic::Ftst
+            | Mnemonic::Fcomi
+            | Mnemonic::Fcomip
+            | Mnemonic::Fucomi
+            | Mnemonic::Fucomip => return self.x87_compare(map, insn),
+            Mnemonic::Fsqrt
+            | Mnemonic::Frndint
+            | Mnemonic::F2xm1
+            | Mnemonic::Fsin
+            | Mnemonic::Fcos
+            | Mnemonic::Fsincos
+            | Mnemonic::Fptan
+            | Mnemonic::Fxtract => self.x87_unary(insn.mnemonic),
+            Mnemonic::Fscale
+            | Mnemonic::Fprem
+            | Mnemonic::Fprem1
+            | Mnemonic::Fyl2x
+            | Mnemonic::Fyl2xp1
+            | Mnemonic::Fpatan => self.x87_binary(insn.mnemonic),
+            Mnemonic::Fdecstp => {
+                self.set_top(self.top().wrapping_sub(1));
+                self.set_condition_codes(0);
+            }
+            Mnemonic::Fincstp => {
+                self.set_top(self.top() + 1);
+                self.set_condition_codes(0);
+            }
+            Mnemonic::Ffree => self.set_tag(stack_index(&insn.operand(0)), TAG_EMPTY),
+            Mnemonic::Ffreep => {
+                self.set_tag(stack_index(&insn.operand(0)), TAG_EMPTY);
+                self.x87_pop();
+            }
+            Mnemonic::Fninit => self.reset_fpu(),
+            Mnemonic::Fnclex => {
+                self.regs.fpu.fsw &= !(EXCEPTIONS | STACK_FAULT | ERROR_SUMMARY | BUSY);
+            }
+            Mnemonic::Fldcw => {
+                self.regs.fpu.fcw = self.read_operand(map, &insn.operand(0))? as u16;
+                self.update_error_summary();
+            }
+            Mnemonic::Fnstcw => {
+                self.write_operand(map, &insn.operand(0), self.regs.fpu.fcw as u64)?;
+            }
+            Mnemonic::Fnstsw => {
+                self.write_operand(map, &insn.operand(0), self.regs.fpu.fsw as u64)?;
+            }
+            Mnemonic::Fnstenv | Mnemonic::Fnsave | Mnemonic::Fldenv | Mnemonic::Frstor => {
+                return self.x87_state(map, insn)
+            }
+            mnemonic => unreachable!("{} is not an x87 instruction", mnemonic),
+        }
+        Ok(())
+    }
+
+    /// FLD, FILD and FBLD.
+    fn x87_load(&mut self, map: &mut dyn MemoryMap, insn: &Instruction) -> Result<(), Exception> {
+        let operand = insn.operand(0);
+        let mut env = self.x87_env();
+        let bits = match (insn.mnemonic, operand) {
+            (_, Operand::Register(Register::St(i), _)) => {
+                if self.x87_check(&[i]) == Err(false) {
+                    return Ok(());
+                }
+                if self.is_empty(i) {
+                    INDEFINITE
+                } else {
+                    self.st_bits(i)
+                }
+            }
+            // Extended-precision values load unchanged, even signaling NaNs
+            (Mnemonic::Fld, Operand::Memory(_, OperandSize::R80)) => {
+                self.x87_read(map, &operand)?
+            }
+            (Mnemonic::Fbld, _) => {
+                let bytes = self.x87_read(map, &operand)?.to_le_bytes();
+                let value = bcd_to_int(bytes[..10].try_into().unwrap());
+                EXTENDED.pack(Float::from_int(value), &mut env)
+            }
+            _ => {
+                let integer = insn.mnemonic == Mnemonic::Fild;
+                let mut value = self.x87_source(map, &operand, integer, &mut env)?;
+                if value.class == Class::SignalingNaN {
+                    env.flags |= Flags::INVALID;
+                    value = value.quiet();
+                }
+                // Exact: every single, double and 64-bit integer fits
+                let bits = EXTENDED.pack(value, &mut env);
+                if !self.x87_raise(&env, false) {
+                    return Ok(());
+                }
+                bits
+            }
+        };
+        self.set_condition_codes(0);
+        self.x87_push(bits);
+        Ok(())
+    }
+
+    /// FST, FSTP, FIST, FISTP, FISTTP and FBSTP.
+    fn x87_store_operand(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        let operand = insn.operand(0);
+        let pop = insn.mnemonic != Mnemonic::Fst && insn.mnemonic != Mnemonic::Fist;
+        let mut env = self.x87_env();
+        let empty = self.is_empty(0);
+        if empty && !self.x87_stack_fault(false) {
+            return Ok(());
+        }
+        let source = if empty { INDEFINITE } else { self.st_bits(0) };
+        let value = EXTENDED.unpack(source);
+        let size = operand.size().unwrap_or(OperandSize::R80);
+
+        match (insn.mnemonic, operand) {
+            (_, Operand::Register(Register::St(i), _)) => self.set_st(i, source),
+            (Mnemonic::Fst | Mnemonic::Fstp, _) if size == OperandSize::R80 => {
+                self.x87_write(map, &operand, source)?;
+            }
+            (Mnemonic::Fst | Mnemonic::Fstp, _) => {
+                if EXTENDED.is_denormal(source) {
+                    env.flags |= Flags::DENORMAL;
+                }
+                if value.class == Class::SignalingNaN {
+                    env.flags |= Flags::INVALID;
+                }
+                let bits = memory_format(size).pack(value, &mut env);
+                if !self.x87_raise(&env, true) {
+                    return Ok(());
+                }
+                self.x87_write(map, &operand, bits)?;
+            }
+            (Mnemonic::Fbstp, _) => {
+                let rounding = env.rounding;
+                let bytes = match value.to_int(64, rounding, &mut env) {
+                    Some(integer) if integer.unsigned_abs() < 1_000_000_000_000_000_000 => {
+                        int_to_bcd(value.sign, integer.unsigned_abs())
+                    }
+                    _ => {
+                        env.flags |= Flags::INVALID;
+                        BCD_INDEFINITE
+                    }
+                };
+                if !self.x87_raise(&env, true) {
+                    return Ok(());
+                }
+                self.x87_write(map, &operand, u128::from_le_bytes(pad(bytes)))?;
+            }
+            _ => {
+                let rounding = match insn.mnemonic {
+                    Mnemonic::Fisttp => Rounding::TowardZero,
+                    _ => env.rounding,
+                };
+                // Out-of-range values store the integer indefinite, the most negative value
+                let integer = value
+                    .to_int(size.bits(), rounding, &mut env)
+                    .unwrap_or(i64::MIN >> (64 - size.bits()));
+                if !self.x87_raise(&env, true) {
+                    return Ok(());
+                }
+                self.x87_write(map, &operand, integer as u64 as u128 & size.mask() as u128)?;
+            }
+        }
+        if pop {
+            self.x87_pop();
+        }
+        Ok(())
+    }
+
+    /// The two-operand arithmetic instructions, with an optional pop.
+    fn x87_arithmetic(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        let (dst, src) = match insn.operands() {
+            [dst, src] => (stack_index(dst), *src),
+            [src] => (0, *src),
+            _ => unreachable!("x87 arithmetic always has operands"),
+        };
+        let mut registers = vec![dst];
+        if let Operand::Register(Register::St(i), _) = src {
+            registers.push(i);
+        }
+        let stored = match self.x87_check(&registers) {
+            Ok(()) => {
+                let integer = matches!(
+                    insn.mnemonic,
+                    Fiadd | Fisub | Fisubr | Fimul | Fidiv | Fidivr
+                );
+                let mut env = self.x87_env();
+                let a = self.st_value(dst, &mut env);
+                let b = self.x87_source(map, &src, integer, &mut env)?;
+                let env = &mut env;
+                let result = match insn.mnemonic {
+                    Fadd | Faddp | Fiadd => a.add(b, env),
+                    Fsub | Fsubp | Fisub => a.sub(b, env),
+                    Fsubr | Fsubrp | Fisubr => b.sub(a, env),
+                    Fmul | Fmulp | Fimul => a.mul(b, env),
+                    Fdiv | Fdivp | Fidiv => a.div(b, env),
+                    _ => b.div(a, env),
+                };
+                self.x87_store(dst, result, self.precision(), env)
+            }
+            Err(true) => {
+                self.set_st(dst, INDEFINITE);
+                true
+            }
+            Err(false) => false,
+        };
+        if stored
+            && matches!(
+                insn.mnemonic,
+                Faddp | Fsubp | Fsubrp | Fmulp | Fdivp | Fdivrp
+            )
+        {
+            self.x87_pop();
+        }
+        Ok(())
+    }
+
+    /// The comparisons, which set condition codes or (FCOMI and FUCOMI) EFLAGS.
+    fn x87_compare(
+        &mut self,
+        map: &mut dyn MemoryMap,
+        insn: &Instruction,
+    ) -> Result<(), Exception> {
+        use Mnemonic::*;
+        let src = match insn.mnemonic {
+            Ftst => None,
+            Fcompp | Fucompp => Some(st(1)),
+            Fcomi | Fcomip | Fucomi | Fucomip => Some(insn.operand(1)),
+            _ => Some(insn.operand(0)),
+        };
+        let mut registers = vec![0];
+        if let Some(Operand::Register(Register::St(i), _)) = src {
+            registers.push(i);
+        }
+        let ordering = match self.x87_check(&registers) {
+            Ok(()) => {
+                let integer = matches!(insn.mnemonic, Ficom | Ficomp);
+                let mut env = self.x87_env();
+                let a = self.st_value(0, &mut env);
+                let b = match src {
+                    Some(src) => self.x87_source(map, &src, integer, &mut env)?,
+                    None => Float::zero(false),
+                };
+                // The unordered comparisons only object to signaling NaNs
+                let signaling =
+                    !matches!(insn.mnemonic, Fucom | Fucomp | Fucompp | Fucomi | Fucomip);
+                let ordering = a.compare(b, signaling, &mut env);
+                self.x87_raise(&env, false).then_some(ordering)
+            }
+            Err(true) => Some(None),
+            Err(false) => None,
+        };
+        // An unmasked exception leaves the flags and the stack alone
+        let Some(ordering) = ordering else {
+            return Ok(());
+        };
+        match insn.mnemonic {
+            Fcomi | Fcomip | Fucomi | Fucomip => {
+                let status = match ordering {
+                    Some(Ordering::Greater) => 0,
+                    Some(Ordering::Less) => flags::CF,
+                    Some(Ordering::Equal) => flags::ZF,
+                    None => flags::ZF | flags::PF | flags::CF,
+                };
+                self.regs.rflags = (self.regs.rflags & !flags::STATUS) | status;
+                self.set_condition_codes(0);
+            }
+            _ => self.set_condition_codes(comparison_codes(ordering)),
+        }
+        let pops = match insn.mnemonic {
+            Fcompp | Fucompp => 2,
+            Fcomp | Ficomp | Fucomp | Fcomip | Fucomip => 1,
+            _ => 0,
+        };
+        for _ in 0..pops {
+            self.x87_pop();
+        }
+        Ok(())
+    }
+
+    /// Instructions that replace ST(0) with a function of it, some also pushing a second
+    /// result.
+    fn x87_unary(&mut self, mnemonic: Mnemonic) {
+        let pushes = matches!(
+            mnemonic,
+            Mnemonic::Fsincos | Mnemonic::Fptan | Mnemonic::Fxtract
+        );
+        if let Err(masked) = self.x87_check(&[0]) {
+            if masked {
+                self.set_st(0, INDEFINITE);
+                if pushes {
+                    self.x87_push(INDEFINITE);
+                }
+            }
+            return;
+        }
+        if pushes && !self.is_empty(7) {
+            if self.x87_stack_fault(true) {
+                self.set_st(0, INDEFINITE);
+                self.set_top(self.top().wrapping_sub(1));
+                self.set_st(0, INDEFINITE);
+            }
+            return;
+        }
+        let mut env = self.x87_env();
+        let x = self.st_value(0, &mut env);
+        let nan = Float::propagate_nan(&[x], &mut env);
+        let trigonometric = matches!(
+            mnemonic,
+            Mnemonic::Fsin | Mnemonic::Fcos | Mnemonic::Fsincos | Mnemonic::Fptan
+        );
+        let (first, second) = match nan {
+            Some(nan) => (nan, pushes.then_some(nan)),
+            None if trigonometric => {
+                let sin_cos = match x.class {
+                    Class::Infinity => {
+                        let nan = Float::invalid(&mut env);
+                        Some((nan, nan))
+                    }
+                    _ => transcendental::sin_cos(x),
+                };
+                // Arguments of 2^63 or more are left for software to reduce
+                let Some((sin, cos)) = sin_cos else {
+                    self.set_condition_codes(C2);
+                    return;
+                };
+                match mnemonic {
+                    Mnemonic::Fsin => (sin, None),
+                    Mnemonic::Fcos => (cos, None),
+                    Mnemonic::Fsincos => (sin, Some(cos)),
+                    _ if sin.is_nan() => (sin, Some(sin)),
+                    _ => (
+                        sin.div(cos, &mut Env::new(Rounding::NearestEven)),
+                        Some(ONE),
+                    ),
+                }
+            }
+            None => match mnemonic {
+                Mnemonic::Fsqrt => (x.sqrt(&mut env), None),
+                Mnemonic::Frndint => (x.round_to_integral(env.rounding, &mut env), None),
+                Mnemonic::F2xm1 => (transcendental::exp2_minus_one(x), None),
+                // FXTRACT: the exponent, then the significand on top of it
+                _ => match x.class {
+                    Class::Zero => {
+                        env.flags |= Flags::DIVIDE_BY_ZERO;
+                        (Float::infinity(true), Some(x))
+                    }
+                    Class::Infinity => (Float::infinity(false), Some(x)),
+                    _ => {
+                        let exponent = Float::from_int(x.exponent as i128);
+                        (exponent, Some(Float { exponent: 0, ..x }))
+                    }
+                },
+            },
+        };
+        let precision = match mnemonic {
+            Mnemonic::Fsqrt => self.precision(),
+            _ => 64,
+        };
+        let first = self.x87_round(first, precision, &mut env);
+        let second = second.map(|second| self.x87_round(second, precision, &mut env));
+        if !self.x87_raise(&env, false) {
+            return;
+        }
+        self.set_st(0, first);
+        if let Some(second) = second {
+            self.set_top(self.top().wrapping_sub(1));
+            self.set_st(0, second);
+        }
+        if trigonometric {
+            let fsw = &mut self.regs.fpu.fsw;
+            *fsw &= !C2;
+        }
+    }
+
+    /// Instructions combining ST(0) and ST(1): FSCALE and the partial remainders replace
+    /// ST(0), while FYL2X, FYL2XP1 and FPATAN replace ST(1) and pop.
+    fn x87_binary(&mut self, mnemonic: Mnemonic) {
+        let pops = matches!(
+            mnemonic,
+            Mnemonic::Fyl2x | Mnemonic::Fyl2xp1 | Mnemonic::Fpatan
+        );
+        let dst = pops as u8;
+        match self.x87_check(&[0, 1]) {
+            Ok(()) => {}
+            Err(true) => {
+                self.set_st(dst, INDEFINITE);
+                if pops {
+                    self.x87_pop();
+                }
+                return;
+            }
+            Err(false) => return,
+        }
+        let mut env = self.x87_env();
+        let (x, y) = (self.st_value(0, &mut env), self.st_value(1, &mut env));
+        let mut codes = None;
+        let result = match Float::propagate_nan(&[x, y], &mut env) {
+            Some(nan) => nan,
+            None => match mnemonic {
+                Mnemonic::Fscale => transcendental::scale_by(x, y, &mut env),
+                Mnemonic::Fyl2x => transcendental::log2_mul(y, x, &mut env),
+                Mnemonic::Fyl2xp1 => transcendental::log2p1_mul(y, x, &mut env),
+                Mnemonic::Fpatan => transcendental::atan2(y, x),
+                _ => {
+                    let nearest = mnemonic == Mnemonic::Fprem1;
+                    let (remainder, quotient, complete) = x.remainder(y, nearest, &mut env);
+                    // C2 flags a partial reduction; otherwise the low quotient bits are
+                    // reported as C0 = Q2, C3 = Q1 and C1 = Q0
+                    codes = Some(if complete {
+                        [(4, C0), (2, C3), (1, C1)]
+                            .iter()
+                            .filter(|&&(bit, _)| quotient & bit != 0)
+                            .fold(0, |codes, &(_, code)| codes | code)
+                    } else {
+                        C2
+                    });
+                    remainder
+                }
+            },
+        };
+        let bits = self.x87_round(result, 64, &mut env);
+        if !self.x87_raise(&env, false) {
+            return;
+        }
+        self.set_st(dst, bits);
+        if let Some(codes) = codes {
+            self.set_condition_codes(codes);
+        }
+        if pops {
+            self.x87_pop();
+        }
+    }
+
+    /// FNSTENV, FNSAVE, FLDENV and FRSTOR. The environment takes the 28-byte 32-bit
+    /// protected-mode layout, or the 14-byte 16-bit one with an operand-size prefix; the
+    /// saved state adds the registers in stack order.
+    fn x87_state(&mut self, map: &mut dyn MemoryMap, insn: &Instruction) -> Result<(), Exception> {
+        let Operand::Memory(mem, _) = insn.operand(0) else {
+            unreachable!("x87 state operands are in memory");
+        };
+        let wide = insn.operand_size != OperandSize::R16;
+        let env_size = if wide { 28 } else { 14 };
+        let save = matches!(insn.mnemonic, Mnemonic::Fnsave | Mnemonic::Frstor);
+        let access = match insn.mnemonic {
+            Mnemonic::Fnstenv | Mnemonic::Fnsave => Access::Write,
+            _ => Access::Read,
+        };
+        let bytes = env_size + if save { 80 } else { 0 };
+        let addr = self.checked_address(&mem, bytes as u64, access)?;
+
+        if matches!(insn.mnemonic, Mnemonic::Fnstenv | Mnemonic::Fnsave) {
+            let fpu = self.regs.fpu;
+            let mut state = Vec::with_capacity(env_size + 80);
+            if wide {
+                for word in [fpu.fcw, fpu.fsw, fpu.ftw] {
+                    state.extend_from_slice(&(word as u32 | 0xFFFF_0000).to_le_bytes());
+                }
+                state.extend_from_slice(&(fpu.fip as u32).to_le_bytes());
+                state.extend_from_slice(&(fpu.fcs as u32 | (fpu.fop as u32) << 16).to_le_bytes());
+                state.extend_from_slice(&(fpu.fdp as u32).to_le_bytes());
+                state.extend_from_slice(&(fpu.fds as u32 | 0xFFFF_0000).to_le_bytes());
+            } else {
+                for word in [
+                    fpu.fcw,
+                    fpu.fsw,
+                    fpu.ftw,
+                    fpu.fip as u16,
+                    fpu.fcs,
+                    fpu.fdp as u16,
+                    fpu.fds,
+                ] {
+                    state.extend_from_slice(&word.to_le_bytes());
+                }
+            }
+            if save {
+                for i in 0..8 {
+                    state.extend_from_slice(&self.st_bits(i).to_le_bytes()[..10]);
+                }
+            }
+            self.write_bytes(map, addr, &state)?;
+            if save {
+                self.reset_fpu();
+            } else {
+                self.regs.fpu.fcw |= EXCEPTIONS;
+            }
+            return Ok(());
+        }
+
+        let mut state = vec![0; bytes];
+        self.read_bytes(map, addr, &mut state)?;
+        let word = |offset: usize| u16::from_le_bytes([state[offset], state[offset + 1]]);
+        let dword =
+            |offset: usize| u32::from_le_bytes(state[offset..offset + 4].try_into().unwrap());
+        let fpu = &mut self.regs.fpu;
+        if wide {
+            fpu.fcw = word(0);
+            fpu.fsw = word(4);
+            fpu.ftw = word(8);
+            fpu.fip = dword(12) as u64;
+            fpu.fcs = word(16);
+            fpu.fop = word(18) & 0x07FF;
+            fpu.fdp = dword(20) as u64;
+            fpu.fds = word(24);
+        } else {
+            fpu.fcw = word(0);
+            fpu.fsw = word(2);
+            fpu.ftw = word(4);
+            fpu.fip = word(6) as u64;
+            fpu.fcs = word(8);
+            fpu.fdp = word(10) as u64;
+            fpu.fds = word(12);
+        }
+        if save {
+            for i in 0..8 {
+                let offset = env_size + 10 * i as usize;
+                let mut bytes = [0; 16];
+                bytes[..10].copy_from_slice(&state[offset..offset + 10]);
+                let bits = u128::from_le_bytes(bytes);
+                // Keep the loaded tag word rather than retagging from the contents
+                self.regs.fpu.st[self.physical(i)] = X87Register {
+                    significand: bits as u64,
+                    sign_exponent: (bits >> 64) as u16,
+                    reserved: [0; 3],
+                };
+            }
+        }
+        self.update_error_summary();
+        Ok(())
+    }
+
+    /// Fills in the x87 part of an FXSAVE image, its first 160 bytes. `wide` stores 64-bit
+    /// instruction and data pointers, as FXSAVE64 does, rather than selector:offset pairs.
+    pub(super) fn fxsave_x87(&self, image: &mut [u8], wide: bool) {
+        let fpu = self.regs.fpu;
+        image[0..2].copy_from_slice(&fpu.fcw.to_le_bytes());
+        image[2..4].copy_from_slice(&fpu.fsw.to_le_bytes());
+        // The abridged tag word has one bit per physical register, set unless it's empty
+        image[4] = (0..8).fold(0, |tags, i| {
+            tags | (((fpu.ftw >> (2 * i)) & 0x03 != TAG_EMPTY) as u8) << i
+        });
+        image[6..8].copy_from_slice(&fpu.fop.to_le_bytes());
+        if wide {
+            image[8..16].copy_from_slice(&fpu.fip.to_le_bytes());
+            image[16..24].copy_from_slice(&fpu.fdp.to_le_bytes());
+        } else {
+            image[8..12].copy_from_slice(&(fpu.fip as u32).to_le_bytes());
+            image[12..14].copy_from_slice(&fpu.fcs.to_le_bytes());
+            image[16..20].copy_from_slice(&(fpu.fdp as u32).to_le_bytes());
+            image[20..22].copy_from_slice(&fpu.fds.to_le_bytes());
+        }
+        for i in 0..8 {
+            let offset = 32 + 16 * i as usize;
+            image[offset..offset + 10].copy_from_slice(&self.st_bits(i).to_le_bytes()[..10]);
+        }
+    }
+
+    /// Loads the x87 state from an FXSAVE image, retagging each non-empty register from its
+    /// contents.
+    pub(super) fn fxrstor_x87(&mut self, image: &[u8], wide: bool) {
+        let word = |offset: usize| u16::from_le_bytes([image[offset], image[offset + 1]]);
+        let dword =
+            |offset: usize| u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap());
+        let fpu = &mut self.regs.fpu;
+        fpu.fcw = word(0);
+        fpu.fsw = word(2);
+        fpu.fop = word(6) & 0x07FF;
+        if wide {
+            fpu.fip = dword(8) as u64 | (dword(12) as u64) << 32;
+            fpu.fdp = dword(16) as u64 | (dword(20) as u64) << 32;
+        } else {
+            fpu.fip = dword(8) as u64;
+            fpu.fcs = word(12);
+            fpu.fdp = dword(16) as u64;
+            fpu.fds = word(20);
+        }
+        for i in 0..8 {
+            let offset = 32 + 16 * i as usize;
+            let mut bytes = [0; 16];
+            bytes[..10].copy_from_slice(&image[offset..offset + 10]);
+            let bits = u128::from_le_bytes(bytes);
+            self.regs.fpu.st[self.physical(i)] = X87Register {
+                significand: bits as u64,
+                sign_exponent: (bits >> 64) as u16,
+                reserved: [0; 3],
+            };
+        }
+        self.regs.fpu.ftw = (0..8).fold(0, |ftw, i| {
+            let reg = self.regs.fpu.st[i];
+            let tag = match image[4] & (1 << i) {
+                0 => TAG_EMPTY,
+                _ => tag_of((reg.sign_exponent as u128) << 64 | reg.significand as u128),
+            };
+            ftw | tag << (2 * i)
+        });
+        self.update_error_summary();
+    }
+}
+
+/// Widens a 10-byte memory image to the 16 bytes of a `u128`.
+fn pad(bytes: [u8; 10]) -> [u8; 16] {
+    let mut padded = [0; 16];
+    padded[..10].copy_from_slice(&bytes);
+    padded
+}
@@ -0,0 +1,947 @@
+//! Software binary floating point, shared by the x87 and SIMD units.
+//!
+//! Values are unpacked into a [`Float`] with an unbounded exponent and a 128-bit significand,
+//! operated on without rounding, and rounded once when packed back into a [`Format`]. Bits an
+//! operation can't keep are folded into the lowest bit of the significand, which keeps the
+//! final rounding correct for every format up to the x87's 64 bits of precision.
+
+use bitflags::bitflags;
+use std::cmp::Ordering;
+
+pub mod transcendental;
+
+/// Rounding direction, numbered like the RC fields of the x87 control word and MXCSR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    NearestEven,
+    Down,
+    Up,
+    TowardZero,
+}
+
+impl Rounding {
+    /// Decodes a two-bit RC field.
+    pub fn from_bits(bits: u32) -> Rounding {
+        match bits & 3 {
+            0 => Rounding::NearestEven,
+            1 => Rounding::Down,
+            2 => Rounding::Up,
+            _ => Rounding::TowardZero,
+        }
+    }
+}
+
+bitflags! {
+    /// Floating-point exceptions, in the bit order of the x87 status word and MXCSR.
+    pub struct Flags: u8 {
+        const INVALID = 1 << 0;
+        const DENORMAL = 1 << 1;
+        const DIVIDE_BY_ZERO = 1 << 2;
+        const OVERFLOW = 1 << 3;
+        const UNDERFLOW = 1 << 4;
+        const INEXACT = 1 << 5;
+    }
+}
+
+/// The environment an operation runs in, and the exceptions it raised.
+#[derive(Clone, Copy, Debug)]
+pub struct Env {
+    pub rounding: Rounding,
+    /// Exceptions whose traps are masked. An unmasked underflow is signaled for every tiny
+    /// result, a masked one only for tiny and inexact results.
+    pub masked: Flags,
+    /// Replace tiny results with zero when underflow is masked (MXCSR.FTZ)
+    pub flush_to_zero: bool,
+    /// When both operands are NaNs, propagate the one with the larger significand as the
+    /// x87 does, rather than the first one as SSE does
+    pub larger_nan: bool,
+    /// Exceptions raised so far
+    pub flags: Flags,
+    /// Whether the last rounding increased the magnitude of the result (x87 C1)
+    pub rounded_up: bool,
+}
+
+impl Env {
+    /// An environment with every exception masked.
+    pub fn new(rounding: Rounding) -> Env {
+        Env {
+            rounding,
+            masked: Flags::all(),
+            flush_to_zero: false,
+            larger_nan: false,
+            flags: Flags::empty(),
+            rounded_up: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    Zero,
+    Finite,
+    Infinity,
+    QuietNaN,
+    SignalingNaN,
+}
+
+/// An unpacked floating-point value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Float {
+    pub sign: bool,
+    pub class: Class,
+    /// For finite values, the power of two of the significand's top bit
+    pub exponent: i32,
+    /// For finite values, normalized so that bit 127 is set. For NaNs, the payload, with the
+    /// quiet bit at bit 127.
+    pub significand: u128,
+}
+
+/// Shifts right, folding any bits shifted out into the lowest bit.
+fn shift_right_jam(value: u128, shift: u32) -> u128 {
+    match shift {
+        0 => value,
+        1..=127 => value >> shift | (value << (128 - shift) != 0) as u128,
+        _ => (value != 0) as u128,
+    }
+}
+
+/// The full 256-bit product of two 128-bit values, as (high, low).
+fn mul_wide(a: u128, b: u128) -> (u128, u128) {
+    let (a1, a0) = (a >> 64, a & u64::MAX as u128);
+    let (b1, b0) = (b >> 64, b & u64::MAX as u128);
+    let (mid, mid_carry) = (a0 * b1).overflowing_add(a1 * b0);
+    let (low, low_carry) = (a0 * b0).overflowing_add(mid << 64);
+    let high = a1 * b1 + (mid >> 64) + ((mid_carry as u128) << 64) + low_carry as u128;
+    (high, low)
+}
+
+/// Rounds a normalized significand to `bits` significant bits (which may be zero or negative
+/// for values below the rounding point). Returns the rounded integer, whether it's inexact,
+/// and whether it was rounded away from zero.
+fn round_significand(
+    sign: bool,
+    significand: u128,
+    bits: i32,
+    rounding: Rounding,
+) -> (u128, bool, bool) {
+    let (quotient, remainder, half) = match bits {
+        128.. => return (significand, false, false),
+        1..=127 => {
+            let shift = 128 - bits as u32;
+            (
+                significand >> shift,
+                significand & ((1 << shift) - 1),
+                Some(1 << (shift - 1)),
+            )
+        }
+        0 => (0, significand, Some(1 << 127)),
+        _ => (0, significand, None),
+    };
+    if remainder == 0 {
+        return (quotient, false, false);
+    }
+    let up = match rounding {
+        Rounding::NearestEven => {
+            half.is_some_and(|half| remainder > half || (remainder == half && quotient & 1 == 1))
+        }
+        Rounding::Down => sign,
+        Rounding::Up => !sign,
+        Rounding::TowardZero => false,
+    };
+    (quotient + up as u128, true, up)
+}
+
+impl Float {
+    pub const fn zero(sign: bool) -> Float {
+        Float {
+            sign,
+            class: Class::Zero,
+            exponent: 0,
+            significand: 0,
+        }
+    }
+
+    pub const fn infinity(sign: bool) -> Float {
+        Float {
+            sign,
+            class: Class::Infinity,
+            exponent: 0,
+            significand: 0,
+        }
+    }
+
+    /// The NaN invalid operations produce: the x87's "indefinite", negative and quiet with
+    /// an empty payload.
+    pub const fn default_nan() -> Float {
+        Float {
+            sign: true,
+            class: Class::QuietNaN,
+            exponent: 0,
+            significand: 1 << 127,
+        }
+    }
+
+    /// A positive finite constant, `significand * 2^(exponent - 127)`.
+    pub const fn constant(exponent: i32, significand: u128) -> Float {
+        Float {
+            sign: false,
+            class: Class::Finite,
+            exponent,
+            significand,
+        }
+    }
+
+    /// The finite value `significand * 2^(exponent - 127)`, normalized; zero if the
+    /// significand is.
+    pub fn normalized(sign: bool, exponent: i32, significand: u128) -> Float {
+        if significand == 0 {
+            return Float::zero(sign);
+        }
+        let shift = significand.leading_zeros();
+        Float {
+            sign,
+            class: Class::Finite,
+            exponent: exponent - shift as i32,
+            significand: significand << shift,
+        }
+    }
+
+    /// An integer, exactly.
+    pub fn from_int(value: i128) -> Float {
+        Float::normalized(value < 0, 127, value.unsigned_abs())
+    }
+
+    pub fn is_nan(self) -> bool {
+        matches!(self.class, Class::QuietNaN | Class::SignalingNaN)
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.class == Class::Zero
+    }
+
+    pub fn is_infinite(self) -> bool {
+        self.class == Class::Infinity
+    }
+
+    pub fn quiet(self) -> Float {
+        match self.class {
+            Class::QuietNaN | Class::SignalingNaN => Float {
+                class: Class::QuietNaN,
+                significand: self.significand | 1 << 127,
+                ..self
+            },
+            _ => self,
+        }
+    }
+
+    pub fn negate(self) -> Float {
+        Float {
+            sign: !self.sign,
+            ..self
+        }
+    }
+
+    pub fn abs(self) -> Float {
+        Float {
+            sign: false,
+            ..self
+        }
+    }
+
+    /// Multiplies a finite value by `2^n` exactly; rounding happens when it's packed.
+    pub fn scale(self, n: i32) -> Float {
+        match self.class {
+            Class::Finite => Float {
+                exponent: self.exponent.saturating_add(n),
+                ..self
+            },
+            _ => self,
+        }
+    }
+
+    /// The result of an operation with a NaN operand: the operand quieted, raising invalid
+    /// for a signaling NaN. None if no operand is a NaN.
+    pub fn propagate_nan(operands: &[Float], env: &mut Env) -> Option<Float> {
+        if operands
+            .iter()
+            .any(|operand| operand.class == Class::SignalingNaN)
+        {
+            env.flags |= Flags::INVALID;
+        }
+        let mut nans = operands.iter().filter(|operand| operand.is_nan());
+        let first = *nans.next()?;
+        let result = match (env.larger_nan, nans.next()) {
+            (true, Some(&second)) => {
+                // A quiet NaN wins over a signaling one, and otherwise the larger payload
+                let key = |nan: Float| (nan.class == Class::QuietNaN, nan.significand | 1 << 127);
+                if key(second) > key(first) {
+                    second
+                } else {
+                    first
+                }
+            }
+            _ => first,
+        };
+        Some(result.quiet())
+    }
+
+    /// Raises invalid and returns the default NaN.
+    pub fn invalid(env: &mut Env) -> Float {
+        env.flags |= Flags::INVALID;
+        Float::default_nan()
+    }
+
+    /// The sign of an exact zero sum of operands with opposite signs.
+    fn zero_sum(env: &Env) -> Float {
+        Float::zero(env.rounding == Rounding::Down)
+    }
+
+    pub fn add(self, other: Float, env: &mut Env) -> Float {
+        if let Some(nan) = Float::propagate_nan(&[self, other], env) {
+            return nan;
+        }
+        match (self.class, other.class) {
+            (Class::Infinity, Class::Infinity) if self.sign != other.sign => Float::invalid(env),
+            (Class::Infinity, _) => self,
+            (_, Class::Infinity) => other,
+            (Class::Zero, Class::Zero) if self.sign != other.sign => Float::zero_sum(env),
+            (Class::Zero, _) => other,
+            (_, Class::Zero) => self,
+            _ => {
+                let (big, small) =
+                    if (self.exponent, self.significand) >= (other.exponent, other.significand) {
+                        (self, other)
+                    } else {
+                        (other, self)
+                    };
+                // Keep a bit of headroom for the carry out of the sum
+                let x = shift_right_jam(big.significand, 1);
+                let distance = (big.exponent as i64 - small.exponent as i64).min(256) as u32;
+                let y = shift_right_jam(small.significand, distance + 1);
+                if big.sign == small.sign {
+                    Float::normalized(big.sign, big.exponent + 1, x + y)
+                } else if x == y {
+                    Float::zero_sum(env)
+                } else {
+                    Float::normalized(big.sign, big.exponent + 1, x - y)
+                }
+            }
+        }
+    }
+
+    pub fn sub(self, other: Float, env: &mut Env) -> Float {
+        // Negating first would flip the sign of a propagated NaN
+        if let Some(nan) = Float::propagate_nan(&[self, other], env) {
+            return nan;
+        }
+        self.add(other.negate(), env)
+    }
+
+    pub fn mul(self, other: Float, env: &mut Env) -> Float {
+        if let Some(nan) = Float::propagate_nan(&[self, other], env) {
+            return nan;
+        }
+        let sign = self.sign != other.sign;
+        match (self.class, other.class) {
+            (Class::Infinity, Class::Zero) | (Class::Zero, Class::Infinity) => Float::invalid(env),
+            (Class::Infinity, _) | (_, Class::Infinity) => Float::infinity(sign),
+            (Class::Zero, _) | (_, Class::Zero) => Float::zero(sign),
+            _ => {
+                let (high, low) = mul_wide(self.significand, other.significand);
+                Float::normalized(
+                    sign,
+                    self.exponent + other.exponent + 1,
+                    high | (low != 0) as u128,
+                )
+            }
+        }
+    }
+
+    pub fn div(self, other: Float, env: &mut Env) -> Float {
+        if let Some(nan) = Float::propagate_nan(&[self, other], env) {
+            return nan;
+        }
+        let sign = self.sign != other.sign;
+        match (self.class, other.class) {
+            (Class::Infinity, Class::Infinity) | (Class::Zero, Class::Zero) => Float::invalid(env),
+            (Class::Infinity, _) | (_, Class::Zero) => {
+                if self.class != Class::Infinity {
+                    env.flags |= Flags::DIVIDE_BY_ZERO;
+                }
+                Float::infinity(sign)
+            }
+            (Class::Zero, _) | (_, Class::Infinity) => Float::zero(sign),
+            _ => {
+                // Restoring division, one quotient bit per step starting with the units bit
+                let (divisor, mut remainder) = (other.significand, self.significand);
+                let (mut quotient, mut carry) = (0u128, false);
+                for _ in 0..128 {
+                    quotient <<= 1;
+                    if carry || remainder >= divisor {
+                        remainder = remainder.wrapping_sub(divisor);
+                        quotient |= 1;
+                    }
+                    carry = remainder >> 127 != 0;
+                    remainder <<= 1;
+                }
+                let sticky = (remainder != 0 || carry) as u128;
+                Float::normalized(sign, self.exponent - other.exponent, quotient | sticky)
+            }
+        }
+    }
+
+    pub fn sqrt(self, env: &mut Env) -> Float {
+        if let Some(nan) = Float::propagate_nan(&[self], env) {
+            return nan;
+        }
+        match self.class {
+            Class::Zero => self,
+            _ if self.sign => Float::invalid(env),
+            Class::Infinity => self,
+            _ => {
+                // Digit-by-digit square root of the significand shifted up to an even power
+                // of two, 124 bits of root and a sticky bit from the remainder
+                let odd = self.exponent.rem_euclid(2) as u32;
+                let shift = 119 + odd;
+                let radicand = [self.significand >> (128 - shift), self.significand << shift];
+                let bit = |n: u32| {
+                    let word = if n >= 128 { radicand[0] } else { radicand[1] };
+                    (word >> (n % 128)) & 1
+                };
+                let (mut root, mut remainder) = (0u128, 0u128);
+                for k in 0..124 {
+                    let top = 247 - 2 * k;
+                    remainder = remainder << 2 | bit(top) << 1 | bit(top - 1);
+                    let trial = root << 2 | 1;
+                    root <<= 1;
+                    if remainder >= trial {
+                        remainder -= trial;
+                        root |= 1;
+                    }
+                }
+                let exponent = (self.exponent - 246 - odd as i32) / 2 + 123;
+                Float::normalized(false, exponent, root << 4 | (remainder != 0) as u128)
+            }
+        }
+    }
+
+    /// `self * b + c` with a single rounding.
+    pub fn mul_add(self, b: Float, c: Float, env: &mut Env) -> Float {
+        let product_invalid = matches!(
+            (self.class, b.class),
+            (Class::Infinity, Class::Zero) | (Class::Zero, Class::Infinity)
+        );
+        if let Some(nan) = Float::propagate_nan(&[self, b, c], env) {
+            if product_invalid {
+                env.flags |= Flags::INVALID;
+            }
+            return nan;
+        }
+        // The product of two significands of at most 64 bits is exact in 128
+        let product = self.mul(b, env);
+        if product.is_zero() && c.is_zero() && product.sign != c.sign {
+            return Float::zero_sum(env);
+        }
+        product.add(c, env)
+    }
+
+    /// Rounds to an integral value in the given direction, raising inexact if that changes
+    /// it.
+    pub fn round_to_integral(self, rounding: Rounding, env: &mut Env) -> Float {
+        if let Some(nan) = Float::propagate_nan(&[self], env) {
+            return nan;
+        }
+        if self.class != Class::Finite || self.exponent >= 127 {
+            return self;
+        }
+        let (integer, inexact, _) =
+            round_significand(self.sign, self.significand, self.exponent + 1, rounding);
+        if inexact {
+            env.flags |= Flags::INEXACT;
+        }
+        Float::normalized(self.sign, 127, integer)
+    }
+
+    /// Converts to a signed integer of `bits` bits, rounding in the given direction. NaNs,
+    /// infinities and out-of-range values raise invalid and return None.
+    pub fn to_int(self, bits: u32, rounding: Rounding, env: &mut Env) -> Option<i64> {
+        match self.class {
+            Class::Zero => return Some(0),
+            Class::Finite if self.exponent < 127 => {}
+            _ => {
+                env.flags |= Flags::INVALID;
+                return None;
+            }
+        }
+        let (integer, inexact, _) =
+            round_significand(self.sign, self.significand, self.exponent + 1, rounding);
+        let limit = 1u128 << (bits - 1);
+        if integer > limit || (integer == limit && !self.sign) {
+            env.flags |= Flags::INVALID;
+            return None;
+        }
+        if inexact {
+            env.flags |= Flags::INEXACT;
+        }
+        let value = integer as i128;
+        Some(if self.sign { -value } else { value } as i64)
+    }
+
+    /// Converts to an unsigned integer of `bits` bits, rounding in the given direction. NaNs,
+    /// infinities and out-of-range values, negative ones that don't round to zero among
+    /// them, raise invalid and return None.
+    pub fn to_uint(self, bits: u32, rounding: Rounding, env: &mut Env) -> Option<u64> {
+        match self.class {
+            Class::Zero => return Some(0),
+            Class::Finite if self.exponent < 127 => {}
+            _ => {
+                env.flags |= Flags::INVALID;
+                return None;
+            }
+        }
+        let (integer, inexact, _) =
+            round_significand(self.sign, self.significand, self.exponent + 1, rounding);
+        if (self.sign && integer != 0) || integer >> bits != 0 {
+            env.flags |= Flags::INVALID;
+            return None;
+        }
+        if inexact {
+            env.flags |= Flags::INEXACT;
+        }
+        Some(integer as u64)
+    }
+
+    /// Compares two values. NaNs are unordered and raise invalid if they're signaling, or if
+    /// `signaling` is set for any NaN.
+    pub fn compare(self, other: Float, signaling: bool, env: &mut Env) -> Option<Ordering> {
+        if self.is_nan() || other.is_nan() {
+            if signaling || self.class == Class::SignalingNaN || other.class == Class::SignalingNaN
+            {
+                env.flags |= Flags::INVALID;
+            }
+            return None;
+        }
+        if self.is_zero() && other.is_zero() {
+            return Some(Ordering::Equal);
+        }
+        if self.sign != other.sign {
+            return Some(if self.sign {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            });
+        }
+        let magnitude = |value: Float| match value.class {
+            Class::Zero => (0, 0, 0),
+            Class::Finite => (1, value.exponent as i64, value.significand),
+            _ => (2, 0, 0),
+        };
+        let ordering = magnitude(self).cmp(&magnitude(other));
+        Some(if self.sign {
+            ordering.reverse()
+        } else {
+            ordering
+        })
+    }
+
+    /// The x87 partial remainder of `self / other`: with a truncated quotient for FPREM or
+    /// a rounded one for FPREM1. Exponents more than 63 apart only reduce the dividend
+    /// partway, and the result says whether the reduction is complete along with the low
+    /// bits of the quotient.
+    pub fn remainder(self, other: Float, nearest: bool, env: &mut Env) -> (Float, u64, bool) {
+        if let Some(nan) = Float::propagate_nan(&[self, other], env) {
+            return (nan, 0, true);
+        }
+        match (self.class, other.class) {
+            (Class::Infinity, _) | (_, Class::Zero) => return (Float::invalid(env), 0, true),
+            (Class::Zero, _) | (_, Class::Infinity) => return (self, 0, true),
+            _ => {}
+        }
+        // Both significands have at most 64 bits
+        let (a, b) = (self.significand >> 64, other.significand >> 64);
+        let distance = self.exponent - other.exponent;
+        if distance >= 64 {
+            let remainder = (a << 63) % b;
+            let exponent = self.exponent - 126 + 127;
+            return (Float::normalized(self.sign, exponent, remainder), 0, false);
+        }
+        if distance < -1 || (distance == -1 && !nearest) {
+            return (self, 0, true);
+        }
+        let (dividend, divisor, unit) = if distance >= 0 {
+            (a << distance, b, other.exponent - 63)
+        } else {
+            (a, b << 1, self.exponent - 63)
+        };
+        let (mut quotient, mut remainder, mut sign) =
+            (dividend / divisor, dividend % divisor, self.sign);
+        if nearest && (remainder * 2 > divisor || (remainder * 2 == divisor && quotient & 1 == 1)) {
+            remainder = divisor - remainder;
+            quotient += 1;
+            sign = !sign;
+        }
+        let result = match remainder {
+            0 => Float::zero(self.sign),
+            _ => Float::normalized(sign, unit + 127, remainder),
+        };
+        (result, quotient as u64, true)
+    }
+}
+
+/// A binary interchange format, or the x87's 80-bit extended format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Format {
+    pub exponent_bits: u32,
+    /// Significand bits, including the integer bit
+    pub precision: u32,
+    /// Whether the integer bit is stored rather than implied
+    pub explicit_integer: bool,
+}
+
+pub const HALF: Format = Format {
+    exponent_bits: 5,
+    precision: 11,
+    explicit_integer: false,
+};
+
+pub const SINGLE: Format = Format {
+    exponent_bits: 8,
+    precision: 24,
+    explicit_integer: false,
+};
+
+pub const DOUBLE: Format = Format {
+    exponent_bits: 11,
+    precision: 53,
+    explicit_integer: false,
+};
+
+pub const EXTENDED: Format = Format {
+    exponent_bits: 15,
+    precision: 64,
+    explicit_integer: true,
+};
+
+impl Format {
+    fn bias(self) -> i32 {
+        (1 << (self.exponent_bits - 1)) - 1
+    }
+
+    /// The smallest exponent of a normal value.
+    pub fn emin(self) -> i32 {
+        1 - self.bias()
+    }
+
+    /// The largest exponent of a finite value.
+    pub fn emax(self) -> i32 {
+        self.bias()
+    }
+
+    fn fraction_bits(self) -> u32 {
+        self.precision - 1
+    }
+
+    /// Width of the stored significand field.
+    fn significand_bits(self) -> u32 {
+        self.fraction_bits() + self.explicit_integer as u32
+    }
+
+    fn max_exponent_field(self) -> u128 {
+        (1 << self.exponent_bits) - 1
+    }
+
+    fn fields(self, bits: u128) -> (bool, u128, u128) {
+        let significand_bits = self.significand_bits();
+        let sign = (bits >> (significand_bits + self.exponent_bits)) & 1 != 0;
+        let exponent = (bits >> significand_bits) & self.max_exponent_field();
+        (sign, exponent, bits & ((1 << significand_bits) - 1))
+    }
+
+    /// Assembles an encoding; `significand` has its integer bit at `precision - 1`, which is
+    /// dropped for formats that imply it.
+    fn encode(self, sign: bool, exponent: u128, significand: u128) -> u128 {
+        let significand_bits = self.significand_bits();
+        let stored = significand & ((1 << significand_bits) - 1);
+        (sign as u128) << (significand_bits + self.exponent_bits)
+            | exponent << significand_bits
+            | stored
+    }
+
+    /// Whether `bits` encodes a denormal, including the x87's pseudo-denormals.
+    pub fn is_denormal(self, bits: u128) -> bool {
+        let (_, exponent, significand) = self.fields(bits);
+        exponent == 0 && significand != 0
+    }
+
+    pub fn unpack(self, bits: u128) -> Float {
+        let (sign, exponent, stored) = self.fields(bits);
+        let fraction_bits = self.fraction_bits();
+        let fraction = stored & ((1 << fraction_bits) - 1);
+        let integer = match self.explicit_integer {
+            true => stored >> fraction_bits != 0,
+            false => exponent != 0,
+        };
+        // Extended encodings whose integer bit contradicts the exponent (pseudo-NaNs,
+        // pseudo-infinities and unnormals) are unsupported, and treated as signaling NaNs
+        // that quiet to the default NaN
+        if self.explicit_integer && exponent != 0 && !integer {
+            return Float {
+                class: Class::SignalingNaN,
+                significand: 0,
+                ..Float::default_nan()
+            };
+        }
+        if exponent == self.max_exponent_field() {
+            if fraction == 0 {
+                return Float::infinity(sign);
+            }
+            let significand = fraction << (128 - fraction_bits);
+            return Float {
+                sign,
+                class: match significand >> 127 {
+                    1 => Class::QuietNaN,
+                    _ => Class::SignalingNaN,
+                },
+                exponent: 0,
+                significand,
+            };
+        }
+        let significand = (integer as u128) << fraction_bits | fraction;
+        let exponent = (exponent as i32).max(1) - self.bias();
+        Float::normalized(sign, exponent + 127 - fraction_bits as i32, significand)
+    }
+
+    /// Rounds `value` into this format according to `env`.
+    pub fn pack(self, value: Float, env: &mut Env) -> u128 {
+        self.pack_with_precision(value, self.precision, env)
+    }
+
+    /// Rounds `value` to `precision` significand bits, which may be fewer than the format
+    /// holds (the x87 precision control), within this format's exponent range.
+    pub fn pack_with_precision(self, value: Float, precision: u32, env: &mut Env) -> u128 {
+        env.rounded_up = false;
+        let max_exponent = self.max_exponent_field();
+        let integer_bit = (self.explicit_integer as u128) << self.fraction_bits();
+        match value.class {
+            Class::Zero => self.encode(value.sign, 0, 0),
+            Class::Infinity => self.encode(value.sign, max_exponent, integer_bit),
+            Class::QuietNaN | Class::SignalingNaN => {
+                let fraction = (value.significand | 1 << 127) >> (128 - self.fraction_bits());
+                self.encode(value.sign, max_exponent, integer_bit | fraction)
+            }
+            Class::Finite => self.pack_finite(value, precision, env),
+        }
+    }
+
+    fn pack_finite(self, value: Float, precision: u32, env: &mut Env) -> u128 {
+        let sign = value.sign;
+        let (emin, emax) = (self.emin(), self.emax());
+        // Tininess is detected after rounding, as if the exponent range were unbounded
+        let (rounded, mut inexact, mut up) =
+            round_significand(sign, value.significand, precision as i32, env.rounding);
+        let carry = rounded >> precision != 0;
+        let exponent = value.exponent + carry as i32;
+        if exponent > emax {
+            env.flags |= Flags::OVERFLOW | Flags::INEXACT;
+            let to_infinity = match env.rounding {
+                Rounding::NearestEven => true,
+                Rounding::Down => sign,
+                Rounding::Up => !sign,
+                Rounding::TowardZero => false,
+            };
+            env.rounded_up = to_infinity;
+            return if to_infinity {
+                let integer_bit = (self.explicit_integer as u128) << self.fraction_bits();
+                self.encode(sign, self.max_exponent_field(), integer_bit)
+            } else {
+                let largest = ((1 << precision) - 1) << (self.precision - precision);
+                self.encode(sign, (emax + self.bias()) as u128, largest)
+            };
+        }
+        let tiny = exponent < emin;
+        let encoded = if value.exponent >= emin {
+            let rounded = if carry { rounded >> 1 } else { rounded };
+            let significand = rounded << (self.precision - precision);
+            self.encode(sign, (exponent + self.bias()) as u128, significand)
+        } else {
+            if tiny && env.flush_to_zero && env.masked.contains(Flags::UNDERFLOW) {
+                env.flags |= Flags::UNDERFLOW | Flags::INEXACT;
+                return self.encode(sign, 0, 0);
+            }
+            // Denormals hold fewer significant bits the smaller they get
+            let available = self.precision as i32 - (emin - value.exponent);
+            let bits = available.min(precision as i32);
+            let denormal;
+            (denormal, inexact, up) =
+                round_significand(sign, value.significand, bits, env.rounding);
+            let fraction = denormal << (available - bits);
+            if tiny && (inexact || !env.masked.contains(Flags::UNDERFLOW)) {
+                env.flags |= Flags::UNDERFLOW;
+            }
+            // Rounding up can carry into the smallest normal
+            let exponent = (fraction >> self.fraction_bits() != 0) as u128;
+            self.encode(sign, exponent, fraction)
+        };
+        if inexact {
+            env.flags |= Flags::INEXACT;
+        }
+        env.rounded_up = up;
+        encoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nearest() -> Env {
+        Env::new(Rounding::NearestEven)
+    }
+
+    fn double(value: f64) -> Float {
+        DOUBLE.unpack(value.to_bits() as u128)
+    }
+
+    fn to_double(value: Float, env: &mut Env) -> f64 {
+        f64::from_bits(DOUBLE.pack(value, env) as u64)
+    }
+
+    #[test]
+    fn arithmetic_matches_the_host() {
+        let values = [1.0, -2.5, 3.0e-310, 1.0e308, 0.1, 7.0, -0.0, 1.0 / 3.0];
+        for &a in &values {
+            for &b in &values {
+                let mut env = nearest();
+                let (x, y) = (double(a), double(b));
+                assert_eq!(to_double(x.add(y, &mut env), &mut env), a + b, "{a} + {b}");
+                assert_eq!(to_double(x.sub(y, &mut env), &mut env), a - b, "{a} - {b}");
+                assert_eq!(to_double(x.mul(y, &mut env), &mut env), a * b, "{a} * {b}");
+                let quotient = to_double(x.div(y, &mut env), &mut env);
+                assert!(
+                    quotient == a / b || (quotient.is_nan() && (a / b).is_nan()),
+                    "{a} / {b}"
+                );
+            }
+            let mut env = nearest();
+            let root = to_double(double(a).sqrt(&mut env), &mut env);
+            assert!(root == a.sqrt() || (root.is_nan() && a < 0.0), "sqrt {a}");
+        }
+    }
+
+    #[test]
+    fn mul_add_rounds_once() {
+        let (a, b, c) = (1.0 + f64::EPSILON, 1.0 - f64::EPSILON, -1.0);
+        let mut env = nearest();
+        let result = double(a).mul_add(double(b), double(c), &mut env);
+        assert_eq!(to_double(result, &mut env), a.mul_add(b, c));
+        assert_ne!(a.mul_add(b, c), a * b + c);
+    }
+
+    #[test]
+    fn rounding_directions_and_flags() {
+        let third = double(1.0).div(double(3.0), &mut nearest());
+        let mut down = Env::new(Rounding::Down);
+        let mut up = Env::new(Rounding::Up);
+        let (low, high) = (SINGLE.pack(third, &mut down), SINGLE.pack(third, &mut up));
+        assert_eq!(high, low + 1);
+        assert!(up.rounded_up && !down.rounded_up);
+        assert_eq!(up.flags, Flags::INEXACT);
+
+        let mut env = nearest();
+        let huge = SINGLE.pack(double(1.0e300), &mut env);
+        assert_eq!(f32::from_bits(huge as u32), f32::INFINITY);
+        assert_eq!(env.flags, Flags::OVERFLOW | Flags::INEXACT);
+
+        let mut env = Env::new(Rounding::TowardZero);
+        let huge = SINGLE.pack(double(1.0e300), &mut env);
+        assert_eq!(f32::from_bits(huge as u32), f32::MAX);
+
+        let mut env = nearest();
+        let tiny = SINGLE.pack(double(1.0e-40), &mut env);
+        assert_eq!(f32::from_bits(tiny as u32), 1.0e-40f32);
+        assert_eq!(env.flags, Flags::UNDERFLOW | Flags::INEXACT);
+
+        env.flush_to_zero = true;
+        assert_eq!(SINGLE.pack(double(-1.0e-40), &mut env), 0x8000_0000);
+    }
+
+    #[test]
+    fn extended_encodings() {
+        let one = EXTENDED.unpack(0x3FFF_8000_0000_0000_0000);
+        assert_eq!(one, Float::from_int(1));
+        // An unnormal is unsupported
+        assert_eq!(
+            EXTENDED.unpack(0x3FFF_4000_0000_0000_0000).quiet(),
+            Float::default_nan()
+        );
+        assert_eq!(
+            EXTENDED.pack(Float::default_nan(), &mut nearest()),
+            0xFFFF_C000_0000_0000_0000
+        );
+        // A pseudo-denormal is the same value as the smallest normal
+        let pseudo = EXTENDED.unpack(0x0000_8000_0000_0000_0000);
+        assert!(EXTENDED.is_denormal(0x0000_8000_0000_0000_0000));
+        assert_eq!(
+            EXTENDED.pack(pseudo, &mut nearest()),
+            0x0001_8000_0000_0000_0000
+        );
+    }
+
+    #[test]
+    fn conversions_to_integers() {
+        let mut env = nearest();
+        assert_eq!(
+            double(2.5).to_int(32, Rounding::NearestEven, &mut env),
+            Some(2)
+        );
+        assert_eq!(double(-2.5).to_int(32, Rounding::Down, &mut env), Some(-3));
+        assert_eq!(env.flags, Flags::INEXACT);
+        assert_eq!(
+            double(2147483648.0).to_int(32, Rounding::NearestEven, &mut env),
+            None
+        );
+        assert_eq!(
+            double(-2147483648.0).to_int(32, Rounding::NearestEven, &mut env),
+            Some(i32::MIN as i64)
+        );
+        assert!(env.flags.contains(Flags::INVALID));
+    }
+
+    #[test]
+    fn conversions_to_unsigned_integers() {
+        let mut env = nearest();
+        assert_eq!(
+            double(4294967295.0).to_uint(32, Rounding::NearestEven, &mut env),
+            Some(u32::MAX as u64)
+        );
+        // Negative values are fine as long as they round to zero
+        assert_eq!(
+            double(-0.25).to_uint(32, Rounding::NearestEven, &mut env),
+            Some(0)
+        );
+        assert_eq!(env.flags, Flags::INEXACT);
+        assert_eq!(double(-0.25).to_uint(32, Rounding::Down, &mut env), None);
+        assert!(env.flags.contains(Flags::INVALID));
+        let mut env = nearest();
+        assert_eq!(
+            double(4294967296.0).to_uint(32, Rounding::NearestEven, &mut env),
+            None
+        );
+        assert_eq!(
+            double(18446744073709549568.0).to_uint(64, Rounding::NearestEven, &mut env),
+            Some(18446744073709549568)
+        );
+        assert_eq!(env.flags, Flags::INVALID);
+    }
+
+    #[test]
+    fn partial_remainders() {
+        let mut env = nearest();
+        let (r, q, complete) = double(7.0).remainder(double(2.0), false, &mut env);
+        assert_eq!((to_double(r, &mut env), q, complete), (1.0, 3, true));
+        let (r, q, _) = double(7.0).remainder(double(2.0), true, &mut env);
+        assert_eq!((to_double(r, &mut env), q), (-1.0, 4));
+        let (_, _, complete) = double(1.0e30).remainder(double(3.0), false, &mut env);
+        assert!(!complete);
+    }
+}
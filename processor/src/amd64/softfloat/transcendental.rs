@@ -0,0 +1,344 @@
+//! Elementary functions for the x87 transcendental instructions.
+//!
+//! Each is evaluated with the unrounded 128-bit arithmetic of the parent module, by argument
+//! reduction and a power series, so the only rounding that matters is the final one into
+//! the destination.
+
+use super::{Class, Env, Flags, Float, Rounding};
+use std::cmp::Ordering;
+
+pub const ONE: Float = Float::constant(0, 1 << 127);
+pub const PI: Float = Float::constant(1, 0xC90F_DAA2_2168_C234_C4C6_628B_80DC_1CD1);
+pub const LN_2: Float = Float::constant(-1, 0xB172_17F7_D1CF_79AB_C9E3_B398_03F2_F6AF);
+pub const LOG2_E: Float = Float::constant(0, 0xB8AA_3B29_5C17_F0BB_BE87_FED0_691D_3E89);
+pub const LOG2_10: Float = Float::constant(1, 0xD49A_784B_CD1B_8AFE_492B_F6FF_4DAF_DB4D);
+pub const LOG10_2: Float = Float::constant(-2, 0x9A20_9A84_FBCF_F798_8F89_59AC_0B7C_9178);
+const SQRT_2: Float = Float::constant(0, 0xB504_F333_F9DE_6484_597D_89B3_754A_BE9F);
+
+/// Terms of a series that can't affect the sum's top 130 bits.
+fn negligible(term: Float, sum: Float) -> bool {
+    term.is_zero() || (!sum.is_zero() && term.exponent < sum.exponent - 130)
+}
+
+// Intermediate steps only ever see finite operands, so they need no exception state
+fn add(a: Float, b: Float) -> Float {
+    a.add(b, &mut Env::new(Rounding::NearestEven))
+}
+
+fn sub(a: Float, b: Float) -> Float {
+    a.sub(b, &mut Env::new(Rounding::NearestEven))
+}
+
+fn mul(a: Float, b: Float) -> Float {
+    a.mul(b, &mut Env::new(Rounding::NearestEven))
+}
+
+fn div(a: Float, b: Float) -> Float {
+    a.div(b, &mut Env::new(Rounding::NearestEven))
+}
+
+fn sin_series(r: Float) -> Float {
+    let square = mul(r, r);
+    let (mut term, mut sum) = (r, r);
+    for n in (1..200).step_by(2) {
+        term = div(mul(term, square), Float::from_int((n + 1) * (n + 2))).negate();
+        if negligible(term, sum) {
+            break;
+        }
+        sum = add(sum, term);
+    }
+    sum
+}
+
+fn cos_series(r: Float) -> Float {
+    let square = mul(r, r);
+    let (mut term, mut sum) = (ONE, ONE);
+    for n in (0..200).step_by(2) {
+        term = div(mul(term, square), Float::from_int((n + 1) * (n + 2))).negate();
+        if negligible(term, sum) {
+            break;
+        }
+        sum = add(sum, term);
+    }
+    sum
+}
+
+/// Sine and cosine of a finite value, or None if its magnitude is 2^63 or more, which the
+/// x87 refuses to reduce.
+pub fn sin_cos(x: Float) -> Option<(Float, Float)> {
+    if x.is_zero() {
+        return Some((x, ONE));
+    }
+    if x.exponent >= 63 {
+        return None;
+    }
+    // Reduce to [-pi/4, pi/4] around the nearest multiple of pi/2
+    let half_pi = PI.scale(-1);
+    let env = &mut Env::new(Rounding::NearestEven);
+    let multiple = div(x, half_pi).round_to_integral(Rounding::NearestEven, env);
+    let r = sub(x, mul(multiple, half_pi));
+    let quadrant = multiple.to_int(64, Rounding::NearestEven, env).unwrap_or(0) & 3;
+    let (sin, cos) = (sin_series(r), cos_series(r));
+    Some(match quadrant {
+        0 => (sin, cos),
+        1 => (cos, sin.negate()),
+        2 => (sin.negate(), cos.negate()),
+        _ => (cos.negate(), sin),
+    })
+}
+
+fn atan_series(z: Float) -> Float {
+    let square = mul(z, z);
+    let (mut power, mut sum) = (z, z);
+    for n in (3..400).step_by(2) {
+        power = mul(power, square).negate();
+        let term = div(power, Float::from_int(n));
+        if negligible(term, sum) {
+            break;
+        }
+        sum = add(sum, term);
+    }
+    sum
+}
+
+/// The arctangent of a value in [0, 1].
+fn atan_unit(z: Float) -> Float {
+    let tan_eighth_pi = sub(SQRT_2, ONE);
+    if z.compare(tan_eighth_pi, false, &mut Env::new(Rounding::NearestEven))
+        == Some(Ordering::Greater)
+    {
+        // atan(z) = pi/4 + atan((z - 1) / (z + 1))
+        add(PI.scale(-2), atan_series(div(sub(z, ONE), add(z, ONE))))
+    } else {
+        atan_series(z)
+    }
+}
+
+/// The angle of the point (x, y), as FPATAN computes it. Neither operand may be a NaN.
+pub fn atan2(y: Float, x: Float) -> Float {
+    let half_pi = PI.scale(-1);
+    let magnitude = match (y.class, x.class) {
+        (Class::Zero, _) if x.sign => PI,
+        (Class::Zero, _) => Float::zero(false),
+        (_, Class::Zero) => half_pi,
+        (Class::Infinity, Class::Infinity) if x.sign => mul(PI, Float::constant(-1, 3 << 126)),
+        (Class::Infinity, Class::Infinity) => PI.scale(-2),
+        (Class::Infinity, _) => half_pi,
+        (_, Class::Infinity) if x.sign => PI,
+        (_, Class::Infinity) => Float::zero(false),
+        _ => {
+            let (y_abs, x_abs) = (y.abs(), x.abs());
+            let steep = y_abs.compare(x_abs, false, &mut Env::new(Rounding::NearestEven))
+                == Some(Ordering::Greater);
+            let angle = if steep {
+                sub(half_pi, atan_unit(div(x_abs, y_abs)))
+            } else {
+                atan_unit(div(y_abs, x_abs))
+            };
+            if x.sign {
+                sub(PI, angle)
+            } else {
+                angle
+            }
+        }
+    };
+    Float {
+        sign: y.sign,
+        ..magnitude
+    }
+}
+
+fn atanh_series(s: Float) -> Float {
+    let square = mul(s, s);
+    let (mut power, mut sum) = (s, s);
+    for n in (3..400).step_by(2) {
+        power = mul(power, square);
+        let term = div(power, Float::from_int(n));
+        if negligible(term, sum) {
+            break;
+        }
+        sum = add(sum, term);
+    }
+    sum
+}
+
+/// log2 of a finite positive value.
+fn log2_finite(x: Float) -> Float {
+    // Split into 2^e * m with m in [sqrt(1/2), sqrt(2)), then ln m = 2 atanh((m-1)/(m+1))
+    let mut exponent = x.exponent;
+    let mut m = Float { exponent: 0, ..x };
+    if m.compare(SQRT_2, false, &mut Env::new(Rounding::NearestEven)) == Some(Ordering::Greater) {
+        m.exponent = -1;
+        exponent += 1;
+    }
+    let ln = atanh_series(div(sub(m, ONE), add(m, ONE))).scale(1);
+    add(Float::from_int(exponent as i128), mul(ln, LOG2_E))
+}
+
+/// `y * log2(x)`, as FYL2X computes it. Neither operand may be a NaN.
+pub fn log2_mul(y: Float, x: Float, env: &mut Env) -> Float {
+    let log = match x.class {
+        Class::Zero => {
+            if y.class == Class::Finite {
+                env.flags |= Flags::DIVIDE_BY_ZERO;
+            }
+            Float::infinity(true)
+        }
+        _ if x.sign => return Float::invalid(env),
+        Class::Infinity => x,
+        _ => log2_finite(x),
+    };
+    y.mul(log, env)
+}
+
+/// `y * log2(x + 1)`, as FYL2XP1 computes it. Neither operand may be a NaN.
+pub fn log2p1_mul(y: Float, x: Float, env: &mut Env) -> Float {
+    match x.class {
+        Class::Zero => y.mul(x, env),
+        // Close to zero, ln(1 + x) = 2 atanh(x / (2 + x)) keeps all of x's precision
+        Class::Finite if x.exponent < -1 => {
+            let two = Float::from_int(2);
+            let ln = atanh_series(div(x, add(two, x))).scale(1);
+            y.mul(mul(ln, LOG2_E), env)
+        }
+        _ => log2_mul(y, add(x, ONE), env),
+    }
+}
+
+fn exp_minus_one_series(t: Float) -> Float {
+    let (mut term, mut sum) = (t, t);
+    for n in 2..200 {
+        term = div(mul(term, t), Float::from_int(n));
+        if negligible(term, sum) {
+            break;
+        }
+        sum = add(sum, term);
+    }
+    sum
+}
+
+/// `2^x - 1`, as F2XM1 computes it. `x` may not be a NaN.
+pub fn exp2_minus_one(x: Float) -> Float {
+    let minus_one = ONE.negate();
+    match x.class {
+        Class::Zero => return x,
+        Class::Infinity if x.sign => return minus_one,
+        Class::Infinity => return x,
+        _ => {}
+    }
+    if x.exponent < 0 {
+        return exp_minus_one_series(mul(x, LN_2));
+    }
+    // Outside F2XM1's documented domain of [-1, 1]: split off the integer part
+    if x.exponent >= 20 {
+        return if x.sign {
+            minus_one
+        } else {
+            Float::infinity(false)
+        };
+    }
+    let env = &mut Env::new(Rounding::NearestEven);
+    let integer = x.round_to_integral(Rounding::Down, env);
+    let fraction = sub(x, integer);
+    let power = add(exp_minus_one_series(mul(fraction, LN_2)), ONE);
+    let shift = integer.to_int(32, Rounding::Down, env).unwrap_or(0) as i32;
+    add(power.scale(shift), minus_one)
+}
+
+/// `x * 2^trunc(y)`, as FSCALE computes it.
+pub fn scale_by(x: Float, y: Float, env: &mut Env) -> Float {
+    if let Some(nan) = Float::propagate_nan(&[x, y], env) {
+        return nan;
+    }
+    match (x.class, y.class) {
+        (Class::Zero, Class::Infinity) if !y.sign => Float::invalid(env),
+        (Class::Infinity, Class::Infinity) if y.sign => Float::invalid(env),
+        (Class::Zero | Class::Infinity, _) => x,
+        (_, Class::Infinity) if y.sign => Float::zero(x.sign),
+        (_, Class::Infinity) => Float::infinity(x.sign),
+        _ => {
+            // Anything beyond this over- or underflows every format anyway
+            let limit = 1 << 20;
+            let scratch = &mut Env::new(Rounding::TowardZero);
+            let n = match y.to_int(32, Rounding::TowardZero, scratch) {
+                Some(n) => n.clamp(-limit, limit),
+                None if y.sign => -limit,
+                None => limit,
+            };
+            x.scale(n as i32)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::DOUBLE;
+    use super::*;
+
+    fn double(value: f64) -> Float {
+        DOUBLE.unpack(value.to_bits() as u128)
+    }
+
+    fn to_double(value: Float) -> f64 {
+        f64::from_bits(DOUBLE.pack(value, &mut Env::new(Rounding::NearestEven)) as u64)
+    }
+
+    /// Within an ulp of the host's result, which isn't always correctly rounded either.
+    fn assert_close(value: Float, expected: f64, what: &str) {
+        let value = to_double(value);
+        let ulp = f64::from_bits(expected.abs().to_bits() + 1) - expected.abs();
+        assert!(
+            (value - expected).abs() <= ulp,
+            "{what}: {value} != {expected}"
+        );
+    }
+
+    #[test]
+    fn trigonometry() {
+        for x in [0.5, -1.0, 3.0, 100.0, 1.0e-5, -7.25] {
+            let (sin, cos) = sin_cos(double(x)).unwrap();
+            assert_close(sin, x.sin(), &format!("sin {x}"));
+            assert_close(cos, x.cos(), &format!("cos {x}"));
+        }
+        assert!(sin_cos(double(1.0e19)).is_none());
+        assert_eq!(
+            to_double(atan2(double(1.0), double(1.0))),
+            std::f64::consts::FRAC_PI_4
+        );
+        for (y, x) in [(1.0, 3.0), (-2.0, 0.5), (5.0, -4.0), (-0.0, -1.0)] {
+            assert_close(
+                atan2(double(y), double(x)),
+                f64::atan2(y, x),
+                &format!("atan2 {y} {x}"),
+            );
+        }
+    }
+
+    #[test]
+    fn logarithms_and_exponentials() {
+        let env = &mut Env::new(Rounding::NearestEven);
+        for x in [2.0, 10.0, 0.3, 1.5, 1.0e-300] {
+            assert_close(
+                log2_mul(ONE, double(x), env),
+                x.log2(),
+                &format!("log2 {x}"),
+            );
+        }
+        let expected = 1.0e-10f64.ln_1p() * std::f64::consts::LOG2_E;
+        assert_close(
+            log2p1_mul(ONE, double(1.0e-10), env),
+            expected,
+            "log2(1 + 1e-10)",
+        );
+        assert_eq!(env.flags, Flags::empty());
+        assert_eq!(
+            to_double(log2_mul(ONE, double(0.0), env)),
+            f64::NEG_INFINITY
+        );
+        assert_eq!(env.flags, Flags::DIVIDE_BY_ZERO);
+        for x in [0.5, -0.75, 1.0e-8] {
+            let expected = (x * std::f64::consts::LN_2).exp_m1();
+            assert_close(exp2_minus_one(double(x)), expected, &format!("2^{x} - 1"));
+        }
+    }
+}
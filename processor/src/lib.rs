@@ -14,10 +14,11 @@ pub trait ProcessableMemoryMap {
 
 impl ProcessableMemoryMap for dyn MemoryMap {
     fn processor_impl(&self) -> Option<Box<dyn ProcessorImplementation>> {
-        if self.bits() == 64 {
-            Some(Box::new(amd64::interp::Amd64Interp::new()))
-        } else {
-            panic!("Non-64-bit architectures are not currently supported!")
+        match self.bits() {
+            // IA-32 programs run in the AMD64 core's 32-bit mode, and boot sectors in its
+            // real mode
+            16 | 32 | 64 => Some(Box::new(amd64::interp::Amd64Interp::new())),
+            _ => None,
         }
     }
 }